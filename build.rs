@@ -1,3 +1,176 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Build-time settings, read from cfg.toml (copy cfg.toml.example, the file is not committed)
+// and overridden by RESP32SOL_<NAME> environment variables, e.g. RESP32SOL_WIFI_SSID
+const CONFIG_FILE: &str = "cfg.toml";
+
 fn main() {
     embuild::espidf::sysenv::output();
+    // The chip cfgs esp-idf-sys sets from MCU, which lib.rs checks the features against
+    println!("cargo::rustc-check-cfg=cfg(esp32, esp32s3, esp32c3, esp32c6)");
+    build_config();
+    build_size();
+}
+
+fn build_config() {
+    println!("cargo:rerun-if-changed={}", CONFIG_FILE);
+    let file: toml::Table = match fs::read_to_string(CONFIG_FILE) {
+        Ok(text) => text
+            .parse()
+            .unwrap_or_else(|e| panic!("{} is not valid TOML: {}", CONFIG_FILE, e)),
+        Err(_) => toml::Table::new(),
+    };
+
+    let setting = |name: &str| {
+        let var = format!("RESP32SOL_{}", name.to_uppercase());
+        println!("cargo:rerun-if-env-changed={}", var);
+        env::var(&var)
+            .ok()
+            .or_else(|| file.get(name).and_then(|value| value.as_str()).map(str::to_string))
+            .unwrap_or_default()
+    };
+    // Secrets only go into debug builds, like the development network. Release builds take the
+    // RPC endpoint from provisioning and the SIM PIN and BLE proof of possession from NVS.
+    let debug = env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_some();
+    let secret = |name: &str| match setting(name) {
+        value if value.is_empty() || debug => value,
+        _ => {
+            println!("cargo:warning={} is only built into debug builds, ignoring it", name);
+            String::new()
+        }
+    };
+
+    let cluster = match setting("cluster").as_str() {
+        "" => "devnet".to_string(),
+        cluster => cluster.to_string(),
+    };
+    // An explicit RPC URL wins over the cluster's public endpoint
+    let rpc_url = match secret("rpc_url").as_str() {
+        "" => match cluster.as_str() {
+            "devnet" => "https://api.devnet.solana.com",
+            "testnet" => "https://api.testnet.solana.com",
+            "mainnet-beta" => "https://api.mainnet-beta.solana.com",
+            other => panic!("Unknown cluster '{}', expected devnet, testnet or mainnet-beta", other),
+        }
+        .to_string(),
+        url => url.to_string(),
+    };
+
+    // Builds with a wired or cellular driver use it unless told otherwise
+    let feature = |name: &str| env::var_os(format!("CARGO_FEATURE_{}", name)).is_some();
+    let ethernet = feature("ETHERNET_W5500") || feature("ETHERNET_RMII");
+    let cellular = feature("CELLULAR");
+    let network = match setting("network").as_str() {
+        "" if ethernet => "ethernet",
+        "" if cellular => "cellular",
+        "" | "wifi" => "wifi",
+        "ethernet" if ethernet => "ethernet",
+        "cellular" if cellular => "cellular",
+        "ethernet" => panic!("network = \"ethernet\" needs --features ethernet-w5500 or ethernet-rmii"),
+        "cellular" => panic!("network = \"cellular\" needs --features cellular"),
+        other => panic!("Unknown network '{}', expected wifi, ethernet or cellular", other),
+    };
+
+    let ip_family = setting("ip_family");
+    if !["", "ipv4", "ipv6"].contains(&ip_family.as_str()) {
+        panic!("Unknown ip_family '{}', expected ipv4 or ipv6, or empty to race both", ip_family);
+    }
+
+    let doh_url = setting("doh_url");
+    if !doh_url.is_empty() && !doh_url.starts_with("https://") {
+        panic!("doh_url '{}' must be an https:// URL", doh_url);
+    }
+
+    // Independent providers the high-value reads must agree with, see quorum.rs
+    let quorum_urls = setting("quorum_urls");
+    let urls: Vec<&str> = quorum_urls.split(',').map(str::trim).filter(|url| !url.is_empty()).collect();
+    if let Some(url) = urls.iter().find(|url| !url.starts_with("https://")) {
+        panic!("quorum_urls entry '{}' must be an https:// URL", url);
+    }
+    let providers = urls.len();
+    let quorum_threshold = match setting("quorum_threshold").as_str() {
+        "" => "2".to_string(),
+        threshold => threshold.to_string(),
+    };
+    match quorum_threshold.parse::<usize>() {
+        Ok(threshold) if providers == 0 || (2..=providers + 1).contains(&threshold) => {}
+        _ => panic!(
+            "quorum_threshold '{}' must be between 2 and the {} providers, the RPC endpoint included",
+            quorum_threshold,
+            providers + 1
+        ),
+    }
+
+    // ESP-NOW shares the WiFi radio and channel, so both relay roles run on WiFi
+    let relay = setting("relay");
+    match relay.as_str() {
+        "" => {}
+        "node" | "gateway" if !feature("ESPNOW_RELAY") => {
+            panic!("relay = \"{}\" needs --features espnow-relay", relay)
+        }
+        "node" | "gateway" if network != "wifi" => panic!("A relay {} needs network = \"wifi\"", relay),
+        "node" if feature("WATCH_ONLY") => panic!("A relay node signs, which watch-only compiles out"),
+        "node" | "gateway" => {}
+        other => panic!("Unknown relay role '{}', expected node or gateway", other),
+    }
+
+    // A LoRa node never goes online, a LoRa gateway can serve ESP-NOW nodes from the same uplink
+    let lora = setting("lora");
+    match lora.as_str() {
+        "" => {}
+        "node" | "gateway" if !feature("LORA_BRIDGE") => {
+            panic!("lora = \"{}\" needs --features lora-bridge", lora)
+        }
+        "node" if feature("WATCH_ONLY") => panic!("A LoRa node signs, which watch-only compiles out"),
+        "node" if feature("CELLULAR") => panic!("A LoRa node has no uplink, build it without --features cellular"),
+        "node" | "gateway" if relay == "node" || (lora == "node" && !relay.is_empty()) => {
+            panic!("A relay node has no uplink to be a gateway with, and reaches only one")
+        }
+        "node" | "gateway" => {}
+        other => panic!("Unknown LoRa role '{}', expected node or gateway", other),
+    }
+
+    println!("cargo:rustc-env=RESP32SOL_WIFI_SSID={}", setting("wifi_ssid"));
+    println!("cargo:rustc-env=RESP32SOL_WIFI_PASSWORD={}", setting("wifi_password"));
+    println!("cargo:rustc-env=RESP32SOL_RPC_URL={}", rpc_url);
+    println!("cargo:rustc-env=RESP32SOL_BLE_POP={}", secret("ble_pop"));
+    println!("cargo:rustc-env=RESP32SOL_NETWORK={}", network);
+    println!("cargo:rustc-env=RESP32SOL_CELLULAR_APN={}", setting("cellular_apn"));
+    println!("cargo:rustc-env=RESP32SOL_CELLULAR_PIN={}", secret("cellular_pin"));
+    println!("cargo:rustc-env=RESP32SOL_IP_FAMILY={}", ip_family);
+    println!("cargo:rustc-env=RESP32SOL_DOH_URL={}", doh_url);
+    println!("cargo:rustc-env=RESP32SOL_QUORUM_URLS={}", urls.join(","));
+    println!("cargo:rustc-env=RESP32SOL_QUORUM_THRESHOLD={}", quorum_threshold);
+    println!("cargo:rustc-env=RESP32SOL_RELAY={}", relay);
+    println!("cargo:rustc-env=RESP32SOL_RELAY_GATEWAY={}", setting("relay_gateway"));
+    println!("cargo:rustc-env=RESP32SOL_LORA={}", lora);
+}
+
+// The size features that reach past the crate: lean-panic relies on a rustc flag cargo features
+// can't set, size-report on a linker map the report is read from
+fn build_size() {
+    let feature = |name: &str| env::var_os(format!("CARGO_FEATURE_{}", name)).is_some();
+
+    if feature("LEAN_PANIC") {
+        let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+        if !rustflags.contains("location-detail=none") {
+            panic!(
+                "--features lean-panic needs RUSTFLAGS=\"--cfg espidf_time64 -Zlocation-detail=none\", \
+                 see the README"
+            );
+        }
+    }
+
+    if feature("SIZE_REPORT") {
+        // OUT_DIR is target/<target>/<profile>/build/<package>-<hash>/out, the map goes next to
+        // the firmware in target/<target>/<profile>
+        let out_dir = env::var("OUT_DIR").unwrap();
+        let profile_dir = Path::new(&out_dir).ancestors().nth(3).unwrap();
+        println!(
+            "cargo:rustc-link-arg-bins=-Wl,-Map={}",
+            profile_dir.join("REsp32Sol.map").display()
+        );
+    }
 }
@@ -0,0 +1,242 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Write as _};
+
+// Amounts as integers in the smallest unit, lamports or a token's raw amount, and the decimal
+// strings people read and type. Both ways go through the digits, never through f64, which
+// can't hold 0.1 SOL exactly and turns 1.15 into 1149999999 lamports once multiplied out.
+
+pub const SOL_DECIMALS: u8 = 9;
+
+// Digits of the largest u64
+const U64_DIGITS: usize = 20;
+
+// A raw amount shown in whole units: 1_050_000_000 lamports is "1.05", 1 with 6 decimals is
+// "0.000001". A precision cuts the fraction to that many digits without rounding up, so
+// `{:.4}` never shows more than there is. Width, fill and alignment pad it like other numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Amount {
+    pub raw: u64,
+    pub decimals: u8,
+}
+
+impl Amount {
+    pub fn new(raw: u64, decimals: u8) -> Self {
+        Amount { raw, decimals }
+    }
+
+    pub fn sol(lamports: u64) -> Self {
+        Amount::new(lamports, SOL_DECIMALS)
+    }
+
+    // The raw amount of a decimal string, see parse_amount
+    pub fn parse(amount: &str, decimals: u8) -> Result<Self, String> {
+        parse_amount(amount, decimals).map(|raw| Amount::new(raw, decimals))
+    }
+}
+
+impl Amount {
+    // The digits, with `precision` decimals if given and without trailing zeros otherwise
+    fn write_digits(&self, precision: Option<usize>, out: &mut String) {
+        let decimals = self.decimals as usize;
+        // Every digit, least significant last, with room for any number of decimals
+        let mut buf = [b'0'; U64_DIGITS + u8::MAX as usize];
+        let digits = &mut buf[..U64_DIGITS + decimals];
+        let mut raw = self.raw;
+        for digit in digits.iter_mut().rev() {
+            *digit = b'0' + (raw % 10) as u8;
+            raw /= 10;
+        }
+        let (whole, fraction) = digits.split_at(U64_DIGITS);
+        let whole = match whole.iter().position(|digit| *digit != b'0') {
+            Some(start) => &whole[start..],
+            None => b"0",
+        };
+        let (fraction, width) = match precision {
+            Some(precision) => (&fraction[..precision.min(decimals)], precision),
+            None => {
+                let end = fraction.iter().rposition(|digit| *digit != b'0').map_or(0, |last| last + 1);
+                (&fraction[..end], end)
+            }
+        };
+        // ASCII digits only
+        out.push_str(core::str::from_utf8(whole).unwrap_or_default());
+        if width > 0 {
+            out.push('.');
+            out.push_str(core::str::from_utf8(fraction).unwrap_or_default());
+            // Zeros for a precision past the amount's decimals
+            for _ in fraction.len()..width {
+                out.push('0');
+            }
+        }
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = String::new();
+        self.write_digits(f.precision(), &mut text);
+        pad(f, &text)
+    }
+}
+
+// Lamports with the unit, "1.05 SOL", for the display, the console and logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sol(pub u64);
+
+impl fmt::Display for Sol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = String::new();
+        Amount::sol(self.0).write_digits(f.precision(), &mut text);
+        text.push_str(" SOL");
+        pad(f, &text)
+    }
+}
+
+// `text` filled out to the width, right-aligned like other numbers unless asked otherwise.
+// Formatter::pad would also cut it to the precision, which here counts decimals.
+fn pad(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    let fill = f.width().unwrap_or_default().saturating_sub(text.len());
+    let (before, after, with) = match f.align() {
+        _ if f.sign_aware_zero_pad() => (fill, 0, '0'),
+        Some(fmt::Alignment::Left) => (0, fill, f.fill()),
+        Some(fmt::Alignment::Center) => (fill / 2, fill - fill / 2, f.fill()),
+        Some(fmt::Alignment::Right) | None => (fill, 0, f.fill()),
+    };
+    for _ in 0..before {
+        f.write_char(with)?;
+    }
+    f.write_str(text)?;
+    for _ in 0..after {
+        f.write_char(with)?;
+    }
+    Ok(())
+}
+
+// "1.5" with 9 decimals is 1_500_000_000, more decimals than the token has are refused rather
+// than rounded
+pub fn parse_amount(amount: &str, decimals: u8) -> Result<u64, String> {
+    let (whole, fraction) = split_amount(amount)?;
+    if fraction.len() > decimals as usize {
+        return Err(format!("Amount {} has more than {} decimals", amount, decimals));
+    }
+    let scale = 10u64.checked_pow(decimals as u32).ok_or("Too many decimals")?;
+    let whole: u64 = whole.parse().map_err(|e| format!("Amount parse: {:?}", e))?;
+    let fraction: u64 = match fraction.is_empty() {
+        true => 0,
+        false => format!("{:0<width$}", fraction, width = decimals as usize)
+            .parse()
+            .map_err(|e| format!("Amount parse: {:?}", e))?,
+    };
+    whole
+        .checked_mul(scale)
+        .and_then(|whole| whole.checked_add(fraction))
+        .ok_or_else(|| format!("Amount {} too large", amount))
+}
+
+// "0.25" SOL in lamports
+pub fn parse_sol(amount: &str) -> Result<u64, String> {
+    parse_amount(amount, SOL_DECIMALS)
+}
+
+// The whole and fractional digits of a decimal amount, for checking one before its decimals
+// are known
+pub fn split_amount(amount: &str) -> Result<(&str, &str), String> {
+    let (whole, fraction) = match amount.split_once('.') {
+        Some((_, "")) => return Err(format!("Invalid amount: {}", amount)),
+        Some(parts) => parts,
+        None => (amount, ""),
+    };
+    let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+        return Err(format!("Invalid amount: {}", amount));
+    }
+    Ok((whole, fraction))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn rejects_malformed() {
+        for amount in ["", ".", "1.", ".5", "-1", "+1", " 1", "1 ", "1.2.3", "1,5", "1e9", "0x10", "one"] {
+            assert!(parse_sol(amount).is_err(), "{:?} parsed", amount);
+        }
+    }
+
+    #[test]
+    fn rejects_more_decimals_than_the_unit() {
+        assert!(parse_sol("0.0000000001").is_err());
+        assert!(parse_amount("1.1234567", 6).is_err());
+        assert!(parse_amount("1.5", 0).is_err());
+        assert_eq!(parse_sol("0.000000001"), Ok(1));
+        assert_eq!(parse_amount("1.123456", 6), Ok(1_123_456));
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(parse_sol("18446744073.709551615"), Ok(u64::MAX));
+        assert!(parse_sol("18446744073.709551616").is_err());
+        assert!(parse_sol("18446744074").is_err());
+        assert!(parse_amount("18446744073709551616", 0).is_err());
+        assert!(parse_amount("99999999999999999999999", 0).is_err());
+        // 10^20 doesn't fit a u64
+        assert!(parse_amount("0", 20).is_err());
+    }
+
+    #[test]
+    fn parses() {
+        assert_eq!(parse_sol("0"), Ok(0));
+        assert_eq!(parse_sol("1"), Ok(1_000_000_000));
+        assert_eq!(parse_sol("1.15"), Ok(1_150_000_000));
+        assert_eq!(parse_sol("0.1"), Ok(100_000_000));
+        assert_eq!(parse_sol("007.50"), Ok(7_500_000_000));
+        assert_eq!(Amount::parse("2.5", 6), Ok(Amount::new(2_500_000, 6)));
+        assert_eq!(split_amount("12.034"), Ok(("12", "034")));
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(Amount::sol(1_050_000_000).to_string(), "1.05");
+        assert_eq!(Amount::new(1, 6).to_string(), "0.000001");
+        assert_eq!(Amount::new(0, 9).to_string(), "0");
+        assert_eq!(Amount::new(42, 0).to_string(), "42");
+        assert_eq!(Amount::sol(u64::MAX).to_string(), "18446744073.709551615");
+        assert_eq!(Sol(1_500_000_000).to_string(), "1.5 SOL");
+    }
+
+    #[test]
+    fn precision_cuts_without_rounding() {
+        assert_eq!(format!("{:.4}", Amount::sol(1_999_999_999)), "1.9999");
+        assert_eq!(format!("{:.2}", Amount::sol(1_000_000_000)), "1.00");
+        assert_eq!(format!("{:.0}", Amount::sol(1_999_999_999)), "1");
+        assert_eq!(format!("{:.3}", Amount::new(5, 1)), "0.500");
+        assert_eq!(format!("{:.2}", Sol(2_345_000_000)), "2.34 SOL");
+    }
+
+    #[test]
+    fn width_and_alignment() {
+        assert_eq!(format!("{:8}", Amount::sol(1_500_000_000)), "     1.5");
+        assert_eq!(format!("{:<8}|", Amount::sol(1_500_000_000)), "1.5     |");
+        assert_eq!(format!("{:^7}", Amount::sol(1_500_000_000)), "  1.5  ");
+        assert_eq!(format!("{:*>6}", Amount::sol(1_500_000_000)), "***1.5");
+        assert_eq!(format!("{:06}", Amount::sol(1_500_000_000)), "0001.5");
+        assert_eq!(format!("{:>10.2}", Amount::sol(1_500_000_000)), "      1.50");
+        assert_eq!(format!("{:2}", Amount::sol(1_500_000_000)), "1.5");
+        assert_eq!(format!("{:>12}", Sol(1_500_000_000)), "     1.5 SOL");
+        assert_eq!(format!("{:<10.1}|", Sol(250_000_000)), "0.2 SOL   |");
+    }
+
+    #[test]
+    fn round_trips() {
+        for decimals in [0, 2, 6, 9, 19] {
+            for raw in [0, 1, 9, 10, 999, 1_000_000_007, 123_456_789_012_345, u64::MAX] {
+                let amount = Amount::new(raw, decimals);
+                assert_eq!(Amount::parse(&amount.to_string(), decimals), Ok(amount));
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use core::time::Duration;
+
+use serde_json::Value;
+
+use crate::leanjson;
+use crate::rpc::{create_solana_payload, ConfirmationStatus, SolanaRpcMethod};
+
+// The JSON-RPC exchange on top of a transport: the request going out and the envelope of the
+// answer coming back, the node's error object or the result. The firmware's solrpc adds its
+// buffers, retries and rate limit around these, firmware without ESP-IDF can use `call` and
+// `call_raw` as they are. Either way the transport only carries the bytes.
+
+// Where a transport hands the response body as it arrives
+pub type OnData<'a, E> = dyn FnMut(&[u8]) -> Result<(), E> + 'a;
+
+pub trait RpcTransport {
+    // What a failed post returns, the node's refusals and unparsable answers included
+    type Error: From<CallError>;
+
+    // POSTs `body` to `url` and hands the response body to `on_data` as it arrives. Anything but
+    // a complete 2xx response is an error, and `timeout` bounds each wait on the network.
+    fn post(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        timeout: Duration,
+        on_data: &mut OnData<'_, Self::Error>,
+    ) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallError {
+    // The node refused the call with this JSON-RPC error
+    Node { code: i64, message: String },
+    // The request didn't serialize
+    Request(String),
+    // The response isn't JSON, or not what the method returns
+    Parse(String),
+}
+
+// The request for `method` into `out`, which is cleared first so one buffer serves every call.
+// The JSON tree is freed again on return, before the TLS session needs the heap.
+pub fn write_request(
+    out: &mut String,
+    method: SolanaRpcMethod,
+    commitment: Option<ConfirmationStatus>,
+) -> Result<(), CallError> {
+    let mut payload = create_solana_payload(method);
+    if let Some(commitment) = commitment {
+        set_commitment(&mut payload, commitment);
+    }
+    out.clear();
+    write!(out, "{}", payload).map_err(|e| CallError::Request(format!("JSON serialize: {:?}", e)))
+}
+
+// The methods' own commitment replaced by `commitment`, in the config object they send
+pub fn set_commitment(payload: &mut Value, commitment: ConfirmationStatus) {
+    let name = match commitment {
+        ConfirmationStatus::Processed => "processed",
+        ConfirmationStatus::Confirmed => "confirmed",
+        ConfirmationStatus::Finalized => "finalized",
+    };
+    for param in payload["params"].as_array_mut().into_iter().flatten() {
+        if let Some(current) = param.get_mut("commitment") {
+            *current = name.into();
+        }
+    }
+}
+
+// Hands a request from write_request to the transport with the JSON headers
+pub fn post<T: RpcTransport + ?Sized>(
+    transport: &T,
+    url: &str,
+    request: &str,
+    timeout: Duration,
+    on_data: &mut OnData<'_, T::Error>,
+) -> Result<(), T::Error> {
+    // On the stack, the request may be the only thing on the heap the TLS session can't have
+    let mut digits = [0u8; 20];
+    let headers = [
+        ("Content-Type", "application/json"),
+        ("Content-Length", decimal(request.len(), &mut digits)),
+    ];
+    transport.post(url, &headers, request.as_bytes(), timeout, on_data)
+}
+
+fn decimal(mut n: usize, digits: &mut [u8; 20]) -> &str {
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    // ASCII digits only
+    core::str::from_utf8(&digits[start..]).unwrap_or_default()
+}
+
+// The node's error object as an error, the result otherwise. Moved out rather than cloned, a
+// large result would briefly be in RAM twice.
+pub fn take_result(mut response: Value) -> Result<Value, CallError> {
+    let error = &response["error"];
+    if !error.is_null() {
+        return Err(CallError::Node {
+            code: error["code"].as_i64().unwrap_or_default(),
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(response.get_mut("result").map(Value::take).unwrap_or_default())
+}
+
+// take_result's check on the raw response
+pub fn read_node_error(body: &[u8]) -> Option<CallError> {
+    let error = leanjson::find(body, &["error"]).ok().flatten()?;
+    if leanjson::is_null(error) {
+        return None;
+    }
+    let code = leanjson::find(error, &["code"])
+        .ok()
+        .flatten()
+        .and_then(|code| core::str::from_utf8(code).ok()?.trim().parse().ok());
+    let message = leanjson::str_at(error, &["message"]).ok().flatten();
+    Some(CallError::Node {
+        code: code.unwrap_or_default(),
+        message: message.unwrap_or_default().to_string(),
+    })
+}
+
+// A whole call, the result parsed into a Value for the parse_* functions
+pub fn call<T: RpcTransport + ?Sized>(
+    transport: &T,
+    url: &str,
+    method: SolanaRpcMethod,
+    commitment: Option<ConfirmationStatus>,
+    timeout: Duration,
+) -> Result<Value, T::Error> {
+    let body = exchange(transport, url, method, commitment, timeout)?;
+    let response = serde_json::from_slice(&body).map_err(|e| CallError::Parse(format!("JSON parse: {:?}", e)))?;
+    Ok(take_result(response)?)
+}
+
+// A whole call with the raw response handed to `parse`, for the read_* functions
+pub fn call_raw<T: RpcTransport + ?Sized, R>(
+    transport: &T,
+    url: &str,
+    method: SolanaRpcMethod,
+    commitment: Option<ConfirmationStatus>,
+    timeout: Duration,
+    parse: impl FnOnce(&[u8]) -> Result<R, String>,
+) -> Result<R, T::Error> {
+    let body = exchange(transport, url, method, commitment, timeout)?;
+    if let Some(error) = read_node_error(&body) {
+        return Err(error.into());
+    }
+    Ok(parse(&body).map_err(CallError::Parse)?)
+}
+
+fn exchange<T: RpcTransport + ?Sized>(
+    transport: &T,
+    url: &str,
+    method: SolanaRpcMethod,
+    commitment: Option<ConfirmationStatus>,
+    timeout: Duration,
+) -> Result<Vec<u8>, T::Error> {
+    let mut request = String::new();
+    write_request(&mut request, method, commitment)?;
+    let mut body = Vec::new();
+    post(transport, url, &request, timeout, &mut |data| {
+        body.extend_from_slice(data);
+        Ok(())
+    })?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::borrow::ToOwned;
+    use alloc::vec;
+    use core::cell::RefCell;
+    use core::str::FromStr;
+
+    use solana_address::Address;
+    use solana_hash::Hash;
+
+    use super::*;
+    use crate::rpc::*;
+
+    const URL: &str = "https://api.mainnet-beta.solana.com";
+    const TIMEOUT: Duration = Duration::from_secs(30);
+    const WALLET: &str = "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM";
+
+    // Replays a recorded response in small pieces, the way a connection delivers it, and keeps
+    // what was posted
+    struct Recorded {
+        response: &'static [u8],
+        request: RefCell<Vec<u8>>,
+        headers: RefCell<Vec<(String, String)>>,
+    }
+
+    impl Recorded {
+        fn new(response: &'static [u8]) -> Self {
+            Recorded {
+                response,
+                request: RefCell::default(),
+                headers: RefCell::default(),
+            }
+        }
+
+        fn request(&self) -> Value {
+            serde_json::from_slice(&self.request.borrow()).unwrap()
+        }
+    }
+
+    impl RpcTransport for Recorded {
+        type Error = CallError;
+
+        fn post(
+            &self,
+            _url: &str,
+            headers: &[(&str, &str)],
+            body: &[u8],
+            _timeout: Duration,
+            on_data: &mut OnData<'_, CallError>,
+        ) -> Result<(), CallError> {
+            *self.request.borrow_mut() = body.to_vec();
+            *self.headers.borrow_mut() = headers.iter().map(|(k, v)| ((*k).to_owned(), (*v).to_owned())).collect();
+            self.response.chunks(7).try_for_each(on_data)
+        }
+    }
+
+    #[test]
+    fn get_balance() {
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/get_balance.json"));
+        let method = SolanaRpcMethod::GetBalance(WALLET.to_string());
+        let lamports = call_raw(&transport, URL, method.clone(), None, TIMEOUT, read_balance).unwrap();
+        assert_eq!(lamports, 1_050_000_000);
+        assert_eq!(
+            transport.request(),
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "getBalance", "params": [WALLET]})
+        );
+        let result = call(&transport, URL, method, None, TIMEOUT).unwrap();
+        assert_eq!(parse_balance(&result).unwrap(), 1_050_000_000);
+    }
+
+    #[test]
+    fn headers() {
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/get_balance.json"));
+        call(&transport, URL, SolanaRpcMethod::GetSlot, None, TIMEOUT).unwrap();
+        let length = transport.request.borrow().len().to_string();
+        assert_eq!(
+            *transport.headers.borrow(),
+            vec![
+                ("Content-Type".to_owned(), "application/json".to_owned()),
+                ("Content-Length".to_owned(), length),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_account_info() {
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/get_account_info.json"));
+        let method = SolanaRpcMethod::GetAccountInfo(WALLET.to_string());
+        let expected = AccountInfo {
+            lamports: 1_461_600,
+            owner: Address::from_str("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA").unwrap(),
+            data: vec![1, 2, 3, 4],
+        };
+        let raw = call_raw(&transport, URL, method.clone(), None, TIMEOUT, read_account_info).unwrap();
+        assert_eq!(raw.as_ref(), Some(&expected));
+        let result = call(&transport, URL, method, None, TIMEOUT).unwrap();
+        assert_eq!(parse_account_info(&result).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn missing_account() {
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/get_account_info_missing.json"));
+        let method = SolanaRpcMethod::GetAccountInfo(WALLET.to_string());
+        assert_eq!(call_raw(&transport, URL, method.clone(), None, TIMEOUT, read_account_info), Ok(None));
+        let result = call(&transport, URL, method, None, TIMEOUT).unwrap();
+        assert_eq!(parse_account_info(&result), Ok(None));
+    }
+
+    #[test]
+    fn signature_status() {
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/get_signature_statuses.json"));
+        let signature = "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnbJLgp8uirBgmQpjKhoR4tjF3ZpRzrFmBV6UjKdiSZkQUW";
+        let method = SolanaRpcMethod::GetSignatureStatuses(vec![signature.to_string()]);
+        let status = call_raw(&transport, URL, method.clone(), None, TIMEOUT, read_signature_status).unwrap();
+        assert_eq!(status, Some(ConfirmationStatus::Finalized));
+        let result = call(&transport, URL, method, None, TIMEOUT).unwrap();
+        assert_eq!(parse_signature_status(&result).unwrap(), Some(ConfirmationStatus::Finalized));
+    }
+
+    #[test]
+    fn latest_blockhash_with_commitment() {
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/get_latest_blockhash.json"));
+        let method = SolanaRpcMethod::GetLatestBlockhash;
+        let hash = call_raw(&transport, URL, method, Some(ConfirmationStatus::Finalized), TIMEOUT, read_blockhash);
+        assert_eq!(hash, Ok(Hash::from_str("EkSnNWid2cvwEVnVx9aBqawnmiCNiDgp3gUdkDPTKN1N").unwrap()));
+        assert_eq!(transport.request()["params"], serde_json::json!([{"commitment": "finalized"}]));
+    }
+
+    #[test]
+    fn commitment_leaves_other_params() {
+        // getBalance sends no config object, there is nothing to override
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/get_balance.json"));
+        let method = SolanaRpcMethod::GetBalance(WALLET.to_string());
+        call(&transport, URL, method, Some(ConfirmationStatus::Processed), TIMEOUT).unwrap();
+        assert_eq!(transport.request()["params"], serde_json::json!([WALLET]));
+    }
+
+    #[test]
+    fn node_error() {
+        let transport = Recorded::new(include_bytes!("../tests/fixtures/send_transaction_preflight_failed.json"));
+        let method = SolanaRpcMethod::SendTransaction("AQ==".to_string());
+        let expected = CallError::Node {
+            code: -32002,
+            message: "Transaction simulation failed: Attempt to debit an account but found no record of a prior credit."
+                .to_string(),
+        };
+        let sent = call_raw(&transport, URL, method.clone(), None, TIMEOUT, read_sent_signature);
+        assert_eq!(sent, Err(expected.clone()));
+        assert_eq!(call(&transport, URL, method, None, TIMEOUT), Err(expected));
+    }
+
+    #[test]
+    fn malformed_response() {
+        let transport = Recorded::new(br#"{"jsonrpc":"2.0","result":{"context":{"slot":1},"val"#);
+        let method = SolanaRpcMethod::GetBalance(WALLET.to_string());
+        assert!(matches!(call(&transport, URL, method.clone(), None, TIMEOUT), Err(CallError::Parse(_))));
+        assert!(matches!(call_raw(&transport, URL, method, None, TIMEOUT, read_balance), Err(CallError::Parse(_))));
+    }
+
+    #[test]
+    fn result_missing_its_field() {
+        let transport = Recorded::new(br#"{"jsonrpc":"2.0","result":{"context":{"slot":1}},"id":1}"#);
+        let method = SolanaRpcMethod::GetBalance(WALLET.to_string());
+        assert!(matches!(call_raw(&transport, URL, method, None, TIMEOUT, read_balance), Err(CallError::Parse(_))));
+    }
+
+    #[test]
+    fn content_length_digits() {
+        let mut digits = [0u8; 20];
+        assert_eq!(decimal(0, &mut digits), "0");
+        assert_eq!(decimal(1234, &mut digits), "1234");
+        assert_eq!(decimal(usize::MAX, &mut digits), usize::MAX.to_string());
+    }
+}
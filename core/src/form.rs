@@ -0,0 +1,76 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// The fields of an application/x-www-form-urlencoded body, what the setup portal's form posts
+
+// The value of `field`, percent and '+' decoded
+pub fn form_value(form: &str, field: &str) -> Option<String> {
+    form.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == field).then(|| url_decode(value))
+    })
+}
+
+// A '%' without two hex digits after it is kept as it is, invalid UTF-8 is replaced
+pub fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            // from_str_radix alone would take "+1"
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| core::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes() {
+        assert_eq!(url_decode("My+Home%20WiFi"), "My Home WiFi");
+        assert_eq!(url_decode("p%40ss%2Bw%3Drd%26"), "p@ss+w=rd&");
+        assert_eq!(url_decode("%C3%A9t%C3%A9"), "été");
+        assert_eq!(url_decode("%2b%2B"), "++");
+        assert_eq!(url_decode(""), "");
+    }
+
+    #[test]
+    fn malformed_escapes_stay() {
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%4"), "%4");
+        assert_eq!(url_decode("%zz%41"), "%zzA");
+        assert_eq!(url_decode("%%41"), "%A");
+        assert_eq!(url_decode("%+1"), "% 1");
+        // A lone byte of a UTF-8 sequence
+        assert_eq!(url_decode("a%C3"), "a\u{fffd}");
+        // A split multibyte character doesn't panic
+        assert_eq!(url_decode("%é"), "%é");
+    }
+
+    #[test]
+    fn fields() {
+        let form = "ssid=My+Home&password=p%26ss&empty=&flag&ssid=second";
+        assert_eq!(form_value(form, "ssid").as_deref(), Some("My Home"));
+        assert_eq!(form_value(form, "password").as_deref(), Some("p&ss"));
+        assert_eq!(form_value(form, "empty").as_deref(), Some(""));
+        assert_eq!(form_value(form, "flag").as_deref(), Some(""));
+        assert_eq!(form_value(form, "recipient"), None);
+        assert_eq!(form_value(form, "pass"), None);
+        assert_eq!(form_value("", "ssid"), None);
+    }
+}
@@ -0,0 +1,227 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
+
+// Splits payloads too large for one QR code (or one BLE/MQTT packet) into self-describing
+// frames and puts them back together:
+//   RSF:<index>/<total>/<checksum>:<base64 chunk>
+// index is 1-based, the checksum is the first 4 bytes of the payload's SHA-256 in hex and
+// ties frames of one payload together, frames may arrive in any order and repeat.
+const FRAME_PREFIX: &str = "RSF:";
+
+// The largest payload framed, a transaction at the 1232-byte packet limit
+pub const MAX_TRANSACTION_LEN: usize = 1232;
+
+fn payload_checksum(data: &[u8]) -> String {
+    Sha256::digest(data)[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn fragment(data: &[u8], chunk_len: usize) -> Vec<String> {
+    let checksum = payload_checksum(data);
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![&[]]
+    } else {
+        data.chunks(chunk_len.max(1)).collect()
+    };
+    let total = chunks.len();
+
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            format!(
+                "{}{}/{}/{}:{}",
+                FRAME_PREFIX,
+                i + 1,
+                total,
+                checksum,
+                general_purpose::STANDARD.encode(chunk)
+            )
+        })
+        .collect()
+}
+
+// The checksum a frame carries, which tells the payloads being received apart without trusting
+// who claims to send them
+pub fn payload_id(frame: &str) -> Option<&str> {
+    let (header, _) = frame.trim().strip_prefix(FRAME_PREFIX)?.split_once(':')?;
+    header.split('/').nth(2)
+}
+
+pub struct Reassembler {
+    checksum: String,
+    chunks: Vec<Option<Vec<u8>>>,
+    max_frames: usize,
+    chunk_len: usize,
+}
+
+impl Reassembler {
+    // Takes payloads of up to `max_len` bytes sent in chunks of up to `chunk_len`. Frames come
+    // from anyone in radio or camera range, one announcing more frames or carrying a longer
+    // chunk is refused before anything is allocated for it.
+    pub fn new(max_len: usize, chunk_len: usize) -> Self {
+        let chunk_len = chunk_len.max(1);
+        Reassembler {
+            checksum: String::new(),
+            chunks: Vec::new(),
+            max_frames: max_len.div_ceil(chunk_len).max(1),
+            chunk_len,
+        }
+    }
+
+    // Progress of the payload being collected, as (received, total) frames
+    pub fn progress(&self) -> (usize, usize) {
+        (self.chunks.iter().filter(|c| c.is_some()).count(), self.chunks.len())
+    }
+
+    // Feeds one frame, returns the payload once every frame has arrived. A frame of a
+    // different payload discards the partial one, so a new scan can start at any time.
+    pub fn push(&mut self, frame: &str) -> Result<Option<Vec<u8>>, String> {
+        let body = frame.trim().strip_prefix(FRAME_PREFIX).ok_or("Not a payload frame")?;
+        let (header, chunk) = body.split_once(':').ok_or("Malformed frame")?;
+
+        let mut fields = header.split('/');
+        let (Some(index), Some(total), Some(checksum), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err("Malformed frame header".to_string());
+        };
+        let index: usize = index.parse().map_err(|e| format!("Frame index: {:?}", e))?;
+        let total: usize = total.parse().map_err(|e| format!("Frame total: {:?}", e))?;
+        if total == 0 || total > self.max_frames || index == 0 || index > total {
+            return Err(format!("Frame {}/{} out of range", index, total));
+        }
+        // Base64 of a full chunk, checked before decoding
+        if chunk.len() > self.chunk_len.div_ceil(3) * 4 {
+            return Err(format!("Frame chunk over {} bytes", self.chunk_len));
+        }
+
+        let chunk = general_purpose::STANDARD
+            .decode(chunk)
+            .map_err(|e| format!("Frame decode: {:?}", e))?;
+        // Base64 rounds up to 3 bytes
+        if chunk.len() > self.chunk_len {
+            return Err(format!("Frame chunk over {} bytes", self.chunk_len));
+        }
+
+        if checksum != self.checksum || total != self.chunks.len() {
+            self.checksum = checksum.to_string();
+            self.chunks = vec![None; total];
+        }
+
+        self.chunks[index - 1] = Some(chunk);
+
+        if self.chunks.iter().any(|c| c.is_none()) {
+            return Ok(None);
+        }
+
+        let payload: Vec<u8> = self.chunks.drain(..).flatten().flatten().collect();
+        let expected = core::mem::take(&mut self.checksum);
+        if payload_checksum(&payload) != expected {
+            return Err("Payload checksum mismatch".to_string());
+        }
+        Ok(Some(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn frames() {
+        let frames = fragment(b"hello world", 4);
+        assert_eq!(frames.len(), 3);
+        let checksum = payload_checksum(b"hello world");
+        assert_eq!(frames[0], format!("RSF:1/3/{}:aGVsbA==", checksum));
+        assert_eq!(frames[2], format!("RSF:3/3/{}:cmxk", checksum));
+        assert_eq!(payload_id(&frames[1]), Some(checksum.as_str()));
+        assert_eq!(payload_id("RSF:1/3"), None);
+        assert_eq!(payload_id("QR:1/1/00000000:AA=="), None);
+    }
+
+    #[test]
+    fn reassembles_in_any_order_with_repeats() {
+        let data = payload(MAX_TRANSACTION_LEN);
+        let frames = fragment(&data, 100);
+        let mut reassembler = Reassembler::new(MAX_TRANSACTION_LEN, 100);
+        for frame in frames[1..].iter().rev() {
+            assert_eq!(reassembler.push(frame), Ok(None));
+        }
+        assert_eq!(reassembler.push(&frames[5]), Ok(None));
+        assert_eq!(reassembler.progress(), (frames.len() - 1, frames.len()));
+        assert_eq!(reassembler.push(&format!("  {}\n", frames[0])), Ok(Some(data)));
+    }
+
+    #[test]
+    fn empty_payload() {
+        let frames = fragment(&[], 100);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(Reassembler::new(100, 100).push(&frames[0]), Ok(Some(Vec::new())));
+    }
+
+    #[test]
+    fn new_payload_discards_the_partial_one() {
+        let first = fragment(&payload(300), 100);
+        let second = fragment(b"second payload", 10);
+        let mut reassembler = Reassembler::new(MAX_TRANSACTION_LEN, 100);
+        assert_eq!(reassembler.push(&first[0]), Ok(None));
+        assert_eq!(reassembler.push(&second[1]), Ok(None));
+        assert_eq!(reassembler.push(&second[0]), Ok(Some(b"second payload".to_vec())));
+        assert_eq!(reassembler.progress(), (0, 0));
+    }
+
+    #[test]
+    fn refuses_oversized_frames() {
+        let mut reassembler = Reassembler::new(200, 100);
+        // Announcing more frames than 200 bytes take
+        assert!(reassembler.push("RSF:1/3/00000000:AA==").is_err());
+        // Chunks longer than 100 bytes, one its base64 already gives away and one it doesn't
+        assert!(reassembler.push(&fragment(&payload(400), 400)[0]).is_err());
+        assert!(reassembler.push(&fragment(&payload(101), 101)[0]).is_err());
+        assert_eq!(reassembler.progress(), (0, 0));
+    }
+
+    #[test]
+    fn refuses_malformed_frames() {
+        let frames = fragment(&payload(300), 100);
+        let mut reassembler = Reassembler::new(MAX_TRANSACTION_LEN, 100);
+        reassembler.push(&frames[0]).unwrap();
+        for frame in [
+            "",
+            "RSF:",
+            "1/1/00000000:AA==",
+            "RSF:1/1/00000000",
+            "RSF:1/1:AA==",
+            "RSF:1/1/00000000/9:AA==",
+            "RSF:x/1/00000000:AA==",
+            "RSF:1/-1/00000000:AA==",
+            "RSF:0/1/00000000:AA==",
+            "RSF:2/1/00000000:AA==",
+            "RSF:1/0/00000000:AA==",
+            "RSF:1/1/00000000:not base64!",
+        ] {
+            assert!(reassembler.push(frame).is_err(), "{:?} taken", frame);
+        }
+        // None of them cost the payload being collected its frames
+        assert_eq!(reassembler.progress(), (1, 3));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let frames = fragment(b"hello world", 4);
+        let forged = frames[1].replace(":d28=", ":d29=").replace(&frames[1][frames[1].len() - 8..], "bywg");
+        let mut reassembler = Reassembler::new(100, 4);
+        reassembler.push(&frames[0]).unwrap();
+        reassembler.push(&forged).unwrap();
+        assert_eq!(reassembler.push(&frames[2]), Err("Payload checksum mismatch".to_string()));
+    }
+}
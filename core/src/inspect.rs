@@ -0,0 +1,515 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use solana_address::{address, Address as Pubkey};
+
+// Decoded view of what a message does, used by signing hooks before anything is signed. System
+// instructions are read the way bincode lays them out, a u32 variant and then the fields, and
+// token instructions by their first byte, so neither program's crate is needed.
+
+pub const SYSTEM_PROGRAM_ID: Pubkey = address!("11111111111111111111111111111111");
+pub const TOKEN_PROGRAM_ID: Pubkey = address!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+pub const TOKEN_2022_PROGRAM_ID: Pubkey = address!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+const SYSTEM_CREATE_ACCOUNT: u32 = 0;
+const SYSTEM_ASSIGN: u32 = 1;
+const SYSTEM_TRANSFER: u32 = 2;
+const SYSTEM_CREATE_ACCOUNT_WITH_SEED: u32 = 3;
+const SYSTEM_WITHDRAW_NONCE_ACCOUNT: u32 = 5;
+const SYSTEM_ALLOCATE: u32 = 8;
+const SYSTEM_ALLOCATE_WITH_SEED: u32 = 9;
+const SYSTEM_ASSIGN_WITH_SEED: u32 = 10;
+const SYSTEM_TRANSFER_WITH_SEED: u32 = 11;
+
+const TOKEN_TRANSFER: u8 = 3;
+const TOKEN_APPROVE: u8 = 4;
+const TOKEN_SET_AUTHORITY: u8 = 6;
+const TOKEN_CLOSE_ACCOUNT: u8 = 9;
+const TOKEN_TRANSFER_CHECKED: u8 = 12;
+const TOKEN_APPROVE_CHECKED: u8 = 13;
+
+// The parts of a message that are inspected, as solana-message's Message holds them: the
+// account keys and instructions naming their program and accounts by index into them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub account_keys: Vec<Pubkey>,
+    pub instructions: Vec<CompiledInstruction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledInstruction {
+    pub program_id_index: u8,
+    pub accounts: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    fn program(&self, instruction: &CompiledInstruction) -> Option<Pubkey> {
+        self.account_keys.get(instruction.program_id_index as usize).copied()
+    }
+
+    fn account(&self, instruction: &CompiledInstruction, index: usize) -> Option<Pubkey> {
+        instruction
+            .accounts
+            .get(index)
+            .and_then(|&i| self.account_keys.get(i as usize))
+            .copied()
+    }
+
+    fn involves(&self, instruction: &CompiledInstruction, signer: &Pubkey) -> bool {
+        instruction
+            .accounts
+            .iter()
+            .any(|&index| self.account_keys.get(index as usize) == Some(signer))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolTransfer {
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub lamports: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenTransfer {
+    pub token_program: Pubkey,
+    pub source: Pubkey,
+    // Only TransferChecked names the mint, a plain Transfer leaves it to the token accounts
+    pub mint: Option<Pubkey>,
+    pub destination: Pubkey,
+    pub authority: Pubkey,
+    pub amount: u64,
+}
+
+// Instructions that give a wallet or token account away without an amount a limit could count:
+// the wallet owned by another program, a delegate, a new authority or the account closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handover {
+    // Assign and AssignWithSeed
+    Assign,
+    // Allocate and AllocateWithSeed
+    Allocate,
+    // Approve and ApproveChecked
+    TokenApprove,
+    TokenSetAuthority,
+    TokenCloseAccount,
+}
+
+impl fmt::Display for Handover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Handover::Assign => write!(f, "Assign"),
+            Handover::Allocate => write!(f, "Allocate"),
+            Handover::TokenApprove => write!(f, "Token Approve"),
+            Handover::TokenSetAuthority => write!(f, "Token SetAuthority"),
+            Handover::TokenCloseAccount => write!(f, "Token CloseAccount"),
+        }
+    }
+}
+
+fn is_token_program(program: &Pubkey) -> bool {
+    *program == TOKEN_PROGRAM_ID || *program == TOKEN_2022_PROGRAM_ID
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// Lamports of a CreateAccountWithSeed, behind the base and the length prefixed seed
+fn seeded_lamports(data: &[u8]) -> Option<u64> {
+    let seed_len = usize::try_from(u64_at(data, 36)?).ok()?;
+    u64_at(data, 44usize.checked_add(seed_len)?)
+}
+
+// Programs invoked by the message's top level instructions
+pub fn invoked_programs(message: &Message) -> Vec<Pubkey> {
+    let mut programs: Vec<Pubkey> = Vec::new();
+    for instruction in &message.instructions {
+        if let Some(program_id) = message.program(instruction) {
+            if !programs.contains(&program_id) {
+                programs.push(program_id);
+            }
+        }
+    }
+    programs
+}
+
+pub fn sol_transfers(message: &Message) -> Vec<SolTransfer> {
+    let mut transfers = Vec::new();
+
+    for instruction in &message.instructions {
+        if message.program(instruction) != Some(SYSTEM_PROGRAM_ID) {
+            continue;
+        }
+
+        let data = &instruction.data;
+        let account = |index: usize| message.account(instruction, index);
+        let transfer = match u32_at(data, 0) {
+            Some(SYSTEM_TRANSFER) | Some(SYSTEM_CREATE_ACCOUNT) => {
+                account(0).zip(account(1)).zip(u64_at(data, 4))
+            }
+            Some(SYSTEM_CREATE_ACCOUNT_WITH_SEED) => account(0).zip(account(1)).zip(seeded_lamports(data)),
+            // The funding account is derived from the base, whose signature moves the lamports
+            Some(SYSTEM_TRANSFER_WITH_SEED) => account(1).zip(account(2)).zip(u64_at(data, 4)),
+            // Paid out of the nonce account on the nonce authority's signature
+            Some(SYSTEM_WITHDRAW_NONCE_ACCOUNT) => account(4).zip(account(1)).zip(u64_at(data, 4)),
+            _ => None,
+        };
+
+        if let Some(((from, to), lamports)) = transfer {
+            transfers.push(SolTransfer { from, to, lamports });
+        }
+    }
+
+    transfers
+}
+
+// Total lamports the message moves out of `from` through system program instructions
+pub fn outgoing_lamports(message: &Message, from: &Pubkey) -> u64 {
+    sol_transfers(message)
+        .iter()
+        .filter(|transfer| transfer.from == *from)
+        .fold(0u64, |total, transfer| total.saturating_add(transfer.lamports))
+}
+
+// Whether `signer` takes part in any token program instruction. Besides transfers, approving a
+// delegate, burning, closing an account or handing over an authority all move its tokens, and
+// none of them has an amount comparable to lamports.
+pub fn authorizes_tokens(message: &Message, signer: &Pubkey) -> bool {
+    message.instructions.iter().any(|instruction| {
+        message.program(instruction).is_some_and(|program| is_token_program(&program))
+            && message.involves(instruction, signer)
+    })
+}
+
+// The handovers `signer` takes part in, as the account, its base or its owner
+pub fn handovers(message: &Message, signer: &Pubkey) -> Vec<Handover> {
+    let mut handovers = Vec::new();
+
+    for instruction in &message.instructions {
+        let Some(program_id) = message.program(instruction) else {
+            continue;
+        };
+        if !message.involves(instruction, signer) {
+            continue;
+        }
+
+        let handover = if program_id == SYSTEM_PROGRAM_ID {
+            match u32_at(&instruction.data, 0) {
+                Some(SYSTEM_ASSIGN) | Some(SYSTEM_ASSIGN_WITH_SEED) => Some(Handover::Assign),
+                Some(SYSTEM_ALLOCATE) | Some(SYSTEM_ALLOCATE_WITH_SEED) => Some(Handover::Allocate),
+                _ => None,
+            }
+        } else if is_token_program(&program_id) {
+            match instruction.data.first() {
+                Some(&TOKEN_APPROVE) | Some(&TOKEN_APPROVE_CHECKED) => Some(Handover::TokenApprove),
+                Some(&TOKEN_SET_AUTHORITY) => Some(Handover::TokenSetAuthority),
+                Some(&TOKEN_CLOSE_ACCOUNT) => Some(Handover::TokenCloseAccount),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        handovers.extend(handover);
+    }
+
+    handovers
+}
+
+pub fn token_transfers(message: &Message) -> Vec<TokenTransfer> {
+    let mut transfers = Vec::new();
+
+    for instruction in &message.instructions {
+        let Some(token_program) = message.program(instruction).filter(is_token_program) else {
+            continue;
+        };
+
+        let account = |index: usize| message.account(instruction, index);
+        let amount = u64_at(&instruction.data, 1);
+
+        let transfer = match (instruction.data.first(), amount) {
+            (Some(&TOKEN_TRANSFER), Some(amount)) => account(0)
+                .zip(account(1))
+                .zip(account(2))
+                .map(|((source, destination), authority)| TokenTransfer {
+                    token_program,
+                    source,
+                    mint: None,
+                    destination,
+                    authority,
+                    amount,
+                }),
+            (Some(&TOKEN_TRANSFER_CHECKED), Some(amount)) => account(0)
+                .zip(account(1))
+                .zip(account(2))
+                .zip(account(3))
+                .map(|(((source, mint), destination), authority)| TokenTransfer {
+                    token_program,
+                    source,
+                    mint: Some(mint),
+                    destination,
+                    authority,
+                    amount,
+                }),
+            _ => None,
+        };
+
+        transfers.extend(transfer);
+    }
+
+    transfers
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use alloc::vec;
+
+    use solana_instruction::{AccountMeta, Instruction};
+    use solana_system_interface::instruction as system;
+
+    use super::*;
+
+    pub(crate) fn key(n: u8) -> Pubkey {
+        Pubkey::new_from_array([n; 32])
+    }
+
+    // Account keys in order of appearance, which is all the inspection cares about
+    pub(crate) fn message(instructions: &[Instruction]) -> Message {
+        let mut account_keys = Vec::new();
+        let mut index = |key: Pubkey| match account_keys.iter().position(|k| *k == key) {
+            Some(position) => position as u8,
+            None => {
+                account_keys.push(key);
+                (account_keys.len() - 1) as u8
+            }
+        };
+        let instructions = instructions
+            .iter()
+            .map(|instruction| CompiledInstruction {
+                program_id_index: index(instruction.program_id),
+                accounts: instruction.accounts.iter().map(|account| index(account.pubkey)).collect(),
+                data: instruction.data.clone(),
+            })
+            .collect();
+        Message { account_keys, instructions }
+    }
+
+    pub(crate) fn token(program: Pubkey, tag: u8, accounts: &[Pubkey], amount: u64) -> Instruction {
+        let mut data = vec![tag];
+        data.extend_from_slice(&amount.to_le_bytes());
+        data.push(6);
+        let accounts = accounts.iter().map(|&account| AccountMeta::new(account, false)).collect();
+        Instruction { program_id: program, accounts, data }
+    }
+
+    fn transfer(from: Pubkey, to: Pubkey, lamports: u64) -> SolTransfer {
+        SolTransfer { from, to, lamports }
+    }
+
+    #[test]
+    fn system_program_id() {
+        assert_eq!(SYSTEM_PROGRAM_ID, solana_system_interface::program::ID);
+    }
+
+    // Every SystemInstruction, with the wallet as the account that signs it
+    #[test]
+    fn system_instructions() {
+        let (wallet, other, owner) = (key(1), key(2), key(3));
+        let (nonce, seed) = (key(4), "seed");
+
+        type Case = (&'static str, Vec<Instruction>, Vec<SolTransfer>, Vec<Handover>);
+        let cases: Vec<Case> = vec![
+            (
+                "CreateAccount",
+                vec![system::create_account(&wallet, &other, 10, 0, &owner)],
+                vec![transfer(wallet, other, 10)],
+                vec![],
+            ),
+            ("Assign", vec![system::assign(&wallet, &owner)], vec![], vec![Handover::Assign]),
+            ("Transfer", vec![system::transfer(&wallet, &other, 20)], vec![transfer(wallet, other, 20)], vec![]),
+            (
+                "CreateAccountWithSeed",
+                vec![system::create_account_with_seed(&wallet, &other, &wallet, seed, 30, 0, &owner)],
+                vec![transfer(wallet, other, 30)],
+                vec![],
+            ),
+            ("AdvanceNonceAccount", vec![system::advance_nonce_account(&nonce, &wallet)], vec![], vec![]),
+            // Counted against the nonce authority that signs it
+            (
+                "WithdrawNonceAccount",
+                vec![system::withdraw_nonce_account(&nonce, &wallet, &other, 40)],
+                vec![transfer(wallet, other, 40)],
+                vec![],
+            ),
+            // CreateAccount and InitializeNonceAccount
+            (
+                "InitializeNonceAccount",
+                system::create_nonce_account(&wallet, &nonce, &wallet, 50),
+                vec![transfer(wallet, nonce, 50)],
+                vec![],
+            ),
+            ("AuthorizeNonceAccount", vec![system::authorize_nonce_account(&nonce, &wallet, &other)], vec![], vec![]),
+            ("Allocate", vec![system::allocate(&wallet, 100)], vec![], vec![Handover::Allocate]),
+            (
+                "AllocateWithSeed",
+                vec![system::allocate_with_seed(&other, &wallet, seed, 100, &owner)],
+                vec![],
+                vec![Handover::Allocate],
+            ),
+            (
+                "AssignWithSeed",
+                vec![system::assign_with_seed(&other, &wallet, seed, &owner)],
+                vec![],
+                vec![Handover::Assign],
+            ),
+            // Out of the account derived from the wallet, on the wallet's signature
+            (
+                "TransferWithSeed",
+                vec![system::transfer_with_seed(&other, &wallet, seed.into(), &owner, &nonce, 60)],
+                vec![transfer(wallet, nonce, 60)],
+                vec![],
+            ),
+            ("UpgradeNonceAccount", vec![system::upgrade_nonce_account(nonce)], vec![], vec![]),
+        ];
+
+        for (name, instructions, transfers, wallet_handovers) in cases {
+            let message = message(&instructions);
+            assert_eq!(sol_transfers(&message), transfers, "{}", name);
+            assert_eq!(handovers(&message, &wallet), wallet_handovers, "{}", name);
+            assert_eq!(invoked_programs(&message), vec![SYSTEM_PROGRAM_ID], "{}", name);
+            assert!(token_transfers(&message).is_empty(), "{}", name);
+            assert!(!authorizes_tokens(&message, &wallet), "{}", name);
+        }
+    }
+
+    #[test]
+    fn outgoing_lamports_adds_every_way_out() {
+        let (wallet, other) = (key(1), key(2));
+        let message = message(&[
+            system::transfer(&wallet, &other, 1),
+            system::create_account(&wallet, &other, 2, 0, &other),
+            system::transfer_with_seed(&other, &wallet, "seed".into(), &other, &other, 4),
+            system::withdraw_nonce_account(&other, &wallet, &other, 8),
+            // Incoming
+            system::transfer(&other, &wallet, 16),
+        ]);
+        assert_eq!(outgoing_lamports(&message, &wallet), 15);
+        assert_eq!(outgoing_lamports(&message, &other), 16);
+
+        let message = self::message(&[
+            system::transfer(&wallet, &other, u64::MAX),
+            system::transfer(&wallet, &other, 1),
+        ]);
+        assert_eq!(outgoing_lamports(&message, &wallet), u64::MAX);
+    }
+
+    #[test]
+    fn handovers_need_the_signer() {
+        let (wallet, other) = (key(1), key(2));
+        let message = message(&[
+            system::assign(&other, &other),
+            token(TOKEN_PROGRAM_ID, TOKEN_APPROVE, &[other, key(3), other], 1),
+        ]);
+        assert!(handovers(&message, &wallet).is_empty());
+        assert_eq!(handovers(&message, &other), vec![Handover::Assign, Handover::TokenApprove]);
+    }
+
+    // Every instruction tag of both token programs, Token-2022's extensions included, with the
+    // wallet as the last account
+    #[test]
+    fn token_instructions() {
+        let (source, mint, destination, wallet) = (key(10), key(11), key(12), key(1));
+        for program in [TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID] {
+            for tag in 0..=45 {
+                let message = message(&[token(program, tag, &[source, mint, destination, wallet], 7)]);
+                let transfers = token_transfers(&message);
+                assert_eq!(transfers.len(), [TOKEN_TRANSFER, TOKEN_TRANSFER_CHECKED].contains(&tag) as usize);
+                let expected = match tag {
+                    TOKEN_APPROVE | TOKEN_APPROVE_CHECKED => vec![Handover::TokenApprove],
+                    TOKEN_SET_AUTHORITY => vec![Handover::TokenSetAuthority],
+                    TOKEN_CLOSE_ACCOUNT => vec![Handover::TokenCloseAccount],
+                    _ => vec![],
+                };
+                assert_eq!(handovers(&message, &wallet), expected, "tag {}", tag);
+                assert!(authorizes_tokens(&message, &wallet), "tag {}", tag);
+                assert!(!authorizes_tokens(&message, &key(2)), "tag {}", tag);
+                assert!(sol_transfers(&message).is_empty());
+                assert_eq!(outgoing_lamports(&message, &wallet), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn token_transfer_accounts() {
+        let (source, mint, destination, wallet) = (key(10), key(11), key(12), key(1));
+        let message = message(&[
+            token(TOKEN_PROGRAM_ID, TOKEN_TRANSFER, &[source, destination, wallet], 5),
+            token(TOKEN_2022_PROGRAM_ID, TOKEN_TRANSFER_CHECKED, &[source, mint, destination, wallet], 6),
+        ]);
+        assert_eq!(
+            token_transfers(&message),
+            vec![
+                TokenTransfer {
+                    token_program: TOKEN_PROGRAM_ID,
+                    source,
+                    mint: None,
+                    destination,
+                    authority: wallet,
+                    amount: 5,
+                },
+                TokenTransfer {
+                    token_program: TOKEN_2022_PROGRAM_ID,
+                    source,
+                    mint: Some(mint),
+                    destination,
+                    authority: wallet,
+                    amount: 6,
+                },
+            ]
+        );
+        assert_eq!(invoked_programs(&message), vec![TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID]);
+    }
+
+    #[test]
+    fn malformed_instructions_are_skipped() {
+        let (wallet, other) = (key(1), key(2));
+        let mut truncated = system::transfer(&wallet, &other, 1);
+        truncated.data.truncate(8);
+        let mut short_token = token(TOKEN_PROGRAM_ID, TOKEN_TRANSFER, &[other, other, wallet], 1);
+        short_token.data.truncate(5);
+        let missing_accounts = token(TOKEN_PROGRAM_ID, TOKEN_TRANSFER_CHECKED, &[other, other, wallet], 1);
+        let mut message = message(&[truncated, short_token, missing_accounts]);
+        // Indices past the account keys
+        message.instructions.push(CompiledInstruction {
+            program_id_index: 0,
+            accounts: vec![200, 201],
+            data: system::transfer(&wallet, &other, 1).data,
+        });
+        message.instructions.push(CompiledInstruction {
+            program_id_index: 200,
+            accounts: vec![],
+            data: vec![],
+        });
+
+        assert!(sol_transfers(&message).is_empty());
+        assert!(token_transfers(&message).is_empty());
+    }
+
+    // A seed length running past the data, or past usize
+    #[test]
+    fn seed_lengths() {
+        let (wallet, other) = (key(1), key(2));
+        let instruction = system::create_account_with_seed(&wallet, &other, &wallet, "seed", 9, 0, &other);
+        for seed_len in [100, u64::MAX] {
+            let mut instruction = instruction.clone();
+            instruction.data[36..44].copy_from_slice(&u64::to_le_bytes(seed_len));
+            assert!(sol_transfers(&message(&[instruction])).is_empty());
+        }
+    }
+}
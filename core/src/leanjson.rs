@@ -0,0 +1,260 @@
+// Reads single values out of a raw JSON response by their path, e.g. `["result", "value",
+// "blockhash"]`, with indexes into arrays as numbers, `["result", "value", "0", "err"]`. Nothing
+// is parsed beyond what the path passes and nothing is allocated, where serde_json builds a
+// Value tree of the whole response and copies every string into it. Meant for the few responses
+// the device reads all the time, not as a validating parser: malformed input reads as an error
+// or a missing value, and strings with escapes are skipped over but not decoded.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+
+// The raw bytes of the value at `path`, None if some part of the path isn't there
+pub fn find<'a>(json: &'a [u8], path: &[&str]) -> Result<Option<&'a [u8]>, String> {
+    let start = skip_whitespace(json, 0);
+    let mut value = &json[start..skip_value(json, start)?];
+    for step in path {
+        let next = match value.first() {
+            Some(b'{') => member(value, step)?,
+            Some(b'[') => {
+                let index = step
+                    .parse()
+                    .map_err(|_| format!("JSON path: {} is not an array index", step))?;
+                element(value, index)?
+            }
+            _ => None,
+        };
+        match next {
+            Some(next) => value = next,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(value))
+}
+
+// A string without escapes, which covers base58 and base64 and the status words nodes send
+pub fn as_str(value: &[u8]) -> Option<&str> {
+    let inner = value.strip_prefix(b"\"")?.strip_suffix(b"\"")?;
+    if inner.contains(&b'\\') {
+        return None;
+    }
+    core::str::from_utf8(inner).ok()
+}
+
+pub fn as_u64(value: &[u8]) -> Option<u64> {
+    core::str::from_utf8(value).ok()?.parse().ok()
+}
+
+pub fn is_null(value: &[u8]) -> bool {
+    value == b"null"
+}
+
+pub fn str_at<'a>(json: &'a [u8], path: &[&str]) -> Result<Option<&'a str>, String> {
+    Ok(find(json, path)?.and_then(as_str))
+}
+
+pub fn u64_at(json: &[u8], path: &[&str]) -> Result<Option<u64>, String> {
+    Ok(find(json, path)?.and_then(as_u64))
+}
+
+fn member<'a>(object: &'a [u8], key: &str) -> Result<Option<&'a [u8]>, String> {
+    let mut pos = skip_whitespace(object, 1);
+    if object.get(pos) == Some(&b'}') {
+        return Ok(None);
+    }
+    loop {
+        let key_end = skip_string(object, pos)?;
+        let name = &object[pos + 1..key_end - 1];
+        pos = skip_whitespace(object, key_end);
+        expect(object, pos, b':')?;
+        let start = skip_whitespace(object, pos + 1);
+        let end = skip_value(object, start)?;
+        if name == key.as_bytes() {
+            return Ok(Some(&object[start..end]));
+        }
+        pos = skip_whitespace(object, end);
+        match object.get(pos) {
+            Some(b',') => pos = skip_whitespace(object, pos + 1),
+            Some(b'}') => return Ok(None),
+            _ => return Err(unexpected(object, pos)),
+        }
+    }
+}
+
+fn element(array: &[u8], index: usize) -> Result<Option<&[u8]>, String> {
+    let mut pos = skip_whitespace(array, 1);
+    if array.get(pos) == Some(&b']') {
+        return Ok(None);
+    }
+    let mut current = 0;
+    loop {
+        let end = skip_value(array, pos)?;
+        if current == index {
+            return Ok(Some(&array[pos..end]));
+        }
+        current += 1;
+        pos = skip_whitespace(array, end);
+        match array.get(pos) {
+            Some(b',') => pos = skip_whitespace(array, pos + 1),
+            Some(b']') => return Ok(None),
+            _ => return Err(unexpected(array, pos)),
+        }
+    }
+}
+
+// The position right after the value starting at `pos`
+fn skip_value(json: &[u8], pos: usize) -> Result<usize, String> {
+    match json.get(pos) {
+        Some(b'"') => skip_string(json, pos),
+        Some(b'{') | Some(b'[') => skip_nested(json, pos),
+        Some(_) => {
+            // Numbers, true, false and null run up to the next delimiter
+            let len = json[pos..]
+                .iter()
+                .position(|byte| matches!(byte, b',' | b'}' | b']') || byte.is_ascii_whitespace())
+                .unwrap_or(json.len() - pos);
+            match len {
+                0 => Err(unexpected(json, pos)),
+                len => Ok(pos + len),
+            }
+        }
+        None => Err(unexpected(json, pos)),
+    }
+}
+
+fn skip_string(json: &[u8], pos: usize) -> Result<usize, String> {
+    expect(json, pos, b'"')?;
+    let mut escaped = false;
+    for (offset, &byte) in json[pos + 1..].iter().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'"' => return Ok(pos + offset + 2),
+            _ => {}
+        }
+    }
+    Err(unexpected(json, json.len()))
+}
+
+// Objects and arrays are only counted through, brackets inside strings don't count
+fn skip_nested(json: &[u8], pos: usize) -> Result<usize, String> {
+    let mut depth = 0usize;
+    let mut index = pos;
+    while index < json.len() {
+        match json[index] {
+            b'"' => {
+                index = skip_string(json, index)?;
+                continue;
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(index + 1);
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    Err(unexpected(json, json.len()))
+}
+
+fn skip_whitespace(json: &[u8], pos: usize) -> usize {
+    pos + json[pos.min(json.len())..]
+        .iter()
+        .take_while(|byte| byte.is_ascii_whitespace())
+        .count()
+}
+
+fn expect(json: &[u8], pos: usize, byte: u8) -> Result<(), String> {
+    match json.get(pos) == Some(&byte) {
+        true => Ok(()),
+        false => Err(unexpected(json, pos)),
+    }
+}
+
+fn unexpected(json: &[u8], pos: usize) -> String {
+    match json.get(pos) {
+        Some(byte) => format!("JSON: unexpected '{}' at {}", *byte as char, pos),
+        None => "JSON: unexpected end".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &[u8] = br#" {"jsonrpc": "2.0", "result": {"context": {"slot": 7},
+        "value": [{"err": null, "confirmationStatus": "confirmed", "note": "a \"quoted\" ]}"},
+        {"err": {"InstructionError": [0, "Custom"]}}]}, "id": 1} "#;
+
+    #[test]
+    fn finds_along_the_path() {
+        assert_eq!(u64_at(RESPONSE, &["result", "context", "slot"]), Ok(Some(7)));
+        assert_eq!(str_at(RESPONSE, &["result", "value", "0", "confirmationStatus"]), Ok(Some("confirmed")));
+        assert_eq!(find(RESPONSE, &["result", "value", "0", "err"]), Ok(Some(&b"null"[..])));
+        assert_eq!(
+            find(RESPONSE, &["result", "value", "1", "err"]),
+            Ok(Some(&br#"{"InstructionError": [0, "Custom"]}"#[..]))
+        );
+        assert_eq!(str_at(RESPONSE, &["result", "value", "1", "err", "InstructionError", "1"]), Ok(Some("Custom")));
+        assert_eq!(u64_at(RESPONSE, &["id"]), Ok(Some(1)));
+    }
+
+    #[test]
+    fn missing_is_none() {
+        assert_eq!(find(RESPONSE, &["error"]), Ok(None));
+        assert_eq!(find(RESPONSE, &["result", "value", "2"]), Ok(None));
+        // Past a scalar
+        assert_eq!(find(RESPONSE, &["id", "more"]), Ok(None));
+        assert_eq!(find(b"{}", &["a"]), Ok(None));
+        assert_eq!(find(b"[]", &["0"]), Ok(None));
+    }
+
+    #[test]
+    fn escaped_strings_are_skipped_not_decoded() {
+        // The bracket and quotes inside the string don't end the object
+        assert_eq!(find(RESPONSE, &["result", "value", "0", "note"]), Ok(Some(&br#""a \"quoted\" ]}""#[..])));
+        assert_eq!(str_at(RESPONSE, &["result", "value", "0", "note"]), Ok(None));
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(as_u64(b"18446744073709551615"), Some(u64::MAX));
+        assert_eq!(as_u64(b"18446744073709551616"), None);
+        assert_eq!(as_u64(b"-1"), None);
+        assert_eq!(as_u64(b"1.5"), None);
+        assert_eq!(as_str(b"\"abc\""), Some("abc"));
+        assert_eq!(as_str(b"abc"), None);
+        assert!(is_null(b"null"));
+        assert!(!is_null(b"\"null\""));
+    }
+
+    #[test]
+    fn path_index_must_be_a_number() {
+        assert!(find(RESPONSE, &["result", "value", "first"]).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        for json in [
+            &b""[..],
+            b"   ",
+            br#"{"result""#,
+            br#"{"result":"#,
+            br#"{"result":}"#,
+            br#"{"result" 1}"#,
+            br#"{"result":1 "id":2}"#,
+            br#"{"result":1,}"#,
+            br#"{"result":"unterminated}"#,
+            br#"{"result":[1,2"#,
+            br#"{result:1}"#,
+        ] {
+            assert!(find(json, &["id"]).is_err(), "{:?} read", core::str::from_utf8(json));
+        }
+        // What comes before the fault still reads
+        assert_eq!(find(br#"{"result":1 "id":2}"#, &["result"]), Ok(Some(&b"1"[..])));
+        assert!(find(b"[1 2]", &["1"]).is_err());
+        assert!(find(b"[1,]", &["1"]).is_err());
+    }
+}
@@ -0,0 +1,25 @@
+#![no_std]
+
+// The Solana side of REsp32Sol that needs neither std nor ESP-IDF: the JSON-RPC requests, the
+// exchange with a node over any transport and what is read out of the responses, the wire
+// format of legacy transactions, and amounts in SOL and token units. Firmware on esp-hal or
+// another MCU's HAL brings an allocator and an HTTP client and reuses the rest. Alongside sits
+// the device's own logic that needs no hardware, kept here to be tested on the host: frames of
+// split payloads, the setup portal's form fields, static IP settings, and what the spending
+// policy reads out of a message and allows.
+// The ESP-IDF firmware uses it through `resp32sol::solrpc`, which adds ESP-IDF's HTTP
+// client as the transport and the buffers, retries and rate limit around each call.
+
+extern crate alloc;
+
+pub mod amount;
+pub mod client;
+pub mod form;
+pub mod frag;
+pub mod inspect;
+pub mod leanjson;
+pub mod policy;
+pub mod rpc;
+pub mod spend;
+pub mod static_ip;
+pub mod wire;
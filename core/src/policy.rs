@@ -0,0 +1,455 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+
+use serde_json::{json, Value};
+use solana_address::Address as Pubkey;
+
+use crate::inspect::{handovers, invoked_programs, outgoing_lamports, sol_transfers, token_transfers, Handover, Message};
+use crate::spend::SpendBuckets;
+
+const HOUR: Duration = Duration::from_secs(3600);
+const DAY: Duration = Duration::from_secs(24 * 3600);
+
+// Limits enforced before every signature, a missing limit or list means unrestricted.
+// Stored as JSON: {"max_tx":..,"max_hour":..,"max_day":..,"programs":[..],"mints":[..],"recipients":[..]}
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpendingPolicy {
+    pub max_lamports_per_tx: Option<u64>,
+    pub max_lamports_per_hour: Option<u64>,
+    pub max_lamports_per_day: Option<u64>,
+    pub allowed_programs: Option<Vec<Pubkey>>,
+    pub allowed_mints: Option<Vec<Pubkey>>,
+    // Wallets (or token accounts) the device key may send SOL and tokens to, a wallet
+    // also covers its associated token accounts
+    pub allowed_recipients: Option<Vec<Pubkey>>,
+}
+
+impl SpendingPolicy {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(json.trim()).map_err(|e| format!("Policy parse: {:?}", e))?;
+
+        let limit = |field: &str| match &value[field] {
+            Value::Null => Ok(None),
+            limit => limit
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| format!("Policy '{}' must be a number of lamports", field)),
+        };
+        let pubkeys = |field: &str| match &value[field] {
+            Value::Null => Ok(None),
+            Value::Array(items) => items
+                .iter()
+                .map(|item| {
+                    item.as_str()
+                        .and_then(|s| Pubkey::from_str(s).ok())
+                        .ok_or_else(|| format!("Policy '{}' contains an invalid pubkey", field))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Some),
+            _ => Err(format!("Policy '{}' must be a list of pubkeys", field)),
+        };
+
+        Ok(Self {
+            max_lamports_per_tx: limit("max_tx")?,
+            max_lamports_per_hour: limit("max_hour")?,
+            max_lamports_per_day: limit("max_day")?,
+            allowed_programs: pubkeys("programs")?,
+            allowed_mints: pubkeys("mints")?,
+            allowed_recipients: pubkeys("recipients")?,
+        })
+    }
+
+    // Nothing to enforce, every field missing
+    pub fn is_unrestricted(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn to_json(&self) -> String {
+        let pubkeys = |list: &Option<Vec<Pubkey>>| {
+            list.as_ref()
+                .map(|list| list.iter().map(|pubkey| pubkey.to_string()).collect::<Vec<_>>())
+        };
+
+        json!({
+            "max_tx": self.max_lamports_per_tx,
+            "max_hour": self.max_lamports_per_hour,
+            "max_day": self.max_lamports_per_day,
+            "programs": pubkeys(&self.allowed_programs),
+            "mints": pubkeys(&self.allowed_mints),
+            "recipients": pubkeys(&self.allowed_recipients),
+        })
+        .to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDenied {
+    TransactionLimit { lamports: u64, limit: u64 },
+    HourlyLimit { lamports: u64, spent: u64, limit: u64 },
+    DailyLimit { lamports: u64, spent: u64, limit: u64 },
+    ProgramNotAllowed(Pubkey),
+    MintNotAllowed(Pubkey),
+    RecipientNotAllowed(Pubkey),
+    // Hourly and daily limits need wall clock time, which is unknown until SNTP has synced
+    ClockNotSynced,
+    // A plain token Transfer doesn't name its mint, so it can't be checked against the mint list
+    UncheckedTokenTransfer,
+    // Gives the wallet or a token account away, which no limit or list could account for
+    Handover(Handover),
+}
+
+impl fmt::Display for PolicyDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyDenied::TransactionLimit { lamports, limit } => {
+                write!(f, "Transfer of {} lamports exceeds the per-transaction limit of {}", lamports, limit)
+            }
+            PolicyDenied::HourlyLimit { lamports, spent, limit } => write!(
+                f,
+                "Transfer of {} lamports exceeds the hourly limit of {} ({} already spent)",
+                lamports, limit, spent
+            ),
+            PolicyDenied::DailyLimit { lamports, spent, limit } => write!(
+                f,
+                "Transfer of {} lamports exceeds the daily limit of {} ({} already spent)",
+                lamports, limit, spent
+            ),
+            PolicyDenied::ProgramNotAllowed(program) => write!(f, "Program {} is not allowed", program),
+            PolicyDenied::MintNotAllowed(mint) => write!(f, "Token mint {} is not allowed", mint),
+            PolicyDenied::RecipientNotAllowed(recipient) => write!(f, "Recipient {} is not allowed", recipient),
+            PolicyDenied::ClockNotSynced => write!(f, "Spend limits need the clock, which is not synced"),
+            PolicyDenied::UncheckedTokenTransfer => {
+                write!(f, "Token transfers must use TransferChecked while mints are restricted")
+            }
+            PolicyDenied::Handover(handover) => {
+                write!(f, "{} by the device key is refused while spending is restricted", handover)
+            }
+        }
+    }
+}
+
+// Checks the message against the policy, the hourly and daily limits against what `spent` holds
+// at `now`, which is None while the clock isn't trusted. Returns the lamports the message moves
+// out of `signer` for the caller to record. Deriving associated token accounts needs the curve,
+// which the caller brings as `associated_token_address(owner, mint, token_program)`.
+pub fn evaluate(
+    policy: &SpendingPolicy,
+    message: &Message,
+    signer: &Pubkey,
+    spent: &SpendBuckets,
+    now: Option<u64>,
+    associated_token_address: impl Fn(&Pubkey, &Pubkey, &Pubkey) -> Pubkey,
+) -> Result<u64, PolicyDenied> {
+    if let Some(allowed) = &policy.allowed_programs {
+        if let Some(program) = invoked_programs(message).into_iter().find(|p| !allowed.contains(p)) {
+            return Err(PolicyDenied::ProgramNotAllowed(program));
+        }
+    }
+
+    // Assigning the wallet to another program or delegating its tokens would get around
+    // every limit and list below
+    if !policy.is_unrestricted() {
+        if let Some(handover) = handovers(message, signer).into_iter().next() {
+            return Err(PolicyDenied::Handover(handover));
+        }
+    }
+
+    if let Some(allowed) = &policy.allowed_mints {
+        for transfer in token_transfers(message) {
+            match transfer.mint {
+                Some(mint) if !allowed.contains(&mint) => return Err(PolicyDenied::MintNotAllowed(mint)),
+                Some(_) => {}
+                None => return Err(PolicyDenied::UncheckedTokenTransfer),
+            }
+        }
+    }
+
+    if let Some(allowed) = &policy.allowed_recipients {
+        for transfer in sol_transfers(message).iter().filter(|t| t.from == *signer) {
+            if !allowed.contains(&transfer.to) {
+                return Err(PolicyDenied::RecipientNotAllowed(transfer.to));
+            }
+        }
+
+        for transfer in token_transfers(message).iter().filter(|t| t.authority == *signer) {
+            let allowed_account = allowed.iter().any(|recipient| {
+                *recipient == transfer.destination
+                    || transfer.mint.is_some_and(|mint| {
+                        associated_token_address(recipient, &mint, &transfer.token_program) == transfer.destination
+                    })
+            });
+            if !allowed_account {
+                return Err(PolicyDenied::RecipientNotAllowed(transfer.destination));
+            }
+        }
+    }
+
+    let lamports = outgoing_lamports(message, signer);
+    if lamports == 0 {
+        return Ok(0);
+    }
+
+    if let Some(limit) = policy.max_lamports_per_tx {
+        if lamports > limit {
+            return Err(PolicyDenied::TransactionLimit { lamports, limit });
+        }
+    }
+    let windowed = policy.max_lamports_per_hour.is_some() || policy.max_lamports_per_day.is_some();
+    let now = match now {
+        Some(now) => now,
+        None if windowed => return Err(PolicyDenied::ClockNotSynced),
+        None => return Ok(lamports),
+    };
+
+    if let Some(limit) = policy.max_lamports_per_hour {
+        let spent = spent.spent_within(HOUR, now);
+        if spent.saturating_add(lamports) > limit {
+            return Err(PolicyDenied::HourlyLimit { lamports, spent, limit });
+        }
+    }
+    if let Some(limit) = policy.max_lamports_per_day {
+        let spent = spent.spent_within(DAY, now);
+        if spent.saturating_add(lamports) > limit {
+            return Err(PolicyDenied::DailyLimit { lamports, spent, limit });
+        }
+    }
+
+    Ok(lamports)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use solana_instruction::Instruction;
+    use solana_system_interface::instruction as system;
+
+    use super::*;
+    use crate::inspect::tests::{key, message, token};
+    use crate::inspect::{SYSTEM_PROGRAM_ID, TOKEN_2022_PROGRAM_ID, TOKEN_PROGRAM_ID};
+
+    const NOW: u64 = 1_750_000_200;
+
+    // Stands in for the derivation, which needs the curve
+    fn ata(owner: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+        let mut address = [0u8; 32];
+        for (i, byte) in address.iter_mut().enumerate() {
+            *byte = owner.as_ref()[i] ^ mint.as_ref()[i].rotate_left(1) ^ token_program.as_ref()[i].rotate_left(2);
+        }
+        Pubkey::new_from_array(address)
+    }
+
+    fn check(policy: &SpendingPolicy, instructions: &[Instruction]) -> Result<u64, PolicyDenied> {
+        evaluate(policy, &message(instructions), &key(1), &SpendBuckets::default(), Some(NOW), ata)
+    }
+
+    fn restricted() -> SpendingPolicy {
+        SpendingPolicy {
+            max_lamports_per_tx: Some(u64::MAX),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn json() {
+        let policy = SpendingPolicy {
+            max_lamports_per_tx: Some(1),
+            max_lamports_per_hour: None,
+            max_lamports_per_day: Some(3),
+            allowed_programs: Some(vec![SYSTEM_PROGRAM_ID]),
+            allowed_mints: Some(vec![]),
+            allowed_recipients: Some(vec![key(2), key(3)]),
+        };
+        assert_eq!(SpendingPolicy::from_json(&policy.to_json()), Ok(policy));
+        assert_eq!(SpendingPolicy::from_json(" {} "), Ok(SpendingPolicy::default()));
+        assert!(SpendingPolicy::default().is_unrestricted());
+
+        assert!(SpendingPolicy::from_json(r#"{"max_tx":-1}"#).is_err());
+        assert!(SpendingPolicy::from_json(r#"{"max_day":"5"}"#).is_err());
+        assert!(SpendingPolicy::from_json(r#"{"programs":"11111111111111111111111111111111"}"#).is_err());
+        assert!(SpendingPolicy::from_json(r#"{"mints":["not a pubkey"]}"#).is_err());
+        assert!(SpendingPolicy::from_json("[").is_err());
+    }
+
+    #[test]
+    fn unrestricted_allows_everything() {
+        let wallet = key(1);
+        let instructions = [
+            system::transfer(&wallet, &key(2), 5),
+            system::assign(&wallet, &key(3)),
+            token(TOKEN_PROGRAM_ID, 6, &[key(4), wallet], 0),
+        ];
+        assert_eq!(check(&SpendingPolicy::default(), &instructions), Ok(5));
+    }
+
+    #[test]
+    fn programs() {
+        let policy = SpendingPolicy {
+            allowed_programs: Some(vec![SYSTEM_PROGRAM_ID]),
+            ..Default::default()
+        };
+        assert_eq!(check(&policy, &[system::transfer(&key(1), &key(2), 5)]), Ok(5));
+        let instruction = token(TOKEN_PROGRAM_ID, 3, &[key(4), key(5), key(1)], 1);
+        assert_eq!(check(&policy, &[instruction]), Err(PolicyDenied::ProgramNotAllowed(TOKEN_PROGRAM_ID)));
+    }
+
+    // Every way of giving the wallet or its token accounts away is refused once anything is
+    // restricted, and left alone when it doesn't involve the wallet
+    #[test]
+    fn handovers() {
+        let (wallet, other) = (key(1), key(2));
+        let cases = [
+            (system::assign(&wallet, &other), Handover::Assign),
+            (system::assign_with_seed(&other, &wallet, "seed", &other), Handover::Assign),
+            (system::allocate(&wallet, 10), Handover::Allocate),
+            (system::allocate_with_seed(&other, &wallet, "seed", 10, &other), Handover::Allocate),
+            (token(TOKEN_PROGRAM_ID, 4, &[other, other, wallet], 1), Handover::TokenApprove),
+            (token(TOKEN_2022_PROGRAM_ID, 13, &[other, other, other, wallet], 1), Handover::TokenApprove),
+            (token(TOKEN_PROGRAM_ID, 6, &[other, wallet], 0), Handover::TokenSetAuthority),
+            (token(TOKEN_2022_PROGRAM_ID, 9, &[other, other, wallet], 0), Handover::TokenCloseAccount),
+        ];
+        for (instruction, handover) in cases {
+            assert_eq!(check(&restricted(), &[instruction]), Err(PolicyDenied::Handover(handover)));
+        }
+
+        assert_eq!(check(&restricted(), &[system::assign(&other, &other)]), Ok(0));
+        let instruction = token(TOKEN_PROGRAM_ID, 4, &[other, other, other], 1);
+        assert_eq!(check(&restricted(), &[instruction]), Ok(0));
+    }
+
+    #[test]
+    fn mints() {
+        let (wallet, mint) = (key(1), key(7));
+        let policy = SpendingPolicy {
+            allowed_mints: Some(vec![mint]),
+            ..Default::default()
+        };
+        let checked = |mint: Pubkey| token(TOKEN_PROGRAM_ID, 12, &[key(4), mint, key(5), wallet], 1);
+
+        assert_eq!(check(&policy, &[checked(mint)]), Ok(0));
+        assert_eq!(check(&policy, &[checked(key(8))]), Err(PolicyDenied::MintNotAllowed(key(8))));
+        let unchecked = token(TOKEN_2022_PROGRAM_ID, 3, &[key(4), key(5), wallet], 1);
+        assert_eq!(check(&policy, &[unchecked]), Err(PolicyDenied::UncheckedTokenTransfer));
+    }
+
+    // Recipients are checked on every instruction that moves lamports out of the wallet
+    #[test]
+    fn sol_recipients() {
+        let (wallet, allowed, other, nonce) = (key(1), key(2), key(3), key(4));
+        let policy = SpendingPolicy {
+            allowed_recipients: Some(vec![allowed]),
+            ..Default::default()
+        };
+        let to = |to: Pubkey| {
+            [
+                system::transfer(&wallet, &to, 1),
+                system::create_account(&wallet, &to, 1, 0, &other),
+                system::create_account_with_seed(&wallet, &to, &wallet, "seed", 1, 0, &other),
+                system::transfer_with_seed(&nonce, &wallet, "seed".into(), &other, &to, 1),
+                system::withdraw_nonce_account(&nonce, &wallet, &to, 1),
+            ]
+        };
+
+        for instruction in to(allowed) {
+            assert_eq!(check(&policy, &[instruction]), Ok(1));
+        }
+        for instruction in to(other) {
+            assert_eq!(check(&policy, &[instruction]), Err(PolicyDenied::RecipientNotAllowed(other)));
+        }
+        // Someone else's transfer in the same message is theirs to authorize
+        assert_eq!(check(&policy, &[system::transfer(&nonce, &other, 1)]), Ok(0));
+    }
+
+    #[test]
+    fn token_recipients() {
+        let (wallet, allowed, mint) = (key(1), key(2), key(7));
+        let policy = SpendingPolicy {
+            allowed_recipients: Some(vec![allowed]),
+            ..Default::default()
+        };
+        let checked =
+            |program: Pubkey, destination: Pubkey| token(program, 12, &[key(4), mint, destination, wallet], 1);
+
+        for program in [TOKEN_PROGRAM_ID, TOKEN_2022_PROGRAM_ID] {
+            let associated = ata(&allowed, &mint, &program);
+            assert_eq!(check(&policy, &[checked(program, associated)]), Ok(0));
+            assert_eq!(check(&policy, &[checked(program, allowed)]), Ok(0));
+            let elsewhere = ata(&key(3), &mint, &program);
+            assert_eq!(
+                check(&policy, &[checked(program, elsewhere)]),
+                Err(PolicyDenied::RecipientNotAllowed(elsewhere))
+            );
+        }
+
+        // A plain Transfer names no mint, so only the listed account itself passes
+        let associated = ata(&allowed, &mint, &TOKEN_PROGRAM_ID);
+        let unchecked = token(TOKEN_PROGRAM_ID, 3, &[key(4), associated, wallet], 1);
+        assert_eq!(check(&policy, &[unchecked]), Err(PolicyDenied::RecipientNotAllowed(associated)));
+    }
+
+    #[test]
+    fn transaction_limit() {
+        let (wallet, other) = (key(1), key(2));
+        let policy = SpendingPolicy {
+            max_lamports_per_tx: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(check(&policy, &[system::transfer(&wallet, &other, 10)]), Ok(10));
+        // Split across instructions, including the ones that aren't a plain Transfer
+        let split = [
+            system::transfer(&wallet, &other, 4),
+            system::create_account(&wallet, &other, 4, 0, &other),
+            system::withdraw_nonce_account(&other, &wallet, &other, 3),
+        ];
+        assert_eq!(check(&policy, &split), Err(PolicyDenied::TransactionLimit { lamports: 11, limit: 10 }));
+    }
+
+    #[test]
+    fn windows() {
+        let (wallet, other) = (key(1), key(2));
+        let policy = SpendingPolicy {
+            max_lamports_per_hour: Some(100),
+            max_lamports_per_day: Some(150),
+            ..Default::default()
+        };
+        let mut spent = SpendBuckets::default();
+        spent.record(60, NOW - 2 * 3600);
+        spent.record(50, NOW - 600);
+        let send = |lamports: u64, spent: &SpendBuckets, now: Option<u64>| {
+            let message = message(&[system::transfer(&wallet, &other, lamports)]);
+            evaluate(&policy, &message, &wallet, spent, now, ata)
+        };
+
+        assert_eq!(send(40, &spent, Some(NOW)), Ok(40));
+        let hourly = PolicyDenied::HourlyLimit { lamports: 51, spent: 50, limit: 100 };
+        assert_eq!(send(51, &spent, Some(NOW)), Err(hourly));
+        let daily = PolicyDenied::DailyLimit { lamports: 41, spent: 110, limit: 150 };
+        assert_eq!(send(41, &spent, Some(NOW + 3600)), Err(daily));
+        assert_eq!(send(40, &spent, None), Err(PolicyDenied::ClockNotSynced));
+        // Nothing leaving the wallet needs neither the clock nor the budget
+        assert_eq!(send(0, &spent, None), Ok(0));
+
+        // Without windows the spend is still returned for recording, with or without a clock
+        let policy = SpendingPolicy {
+            max_lamports_per_tx: Some(100),
+            ..Default::default()
+        };
+        let message = message(&[system::transfer(&wallet, &other, 90)]);
+        assert_eq!(evaluate(&policy, &message, &wallet, &spent, None, ata), Ok(90));
+    }
+
+    #[test]
+    fn display() {
+        let denied = PolicyDenied::Handover(Handover::TokenApprove);
+        assert_eq!(
+            denied.to_string(),
+            "Token Approve by the device key is refused while spending is restricted"
+        );
+        let denied = PolicyDenied::HourlyLimit { lamports: 1, spent: 2, limit: 3 };
+        assert_eq!(denied.to_string(), "Transfer of 1 lamports exceeds the hourly limit of 3 (2 already spent)");
+    }
+}
@@ -0,0 +1,335 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str::FromStr;
+
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use solana_address::Address as Pubkey;
+use solana_hash::Hash;
+
+use crate::leanjson;
+
+// Owner of nonce accounts, the all-zero address
+const SYSTEM_PROGRAM: Pubkey = Pubkey::new_from_array([0; 32]);
+
+#[derive(Debug, Clone)]
+pub enum SolanaRpcMethod {
+    GetLatestBlockhash,
+    GetBalance(String),
+    #[cfg(feature = "rpc-history")]
+    GetTransaction(String),
+    GetAccountInfo(String),
+    #[cfg(feature = "rpc-program")]
+    GetProgramAccounts(String),
+    GetRecentBlockhash,
+    GetSlot,
+    GetVersion,
+    SendTransaction(String),
+    #[cfg(feature = "rpc-token")]
+    GetTokenAccountBalance(String),
+    GetFeeForMessage(String),
+    GetSignatureStatuses(Vec<String>),
+    GetMinimumBalanceForRentExemption(usize),
+    #[cfg(feature = "rpc-cluster")]
+    GetSlotLeaders(u64, u64),
+    #[cfg(feature = "rpc-cluster")]
+    GetClusterNodes,
+    #[cfg(feature = "rpc-history")]
+    GetSignaturesForAddress(String, usize),
+    #[cfg(feature = "rpc-airdrop")]
+    RequestAirdrop(String, u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountInfo {
+    pub lamports: u64,
+    pub owner: Pubkey,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfirmationStatus {
+    Processed,
+    Confirmed,
+    Finalized,
+}
+
+pub fn create_solana_payload(method: SolanaRpcMethod) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method.method_name(),
+        "params": method.params()
+    })
+}
+
+// The parse_* functions take the `result` of a response parsed into a Value, the read_* ones
+// the raw response, envelope included, and only pick out the fields they need with leanjson
+
+pub fn parse_blockhash(result: &Value) -> Result<Hash, String> {
+    let blockhash_str = result["value"]["blockhash"]
+        .as_str()
+        .ok_or("No blockhash in response")?;
+
+    Hash::from_str(blockhash_str).map_err(|e| format!("Hash parse: {:?}", e))
+}
+
+pub fn read_blockhash(body: &[u8]) -> Result<Hash, String> {
+    let blockhash_str =
+        leanjson::str_at(body, &["result", "value", "blockhash"])?.ok_or("No blockhash in response")?;
+    Hash::from_str(blockhash_str).map_err(|e| format!("Hash parse: {:?}", e))
+}
+
+pub fn parse_balance(result: &Value) -> Result<u64, String> {
+    result["value"]
+        .as_u64()
+        .ok_or_else(|| "No balance in response".to_string())
+}
+
+pub fn read_balance(body: &[u8]) -> Result<u64, String> {
+    leanjson::u64_at(body, &["result", "value"])?.ok_or_else(|| "No balance in response".to_string())
+}
+
+// None if the account doesn't exist
+pub fn parse_account_info(result: &Value) -> Result<Option<AccountInfo>, String> {
+    let value = &result["value"];
+    if value.is_null() {
+        return Ok(None);
+    }
+
+    let lamports = value["lamports"].as_u64().ok_or("No lamports in response")?;
+    let owner = value["owner"]
+        .as_str()
+        .and_then(|owner| Pubkey::from_str(owner).ok())
+        .ok_or("No owner in response")?;
+    let data = general_purpose::STANDARD
+        .decode(value["data"][0].as_str().ok_or("No account data in response")?)
+        .map_err(|e| format!("Account data decode: {:?}", e))?;
+
+    Ok(Some(AccountInfo { lamports, owner, data }))
+}
+
+// The data is decoded straight from the response, no copy of the base64 on the way
+pub fn read_account_info(body: &[u8]) -> Result<Option<AccountInfo>, String> {
+    let value = leanjson::find(body, &["result", "value"])?.ok_or("No account in response")?;
+    if leanjson::is_null(value) {
+        return Ok(None);
+    }
+    let lamports = leanjson::u64_at(value, &["lamports"])?.ok_or("No lamports in response")?;
+    let owner = leanjson::str_at(value, &["owner"])?
+        .and_then(|owner| Pubkey::from_str(owner).ok())
+        .ok_or("No owner in response")?;
+    let data = general_purpose::STANDARD
+        .decode(leanjson::str_at(value, &["data", "0"])?.ok_or("No account data in response")?)
+        .map_err(|e| format!("Account data decode: {:?}", e))?;
+    Ok(Some(AccountInfo { lamports, owner, data }))
+}
+
+// Current value of a durable nonce, the "blockhash" transactions using it are signed with
+pub fn parse_nonce(account: &AccountInfo) -> Result<Hash, String> {
+    if account.owner != SYSTEM_PROGRAM {
+        return Err("Not a nonce account".to_string());
+    }
+    // Versions and State tags (u32 each, Current and Initialized), the authority, then the nonce
+    if account.data.len() < 72 || account.data[4..8] != [1, 0, 0, 0] {
+        return Err("Nonce account not initialized".to_string());
+    }
+    let nonce: [u8; 32] = account.data[40..72].try_into().unwrap();
+    Ok(Hash::new_from_array(nonce))
+}
+
+// Returns the raw token amount and mint decimals, None if the token account doesn't exist
+#[cfg(feature = "rpc-token")]
+pub fn parse_token_account_balance(result: &Value) -> Result<Option<(u64, u8)>, String> {
+    if result.is_null() {
+        return Ok(None);
+    }
+
+    let amount = result["value"]["amount"]
+        .as_str()
+        .ok_or("No token amount in response")?
+        .parse::<u64>()
+        .map_err(|e| format!("Token amount parse: {:?}", e))?;
+    let decimals = result["value"]["decimals"]
+        .as_u64()
+        .ok_or("No token decimals in response")? as u8;
+
+    Ok(Some((amount, decimals)))
+}
+
+// None while the cluster hasn't seen the transaction yet, Err if it landed but failed
+pub fn parse_signature_status(result: &Value) -> Result<Option<ConfirmationStatus>, String> {
+    let status = &result["value"][0];
+    if status.is_null() {
+        return Ok(None);
+    }
+    if !status["err"].is_null() {
+        return Err(format!("Transaction failed: {}", status["err"]));
+    }
+
+    confirmation_status(status["confirmationStatus"].as_str())
+}
+
+pub fn read_signature_status(body: &[u8]) -> Result<Option<ConfirmationStatus>, String> {
+    let status = match leanjson::find(body, &["result", "value", "0"])? {
+        Some(status) if !leanjson::is_null(status) => status,
+        _ => return Ok(None),
+    };
+    if let Some(err) = leanjson::find(status, &["err"])?.filter(|err| !leanjson::is_null(err)) {
+        return Err(format!("Transaction failed: {}", String::from_utf8_lossy(err)));
+    }
+    confirmation_status(leanjson::str_at(status, &["confirmationStatus"])?)
+}
+
+pub fn confirmation_status(status: Option<&str>) -> Result<Option<ConfirmationStatus>, String> {
+    match status {
+        Some("processed") => Ok(Some(ConfirmationStatus::Processed)),
+        Some("confirmed") => Ok(Some(ConfirmationStatus::Confirmed)),
+        Some("finalized") => Ok(Some(ConfirmationStatus::Finalized)),
+        other => Err(format!("Unknown confirmation status: {:?}", other)),
+    }
+}
+
+// The signature sendTransaction answers with
+pub fn parse_sent_signature(result: &Value) -> Result<String, String> {
+    result
+        .as_str()
+        .map(|signature| signature.to_string())
+        .ok_or_else(|| "Invalid response format: expected transaction signature".to_string())
+}
+
+pub fn read_sent_signature(body: &[u8]) -> Result<String, String> {
+    leanjson::str_at(body, &["result"])?
+        .map(|signature| signature.to_string())
+        .ok_or_else(|| "Invalid response format: expected transaction signature".to_string())
+}
+
+// Newest first, transactions that failed included
+#[cfg(feature = "rpc-history")]
+pub fn parse_signatures(result: &Value) -> Result<Vec<String>, String> {
+    result
+        .as_array()
+        .ok_or("No signatures in response")?
+        .iter()
+        .map(|entry| {
+            entry["signature"]
+                .as_str()
+                .map(|signature| signature.to_string())
+                .ok_or_else(|| "No signature in response entry".to_string())
+        })
+        .collect()
+}
+
+// One entry per slot
+#[cfg(feature = "rpc-cluster")]
+pub fn parse_slot_leaders(result: &Value) -> Result<Vec<Pubkey>, String> {
+    result
+        .as_array()
+        .ok_or("Invalid response format: expected leader list")?
+        .iter()
+        .map(|leader| {
+            let leader = leader.as_str().ok_or("Invalid leader")?;
+            Pubkey::from_str(leader).map_err(|e| format!("Pubkey parse: {:?}", e))
+        })
+        .collect()
+}
+
+impl SolanaRpcMethod {
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            SolanaRpcMethod::GetLatestBlockhash => "getLatestBlockhash",
+            SolanaRpcMethod::GetBalance(_) => "getBalance",
+            #[cfg(feature = "rpc-history")]
+            SolanaRpcMethod::GetTransaction(_) => "getTransaction",
+            SolanaRpcMethod::GetAccountInfo(_) => "getAccountInfo",
+            #[cfg(feature = "rpc-program")]
+            SolanaRpcMethod::GetProgramAccounts(_) => "getProgramAccounts",
+            SolanaRpcMethod::GetRecentBlockhash => "getRecentBlockhash",
+            SolanaRpcMethod::GetSlot => "getSlot",
+            SolanaRpcMethod::GetVersion => "getVersion",
+            SolanaRpcMethod::SendTransaction(_) => "sendTransaction",
+            #[cfg(feature = "rpc-token")]
+            SolanaRpcMethod::GetTokenAccountBalance(_) => "getTokenAccountBalance",
+            SolanaRpcMethod::GetFeeForMessage(_) => "getFeeForMessage",
+            SolanaRpcMethod::GetSignatureStatuses(_) => "getSignatureStatuses",
+            SolanaRpcMethod::GetMinimumBalanceForRentExemption(_) => "getMinimumBalanceForRentExemption",
+            #[cfg(feature = "rpc-cluster")]
+            SolanaRpcMethod::GetSlotLeaders(_, _) => "getSlotLeaders",
+            #[cfg(feature = "rpc-cluster")]
+            SolanaRpcMethod::GetClusterNodes => "getClusterNodes",
+            #[cfg(feature = "rpc-history")]
+            SolanaRpcMethod::GetSignaturesForAddress(_, _) => "getSignaturesForAddress",
+            #[cfg(feature = "rpc-airdrop")]
+            SolanaRpcMethod::RequestAirdrop(_, _) => "requestAirdrop",
+        }
+    }
+
+    pub fn params(&self) -> Value {
+        match self {
+            SolanaRpcMethod::GetLatestBlockhash => {
+                json!([{"commitment": "confirmed"}])
+            }
+            SolanaRpcMethod::GetBalance(wallet) => {
+                json!([wallet])
+            }
+            #[cfg(feature = "rpc-history")]
+            SolanaRpcMethod::GetTransaction(signature) => {
+                json!([signature, {"encoding": "jsonParsed", "commitment": "confirmed", "maxSupportedTransactionVersion": 0}])
+            }
+            SolanaRpcMethod::GetAccountInfo(account) => {
+                json!([account, {"encoding": "base64"}])
+            }
+            #[cfg(feature = "rpc-program")]
+            SolanaRpcMethod::GetProgramAccounts(program) => {
+                json!([program, {"encoding": "base64"}])
+            }
+            SolanaRpcMethod::GetRecentBlockhash => {
+                json!([])
+            }
+            SolanaRpcMethod::GetSlot => {
+                json!([{"commitment": "processed"}])
+            }
+            SolanaRpcMethod::GetVersion => {
+                json!([])
+            }
+            SolanaRpcMethod::SendTransaction(transaction) => {
+                json!([transaction, {
+                    "encoding": "base64",
+                    "skipPreflight": false,
+                    "preflightCommitment": "confirmed",
+                    "maxRetries": 3
+                }])
+            }
+            #[cfg(feature = "rpc-token")]
+            SolanaRpcMethod::GetTokenAccountBalance(account) => {
+                json!([account, {"commitment": "confirmed"}])
+            }
+            SolanaRpcMethod::GetFeeForMessage(message) => {
+                json!([message, {"commitment": "confirmed"}])
+            }
+            SolanaRpcMethod::GetSignatureStatuses(signatures) => {
+                json!([signatures])
+            }
+            SolanaRpcMethod::GetMinimumBalanceForRentExemption(data_len) => {
+                json!([data_len])
+            }
+            #[cfg(feature = "rpc-cluster")]
+            SolanaRpcMethod::GetSlotLeaders(start_slot, limit) => {
+                json!([start_slot, limit])
+            }
+            #[cfg(feature = "rpc-cluster")]
+            SolanaRpcMethod::GetClusterNodes => {
+                json!([])
+            }
+            #[cfg(feature = "rpc-history")]
+            SolanaRpcMethod::GetSignaturesForAddress(address, limit) => {
+                json!([address, {"limit": limit, "commitment": "confirmed"}])
+            }
+            #[cfg(feature = "rpc-airdrop")]
+            SolanaRpcMethod::RequestAirdrop(address, lamports) => {
+                json!([address, lamports])
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+// Spending is kept in 5 minute buckets covering the last day, so windows are exact to 5 minutes
+const BUCKET_SECS: u64 = 300;
+const BUCKETS: usize = 288;
+// The newest bucket's index and the buckets, as u64 LE words
+pub const LEDGER_LEN: usize = 8 * (BUCKETS + 1);
+
+// Rolling spend counters, which the firmware persists in NVS so they survive reboots and
+// deep sleep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendBuckets {
+    // Index of the newest bucket (unix time / BUCKET_SECS), buckets are a ring indexed modulo BUCKETS
+    newest: u64,
+    buckets: Vec<u64>,
+}
+
+impl Default for SpendBuckets {
+    fn default() -> Self {
+        Self {
+            newest: 0,
+            buckets: vec![0; BUCKETS],
+        }
+    }
+}
+
+impl SpendBuckets {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() != LEDGER_LEN {
+            return Err(format!("Spend ledger has invalid length {}", data.len()));
+        }
+        let mut words = data
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()));
+        Ok(Self {
+            newest: words.next().unwrap(),
+            buckets: words.collect(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(LEDGER_LEN);
+        data.extend_from_slice(&self.newest.to_le_bytes());
+        for bucket in &self.buckets {
+            data.extend_from_slice(&bucket.to_le_bytes());
+        }
+        data
+    }
+
+    // Lamports spent in the window ending at `now`. If the clock went backwards the newest
+    // recorded bucket counts as now, so setting the clock back never frees up budget.
+    pub fn spent_within(&self, window: Duration, now: u64) -> u64 {
+        let now_bucket = (now / BUCKET_SECS).max(self.newest);
+        let window_buckets = window.as_secs().div_ceil(BUCKET_SECS).min(BUCKETS as u64);
+
+        (0..window_buckets)
+            .filter_map(|age| now_bucket.checked_sub(age))
+            .filter(|&bucket| bucket <= self.newest && self.newest - bucket < BUCKETS as u64)
+            .fold(0u64, |total, bucket| total.saturating_add(self.buckets[bucket as usize % BUCKETS]))
+    }
+
+    // Returns the bucket the lamports went into, for release
+    pub fn record(&mut self, lamports: u64, now: u64) -> u64 {
+        self.advance(now / BUCKET_SECS);
+
+        let slot = &mut self.buckets[self.newest as usize % BUCKETS];
+        *slot = slot.saturating_add(lamports);
+        self.newest
+    }
+
+    // Takes back lamports recorded into `bucket` for a spend that didn't happen after all, false
+    // when the bucket has left the ring and nothing changed
+    pub fn release(&mut self, lamports: u64, bucket: u64) -> bool {
+        if bucket > self.newest || self.newest - bucket >= BUCKETS as u64 {
+            return false;
+        }
+        let slot = &mut self.buckets[bucket as usize % BUCKETS];
+        *slot = slot.saturating_sub(lamports);
+        true
+    }
+
+    // Clears the buckets that fell out of the window since the newest one
+    fn advance(&mut self, bucket: u64) {
+        if bucket <= self.newest {
+            return;
+        }
+
+        let expired = (bucket - self.newest).min(BUCKETS as u64);
+        for offset in 1..=expired {
+            self.buckets[(self.newest + offset) as usize % BUCKETS] = 0;
+        }
+        self.newest = bucket;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+    // A bucket boundary, so offsets below land in the buckets they name
+    const NOW: u64 = 1_750_000_200;
+
+    #[test]
+    fn windows() {
+        let mut spend = SpendBuckets::default();
+        spend.record(100, NOW - 2 * 3600);
+        spend.record(10, NOW - 3300);
+        spend.record(1, NOW);
+
+        assert_eq!(spend.spent_within(HOUR, NOW), 11);
+        assert_eq!(spend.spent_within(DAY, NOW), 111);
+        // Only the 5 minute bucket `now` falls in
+        assert_eq!(spend.spent_within(Duration::from_secs(1), NOW + 299), 1);
+        assert_eq!(spend.spent_within(HOUR, NOW + 3600), 0);
+        assert_eq!(spend.spent_within(DAY, NOW + 24 * 3600 - 2 * 3600), 11);
+    }
+
+    #[test]
+    fn buckets_expire_after_a_day() {
+        let mut spend = SpendBuckets::default();
+        spend.record(5, NOW);
+        spend.record(7, NOW + 24 * 3600);
+        assert_eq!(spend.spent_within(DAY, NOW + 24 * 3600), 7);
+
+        // A week without spending clears the whole ring
+        spend.record(3, NOW + 8 * 24 * 3600);
+        assert_eq!(spend.spent_within(DAY, NOW + 8 * 24 * 3600), 3);
+    }
+
+    #[test]
+    fn clock_going_back_frees_nothing() {
+        let mut spend = SpendBuckets::default();
+        spend.record(50, NOW);
+        assert_eq!(spend.spent_within(HOUR, NOW - 24 * 3600), 50);
+
+        // Counted in the newest bucket rather than the earlier one the clock claims
+        assert_eq!(spend.record(20, NOW - 3 * 3600), NOW / BUCKET_SECS);
+        assert_eq!(spend.spent_within(HOUR, NOW), 70);
+    }
+
+    #[test]
+    fn release() {
+        let mut spend = SpendBuckets::default();
+        let bucket = spend.record(40, NOW);
+        assert!(spend.release(15, bucket));
+        assert_eq!(spend.spent_within(HOUR, NOW), 25);
+        assert!(spend.release(100, bucket));
+        assert_eq!(spend.spent_within(HOUR, NOW), 0);
+
+        // A bucket that left the ring, or never existed, is left alone
+        let old = spend.record(40, NOW);
+        spend.record(1, NOW + 24 * 3600);
+        assert!(!spend.release(40, old));
+        assert!(!spend.release(1, old + BUCKETS as u64 + 1));
+        assert_eq!(spend.spent_within(DAY, NOW + 24 * 3600), 1);
+    }
+
+    #[test]
+    fn saturates() {
+        let mut spend = SpendBuckets::default();
+        spend.record(u64::MAX, NOW);
+        spend.record(1, NOW + 300);
+        assert_eq!(spend.spent_within(HOUR, NOW + 300), u64::MAX);
+    }
+
+    #[test]
+    fn bytes() {
+        let mut spend = SpendBuckets::default();
+        spend.record(123, NOW);
+        spend.record(456, NOW + 600);
+
+        let data = spend.to_bytes();
+        assert_eq!(data.len(), LEDGER_LEN);
+        assert_eq!(SpendBuckets::from_bytes(&data), Ok(spend));
+        assert!(SpendBuckets::from_bytes(&data[1..]).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+
+use serde_json::{json, Value};
+
+// Fixed address for networks without reliable DHCP, as WiFi networks store it and the setup
+// commands take it:
+// {"address":"192.168.1.50","netmask":"255.255.255.0","gateway":"192.168.1.1","dns":["1.1.1.1"]}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticIp {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    // The gateway when none is configured
+    pub dns: Ipv4Addr,
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+impl StaticIp {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let address = |name: &str| -> Result<Ipv4Addr, String> {
+            value[name]
+                .as_str()
+                .ok_or_else(|| format!("Static IP needs a '{}'", name))?
+                .parse()
+                .map_err(|_| format!("Static IP '{}' is not an IPv4 address", name))
+        };
+        let netmask = u32::from(address("netmask")?);
+        if netmask.leading_ones() + netmask.trailing_zeros() != 32 {
+            return Err("Static IP 'netmask' is not a valid netmask".to_string());
+        }
+
+        let dns = match &value["dns"] {
+            Value::Null => Vec::new(),
+            Value::Array(servers) if servers.len() <= 2 => servers
+                .iter()
+                .map(|server| server.as_str().and_then(|server| server.parse().ok()))
+                .collect::<Option<Vec<Ipv4Addr>>>()
+                .ok_or("Static IP 'dns' entries must be IPv4 addresses")?,
+            _ => return Err("Static IP 'dns' must be a list of up to 2 servers".to_string()),
+        };
+        let gateway = address("gateway")?;
+
+        Ok(Self {
+            address: address("address")?,
+            prefix_len: netmask.leading_ones() as u8,
+            gateway,
+            dns: dns.first().copied().unwrap_or(gateway),
+            secondary_dns: dns.get(1).copied(),
+        })
+    }
+
+    pub fn to_value(&self) -> Value {
+        let netmask = Ipv4Addr::from(u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0));
+        let dns: Vec<String> = core::iter::once(self.dns)
+            .chain(self.secondary_dns)
+            .map(|server| server.to_string())
+            .collect();
+        json!({
+            "address": self.address.to_string(),
+            "netmask": netmask.to_string(),
+            "gateway": self.gateway.to_string(),
+            "dns": dns,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(value: Value) -> Result<StaticIp, String> {
+        StaticIp::from_value(&value)
+    }
+
+    #[test]
+    fn parses() {
+        let ip = parse(json!({
+            "address": "192.168.1.50",
+            "netmask": "255.255.255.0",
+            "gateway": "192.168.1.1",
+            "dns": ["1.1.1.1", "9.9.9.9"],
+        }))
+        .unwrap();
+        assert_eq!(
+            ip,
+            StaticIp {
+                address: Ipv4Addr::new(192, 168, 1, 50),
+                prefix_len: 24,
+                gateway: Ipv4Addr::new(192, 168, 1, 1),
+                dns: Ipv4Addr::new(1, 1, 1, 1),
+                secondary_dns: Some(Ipv4Addr::new(9, 9, 9, 9)),
+            }
+        );
+        assert_eq!(StaticIp::from_value(&ip.to_value()), Ok(ip));
+    }
+
+    #[test]
+    fn dns_defaults_to_the_gateway() {
+        let ip = json!({"address": "10.0.0.2", "netmask": "255.0.0.0", "gateway": "10.0.0.1"});
+        let ip = parse(ip).unwrap();
+        assert_eq!((ip.prefix_len, ip.dns, ip.secondary_dns), (8, Ipv4Addr::new(10, 0, 0, 1), None));
+        assert_eq!(ip.to_value()["dns"], json!(["10.0.0.1"]));
+    }
+
+    #[test]
+    fn netmasks() {
+        for (netmask, prefix_len) in [("0.0.0.0", 0), ("255.255.255.255", 32), ("255.255.240.0", 20)] {
+            let ip = json!({"address": "10.0.0.2", "netmask": netmask, "gateway": "10.0.0.1"});
+            let ip = parse(ip).unwrap();
+            assert_eq!(ip.prefix_len, prefix_len);
+            assert_eq!(ip.to_value()["netmask"], json!(netmask));
+        }
+    }
+
+    #[test]
+    fn refuses_malformed() {
+        let valid = json!({"address": "10.0.0.2", "netmask": "255.255.255.0", "gateway": "10.0.0.1"});
+        let with = |field: &str, value: Value| {
+            let mut ip = valid.clone();
+            ip[field] = value;
+            parse(ip)
+        };
+        for field in ["address", "netmask", "gateway"] {
+            let mut missing = valid.clone();
+            missing.as_object_mut().unwrap().remove(field);
+            assert!(parse(missing).is_err(), "no {}", field);
+            assert!(with(field, json!("10.0.0")).is_err());
+            assert!(with(field, json!("10.0.0.256")).is_err());
+            assert!(with(field, json!("::1")).is_err());
+            assert!(with(field, json!(167772162)).is_err());
+        }
+        assert!(with("netmask", json!("255.0.255.0")).is_err());
+        assert!(with("netmask", json!("0.255.255.255")).is_err());
+        assert!(with("dns", json!("1.1.1.1")).is_err());
+        assert!(with("dns", json!(["1.1.1.1", "8.8.8.8", "9.9.9.9"])).is_err());
+        assert!(with("dns", json!(["1.1.1"])).is_err());
+        assert!(with("dns", json!([1])).is_err());
+        assert!(parse(Value::Null).is_err());
+        assert!(parse(json!("10.0.0.2/24")).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use base64::{engine::general_purpose, Engine as _};
+use solana_address::Address as Pubkey;
+use solana_hash::Hash;
+use solana_instruction::Instruction;
+use solana_signature::Signature;
+
+// Legacy messages and transactions in the bytes nodes take, the same bytes bincode makes of
+// solana-transaction's Message and Transaction. Accounts are ordered like Message::new orders
+// them: the payer, then signers, writable before readonly and each group by address.
+
+#[derive(Default, Clone, Copy)]
+struct KeyMeta {
+    is_signer: bool,
+    is_writable: bool,
+}
+
+// The message the signers sign, with `payer` as the first signer
+pub fn compile_message(instructions: &[Instruction], payer: &Pubkey, blockhash: &Hash) -> Result<Vec<u8>, String> {
+    let mut metas = BTreeMap::<Pubkey, KeyMeta>::new();
+    for instruction in instructions {
+        metas.entry(instruction.program_id).or_default();
+        for account in &instruction.accounts {
+            let meta = metas.entry(account.pubkey).or_default();
+            meta.is_signer |= account.is_signer;
+            meta.is_writable |= account.is_writable;
+        }
+    }
+    metas.remove(payer);
+
+    let group = |is_signer: bool, is_writable: bool| {
+        metas
+            .iter()
+            .filter(move |(_, meta)| meta.is_signer == is_signer && meta.is_writable == is_writable)
+            .map(|(key, _)| *key)
+    };
+    let keys: Vec<Pubkey> = core::iter::once(*payer)
+        .chain(group(true, true))
+        .chain(group(true, false))
+        .chain(group(false, true))
+        .chain(group(false, false))
+        .collect();
+    let readonly_signers = group(true, false).count();
+    let readonly_unsigned = group(false, false).count();
+    let signers = 1 + group(true, true).count() + readonly_signers;
+    if keys.len() > u8::MAX as usize {
+        return Err(format!("Message has {} accounts, at most 255 fit", keys.len()));
+    }
+    let index = |key: &Pubkey| keys.iter().position(|k| k == key).unwrap() as u8;
+
+    let mut message = Vec::new();
+    message.extend_from_slice(&[signers as u8, readonly_signers as u8, readonly_unsigned as u8]);
+    push_len(&mut message, keys.len());
+    keys.iter().for_each(|key| message.extend_from_slice(key.as_ref()));
+    message.extend_from_slice(blockhash.as_ref());
+    push_len(&mut message, instructions.len());
+    for instruction in instructions {
+        message.push(index(&instruction.program_id));
+        push_len(&mut message, instruction.accounts.len());
+        message.extend(instruction.accounts.iter().map(|account| index(&account.pubkey)));
+        push_len(&mut message, instruction.data.len());
+        message.extend_from_slice(&instruction.data);
+    }
+
+    Ok(message)
+}
+
+// The signed transaction, `signatures` in the order of the message's signers
+pub fn transaction(signatures: &[Signature], message: &[u8]) -> Vec<u8> {
+    let mut transaction = Vec::with_capacity(3 + signatures.len() * 64 + message.len());
+    push_len(&mut transaction, signatures.len());
+    signatures
+        .iter()
+        .for_each(|signature| transaction.extend_from_slice(signature.as_ref()));
+    transaction.extend_from_slice(message);
+    transaction
+}
+
+// How sendTransaction and getFeeForMessage take transactions and messages
+pub fn base64(bytes: &[u8]) -> String {
+    general_purpose::STANDARD.encode(bytes)
+}
+
+// Solana's compact-u16: 7 bits per byte, the high bit set while more follow
+fn push_len(out: &mut Vec<u8>, len: usize) {
+    let mut rest = len as u16;
+    loop {
+        let byte = (rest & 0x7f) as u8;
+        rest >>= 7;
+        if rest == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
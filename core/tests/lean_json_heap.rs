@@ -0,0 +1,86 @@
+// The heap each way of reading a response takes at its peak, the figures in the README's Lean
+// JSON table. Run with `cargo test --test lean_json_heap -- --nocapture` to see them.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use base64::{engine::general_purpose, Engine as _};
+use resp32sol_core::client::take_result;
+use resp32sol_core::rpc::{parse_account_info, parse_blockhash, read_account_info, read_blockhash};
+
+// Counts only what the measuring thread allocates, the test harness runs beside it
+struct Counting;
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.get() {
+            let current = CURRENT.get() + layout.size();
+            CURRENT.set(current);
+            PEAK.set(PEAK.get().max(current));
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if COUNTING.get() {
+            CURRENT.set(CURRENT.get().saturating_sub(layout.size()));
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// Peak heap of `parse`, with what it returns still held
+fn peak<T>(parse: impl FnOnce() -> T) -> usize {
+    CURRENT.set(0);
+    PEAK.set(0);
+    COUNTING.set(true);
+    let parsed = parse();
+    COUNTING.set(false);
+    drop(parsed);
+    PEAK.get()
+}
+
+fn account_info(data_len: usize) -> Vec<u8> {
+    let data = general_purpose::STANDARD.encode((0..data_len).map(|i| i as u8).collect::<Vec<_>>());
+    // get_account_info.json with the data swapped out
+    String::from_utf8_lossy(include_bytes!("fixtures/get_account_info.json"))
+        .replace(r#""AQIDBA==""#, &format!(r#""{}""#, data))
+        .replace(r#""space":4"#, &format!(r#""space":{}"#, data_len))
+        .into_bytes()
+}
+
+fn serde(body: &[u8]) -> serde_json::Value {
+    take_result(serde_json::from_slice(body).unwrap()).unwrap()
+}
+
+#[test]
+fn peak_heap() {
+    let blockhash = include_bytes!("fixtures/get_latest_blockhash.json");
+    let serde_blockhash = peak(|| parse_blockhash(&serde(blockhash)).unwrap());
+    let lean_blockhash = peak(|| read_blockhash(blockhash).unwrap());
+    println!("getLatestBlockhash: serde_json {} bytes, lean-json {} bytes", serde_blockhash, lean_blockhash);
+    assert_eq!(lean_blockhash, 0);
+    assert!(serde_blockhash > 0);
+
+    for data_len in [80, 10 * 1024] {
+        let body = account_info(data_len);
+        let serde_account = peak(|| parse_account_info(&serde(&body)).unwrap());
+        let lean_account = peak(|| read_account_info(&body).unwrap());
+        println!(
+            "getAccountInfo, {} bytes of data: serde_json {} bytes, lean-json {} bytes",
+            data_len, serde_account, lean_account
+        );
+        // The decoded data is all lean-json allocates, base64 rounds its buffer up a little
+        assert!(lean_account >= data_len && lean_account <= data_len + 4);
+        assert!(serde_account > lean_account + data_len);
+    }
+}
@@ -0,0 +1,92 @@
+// The transfer demo on the library alone: joins WiFi, loads the device key from the keystore and
+// sends a small transfer every minute, confirming each one. A starting point for projects that
+// depend on this crate instead of copying its modules.
+//
+//   cargo run --release --example transfer
+
+#[cfg(any(feature = "remote-signer", feature = "watch-only"))]
+compile_error!("the transfer example signs and sends, build it without `remote-signer` and `watch-only`");
+
+use std::error::Error;
+use std::time::Duration;
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::peripherals::Peripherals;
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::link_patches;
+use log::{info, warn};
+use solana_keypair::Keypair;
+use solana_program::pubkey::Pubkey;
+use solana_system_interface::instruction as system_instruction;
+use solana_transaction::{Signature, Transaction};
+
+use resp32sol::keystore::{self, Keystore};
+use resp32sol::pin::PinGate;
+use resp32sol::recovery::Recovery;
+use resp32sol::signer::{DeviceSigner, TxSigner};
+use resp32sol::solrpc::{self, ConfirmationStatus, RpcClient};
+use resp32sol::net::wifi;
+
+// Above the rent-exempt minimum, so the transfer can create the recipient's account
+const LAMPORTS: u64 = 1_000_000;
+const INTERVAL: Duration = Duration::from_secs(60);
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn main() {
+    link_patches();
+    EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take().unwrap();
+    let sys_loop = EspSystemEventLoop::take().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+    // Taken once, WiFi certificates, the PIN and the keystore each keep a namespace in it
+    let encrypted = keystore::take_encrypted_partition();
+
+    // Joins in the background once the device has WiFi credentials, the first RPC call waits for
+    // it. A device without them always waits for provisioning.
+    if let Err(e) = wifi::connect(peripherals.modem, sys_loop, nvs.clone(), encrypted.clone(), |_, _, _| {
+        Recovery::Provision
+    }) {
+        warn!("WiFi unavailable: {}", e);
+    }
+    // Public endpoints drop a call now and then, a retry or two rides that out
+    RpcClient::reconfigure().retries(2).build().install();
+
+    // Without flash encryption the key only lives until the next reset
+    let pin_gate = PinGate::open(nvs.clone(), encrypted.clone());
+    let keypair = match Keystore::open(nvs, encrypted, false).and_then(|mut keystore| keystore.load_or_generate()) {
+        Ok(keypair) => keypair,
+        Err(e) => {
+            warn!("Keystore unavailable ({}), using ephemeral key", e);
+            Keypair::new()
+        }
+    };
+    // The PIN only applies once one is set, and there are no policy or approval hooks, the
+    // firmware in main.rs shows how to add them
+    let signer = DeviceSigner::new(keypair, pin_gate);
+    info!("Device address: {}", signer.pubkey());
+
+    loop {
+        match solrpc::get_balance(&signer.pubkey()) {
+            Ok(lamports) => info!("Balance: {} lamports", lamports),
+            Err(e) => warn!("Balance unavailable: {}", e),
+        }
+        match transfer(&signer, &Pubkey::new_unique()) {
+            Ok(signature) => info!("Transfer confirmed: {}", signature),
+            Err(e) => warn!("Transfer failed: {}", e),
+        }
+        std::thread::sleep(INTERVAL);
+    }
+}
+
+fn transfer(signer: &impl TxSigner, to: &Pubkey) -> Result<Signature, Box<dyn Error>> {
+    let from = signer.pubkey();
+    let instruction = system_instruction::transfer(&from, to, LAMPORTS);
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&from));
+    signer.sign_transaction(&mut transaction, solrpc::get_latest_blockhash()?)?;
+    let signature = solrpc::send_transaction(&transaction)?;
+    let signature = signature.parse().map_err(|e| format!("Invalid signature: {:?}", e))?;
+    solrpc::confirm_transaction(&signature, ConfirmationStatus::Confirmed, CONFIRM_TIMEOUT)?;
+    Ok(signature)
+}
@@ -0,0 +1,231 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::ledc::config::{Resolution, TimerConfig};
+use esp_idf_svc::hal::ledc::{LedcDriver, LedcTimerDriver, CHANNEL1, TIMER1};
+use esp_idf_svc::hal::units::Hertz;
+use log::{info, warn};
+use serde_json::Value;
+use solana_program::pubkey::Pubkey;
+
+use crate::signer::TxSigner;
+use crate::solrpc::{get_signatures_for_address, get_transaction};
+
+// Payments to the device drive a motor, light or servo, harder and for longer the more they
+// pay: at `min_lamports` the action is at its weakest and shortest, from `full_lamports` on at
+// its strongest and longest. Made for tip jars, art installations and demo stands.
+
+// Payments landing faster than this between polls are only partly seen
+const SIGNATURES_PER_POLL: usize = 10;
+const SERVO_FREQUENCY_HZ: u32 = 50;
+const SERVO_PERIOD_US: u32 = 1_000_000 / SERVO_FREQUENCY_HZ;
+const SERVO_MAX_DEGREES: u32 = 180;
+// Payments arriving while one acts out queue up to this many, the rest only get logged
+const MAX_QUEUED: usize = 4;
+const ACTUATOR_STACK_SIZE: usize = 4 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Actuation {
+    // Through a MOSFET or motor driver: a fan, LED strip, vibration motor or pump. Off at rest.
+    Pwm {
+        frequency_hz: u32,
+        min_duty_percent: u32,
+        max_duty_percent: u32,
+    },
+    // Hobby servo, 0 to 180 degrees across `min_pulse_us` to `max_pulse_us`, returning to
+    // `rest_degrees` after each payment
+    Servo {
+        min_pulse_us: u32,
+        max_pulse_us: u32,
+        rest_degrees: u32,
+        min_degrees: u32,
+        max_degrees: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ActuatorConfig {
+    pub actuation: Actuation,
+    // Smaller payments are ignored, they are mostly spam
+    pub min_lamports: u64,
+    pub full_lamports: u64,
+    pub min_duration: Duration,
+    pub max_duration: Duration,
+    pub poll_interval: Duration,
+}
+
+struct Watcher {
+    config: ActuatorConfig,
+    actions: SyncSender<u64>,
+    // Newest transaction to the device dealt with, None before the first poll
+    cursor: Option<String>,
+    polled: Option<Instant>,
+}
+
+static WATCHER: Mutex<Option<Watcher>> = Mutex::new(None);
+
+// The output on a LEDC channel of its own, the buzzer keeps the first one
+pub fn start(timer: TIMER1, channel: CHANNEL1, pin: AnyIOPin, config: ActuatorConfig) -> Result<(), String> {
+    if config.full_lamports <= config.min_lamports {
+        return Err("full_lamports must be above min_lamports".to_string());
+    }
+    let timer_config = match config.actuation {
+        Actuation::Pwm { frequency_hz, .. } => TimerConfig::new()
+            .frequency(Hertz(frequency_hz))
+            .resolution(Resolution::Bits10),
+        // A microsecond is about one step in 14 bits at 50 Hz
+        Actuation::Servo { .. } => TimerConfig::new()
+            .frequency(Hertz(SERVO_FREQUENCY_HZ))
+            .resolution(Resolution::Bits14),
+    };
+    let timer = LedcTimerDriver::new(timer, &timer_config).map_err(|e| format!("LEDC timer init: {:?}", e))?;
+    let mut driver = LedcDriver::new(channel, &timer, pin).map_err(|e| format!("LEDC init: {:?}", e))?;
+    let rest = rest_duty(&config.actuation, driver.get_max_duty());
+    driver.set_duty(rest).map_err(|e| format!("LEDC duty: {:?}", e))?;
+
+    let (actions, received) = sync_channel(MAX_QUEUED);
+    std::thread::Builder::new()
+        .name("actuator".to_string())
+        .stack_size(ACTUATOR_STACK_SIZE)
+        .spawn(move || {
+            // The timer stops once dropped, it lives as long as the channel driving the output
+            let _timer = timer;
+            run(driver, config, received)
+        })
+        .map_err(|e| format!("Actuator thread: {:?}", e))?;
+
+    *WATCHER.lock().unwrap() = Some(Watcher {
+        config,
+        actions,
+        cursor: None,
+        polled: None,
+    });
+    info!("Actuator up, full action from {} lamports", config.full_lamports);
+    Ok(())
+}
+
+// Looks for new payments to the device every poll interval, called from the main loop which
+// holds the signer
+pub fn poll_due(signer: &impl TxSigner) {
+    let mut watcher = WATCHER.lock().unwrap();
+    let Some(watcher) = watcher.as_mut() else {
+        return;
+    };
+    if watcher.polled.is_some_and(|polled| polled.elapsed() < watcher.config.poll_interval) {
+        return;
+    }
+    watcher.polled = Some(Instant::now());
+    if let Err(e) = watcher.poll(&signer.pubkey()) {
+        warn!("Payment check failed: {}", e);
+    }
+}
+
+impl Watcher {
+    fn poll(&mut self, device: &Pubkey) -> Result<(), String> {
+        let signatures = get_signatures_for_address(device, SIGNATURES_PER_POLL).map_err(|e| e.to_string())?;
+        // Payments from before the device came up don't act
+        let Some(cursor) = self.cursor.as_ref() else {
+            self.cursor = Some(signatures.first().cloned().unwrap_or_default());
+            return Ok(());
+        };
+        let new = match signatures.iter().position(|signature| signature == cursor) {
+            Some(seen) => &signatures[..seen],
+            None => &signatures[..],
+        };
+
+        // Oldest first, up to one the node serving getTransaction doesn't have yet
+        for signature in new.iter().rev() {
+            let Some(transaction) = get_transaction(signature).map_err(|e| e.to_string())? else {
+                break;
+            };
+            self.cursor = Some(signature.clone());
+            let lamports = received_lamports(&transaction, device).unwrap_or(0);
+            if lamports < self.config.min_lamports {
+                continue;
+            }
+            info!("Payment of {} lamports received: {}", lamports, signature);
+            match self.actions.try_send(lamports) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => warn!("Actions queued up, {} not acted out", signature),
+                Err(TrySendError::Disconnected(_)) => return Err("Actuator thread stopped".to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn run(mut driver: LedcDriver<'static>, config: ActuatorConfig, actions: Receiver<u64>) {
+    let max_duty = driver.get_max_duty();
+    let rest = rest_duty(&config.actuation, max_duty);
+    for lamports in actions {
+        // 0 at min_lamports, 1 from full_lamports on
+        let share = (lamports - config.min_lamports) as f32 / (config.full_lamports - config.min_lamports) as f32;
+        let share = share.min(1.0);
+        let duration = config.min_duration + (config.max_duration.saturating_sub(config.min_duration)).mul_f32(share);
+        let duty = match config.actuation {
+            Actuation::Pwm {
+                min_duty_percent,
+                max_duty_percent,
+                ..
+            } => max_duty * lerp(min_duty_percent, max_duty_percent, share).min(100) / 100,
+            Actuation::Servo {
+                min_degrees,
+                max_degrees,
+                ..
+            } => servo_duty(&config.actuation, lerp(min_degrees, max_degrees, share), max_duty),
+        };
+
+        info!("Acting on {} lamports for {}ms", lamports, duration.as_millis());
+        if let Err(e) = driver.set_duty(duty) {
+            warn!("Actuator duty: {:?}", e);
+            continue;
+        }
+        std::thread::sleep(duration);
+        if let Err(e) = driver.set_duty(rest) {
+            warn!("Actuator duty: {:?}", e);
+        }
+    }
+}
+
+fn rest_duty(actuation: &Actuation, max_duty: u32) -> u32 {
+    match actuation {
+        Actuation::Pwm { .. } => 0,
+        Actuation::Servo { rest_degrees, .. } => servo_duty(actuation, *rest_degrees, max_duty),
+    }
+}
+
+fn servo_duty(actuation: &Actuation, degrees: u32, max_duty: u32) -> u32 {
+    let Actuation::Servo {
+        min_pulse_us,
+        max_pulse_us,
+        ..
+    } = *actuation
+    else {
+        return 0;
+    };
+    let degrees = degrees.min(SERVO_MAX_DEGREES);
+    let pulse_us = min_pulse_us + max_pulse_us.saturating_sub(min_pulse_us) * degrees / SERVO_MAX_DEGREES;
+    (max_duty as u64 * pulse_us as u64 / SERVO_PERIOD_US as u64) as u32
+}
+
+fn lerp(from: u32, to: u32, share: f32) -> u32 {
+    (from as f32 + (to as f32 - from as f32) * share).round() as u32
+}
+
+// What a transaction from getTransaction moved into the device's account, 0 for failed ones
+fn received_lamports(transaction: &Value, device: &Pubkey) -> Option<u64> {
+    let meta = &transaction["meta"];
+    if !meta["err"].is_null() {
+        return Some(0);
+    }
+    let device = device.to_string();
+    let index = transaction["transaction"]["message"]["accountKeys"]
+        .as_array()?
+        .iter()
+        .position(|key| key["pubkey"].as_str() == Some(&device))?;
+    let pre = meta["preBalances"][index].as_u64()?;
+    let post = meta["postBalances"][index].as_u64()?;
+    Some(post.saturating_sub(pre))
+}
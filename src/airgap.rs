@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use solana_transaction::{Message, Signature, Transaction};
+
+use crate::frag::{fragment, Reassembler, MAX_TRANSACTION_LEN};
+use crate::qr::QrMatrix;
+use crate::serial::LineReader;
+use crate::signer::TxSigner;
+
+// Keeps each frame small enough for a low version QR code that fits small displays
+const FRAME_CHUNK_LEN: usize = 120;
+const FRAME_INTERVAL: Duration = Duration::from_millis(500);
+// How long the signed transaction keeps cycling before the device listens for the next one
+const DISPLAY_DURATION: Duration = Duration::from_secs(30);
+
+// Source of decoded QR payloads (an attached camera, or a UART scanner module)
+pub trait QrScanner {
+    fn scan(&mut self, timeout: Duration) -> Option<String>;
+}
+
+// Where response frames are shown
+pub trait QrDisplay {
+    fn show(&mut self, qr: &QrMatrix) -> Result<(), String>;
+}
+
+// UART QR scanner modules (GM65, GM805, ...) send every decoded code as one text line,
+// so they can be wired straight to the console UART
+pub struct SerialScanner {
+    reader: LineReader,
+}
+
+impl SerialScanner {
+    pub fn new() -> Self {
+        Self {
+            reader: LineReader::new(),
+        }
+    }
+}
+
+impl Default for SerialScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QrScanner for SerialScanner {
+    fn scan(&mut self, timeout: Duration) -> Option<String> {
+        self.reader.read_line(timeout).map(|line| line.to_string())
+    }
+}
+
+// Draws frames on the serial terminal, for development without a display
+pub struct TerminalDisplay;
+
+impl QrDisplay for TerminalDisplay {
+    fn show(&mut self, qr: &QrMatrix) -> Result<(), String> {
+        println!("{}", qr.to_terminal_string());
+        Ok(())
+    }
+}
+
+// Air-gapped signer loop: the unsigned message arrives as (animated) QR frames, the device
+// signs it and shows the signed transaction back as animated QR frames. No radio is used.
+pub fn run(signer: &impl TxSigner, scanner: &mut impl QrScanner, display: &mut impl QrDisplay) -> ! {
+    info!("Air-gapped signer ready for {}, scan an unsigned transaction", signer.pubkey());
+    let mut reassembler = Reassembler::new(MAX_TRANSACTION_LEN, FRAME_CHUNK_LEN);
+
+    loop {
+        let Some(frame) = scanner.scan(Duration::from_secs(3600)) else {
+            continue;
+        };
+
+        let payload = match reassembler.push(&frame) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => {
+                let (received, total) = reassembler.progress();
+                info!("Scanned frame {}/{}", received, total);
+                continue;
+            }
+            Err(e) => {
+                warn!("Ignoring frame: {}", e);
+                continue;
+            }
+        };
+
+        match sign_payload(signer, &payload) {
+            Ok(signed) => {
+                info!("Transaction signed, showing it for {}s", DISPLAY_DURATION.as_secs());
+                if let Err(e) = show_animated(display, &signed) {
+                    warn!("Display failed: {}", e);
+                }
+            }
+            Err(e) => warn!("Air-gapped sign request refused: {}", e),
+        }
+    }
+}
+
+// Payload is a bincode-serialized legacy Message, the response the serialized transaction
+// carrying the device signature
+fn sign_payload(signer: &impl TxSigner, payload: &[u8]) -> Result<Vec<u8>, String> {
+    let message: Message = bincode::deserialize(payload).map_err(|e| format!("Message decode: {:?}", e))?;
+    let signature = signer.sign_message(&message).map_err(|e| e.to_string())?;
+
+    let position = message
+        .account_keys
+        .iter()
+        .position(|key| *key == signer.pubkey())
+        .ok_or("Device key is not a signer of this message")?;
+    let mut transaction = Transaction {
+        signatures: vec![Signature::default(); message.header.num_required_signatures as usize],
+        message,
+    };
+    transaction.signatures[position] = signature;
+
+    bincode::serialize(&transaction).map_err(|e| format!("Transaction serialization failed: {:?}", e))
+}
+
+fn show_animated(display: &mut impl QrDisplay, data: &[u8]) -> Result<(), String> {
+    let frames = fragment(data, FRAME_CHUNK_LEN)
+        .iter()
+        .map(|frame| QrMatrix::encode(frame))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let deadline = Instant::now() + DISPLAY_DURATION;
+    for qr in frames.iter().cycle() {
+        if Instant::now() >= deadline {
+            break;
+        }
+        display.show(qr)?;
+        std::thread::sleep(FRAME_INTERVAL);
+    }
+
+    Ok(())
+}
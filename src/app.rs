@@ -0,0 +1,691 @@
+#[cfg(all(feature = "sensor-log", not(feature = "battery-monitor")))]
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(not(feature = "remote-signer"))]
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+#[cfg(all(feature = "sensor-log", not(feature = "battery-monitor")))]
+use esp_idf_svc::hal::adc::{oneshot::AdcDriver, ADC1};
+#[cfg(all(feature = "sensor-log", not(feature = "battery-monitor")))]
+use esp_idf_svc::hal::gpio::Gpio4;
+#[cfg(any(
+    not(feature = "watch-only"),
+    feature = "oled-display",
+    feature = "status-led",
+    feature = "buzzer",
+    feature = "lora-bridge",
+    feature = "sd-log"
+))]
+use esp_idf_svc::hal::gpio::IOPin;
+#[cfg(not(feature = "remote-signer"))]
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::hal::peripherals::Peripherals;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{info, warn};
+
+#[cfg(feature = "pay-actuator")]
+use crate::actuator::{self, ActuatorConfig};
+#[cfg(feature = "battery-monitor")]
+use crate::battery::{self, BatteryConfig};
+#[cfg(all(feature = "battery-monitor", feature = "sensor-log"))]
+use crate::battery::BatterySensor;
+#[cfg(feature = "gps-beacon")]
+use crate::beacon::{self, BeaconConfig};
+#[cfg(not(feature = "remote-signer"))]
+use crate::buffers::{self, BufferConfig};
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, BuzzerConfig};
+#[cfg(feature = "can-log")]
+use crate::canlog::{self, CanLogConfig};
+#[cfg(not(feature = "remote-signer"))]
+use crate::captive;
+#[cfg(all(feature = "cellular", not(feature = "remote-signer")))]
+use crate::cellular::{self, CellularPins};
+use crate::chip;
+#[cfg(all(
+    any(feature = "ethernet-w5500", feature = "ethernet-rmii", feature = "cellular"),
+    not(feature = "remote-signer")
+))]
+use crate::config::NETWORK;
+use crate::crashlog::{self, PanicLog};
+#[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+use crate::display;
+#[cfg(feature = "rotary-encoder")]
+use crate::encoder;
+#[cfg(feature = "energy-meter")]
+use crate::energy::{self, EnergyConfig};
+#[cfg(all(feature = "epaper", not(feature = "remote-signer")))]
+use crate::epaper::{EPaper, EPaperConfig};
+#[cfg(all(feature = "ethernet-rmii", not(feature = "remote-signer")))]
+use crate::eth::RmiiPins;
+#[cfg(all(feature = "ethernet-w5500", not(feature = "remote-signer")))]
+use crate::eth::W5500Pins;
+#[cfg(all(any(feature = "ethernet-w5500", feature = "ethernet-rmii"), not(feature = "remote-signer")))]
+use crate::eth;
+#[cfg(feature = "fingerprint")]
+use crate::fingerprint::{FingerprintApproval, FingerprintConfig};
+#[cfg(feature = "gps-beacon")]
+use crate::gps;
+#[cfg(feature = "ir-remote")]
+use crate::ir::{self, IrConfig};
+#[cfg(not(feature = "watch-only"))]
+use crate::keystore;
+#[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+use crate::led::{self, LedConfig, LedState};
+#[cfg(not(feature = "remote-signer"))]
+use crate::lifecycle;
+use crate::loglevel;
+#[cfg(feature = "lora-bridge")]
+use crate::lora::{self, LoraConfig};
+#[cfg(all(feature = "low-power", not(feature = "remote-signer")))]
+use crate::lowpower::{self, PowerProfile};
+#[cfg(not(feature = "remote-signer"))]
+use crate::net;
+#[cfg(not(feature = "remote-signer"))]
+use crate::netwatch;
+#[cfg(feature = "nfc")]
+use crate::nfc::{self, NfcReader, TagReader};
+#[cfg(all(feature = "oled-display", not(any(feature = "remote-signer", feature = "epaper"))))]
+use crate::oled::{Controller, Oled};
+#[cfg(feature = "pay-button")]
+use crate::paybutton;
+#[cfg(feature = "nfc")]
+use crate::pn532::Pn532;
+#[cfg(feature = "receipt-printer")]
+use crate::printer::{self, PrinterConfig};
+use crate::psram;
+#[cfg(feature = "nfc")]
+use crate::rc522::Rc522;
+use crate::recovery::{self, BootFailure, BootStep, Recovery};
+#[cfg(feature = "espnow-relay")]
+use crate::relay;
+#[cfg(feature = "sd-log")]
+use crate::sdlog::{self, SdLogConfig};
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+use crate::sender;
+#[cfg(all(feature = "sensor-log", not(feature = "battery-monitor")))]
+use crate::sensorlog::AdcSensor;
+#[cfg(feature = "sensor-log")]
+use crate::sensorlog::{self, Sensor, SensorLogConfig};
+#[cfg(not(feature = "remote-signer"))]
+use crate::taskwdt;
+use crate::tasks::{self, StackConfig};
+#[cfg(not(feature = "remote-signer"))]
+use crate::timesync;
+#[cfg(feature = "pay-to-unlock")]
+use crate::unlock;
+#[cfg(feature = "usb-wallet")]
+use crate::usbwallet;
+#[cfg(feature = "vending")]
+use crate::vending;
+#[cfg(not(feature = "watch-only"))]
+use crate::wallet::{self, WalletConfig};
+#[cfg(feature = "watch-only")]
+use crate::watch;
+
+// The application modes the firmware ends up in once its signer is up
+#[cfg(not(feature = "watch-only"))]
+mod modes;
+#[cfg(not(feature = "watch-only"))]
+pub use modes::{run, AppConfig};
+
+// Bringing the board up: `start` takes the peripherals, the storage and the uplink, hands each
+// feature's driver its pins and settings, opens the wallet and runs the application. The
+// firmware in main.rs only fills in a FirmwareConfig for it.
+
+// Attempts at a failing boot step before the device goes on without what it brings up, and the
+// pause between them
+const BOOT_ATTEMPTS: u32 = 3;
+const BOOT_RETRY_DELAY: Duration = Duration::from_secs(2);
+// A device that can't take its peripherals restarts after this long
+const BOOT_RESTART_DELAY: Duration = Duration::from_secs(5);
+// A device without storage shows why for this long before it restarts and tries again
+const DEGRADED_RESTART_DELAY: Duration = Duration::from_secs(10 * 60);
+
+pub struct FirmwareConfig {
+    // Stack sizes of the network, rpc and ui tasks and when their high-water marks are reported
+    pub task_stacks: StackConfig,
+    // RAM for RPC traffic and the task queues
+    #[cfg(not(feature = "remote-signer"))]
+    pub buffers: BufferConfig,
+    // RPC calls that go this long between steps reset the device
+    #[cfg(not(feature = "remote-signer"))]
+    pub task_watchdog_timeout: Duration,
+    #[cfg(all(feature = "low-power", not(feature = "remote-signer")))]
+    pub low_power: PowerProfile,
+    #[cfg(feature = "sd-log")]
+    pub sd_log: SdLogConfig,
+    #[cfg(all(feature = "oled-display", not(any(feature = "remote-signer", feature = "epaper"))))]
+    pub oled_controller: Controller,
+    #[cfg(all(feature = "epaper", not(feature = "remote-signer")))]
+    pub epaper: EPaperConfig,
+    #[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+    pub status_led: LedConfig,
+    #[cfg(feature = "buzzer")]
+    pub buzzer: BuzzerConfig,
+    #[cfg(feature = "lora-bridge")]
+    pub lora: LoraConfig,
+    #[cfg(feature = "energy-meter")]
+    pub energy: EnergyConfig,
+    #[cfg(feature = "battery-monitor")]
+    pub battery: BatteryConfig,
+    #[cfg(feature = "can-log")]
+    pub can_log: CanLogConfig,
+    #[cfg(feature = "gps-beacon")]
+    pub gps_beacon: BeaconConfig,
+    #[cfg(feature = "pay-actuator")]
+    pub pay_actuator: ActuatorConfig,
+    #[cfg(feature = "ir-remote")]
+    pub ir_remote: IrConfig,
+    #[cfg(feature = "receipt-printer")]
+    pub receipt_printer: PrinterConfig,
+    #[cfg(feature = "fingerprint")]
+    pub fingerprint: FingerprintConfig,
+    // The signer and the application mode it runs
+    #[cfg(not(feature = "watch-only"))]
+    pub wallet: WalletConfig,
+    #[cfg(not(feature = "watch-only"))]
+    pub app: AppConfig,
+}
+
+// Brings up everything the features built in need, then runs the application for good
+pub fn start(config: &'static FirmwareConfig) -> ! {
+    info!("Running on {}", chip::describe());
+    crashlog::install();
+
+    // Before the other tasks come up, see `tasks` for the layout
+    if let Err(e) = tasks::start(config.task_stacks) {
+        warn!("{}", e);
+    }
+    #[cfg(not(feature = "remote-signer"))]
+    if let Err(e) = buffers::configure(config.buffers) {
+        warn!("Buffer sizes not applied, using the defaults: {}", e);
+    }
+    // Large RPC responses go there on boards that have it
+    if psram::available() {
+        info!("PSRAM found, {} KB free", psram::free() / 1024);
+    }
+
+    // Nothing comes up without the peripherals, boot_recovery decides everything else
+    let peripherals = match recovery::attempt(BootStep::Peripherals, boot_recovery, || {
+        Peripherals::take().map_err(|e| format!("Peripherals: {:?}", e))
+    }) {
+        Ok(peripherals) => peripherals,
+        Err(_) => recovery::restart(BOOT_RESTART_DELAY),
+    };
+    // Held until the display and LED are up, which can show why a device without it stops
+    let storage = recovery::attempt(BootStep::Storage, boot_recovery, || {
+        EspDefaultNvsPartition::take().map_err(|e| format!("NVS: {:?}", e))
+    });
+    // Keeps a panic from before this restart, the console's `panic` command reads and clears it
+    if let Err(e) =
+        storage.as_ref().map_err(|failure| failure.to_string()).and_then(|nvs| PanicLog::open(nvs.clone()))
+    {
+        warn!("Panic log unavailable: {}", e);
+    }
+    // The log levels set at runtime, the console's `log` command changes them
+    if let Err(e) =
+        storage.as_ref().map_err(|failure| failure.to_string()).and_then(|nvs| loglevel::restore(nvs.clone()))
+    {
+        warn!("Log levels not restored: {}", e);
+    }
+
+    // First, so the event log covers all of the boot. The SD card on SPI2: SCLK GPIO6, MOSI
+    // GPIO7, MISO GPIO2, CS GPIO10.
+    #[cfg(feature = "sd-log")]
+    if let Err(e) = sdlog::start(
+        peripherals.spi2,
+        peripherals.pins.gpio6.downgrade(),
+        peripherals.pins.gpio7.downgrade(),
+        peripherals.pins.gpio2.downgrade(),
+        peripherals.pins.gpio10.downgrade(),
+        config.sd_log,
+    ) {
+        warn!("SD card event log unavailable: {}", e);
+    }
+
+    // The relay on GPIO10, held off from the first moment so the machine doesn't open while
+    // the rest comes up
+    #[cfg(feature = "pay-to-unlock")]
+    if let Err(e) = unlock::arm(peripherals.pins.gpio10.downgrade(), &config.app.unlock) {
+        warn!("Relay unavailable: {}", e);
+    }
+
+    // The dispense output on GPIO10 held off the same way, the HX711's DOUT on GPIO1 and PD_SCK
+    // on GPIO0
+    #[cfg(feature = "vending")]
+    if let Err(e) = vending::arm(
+        peripherals.pins.gpio1.downgrade(),
+        peripherals.pins.gpio0.downgrade(),
+        peripherals.pins.gpio10.downgrade(),
+        &config.app.vending,
+    ) {
+        warn!("Vending hardware unavailable: {}", e);
+    }
+
+    // Up before the network so the screen follows the connection attempts, SDA on GPIO5 and
+    // SCL on GPIO6
+    #[cfg(all(feature = "oled-display", not(any(feature = "remote-signer", feature = "epaper"))))]
+    if let Err(e) = Oled::new(
+        peripherals.i2c0,
+        peripherals.pins.gpio5.downgrade(),
+        peripherals.pins.gpio6.downgrade(),
+        config.oled_controller,
+    )
+    .and_then(|oled| display::start(Box::new(oled)))
+    {
+        warn!("Status display unavailable: {}", e);
+    }
+    // The e-paper panel on SPI2: SCLK GPIO6, DIN GPIO7, CS GPIO10, DC GPIO5, RST GPIO2, BUSY GPIO4
+    #[cfg(all(feature = "epaper", not(feature = "remote-signer")))]
+    if let Err(e) = EPaper::new(
+        peripherals.spi2,
+        peripherals.pins.gpio6.downgrade(),
+        peripherals.pins.gpio7.downgrade(),
+        peripherals.pins.gpio10.downgrade(),
+        peripherals.pins.gpio5.downgrade(),
+        peripherals.pins.gpio2.downgrade(),
+        peripherals.pins.gpio4.downgrade(),
+        config.epaper,
+    )
+    .and_then(|epaper| display::start(Box::new(epaper)))
+    {
+        warn!("Status display unavailable: {}", e);
+    }
+    // GPIO8 drives the RGB LED on the ESP32-C3-DevKitM-1 and the ESP32-C6-DevKitC-1, GPIO48 on
+    // the ESP32-S3-DevKitC-1 (GPIO38 from its v1.1), change it for an LED wired elsewhere
+    #[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+    {
+        #[cfg(esp32s3)]
+        let led_pin = peripherals.pins.gpio48.downgrade();
+        #[cfg(not(esp32s3))]
+        let led_pin = peripherals.pins.gpio8.downgrade();
+        if let Err(e) = led::start(peripherals.rmt.channel0, led_pin, config.status_led) {
+            warn!("Status LED unavailable: {}", e);
+        }
+    }
+    // Passive piezo buzzer between GPIO1 and GND, through a transistor for more volume
+    #[cfg(feature = "buzzer")]
+    if let Err(e) = buzzer::start(
+        peripherals.ledc.timer0,
+        peripherals.ledc.channel0,
+        peripherals.pins.gpio1.downgrade(),
+        config.buzzer,
+    ) {
+        warn!("Buzzer unavailable: {}", e);
+    }
+
+    // Keys, credentials and policy all live in NVS, without it the device only shows why
+    let nvs = match storage {
+        Ok(nvs) => nvs,
+        Err(failure) => run_display_only(&failure),
+    };
+    // Taken once per boot and shared, the WiFi certificates, the keystore and the PIN gate each
+    // keep a namespace in it. Watch-only builds have no keystore to take it, their WiFi
+    // certificates stay in the plaintext partition.
+    #[cfg(not(feature = "watch-only"))]
+    let encrypted = keystore::take_encrypted_partition();
+    #[cfg(feature = "watch-only")]
+    let encrypted = None;
+
+    // The supply relay on GPIO10 cut off until the payer's allowance has been checked, the meter
+    // on GPIO1 (and GPIO0 for a PZEM)
+    #[cfg(feature = "energy-meter")]
+    if let Err(e) = energy::start(
+        peripherals.uart1,
+        peripherals.pins.gpio0,
+        peripherals.pins.gpio1,
+        peripherals.pins.gpio10.downgrade(),
+        config.energy,
+        nvs.clone(),
+    ) {
+        warn!("Energy metering unavailable: {}", e);
+    }
+
+    // Network bring-up, skipped in remote-signer mode where the device never goes online.
+    // The uplink `network` selects (see build.rs) needs its driver built in, WiFi takes over
+    // when that hardware doesn't answer.
+    // Relay nodes only bring up the radio for ESP-NOW, LoRa nodes not even that, both leave the
+    // network to their gateway
+    #[cfg(not(feature = "remote-signer"))]
+    let relay_node = relay_node();
+    // The SX1276 on SPI2: SCLK GPIO6, MOSI GPIO7, MISO GPIO2, NSS GPIO10
+    #[cfg(feature = "lora-bridge")]
+    if lora::is_node() || lora::is_gateway() {
+        if let Err(e) = lora::start(
+            peripherals.spi2,
+            peripherals.pins.gpio6.downgrade(),
+            peripherals.pins.gpio7.downgrade(),
+            peripherals.pins.gpio2.downgrade(),
+            peripherals.pins.gpio10.downgrade(),
+            &config.lora,
+        ) {
+            warn!("LoRa bridge unavailable: {}", e);
+        }
+    }
+    #[cfg(not(feature = "remote-signer"))]
+    {
+        if let Err(e) = taskwdt::start(peripherals.twdt, config.task_watchdog_timeout) {
+            warn!("{}", e);
+        }
+        // Before the uplink comes up, so the lifecycle sees its first events
+        lifecycle::start();
+        // Without it no uplink comes up, but the rest of the device still runs offline
+        let sys_loop = recovery::attempt(BootStep::EventLoop, boot_recovery, || {
+            EspSystemEventLoop::take().map_err(|e| format!("System event loop: {:?}", e))
+        });
+        match sys_loop {
+            Err(failure) => warn!("{}, running offline", failure),
+            Ok(sys_loop) => {
+            #[allow(unused_mut)]
+            let mut connected = false;
+            #[cfg(feature = "ethernet-w5500")]
+            if NETWORK == "ethernet" {
+                connected = eth::connect_w5500(
+                    peripherals.spi2,
+                    W5500Pins {
+                        sclk: peripherals.pins.gpio6,
+                        mosi: peripherals.pins.gpio7,
+                        miso: peripherals.pins.gpio2,
+                        cs: peripherals.pins.gpio10,
+                        int: peripherals.pins.gpio4,
+                        rst: peripherals.pins.gpio5,
+                    },
+                    sys_loop.clone(),
+                );
+            }
+            #[cfg(feature = "ethernet-rmii")]
+            if NETWORK == "ethernet" {
+                connected = eth::connect_rmii(
+                    peripherals.mac,
+                    RmiiPins {
+                        rxd0: peripherals.pins.gpio25,
+                        rxd1: peripherals.pins.gpio26,
+                        crs_dv: peripherals.pins.gpio27,
+                        mdc: peripherals.pins.gpio23,
+                        txd1: peripherals.pins.gpio22,
+                        tx_en: peripherals.pins.gpio21,
+                        txd0: peripherals.pins.gpio19,
+                        mdio: peripherals.pins.gpio18,
+                        clock: peripherals.pins.gpio0,
+                        reset: peripherals.pins.gpio16,
+                    },
+                    sys_loop.clone(),
+                );
+            }
+            #[cfg(feature = "cellular")]
+            if NETWORK == "cellular" {
+                connected = cellular::connect(
+                    peripherals.uart1,
+                    CellularPins {
+                        tx: peripherals.pins.gpio0,
+                        rx: peripherals.pins.gpio1,
+                    },
+                    sys_loop.clone(),
+                    nvs.clone(),
+                );
+            }
+
+            if relay_node {
+                #[cfg(feature = "espnow-relay")]
+                if relay::is_node() {
+                    relay::start_node(peripherals.modem, sys_loop, nvs.clone());
+                }
+            } else if !connected {
+                // A failed attempt drops its driver, which frees the modem for the next one
+                let mut modem = Some(peripherals.modem);
+                if let Err(failure) = recovery::attempt(BootStep::Uplink, boot_recovery, || {
+                    let modem = modem.take().unwrap_or_else(|| unsafe { Modem::new() });
+                    net::wifi::connect(modem, sys_loop.clone(), nvs.clone(), encrypted.clone(), boot_recovery)
+                }) {
+                    warn!("{}, running offline", failure);
+                }
+            }
+            }
+        }
+        if !relay_node {
+            if let Err(e) = netwatch::spawn().and_then(|_| captive::start()) {
+                warn!("{}", e);
+            }
+        }
+        #[cfg(not(feature = "watch-only"))]
+        if let Err(e) = sender::start() {
+            warn!("RPC task unavailable, sending from the main loop: {}", e);
+        }
+        #[cfg(feature = "low-power")]
+        if let Err(e) = lowpower::start(config.low_power) {
+            warn!("Low-power profile unavailable: {}", e);
+        }
+
+        #[cfg(feature = "espnow-relay")]
+        if relay::is_gateway() {
+            if let Err(e) = relay::start_gateway() {
+                warn!("ESP-NOW relay gateway unavailable: {}", e);
+            }
+        }
+    }
+
+    // Cluster picked in the setup portal
+    #[cfg(not(feature = "remote-signer"))]
+    crate::config::apply_cluster(nvs.clone());
+
+    // Wall clock for the spend limits, the RTC keeps it across deep sleep
+    #[cfg(not(feature = "remote-signer"))]
+    let _sntp = match relay_node {
+        true => None,
+        false => match timesync::start() {
+            Ok(sntp) => Some(sntp),
+            Err(e) => {
+                warn!("Time sync unavailable: {}", e);
+                None
+            }
+        },
+    };
+
+    #[cfg(feature = "watch-only")]
+    watch::run(nvs);
+
+    // Dash button between GPIO0 and GND
+    #[cfg(feature = "pay-button")]
+    if let Err(e) = paybutton::listen(peripherals.pins.gpio0.downgrade()) {
+        warn!("Pay button unavailable: {}", e);
+    }
+
+    // Rotary encoder with A on GPIO2, B on GPIO7 and the switch on GPIO10, common to GND
+    #[cfg(feature = "rotary-encoder")]
+    if let Err(e) = encoder::listen(
+        peripherals.pins.gpio2.downgrade(),
+        peripherals.pins.gpio7.downgrade(),
+        peripherals.pins.gpio10.downgrade(),
+    ) {
+        warn!("Rotary encoder unavailable: {}", e);
+    }
+
+    // Battery behind a divider on GPIO4, alerts are sent from the main loop like the sensor log's
+    #[cfg(feature = "battery-monitor")]
+    if let Err(e) = battery::start(peripherals.adc1, peripherals.pins.gpio4, config.battery, nvs.clone()) {
+        warn!("Battery monitor unavailable: {}", e);
+    }
+
+    // CAN transceiver with TX on GPIO21 and RX on GPIO20, anchored from the main loop as well
+    #[cfg(feature = "can-log")]
+    if let Err(e) = canlog::start(
+        peripherals.can,
+        peripherals.pins.gpio21.downgrade(),
+        peripherals.pins.gpio20.downgrade(),
+        config.can_log,
+        nvs.clone(),
+    ) {
+        warn!("CAN log unavailable: {}", e);
+    }
+
+    // GPS module's TX on GPIO1, positions are published from the main loop as well
+    #[cfg(feature = "gps-beacon")]
+    if let Err(e) = gps::start(peripherals.uart1, peripherals.pins.gpio1, config.gps_beacon.baud_rate)
+        .and_then(|_| beacon::start(config.gps_beacon, nvs.clone()))
+    {
+        warn!("GPS beacon unavailable: {}", e);
+    }
+
+    // Servo or PWM output on GPIO4, payments to the device are looked for from the main loop
+    #[cfg(feature = "pay-actuator")]
+    if let Err(e) = actuator::start(
+        peripherals.ledc.timer1,
+        peripherals.ledc.channel1,
+        peripherals.pins.gpio4.downgrade(),
+        config.pay_actuator,
+    ) {
+        warn!("Actuator unavailable: {}", e);
+    }
+
+    // IR receiver output on GPIO4, its buttons act from the main loop
+    #[cfg(feature = "ir-remote")]
+    {
+        #[cfg(esp32s3)]
+        let channel = peripherals.rmt.channel4;
+        #[cfg(not(esp32s3))]
+        let channel = peripherals.rmt.channel2;
+        if let Err(e) = ir::start(channel, peripherals.pins.gpio4.downgrade(), config.ir_remote) {
+            warn!("IR receiver unavailable: {}", e);
+        }
+    }
+
+    // Thermal printer on UART1, its RX on GPIO0
+    #[cfg(feature = "receipt-printer")]
+    if let Err(e) = printer::start(peripherals.uart1, peripherals.pins.gpio0, config.receipt_printer) {
+        warn!("Receipt printer unavailable: {}", e);
+    }
+
+    // Wallet protocol for a host on the USB Serial/JTAG port, whose D- and D+ lines are GPIO18 and
+    // GPIO19 on the ESP32-C3, GPIO19 and GPIO20 on the ESP32-S3 and GPIO12 and GPIO13 on the ESP32-C6
+    #[cfg(feature = "usb-wallet")]
+    {
+        #[cfg(esp32c3)]
+        let (d_minus, d_plus) = (peripherals.pins.gpio18, peripherals.pins.gpio19);
+        #[cfg(esp32s3)]
+        let (d_minus, d_plus) = (peripherals.pins.gpio19, peripherals.pins.gpio20);
+        #[cfg(esp32c6)]
+        let (d_minus, d_plus) = (peripherals.pins.gpio12, peripherals.pins.gpio13);
+        if let Err(e) = usbwallet::start(peripherals.usb_serial, d_minus, d_plus) {
+            warn!("USB wallet protocol unavailable: {}", e);
+        }
+    }
+
+    // Sensors logged on-chain, published from the main loop since that holds the signer
+    #[cfg(all(feature = "sensor-log", not(feature = "battery-monitor")))]
+    if let Err(e) = start_sensor_log(peripherals.adc1, peripherals.pins.gpio4, nvs.clone()) {
+        warn!("Sensor log unavailable: {}", e);
+    }
+    // The battery monitor holds GPIO4, its readings are logged in place of the pin's
+    #[cfg(all(feature = "sensor-log", feature = "battery-monitor"))]
+    if let Err(e) =
+        sensorlog::start(vec![Box::new(BatterySensor) as Box<dyn Sensor>], SensorLogConfig::default(), nvs.clone())
+    {
+        warn!("Sensor log unavailable: {}", e);
+    }
+
+    // Tap-to-pay reader, NfcReader lists the pins of each
+    #[cfg(feature = "nfc")]
+    {
+        let reader: Result<Box<dyn TagReader>, String> = match config.app.nfc.reader {
+            NfcReader::Pn532 => Pn532::new(
+                peripherals.i2c0,
+                peripherals.pins.gpio5.downgrade(),
+                peripherals.pins.gpio6.downgrade(),
+            )
+            .map(|reader| Box::new(reader) as Box<dyn TagReader>),
+            NfcReader::Rc522 => Rc522::new(
+                peripherals.spi2,
+                peripherals.pins.gpio6.downgrade(),
+                peripherals.pins.gpio7.downgrade(),
+                peripherals.pins.gpio2.downgrade(),
+                peripherals.pins.gpio10.downgrade(),
+            )
+            .map(|reader| Box::new(reader) as Box<dyn TagReader>),
+        };
+        if let Err(e) = reader.and_then(nfc::listen) {
+            warn!("NFC reader unavailable: {}", e);
+        }
+    }
+
+    // R503 or AS608 fingerprint module on UART1, its RX on GPIO0 and TX on GPIO1
+    #[cfg(feature = "fingerprint")]
+    let fingerprint =
+        FingerprintApproval::new(peripherals.uart1, peripherals.pins.gpio0, peripherals.pins.gpio1, config.fingerprint)
+            .map_err(|e| warn!("Fingerprint module unavailable, the button approves instead: {}", e))
+            .ok();
+
+    // GPIO3 is the tamper switch input, GPIO9 the BOOT button on the ESP32-C3 supermini and the
+    // ESP32-C6, and a button to GND on the ESP32-S3, whose BOOT button on GPIO0 the UART1
+    // features use. The classic ESP32 wires GPIO3 to the console and GPIO9 to flash, it takes
+    // GPIO13 and GPIO15 instead.
+    #[cfg(all(not(feature = "watch-only"), not(esp32)))]
+    let (tamper_pin, button_pin) = (peripherals.pins.gpio3.downgrade(), peripherals.pins.gpio9.downgrade());
+    #[cfg(all(not(feature = "watch-only"), esp32))]
+    let (tamper_pin, button_pin) = (peripherals.pins.gpio13.downgrade(), peripherals.pins.gpio15.downgrade());
+    #[cfg(not(feature = "watch-only"))]
+    let signer = wallet::open(
+        nvs.clone(),
+        encrypted,
+        tamper_pin,
+        button_pin,
+        &config.wallet,
+        #[cfg(feature = "fingerprint")]
+        fingerprint,
+    );
+    #[cfg(not(feature = "watch-only"))]
+    run(signer, nvs, &config.app);
+}
+
+// What boot does about a failed step. Peripherals are taken once, so a failure there needs a
+// restart, a device without credentials waits for provisioning, and the others are retried a
+// few times before the device goes on without them.
+fn boot_recovery(step: BootStep, attempt: u32, _error: &str) -> Recovery {
+    match step {
+        BootStep::Peripherals => Recovery::Restart(BOOT_RESTART_DELAY),
+        BootStep::Credentials => Recovery::Provision,
+        BootStep::Storage | BootStep::EventLoop | BootStep::Uplink if attempt < BOOT_ATTEMPTS => {
+            Recovery::Retry(BOOT_RETRY_DELAY)
+        }
+        BootStep::Storage | BootStep::EventLoop | BootStep::Uplink => Recovery::Degrade,
+    }
+}
+
+// Shows a failure boot can't get past on the display and LED, then restarts to try again
+#[allow(unused_variables)]
+fn run_display_only(failure: &BootFailure) -> ! {
+    #[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+    display::show_entry(&["Boot failed", &format!("{:?}", failure.step), "Restarting soon"]);
+    let until = std::time::Instant::now() + DEGRADED_RESTART_DELAY;
+    while std::time::Instant::now() < until {
+        // The LED falls back to idle after an error, so it is shown again until the restart
+        #[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+        led::show(LedState::Error);
+        std::thread::sleep(Duration::from_secs(5));
+    }
+    recovery::restart(Duration::ZERO)
+}
+
+// Nodes of either relay, which have no uplink of their own
+#[cfg(not(feature = "remote-signer"))]
+fn relay_node() -> bool {
+    #[cfg(feature = "espnow-relay")]
+    if relay::is_node() {
+        return true;
+    }
+    #[cfg(feature = "lora-bridge")]
+    if lora::is_node() {
+        return true;
+    }
+    false
+}
+
+// The sensors to log, a battery behind a 1:1 divider on GPIO4 to start from. More ADC1 pins
+// share the driver, I2C sensors a bus: `I2cSensor::new("temp", bus, 0x48, 0x00, 0.00390625)`
+// for a TMP102.
+#[cfg(all(feature = "sensor-log", not(feature = "battery-monitor")))]
+fn start_sensor_log(adc1: ADC1, battery: Gpio4, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+    let adc = Arc::new(AdcDriver::new(adc1).map_err(|e| format!("ADC init: {:?}", e))?);
+    let sensors: Vec<Box<dyn Sensor>> = vec![Box::new(AdcSensor::new("vbat", adc, battery, 0.002)?)];
+    sensorlog::start(sensors, SensorLogConfig::default(), nvs)
+}
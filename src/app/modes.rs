@@ -0,0 +1,794 @@
+#[cfg(not(feature = "remote-signer"))]
+use std::time::Duration;
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{info, warn};
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera",
+    feature = "cli-console"
+)))]
+use solana_program::native_token::LAMPORTS_PER_SOL;
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+use solana_program::{instruction::Instruction, pubkey::Pubkey};
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+use solana_system_interface::instruction as system_instruction;
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+use solana_transaction::Hash;
+#[cfg(not(any(feature = "remote-signer", feature = "receive-qr", feature = "pay-to-unlock")))]
+use solana_transaction::Transaction;
+
+#[cfg(feature = "air-gap")]
+use crate::airgap::{self, SerialScanner, TerminalDisplay};
+#[cfg(not(feature = "remote-signer"))]
+use crate::attestation;
+#[cfg(feature = "battery-monitor")]
+use crate::battery;
+#[cfg(feature = "gps-beacon")]
+use crate::beacon;
+#[cfg(feature = "camera")]
+use crate::camera::{CameraConfig, CameraScanner};
+#[cfg(feature = "can-log")]
+use crate::canlog;
+#[cfg(feature = "cli-console")]
+use crate::cli;
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+use crate::config::settings as device_settings;
+#[cfg(not(feature = "remote-signer"))]
+use crate::discovery;
+#[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+use crate::display;
+#[cfg(feature = "rotary-encoder")]
+use crate::encoder::{AmountDial, DialConfig};
+#[cfg(all(feature = "rotary-encoder", feature = "receive-qr"))]
+use crate::encoder::{self, DialEvent};
+#[cfg(feature = "energy-meter")]
+use crate::energy;
+#[cfg(feature = "ir-remote")]
+use crate::ir;
+#[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+use crate::led;
+#[cfg(all(feature = "nfc", feature = "status-led"))]
+use crate::led::LedState;
+#[cfg(feature = "lora-bridge")]
+use crate::lora;
+#[cfg(feature = "nfc")]
+use crate::nfc::{self, NfcConfig, ReplayGuard, TapAction};
+#[cfg(all(
+    feature = "lora-bridge",
+    not(any(
+        feature = "remote-signer",
+        feature = "pay-button",
+        feature = "nfc",
+        feature = "receive-qr",
+        feature = "pay-to-unlock",
+        feature = "camera"
+    ))
+))]
+use crate::offline;
+#[cfg(not(feature = "remote-signer"))]
+use crate::offline::OfflineQueue;
+#[cfg(not(any(feature = "remote-signer", feature = "receive-qr", feature = "pay-to-unlock")))]
+use crate::outbox::Outbox;
+#[cfg(feature = "pay-actuator")]
+use crate::actuator;
+#[cfg(feature = "pay-button")]
+use crate::paybutton::{self, PaymentPresets};
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+use crate::power::{PowerManager, SleepConfig};
+use crate::qr::{wallet_uri, QrMatrix};
+#[cfg(all(feature = "receive-qr", not(feature = "vending")))]
+use crate::receive::PaymentRequest;
+#[cfg(feature = "receive-qr")]
+use crate::receive::ReceiveConfig;
+#[cfg(all(feature = "remote-signer", not(feature = "air-gap")))]
+use crate::remote_signer::{self, SerialChannel};
+#[cfg(not(any(feature = "remote-signer", feature = "receive-qr", feature = "pay-to-unlock")))]
+use crate::sender;
+#[cfg(feature = "sensor-log")]
+use crate::sensorlog;
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "cli-console"
+)))]
+use crate::serial::LineReader;
+use crate::signer::DeviceSigner;
+#[cfg(any(feature = "nfc", all(feature = "camera", not(feature = "air-gap"))))]
+use crate::solanapay::PaymentTarget;
+#[cfg(all(
+    feature = "lora-bridge",
+    not(any(
+        feature = "remote-signer",
+        feature = "pay-button",
+        feature = "nfc",
+        feature = "receive-qr",
+        feature = "pay-to-unlock",
+        feature = "camera"
+    ))
+))]
+use crate::solrpc;
+#[cfg(not(any(feature = "remote-signer", feature = "receive-qr", feature = "pay-to-unlock")))]
+use crate::solrpc::get_latest_blockhash;
+#[cfg(not(feature = "remote-signer"))]
+use crate::tasks;
+#[cfg(feature = "pay-to-unlock")]
+use crate::unlock::{UnlockConfig, Unlocker};
+#[cfg(feature = "usb-wallet")]
+use crate::usbwallet;
+#[cfg(feature = "vending")]
+use crate::vending::{VendingConfig, VendingMachine};
+
+// What the device does once its signer is up. Exactly one mode runs, picked by the features
+// built in: remote or air-gapped signing, the pay button, NFC taps, the receive-qr terminal,
+// vending, pay-to-unlock, the camera, or the transfer demo. Every mode calls `serve_duties`
+// between waits for its own input, which runs the background work of the other features.
+
+pub struct AppConfig {
+    // The task supervisor restarts the device when one pass of a mode's loop takes longer
+    #[cfg(not(feature = "remote-signer"))]
+    pub deadline: Duration,
+    // Outgoing transfers wait this long in the outbox before they are sent, None sends right away
+    #[cfg(not(any(feature = "remote-signer", feature = "receive-qr", feature = "pay-to-unlock")))]
+    pub outbox_delay: Option<Duration>,
+    // The transfer demo's deep sleep between cycles, None stays up
+    #[cfg(not(any(
+        feature = "remote-signer",
+        feature = "pay-button",
+        feature = "nfc",
+        feature = "receive-qr",
+        feature = "pay-to-unlock",
+        feature = "camera"
+    )))]
+    pub deep_sleep: Option<SleepConfig>,
+    #[cfg(feature = "nfc")]
+    pub nfc: NfcConfig,
+    #[cfg(feature = "receive-qr")]
+    pub receive: ReceiveConfig,
+    #[cfg(feature = "vending")]
+    pub vending: VendingConfig,
+    #[cfg(feature = "pay-to-unlock")]
+    pub unlock: UnlockConfig,
+    #[cfg(feature = "camera")]
+    pub camera: CameraConfig,
+    #[cfg(feature = "rotary-encoder")]
+    pub dial: DialConfig,
+    // Durable nonce account a LoRa node signs the demo transfer with
+    #[cfg(all(
+        feature = "lora-bridge",
+        not(any(
+            feature = "remote-signer",
+            feature = "pay-button",
+            feature = "nfc",
+            feature = "receive-qr",
+            feature = "pay-to-unlock",
+            feature = "camera"
+        ))
+    ))]
+    pub lora_nonce_account: Option<Pubkey>,
+}
+
+// Announces the device's address, then runs the mode the features select for good
+#[allow(unused_variables, unused_mut)]
+pub fn run(mut signer: DeviceSigner, nvs: EspDefaultNvsPartition, config: &'static AppConfig) -> ! {
+    match QrMatrix::encode(&wallet_uri(&signer.pubkey())) {
+        Ok(qr) => info!("Scan to fund {}:\n{}", signer.pubkey(), qr.to_terminal_string()),
+        Err(e) => warn!("Address QR code: {}", e),
+    }
+    #[cfg(not(feature = "remote-signer"))]
+    discovery::advertise(Some(&signer.pubkey()));
+    #[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+    display::set_address(signer.pubkey());
+    #[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+    led::set_address(signer.pubkey());
+
+    // Let the backend know which firmware this device is running
+    #[cfg(not(feature = "remote-signer"))]
+    match attestation::publish_attestation(&signer) {
+        Ok(signature) => info!("Firmware attestation published: {}", signature),
+        Err(e) => warn!("Firmware attestation failed: {}", e),
+    }
+
+    // Durable-nonce transactions the rpc task can't send for the link being down wait in NVS and
+    // go out once it's back
+    #[cfg(not(feature = "remote-signer"))]
+    match OfflineQueue::open(nvs.clone()).and_then(|queue| queue.spawn_flusher().map(|_| queue)) {
+        Ok(queue) => sender::queue_offline(queue),
+        Err(e) => warn!("Offline queue unavailable: {}", e),
+    }
+
+    #[cfg(all(feature = "remote-signer", not(feature = "air-gap")))]
+    remote_signer::run(&mut signer, &mut SerialChannel::new());
+
+    // The ESP32-CAM's camera when it comes up, a UART scanner module on the console otherwise
+    #[cfg(all(feature = "air-gap", feature = "camera"))]
+    match CameraScanner::new(&config.camera) {
+        Ok(mut scanner) => airgap::run(&signer, &mut scanner, &mut TerminalDisplay),
+        Err(e) => warn!("Camera unavailable, scanning over the console: {}", e),
+    }
+
+    #[cfg(feature = "air-gap")]
+    airgap::run(&signer, &mut SerialScanner::new(), &mut TerminalDisplay);
+
+    #[cfg(feature = "pay-button")]
+    run_pay_button(&signer, nvs, config);
+
+    #[cfg(feature = "nfc")]
+    run_nfc(&signer, nvs, config);
+
+    #[cfg(all(feature = "receive-qr", not(feature = "vending")))]
+    run_receive_qr(&signer, config);
+
+    #[cfg(feature = "vending")]
+    run_vending(&signer, config);
+
+    #[cfg(feature = "pay-to-unlock")]
+    run_pay_to_unlock(&signer, nvs, config);
+
+    #[cfg(all(feature = "camera", not(feature = "air-gap")))]
+    run_camera(&signer, config);
+
+    #[cfg(not(any(
+        feature = "remote-signer",
+        feature = "pay-button",
+        feature = "nfc",
+        feature = "receive-qr",
+        feature = "pay-to-unlock",
+        feature = "camera"
+    )))]
+    run_transfer_demo(&signer, nvs, config);
+}
+
+// The background duties of the features built in, each returns at once when nothing is due.
+// Every mode's loop calls this between waits for its own input, which also reports the
+// application task alive.
+#[cfg(not(feature = "remote-signer"))]
+#[allow(unused_variables)]
+fn serve_duties(signer: &DeviceSigner, config: &AppConfig) {
+    tasks::beat("application", config.deadline);
+    #[cfg(feature = "sensor-log")]
+    sensorlog::publish_due(signer);
+    #[cfg(feature = "battery-monitor")]
+    battery::alert_due(signer);
+    #[cfg(feature = "can-log")]
+    canlog::anchor_due(signer);
+    #[cfg(feature = "gps-beacon")]
+    beacon::beacon_due(signer);
+    #[cfg(feature = "usb-wallet")]
+    usbwallet::serve_due(signer);
+    #[cfg(feature = "energy-meter")]
+    energy::settle_due(signer);
+    #[cfg(feature = "pay-actuator")]
+    actuator::poll_due(signer);
+    #[cfg(feature = "ir-remote")]
+    ir::handle_due(signer);
+}
+
+#[cfg(feature = "pay-button")]
+fn run_pay_button(signer: &DeviceSigner, nvs: EspDefaultNvsPartition, config: &AppConfig) -> ! {
+    let presets = PaymentPresets::load(nvs).unwrap_or_else(|e| {
+        warn!("Payment presets unavailable: {}", e);
+        PaymentPresets::default()
+    });
+    if presets.is_empty() {
+        warn!("No payment presets in NVS, the pay button does nothing");
+    }
+    let outbox = config.outbox_delay.map(Outbox::new);
+    let mut console = LineReader::new();
+
+    loop {
+        serve_duties(signer, config);
+
+        if let Some(outbox) = &outbox {
+            // Accidental presses can still be cancelled on the console
+            if let Some(line) = console.read_line(Duration::from_millis(100)) {
+                match outbox.handle_command(&line) {
+                    Ok(response) => println!("OK {}", response),
+                    Err(e) => println!("ERR {}", e),
+                }
+            }
+            outbox.release_due(signer);
+        }
+
+        let Some(gesture) = paybutton::next_gesture(Duration::from_secs(1)) else {
+            continue;
+        };
+        let Some(preset) = presets.get(gesture) else {
+            info!("No payment preset for a {:?} press", gesture);
+            continue;
+        };
+        let from_pubkey = signer.pubkey();
+        let instruction = system_instruction::transfer(&from_pubkey, &preset.recipient, preset.lamports);
+        let description = format!("{} lamports to {}", preset.lamports, preset.recipient);
+        info!("{:?} press: paying {}", gesture, description);
+
+        if let Some(outbox) = &outbox {
+            outbox.queue(&description, vec![instruction], from_pubkey);
+            continue;
+        }
+
+        let blockhash = match get_latest_blockhash() {
+            Ok(blockhash) => blockhash,
+            Err(e) => {
+                warn!("Payment not sent, no blockhash: {}", e);
+                continue;
+            }
+        };
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&from_pubkey));
+        if let Err(e) = signer.sign_transaction(&mut transaction, blockhash) {
+            warn!("Payment not signed: {}", e);
+            continue;
+        }
+        // The rpc task sends it and logs the outcome while this waits for the next payment
+        if let Err(e) = sender::submit(transaction, &format!("payment of {}", description)) {
+            warn!("Payment not sent: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "nfc")]
+fn run_nfc(signer: &DeviceSigner, nvs: EspDefaultNvsPartition, config: &AppConfig) -> ! {
+    // Without it copies of a tag would pass, so taps are refused instead
+    let mut replay_guard = ReplayGuard::open(nvs, &config.nfc)
+        .map_err(|e| warn!("NFC taps refused, replay protection unavailable: {}", e))
+        .ok();
+    let outbox = config.outbox_delay.map(Outbox::new);
+    let mut console = LineReader::new();
+
+    loop {
+        serve_duties(signer, config);
+
+        if let Some(outbox) = &outbox {
+            if let Some(line) = console.read_line(Duration::from_millis(100)) {
+                match outbox.handle_command(&line) {
+                    Ok(response) => println!("OK {}", response),
+                    Err(e) => println!("ERR {}", e),
+                }
+            }
+            outbox.release_due(signer);
+        }
+
+        let Some(tag) = nfc::next_tap(Duration::from_secs(1)) else {
+            continue;
+        };
+        let Some(replay_guard) = replay_guard.as_mut() else {
+            continue;
+        };
+        if let Err(e) = replay_guard.admit(&tag) {
+            warn!("Tap of tag {} refused: {}", tag.id(), e);
+            continue;
+        }
+        let target = match tag.content.as_deref().map(PaymentTarget::parse) {
+            Some(Ok(target)) => target,
+            Some(Err(e)) => {
+                warn!("Tag {}: {}", tag.id(), e);
+                continue;
+            }
+            None => {
+                warn!("Tag {} holds no URI or text record", tag.id());
+                continue;
+            }
+        };
+
+        let (lamports, max_lamports) = match config.nfc.action {
+            TapAction::Pay { lamports, max_lamports } => (lamports, max_lamports),
+            TapAction::CheckOwnership { mint } => {
+                let holds = nfc::holds_token(target.address(), &mint);
+                match &holds {
+                    Ok(true) => info!("{} holds {}", target.address(), mint),
+                    Ok(false) => warn!("{} doesn't hold {}", target.address(), mint),
+                    Err(e) => warn!("Ownership check failed: {}", e),
+                }
+                #[cfg(feature = "status-led")]
+                led::show(match holds {
+                    Ok(true) => LedState::Confirmed,
+                    _ => LedState::Error,
+                });
+                continue;
+            }
+        };
+        let from_pubkey = signer.pubkey();
+        let (description, instructions) = match target.payment(&from_pubkey, lamports, max_lamports) {
+            Ok(payment) => payment,
+            Err(e) => {
+                warn!("Tap of tag {} not paid: {}", tag.id(), e);
+                continue;
+            }
+        };
+        info!("Tag {} tapped: paying {}", tag.id(), description);
+
+        if let Some(outbox) = &outbox {
+            outbox.queue(&description, instructions, from_pubkey);
+            continue;
+        }
+
+        let blockhash = match get_latest_blockhash() {
+            Ok(blockhash) => blockhash,
+            Err(e) => {
+                warn!("Payment not sent, no blockhash: {}", e);
+                continue;
+            }
+        };
+        let mut transaction = Transaction::new_with_payer(&instructions, Some(&from_pubkey));
+        if let Err(e) = signer.sign_transaction(&mut transaction, blockhash) {
+            warn!("Payment not signed: {}", e);
+            continue;
+        }
+        // The rpc task sends it and logs the outcome while this waits for the next payment
+        if let Err(e) = sender::submit(transaction, &format!("payment of {}", description)) {
+            warn!("Payment not sent: {}", e);
+        }
+    }
+}
+
+#[cfg(all(feature = "receive-qr", not(feature = "vending")))]
+fn run_receive_qr(signer: &DeviceSigner, config: &AppConfig) -> ! {
+    let receive = &config.receive;
+    #[cfg(feature = "rotary-encoder")]
+    let mut dial = AmountDial::new(config.dial);
+    loop {
+        // Each sale's amount is dialed in on the encoder when there is one
+        #[cfg(feature = "rotary-encoder")]
+        let amount = {
+            dial.show();
+            loop {
+                serve_duties(signer, config);
+                if let Some(units) = dial.poll(Duration::from_secs(1)) {
+                    break dial.decimal(units);
+                }
+            }
+        };
+        #[cfg(not(feature = "rotary-encoder"))]
+        let amount = receive.amount.to_string();
+
+        let request = PaymentRequest::new(signer.pubkey(), &amount, receive);
+        let mut request = match request.and_then(|request| request.show().map(|_| request)) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Payment request not shown: {}", e);
+                std::thread::sleep(receive.poll_interval);
+                continue;
+            }
+        };
+
+        while !request.expired(receive.expiry) {
+            serve_duties(signer, config);
+
+            // Holding the encoder's switch cancels the request, for a wrongly dialed amount
+            #[cfg(feature = "rotary-encoder")]
+            if encoder::next_event(receive.poll_interval) == Some(DialEvent::Held) {
+                info!("Payment request cancelled");
+                break;
+            }
+            #[cfg(not(feature = "rotary-encoder"))]
+            std::thread::sleep(receive.poll_interval);
+            match request.poll() {
+                // The paid screen stays up for a moment before the next customer's code
+                Ok(Some(_)) => {
+                    std::thread::sleep(Duration::from_secs(10));
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Payment check failed: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "vending")]
+fn run_vending(signer: &DeviceSigner, config: &'static AppConfig) -> ! {
+    let mut machine = loop {
+        match VendingMachine::new(signer.pubkey(), &config.vending, &config.receive) {
+            Ok(machine) => break machine,
+            Err(e) => {
+                warn!("Vending unavailable, retrying: {}", e);
+                std::thread::sleep(Duration::from_secs(10));
+            }
+        }
+    };
+
+    loop {
+        serve_duties(signer, config);
+
+        // The scale is read every step, payments are checked at the receive poll interval
+        std::thread::sleep(Duration::from_millis(200));
+        if let Err(e) = machine.step() {
+            warn!("Vending: {}", e);
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+#[cfg(feature = "pay-to-unlock")]
+fn run_pay_to_unlock(signer: &DeviceSigner, nvs: EspDefaultNvsPartition, config: &AppConfig) -> ! {
+    let mut unlocker = loop {
+        match Unlocker::open(signer.pubkey(), &config.unlock, nvs.clone()) {
+            Ok(unlocker) => break unlocker,
+            Err(e) => {
+                warn!("Pay-to-unlock unavailable, retrying: {}", e);
+                std::thread::sleep(Duration::from_secs(10));
+            }
+        }
+    };
+    if let Err(e) = unlocker.show() {
+        warn!("Payment code not shown: {}", e);
+    }
+
+    loop {
+        serve_duties(signer, config);
+
+        std::thread::sleep(config.unlock.poll_interval);
+        if let Err(e) = unlocker.poll() {
+            warn!("Payment check failed: {}", e);
+        }
+    }
+}
+
+#[cfg(all(feature = "camera", not(feature = "air-gap")))]
+fn run_camera(signer: &DeviceSigner, config: &AppConfig) -> ! {
+    let mut scanner = loop {
+        match CameraScanner::new(&config.camera) {
+            Ok(scanner) => break scanner,
+            Err(e) => {
+                warn!("Camera unavailable, retrying: {}", e);
+                std::thread::sleep(Duration::from_secs(10));
+            }
+        }
+    };
+    let outbox = config.outbox_delay.map(Outbox::new);
+    let mut console = LineReader::new();
+
+    loop {
+        serve_duties(signer, config);
+
+        if let Some(outbox) = &outbox {
+            if let Some(line) = console.read_line(Duration::from_millis(100)) {
+                match outbox.handle_command(&line) {
+                    Ok(response) => println!("OK {}", response),
+                    Err(e) => println!("ERR {}", e),
+                }
+            }
+            outbox.release_due(signer);
+        }
+
+        let Some(content) = scanner.scan(Duration::from_secs(1)) else {
+            continue;
+        };
+        let target = match PaymentTarget::parse(&content) {
+            Ok(target) => target,
+            Err(e) => {
+                warn!("Scanned code: {}", e);
+                continue;
+            }
+        };
+        let from_pubkey = signer.pubkey();
+        let (description, instructions) =
+            match target.payment(&from_pubkey, config.camera.lamports, config.camera.max_lamports) {
+                Ok(payment) => payment,
+                Err(e) => {
+                    warn!("Scanned code not paid: {}", e);
+                    continue;
+                }
+            };
+        info!("Code scanned: paying {}", description);
+
+        if let Some(outbox) = &outbox {
+            outbox.queue(&description, instructions, from_pubkey);
+            continue;
+        }
+
+        let blockhash = match get_latest_blockhash() {
+            Ok(blockhash) => blockhash,
+            Err(e) => {
+                warn!("Payment not sent, no blockhash: {}", e);
+                continue;
+            }
+        };
+        let mut transaction = Transaction::new_with_payer(&instructions, Some(&from_pubkey));
+        if let Err(e) = signer.sign_transaction(&mut transaction, blockhash) {
+            warn!("Payment not signed: {}", e);
+            continue;
+        }
+        // The rpc task sends it and logs the outcome while this waits for the next payment
+        if let Err(e) = sender::submit(transaction, &format!("payment of {}", description)) {
+            warn!("Payment not sent: {}", e);
+        }
+    }
+}
+
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+fn run_transfer_demo(signer: &DeviceSigner, nvs: EspDefaultNvsPartition, config: &AppConfig) -> ! {
+    let mut power = config.deep_sleep.and_then(|sleep| match PowerManager::open(nvs.clone(), sleep) {
+        Ok(power) => Some(power),
+        Err(e) => {
+            warn!("Deep sleep unavailable: {}", e);
+            None
+        }
+    });
+    let outbox = config.outbox_delay.map(Outbox::new);
+    #[cfg(not(feature = "cli-console"))]
+    let mut console = LineReader::new();
+    // The outbox holds transfers in RAM, which deep sleep would lose
+    if outbox.is_some() && power.take().is_some() {
+        warn!("Deep sleep is off while OUTBOX_DELAY is set");
+    }
+    // Transfers come from the console and its thread reads every line, the outbox's commands too
+    #[cfg(feature = "cli-console")]
+    {
+        if power.take().is_some() {
+            warn!("Deep sleep is off while the wallet console is up");
+        }
+        if let Err(e) = cli::start(signer.pubkey(), nvs, outbox.clone()) {
+            warn!("Wallet console unavailable: {}", e);
+        }
+    }
+    // A host waiting on the USB port gets no answer from a sleeping device
+    #[cfg(feature = "usb-wallet")]
+    if power.take().is_some() {
+        warn!("Deep sleep is off while the USB wallet protocol is up");
+    }
+    if let Some(power) = power.as_mut() {
+        power.resume();
+    }
+    #[cfg(feature = "rotary-encoder")]
+    let mut dial = AmountDial::new(config.dial);
+    #[cfg(feature = "rotary-encoder")]
+    dial.show();
+
+    loop {
+        serve_duties(signer, config);
+        #[cfg(feature = "cli-console")]
+        cli::send_due(signer, &|from, instruction| unsigned_transfer(config, from, instruction));
+        // Read every cycle, so changes over the console apply from the next one
+        let settings = device_settings();
+        let recipient = settings.recipient;
+
+        match &outbox {
+            // Listen on the console instead of sleeping, so queued transfers can be cancelled
+            #[cfg(not(feature = "cli-console"))]
+            Some(outbox) => {
+                if let Some(line) = console.read_line(settings.poll_interval()) {
+                    match outbox.handle_command(&line) {
+                        Ok(response) => println!("OK {}", response),
+                        Err(e) => println!("ERR {}", e),
+                    }
+                }
+            }
+            #[cfg(not(feature = "rotary-encoder"))]
+            _ => std::thread::sleep(settings.poll_interval()),
+            #[cfg(feature = "rotary-encoder")]
+            _ => {}
+        }
+
+        // Transfer 1 sol, or what was dialed in on the encoder, which waits for it instead of
+        // sleeping. The wallet console makes its own transfers instead of the 1 sol ones.
+        #[cfg(all(feature = "cli-console", not(feature = "rotary-encoder")))]
+        let lamports: Option<u64> = None;
+        #[cfg(not(any(feature = "rotary-encoder", feature = "cli-console")))]
+        let lamports = Some(LAMPORTS_PER_SOL).filter(|_| outbox.as_ref().is_none_or(|outbox| outbox.is_empty()));
+        #[cfg(feature = "rotary-encoder")]
+        let lamports = dial.poll(settings.poll_interval());
+
+        if let Some(outbox) = &outbox {
+            if let Some(lamports) = lamports {
+                let to_pubkey = recipient.unwrap_or_else(Pubkey::new_unique);
+                let instruction = system_instruction::transfer(&signer.pubkey(), &to_pubkey, lamports);
+                outbox.queue(&format!("{} lamports to {}", lamports, to_pubkey), vec![instruction], signer.pubkey());
+            }
+            outbox.release_due(signer);
+            continue;
+        }
+        let Some(lamports) = lamports else {
+            continue;
+        };
+
+        let mut sent = None;
+        // Example: Build and sign a transaction
+        let to_pubkey = recipient.unwrap_or_else(Pubkey::new_unique);
+        let from_pubkey = signer.pubkey();
+        let instruction = system_instruction::transfer(&from_pubkey, &to_pubkey, lamports);
+
+        if let Ok((mut transaction, blockhash)) = unsigned_transfer(config, &from_pubkey, instruction) {
+            info!("Latest blockhash: {}", blockhash);
+
+            if let Err(e) = signer.sign_transaction(&mut transaction, blockhash) {
+                info!("Failed to sign transaction: {}", e);
+                continue;
+            }
+
+            info!("Signed transaction: {}", transaction.signatures[0]);
+            if let Some(power) = power.as_mut() {
+                if let Err(e) = power.track(&transaction.signatures[0]) {
+                    // Without the record a lost send would be paid again after the next wake
+                    warn!("In-flight transaction not persisted, not sending: {}", e);
+                    power.sleep();
+                }
+            }
+
+            // Send the transaction to the Solana network from the rpc task, a device sleeping
+            // between cycles waits for the outcome before it ends the cycle
+            match sender::submit(transaction, &format!("{} lamports to {}", lamports, to_pubkey)) {
+                Ok(outcome) if power.is_some() => sent = outcome.recv().ok().and_then(Result::ok),
+                Ok(_) => {}
+                Err(e) => {
+                    info!("Failed to send transaction: {}", e);
+                }
+            }
+        } else {
+            info!("Failed to get blockhash");
+        }
+
+        // One transfer per wake when sleeping between cycles, a failed one is retried after the next
+        if let Some(power) = power.as_mut() {
+            power.end_cycle(sent.as_deref());
+        }
+    }
+}
+
+// The demo transfer and what to sign it with. LoRa nodes can't fetch a recent blockhash, they
+// advance a durable nonce the device's key is the authority of.
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+#[allow(unused_variables)]
+fn unsigned_transfer(config: &AppConfig, from: &Pubkey, instruction: Instruction) -> Result<(Transaction, Hash), String> {
+    #[cfg(feature = "lora-bridge")]
+    if lora::is_node() {
+        let nonce_account = config.lora_nonce_account.ok_or("LoRa nodes need LORA_NONCE_ACCOUNT")?;
+        let nonce = solrpc::get_nonce(&nonce_account).map_err(|e| e.to_string())?;
+        return Ok((offline::nonce_transaction(&[instruction], from, &nonce_account, from), nonce));
+    }
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    Ok((Transaction::new_with_payer(&[instruction], Some(from)), blockhash))
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, PinDriver, Pull};
+use log::info;
+use solana_program::native_token::LAMPORTS_PER_SOL;
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Message;
+
+use crate::error::WalletError;
+use crate::inspect::{authorizes_tokens, outgoing_lamports};
+use crate::signer::SigningHook;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+const DEBOUNCE: Duration = Duration::from_millis(30);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ApprovalConfig {
+    // SOL transfers up to this amount are signed without asking, token instructions never are
+    pub threshold_lamports: u64,
+    pub timeout: Duration,
+    // Holding the button at least this long rejects instead of approving
+    pub long_press_reject: Option<Duration>,
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self {
+            threshold_lamports: LAMPORTS_PER_SOL / 10,
+            timeout: Duration::from_secs(30),
+            long_press_reject: Some(Duration::from_secs(2)),
+        }
+    }
+}
+
+// Whether signing needs approval on the device: the message sends more than `threshold_lamports`
+// of the signer's SOL, or has the signer authorize a token instruction, whatever its amount
+pub fn needs_approval(message: &Message, signer: &Pubkey, threshold_lamports: u64) -> bool {
+    outgoing_lamports(message, signer) > threshold_lamports || authorizes_tokens(message, signer)
+}
+
+// What is being approved, for the prompt
+pub fn describe(message: &Message, signer: &Pubkey) -> String {
+    let lamports = outgoing_lamports(message, signer);
+    match authorizes_tokens(message, signer) {
+        true => format!("sending {} lamports and a token instruction", lamports),
+        false => format!("sending {} lamports", lamports),
+    }
+}
+
+// Takes the place of an approval input that didn't start: everything that would need approval
+// is refused instead of signed without it
+pub struct NoApproval {
+    pub threshold_lamports: u64,
+    pub reason: String,
+}
+
+impl SigningHook for NoApproval {
+    fn check(&self, message: &Message, signer: &Pubkey) -> Result<(), WalletError> {
+        match needs_approval(message, signer, self.threshold_lamports) {
+            true => Err(WalletError::Refused(format!("Approval unavailable: {}", self.reason))),
+            false => Ok(()),
+        }
+    }
+}
+
+// Requires a press on an active-low button (e.g. the BOOT button) before signing large transfers
+pub struct ButtonApproval {
+    button: PinDriver<'static, AnyIOPin, Input>,
+    config: ApprovalConfig,
+}
+
+impl ButtonApproval {
+    pub fn new(pin: AnyIOPin, config: ApprovalConfig) -> Result<Self, String> {
+        let mut button = PinDriver::input(pin).map_err(|e| format!("Button init: {:?}", e))?;
+        button
+            .set_pull(Pull::Up)
+            .map_err(|e| format!("Button pull-up: {:?}", e))?;
+
+        Ok(Self { button, config })
+    }
+
+    // Waits for a debounced press and release, returning how long the button was held
+    fn wait_for_press(&self, deadline: Instant) -> Option<Duration> {
+        let pressed_at = self.wait_for_level(true, deadline)?;
+        // A press that started before the deadline may still be released after it
+        let released_at = self.wait_for_level(false, Instant::now() + Duration::from_secs(10))?;
+        Some(released_at - pressed_at)
+    }
+
+    fn wait_for_level(&self, pressed: bool, deadline: Instant) -> Option<Instant> {
+        let mut stable_since: Option<Instant> = None;
+
+        while Instant::now() < deadline {
+            if self.button.is_low() == pressed {
+                let since = *stable_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= DEBOUNCE {
+                    return Some(since);
+                }
+            } else {
+                stable_since = None;
+            }
+            std::thread::sleep(SAMPLE_INTERVAL);
+        }
+
+        None
+    }
+}
+
+impl SigningHook for ButtonApproval {
+    fn check(&self, message: &Message, signer: &Pubkey) -> Result<(), WalletError> {
+        if !needs_approval(message, signer, self.config.threshold_lamports) {
+            return Ok(());
+        }
+
+        info!(
+            "Press the button within {}s to approve {}",
+            self.config.timeout.as_secs(),
+            describe(message, signer)
+        );
+
+        let held = self
+            .wait_for_press(Instant::now() + self.config.timeout)
+            .ok_or_else(|| WalletError::Refused("Approval timed out".to_string()))?;
+
+        match self.config.long_press_reject {
+            Some(reject_after) if held >= reject_after => {
+                Err(WalletError::Refused("Rejected with long press".to_string()))
+            }
+            _ => {
+                info!("Transaction approved on device");
+                Ok(())
+            }
+        }
+    }
+}
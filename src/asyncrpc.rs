@@ -0,0 +1,175 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::timer::EspTaskTimerService;
+use solana_program::pubkey::Pubkey;
+use solana_transaction::{Hash, Signature, Transaction};
+
+use crate::error::RpcError;
+use crate::solrpc::{self, AccountInfo, ConfirmationStatus, RpcConfig, SolanaRpcMethod, CONFIRM_POLL_INTERVAL};
+
+// Async variant of the RPC client, for code that keeps several calls in flight or works on while
+// they run, on esp_idf_svc::hal::task::block_on or any other executor. esp_http_client has no
+// async interface, so each call runs the blocking client from solrpc on one of a few worker
+// threads and wakes its future once done, with the same side effects on the display, LED, buzzer
+// and logs. A call starts when it is made rather than when first polled, so calls made one after
+// the other run side by side:
+//   let balance = asyncrpc::get_balance(&address);
+//   let blockhash = asyncrpc::get_latest_blockhash();
+//   let (balance, blockhash) = (balance.await?, blockhash.await?);
+// Waits between confirmation polls are ESP timers, which leave the executor free meanwhile.
+
+// Calls in flight at once, each holds a TLS session of about 40 KB of heap. More wait for a free
+// worker.
+const WORKERS: usize = 2;
+// TLS handshakes run on these
+const WORKER_STACK_SIZE: usize = 10 * 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Started with the first call
+static JOBS: Mutex<Option<Sender<Job>>> = Mutex::new(None);
+
+struct Shared<T> {
+    result: Option<Result<T, RpcError>>,
+    waker: Option<Waker>,
+}
+
+// A call on its way, resolving to what the blocking call returns
+pub struct RpcCall<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Future for RpcCall<T> {
+    type Output = Result<T, RpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.0.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Runs any blocking call on a worker, e.g. `asyncrpc::spawn(solrpc::get_slot)`
+pub fn spawn<T: Send + 'static>(call: impl FnOnce() -> Result<T, RpcError> + Send + 'static) -> RpcCall<T> {
+    let shared = Arc::new(Mutex::new(Shared { result: None, waker: None }));
+    let done = shared.clone();
+    let job: Job = Box::new(move || {
+        let result = call();
+        let waker = {
+            let mut done = done.lock().unwrap();
+            done.result = Some(result);
+            done.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+    if let Err(e) = submit(job) {
+        shared.lock().unwrap().result = Some(Err(e));
+    }
+    RpcCall(shared)
+}
+
+fn submit(job: Job) -> Result<(), RpcError> {
+    let mut jobs = JOBS.lock().unwrap();
+    if jobs.is_none() {
+        *jobs = Some(start_workers()?);
+    }
+    jobs.as_ref()
+        .unwrap()
+        .send(job)
+        .map_err(|_| RpcError::Client("RPC workers stopped".to_string()))
+}
+
+fn start_workers() -> Result<Sender<Job>, RpcError> {
+    let (jobs, received) = channel::<Job>();
+    let received = Arc::new(Mutex::new(received));
+    for index in 0..WORKERS {
+        let received = received.clone();
+        std::thread::Builder::new()
+            .name(format!("rpc{}", index))
+            .stack_size(WORKER_STACK_SIZE)
+            .spawn(move || loop {
+                // The lock is only held while waiting, the next worker takes the next job
+                let Ok(job) = received.lock().unwrap().recv() else {
+                    return;
+                };
+                job();
+            })
+            .map_err(|e| RpcError::Client(format!("RPC worker thread: {:?}", e)))?;
+    }
+    Ok(jobs)
+}
+
+pub fn call(method: SolanaRpcMethod) -> RpcCall<serde_json::Value> {
+    call_with(solrpc::rpc_config(), method)
+}
+
+// Same as call against an explicit endpoint instead of the configured one
+pub fn call_with(config: RpcConfig, method: SolanaRpcMethod) -> RpcCall<serde_json::Value> {
+    spawn(move || solrpc::rpc_call(&config, method))
+}
+
+pub fn get_latest_blockhash() -> RpcCall<Hash> {
+    spawn(solrpc::get_latest_blockhash)
+}
+
+pub fn get_balance(pubkey: &Pubkey) -> RpcCall<u64> {
+    let pubkey = *pubkey;
+    spawn(move || solrpc::get_balance(&pubkey))
+}
+
+pub fn get_account_info(pubkey: &Pubkey) -> RpcCall<Option<AccountInfo>> {
+    let pubkey = *pubkey;
+    spawn(move || solrpc::get_account_info(&pubkey))
+}
+
+pub fn get_signature_status(signature: &Signature) -> RpcCall<Option<ConfirmationStatus>> {
+    let signature = *signature;
+    spawn(move || solrpc::get_signature_status(&signature))
+}
+
+pub fn send_transaction(transaction: &Transaction) -> RpcCall<String> {
+    let transaction = transaction.clone();
+    spawn(move || solrpc::send_transaction(&transaction))
+}
+
+// Polls the signature status until it reaches `target` or the timeout expires, other futures on
+// the executor run in between
+pub async fn confirm_transaction(
+    signature: &Signature,
+    target: ConfirmationStatus,
+    timeout: Duration,
+) -> Result<(), RpcError> {
+    let mut timer = EspTaskTimerService::new()
+        .and_then(|service| service.timer_async())
+        .map_err(|e| RpcError::Client(format!("Timer: {:?}", e)))?;
+    let deadline = Instant::now() + timeout;
+
+    let result = loop {
+        match get_signature_status(signature).await {
+            Ok(Some(status)) if status >= target => break Ok(status),
+            Ok(_) if Instant::now() < deadline => timer
+                .after(CONFIRM_POLL_INTERVAL)
+                .await
+                .map_err(|e| RpcError::Client(format!("Timer: {:?}", e)))?,
+            Ok(_) => {
+                break Err(RpcError::Unconfirmed {
+                    signature: signature.to_string(),
+                    waited: timeout,
+                })
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    solrpc::confirmation_done(signature, &result);
+    result.map(|_| ())
+}
@@ -0,0 +1,83 @@
+use log::info;
+use serde_json::json;
+use solana_transaction::Transaction;
+
+use crate::keystore::flash_encryption_enabled;
+use crate::memo;
+use crate::signer::TxSigner;
+use crate::solrpc::{get_latest_blockhash, send_transaction};
+
+extern "C" {
+    // Provided by the bootloader_support component, not part of the generated bindings
+    fn esp_secure_boot_enabled() -> bool;
+}
+
+#[derive(Debug, Clone)]
+pub struct FirmwareAttestation {
+    pub app_sha256: [u8; 32],
+    pub version: &'static str,
+    pub secure_boot: bool,
+    pub flash_encryption: bool,
+}
+
+impl FirmwareAttestation {
+    // Reads the SHA-256 of the running app partition (as computed by the bootloader for
+    // image verification) together with the secure boot and flash encryption state
+    pub fn read() -> Result<Self, String> {
+        let mut app_sha256 = [0u8; 32];
+        let ret = unsafe {
+            let partition = esp_idf_svc::sys::esp_ota_get_running_partition();
+            if partition.is_null() {
+                return Err("No running app partition".to_string());
+            }
+            esp_idf_svc::sys::esp_partition_get_sha256(partition, app_sha256.as_mut_ptr())
+        };
+        if ret != esp_idf_svc::sys::ESP_OK {
+            return Err(format!("App partition hash failed: {}", ret));
+        }
+
+        Ok(Self {
+            app_sha256,
+            version: env!("CARGO_PKG_VERSION"),
+            secure_boot: unsafe { esp_secure_boot_enabled() },
+            flash_encryption: flash_encryption_enabled(),
+        })
+    }
+
+    pub fn app_sha256_hex(&self) -> String {
+        self.app_sha256.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Compact JSON so the memo stays small, the backend matches `fw` against approved builds
+    pub fn memo(&self) -> String {
+        json!({
+            "t": "attest",
+            "fw": self.app_sha256_hex(),
+            "ver": self.version,
+            "sb": self.secure_boot,
+            "fe": self.flash_encryption,
+        })
+        .to_string()
+    }
+}
+
+// Publishes the attestation as a memo signed by the device key, returning the signature
+pub fn publish_attestation(signer: &impl TxSigner) -> Result<String, String> {
+    let attestation = FirmwareAttestation::read()?;
+    info!(
+        "Firmware {} sha256 {} secure boot {} flash encryption {}",
+        attestation.version,
+        attestation.app_sha256_hex(),
+        attestation.secure_boot,
+        attestation.flash_encryption
+    );
+
+    let device = signer.pubkey();
+    let instruction = memo::memo(&attestation.memo(), &[&device]);
+
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&device));
+    signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+
+    send_transaction(&transaction).map_err(|e| e.to_string())
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+
+// Base58 for logs, the status display and receipts. The solana crates' Display impls each bring
+// a table-driven encoder sized for their type and return a String, this is one small loop over
+// a stack buffer for keys, hashes and signatures alike. Parsing still goes through FromStr.
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// Room for a 64-byte signature, the longest value encoded
+pub const MAX_LEN: usize = 88;
+
+// Formats bytes as base58 where a Pubkey, Hash or Signature would be formatted
+pub struct Base58<'a>(pub &'a [u8]);
+
+impl fmt::Display for Base58<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = [0u8; MAX_LEN];
+        f.write_str(encode(self.0, &mut buf))
+    }
+}
+
+// Encodes into `buf`, input past 64 bytes is left out
+pub fn encode<'a>(bytes: &[u8], buf: &'a mut [u8; MAX_LEN]) -> &'a str {
+    let bytes = &bytes[..bytes.len().min(64)];
+    // Base 58 digits, least significant first
+    let mut len = 0;
+    for &byte in bytes {
+        let mut carry = byte as u32;
+        for digit in &mut buf[..len] {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            buf[len] = (carry % 58) as u8;
+            len += 1;
+            carry /= 58;
+        }
+    }
+    // Every leading zero byte is a leading '1'
+    for _ in bytes.iter().take_while(|byte| **byte == 0) {
+        buf[len] = 0;
+        len += 1;
+    }
+    buf[..len].reverse();
+    for digit in &mut buf[..len] {
+        *digit = ALPHABET[*digit as usize];
+    }
+    core::str::from_utf8(&buf[..len]).unwrap_or_default()
+}
+
+// The first and last characters of a base58 string with "..." between, as wallets show them,
+// or the whole string when it's hardly longer
+pub struct Short<'a>(pub &'a str, pub usize);
+
+impl fmt::Display for Short<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Short(value, ends) = *self;
+        match value.len() > 2 * ends + 3 {
+            true => write!(f, "{}...{}", &value[..ends], &value[value.len() - ends..]),
+            false => f.write_str(value),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::adc::attenuation::DB_11;
+use esp_idf_svc::hal::adc::oneshot::config::{AdcChannelConfig, Calibration};
+use esp_idf_svc::hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
+use esp_idf_svc::hal::adc::ADC1;
+use esp_idf_svc::hal::gpio::Gpio4;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use serde_json::json;
+use solana_transaction::Transaction;
+
+use crate::memo;
+#[cfg(feature = "sensor-log")]
+use crate::sensorlog::Sensor;
+use crate::signer::TxSigner;
+use crate::solrpc::{get_latest_blockhash, send_transaction};
+use crate::spend::unix_time;
+
+// Battery voltage on GPIO4 behind a divider, sampled on a thread of its own. The ADC's eFuse
+// calibration turns counts into millivolts, a two-point correction taken against a multimeter
+// removes what the divider's tolerance adds. Below the alert threshold the device publishes one
+// signed memo per discharge, so whoever looks after remote devices knows which need a visit.
+
+const BATTERY_NAMESPACE: &str = "battery";
+const ALERTED_KEY: &str = "alerted";
+// Reads averaged per reading, single ADC reads scatter by tens of millivolts
+const SAMPLES: u32 = 16;
+// The alert is armed again once the battery is this far above the threshold, after a charge
+const REARM_MV: u32 = 200;
+// After a failed alert, the main loop would retry every few seconds otherwise
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const SAMPLER_STACK_SIZE: usize = 4 * 1024;
+
+type BatteryChannel = AdcChannelDriver<'static, Gpio4, AdcDriver<'static, ADC1>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryConfig {
+    // The ADC calibration scheme, which depends on the chip
+    pub calibration: Calibration,
+    // Battery voltage over pin voltage, 2.0 behind a 1:1 divider
+    pub divider: f64,
+    // Battery voltage = reading * gain + offset_mv, from two readings taken against a multimeter
+    pub gain: f64,
+    pub offset_mv: f64,
+    // Battery voltage at 0% and 100%
+    pub empty_mv: u32,
+    pub full_mv: u32,
+    pub interval: Duration,
+    // A signed low-battery memo is sent below this, None only measures
+    pub alert_below_mv: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryReading {
+    pub millivolts: u32,
+    pub percent: u8,
+}
+
+impl BatteryReading {
+    // Payload for `TelemetrySigner::sign`, the same fields as the alert memo
+    pub fn to_json(self) -> String {
+        json!({ "vbat_mv": self.millivolts, "vbat_pct": self.percent }).to_string()
+    }
+}
+
+struct Alert {
+    threshold: u32,
+    nvs: EspNvs<NvsDefault>,
+    // Sent for this discharge, kept in NVS so a reboot doesn't send it again
+    alerted: bool,
+    retry_at: Option<Instant>,
+}
+
+impl Alert {
+    fn set_alerted(&mut self, alerted: bool) {
+        self.alerted = alerted;
+        if let Err(e) = self.nvs.set_u8(ALERTED_KEY, alerted as u8) {
+            warn!("Low-battery alert state not stored: {:?}", e);
+        }
+    }
+}
+
+static LATEST: Mutex<Option<BatteryReading>> = Mutex::new(None);
+static ALERT: Mutex<Option<Alert>> = Mutex::new(None);
+
+pub fn start(adc1: ADC1, pin: Gpio4, config: BatteryConfig, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+    let adc = AdcDriver::new(adc1).map_err(|e| format!("ADC init: {:?}", e))?;
+    let channel_config = AdcChannelConfig {
+        attenuation: DB_11,
+        calibration: config.calibration,
+        ..Default::default()
+    };
+    let mut channel = AdcChannelDriver::new(adc, pin, &channel_config).map_err(|e| format!("ADC channel: {:?}", e))?;
+
+    if let Some(threshold) = config.alert_below_mv {
+        let nvs = EspNvs::new(nvs, BATTERY_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        let alerted = nvs
+            .get_u8(ALERTED_KEY)
+            .map_err(|e| format!("Low-battery alert state read: {:?}", e))?
+            == Some(1);
+        *ALERT.lock().unwrap() = Some(Alert {
+            threshold,
+            nvs,
+            alerted,
+            retry_at: None,
+        });
+    }
+
+    // Once before returning, so a device that sleeps right after its work still has a reading
+    sample(&mut channel, &config);
+    std::thread::Builder::new()
+        .name("battery".to_string())
+        .stack_size(SAMPLER_STACK_SIZE)
+        .spawn(move || loop {
+            std::thread::sleep(config.interval);
+            sample(&mut channel, &config);
+        })
+        .map_err(|e| format!("Battery sampler: {:?}", e))?;
+    info!("Battery monitor sampling every {}s", config.interval.as_secs());
+    Ok(())
+}
+
+// The last reading, None until the first one succeeded
+pub fn latest() -> Option<BatteryReading> {
+    *LATEST.lock().unwrap()
+}
+
+fn sample(channel: &mut BatteryChannel, config: &BatteryConfig) {
+    match read(channel, config) {
+        Ok(reading) => *LATEST.lock().unwrap() = Some(reading),
+        Err(e) => warn!("Battery not measured: {}", e),
+    }
+}
+
+fn read(channel: &mut BatteryChannel, config: &BatteryConfig) -> Result<BatteryReading, String> {
+    let mut total = 0u32;
+    for _ in 0..SAMPLES {
+        total += channel.read().map_err(|e| format!("ADC read: {:?}", e))? as u32;
+    }
+    let pin_mv = total as f64 / SAMPLES as f64;
+    let millivolts = (pin_mv * config.divider * config.gain + config.offset_mv).max(0.0).round() as u32;
+    let span = config.full_mv.saturating_sub(config.empty_mv).max(1);
+    let percent = (millivolts.saturating_sub(config.empty_mv) as u64 * 100 / span as u64).min(100) as u8;
+    Ok(BatteryReading { millivolts, percent })
+}
+
+// Publishes the low-battery memo once the battery has dropped below the threshold, called from
+// the main loop which holds the signer
+pub fn alert_due(signer: &impl TxSigner) {
+    let Some(reading) = latest() else {
+        return;
+    };
+    let mut alert = ALERT.lock().unwrap();
+    let Some(alert) = alert.as_mut() else {
+        return;
+    };
+    if alert.alerted {
+        if reading.millivolts >= alert.threshold + REARM_MV {
+            info!("Battery back at {} mV, low-battery alert armed again", reading.millivolts);
+            alert.set_alerted(false);
+        }
+        return;
+    }
+    if reading.millivolts >= alert.threshold || alert.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+        return;
+    }
+
+    let memo = json!({
+        "alert": "low-battery",
+        "vbat_mv": reading.millivolts,
+        "vbat_pct": reading.percent,
+        "time": unix_time(),
+    })
+    .to_string();
+    match publish(signer, &memo) {
+        Ok(signature) => {
+            warn!("Battery low at {} mV, alert published: {}", reading.millivolts, signature);
+            alert.retry_at = None;
+            alert.set_alerted(true);
+        }
+        Err(e) => {
+            warn!("Low-battery alert not sent, retrying in {}s: {}", RETRY_DELAY.as_secs(), e);
+            alert.retry_at = Some(Instant::now() + RETRY_DELAY);
+        }
+    }
+}
+
+fn publish(signer: &impl TxSigner, memo: &str) -> Result<String, String> {
+    let device = signer.pubkey();
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    let mut transaction = Transaction::new_with_payer(&[memo::memo(memo, &[&device])], Some(&device));
+    signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+    send_transaction(&transaction).map_err(|e| e.to_string())
+}
+
+// The monitor's readings in volts for the sensor log, which can't sample GPIO4 itself while the
+// monitor holds it
+#[cfg(feature = "sensor-log")]
+pub struct BatterySensor;
+
+#[cfg(feature = "sensor-log")]
+impl Sensor for BatterySensor {
+    fn name(&self) -> &str {
+        "vbat"
+    }
+
+    fn read(&mut self) -> Result<f64, String> {
+        latest()
+            .map(|reading| reading.millivolts as f64 / 1000.0)
+            .ok_or_else(|| "No battery reading yet".to_string())
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{info, warn};
+use serde_json::json;
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Transaction;
+
+use crate::gps::{self, Fix};
+use crate::memo;
+use crate::signer::TxSigner;
+use crate::solrpc::{get_fee_for_message, get_latest_blockhash, send_transaction};
+use crate::spend::{unix_time, SpendLedger};
+
+// Publishes the device's position on-chain, each fix in a transaction signed by the device key so
+// anyone can follow the track and tell it came from this device. Beacons go out often while the
+// device moves and rarely while it stands still, and can carry a geohash cell in place of the
+// exact position:
+//   {"t":"gps","ts":1718000000,"lat":52.52001,"lon":13.40495,"spd":12.4,"sat":9}
+//   {"t":"gps","ts":1718000000,"gh":"u33dc","spd":12.4,"sat":9}
+
+const FEES_NAMESPACE: &str = "gpsfees";
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+// After a failed or refused beacon, the main loop would retry every few seconds otherwise
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BeaconConfig {
+    // NMEA output rate of the module, 9600 for most
+    pub baud_rate: u32,
+    pub moving_interval: Duration,
+    pub still_interval: Duration,
+    // Moving is faster than this, or further than `moving_distance_m` from the last beacon.
+    // A standing receiver reports a few tenths of a m/s from noise alone.
+    pub moving_speed_mps: f64,
+    pub moving_distance_m: f64,
+    // Publishes a geohash of this many characters instead of the position, 5 is a cell of about
+    // 5 km, 7 about 150 m
+    pub geohash_precision: Option<usize>,
+    // Instruction data for a tracking program, with the device key as its one signer account,
+    // in place of a memo
+    pub tracking_program: Option<Pubkey>,
+    // Fees spent on beacons within any 24 hours
+    pub max_fees_per_day: u64,
+}
+
+struct Beacon {
+    config: BeaconConfig,
+    fees: SpendLedger,
+    // Fix of the last beacon published, and when
+    last: Option<(Fix, Instant)>,
+    retry_at: Option<Instant>,
+}
+
+static BEACON: Mutex<Option<Beacon>> = Mutex::new(None);
+
+pub fn start(config: BeaconConfig, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+    if config.geohash_precision.is_some_and(|precision| !(1..=12).contains(&precision)) {
+        return Err("Geohash precision must be 1 to 12 characters".to_string());
+    }
+    *BEACON.lock().unwrap() = Some(Beacon {
+        config,
+        fees: SpendLedger::open_in(nvs, FEES_NAMESPACE)?,
+        last: None,
+        retry_at: None,
+    });
+    info!(
+        "GPS beacon every {}s while moving, every {}s standing",
+        config.moving_interval.as_secs(),
+        config.still_interval.as_secs()
+    );
+    Ok(())
+}
+
+// Publishes the current fix when the interval for the device's motion has passed, called from
+// the main loop which holds the signer
+pub fn beacon_due(signer: &impl TxSigner) {
+    let mut beacon = BEACON.lock().unwrap();
+    let Some(beacon) = beacon.as_mut() else {
+        return;
+    };
+    let Some(fix) = gps::latest() else {
+        return;
+    };
+    if beacon.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+        return;
+    }
+    if let Some((last, at)) = beacon.last {
+        let moving = fix.speed_mps >= beacon.config.moving_speed_mps
+            || distance_m(&last, &fix) >= beacon.config.moving_distance_m;
+        let interval = match moving {
+            true => beacon.config.moving_interval,
+            false => beacon.config.still_interval,
+        };
+        if at.elapsed() < interval {
+            return;
+        }
+    }
+
+    let payload = payload(&fix, &beacon.config);
+    let device = signer.pubkey();
+    let instruction = match beacon.config.tracking_program {
+        Some(program) => Instruction::new_with_bytes(program, payload.as_bytes(), vec![AccountMeta::new_readonly(device, true)]),
+        None => memo::memo(&payload, &[&device]),
+    };
+    // The satellites' time stands in until SNTP has synced
+    let now = unix_time().unwrap_or(fix.time);
+    match publish(signer, instruction, &mut beacon.fees, beacon.config.max_fees_per_day, now) {
+        Ok(signature) => {
+            info!("Position published: {}", signature);
+            beacon.last = Some((fix, Instant::now()));
+            beacon.retry_at = None;
+        }
+        Err(e) => {
+            warn!("Position not published, retrying in {}s: {}", RETRY_DELAY.as_secs(), e);
+            beacon.retry_at = Some(Instant::now() + RETRY_DELAY);
+        }
+    }
+}
+
+fn payload(fix: &Fix, config: &BeaconConfig) -> String {
+    // One decimal of speed, five of a degree are about a meter
+    let round = |value: f64, scale: f64| (value * scale).round() / scale;
+    let mut payload = json!({ "t": "gps", "ts": fix.time });
+    match config.geohash_precision {
+        Some(precision) => payload["gh"] = json!(geohash(fix.latitude, fix.longitude, precision)),
+        None => {
+            payload["lat"] = json!(round(fix.latitude, 1e5));
+            payload["lon"] = json!(round(fix.longitude, 1e5));
+        }
+    }
+    payload["spd"] = json!(round(fix.speed_mps, 10.0));
+    if let Some(satellites) = fix.satellites {
+        payload["sat"] = json!(satellites);
+    }
+    payload.to_string()
+}
+
+fn publish(signer: &impl TxSigner, instruction: Instruction, fees: &mut SpendLedger, budget: u64, now: u64) -> Result<String, String> {
+    let device = signer.pubkey();
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&device));
+    transaction.message.recent_blockhash = blockhash;
+
+    let fee = get_fee_for_message(&transaction.message).map_err(|e| e.to_string())?;
+    let spent = fees.spent_within(DAY, now);
+    if spent.saturating_add(fee) > budget {
+        return Err(format!("daily fee budget reached ({} of {} lamports)", spent, budget));
+    }
+
+    signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+    let signature = send_transaction(&transaction).map_err(|e| e.to_string())?;
+    // Counted as soon as it is sent, whether it lands or not
+    if let Err(e) = fees.record(fee, now) {
+        warn!("Beacon fee not recorded: {}", e);
+    }
+    Ok(signature)
+}
+
+// Standard geohash, longitude and latitude bits interleaved, five to a base32 character
+fn geohash(latitude: f64, longitude: f64, precision: usize) -> String {
+    let (mut latitudes, mut longitudes) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    while hash.len() < precision {
+        let mut index = 0;
+        for _ in 0..5 {
+            let (range, value): (&mut (f64, f64), f64) = match even {
+                true => (&mut longitudes, longitude),
+                false => (&mut latitudes, latitude),
+            };
+            let middle = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= middle {
+                index |= 1;
+                range.0 = middle;
+            } else {
+                range.1 = middle;
+            }
+            even = !even;
+        }
+        hash.push(GEOHASH_ALPHABET[index] as char);
+    }
+    hash
+}
+
+// Great-circle distance between two fixes
+fn distance_m(from: &Fix, to: &Fix) -> f64 {
+    let (from_latitude, to_latitude) = (from.latitude.to_radians(), to.latitude.to_radians());
+    let latitude_delta = to_latitude - from_latitude;
+    let longitude_delta = (to.longitude - from.longitude).to_radians();
+    let a = (latitude_delta / 2.0).sin().powi(2)
+        + from_latitude.cos() * to_latitude.cos() * (longitude_delta / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
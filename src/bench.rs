@@ -0,0 +1,106 @@
+use std::hint::black_box;
+#[cfg(not(feature = "remote-signer"))]
+use std::time::Duration;
+use std::time::Instant;
+
+use base64::{engine::general_purpose, Engine as _};
+use log::{info, warn};
+use serde_json::{json, Value};
+use solana_keypair::{Keypair, Signer};
+use solana_program::pubkey::Pubkey;
+use solana_system_interface::instruction as system_instruction;
+use solana_transaction::{Hash, Transaction};
+
+use crate::chip;
+use crate::ed25519;
+use crate::leanjson;
+#[cfg(not(feature = "remote-signer"))]
+use crate::solrpc;
+
+// A getLatestBlockhash answer as nodes send it, for the parse timings
+const SAMPLE_RESPONSE: &str = r#"{"jsonrpc":"2.0","result":{"context":{"apiVersion":"2.2.14","slot":345678901},"value":{"blockhash":"EkSnNWid2cvwEVnVx9aBqawnmiCNiDgp3gUdkDPTKN1N","lastValidBlockHeight":323456789}},"id":1}"#;
+
+// Times the work every transaction goes through on this chip and logs it, so an optimization
+// can be checked on each chip it targets: signing with both backends, bincode serialization,
+// base64 encoding, building and parsing RPC JSON, and with the network up the RPC round trip.
+// `iterations` applies to the local steps, `rpc_calls` to the round trips.
+#[cfg_attr(feature = "remote-signer", allow(unused_variables))]
+pub fn run(keypair: &Keypair, iterations: u32, rpc_calls: u32) {
+    info!("Benchmarking on {}", chip::describe());
+
+    if let Err(e) = ed25519::benchmark(keypair, iterations) {
+        warn!("Signing benchmark failed: {}", e);
+    }
+
+    let payer = keypair.pubkey();
+    let instruction = system_instruction::transfer(&payer, &Pubkey::new_from_array([7; 32]), 1_000_000);
+    let transaction = Transaction::new_signed_with_payer(&[instruction], Some(&payer), &[keypair], Hash::default());
+    let serialized = match bincode::serialize(&transaction) {
+        Ok(serialized) => serialized,
+        Err(e) => {
+            warn!("Benchmark transaction not serialized: {:?}", e);
+            return;
+        }
+    };
+    let encoded = general_purpose::STANDARD.encode(&serialized);
+
+    report("Message serialize", iterations, || transaction.message.serialize());
+    report("Transaction bincode", iterations, || bincode::serialize(&transaction));
+    report("Transaction base64", iterations, || general_purpose::STANDARD.encode(&serialized));
+    report("sendTransaction JSON build", iterations, || {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "sendTransaction",
+            "params": [encoded, { "encoding": "base64" }],
+        })
+        .to_string()
+    });
+    report("Response parse, serde_json", iterations, || {
+        serde_json::from_str::<Value>(SAMPLE_RESPONSE)
+            .map(|response| response["result"]["value"]["blockhash"].as_str().map(str::len))
+    });
+    report("Response parse, lean-json", iterations, || {
+        leanjson::str_at(SAMPLE_RESPONSE.as_bytes(), &["result", "value", "blockhash"])
+            .map(|blockhash| blockhash.map(str::len))
+    });
+
+    #[cfg(not(feature = "remote-signer"))]
+    rpc_latency(rpc_calls);
+}
+
+// Round trips of getLatestBlockhash, each over a fresh connection as the client makes them,
+// so the TLS handshake is part of every one
+#[cfg(not(feature = "remote-signer"))]
+fn rpc_latency(calls: u32) {
+    let mut timings = Vec::new();
+    for _ in 0..calls {
+        let start = Instant::now();
+        match solrpc::get_latest_blockhash() {
+            Ok(_) => timings.push(start.elapsed()),
+            Err(e) => warn!("Benchmark RPC call failed: {}", e),
+        }
+    }
+
+    let (Some(fastest), Some(slowest)) = (timings.iter().min(), timings.iter().max()) else {
+        warn!("RPC latency not measured, every call failed");
+        return;
+    };
+    let average = timings.iter().sum::<Duration>() / timings.len() as u32;
+    info!(
+        "getLatestBlockhash round trip: {} ms average, {} ms fastest, {} ms slowest over {} calls",
+        average.as_millis(),
+        fastest.as_millis(),
+        slowest.as_millis(),
+        timings.len()
+    );
+}
+
+fn report<T>(label: &str, iterations: u32, mut step: impl FnMut() -> T) {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(step());
+    }
+    let per_call = start.elapsed() / iterations.max(1);
+    info!("{}: {} us per call", label, per_call.as_micros());
+}
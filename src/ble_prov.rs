@@ -0,0 +1,147 @@
+use std::ffi::{c_void, CString};
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::{
+    esp_err_t, malloc, ssize_t, wifi_prov_event_handler_t, wifi_prov_mgr_config_t,
+    wifi_prov_mgr_deinit, wifi_prov_mgr_endpoint_create, wifi_prov_mgr_endpoint_register, wifi_prov_mgr_init,
+    wifi_prov_mgr_start_provisioning, wifi_prov_mgr_wait, wifi_prov_scheme_ble,
+    wifi_prov_scheme_ble_event_cb_free_btdm, wifi_prov_security_WIFI_PROV_SECURITY_1, ESP_FAIL, ESP_OK,
+};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{AsyncWifi, Configuration, EspWifi};
+use log::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::config::{device_name, device_secret, WifiCredentials, BLE_POP_KEY};
+
+// Development proof of possession from `ble_pop` in cfg.toml, empty in release builds, which
+// read a per-device one from NVS (see build.rs)
+const DEV_BLE_POP: &str = env!("RESP32SOL_BLE_POP");
+
+// Same name as the endpoint in ESP-IDF's provisioning example, so `esp_prov.py --custom_data`
+// can push the RPC endpoint
+const RPC_ENDPOINT: &str = "custom-data";
+
+// RPC endpoint pushed by the phone, picked up by the signer once the keystore is open
+static PROVISIONED_RPC_URL: Mutex<Option<String>> = Mutex::new(None);
+
+fn check(ret: esp_err_t, what: &str) -> Result<(), String> {
+    match ret {
+        ESP_OK => Ok(()),
+        ret => Err(format!("{}: {}", what, ret)),
+    }
+}
+
+// Advertises as "REsp32Sol-XXXX" and runs ESP-IDF's provisioning manager (security 1 with the
+// proof of possession) until a phone has pushed WiFi credentials that connect, then stores
+// them in NVS like any other credentials. Print the proof of possession on the device label,
+// anyone within BLE range who knows it can configure an unprovisioned device.
+pub fn provision(wifi: &AsyncWifi<EspWifi<'static>>, nvs: EspDefaultNvsPartition) -> Result<WifiCredentials, String> {
+    let pop = device_secret(nvs.clone(), BLE_POP_KEY, DEV_BLE_POP)?
+        .ok_or("BLE provisioning needs a proof of possession, store ble_pop in the secrets NVS namespace")?;
+
+    let service_name = device_name()?;
+
+    let service_name_c = CString::new(service_name.as_str()).unwrap();
+    let pop = Zeroizing::new(
+        CString::new(pop.as_bytes()).map_err(|_| "BLE proof of possession contains a NUL byte")?.into_bytes_with_nul(),
+    );
+    let endpoint = CString::new(RPC_ENDPOINT).unwrap();
+
+    let config = wifi_prov_mgr_config_t {
+        scheme: unsafe { wifi_prov_scheme_ble },
+        // Releases the BT controller memory once provisioning is over
+        scheme_event_handler: wifi_prov_event_handler_t {
+            event_cb: Some(wifi_prov_scheme_ble_event_cb_free_btdm),
+            user_data: std::ptr::null_mut(),
+        },
+        app_event_handler: wifi_prov_event_handler_t {
+            event_cb: None,
+            user_data: std::ptr::null_mut(),
+        },
+    };
+
+    unsafe {
+        check(wifi_prov_mgr_init(config), "Provisioning init")?;
+        let started = check(wifi_prov_mgr_endpoint_create(endpoint.as_ptr()), "Provisioning endpoint")
+            .and_then(|_| {
+                check(
+                    wifi_prov_mgr_start_provisioning(
+                        wifi_prov_security_WIFI_PROV_SECURITY_1,
+                        pop.as_ptr() as *const c_void,
+                        service_name_c.as_ptr(),
+                        std::ptr::null(),
+                    ),
+                    "Provisioning start",
+                )
+            })
+            .and_then(|_| {
+                check(
+                    wifi_prov_mgr_endpoint_register(endpoint.as_ptr(), Some(rpc_endpoint_handler), std::ptr::null_mut()),
+                    "Provisioning endpoint register",
+                )
+            });
+        if let Err(e) = started {
+            wifi_prov_mgr_deinit();
+            return Err(e);
+        }
+
+        info!("BLE provisioning started, connect to {} with the proof of possession", service_name);
+        wifi_prov_mgr_wait();
+        wifi_prov_mgr_deinit();
+    }
+
+    // The manager leaves the credentials in the driver's station configuration
+    let credentials = match wifi.get_configuration().map_err(|e| format!("WiFi config read: {:?}", e))? {
+        Configuration::Client(client) | Configuration::Mixed(client, _) => {
+            WifiCredentials::new(client.ssid.as_str(), client.password.as_str())?
+        }
+        _ => return Err("Provisioning left no station configuration".to_string()),
+    };
+    credentials.store(nvs)?;
+
+    info!("BLE provisioning complete, joined {}", credentials.ssid);
+    Ok(credentials)
+}
+
+pub fn take_rpc_url() -> Option<String> {
+    PROVISIONED_RPC_URL.lock().unwrap().take()
+}
+
+// Runs in the protocomm task. The reply buffer is freed by protocomm, so it comes from malloc.
+unsafe extern "C" fn rpc_endpoint_handler(
+    _session_id: u32,
+    inbuf: *const u8,
+    inlen: ssize_t,
+    outbuf: *mut *mut u8,
+    outlen: *mut ssize_t,
+    _priv_data: *mut c_void,
+) -> esp_err_t {
+    let data = match inbuf.is_null() || inlen <= 0 {
+        true => &[][..],
+        false => std::slice::from_raw_parts(inbuf, inlen as usize),
+    };
+
+    let reply: &[u8] = match std::str::from_utf8(data).map(str::trim) {
+        // Watch-only firmware has no keystore to keep the endpoint in
+        _ if cfg!(feature = "watch-only") => b"ERR not supported by watch-only firmware",
+        Ok(url) if url.starts_with("https://") => {
+            info!("RPC endpoint received over BLE");
+            *PROVISIONED_RPC_URL.lock().unwrap() = Some(url.to_string());
+            b"OK"
+        }
+        _ => {
+            warn!("Rejected RPC endpoint pushed over BLE");
+            b"ERR expected an https:// URL"
+        }
+    };
+
+    let buf = malloc(reply.len()) as *mut u8;
+    if buf.is_null() {
+        return ESP_FAIL;
+    }
+    std::ptr::copy_nonoverlapping(reply.as_ptr(), buf, reply.len());
+    *outbuf = buf;
+    *outlen = reply.len() as ssize_t;
+    ESP_OK
+}
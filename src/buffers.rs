@@ -0,0 +1,64 @@
+use std::sync::Mutex;
+
+// The RAM the firmware sets aside for RPC traffic and the queues between its tasks, in one place
+// so a board can trade it against what else it runs: an ESP32-C3 with a display and TLS pinning
+// is short of heap, an S3 with PSRAM can afford larger reads and responses. `configure` applies
+// a set at boot, before the RPC client and the tasks come up, DEFAULT applies until then.
+
+#[derive(Debug, Clone, Copy)]
+pub struct BufferConfig {
+    // Bytes ESP-IDF's HTTP client receives into, and the chunk read out of it at a time. Larger
+    // chunks mean fewer reads for large responses, both are allocated for every call.
+    pub http_buffer: usize,
+    pub read_chunk: usize,
+    // Largest response held in internal RAM, larger ones fail unless the board has PSRAM.
+    // getClusterNodes and other large responses go through solrpc::rpc_call_streaming instead.
+    pub max_response: usize,
+    // Largest response kept in PSRAM on boards that have it, e.g. a busy program's accounts
+    pub max_external_response: usize,
+    // What each thread's JSON request and response buffers keep of their capacity between calls.
+    // Blockhashes, balances and signature statuses fit in 4 KB with room to spare, a larger
+    // response's memory goes back to the heap. Responses move to PSRAM past this size.
+    pub json_scratch: usize,
+    // Signed transactions waiting for the rpc task. A full queue refuses more, a blockhash is
+    // only valid for about a minute anyway.
+    pub send_queue: usize,
+    // Status display updates waiting for the ui task, more are dropped so a stuck bus never
+    // holds up the sender
+    pub display_queue: usize,
+}
+
+impl BufferConfig {
+    pub const DEFAULT: BufferConfig = BufferConfig {
+        http_buffer: 512,
+        read_chunk: 256,
+        max_response: 128 * 1024,
+        max_external_response: 1024 * 1024,
+        json_scratch: 4 * 1024,
+        send_queue: 4,
+        display_queue: 16,
+    };
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+static CONFIG: Mutex<BufferConfig> = Mutex::new(BufferConfig::DEFAULT);
+
+pub fn configure(config: BufferConfig) -> Result<(), String> {
+    if config.read_chunk == 0 || config.http_buffer == 0 {
+        return Err("Buffer sizes must not be 0".to_string());
+    }
+    if config.send_queue == 0 || config.display_queue == 0 {
+        return Err("Queue depths must not be 0".to_string());
+    }
+    *CONFIG.lock().unwrap() = config;
+    Ok(())
+}
+
+pub fn config() -> BufferConfig {
+    *CONFIG.lock().unwrap()
+}
@@ -0,0 +1,131 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::ledc::config::TimerConfig;
+use esp_idf_svc::hal::ledc::{LedcDriver, LedcTimerDriver, CHANNEL0, TIMER0};
+use esp_idf_svc::hal::units::Hertz;
+use log::{info, warn};
+
+use crate::error::RpcError;
+
+// Passive piezo buzzer on a LEDC channel, the LEDC timer's frequency setting the pitch. Sounds
+// play on a thread of their own, nothing that triggers one waits for it to finish.
+
+// Passive buzzers are loudest around their 2 to 4 kHz resonance
+const SENT: &[(u32, u64)] = &[(2_700, 60)];
+const CONFIRMED: &[(u32, u64)] = &[(2_000, 80), (0, 40), (3_000, 120)];
+const FAILED: &[(u32, u64)] = &[(400, 250), (0, 80), (400, 250)];
+const INCOMING: &[(u32, u64)] = &[(1_800, 70), (0, 30), (2_400, 70), (0, 30), (3_200, 140)];
+// Sounds asked for while others are still playing, the rest are dropped rather than queued up
+const MAX_PENDING: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sound {
+    Sent,
+    Confirmed,
+    Failed,
+    Incoming,
+}
+
+impl Sound {
+    // Tones as frequency in Hz and length in ms, 0 Hz a pause
+    fn tones(self) -> &'static [(u32, u64)] {
+        match self {
+            Sound::Sent => SENT,
+            Sound::Confirmed => CONFIRMED,
+            Sound::Failed => FAILED,
+            Sound::Incoming => INCOMING,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BuzzerConfig {
+    // Off leaves the pin alone, for devices that have to stay quiet
+    pub enabled: bool,
+    // Duty cycle in percent, 50 is the loudest
+    pub volume: u32,
+}
+
+static SOUNDS: Mutex<Option<Sender<Sound>>> = Mutex::new(None);
+static PENDING: Mutex<usize> = Mutex::new(0);
+
+pub fn start(timer: TIMER0, channel0: CHANNEL0, pin: AnyIOPin, config: BuzzerConfig) -> Result<(), String> {
+    if !config.enabled {
+        info!("Buzzer disabled");
+        return Ok(());
+    }
+    let timer = LedcTimerDriver::new(timer, &TimerConfig::new().frequency(Hertz(2_000)))
+        .map_err(|e| format!("LEDC timer init: {:?}", e))?;
+    let mut driver = LedcDriver::new(channel0, &timer, pin).map_err(|e| format!("LEDC init: {:?}", e))?;
+    driver.set_duty(0).map_err(|e| format!("LEDC duty: {:?}", e))?;
+    let buzzer = Buzzer {
+        duty: driver.get_max_duty() * config.volume.min(50) / 100,
+        timer,
+        driver,
+    };
+
+    let (sounds, received) = channel();
+    std::thread::Builder::new()
+        .name("buzzer".to_string())
+        .stack_size(4 * 1024)
+        .spawn(move || run(buzzer, received))
+        .map_err(|e| format!("Buzzer thread: {:?}", e))?;
+
+    *SOUNDS.lock().unwrap() = Some(sounds);
+    info!("Buzzer up");
+    Ok(())
+}
+
+pub fn play(sound: Sound) {
+    let mut pending = PENDING.lock().unwrap();
+    if *pending >= MAX_PENDING {
+        return;
+    }
+    if let Some(sounds) = SOUNDS.lock().unwrap().as_ref() {
+        if sounds.send(sound).is_ok() {
+            *pending += 1;
+        }
+    }
+}
+
+// Called with the outcome of every sendTransaction
+pub fn transaction_sent(result: &Result<String, RpcError>) {
+    play(match result {
+        Ok(_) => Sound::Sent,
+        Err(_) => Sound::Failed,
+    });
+}
+
+fn run(mut buzzer: Buzzer, sounds: Receiver<Sound>) {
+    while let Ok(sound) = sounds.recv() {
+        if let Err(e) = buzzer.play(sound) {
+            warn!("Buzzer: {}", e);
+        }
+        *PENDING.lock().unwrap() -= 1;
+    }
+}
+
+struct Buzzer {
+    timer: LedcTimerDriver<'static, TIMER0>,
+    driver: LedcDriver<'static>,
+    duty: u32,
+}
+
+impl Buzzer {
+    fn play(&mut self, sound: Sound) -> Result<(), String> {
+        for &(frequency, ms) in sound.tones() {
+            if frequency > 0 {
+                self.timer
+                    .set_frequency(Hertz(frequency))
+                    .map_err(|e| format!("LEDC frequency: {:?}", e))?;
+                self.driver.set_duty(self.duty).map_err(|e| format!("LEDC duty: {:?}", e))?;
+            }
+            std::thread::sleep(Duration::from_millis(ms));
+            self.driver.set_duty(0).map_err(|e| format!("LEDC duty: {:?}", e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,2 @@
+// Bindings for src/camera.rs, see the esp32-camera component in Cargo.toml
+#include "esp_camera.h"
@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::camera::{
+    camera_config_t, camera_config_t__bindgen_ty_1, camera_config_t__bindgen_ty_2,
+    camera_fb_location_t_CAMERA_FB_IN_PSRAM, camera_grab_mode_t_CAMERA_GRAB_LATEST, esp_camera_fb_get,
+    esp_camera_fb_return, esp_camera_init, framesize_t_FRAMESIZE_QVGA, ledc_channel_t_LEDC_CHANNEL_0,
+    ledc_timer_t_LEDC_TIMER_0, pixformat_t_PIXFORMAT_GRAYSCALE,
+};
+use esp_idf_svc::sys::ESP_OK;
+use log::{info, warn};
+
+#[cfg(feature = "air-gap")]
+use crate::airgap::QrScanner;
+
+// QR codes read through the OV2640 of an ESP32-CAM, with Espressif's esp32-camera component
+// driving the sensor (see the README for adding it). Frames come in grayscale at QVGA, enough
+// for a phone screen held 10 to 20 cm away, and rqrr looks for codes in them.
+
+const XCLK_HZ: i32 = 20_000_000;
+// Between frames that failed to arrive, so a stalled sensor doesn't spin the CPU
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// GPIO numbers as the esp32-camera driver takes them, -1 for a line that isn't wired
+#[derive(Debug, Clone, Copy)]
+pub struct CameraPins {
+    pub power_down: i32,
+    pub reset: i32,
+    pub xclk: i32,
+    pub sda: i32,
+    pub scl: i32,
+    // D0 to D7, the Y2 to Y9 of most schematics
+    pub data: [i32; 8],
+    pub vsync: i32,
+    pub href: i32,
+    pub pclk: i32,
+}
+
+impl CameraPins {
+    pub const AI_THINKER: CameraPins = CameraPins {
+        power_down: 32,
+        reset: -1,
+        xclk: 0,
+        sda: 26,
+        scl: 27,
+        data: [5, 18, 19, 21, 36, 39, 34, 35],
+        vsync: 25,
+        href: 23,
+        pclk: 22,
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CameraConfig {
+    pub pins: CameraPins,
+    // A code held in view is read from every frame, it only counts again once it has been out
+    // of view this long
+    pub repeat_after: Duration,
+    // Scan-to-pay: an address is paid `lamports`, a Solana Pay URL what it asks for up to
+    // `max_lamports`. Unused by air-gap builds.
+    pub lamports: u64,
+    pub max_lamports: u64,
+}
+
+pub struct CameraScanner {
+    repeat_after: Duration,
+    // The code read last and when it was last in view
+    last: Option<(String, Instant)>,
+}
+
+impl CameraScanner {
+    pub fn new(config: &CameraConfig) -> Result<Self, String> {
+        let pins = &config.pins;
+        let camera_config = camera_config_t {
+            pin_pwdn: pins.power_down,
+            pin_reset: pins.reset,
+            pin_xclk: pins.xclk,
+            __bindgen_anon_1: camera_config_t__bindgen_ty_1 { pin_sccb_sda: pins.sda },
+            __bindgen_anon_2: camera_config_t__bindgen_ty_2 { pin_sccb_scl: pins.scl },
+            pin_d7: pins.data[7],
+            pin_d6: pins.data[6],
+            pin_d5: pins.data[5],
+            pin_d4: pins.data[4],
+            pin_d3: pins.data[3],
+            pin_d2: pins.data[2],
+            pin_d1: pins.data[1],
+            pin_d0: pins.data[0],
+            pin_vsync: pins.vsync,
+            pin_href: pins.href,
+            pin_pclk: pins.pclk,
+            xclk_freq_hz: XCLK_HZ,
+            ledc_timer: ledc_timer_t_LEDC_TIMER_0,
+            ledc_channel: ledc_channel_t_LEDC_CHANNEL_0,
+            pixel_format: pixformat_t_PIXFORMAT_GRAYSCALE,
+            frame_size: framesize_t_FRAMESIZE_QVGA,
+            // Only used for JPEG
+            jpeg_quality: 12,
+            fb_count: 1,
+            fb_location: camera_fb_location_t_CAMERA_FB_IN_PSRAM,
+            // A frame taken while the last one was decoded is stale, the newest is wanted
+            grab_mode: camera_grab_mode_t_CAMERA_GRAB_LATEST,
+            // Its own SCCB bus, not a shared I2C port
+            sccb_i2c_port: -1,
+        };
+        let ret = unsafe { esp_camera_init(&camera_config) };
+        if ret != ESP_OK {
+            return Err(format!("Camera init: error {}", ret));
+        }
+        info!("Camera up, scanning for QR codes");
+        Ok(Self {
+            repeat_after: config.repeat_after,
+            last: None,
+        })
+    }
+
+    // The next code that came into view within the timeout
+    pub fn scan(&mut self, timeout: Duration) -> Option<String> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let Some(content) = self.read_frame() else {
+                continue;
+            };
+            let repeated = matches!(&self.last, Some((last, seen)) if *last == content && seen.elapsed() < self.repeat_after);
+            self.last = Some((content.clone(), Instant::now()));
+            if !repeated {
+                return Some(content);
+            }
+        }
+        None
+    }
+
+    // Takes a frame and decodes the first QR code found in it
+    fn read_frame(&mut self) -> Option<String> {
+        let frame = unsafe { esp_camera_fb_get() };
+        if frame.is_null() {
+            warn!("Camera frame not captured");
+            std::thread::sleep(RETRY_INTERVAL);
+            return None;
+        }
+        let (width, height) = unsafe { ((*frame).width, (*frame).height) };
+        let pixels = unsafe { std::slice::from_raw_parts((*frame).buf, (*frame).len) };
+        // Copies the pixels, so the frame buffer goes back to the driver before decoding
+        let mut image = (pixels.len() >= width * height)
+            .then(|| rqrr::PreparedImage::prepare_from_greyscale(width, height, |x, y| pixels[y * width + x]));
+        unsafe { esp_camera_fb_return(frame) };
+
+        // Partly visible or blurred codes fail to decode, the next frame gets another try
+        image
+            .as_mut()?
+            .detect_grids()
+            .into_iter()
+            .find_map(|grid| grid.decode().ok().map(|(_, content)| content))
+    }
+}
+
+#[cfg(feature = "air-gap")]
+impl QrScanner for CameraScanner {
+    fn scan(&mut self, timeout: Duration) -> Option<String> {
+        CameraScanner::scan(self, timeout)
+    }
+}
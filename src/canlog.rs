@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::can::config::{Config, Filter, Mode, Timing};
+use esp_idf_svc::hal::can::{CanDriver, CAN};
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use solana_transaction::Transaction;
+
+use crate::memo;
+use crate::signer::TxSigner;
+use crate::solrpc::{get_fee_for_message, get_latest_blockhash, send_transaction};
+use crate::spend::{unix_time, SpendLedger};
+
+// Vehicle or machine telemetry off a CAN bus (TWAI on the ESP32s, through a 3.3 V transceiver
+// such as the SN65HVD230), in listen-only mode so the device never puts a bit on the bus.
+// Configured signals are collected in windows, and each window's summary is anchored on-chain
+// in a memo signed by the device key, either in full or as its SHA-256. Every summary carries
+// the hash of the one before, the chain kept in NVS across reboots, so a missing, reordered or
+// edited window shows against the anchors.
+
+const CAN_NAMESPACE: &str = "canlog";
+const SEQ_KEY: &str = "seq";
+const HEAD_KEY: &str = "head";
+const FEES_NAMESPACE: &str = "canfees";
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+// Leaves room in the 1232 byte transaction for the signature, accounts and blockhash
+const MAX_MEMO_LEN: usize = 700;
+// Windows held back by the budget or an outage, the oldest are dropped past this
+const MAX_PENDING: usize = 64;
+// After a failed or refused anchor, the main loop would retry every few seconds otherwise
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const RECEIVE_TIMEOUT_MS: u64 = 1000;
+const RX_QUEUE_LEN: u32 = 32;
+const LISTENER_STACK_SIZE: usize = 6 * 1024;
+
+// A value in a frame's data, like a DBC signal but at byte granularity
+#[derive(Debug, Clone, Copy)]
+pub struct CanSignal {
+    pub name: &'static str,
+    pub id: u32,
+    // Bits of the identifier that have to match, e.g. 0x00FFFF00 to match a J1939 PGN from any
+    // source address
+    pub id_mask: u32,
+    pub extended: bool,
+    // First data byte and number of bytes, 1 to 8
+    pub start: usize,
+    pub len: usize,
+    pub big_endian: bool,
+    pub signed: bool,
+    // value = raw * scale + offset
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl CanSignal {
+    fn matches(&self, id: u32, extended: bool) -> bool {
+        self.extended == extended && id & self.id_mask == self.id & self.id_mask
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<f64> {
+        let bytes = data.get(self.start..self.start.checked_add(self.len)?)?;
+        if bytes.is_empty() || bytes.len() > 8 {
+            return None;
+        }
+        let raw = match self.big_endian {
+            true => bytes.iter().fold(0u64, |raw, &byte| (raw << 8) | byte as u64),
+            false => bytes.iter().rev().fold(0u64, |raw, &byte| (raw << 8) | byte as u64),
+        };
+        let bits = 8 * bytes.len() as u32;
+        let value = match self.signed {
+            // Sign-extends the top bit of the field
+            true => ((raw << (64 - bits)) as i64 >> (64 - bits)) as f64,
+            false => raw as f64,
+        };
+        Some(value * self.scale + self.offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    // The summary itself goes on-chain, readable by anyone
+    Values,
+    // Only its hash, the summary is logged for whoever keeps the device's logs
+    Hash,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CanLogConfig {
+    pub timing: Timing,
+    pub signals: &'static [CanSignal],
+    // Frames are summarized and anchored once per window
+    pub window: Duration,
+    pub anchor: Anchor,
+    // Fees spent on anchors within any 24 hours
+    pub max_fees_per_day: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Aggregate {
+    count: u32,
+    min: f64,
+    max: f64,
+    sum: f64,
+    last: f64,
+}
+
+struct Window {
+    started: Option<u64>,
+    opened: Instant,
+    frames: u32,
+    aggregates: Vec<Option<Aggregate>>,
+}
+
+impl Window {
+    fn new(signals: usize) -> Self {
+        Self {
+            started: unix_time(),
+            opened: Instant::now(),
+            frames: 0,
+            aggregates: vec![None; signals],
+        }
+    }
+
+    fn add(&mut self, signal: usize, value: f64) {
+        let aggregate = self.aggregates[signal].get_or_insert(Aggregate {
+            count: 0,
+            min: value,
+            max: value,
+            sum: 0.0,
+            last: value,
+        });
+        aggregate.count += 1;
+        aggregate.min = aggregate.min.min(value);
+        aggregate.max = aggregate.max.max(value);
+        aggregate.sum += value;
+        aggregate.last = value;
+    }
+}
+
+struct Chain {
+    nvs: EspNvs<NvsDefault>,
+    seq: u32,
+    // Hash of the last summary, zeros before the first
+    head: [u8; 32],
+}
+
+struct Log {
+    config: CanLogConfig,
+    chain: Chain,
+    // Sequence number and memo of each closed window not anchored yet
+    pending: VecDeque<(u32, String)>,
+    fees: SpendLedger,
+    retry_at: u64,
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+pub fn start(can: CAN, tx: AnyIOPin, rx: AnyIOPin, config: CanLogConfig, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+    if config.signals.is_empty() {
+        return Err("No CAN signals configured".to_string());
+    }
+    let driver_config = Config::new()
+        .timing(config.timing)
+        .filter(acceptance_filter(config.signals))
+        .mode(Mode::ListenOnly)
+        .rx_queue_len(RX_QUEUE_LEN);
+    let mut driver = CanDriver::new(can, tx, rx, &driver_config).map_err(|e| format!("TWAI init: {:?}", e))?;
+    driver.start().map_err(|e| format!("TWAI start: {:?}", e))?;
+
+    let chain_nvs = EspNvs::new(nvs.clone(), CAN_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+    let seq = chain_nvs
+        .get_u32(SEQ_KEY)
+        .map_err(|e| format!("CAN log sequence read: {:?}", e))?
+        .unwrap_or(0);
+    let mut head = [0u8; 32];
+    chain_nvs
+        .get_blob(HEAD_KEY, &mut head)
+        .map_err(|e| format!("CAN log chain read: {:?}", e))?;
+    *LOG.lock().unwrap() = Some(Log {
+        config,
+        chain: Chain {
+            nvs: chain_nvs,
+            seq,
+            head,
+        },
+        pending: VecDeque::new(),
+        fees: SpendLedger::open_in(nvs, FEES_NAMESPACE)?,
+        retry_at: 0,
+    });
+
+    std::thread::Builder::new()
+        .name("can-log".to_string())
+        .stack_size(LISTENER_STACK_SIZE)
+        .spawn(move || listen(driver, config))
+        .map_err(|e| format!("CAN listener: {:?}", e))?;
+    info!("CAN log listening for {} signals, anchoring every {}s", config.signals.len(), config.window.as_secs());
+    Ok(())
+}
+
+// The hardware filter lets through every identifier the signals could match, the signals
+// sort out the rest. Mixed standard and extended signals take every frame.
+fn acceptance_filter(signals: &[CanSignal]) -> Filter {
+    let first = signals[0];
+    if signals.iter().any(|signal| signal.extended != first.extended) {
+        return Filter::standard_allow_all();
+    }
+    let (bits, differing) = signals.iter().fold((u32::MAX, 0), |(bits, differing), signal| {
+        (bits & signal.id_mask, differing | ((signal.id ^ first.id) & signal.id_mask))
+    });
+    let mask = bits & !differing;
+    match first.extended {
+        true => Filter::Extended {
+            filter: first.id & mask & 0x1FFF_FFFF,
+            mask: mask & 0x1FFF_FFFF,
+        },
+        false => Filter::Standard {
+            filter: (first.id & mask & 0x7FF) as u16,
+            mask: (mask & 0x7FF) as u16,
+        },
+    }
+}
+
+fn listen(driver: CanDriver<'static>, config: CanLogConfig) {
+    let mut window = Window::new(config.signals.len());
+    loop {
+        // Times out on a quiet bus, so windows close without traffic too
+        if let Ok(frame) = driver.receive(TickType::new_millis(RECEIVE_TIMEOUT_MS).into()) {
+            if !frame.is_remote_frame() {
+                let mut matched = false;
+                for (index, signal) in config.signals.iter().enumerate() {
+                    if signal.matches(frame.identifier(), frame.is_extended()) {
+                        if let Some(value) = signal.decode(frame.data()) {
+                            window.add(index, value);
+                            matched = true;
+                        }
+                    }
+                }
+                window.frames += matched as u32;
+            }
+        }
+
+        if window.opened.elapsed() >= config.window {
+            let closed = std::mem::replace(&mut window, Window::new(config.signals.len()));
+            close(closed, &config);
+        }
+    }
+}
+
+// Summarizes a window, links it into the chain and queues its anchor
+fn close(window: Window, config: &CanLogConfig) {
+    // A window without times proves little, wait for the clock
+    let (Some(from), Some(to)) = (window.started, unix_time()) else {
+        warn!("CAN window skipped, the clock is not set");
+        return;
+    };
+    let mut log = LOG.lock().unwrap();
+    let Some(log) = log.as_mut() else {
+        return;
+    };
+
+    let signals: serde_json::Map<String, serde_json::Value> = config
+        .signals
+        .iter()
+        .zip(&window.aggregates)
+        .filter_map(|(signal, aggregate)| {
+            let aggregate = aggregate.as_ref()?;
+            // min, max, mean, last and count, three decimals keep the memo short
+            let round = |value: f64| (value * 1000.0).round() / 1000.0;
+            let entry = json!([
+                round(aggregate.min),
+                round(aggregate.max),
+                round(aggregate.sum / aggregate.count as f64),
+                round(aggregate.last),
+                aggregate.count
+            ]);
+            Some((signal.name.to_string(), entry))
+        })
+        .collect();
+    let seq = log.chain.seq + 1;
+    let summary = json!({
+        "seq": seq,
+        "from": from,
+        "to": to,
+        "frames": window.frames,
+        "signals": signals,
+        "prev": hex(&log.chain.head),
+    })
+    .to_string();
+    let hash: [u8; 32] = Sha256::digest(summary.as_bytes()).into();
+
+    let memo = match config.anchor {
+        Anchor::Values if summary.len() <= MAX_MEMO_LEN => summary.clone(),
+        anchor => {
+            if anchor == Anchor::Values {
+                warn!("CAN summary {} too long for a memo, anchoring its hash", seq);
+            }
+            json!({ "can": seq, "sha256": hex(&hash) }).to_string()
+        }
+    };
+    // The chain moves on whether the anchor is sent or dropped, a gap in the anchors shows
+    if let Err(e) = log
+        .chain
+        .nvs
+        .set_blob(HEAD_KEY, &hash)
+        .and_then(|_| log.chain.nvs.set_u32(SEQ_KEY, seq))
+    {
+        warn!("CAN log chain not stored: {:?}", e);
+    }
+    log.chain.seq = seq;
+    log.chain.head = hash;
+    info!("CAN window {}: {}", seq, summary);
+
+    if log.pending.len() == MAX_PENDING {
+        if let Some((dropped, _)) = log.pending.pop_front() {
+            warn!("CAN log full, window {} not anchored", dropped);
+        }
+    }
+    log.pending.push_back((seq, memo));
+}
+
+// Anchors the oldest closed window, called from the main loop which holds the signer
+pub fn anchor_due(signer: &impl TxSigner) {
+    let mut log = LOG.lock().unwrap();
+    let Some(log) = log.as_mut() else {
+        return;
+    };
+    let (Some(now), Some((seq, memo))) = (unix_time(), log.pending.front().cloned()) else {
+        return;
+    };
+    if now < log.retry_at {
+        return;
+    }
+    match publish(signer, &memo, &mut log.fees, log.config.max_fees_per_day, now) {
+        Ok(signature) => {
+            info!("CAN window {} anchored: {}", seq, signature);
+            log.pending.pop_front();
+        }
+        Err(e) => {
+            warn!("CAN window {} not anchored, retrying in {}s: {}", seq, RETRY_DELAY.as_secs(), e);
+            log.retry_at = now + RETRY_DELAY.as_secs();
+        }
+    }
+}
+
+fn publish(signer: &impl TxSigner, memo: &str, fees: &mut SpendLedger, budget: u64, now: u64) -> Result<String, String> {
+    let device = signer.pubkey();
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    let mut transaction = Transaction::new_with_payer(&[memo::memo(memo, &[&device])], Some(&device));
+    transaction.message.recent_blockhash = blockhash;
+
+    let fee = get_fee_for_message(&transaction.message).map_err(|e| e.to_string())?;
+    let spent = fees.spent_within(DAY, now);
+    if spent.saturating_add(fee) > budget {
+        return Err(format!("daily fee budget reached ({} of {} lamports)", spent, budget));
+    }
+
+    signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+    let signature = send_transaction(&transaction).map_err(|e| e.to_string())?;
+    // Counted as soon as it is sent, whether it lands or not
+    if let Err(e) = fees.record(fee, now) {
+        warn!("CAN anchor fee not recorded: {}", e);
+    }
+    Ok(signature)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
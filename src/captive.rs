@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection, FollowRedirectsPolicy};
+use esp_idf_svc::http::Method;
+use log::{info, warn};
+
+use crate::net::{self, NetEvent, NetSubscription};
+use crate::taskwdt;
+
+// Hotel and guest networks hand out an address, then answer every HTTP request with their login
+// page until someone accepts the terms in a browser. Each time the link comes up a plain-HTTP
+// URL that always answers 204 is probed; anything else means a portal sits in the way. RPC
+// calls fail straight away while one does, instead of timing out against the real endpoint.
+
+// Android's check, widely whitelisted by portals so it never needs a redirect to follow
+const PROBE_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+// A portal opens once the terms are accepted, e.g. from a phone on the same network
+const PORTAL_RECHECK: Duration = Duration::from_secs(30);
+const PROBE_STACK_SIZE: usize = 6 * 1024;
+
+static SUSPECTED: AtomicBool = AtomicBool::new(false);
+static SUBSCRIPTION: Mutex<Option<NetSubscription>> = Mutex::new(None);
+
+// Whether the last probe ran into a login page
+pub fn suspected() -> bool {
+    SUSPECTED.load(Ordering::Relaxed)
+}
+
+pub fn start() -> Result<(), String> {
+    let (link_up, links) = channel();
+    std::thread::Builder::new()
+        .name("captive".to_string())
+        .stack_size(PROBE_STACK_SIZE)
+        .spawn(move || loop {
+            // Rechecks on its own while a portal is up, waits for the next link otherwise
+            let woken = match suspected() {
+                true => links.recv_timeout(PORTAL_RECHECK).is_ok() || net::link_up(),
+                false => links.recv().is_ok(),
+            };
+            if woken {
+                check();
+            }
+        })
+        .map_err(|e| format!("Captive portal check: {:?}", e))?;
+
+    // The link may already be up, it came up before this subscribed
+    if net::link_up() {
+        let _ = link_up.send(());
+    }
+    *SUBSCRIPTION.lock().unwrap() = Some(net::subscribe(move |event| {
+        if event == NetEvent::GotIp {
+            let _ = link_up.send(());
+        }
+    }));
+    Ok(())
+}
+
+fn check() {
+    match probe() {
+        Ok(204) => {
+            if SUSPECTED.swap(false, Ordering::Relaxed) {
+                info!("Captive portal gone, RPC resumes");
+            }
+        }
+        Ok(status) => {
+            if !SUSPECTED.swap(true, Ordering::Relaxed) {
+                warn!("Captive portal suspected (probe answered {}), log in to the network first", status);
+                net::notify(NetEvent::CaptivePortalSuspected);
+            }
+        }
+        // Says nothing either way, the RPC calls will show whether the network works
+        Err(e) => warn!("Captive portal probe failed: {}", e),
+    }
+}
+
+fn probe() -> Result<u16, String> {
+    let _supervised = taskwdt::supervise();
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(PROBE_TIMEOUT),
+        // A portal's redirect is the answer, not something to follow
+        follow_redirects_policy: FollowRedirectsPolicy::FollowNone,
+        ..Default::default()
+    })
+    .map_err(|e| format!("HTTP init: {:?}", e))?;
+    let mut client = Client::wrap(connection);
+    let request = client
+        .request(Method::Get, PROBE_URL, &[])
+        .map_err(|e| format!("Request: {:?}", e))?;
+    let response = request.submit().map_err(|e| format!("Submit: {:?}", e))?;
+    Ok(response.status())
+}
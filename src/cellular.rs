@@ -0,0 +1,300 @@
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Gpio0, Gpio1};
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::netif::{EspNetif, EspNetifDriver, IpEvent, NetifConfiguration, PppConfiguration};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{esp_netif_action_start, esp_netif_action_stop};
+use log::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::config::{device_secret, SIM_PIN_KEY};
+use crate::net::{self, set_link, Backoff, NetEvent, Recovery};
+
+// Access point name from cfg.toml or the environment (see build.rs)
+const APN: &str = env!("RESP32SOL_CELLULAR_APN");
+// Development SIM PIN from cfg.toml, empty in release builds, which read it from NVS
+const DEV_SIM_PIN: &str = env!("RESP32SOL_CELLULAR_PIN");
+
+// SIM7000 and SIM800 both autobaud and default to this
+const BAUD_RATE: u32 = 115_200;
+// The modem answers AT a few seconds after power-up
+const MODEM_BOOT_ATTEMPTS: u32 = 10;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+// Network registration can take a minute on a cold start, longer on NB-IoT
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(120);
+const REGISTRATION_POLL: Duration = Duration::from_secs(2);
+const DIAL_TIMEOUT: Duration = Duration::from_secs(30);
+// Silence the modem needs around "+++" to take it as the escape to command mode
+const ESCAPE_GUARD: Duration = Duration::from_millis(1100);
+const READ_POLL_MS: u64 = 20;
+// PPP negotiation follows the dial, RPC calls wait for it like they do for WiFi
+const BOOT_LINK_WAIT: Duration = Duration::from_secs(30);
+const SUPERVISOR_STACK_SIZE: usize = 6 * 1024;
+
+// Modem UART wiring on the ESP32-C3, clear of the W5500 pins so both can be built in
+pub struct CellularPins {
+    pub tx: Gpio0,
+    pub rx: Gpio1,
+}
+
+// Dials a SIM7000/SIM800 style modem and runs PPP over its UART, false when the modem doesn't
+// get online so the caller can use WiFi
+pub fn connect(uart: UART1, pins: CellularPins, sys_loop: EspSystemEventLoop, nvs: EspDefaultNvsPartition) -> bool {
+    match start(uart, pins, sys_loop, nvs) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Cellular unavailable, falling back to WiFi: {}", e);
+            false
+        }
+    }
+}
+
+fn start(
+    uart: UART1,
+    pins: CellularPins,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+) -> Result<(), String> {
+    let sim_pin = device_secret(nvs, SIM_PIN_KEY, DEV_SIM_PIN)?;
+    let uart = Arc::new(
+        UartDriver::new(
+            uart,
+            pins.tx,
+            pins.rx,
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &UartConfig::default().baudrate(Hertz(BAUD_RATE)),
+        )
+        .map_err(|e| format!("Modem UART: {:?}", e))?,
+    );
+    dial(&uart, sim_pin.as_deref().map(String::as_str))?;
+
+    let (redial, redials) = channel();
+    let lost = redial.clone();
+    let ip_events = sys_loop
+        .subscribe::<IpEvent, _>(move |event| match event {
+            IpEvent::DhcpIpAssigned(_) => set_link(true),
+            IpEvent::DhcpIpDeassigned(_) => {
+                set_link(false);
+                let _ = lost.send(Redial::Lost);
+            }
+            _ => {}
+        })
+        .map_err(|e| format!("IP events: {:?}", e))?;
+    // PPP has no lease of its own to renew, redialing is the gentlest step
+    net::set_recovery(move |step| {
+        let wanted = match step {
+            Recovery::RestartDhcp => return false,
+            Recovery::Reassociate => Redial::Requested,
+            Recovery::PowerCycle => Redial::ResetModem,
+        };
+        redial.send(wanted).is_ok()
+    });
+
+    // The PPP driver isn't Send, so the supervisor creates it and reports back how that went.
+    // Frames only flow while it pumps the UART.
+    let (started, start_result) = channel();
+    std::thread::Builder::new()
+        .name("cellular".to_string())
+        .stack_size(SUPERVISOR_STACK_SIZE)
+        .spawn(move || {
+            let _subscription = ip_events;
+            match start_ppp(uart.clone()) {
+                Ok(driver) => {
+                    let _ = started.send(Ok(()));
+                    supervise(uart, driver, redials, sim_pin)
+                }
+                Err(e) => {
+                    let _ = started.send(Err(e));
+                }
+            }
+        })
+        .map_err(|e| format!("Cellular supervisor: {:?}", e))?;
+    start_result
+        .recv()
+        .map_err(|_| "Cellular supervisor exited".to_string())??;
+
+    match net::wait_for_link(BOOT_LINK_WAIT) {
+        true => info!("Cellular connected"),
+        false => warn!("PPP not up yet, RPC waits for it"),
+    }
+    Ok(())
+}
+
+fn start_ppp(uart: Arc<UartDriver<'static>>) -> Result<EspNetifDriver<'static, EspNetif>, String> {
+    let mut driver = EspNetifDriver::new(
+        EspNetif::new_with_conf(&NetifConfiguration::ppp_default_client())
+            .map_err(|e| format!("PPP netif: {:?}", e))?,
+        |netif| {
+            netif.set_ppp_conf(&PppConfiguration {
+                phase_events_enabled: false,
+                ..Default::default()
+            })
+        },
+        move |mut data: &[u8]| {
+            while !data.is_empty() {
+                let written = uart.write(data)?;
+                data = &data[written..];
+            }
+            Ok(())
+        },
+    )
+    .map_err(|e| format!("PPP driver: {:?}", e))?;
+    driver.start().map_err(|e| format!("PPP start: {:?}", e))?;
+    Ok(driver)
+}
+
+enum Redial {
+    Lost,
+    Requested,
+    ResetModem,
+}
+
+// Feeds received PPP frames to the netif and redials when the session drops
+fn supervise(
+    uart: Arc<UartDriver<'static>>,
+    driver: EspNetifDriver<'static, EspNetif>,
+    redials: Receiver<Redial>,
+    sim_pin: Option<Zeroizing<String>>,
+) -> ! {
+    // EspNetifDriver::stop refuses to run because its start never records the started state,
+    // so the session is cycled with the netif actions directly
+    let netif = driver.netif().handle() as *mut c_void;
+    let mut buf = [0u8; 512];
+    loop {
+        match uart.read(&mut buf, TickType::new_millis(READ_POLL_MS).into()) {
+            Ok(0) => {}
+            Ok(len) => {
+                if let Err(e) = driver.rx(&buf[..len]) {
+                    warn!("PPP receive: {:?}", e);
+                }
+            }
+            Err(e) => warn!("Modem UART read: {:?}", e),
+        }
+        let Ok(reason) = redials.try_recv() else {
+            continue;
+        };
+
+        match reason {
+            Redial::Lost => warn!("Cellular link lost, pausing RPC until it is back"),
+            Redial::Requested => warn!("Redialing the data call"),
+            Redial::ResetModem => warn!("Resetting the modem"),
+        }
+        unsafe { esp_netif_action_stop(netif, ptr::null_mut(), 0, ptr::null_mut()) };
+        set_link(false);
+        net::notify(NetEvent::Reconnecting);
+        if let Redial::ResetModem = reason {
+            reset_modem(&uart);
+        }
+        let mut backoff = Backoff::new();
+        while let Err(e) = dial(&uart, sim_pin.as_deref().map(String::as_str)) {
+            let delay = backoff.next();
+            warn!("Cellular redial failed, retrying in {}s: {}", delay.as_secs(), e);
+            std::thread::sleep(delay);
+        }
+        // The stop reports a loss of its own
+        while let Ok(Redial::Lost) = redials.try_recv() {}
+        unsafe { esp_netif_action_start(netif, ptr::null_mut(), 0, ptr::null_mut()) };
+    }
+}
+
+// Gets the modem from whatever state it is in to a data call, ready for PPP
+fn dial(uart: &UartDriver, sim_pin: Option<&str>) -> Result<(), String> {
+    hang_up(uart);
+
+    let mut attempts = 1;
+    while command(uart, "AT", "OK", Duration::from_secs(1)).is_err() {
+        if attempts == MODEM_BOOT_ATTEMPTS {
+            return Err("Modem not answering".to_string());
+        }
+        attempts += 1;
+    }
+    command(uart, "ATE0", "OK", COMMAND_TIMEOUT)?;
+
+    let sim = command(uart, "AT+CPIN?", "OK", COMMAND_TIMEOUT)?;
+    if sim.contains("SIM PIN") {
+        let Some(pin) = sim_pin else {
+            return Err("SIM is locked, store sim_pin in the secrets NVS namespace".to_string());
+        };
+        command(uart, &Zeroizing::new(format!("AT+CPIN=\"{}\"", pin)), "OK", COMMAND_TIMEOUT)?;
+    } else if !sim.contains("READY") {
+        return Err(format!("SIM not ready: {}", sim.trim()));
+    }
+
+    command(uart, &format!("AT+CGDCONT=1,\"IP\",\"{}\"", APN), "OK", COMMAND_TIMEOUT)?;
+    wait_registered(uart)?;
+    if let Ok(signal) = command(uart, "AT+CSQ", "OK", COMMAND_TIMEOUT) {
+        info!("Modem signal: {}", signal.trim().trim_end_matches("OK").trim());
+    }
+
+    command(uart, "ATD*99#", "CONNECT", DIAL_TIMEOUT)?;
+    info!("Cellular data call up on APN {}", APN);
+    Ok(())
+}
+
+fn wait_registered(uart: &UartDriver) -> Result<(), String> {
+    let deadline = Instant::now() + REGISTRATION_TIMEOUT;
+    while Instant::now() < deadline {
+        // +CGREG: <n>,<stat>, 1 is the home network and 5 roaming
+        let reply = command(uart, "AT+CGREG?", "OK", COMMAND_TIMEOUT)?;
+        let status = reply
+            .split("+CGREG:")
+            .nth(1)
+            .and_then(|rest| rest.lines().next())
+            .and_then(|line| line.split(',').nth(1))
+            .map(str::trim);
+        match status {
+            Some("1") | Some("5") => return Ok(()),
+            Some("3") => return Err("Network registration denied".to_string()),
+            _ => std::thread::sleep(REGISTRATION_POLL),
+        }
+    }
+    Err("Not registered on the cellular network".to_string())
+}
+
+// Full functionality with a module reset, the modem reboots and dial waits for it to answer
+fn reset_modem(uart: &UartDriver) {
+    hang_up(uart);
+    let _ = command(uart, "AT+CFUN=1,1", "OK", COMMAND_TIMEOUT);
+}
+
+// Back to command mode and off any call in progress, harmless when the modem is idle
+fn hang_up(uart: &UartDriver) {
+    std::thread::sleep(ESCAPE_GUARD);
+    let _ = uart.write(b"+++");
+    std::thread::sleep(ESCAPE_GUARD);
+    let _ = command(uart, "ATH", "OK", COMMAND_TIMEOUT);
+}
+
+// Sends an AT command and collects the reply until `expect`, an error or the timeout
+fn command(uart: &UartDriver, command: &str, expect: &str, timeout: Duration) -> Result<String, String> {
+    uart.clear_rx().map_err(|e| format!("Modem UART: {:?}", e))?;
+    uart.write(format!("{}\r", command).as_bytes())
+        .map_err(|e| format!("Modem UART write: {:?}", e))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut reply = String::new();
+    let mut buf = [0u8; 128];
+    while Instant::now() < deadline {
+        let len = uart
+            .read(&mut buf, TickType::new_millis(READ_POLL_MS).into())
+            .map_err(|e| format!("Modem UART read: {:?}", e))?;
+        reply.push_str(&String::from_utf8_lossy(&buf[..len]));
+        if reply.contains(expect) {
+            return Ok(reply);
+        }
+        if reply.contains("ERROR") || reply.contains("NO CARRIER") {
+            return Err(format!("{}: {}", command, reply.trim()));
+        }
+    }
+    Err(format!("{}: no answer", command))
+}
@@ -0,0 +1,44 @@
+use esp_idf_svc::sys::{
+    esp_chip_info, esp_chip_info_t, esp_chip_model_t_CHIP_ESP32, esp_chip_model_t_CHIP_ESP32C3,
+    esp_chip_model_t_CHIP_ESP32C6, esp_chip_model_t_CHIP_ESP32S3,
+};
+
+// The chip the firmware is built for, from the MCU esp-idf-sys builds ESP-IDF for, which sets
+// the matching cfg. What each chip supports is the feature matrix in the README, lib.rs refuses
+// feature sets the chip can't run.
+
+#[cfg(esp32)]
+pub const NAME: &str = "ESP32";
+#[cfg(esp32s3)]
+pub const NAME: &str = "ESP32-S3";
+#[cfg(esp32c3)]
+pub const NAME: &str = "ESP32-C3";
+#[cfg(esp32c6)]
+pub const NAME: &str = "ESP32-C6";
+
+// SHA-512 in the SHA peripheral, which makes the accelerated signing backend the faster one.
+// The ESP32-C3 and ESP32-C6 only accelerate SHA-256.
+pub const SHA512_ACCELERATED: bool = cfg!(any(esp32, esp32s3));
+
+// The chip as ESP-IDF reports it at runtime, e.g. "ESP32-C3 rev 0.4, 1 core(s)"
+pub fn describe() -> String {
+    let mut chip: esp_chip_info_t = unsafe { core::mem::zeroed() };
+    unsafe { esp_chip_info(&mut chip) };
+
+    let model = [
+        (esp_chip_model_t_CHIP_ESP32, "ESP32"),
+        (esp_chip_model_t_CHIP_ESP32S3, "ESP32-S3"),
+        (esp_chip_model_t_CHIP_ESP32C3, "ESP32-C3"),
+        (esp_chip_model_t_CHIP_ESP32C6, "ESP32-C6"),
+    ]
+    .iter()
+    .find(|(id, _)| *id == chip.model)
+    .map_or("unknown chip", |(_, name)| *name);
+    format!(
+        "{} rev {}.{}, {} core(s)",
+        model,
+        chip.revision / 100,
+        chip.revision % 100,
+        chip.cores
+    )
+}
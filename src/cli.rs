@@ -0,0 +1,209 @@
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{info, warn};
+use solana_program::native_token::LAMPORTS_PER_SOL;
+use solana_program::instruction::Instruction;
+use solana_program::pubkey::Pubkey;
+use solana_system_interface::instruction as system_instruction;
+use solana_transaction::{Hash, Signature, Transaction};
+
+use crate::amount::{parse_sol, Sol};
+use crate::config::{self, DeviceSettings, Setting};
+use crate::crashlog::PanicLog;
+use crate::loglevel;
+use crate::outbox::Outbox;
+use crate::serial::LineReader;
+use crate::signer::TxSigner;
+use crate::solrpc::{self, ConfirmationStatus};
+
+// Wallet console on the serial port, for development and servicing without reflashing. Lines
+// are read and answered on a thread of its own, one "OK <result>" or "ERR <reason>" per command:
+//   address                    the device's address
+//   balance [address]          in SOL, the device's unless another address is given
+//   send <address> <SOL>       transfers from the device's key, e.g. `send 9xQe... 0.25`
+//   airdrop [SOL]              from the devnet or testnet faucet, 1 SOL by default
+//   config get [key]           the device settings, see config::Setting
+//   config set <key> <value>   stores one, `none` clears it, in use at once but for the cluster
+//   history [count]            the latest signatures of the device's address, 10 by default
+//   panic [clear]              the last panic before a restart and how many there have been
+//   log [<target> <level>]     the log levels, or sets one, e.g. `log resp32sol::solrpc debug`
+// The key stays with the main loop, which signs transfers the console queued for it. With the
+// outbox on, transfers wait out its window and `pending` and `cancel` are taken here as well.
+
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_AIRDROP: u64 = LAMPORTS_PER_SOL;
+const DEFAULT_HISTORY: usize = 10;
+const MAX_HISTORY: usize = 100;
+// Makes RPC calls, TLS handshakes included
+const CONSOLE_STACK_SIZE: usize = 8 * 1024;
+
+struct Transfer {
+    to: Pubkey,
+    lamports: u64,
+    sent: Sender<Result<String, String>>,
+}
+
+// An unsigned transaction for `from` carrying the instruction, and the blockhash to sign it with
+pub type UnsignedTransfer<'a> = &'a dyn Fn(&Pubkey, Instruction) -> Result<(Transaction, Hash), String>;
+
+static TRANSFERS: Mutex<Vec<Transfer>> = Mutex::new(Vec::new());
+
+// Starts answering commands on the console
+pub fn start(address: Pubkey, nvs: EspDefaultNvsPartition, outbox: Option<Outbox>) -> Result<(), String> {
+    std::thread::Builder::new()
+        .name("cli".to_string())
+        .stack_size(CONSOLE_STACK_SIZE)
+        .spawn(move || {
+            let console = Console { address, nvs, outbox };
+            let mut lines = LineReader::new();
+            loop {
+                let Some(line) = lines.read_line(Duration::from_secs(60)) else {
+                    continue;
+                };
+                match console.handle(&line) {
+                    Ok(response) => println!("OK {}", response),
+                    Err(e) => println!("ERR {}", e),
+                }
+            }
+        })
+        .map_err(|e| format!("Console thread: {:?}", e))?;
+    info!("Wallet console up, `help` lists the commands");
+    Ok(())
+}
+
+// Signs and sends the transfers the console queued, from the main loop that holds the key.
+// `unsigned` builds each one's transaction and what to sign it with, e.g. a durable nonce.
+pub fn send_due(signer: &impl TxSigner, unsigned: UnsignedTransfer<'_>) {
+    let transfers = std::mem::take(&mut *TRANSFERS.lock().unwrap());
+    for transfer in transfers {
+        let from = signer.pubkey();
+        let instruction = system_instruction::transfer(&from, &transfer.to, transfer.lamports);
+        let result = unsigned(&from, instruction).and_then(|(mut transaction, blockhash)| {
+            signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+            solrpc::send_transaction(&transaction).map_err(|e| e.to_string())
+        });
+        if let Err(e) = &result {
+            warn!("Console transfer not sent: {}", e);
+        }
+        let _ = transfer.sent.send(result);
+    }
+}
+
+struct Console {
+    address: Pubkey,
+    nvs: EspDefaultNvsPartition,
+    outbox: Option<Outbox>,
+}
+
+impl Console {
+    fn handle(&self, line: &str) -> Result<String, String> {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["help"] => Ok("address | balance [address] | send <address> <SOL> | airdrop [SOL] | config get [key] \
+                | config set <key> <value> | history [count] | panic [clear] | log [<target> <level>]"
+                .to_string()),
+            ["address"] => Ok(self.address.to_string()),
+            ["balance"] => self.balance(&self.address),
+            ["balance", address] => self.balance(&parse_address(address)?),
+            ["send", to, amount] => self.send(parse_address(to)?, parse_sol(amount)?),
+            ["airdrop"] => self.airdrop(DEFAULT_AIRDROP),
+            ["airdrop", amount] => self.airdrop(parse_sol(amount)?),
+            ["config", "get"] => {
+                let settings = self.settings()?;
+                let values: Vec<String> = Setting::ALL
+                    .iter()
+                    .map(|setting| format!("{}={}", setting.key(), settings.get(*setting)))
+                    .collect();
+                Ok(values.join(" "))
+            }
+            ["config", "get", key] => Ok(self.settings()?.get(key.parse()?)),
+            ["config", "set", key, value] => self.set(key, value),
+            ["history"] => self.history(DEFAULT_HISTORY),
+            ["history", count] => self.history(count.parse().map_err(|e| format!("Invalid count: {:?}", e))?),
+            ["panic"] => {
+                let log = PanicLog::open(self.nvs.clone())?;
+                Ok(match log.last() {
+                    Some(panic) => format!(
+                        "{} panic(s), last in thread '{}' at {} after {} ms on build {}: {}",
+                        log.count(),
+                        panic.thread,
+                        panic.location,
+                        panic.uptime_ms,
+                        panic.build,
+                        panic.message
+                    ),
+                    None => "no panics".to_string(),
+                })
+            }
+            ["panic", "clear"] => PanicLog::open(self.nvs.clone())?.clear().map(|_| "cleared".to_string()),
+            ["log"] => Ok(loglevel::levels()),
+            ["log", target, level] => loglevel::set_level(self.nvs.clone(), target, level).map(|_| loglevel::levels()),
+            _ => match &self.outbox {
+                Some(outbox) => outbox.handle_command(line),
+                None => Err(format!("Unknown command '{}', try `help`", line)),
+            },
+        }
+    }
+
+    fn balance(&self, address: &Pubkey) -> Result<String, String> {
+        Ok(Sol(solrpc::get_balance(address).map_err(|e| e.to_string())?).to_string())
+    }
+
+    fn send(&self, to: Pubkey, lamports: u64) -> Result<String, String> {
+        if lamports == 0 {
+            return Err("Amount must not be zero".to_string());
+        }
+        let description = format!("{} to {}", Sol(lamports), to);
+        if let Some(outbox) = &self.outbox {
+            let instruction = system_instruction::transfer(&self.address, &to, lamports);
+            let id = outbox.queue(&description, vec![instruction], self.address);
+            return Ok(format!("#{} queued, `cancel {}` stops it", id, id));
+        }
+
+        info!("Console transfer of {}", description);
+        let (sent, result) = channel();
+        TRANSFERS.lock().unwrap().push(Transfer { to, lamports, sent });
+        let signature = result.recv().map_err(|_| "Transfer dropped".to_string())??;
+        confirm(&signature)
+    }
+
+    fn airdrop(&self, lamports: u64) -> Result<String, String> {
+        let signature = solrpc::request_airdrop(&self.address, lamports).map_err(|e| e.to_string())?;
+        confirm(&signature)
+    }
+
+    fn settings(&self) -> Result<DeviceSettings, String> {
+        DeviceSettings::load(self.nvs.clone())
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<String, String> {
+        match config::change_setting(self.nvs.clone(), key, value)? {
+            Setting::Cluster => Ok(format!("{} stored, in use after a restart", key)),
+            _ => Ok(format!("{} stored", key)),
+        }
+    }
+
+    fn history(&self, count: usize) -> Result<String, String> {
+        let signatures = solrpc::get_signatures_for_address(&self.address, count.clamp(1, MAX_HISTORY))
+            .map_err(|e| e.to_string())?;
+        match signatures.is_empty() {
+            true => Ok("no transactions".to_string()),
+            false => Ok(signatures.join(", ")),
+        }
+    }
+}
+
+// Waits for the transaction to confirm, answering with its signature either way
+fn confirm(signature: &str) -> Result<String, String> {
+    let parsed = Signature::from_str(signature).map_err(|e| format!("Signature parse: {:?}", e))?;
+    solrpc::confirm_transaction(&parsed, ConfirmationStatus::Confirmed, CONFIRM_TIMEOUT)
+        .map(|_| signature.to_string())
+        .map_err(|e| format!("{} sent, {}", signature, e))
+}
+
+fn parse_address(address: &str) -> Result<Pubkey, String> {
+    Pubkey::from_str(address).map_err(|e| format!("Invalid address {}: {:?}", address, e))
+}
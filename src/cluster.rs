@@ -0,0 +1,119 @@
+use std::fmt;
+use std::str::FromStr;
+
+// The Solana cluster the device talks to, with its public endpoints and explorer links. The RPC
+// client's URL says which one is in use, see solrpc::cluster(): one of the public endpoints
+// names its cluster, anything else is a custom endpoint. The public endpoints are the same ones
+// build.rs knows.
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Cluster {
+    #[default]
+    Devnet,
+    Testnet,
+    MainnetBeta,
+    // A provider's or a private node's HTTP endpoint, and its websocket endpoint if known
+    Custom(String, Option<String>),
+}
+
+impl Cluster {
+    // The clusters the setup portal and the console offer by name
+    pub const PUBLIC: [Cluster; 3] = [Cluster::Devnet, Cluster::Testnet, Cluster::MainnetBeta];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Cluster::Devnet => "devnet",
+            Cluster::Testnet => "testnet",
+            Cluster::MainnetBeta => "mainnet-beta",
+            Cluster::Custom(..) => "custom",
+        }
+    }
+
+    pub fn rpc_url(&self) -> &str {
+        match self {
+            Cluster::Devnet => "https://api.devnet.solana.com",
+            Cluster::Testnet => "https://api.testnet.solana.com",
+            Cluster::MainnetBeta => "https://api.mainnet-beta.solana.com",
+            Cluster::Custom(url, _) => url,
+        }
+    }
+
+    // None for a custom endpoint that wasn't given one
+    pub fn ws_url(&self) -> Option<&str> {
+        match self {
+            Cluster::Devnet => Some("wss://api.devnet.solana.com"),
+            Cluster::Testnet => Some("wss://api.testnet.solana.com"),
+            Cluster::MainnetBeta => Some("wss://api.mainnet-beta.solana.com"),
+            Cluster::Custom(_, ws_url) => ws_url.as_deref(),
+        }
+    }
+
+    // The public cluster a URL is the endpoint of, a custom endpoint otherwise
+    pub fn from_url(url: &str) -> Cluster {
+        let url = url.trim_end_matches('/');
+        Cluster::PUBLIC
+            .into_iter()
+            .find(|cluster| cluster.rpc_url() == url)
+            .unwrap_or_else(|| Cluster::Custom(url.to_string(), None))
+    }
+
+    // Real funds. A custom endpoint counts when its URL says mainnet, as providers' URLs do.
+    pub fn is_mainnet(&self) -> bool {
+        match self {
+            Cluster::MainnetBeta => true,
+            Cluster::Custom(url, _) => url.contains("mainnet"),
+            _ => false,
+        }
+    }
+
+    // Devnet and testnet have a faucet, a custom endpoint may be a local test validator's
+    pub fn has_faucet(&self) -> bool {
+        !self.is_mainnet()
+    }
+
+    pub fn explorer_tx_url(&self, signature: &str) -> String {
+        format!("https://explorer.solana.com/tx/{}{}", signature, self.explorer_query())
+    }
+
+    pub fn explorer_address_url(&self, address: &str) -> String {
+        format!("https://explorer.solana.com/address/{}{}", address, self.explorer_query())
+    }
+
+    // The explorer shows mainnet unless told otherwise. A custom endpoint on devnet or testnet
+    // is shown as that cluster, the explorer can't reach a private node anyway.
+    fn explorer_query(&self) -> String {
+        match self {
+            Cluster::Devnet | Cluster::Testnet => format!("?cluster={}", self.name()),
+            Cluster::MainnetBeta => String::new(),
+            Cluster::Custom(url, _) => match ["devnet", "testnet"].into_iter().find(|name| url.contains(name)) {
+                Some(name) => format!("?cluster={}", name),
+                None => String::new(),
+            },
+        }
+    }
+}
+
+// A public cluster's name, or an http(s) URL for a custom endpoint
+impl FromStr for Cluster {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "devnet" => Ok(Cluster::Devnet),
+            "testnet" => Ok(Cluster::Testnet),
+            "mainnet-beta" => Ok(Cluster::MainnetBeta),
+            url if url.starts_with("https://") || url.starts_with("http://") => Ok(Cluster::from_url(url)),
+            other => Err(format!("Unknown cluster '{}', devnet, testnet or mainnet-beta", other)),
+        }
+    }
+}
+
+// The name, a custom endpoint's URL stays out of logs as it often carries an API key
+impl fmt::Display for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cluster::Custom(..) => write!(f, "a custom endpoint"),
+            cluster => write!(f, "{}", cluster.name()),
+        }
+    }
+}
@@ -0,0 +1,783 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine as _};
+use esp_idf_svc::nvs::{
+    EspDefaultNvsPartition, EspEncryptedNvsPartition, EspNvs, NvsDefault, NvsEncrypted, NvsPartitionId,
+};
+use esp_idf_svc::sys::{esp_efuse_mac_get_default, ESP_OK};
+use esp_idf_svc::wifi::AuthMethod;
+use log::{info, warn};
+use serde_json::{json, Value};
+use solana_program::pubkey::Pubkey;
+use zeroize::Zeroizing;
+
+use crate::cluster::Cluster;
+// Parsed and stored with the networks
+pub use crate::static_ip::StaticIp;
+#[cfg(not(feature = "remote-signer"))]
+use crate::solrpc::RpcClient;
+
+const WIFI_NAMESPACE: &str = "wifi";
+const SSID_KEY: &str = "ssid";
+const PASSWORD_KEY: &str = "password";
+const NETWORKS_KEY: &str = "networks";
+const LAST_KEY: &str = "last";
+
+const CERTS_NAMESPACE: &str = "wifi_certs";
+
+const SETTINGS_NAMESPACE: &str = "settings";
+const RECIPIENT_KEY: &str = "recipient";
+const CLUSTER_KEY: &str = "cluster";
+const POLL_INTERVAL_KEY: &str = "poll_interval";
+const MAX_FEE_KEY: &str = "max_fee";
+const DISPLAY_DIM_KEY: &str = "display_dim";
+
+// Per-device secrets, written with an NVS image when the device is flashed
+const SECRETS_NAMESPACE: &str = "secrets";
+pub const BLE_POP_KEY: &str = "ble_pop";
+pub const SIM_PIN_KEY: &str = "sim_pin";
+const MAX_SECRET_LEN: usize = 64;
+
+// Between the transfer demo's cycles when no poll interval is set
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+const MAX_NETWORKS: usize = 5;
+// NVS limit for a string value
+const MAX_NETWORKS_JSON_LEN: usize = 4000;
+// NVS key length limit
+const MAX_CERT_NAME_LEN: usize = 15;
+const MAX_CERT_LEN: usize = 8 * 1024;
+
+// Development network from cfg.toml or the environment (see build.rs), only debug builds
+// join it when nothing has been stored, release builds never embed it
+#[cfg(debug_assertions)]
+const DEV_WIFI_SSID: &str = env!("RESP32SOL_WIFI_SSID");
+#[cfg(debug_assertions)]
+const DEV_WIFI_PASSWORD: &str = env!("RESP32SOL_WIFI_PASSWORD");
+// "wifi", "ethernet" or "cellular", the latter two only in builds with their driver (see build.rs)
+pub const NETWORK: &str = env!("RESP32SOL_NETWORK");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EapMethod {
+    // Username and password inside a TLS tunnel, the usual campus setup
+    Peap,
+    // Client certificate, common on industrial networks
+    Tls,
+}
+
+// 802.1X settings, certificates are referenced by name in WifiCerts
+#[derive(Debug, Clone)]
+pub struct EnterpriseConfig {
+    pub method: EapMethod,
+    // Outer identity, sent before the tunnel is up, e.g. "anonymous@example.org"
+    pub identity: String,
+    // Inner PEAP username, the password is the network's password
+    pub username: String,
+    // Without a CA certificate the server isn't authenticated
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    // PEM key, encrypted with the network's password if one is set
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum WifiSecurity {
+    // WPA2 or better, or an open network without a password
+    Personal,
+    // Refuses to fall back to WPA2
+    Wpa3,
+    Enterprise(EnterpriseConfig),
+}
+
+#[derive(Clone)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub password: Zeroizing<String>,
+    pub security: WifiSecurity,
+    // DHCP when unset
+    pub ip: Option<StaticIp>,
+}
+
+impl WifiCredentials {
+    pub fn new(ssid: &str, password: &str) -> Result<Self, String> {
+        Self::with_security(ssid, password, WifiSecurity::Personal)
+    }
+
+    pub fn with_security(ssid: &str, password: &str, security: WifiSecurity) -> Result<Self, String> {
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LEN {
+            return Err(format!("SSID must be 1-{} bytes", MAX_SSID_LEN));
+        }
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(format!("WiFi password must be at most {} bytes", MAX_PASSWORD_LEN));
+        }
+        match &security {
+            WifiSecurity::Personal => {}
+            WifiSecurity::Wpa3 if password.is_empty() => return Err("WPA3 needs a password".to_string()),
+            WifiSecurity::Wpa3 => {}
+            WifiSecurity::Enterprise(enterprise) => {
+                if enterprise.identity.is_empty() {
+                    return Err("Enterprise WiFi needs an 'identity'".to_string());
+                }
+                match enterprise.method {
+                    EapMethod::Peap if enterprise.username.is_empty() || password.is_empty() => {
+                        return Err("PEAP needs a 'username' and 'password'".to_string());
+                    }
+                    EapMethod::Tls if enterprise.client_cert.is_none() || enterprise.client_key.is_none() => {
+                        return Err("EAP-TLS needs a 'client_cert' and 'client_key'".to_string());
+                    }
+                    _ => {}
+                }
+                for name in [&enterprise.ca_cert, &enterprise.client_cert, &enterprise.client_key]
+                    .into_iter()
+                    .flatten()
+                {
+                    check_certificate_name(name)?;
+                }
+            }
+        }
+
+        Ok(Self {
+            ssid: ssid.to_string(),
+            password: Zeroizing::new(password.to_string()),
+            security,
+            ip: None,
+        })
+    }
+
+    // {"ssid":"..","password":".."}, JSON so SSIDs and passwords may contain spaces. "auth" picks
+    // "wpa3", or "peap"/"tls" with "identity", "username", "ca_cert", "client_cert" and
+    // "client_key" for enterprise networks. "ip" sets a static address, see StaticIp.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(json.trim()).map_err(|e| format!("WiFi config parse: {:?}", e))?;
+        Self::from_value(&value)
+    }
+
+    fn from_value(value: &Value) -> Result<Self, String> {
+        let ssid = value["ssid"].as_str().ok_or("WiFi config needs an 'ssid'")?;
+        let password = value["password"].as_str().unwrap_or("");
+        let field = |name: &str| value[name].as_str().map(str::to_string);
+
+        let enterprise = |method| {
+            WifiSecurity::Enterprise(EnterpriseConfig {
+                method,
+                identity: field("identity").unwrap_or_default(),
+                username: field("username").unwrap_or_default(),
+                ca_cert: field("ca_cert"),
+                client_cert: field("client_cert"),
+                client_key: field("client_key"),
+            })
+        };
+        let security = match value["auth"].as_str() {
+            None | Some("wpa2") => WifiSecurity::Personal,
+            Some("wpa3") => WifiSecurity::Wpa3,
+            Some("peap") => enterprise(EapMethod::Peap),
+            Some("tls") => enterprise(EapMethod::Tls),
+            Some(other) => return Err(format!("Unknown WiFi auth '{}', expected wpa2, wpa3, peap or tls", other)),
+        };
+
+        let mut credentials = Self::with_security(ssid, password, security)?;
+        if !value["ip"].is_null() {
+            credentials.ip = Some(StaticIp::from_value(&value["ip"])?);
+        }
+        Ok(credentials)
+    }
+
+    fn to_value(&self) -> Value {
+        let mut value = json!({ "ssid": self.ssid, "password": self.password.as_str() });
+        match &self.security {
+            WifiSecurity::Personal => {}
+            WifiSecurity::Wpa3 => value["auth"] = json!("wpa3"),
+            WifiSecurity::Enterprise(enterprise) => {
+                value["auth"] = json!(match enterprise.method {
+                    EapMethod::Peap => "peap",
+                    EapMethod::Tls => "tls",
+                });
+                value["identity"] = json!(enterprise.identity);
+                value["username"] = json!(enterprise.username);
+                value["ca_cert"] = json!(enterprise.ca_cert);
+                value["client_cert"] = json!(enterprise.client_cert);
+                value["client_key"] = json!(enterprise.client_key);
+            }
+        }
+        if let Some(ip) = &self.ip {
+            value["ip"] = ip.to_value();
+        }
+        value
+    }
+
+    // Minimum the access point has to offer. An empty password joins an open network.
+    pub fn auth_method(&self) -> AuthMethod {
+        match self.security {
+            WifiSecurity::Personal if self.password.is_empty() => AuthMethod::None,
+            WifiSecurity::Personal => AuthMethod::WPA2Personal,
+            WifiSecurity::Wpa3 => AuthMethod::WPA3Personal,
+            WifiSecurity::Enterprise(_) => AuthMethod::WPA2Enterprise,
+        }
+    }
+
+    // Adds the network in front of the stored ones
+    pub fn store(&self, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+        WifiNetworks::open(nvs)?.add(self.clone())
+    }
+}
+
+// Known networks in order of preference, tried one after another until one connects. The one
+// that worked last is tried first, so a device carried between sites doesn't wait for the
+// other networks to time out on every boot.
+pub struct WifiNetworks {
+    nvs: EspNvs<NvsDefault>,
+    networks: Vec<WifiCredentials>,
+    last: Option<usize>,
+}
+
+impl WifiNetworks {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, WIFI_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+
+        let mut networks_buf = Zeroizing::new(vec![0u8; MAX_NETWORKS_JSON_LEN]);
+        let networks = match nvs
+            .get_str(NETWORKS_KEY, networks_buf.as_mut_slice())
+            .map_err(|e| format!("WiFi networks read: {:?}", e))?
+        {
+            Some(json) => parse_networks(json)?,
+            None => load_single_network(&nvs)?.into_iter().collect(),
+        };
+        let last = nvs
+            .get_u8(LAST_KEY)
+            .ok()
+            .flatten()
+            .map(usize::from)
+            .filter(|last| *last < networks.len());
+
+        Ok(Self { nvs, networks, last })
+    }
+
+    pub fn networks(&self) -> &[WifiCredentials] {
+        &self.networks
+    }
+
+    // Indices in the order to try them
+    pub fn order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.networks.len()).collect();
+        if let Some(last) = self.last {
+            order.retain(|index| *index != last);
+            order.insert(0, last);
+        }
+        order
+    }
+
+    pub fn remember(&mut self, index: usize) -> Result<(), String> {
+        if self.last == Some(index) {
+            return Ok(());
+        }
+        self.nvs
+            .set_u8(LAST_KEY, index as u8)
+            .map_err(|e| format!("Last WiFi network store: {:?}", e))?;
+        self.last = Some(index);
+        Ok(())
+    }
+
+    // Puts the network first, replacing a stored one with the same SSID
+    pub fn add(&mut self, credentials: WifiCredentials) -> Result<(), String> {
+        self.networks.retain(|network| network.ssid != credentials.ssid);
+        self.networks.insert(0, credentials);
+        self.networks.truncate(MAX_NETWORKS);
+        self.save()
+    }
+
+    pub fn replace(&mut self, networks: Vec<WifiCredentials>) -> Result<(), String> {
+        if networks.is_empty() || networks.len() > MAX_NETWORKS {
+            return Err(format!("Between 1 and {} WiFi networks can be stored", MAX_NETWORKS));
+        }
+        self.networks = networks;
+        self.save()
+    }
+
+    fn save(&mut self) -> Result<(), String> {
+        let networks = self.networks.iter().map(WifiCredentials::to_value).collect();
+        let json = Zeroizing::new(Value::Array(networks).to_string());
+        if json.len() >= MAX_NETWORKS_JSON_LEN {
+            return Err("WiFi networks too long to store".to_string());
+        }
+
+        self.nvs
+            .set_str(NETWORKS_KEY, &json)
+            .map_err(|e| format!("WiFi networks store: {:?}", e))?;
+        // The list supersedes the single network of earlier firmware, and the indices changed
+        for key in [SSID_KEY, PASSWORD_KEY, LAST_KEY] {
+            self.nvs
+                .remove(key)
+                .map_err(|e| format!("WiFi config cleanup: {:?}", e))?;
+        }
+        self.last = None;
+        Ok(())
+    }
+}
+
+// Certificates and keys of enterprise networks by name. An EAP-TLS client key is as secret as
+// the device key, so they live in the encrypted partition whenever it is available. Ones that
+// older firmware stored in the plaintext partition move over the first time they are read.
+pub struct WifiCerts {
+    plaintext: EspNvs<NvsDefault>,
+    encrypted: Option<EspNvs<NvsEncrypted>>,
+}
+
+impl WifiCerts {
+    pub fn open(nvs: EspDefaultNvsPartition, encrypted: Option<EspEncryptedNvsPartition>) -> Result<Self, String> {
+        let plaintext = EspNvs::new(nvs, CERTS_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        let encrypted = encrypted
+            .map(|partition| EspNvs::new(partition, CERTS_NAMESPACE, true))
+            .transpose()
+            .map_err(|e| format!("Encrypted NVS open: {:?}", e))?;
+        Ok(Self { plaintext, encrypted })
+    }
+
+    // PEM (with the NUL terminator mbedTLS expects) or DER, as stored
+    pub fn certificate(&mut self, name: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+        if let Some(encrypted) = self.encrypted.as_ref() {
+            if let Some(certificate) = read_certificate(encrypted, name)? {
+                return Ok(certificate);
+            }
+        }
+        let certificate =
+            read_certificate(&self.plaintext, name)?.ok_or_else(|| format!("No WiFi certificate named '{}'", name))?;
+        if let Some(encrypted) = self.encrypted.as_mut() {
+            encrypted
+                .set_blob(name, &certificate)
+                .map_err(|e| format!("Certificate store: {:?}", e))?;
+            self.plaintext
+                .remove(name)
+                .map_err(|e| format!("Plaintext certificate cleanup: {:?}", e))?;
+            info!("WiFi certificate '{}' moved to encrypted NVS", name);
+        }
+        Ok(certificate)
+    }
+
+    pub fn store_certificate(&mut self, name: &str, certificate: &[u8]) -> Result<(), String> {
+        check_certificate_name(name)?;
+        if certificate.is_empty() || certificate.len() > MAX_CERT_LEN {
+            return Err(format!("Certificates must be 1-{} bytes", MAX_CERT_LEN));
+        }
+
+        let mut blob = Zeroizing::new(certificate.to_vec());
+        if blob.starts_with(b"-----BEGIN") && blob.last() != Some(&0) {
+            blob.push(0);
+        }
+        let Some(encrypted) = self.encrypted.as_mut() else {
+            warn!("WiFi certificate '{}' stored without NVS encryption", name);
+            return self
+                .plaintext
+                .set_blob(name, &blob)
+                .map_err(|e| format!("Certificate store: {:?}", e));
+        };
+        encrypted
+            .set_blob(name, &blob)
+            .map_err(|e| format!("Certificate store: {:?}", e))?;
+        // A copy older firmware stored would be found after this one is removed
+        self.plaintext
+            .remove(name)
+            .map_err(|e| format!("Plaintext certificate cleanup: {:?}", e))?;
+        Ok(())
+    }
+}
+
+fn read_certificate<T: NvsPartitionId>(nvs: &EspNvs<T>, name: &str) -> Result<Option<Zeroizing<Vec<u8>>>, String> {
+    let Some(len) = nvs.blob_len(name).map_err(|e| format!("Certificate read: {:?}", e))? else {
+        return Ok(None);
+    };
+    let mut buf = Zeroizing::new(vec![0u8; len]);
+    let len = nvs
+        .get_blob(name, &mut buf)
+        .map_err(|e| format!("Certificate read: {:?}", e))?
+        .map(|certificate| certificate.len());
+    Ok(len.map(|len| {
+        buf.truncate(len);
+        buf
+    }))
+}
+
+fn check_certificate_name(name: &str) -> Result<(), String> {
+    match !name.is_empty() && name.len() <= MAX_CERT_NAME_LEN {
+        true => Ok(()),
+        false => Err(format!("Certificate names must be 1-{} bytes", MAX_CERT_NAME_LEN)),
+    }
+}
+
+fn parse_networks(json: &str) -> Result<Vec<WifiCredentials>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("WiFi networks parse: {:?}", e))?;
+    value
+        .as_array()
+        .ok_or("WiFi networks must be a JSON array")?
+        .iter()
+        .map(WifiCredentials::from_value)
+        .collect()
+}
+
+// `ssid`/`password` keys written by earlier firmware or an NVS image
+fn load_single_network(nvs: &EspNvs<NvsDefault>) -> Result<Option<WifiCredentials>, String> {
+    let mut ssid_buf = [0u8; MAX_SSID_LEN + 1];
+    let mut password_buf = Zeroizing::new([0u8; MAX_PASSWORD_LEN + 1]);
+    let Some(ssid) = nvs
+        .get_str(SSID_KEY, &mut ssid_buf)
+        .map_err(|e| format!("SSID read: {:?}", e))?
+    else {
+        return Ok(None);
+    };
+    let password = nvs
+        .get_str(PASSWORD_KEY, password_buf.as_mut_slice())
+        .map_err(|e| format!("WiFi password read: {:?}", e))?
+        .unwrap_or("");
+
+    WifiCredentials::new(ssid, password).map(Some)
+}
+
+// A secret from the `secrets` namespace, falling back to the cfg.toml value that build.rs only
+// passes to debug builds
+pub fn device_secret(
+    nvs: EspDefaultNvsPartition,
+    key: &str,
+    dev_value: &str,
+) -> Result<Option<Zeroizing<String>>, String> {
+    let nvs = EspNvs::new(nvs, SECRETS_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+    let mut buf = Zeroizing::new([0u8; MAX_SECRET_LEN + 1]);
+    let stored = nvs
+        .get_str(key, buf.as_mut_slice())
+        .map_err(|e| format!("{} read: {:?}", key, e))?
+        .filter(|value| !value.is_empty());
+    Ok(match stored {
+        Some(value) => Some(Zeroizing::new(value.to_string())),
+        None if !dev_value.is_empty() => Some(Zeroizing::new(dev_value.to_string())),
+        None => None,
+    })
+}
+
+// Networks from NVS, falling back to the development network in debug builds
+pub fn stored_wifi_networks(nvs: EspDefaultNvsPartition) -> Option<WifiNetworks> {
+    #[allow(unused_mut)]
+    let mut networks = match WifiNetworks::open(nvs) {
+        Ok(networks) => networks,
+        Err(e) => {
+            warn!("Stored WiFi networks unusable: {}", e);
+            return None;
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    if networks.networks.is_empty() && !DEV_WIFI_SSID.is_empty() {
+        match WifiCredentials::new(DEV_WIFI_SSID, DEV_WIFI_PASSWORD) {
+            Ok(credentials) => {
+                warn!("No WiFi credentials in NVS, using the development network");
+                networks.networks.push(credentials);
+            }
+            Err(e) => warn!("Development WiFi credentials unusable: {}", e),
+        }
+    }
+
+    match networks.networks.is_empty() {
+        true => None,
+        false => Some(networks),
+    }
+}
+
+// Answers a `wifi` console line: `wifi {"ssid":..,"password":..}` adds a network in front of
+// the stored ones, `wifi [{..}, {..}]` replaces them with an ordered list and
+// `wifi cert <name> <base64>` stores a certificate or key for enterprise networks
+pub fn handle_wifi_command(line: &str, nvs: EspDefaultNvsPartition, certs: &mut WifiCerts) -> Result<String, String> {
+    let json = line
+        .strip_prefix("wifi ")
+        .ok_or("Usage: wifi {\"ssid\":\"..\",\"password\":\"..\"} or wifi [{..}, {..}]")?
+        .trim();
+
+    if let Some(args) = json.strip_prefix("cert ") {
+        let (name, encoded) = args.trim().split_once(' ').ok_or("Usage: wifi cert <name> <base64>")?;
+        let certificate = Zeroizing::new(
+            general_purpose::STANDARD
+                .decode(encoded.trim())
+                .map_err(|e| format!("Certificate decode: {:?}", e))?,
+        );
+        certs.store_certificate(name, &certificate)?;
+        return Ok(format!("certificate {}", name));
+    }
+
+    let mut networks = WifiNetworks::open(nvs)?;
+    match json.starts_with('[') {
+        true => {
+            let list = parse_networks(json)?;
+            let stored = format!("{} networks", list.len());
+            networks.replace(list)?;
+            Ok(stored)
+        }
+        false => {
+            let credentials = WifiCredentials::from_json(json)?;
+            let ssid = credentials.ssid.clone();
+            networks.add(credentials)?;
+            Ok(ssid)
+        }
+    }
+}
+
+// Behavior adjusted at runtime, kept in the `settings` NVS namespace. The setup portal takes the
+// recipient and the cluster next to the WiFi credentials, the wallet console and other remote
+// handlers change any of them through `change_setting`, which tells the subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSettings {
+    // Where the demo transfers go, a fresh address every time when unset
+    pub recipient: Option<Pubkey>,
+    // Overrides the build-time cluster, an RPC endpoint in the keystore still wins. One of the
+    // public clusters, custom endpoints are provisioned into the keystore. Applied at boot.
+    pub cluster: Option<Cluster>,
+    // Between the transfer demo's cycles, DEFAULT_POLL_INTERVAL when unset
+    pub poll_interval: Option<Duration>,
+    // Largest network fee the rpc task pays for a transaction in lamports, pricier ones are
+    // refused before they're sent
+    pub max_fee: Option<u64>,
+    // The status display at low brightness all the time, not only while idle on low-power
+    pub display_dim: bool,
+}
+
+// The settings by the names the console and NVS know them by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Recipient,
+    Cluster,
+    PollInterval,
+    MaxFee,
+    DisplayDim,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 5] = [
+        Setting::Recipient,
+        Setting::Cluster,
+        Setting::PollInterval,
+        Setting::MaxFee,
+        Setting::DisplayDim,
+    ];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            Setting::Recipient => RECIPIENT_KEY,
+            Setting::Cluster => CLUSTER_KEY,
+            Setting::PollInterval => POLL_INTERVAL_KEY,
+            Setting::MaxFee => MAX_FEE_KEY,
+            Setting::DisplayDim => DISPLAY_DIM_KEY,
+        }
+    }
+}
+
+impl FromStr for Setting {
+    type Err = String;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        Setting::ALL.into_iter().find(|setting| setting.key() == key).ok_or_else(|| {
+            let keys: Vec<&str> = Setting::ALL.iter().map(Setting::key).collect();
+            format!("Unknown setting '{}', {}", key, keys.join(", "))
+        })
+    }
+}
+
+impl DeviceSettings {
+    pub fn load(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, SETTINGS_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+
+        let mut recipient_buf = [0u8; 48];
+        let mut cluster_buf = [0u8; 16];
+        let recipient = nvs
+            .get_str(RECIPIENT_KEY, &mut recipient_buf)
+            .map_err(|e| format!("Recipient read: {:?}", e))?
+            .map(|recipient| Pubkey::from_str(recipient).map_err(|e| format!("Recipient parse: {:?}", e)))
+            .transpose()?;
+        let cluster = nvs
+            .get_str(CLUSTER_KEY, &mut cluster_buf)
+            .map_err(|e| format!("Cluster read: {:?}", e))?
+            .and_then(|cluster| Cluster::from_str(cluster).ok())
+            .filter(|cluster| Cluster::PUBLIC.contains(cluster));
+        let poll_interval = nvs
+            .get_u32(POLL_INTERVAL_KEY)
+            .map_err(|e| format!("Poll interval read: {:?}", e))?
+            .map(|seconds| Duration::from_secs(seconds as u64));
+        let max_fee = nvs.get_u64(MAX_FEE_KEY).map_err(|e| format!("Fee cap read: {:?}", e))?;
+        let display_dim = nvs
+            .get_u8(DISPLAY_DIM_KEY)
+            .map_err(|e| format!("Display setting read: {:?}", e))?
+            .is_some_and(|dim| dim != 0);
+
+        Ok(Self {
+            recipient,
+            cluster,
+            poll_interval,
+            max_fee,
+            display_dim,
+        })
+    }
+
+    pub fn store(&self, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+        let mut nvs = EspNvs::new(nvs, SETTINGS_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+
+        match &self.recipient {
+            Some(recipient) => nvs.set_str(RECIPIENT_KEY, &recipient.to_string()),
+            None => nvs.remove(RECIPIENT_KEY).map(|_| ()),
+        }
+        .map_err(|e| format!("Recipient store: {:?}", e))?;
+        match &self.cluster {
+            Some(cluster) => nvs.set_str(CLUSTER_KEY, cluster.name()),
+            None => nvs.remove(CLUSTER_KEY).map(|_| ()),
+        }
+        .map_err(|e| format!("Cluster store: {:?}", e))?;
+        match self.poll_interval {
+            Some(interval) => nvs.set_u32(POLL_INTERVAL_KEY, interval.as_secs() as u32),
+            None => nvs.remove(POLL_INTERVAL_KEY).map(|_| ()),
+        }
+        .map_err(|e| format!("Poll interval store: {:?}", e))?;
+        match self.max_fee {
+            Some(max_fee) => nvs.set_u64(MAX_FEE_KEY, max_fee),
+            None => nvs.remove(MAX_FEE_KEY).map(|_| ()),
+        }
+        .map_err(|e| format!("Fee cap store: {:?}", e))?;
+        nvs.set_u8(DISPLAY_DIM_KEY, self.display_dim as u8)
+            .map_err(|e| format!("Display setting store: {:?}", e))?;
+        Ok(())
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL)
+    }
+
+    // The value as the console shows it, "none" when unset
+    pub fn get(&self, setting: Setting) -> String {
+        let value = match setting {
+            Setting::Recipient => self.recipient.map(|recipient| recipient.to_string()),
+            Setting::Cluster => self.cluster.as_ref().map(|cluster| cluster.name().to_string()),
+            Setting::PollInterval => self.poll_interval.map(|interval| interval.as_secs().to_string()),
+            Setting::MaxFee => self.max_fee.map(|max_fee| max_fee.to_string()),
+            Setting::DisplayDim => Some(if self.display_dim { "on" } else { "off" }.to_string()),
+        };
+        value.unwrap_or_else(|| "none".to_string())
+    }
+
+    // Parses `value` as the console takes it: an address, a cluster name, seconds, lamports, or
+    // on/off. "none" clears the setting.
+    pub fn set(&mut self, setting: Setting, value: &str) -> Result<(), String> {
+        let value = Some(value).filter(|value| *value != "none");
+        match setting {
+            Setting::Recipient => {
+                self.recipient = value
+                    .map(|recipient| Pubkey::from_str(recipient).map_err(|e| format!("Invalid address: {:?}", e)))
+                    .transpose()?
+            }
+            Setting::Cluster => {
+                let cluster = value.map(Cluster::from_str).transpose()?;
+                if let Some(Cluster::Custom(..)) = cluster {
+                    return Err("Custom endpoints are provisioned into the keystore, not set here".to_string());
+                }
+                self.cluster = cluster;
+            }
+            Setting::PollInterval => {
+                self.poll_interval = value
+                    .map(|seconds| match seconds.parse::<u32>() {
+                        Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds as u64)),
+                        _ => Err(format!("Invalid poll interval '{}', whole seconds", seconds)),
+                    })
+                    .transpose()?
+            }
+            Setting::MaxFee => {
+                self.max_fee = value
+                    .map(|lamports| lamports.parse().map_err(|_| format!("Invalid fee cap '{}', lamports", lamports)))
+                    .transpose()?
+            }
+            Setting::DisplayDim => {
+                self.display_dim = match value {
+                    Some("on") => true,
+                    Some("off") | None => false,
+                    Some(other) => return Err(format!("Invalid display_dim '{}', on or off", other)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+type SettingsListener = Arc<dyn Fn(Setting, &DeviceSettings) + Send + Sync>;
+
+// The settings in use, None until load_settings has run
+static SETTINGS: Mutex<Option<DeviceSettings>> = Mutex::new(None);
+static SETTINGS_LISTENERS: Mutex<Vec<(u32, SettingsListener)>> = Mutex::new(Vec::new());
+static NEXT_SETTINGS_LISTENER: AtomicU32 = AtomicU32::new(0);
+
+// Reads the settings from NVS for `settings()`, the defaults when they can't be read
+pub fn load_settings(nvs: EspDefaultNvsPartition) -> DeviceSettings {
+    let settings = DeviceSettings::load(nvs).unwrap_or_else(|e| {
+        warn!("Device settings unavailable: {}", e);
+        DeviceSettings::default()
+    });
+    *SETTINGS.lock().unwrap() = Some(settings.clone());
+    settings
+}
+
+// The settings in use, the defaults before load_settings
+pub fn settings() -> DeviceSettings {
+    SETTINGS.lock().unwrap().clone().unwrap_or_default()
+}
+
+// Stores one setting from its text form, e.g. `change_setting(nvs, "poll_interval", "10")`,
+// and tells the subscribers. For the serial console, BLE or MQTT handlers alike.
+pub fn change_setting(nvs: EspDefaultNvsPartition, key: &str, value: &str) -> Result<Setting, String> {
+    let setting = Setting::from_str(key)?;
+    let mut settings = DeviceSettings::load(nvs.clone())?;
+    settings.set(setting, value)?;
+    settings.store(nvs)?;
+    *SETTINGS.lock().unwrap() = Some(settings.clone());
+
+    // Called outside the lock, so a callback may read the settings or unsubscribe
+    let listeners: Vec<SettingsListener> = SETTINGS_LISTENERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, listener)| listener.clone())
+        .collect();
+    for listener in listeners {
+        listener(setting, &settings);
+    }
+    Ok(setting)
+}
+
+// Keeps a callback registered, dropping it unsubscribes
+pub struct SettingsSubscription(u32);
+
+impl Drop for SettingsSubscription {
+    fn drop(&mut self) {
+        SETTINGS_LISTENERS.lock().unwrap().retain(|(id, _)| *id != self.0);
+    }
+}
+
+// Calls `callback` with every changed setting and the settings it's part of, on the thread
+// that made the change
+pub fn subscribe(callback: impl Fn(Setting, &DeviceSettings) + Send + Sync + 'static) -> SettingsSubscription {
+    let id = NEXT_SETTINGS_LISTENER.fetch_add(1, Ordering::Relaxed);
+    SETTINGS_LISTENERS.lock().unwrap().push((id, Arc::new(callback)));
+    SettingsSubscription(id)
+}
+
+// Loads the device settings and points the RPC client at the cluster picked in them. An RPC
+// endpoint provisioned into the keystore still takes precedence, the wallet applies it once the
+// keystore is open.
+#[cfg(not(feature = "remote-signer"))]
+pub fn apply_cluster(nvs: EspDefaultNvsPartition) {
+    if let Some(cluster) = load_settings(nvs).cluster {
+        RpcClient::reconfigure().cluster(&cluster).build().install();
+    }
+}
+
+// "REsp32Sol-XXXX" from the last two bytes of the MAC, names the device in BLE and SoftAP setup
+pub fn device_name() -> Result<String, String> {
+    let mut mac = [0u8; 6];
+    match unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) } {
+        ESP_OK => Ok(format!("REsp32Sol-{:02X}{:02X}", mac[4], mac[5])),
+        ret => Err(format!("MAC read: {}", ret)),
+    }
+}
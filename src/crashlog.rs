@@ -0,0 +1,227 @@
+use std::fmt::{self, Write as _};
+use std::panic::PanicHookInfo;
+use std::ptr::{addr_of, addr_of_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{esp_app_get_elf_sha256, esp_timer_get_time};
+use log::warn;
+
+// A panic is written to RTC memory from the panic hook, which can't rely on the heap or on NVS
+// (the panicking thread may hold its lock), and moved into NVS on the next boot. The task
+// watchdog and other ESP-IDF level panics bypass the hook, their reset reason still tells.
+
+const PANIC_NAMESPACE: &str = "panic";
+const COUNT_KEY: &str = "count";
+const MESSAGE_KEY: &str = "message";
+const LOCATION_KEY: &str = "location";
+const THREAD_KEY: &str = "thread";
+const UPTIME_KEY: &str = "uptime";
+const BUILD_KEY: &str = "build";
+
+const MAGIC: u32 = 0x5041_4e43;
+
+#[repr(C)]
+struct Record {
+    magic: u32,
+    // FNV-1a over everything after it, RTC memory holds noise after a power cut
+    checksum: u32,
+    uptime_ms: u64,
+    // Start of the ELF SHA-256 of the build that panicked, the build to decode its backtrace with
+    build: [u8; 8],
+    message: [u8; 120],
+    location: [u8; 80],
+    thread: [u8; 16],
+}
+
+// Not initialized at boot, so a software reset keeps it
+#[link_section = ".rtc_noinit"]
+static mut RECORD: Record = Record {
+    magic: 0,
+    checksum: 0,
+    uptime_ms: 0,
+    build: [0; 8],
+    message: [0; 120],
+    location: [0; 80],
+    thread: [0; 16],
+};
+
+// Only the first of several threads panicking at once is recorded
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone)]
+pub struct LastPanic {
+    pub message: String,
+    pub location: String,
+    pub thread: String,
+    pub uptime_ms: u64,
+    pub build: String,
+}
+
+// Records the panic ahead of the default output on the console. The abort that follows has
+// ESP-IDF's panic handler print the backtrace and restart, sdkconfig.defaults makes sure it
+// restarts rather than halts. Installed first in app::start, so boot code is covered as well.
+pub fn install() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if !PANICKED.swap(true, Ordering::SeqCst) {
+            record(info);
+        }
+        default(info);
+    }));
+}
+
+fn record(info: &PanicHookInfo) {
+    let record = unsafe { &mut *addr_of_mut!(RECORD) };
+    record.uptime_ms = (unsafe { esp_timer_get_time() } / 1000) as u64;
+
+    let mut sha = [0u8; 17];
+    unsafe { esp_app_get_elf_sha256(sha.as_mut_ptr() as *mut _, sha.len()) };
+    record.build.copy_from_slice(&sha[..8]);
+
+    let message = match (info.payload().downcast_ref::<&str>(), info.payload().downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message.as_str(),
+        _ => "non-string panic payload",
+    };
+    let _ = write!(Field::new(&mut record.message), "{}", message);
+    // Cleared even without a location to write, a previous panic's would pass as this one's.
+    // lean-panic builds compile the locations out, every one reads "<redacted>".
+    let mut location = Field::new(&mut record.location);
+    if let Some(at) = info.location().filter(|_| !cfg!(feature = "lean-panic")) {
+        let _ = write!(location, "{}:{}", at.file(), at.line());
+    }
+    let _ = write!(Field::new(&mut record.thread), "{}", std::thread::current().name().unwrap_or("?"));
+
+    record.checksum = checksum(record);
+    record.magic = MAGIC;
+}
+
+// Takes the record left by the last panic, if the restart was one
+fn take_record() -> Option<LastPanic> {
+    let record = unsafe { &mut *addr_of_mut!(RECORD) };
+    let valid = record.magic == MAGIC && record.checksum == checksum(record);
+    record.magic = 0;
+    if !valid {
+        return None;
+    }
+    Some(LastPanic {
+        message: text(&record.message),
+        location: text(&record.location),
+        thread: text(&record.thread),
+        uptime_ms: record.uptime_ms,
+        build: text(&record.build),
+    })
+}
+
+// The last panic and how many there have been, until cleared
+pub struct PanicLog {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl PanicLog {
+    // Moves a panic recorded before this boot's restart into NVS
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, PANIC_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        let mut log = Self { nvs };
+        if let Some(panic) = take_record() {
+            warn!(
+                "Restarted after a panic in thread '{}' at {} after {} ms: {}",
+                panic.thread, panic.location, panic.uptime_ms, panic.message
+            );
+            log.store(&panic)?;
+        }
+        Ok(log)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.nvs.get_u32(COUNT_KEY).ok().flatten().unwrap_or(0)
+    }
+
+    pub fn last(&self) -> Option<LastPanic> {
+        let mut buf = [0u8; 128];
+        let mut get = |key: &str| {
+            self.nvs
+                .get_str(key, &mut buf)
+                .ok()
+                .flatten()
+                .map(str::to_string)
+        };
+        Some(LastPanic {
+            message: get(MESSAGE_KEY)?,
+            location: get(LOCATION_KEY).unwrap_or_default(),
+            thread: get(THREAD_KEY).unwrap_or_default(),
+            uptime_ms: self.nvs.get_u64(UPTIME_KEY).ok().flatten().unwrap_or(0),
+            build: get(BUILD_KEY).unwrap_or_default(),
+        })
+    }
+
+    pub fn clear(&mut self) -> Result<(), String> {
+        for key in [COUNT_KEY, MESSAGE_KEY, LOCATION_KEY, THREAD_KEY, UPTIME_KEY, BUILD_KEY] {
+            self.nvs
+                .remove(key)
+                .map_err(|e| format!("Panic log clear: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, panic: &LastPanic) -> Result<(), String> {
+        for (key, value) in [
+            (MESSAGE_KEY, &panic.message),
+            (LOCATION_KEY, &panic.location),
+            (THREAD_KEY, &panic.thread),
+            (BUILD_KEY, &panic.build),
+        ] {
+            self.nvs
+                .set_str(key, value)
+                .map_err(|e| format!("Panic log store: {:?}", e))?;
+        }
+        self.nvs
+            .set_u64(UPTIME_KEY, panic.uptime_ms)
+            .map_err(|e| format!("Panic log store: {:?}", e))?;
+        self.nvs
+            .set_u32(COUNT_KEY, self.count().saturating_add(1))
+            .map_err(|e| format!("Panic log store: {:?}", e))
+    }
+}
+
+// Writes into a fixed buffer and drops what doesn't fit, whole characters only
+struct Field<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Field<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        buf.fill(0);
+        Self { buf, len: 0 }
+    }
+}
+
+impl fmt::Write for Field<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > self.buf.len() {
+                break;
+            }
+            c.encode_utf8(&mut self.buf[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+fn text(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+fn checksum(record: &Record) -> u32 {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(addr_of!(*record) as *const u8, core::mem::size_of::<Record>())
+    };
+    bytes[8..]
+        .iter()
+        .fold(0x811c_9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
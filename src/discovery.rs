@@ -0,0 +1,58 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::sys::{esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac, ESP_OK};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+
+// Local dashboards and companion apps browse for this to find devices without knowing their address
+const SERVICE_TYPE: &str = "_solwallet";
+const SERVICE_PROTO: &str = "_tcp";
+// Nothing on the device takes connections, the record is there for the address and TXT data
+const SERVICE_PORT: u16 = 0;
+
+// The responder keeps answering queries for as long as this is held
+static MDNS: Mutex<Option<EspMdns>> = Mutex::new(None);
+
+// Advertises the device as <hostname>.local with its wallet address and firmware version in TXT
+// records. Watch-only devices hold no key and leave the address out.
+pub fn advertise(pubkey: Option<&Pubkey>) {
+    // Relay nodes are not on the LAN
+    #[cfg(feature = "espnow-relay")]
+    if crate::relay::is_node() {
+        return;
+    }
+    #[cfg(feature = "lora-bridge")]
+    if crate::lora::is_node() {
+        return;
+    }
+    match start(pubkey) {
+        Ok(hostname) => info!("Advertised over mDNS as {}.local", hostname),
+        Err(e) => warn!("mDNS advertisement unavailable: {}", e),
+    }
+}
+
+fn start(pubkey: Option<&Pubkey>) -> Result<String, String> {
+    let mut mdns = EspMdns::take().map_err(|e| format!("mDNS: {:?}", e))?;
+
+    // Unique per device, from the end of the station MAC
+    let mut mac = [0u8; 6];
+    let hostname = match unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) } {
+        ESP_OK => format!("resp32sol-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]),
+        ret => return Err(format!("MAC read: {}", ret)),
+    };
+    mdns.set_hostname(&hostname).map_err(|e| format!("mDNS hostname: {:?}", e))?;
+    mdns.set_instance_name(&hostname)
+        .map_err(|e| format!("mDNS instance name: {:?}", e))?;
+
+    let pubkey = pubkey.map(|pubkey| pubkey.to_string());
+    let mut txt = vec![("version", env!("CARGO_PKG_VERSION"))];
+    if let Some(pubkey) = pubkey.as_deref() {
+        txt.push(("pubkey", pubkey));
+    }
+    mdns.add_service(None, SERVICE_TYPE, SERVICE_PROTO, SERVICE_PORT, &txt)
+        .map_err(|e| format!("mDNS service: {:?}", e))?;
+
+    *MDNS.lock().unwrap() = Some(mdns);
+    Ok(hostname)
+}
@@ -0,0 +1,486 @@
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+
+use crate::amount::Sol;
+use crate::b58::{self, Short};
+use crate::buffers;
+use crate::config::{self, Setting, SettingsSubscription};
+use crate::error::RpcError;
+use crate::lifecycle::{self, LifecycleSubscription, WalletState};
+#[cfg(feature = "low-power")]
+use crate::lowpower;
+use crate::net::{self, NetEvent, NetSubscription};
+use crate::qr::{QrMatrix, SSD1306_BUFFER_LEN};
+use crate::solrpc;
+use crate::tasks::{self, UI};
+
+// Status screen in 128x64 pixels, on an I2C OLED (see `oled`) or an e-paper panel (`epaper`).
+// The panel is owned by a thread of its own that redraws on network events, wallet state changes,
+// new transactions and balance changes, so nothing in the signing path waits on the bus. Text uses a 5x7 font in 6x8
+// cells, 21 characters on each of the 8 rows.
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = PAGES * 8;
+pub const PAGES: usize = 8;
+const COLUMNS: usize = WIDTH / 6;
+// Polled while online, the balance also changes through transfers in from elsewhere
+const BALANCE_REFRESH: Duration = Duration::from_secs(60);
+// Longest a redraw with its balance lookup takes, waiting for the link included
+const REDRAW_DEADLINE: Duration = Duration::from_secs(300);
+
+// What frames are drawn on
+pub trait Backend: Send {
+    fn flush(&mut self, frame: &Frame) -> Result<(), String>;
+
+    // Lit pixels come out dark, as ink on e-paper
+    fn lit_is_dark(&self) -> bool {
+        false
+    }
+
+    // Lower brightness for battery operation, panels that draw nothing while static ignore it
+    fn set_dimmed(&mut self, _dimmed: bool) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+enum Update {
+    Net(NetEvent),
+    State(WalletState),
+    Address(Pubkey),
+    Transaction(Result<String, RpcError>),
+    // Takes over the whole screen, None goes back to the status
+    Overlay(Option<Box<Frame>>),
+    Received(String),
+    // Back to the status screen with the balance looked up again
+    Balance,
+    ToggleBlank,
+    Dim(bool),
+    // The display_dim setting changed
+    DimSetting,
+}
+
+static UPDATES: Mutex<Option<SyncSender<Update>>> = Mutex::new(None);
+static SUBSCRIPTION: Mutex<Option<NetSubscription>> = Mutex::new(None);
+static LIFECYCLE: Mutex<Option<LifecycleSubscription>> = Mutex::new(None);
+static SETTINGS: Mutex<Option<SettingsSubscription>> = Mutex::new(None);
+static LIT_IS_DARK: AtomicBool = AtomicBool::new(false);
+
+pub fn start(mut panel: Box<dyn Backend>) -> Result<(), String> {
+    LIT_IS_DARK.store(panel.lit_is_dark(), Ordering::Relaxed);
+    let screen = Screen {
+        link: if net::link_up() { Link::Online } else { Link::Connecting },
+        state: lifecycle::state(),
+        address: None,
+        balance: None,
+        last_transaction: None,
+        overlay: None,
+        last_received: None,
+        blank: false,
+    };
+    panel.flush(&screen.render())?;
+
+    let (updates, received) = sync_channel(buffers::config().display_queue);
+    tasks::spawn(&UI, move || run(panel, screen, received))?;
+
+    *UPDATES.lock().unwrap() = Some(updates.clone());
+    let dim_settings = updates.clone();
+    *SETTINGS.lock().unwrap() = Some(config::subscribe(move |setting, _| {
+        if setting == Setting::DisplayDim {
+            let _ = dim_settings.try_send(Update::DimSetting);
+        }
+    }));
+    let states = updates.clone();
+    *LIFECYCLE.lock().unwrap() = Some(lifecycle::subscribe(move |transition| {
+        let _ = states.try_send(Update::State(transition.to));
+    }));
+    *SUBSCRIPTION.lock().unwrap() = Some(net::subscribe(move |event| {
+        let _ = updates.try_send(Update::Net(event));
+    }));
+    info!("Status display up");
+    Ok(())
+}
+
+// The wallet whose address and balance are shown
+pub fn set_address(address: Pubkey) {
+    send(Update::Address(address));
+}
+
+// Dims and brightens the panel, see `lowpower`
+pub fn set_dimmed(dimmed: bool) {
+    send(Update::Dim(dimmed));
+}
+
+// Called with the outcome of every sendTransaction
+pub fn transaction_sent(result: &Result<String, RpcError>) {
+    send(Update::Transaction(result.clone()));
+}
+
+// A QR code, a payment request or the setup portal's network, in place of the status screen,
+// the caption left of it in the space the code leaves
+pub fn show_request(qr: &QrMatrix, caption: &[&str]) -> Result<(), String> {
+    let mut buffer = [0; SSD1306_BUFFER_LEN];
+    qr.render_ssd1306(&mut buffer)?;
+    let mut frame = Frame::new();
+    for (page, columns) in frame.0.iter_mut().zip(buffer.chunks(WIDTH)) {
+        page.copy_from_slice(columns);
+    }
+    // Drawn for an OLED, dark modules unlit
+    if LIT_IS_DARK.load(Ordering::Relaxed) {
+        frame.invert_code();
+    }
+    let first_row = (PAGES - caption.len().min(PAGES)) / 2;
+    for (row, line) in caption.iter().take(PAGES).enumerate() {
+        frame.caption(first_row + row, line);
+    }
+    send(Update::Overlay(Some(Box::new(frame))));
+    Ok(())
+}
+
+// Lines of text in place of the status screen, one per row from the top
+pub fn show_entry(lines: &[&str]) {
+    let mut frame = Frame::new();
+    for (row, line) in lines.iter().take(PAGES).enumerate() {
+        frame.text(row, line);
+    }
+    send(Update::Overlay(Some(Box::new(frame))));
+}
+
+// Back to the status screen
+pub fn clear_overlay() {
+    send(Update::Overlay(None));
+}
+
+// The status screen with the balance looked up again
+pub fn show_balance() {
+    send(Update::Balance);
+}
+
+// Turns the panel dark or back on, e.g. against OLED burn-in
+pub fn toggle_blank() {
+    send(Update::ToggleBlank);
+}
+
+// Back to the status screen, with the payment that came in
+pub fn payment_received(description: &str) {
+    send(Update::Received(description.to_string()));
+}
+
+fn send(update: Update) {
+    if let Some(updates) = UPDATES.lock().unwrap().as_ref() {
+        if let Err(TrySendError::Full(_)) = updates.try_send(update) {
+            warn!("Display behind, update dropped");
+        }
+    }
+}
+
+fn run(mut panel: Box<dyn Backend>, mut screen: Screen, updates: Receiver<Update>) {
+    let mut balance_checked: Option<Instant> = None;
+    // Dimmed by low-power while idle
+    let mut idle_dimmed = false;
+    dim(panel.as_mut(), idle_dimmed);
+    loop {
+        tasks::beat("ui", REDRAW_DEADLINE);
+        let due = balance_checked.map(|checked| BALANCE_REFRESH.saturating_sub(checked.elapsed()));
+        #[cfg(feature = "low-power")]
+        let due = due.map(|due| due.max(lowpower::until_window()));
+        match updates.recv_timeout(due.unwrap_or(BALANCE_REFRESH)) {
+            Ok(Update::Net(event)) => {
+                screen.link = match event {
+                    NetEvent::GotIp => Link::Online,
+                    NetEvent::LostIp => Link::Offline,
+                    NetEvent::Reconnecting => Link::Connecting,
+                    NetEvent::CaptivePortalSuspected => Link::Portal,
+                };
+                if event == NetEvent::GotIp {
+                    balance_checked = None;
+                }
+            }
+            Ok(Update::State(state)) => screen.state = state,
+            Ok(Update::Address(address)) => {
+                screen.address = Some(address);
+                screen.balance = None;
+                balance_checked = None;
+            }
+            Ok(Update::Transaction(result)) => {
+                screen.last_transaction = Some(result);
+                balance_checked = None;
+            }
+            Ok(Update::Overlay(frame)) => screen.overlay = frame.map(|frame| *frame),
+            Ok(Update::Received(description)) => {
+                screen.overlay = None;
+                screen.last_received = Some(description);
+                balance_checked = None;
+            }
+            Ok(Update::Balance) => {
+                screen.overlay = None;
+                screen.blank = false;
+                balance_checked = None;
+            }
+            Ok(Update::ToggleBlank) => screen.blank = !screen.blank,
+            Ok(Update::Dim(dimmed)) => {
+                idle_dimmed = dimmed;
+                dim(panel.as_mut(), idle_dimmed);
+            }
+            Ok(Update::DimSetting) => dim(panel.as_mut(), idle_dimmed),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let balance_due = balance_checked.is_none_or(|checked| checked.elapsed() >= BALANCE_REFRESH);
+        // A battery device refreshes in its next RPC window
+        #[cfg(feature = "low-power")]
+        let balance_due = balance_due && lowpower::until_window().is_zero();
+        if let (true, true, Some(address)) = (balance_due, screen.link == Link::Online, screen.address) {
+            match solrpc::get_balance(&address) {
+                Ok(lamports) => screen.balance = Some(lamports),
+                Err(e) => warn!("Display balance refresh failed: {}", e),
+            }
+            balance_checked = Some(Instant::now());
+        }
+
+        if let Err(e) = panel.flush(&screen.render()) {
+            warn!("Display update failed: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    Connecting,
+    Online,
+    Offline,
+    Portal,
+}
+
+struct Screen {
+    link: Link,
+    state: WalletState,
+    address: Option<Pubkey>,
+    balance: Option<u64>,
+    last_transaction: Option<Result<String, RpcError>>,
+    overlay: Option<Frame>,
+    last_received: Option<String>,
+    blank: bool,
+}
+
+impl Screen {
+    fn render(&self) -> Frame {
+        if self.blank {
+            return Frame::new();
+        }
+        if let Some(overlay) = &self.overlay {
+            return overlay.clone();
+        }
+        let mut frame = Frame::new();
+        frame.text(
+            0,
+            match self.link {
+                Link::Connecting => "Net: connecting",
+                Link::Online => "Net: online",
+                Link::Offline => "Net: offline",
+                Link::Portal => "Net: login needed",
+            },
+        );
+        frame.text(
+            1,
+            match self.state {
+                WalletState::Provisioning => "Wallet: setup",
+                WalletState::Connecting => "Wallet: connecting",
+                WalletState::Syncing => "Wallet: syncing",
+                WalletState::Ready => "Wallet: ready",
+                WalletState::Sending => "Wallet: sending",
+                WalletState::Confirming => "Wallet: confirming",
+                WalletState::Error => "Wallet: error",
+                WalletState::Recovering => "Wallet: recovering",
+            },
+        );
+        if let Some(address) = &self.address {
+            let mut buf = [0u8; b58::MAX_LEN];
+            frame.text(2, &Short(b58::encode(address.as_ref(), &mut buf), 8).to_string());
+        }
+        if let Some(balance) = self.balance {
+            frame.text(3, &format!("{:.4}", Sol(balance)));
+        }
+        match &self.last_transaction {
+            Some(Ok(signature)) => {
+                frame.text(5, "Last tx: sent");
+                frame.text(6, &Short(signature, 8).to_string());
+            }
+            Some(Err(e)) => {
+                frame.text(5, "Last tx: failed");
+                frame.text(6, &e.to_string());
+            }
+            None => {}
+        }
+        if let Some(received) = &self.last_received {
+            frame.text(7, received);
+        }
+        frame
+    }
+}
+
+// Low brightness while idle on low-power, or all the time with the display_dim setting
+fn dim(panel: &mut dyn Backend, idle_dimmed: bool) {
+    if let Err(e) = panel.set_dimmed(idle_dimmed || config::settings().display_dim) {
+        warn!("Display dimming failed: {}", e);
+    }
+}
+
+// One bit per pixel, a byte per 8 pixel column of a page as the OLED controllers take it
+#[derive(Clone, PartialEq, Eq)]
+pub struct Frame([[u8; WIDTH]; PAGES]);
+
+impl Frame {
+    fn new() -> Self {
+        Self([[0; WIDTH]; PAGES])
+    }
+
+    // Rows of 8 pixels, a byte per column with the top pixel in bit 0
+    pub fn pages(&self) -> &[[u8; WIDTH]; PAGES] {
+        &self.0
+    }
+
+    // Whether the pixel is lit on an OLED, black on e-paper
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.0[y / 8][x] & (1 << (y % 8)) != 0
+    }
+
+    // Writes a row of text, cut at the edge of the screen
+    fn text(&mut self, row: usize, text: &str) {
+        for (column, c) in text.chars().take(COLUMNS).enumerate() {
+            let glyph = match c {
+                ' '..='~' => &FONT[c as usize - ' ' as usize],
+                _ => &FONT['?' as usize - ' ' as usize],
+            };
+            let start = column * 6;
+            self.0[row][start..start + 5].copy_from_slice(glyph);
+        }
+    }
+
+    // Inverts the square of a QR code centered on an otherwise blank frame, found by its quiet
+    // zone which is lit all around
+    fn invert_code(&mut self) {
+        let lit = |x: &usize| self.0.iter().any(|page| page[*x] != 0);
+        let (Some(left), Some(right)) = ((0..WIDTH).find(lit), (0..WIDTH).rfind(lit)) else {
+            return;
+        };
+        let side = right + 1 - left;
+        let top = HEIGHT.saturating_sub(side) / 2;
+        for y in top..(top + side).min(HEIGHT) {
+            for column in &mut self.0[y / 8][left..=right] {
+                *column ^= 1 << (y % 8);
+            }
+        }
+    }
+
+    // Writes text from the left edge for as long as the row is dark, so it stops short of
+    // whatever is drawn to its right
+    fn caption(&mut self, row: usize, text: &str) {
+        let clear = self.0[row].iter().take_while(|column| **column == 0).count() / 6;
+        self.text(row, &text.chars().take(clear).collect::<String>());
+    }
+}
+
+// Printable ASCII from ' ' to '~', five columns per glyph with the top row in bit 0
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5F, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7F, 0x14, 0x7F, 0x14],
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50],
+    [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1C, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1C, 0x00],
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08],
+    [0x08, 0x08, 0x3E, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3E, 0x51, 0x49, 0x45, 0x3E],
+    [0x00, 0x42, 0x7F, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4B, 0x31],
+    [0x18, 0x14, 0x12, 0x7F, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3C, 0x4A, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1E],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x51, 0x09, 0x06],
+    [0x32, 0x49, 0x79, 0x41, 0x3E],
+    [0x7E, 0x11, 0x11, 0x11, 0x7E],
+    [0x7F, 0x49, 0x49, 0x49, 0x36],
+    [0x3E, 0x41, 0x41, 0x41, 0x22],
+    [0x7F, 0x41, 0x41, 0x22, 0x1C],
+    [0x7F, 0x49, 0x49, 0x49, 0x41],
+    [0x7F, 0x09, 0x09, 0x09, 0x01],
+    [0x3E, 0x41, 0x49, 0x49, 0x7A],
+    [0x7F, 0x08, 0x08, 0x08, 0x7F],
+    [0x00, 0x41, 0x7F, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3F, 0x01],
+    [0x7F, 0x08, 0x14, 0x22, 0x41],
+    [0x7F, 0x40, 0x40, 0x40, 0x40],
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F],
+    [0x7F, 0x04, 0x08, 0x10, 0x7F],
+    [0x3E, 0x41, 0x41, 0x41, 0x3E],
+    [0x7F, 0x09, 0x09, 0x09, 0x06],
+    [0x3E, 0x41, 0x51, 0x21, 0x5E],
+    [0x7F, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7F, 0x01, 0x01],
+    [0x3F, 0x40, 0x40, 0x40, 0x3F],
+    [0x1F, 0x20, 0x40, 0x20, 0x1F],
+    [0x3F, 0x40, 0x38, 0x40, 0x3F],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x00, 0x7F, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x00, 0x41, 0x41, 0x7F, 0x00],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x01, 0x02, 0x04, 0x00],
+    [0x20, 0x54, 0x54, 0x54, 0x78],
+    [0x7F, 0x48, 0x44, 0x44, 0x38],
+    [0x38, 0x44, 0x44, 0x44, 0x20],
+    [0x38, 0x44, 0x44, 0x48, 0x7F],
+    [0x38, 0x54, 0x54, 0x54, 0x18],
+    [0x08, 0x7E, 0x09, 0x01, 0x02],
+    [0x0C, 0x52, 0x52, 0x52, 0x3E],
+    [0x7F, 0x08, 0x04, 0x04, 0x78],
+    [0x00, 0x44, 0x7D, 0x40, 0x00],
+    [0x20, 0x40, 0x44, 0x3D, 0x00],
+    [0x7F, 0x10, 0x28, 0x44, 0x00],
+    [0x00, 0x41, 0x7F, 0x40, 0x00],
+    [0x7C, 0x04, 0x18, 0x04, 0x78],
+    [0x7C, 0x08, 0x04, 0x04, 0x78],
+    [0x38, 0x44, 0x44, 0x44, 0x38],
+    [0x7C, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7C],
+    [0x7C, 0x08, 0x04, 0x04, 0x08],
+    [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3F, 0x44, 0x40, 0x20],
+    [0x3C, 0x40, 0x40, 0x20, 0x7C],
+    [0x1C, 0x20, 0x40, 0x20, 0x1C],
+    [0x3C, 0x40, 0x30, 0x40, 0x3C],
+    [0x44, 0x28, 0x10, 0x28, 0x44],
+    [0x0C, 0x50, 0x50, 0x50, 0x3C],
+    [0x44, 0x64, 0x54, 0x4C, 0x44],
+    [0x00, 0x08, 0x36, 0x41, 0x00],
+    [0x00, 0x00, 0x7F, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00],
+    [0x08, 0x04, 0x08, 0x10, 0x08],
+];
@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::{
+    client::{Configuration, EspHttpConnection},
+    Method,
+};
+use log::info;
+
+use crate::dualstack;
+use crate::error::NetError;
+use crate::transport;
+
+// DNS-over-HTTPS for the RPC host, so a guest network's resolver can't point the wallet at a
+// look-alike endpoint. Answers come from the resolver over TLS, are cached for their TTL and
+// are handed to the HTTP client's lookup through lwIP's resolve hook (see dualstack.rs).
+
+// JSON DoH endpoint, e.g. https://1.1.1.1/dns-query, empty uses the network's resolver (see build.rs)
+const DOH_URL: &str = env!("RESP32SOL_DOH_URL");
+const DOH_TIMEOUT: Duration = Duration::from_secs(10);
+// Bounds on the records' TTL, short ones would cost a TLS handshake per call
+const MIN_TTL: u64 = 60;
+const MAX_TTL: u64 = 3600;
+// An A plus AAAA answer is a few hundred bytes
+const MAX_RESPONSE_LEN: usize = 4096;
+// RR types in the JSON answer
+const TYPE_A: u64 = 1;
+const TYPE_AAAA: u64 = 28;
+
+struct Answer {
+    host: String,
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+static ANSWERS: Mutex<Vec<Answer>> = Mutex::new(Vec::new());
+
+pub fn enabled() -> bool {
+    !DOH_URL.is_empty()
+}
+
+// Resolves the URL's host over DoH unless a fresh answer is cached. Fails when the resolver
+// can't be reached rather than falling back to plain DNS, which is what DoH is there to avoid.
+pub fn prepare(url: &str) -> Result<(), NetError> {
+    if !enabled() {
+        return Ok(());
+    }
+    let Some((host, _)) = dualstack::host_and_port(url) else {
+        return Ok(());
+    };
+    if lookup(host).is_some() {
+        return Ok(());
+    }
+
+    let (addresses, ttl) = query(host).map_err(|e| NetError::Dns(format!("DoH lookup of {} failed: {}", host, e)))?;
+    if addresses.is_empty() {
+        return Err(NetError::Dns(format!("DoH lookup of {} found no address", host)));
+    }
+    info!("{} resolved over DoH to {:?}", host, addresses);
+
+    let mut answers = ANSWERS.lock().unwrap();
+    answers.retain(|answer| !answer.host.eq_ignore_ascii_case(host) && answer.expires > Instant::now());
+    answers.push(Answer {
+        host: host.to_string(),
+        addresses,
+        expires: Instant::now() + Duration::from_secs(ttl),
+    });
+    Ok(())
+}
+
+// The cached DoH addresses of a host, None when there is no fresh answer
+pub fn lookup(host: &str) -> Option<Vec<IpAddr>> {
+    ANSWERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|answer| answer.host.eq_ignore_ascii_case(host) && answer.expires > Instant::now())
+        .map(|answer| answer.addresses.clone())
+}
+
+// Asks for A and AAAA records over one connection, returns the addresses and the lowest TTL
+fn query(host: &str) -> Result<(Vec<IpAddr>, u64), String> {
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(DOH_TIMEOUT),
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })
+    .map_err(|e| format!("HTTP init: {:?}", e))?;
+    let mut client = Client::wrap(connection);
+
+    let mut addresses = Vec::new();
+    let mut ttl = MAX_TTL;
+    for record_type in ["A", "AAAA"] {
+        let url = format!("{}?name={}&type={}", DOH_URL, host, record_type);
+        let headers = [("Accept", "application/dns-json")];
+        let request = client
+            .request(Method::Get, &url, &headers)
+            .map_err(|e| format!("Request: {:?}", e))?;
+        let mut response = request.submit().map_err(|e| format!("Submit: {:?}", e))?;
+        let status = response.status();
+        if !(200..=299).contains(&status) {
+            return Err(format!("HTTP Error: Status code {}", status));
+        }
+
+        let mut body = Vec::new();
+        transport::read_body::<Box<dyn Error>>(&mut response, DOH_TIMEOUT, &mut |data| {
+            if body.len() + data.len() > MAX_RESPONSE_LEN {
+                return Err("Response too large".into());
+            }
+            body.extend_from_slice(data);
+            Ok(())
+        })
+        .map_err(|e| e.to_string())?;
+        let json: serde_json::Value = serde_json::from_slice(&body).map_err(|e| format!("JSON parse: {:?}", e))?;
+
+        // 0 is NOERROR, 3 NXDOMAIN
+        match json["Status"].as_u64() {
+            Some(0) => {}
+            Some(3) => return Err("No such host".to_string()),
+            status => return Err(format!("Resolver status {:?}", status)),
+        }
+        // CNAME records come first and are skipped, the addresses of their target follow
+        for record in json["Answer"].as_array().into_iter().flatten() {
+            let address = match record["type"].as_u64() {
+                Some(TYPE_A) | Some(TYPE_AAAA) => record["data"].as_str().and_then(|data| data.parse::<IpAddr>().ok()),
+                _ => None,
+            };
+            if let Some(address) = address {
+                addresses.push(address);
+                ttl = ttl.min(record["TTL"].as_u64().unwrap_or(MIN_TTL));
+            }
+        }
+    }
+    Ok((addresses, ttl.max(MIN_TTL)))
+}
@@ -0,0 +1,275 @@
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::ptr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::{
+    addrinfo, err_enum_t_ERR_OK, err_enum_t_ERR_VAL, err_t, esp_ip6_addr_t, esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_GLOBAL,
+    esp_netif_create_ip6_linklocal, esp_netif_get_all_ip6, esp_netif_ip6_get_addr_type, esp_netif_t, ip_addr_t,
+    lwip_freeaddrinfo, lwip_getaddrinfo, lwip_ip_addr_type_IPADDR_TYPE_V4, lwip_ip_addr_type_IPADDR_TYPE_V6, sockaddr,
+    sockaddr_in, sockaddr_in6, AF_INET, AF_INET6, LWIP_DNS_ADDRTYPE_IPV4, LWIP_DNS_ADDRTYPE_IPV4_IPV6,
+    LWIP_DNS_ADDRTYPE_IPV6, LWIP_DNS_ADDRTYPE_IPV6_IPV4, SOCK_STREAM,
+};
+use log::info;
+
+use crate::doh;
+
+// IPv6 next to IPv4 on every uplink, for the v6-only networks carriers and campuses run for IoT.
+// The HTTP client connects to whatever address its lookup returns first and lwIP looks up IPv4
+// first, so before an RPC call the two families are raced (RFC 8305 style) and later lookups of
+// that host are steered to the winner through lwIP's resolve hook.
+
+// "ipv6" always prefers IPv6, "ipv4" leaves lwIP's default, empty races the two (see build.rs)
+const IP_FAMILY: &str = env!("RESP32SOL_IP_FAMILY");
+// Head start IPv6 gets before IPv4 is tried too
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a race result is reused, networks rarely change family behind a live link
+const PREFERENCE_TTL: Duration = Duration::from_secs(600);
+const PROBE_STACK_SIZE: usize = 4 * 1024;
+// lwIP keeps at most LWIP_IPV6_NUM_ADDRESSES (3) per interface
+const MAX_IP6_ADDRESSES: usize = 8;
+
+extern "C" {
+    fn netconn_gethostbyname_addrtype(name: *const c_char, addr: *mut ip_addr_t, dns_addrtype: u8) -> err_t;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+static PREFERRED: Mutex<Vec<(String, Family, Instant)>> = Mutex::new(Vec::new());
+
+// Starts IPv6 on an interface once its link is up: the link-local address, from which SLAAC
+// and stateless DHCPv6 get the global address and DNS servers
+pub fn enable(netif: *mut esp_netif_t) {
+    // Fails harmlessly when the interface already has one
+    unsafe { esp_netif_create_ip6_linklocal(netif) };
+}
+
+// Whether SLAAC has given the interface a routable IPv6 address yet
+pub fn has_global_address(netif: *mut esp_netif_t) -> bool {
+    let mut addresses = [esp_ip6_addr_t::default(); MAX_IP6_ADDRESSES];
+    let count = unsafe { esp_netif_get_all_ip6(netif, addresses.as_mut_ptr()) }.clamp(0, MAX_IP6_ADDRESSES as i32);
+    addresses[..count as usize]
+        .iter_mut()
+        .any(|address| unsafe { esp_netif_ip6_get_addr_type(address) } == esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_GLOBAL)
+}
+
+// Picks the family for the URL's host ahead of the HTTP client's own lookup, when it isn't known
+// or configured already
+pub fn prepare(url: &str) {
+    if !IP_FAMILY.is_empty() {
+        return;
+    }
+    let Some((host, port)) = host_and_port(url) else {
+        return;
+    };
+    if cached(host).is_some() {
+        return;
+    }
+
+    if let Some(family) = race(host, port) {
+        info!("{} reached over {:?}", host, family);
+        let mut preferred = PREFERRED.lock().unwrap();
+        preferred.retain(|(known, _, at)| known != host && at.elapsed() < PREFERENCE_TTL);
+        preferred.push((host.to_string(), family, Instant::now()));
+    }
+}
+
+fn cached(host: &str) -> Option<Family> {
+    PREFERRED
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(known, _, at)| known == host && at.elapsed() < PREFERENCE_TTL)
+        .map(|(_, family, _)| *family)
+}
+
+// Connects to the host's IPv6 and IPv4 addresses, IPv6 first, and returns the family that
+// answered first. The probe connections are closed right away, TLS only runs on the real one.
+fn race(host: &str, port: u16) -> Option<Family> {
+    let v6 = resolve(host, AF_INET6);
+    let v4 = resolve(host, AF_INET);
+    let (v6, v4) = match (v6, v4) {
+        (Some(v6), Some(v4)) => (SocketAddr::new(v6, port), SocketAddr::new(v4, port)),
+        (Some(_), None) => return Some(Family::V6),
+        (None, Some(_)) => return Some(Family::V4),
+        (None, None) => return None,
+    };
+
+    let (finished, results) = channel();
+    probe(v6, Family::V6, finished.clone());
+    let mut v4_probe = Some((v4, finished));
+    let mut failed = 0;
+    loop {
+        let wait = match v4_probe {
+            Some(_) => ATTEMPT_DELAY,
+            None => CONNECT_TIMEOUT,
+        };
+        match results.recv_timeout(wait) {
+            Ok((family, true)) => return Some(family),
+            Ok((_, false)) => {
+                failed += 1;
+                if failed == 2 {
+                    return None;
+                }
+            }
+            Err(_) if v4_probe.is_none() => return None,
+            Err(_) => {}
+        }
+        // IPv6 failed or is slow
+        if let Some((v4, finished)) = v4_probe.take() {
+            probe(v4, Family::V4, finished);
+        }
+    }
+}
+
+fn probe(address: SocketAddr, family: Family, finished: Sender<(Family, bool)>) {
+    let _ = std::thread::Builder::new()
+        .name("ip-probe".to_string())
+        .stack_size(PROBE_STACK_SIZE)
+        .spawn(move || {
+            let connected = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).is_ok();
+            let _ = finished.send((family, connected));
+        });
+}
+
+fn resolve(host: &str, family: u32) -> Option<IpAddr> {
+    let host = CString::new(host).ok()?;
+    let mut hints: addrinfo = unsafe { core::mem::zeroed() };
+    hints.ai_family = family as _;
+    hints.ai_socktype = SOCK_STREAM as _;
+
+    let mut found: *mut addrinfo = ptr::null_mut();
+    if unsafe { lwip_getaddrinfo(host.as_ptr(), ptr::null(), &hints, &mut found) } != 0 || found.is_null() {
+        return None;
+    }
+    let ip = unsafe { socket_ip((*found).ai_addr) };
+    unsafe { lwip_freeaddrinfo(found) };
+    ip
+}
+
+unsafe fn socket_ip(address: *const sockaddr) -> Option<IpAddr> {
+    if address.is_null() {
+        return None;
+    }
+    match u32::from((*address).sa_family) {
+        AF_INET6 => {
+            let address = &*(address as *const sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(address.sin6_addr.un.u8_addr)))
+        }
+        AF_INET => {
+            let address = &*(address as *const sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr))))
+        }
+        _ => None,
+    }
+}
+
+// "https://host[:port]/path", IP literals need no lookup and give None
+pub fn host_and_port(url: &str) -> Option<(&str, u16)> {
+    let (default_port, rest) = match url.split_once("://") {
+        Some(("http", rest)) => (80, rest),
+        Some((_, rest)) => (443, rest),
+        None => (443, url),
+    };
+    let authority = rest.split(['/', '?']).next()?;
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    if authority.starts_with('[') {
+        return None;
+    }
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, default_port),
+    };
+    match host.parse::<Ipv4Addr>() {
+        Ok(_) => None,
+        Err(_) if host.is_empty() => None,
+        Err(_) => Some((host, port)),
+    }
+}
+
+// lwIP runs every netconn lookup through this (CONFIG_LWIP_HOOK_NETCONN_EXT_RESOLVE_CUSTOM in
+// sdkconfig.defaults). Hosts resolved over DoH get their cached answer. Other lookups that leave
+// the family open, like the HTTP client's, are sent to IPv6 first when that is preferred.
+// Returning 0 leaves the lookup to lwIP.
+#[no_mangle]
+unsafe extern "C" fn lwip_hook_netconn_external_resolve(
+    name: *const c_char,
+    addr: *mut ip_addr_t,
+    addrtype: u8,
+    err: *mut err_t,
+) -> c_int {
+    if name.is_null() {
+        return 0;
+    }
+    let Ok(host) = CStr::from_ptr(name).to_str() else {
+        return 0;
+    };
+    let preferred = match IP_FAMILY {
+        "ipv6" => Some(Family::V6),
+        "ipv4" => Some(Family::V4),
+        _ => cached(host),
+    };
+
+    if let Some(addresses) = doh::lookup(host) {
+        let families: &[Family] = match u32::from(addrtype) {
+            LWIP_DNS_ADDRTYPE_IPV4 => &[Family::V4],
+            LWIP_DNS_ADDRTYPE_IPV6 => &[Family::V6],
+            LWIP_DNS_ADDRTYPE_IPV6_IPV4 => &[Family::V6, Family::V4],
+            _ if preferred == Some(Family::V6) => &[Family::V6, Family::V4],
+            _ => &[Family::V4, Family::V6],
+        };
+        let found = families
+            .iter()
+            .find_map(|family| addresses.iter().find(|ip| Family::of(ip) == *family));
+        // No plain DNS fallback for a host DoH answered, its missing family stays missing
+        *err = match found {
+            Some(ip) => {
+                write_ip(addr, ip);
+                err_enum_t_ERR_OK as err_t
+            }
+            None => err_enum_t_ERR_VAL as err_t,
+        };
+        return 1;
+    }
+
+    if u32::from(addrtype) != LWIP_DNS_ADDRTYPE_IPV4_IPV6 || preferred != Some(Family::V6) {
+        return 0;
+    }
+    // Falls back to IPv4 when the host has no IPv6 address
+    *err = netconn_gethostbyname_addrtype(name, addr, LWIP_DNS_ADDRTYPE_IPV6_IPV4 as u8);
+    1
+}
+
+// lwIP keeps addresses in network byte order
+unsafe fn write_ip(addr: *mut ip_addr_t, ip: &IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            (*addr).u_addr.ip4.addr = u32::from_ne_bytes(ip.octets());
+            (*addr).type_ = lwip_ip_addr_type_IPADDR_TYPE_V4 as u8;
+        }
+        IpAddr::V6(ip) => {
+            let octets = ip.octets();
+            for (word, bytes) in (*addr).u_addr.ip6.addr.iter_mut().zip(octets.chunks_exact(4)) {
+                *word = u32::from_ne_bytes(bytes.try_into().unwrap());
+            }
+            (*addr).u_addr.ip6.zone = 0;
+            (*addr).type_ = lwip_ip_addr_type_IPADDR_TYPE_V6 as u8;
+        }
+    }
+}
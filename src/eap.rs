@@ -0,0 +1,125 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::{
+    esp_err_t, esp_wifi_sta_wpa2_ent_clear_ca_cert, esp_wifi_sta_wpa2_ent_clear_cert_key,
+    esp_wifi_sta_wpa2_ent_clear_identity, esp_wifi_sta_wpa2_ent_clear_password, esp_wifi_sta_wpa2_ent_clear_username,
+    esp_wifi_sta_wpa2_ent_disable, esp_wifi_sta_wpa2_ent_enable, esp_wifi_sta_wpa2_ent_set_ca_cert,
+    esp_wifi_sta_wpa2_ent_set_cert_key, esp_wifi_sta_wpa2_ent_set_identity, esp_wifi_sta_wpa2_ent_set_password,
+    esp_wifi_sta_wpa2_ent_set_username, ESP_OK,
+};
+use log::warn;
+use zeroize::Zeroizing;
+
+use crate::config::{EapMethod, EnterpriseConfig, WifiCerts};
+
+// The supplicant keeps pointers to the certificates and key instead of copying them, so the
+// buffers of the network being joined live here until the next network is configured
+static CREDENTIALS: Mutex<Vec<Zeroizing<Vec<u8>>>> = Mutex::new(Vec::new());
+
+fn check(ret: esp_err_t, what: &str) -> Result<(), String> {
+    match ret {
+        ESP_OK => Ok(()),
+        ret => Err(format!("{}: {}", what, ret)),
+    }
+}
+
+// Sets up the supplicant for the next join, or turns 802.1X off for a personal network.
+// Uses the esp_wifi_sta_wpa2_ent API, esp-idf-sys doesn't generate bindings for its
+// esp_eap_client replacement.
+pub fn configure(enterprise: Option<&EnterpriseConfig>, password: &str, certs: &mut WifiCerts) -> Result<(), String> {
+    let mut buffers = CREDENTIALS.lock().unwrap();
+
+    // Let go of the previous network's buffers before they are freed
+    unsafe {
+        esp_wifi_sta_wpa2_ent_clear_identity();
+        esp_wifi_sta_wpa2_ent_clear_username();
+        esp_wifi_sta_wpa2_ent_clear_password();
+        esp_wifi_sta_wpa2_ent_clear_ca_cert();
+        esp_wifi_sta_wpa2_ent_clear_cert_key();
+    }
+    buffers.clear();
+
+    let Some(enterprise) = enterprise else {
+        // Fails harmlessly when 802.1X was never enabled
+        unsafe { esp_wifi_sta_wpa2_ent_disable() };
+        return Ok(());
+    };
+
+    let mut load = |name: &Option<String>| -> Result<Option<Zeroizing<Vec<u8>>>, String> {
+        name.as_deref().map(|name| certs.certificate(name)).transpose()
+    };
+    let ca_cert = load(&enterprise.ca_cert)?;
+    let client = match enterprise.method {
+        EapMethod::Tls => match (load(&enterprise.client_cert)?, load(&enterprise.client_key)?) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => return Err("EAP-TLS needs a client certificate and key".to_string()),
+        },
+        EapMethod::Peap => None,
+    };
+    let password = Zeroizing::new(password.as_bytes().to_vec());
+
+    let result = unsafe {
+        apply(
+            enterprise,
+            ca_cert.as_deref().map(Vec::as_slice),
+            client.as_ref().map(|(cert, key)| (cert.as_slice(), key.as_slice())),
+            &password,
+        )
+    };
+
+    // Kept even when a step failed, the supplicant may already point into them
+    buffers.extend(ca_cert);
+    if let Some((cert, key)) = client {
+        buffers.push(cert);
+        buffers.push(key);
+    }
+    buffers.push(password);
+    result
+}
+
+unsafe fn apply(
+    enterprise: &EnterpriseConfig,
+    ca_cert: Option<&[u8]>,
+    client: Option<(&[u8], &[u8])>,
+    password: &[u8],
+) -> Result<(), String> {
+    check(
+        esp_wifi_sta_wpa2_ent_set_identity(enterprise.identity.as_ptr(), enterprise.identity.len() as i32),
+        "EAP identity",
+    )?;
+
+    match ca_cert {
+        Some(ca_cert) => check(
+            esp_wifi_sta_wpa2_ent_set_ca_cert(ca_cert.as_ptr(), ca_cert.len() as i32),
+            "EAP CA certificate",
+        )?,
+        None => warn!("No CA certificate for the enterprise network, its server is not authenticated"),
+    }
+
+    match client {
+        // The password decrypts the private key, if it is encrypted
+        Some((cert, key)) => check(
+            esp_wifi_sta_wpa2_ent_set_cert_key(
+                cert.as_ptr(),
+                cert.len() as i32,
+                key.as_ptr(),
+                key.len() as i32,
+                password.as_ptr(),
+                password.len() as i32,
+            ),
+            "EAP client certificate",
+        )?,
+        None => {
+            check(
+                esp_wifi_sta_wpa2_ent_set_username(enterprise.username.as_ptr(), enterprise.username.len() as i32),
+                "EAP username",
+            )?;
+            check(
+                esp_wifi_sta_wpa2_ent_set_password(password.as_ptr(), password.len() as i32),
+                "EAP password",
+            )?;
+        }
+    }
+
+    check(esp_wifi_sta_wpa2_ent_enable(), "EAP enable")
+}
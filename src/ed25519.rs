@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::scalar::{clamp_integer, Scalar};
+use esp_idf_svc::sys::{
+    mbedtls_sha512_context, mbedtls_sha512_finish, mbedtls_sha512_free, mbedtls_sha512_init,
+    mbedtls_sha512_starts, mbedtls_sha512_update,
+};
+use log::info;
+use solana_keypair::{Keypair, Signer};
+use solana_transaction::Signature;
+use zeroize::Zeroizing;
+
+use crate::chip;
+
+// Which implementation produces device signatures. Both produce identical signatures
+// (ed25519 is deterministic), they only differ in speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SigningBackend {
+    // ed25519-dalek with its software SHA-512
+    #[default]
+    Software,
+    // SHA-512 through mbedtls, which uses the SHA peripheral on chips that accelerate SHA-512
+    // (ESP32, ESP32-S2/S3), curve arithmetic still runs on the CPU
+    Accelerated,
+}
+
+impl SigningBackend {
+    // The faster backend on the chip the firmware is built for
+    pub const fn for_chip() -> Self {
+        match chip::SHA512_ACCELERATED {
+            true => SigningBackend::Accelerated,
+            false => SigningBackend::Software,
+        }
+    }
+
+    pub fn sign(self, keypair: &Keypair, message: &[u8]) -> Result<Signature, String> {
+        match self {
+            SigningBackend::Software => Ok(keypair.sign_message(message)),
+            SigningBackend::Accelerated => sign_accelerated(keypair, message),
+        }
+    }
+}
+
+// RFC 8032 signing with every SHA-512 invocation routed through mbedtls
+fn sign_accelerated(keypair: &Keypair, message: &[u8]) -> Result<Signature, String> {
+    let expanded = Zeroizing::new(sha512(&[keypair.secret_bytes()])?);
+    let mut secret = Zeroizing::new([0u8; 32]);
+    let mut prefix = Zeroizing::new([0u8; 32]);
+    secret.copy_from_slice(&expanded[..32]);
+    prefix.copy_from_slice(&expanded[32..]);
+
+    let a = Zeroizing::new(Scalar::from_bytes_mod_order(clamp_integer(*secret)));
+    let public = keypair.pubkey().to_bytes();
+
+    let r = Zeroizing::new(Scalar::from_bytes_mod_order_wide(&sha512(&[prefix.as_slice(), message])?));
+    let big_r = EdwardsPoint::mul_base(&r).compress();
+
+    let k = Scalar::from_bytes_mod_order_wide(&sha512(&[big_r.as_bytes(), &public, message])?);
+    let s = k * *a + *r;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(big_r.as_bytes());
+    signature[32..].copy_from_slice(s.as_bytes());
+
+    Ok(Signature::from(signature))
+}
+
+fn sha512(parts: &[&[u8]]) -> Result<[u8; 64], String> {
+    let mut ctx: mbedtls_sha512_context = unsafe { core::mem::zeroed() };
+    let mut output = [0u8; 64];
+
+    let result = unsafe {
+        mbedtls_sha512_init(&mut ctx);
+        let mut ret = mbedtls_sha512_starts(&mut ctx, 0);
+        for part in parts {
+            if ret != 0 {
+                break;
+            }
+            ret = mbedtls_sha512_update(&mut ctx, part.as_ptr(), part.len());
+        }
+        if ret == 0 {
+            ret = mbedtls_sha512_finish(&mut ctx, output.as_mut_ptr());
+        }
+        // Also wipes the hash state, which holds secret-derived data
+        mbedtls_sha512_free(&mut ctx);
+        ret
+    };
+
+    if result != 0 {
+        return Err(format!("SHA-512: mbedtls error {}", result));
+    }
+    Ok(output)
+}
+
+// Times both backends on the same key and checks they agree, logs the per-signature cost
+pub fn benchmark(keypair: &Keypair, iterations: u32) -> Result<(), String> {
+    let message = [0x5au8; 256];
+    let expected = SigningBackend::Software.sign(keypair, &message)?;
+
+    let mut timings: Vec<(SigningBackend, Duration)> = Vec::new();
+    for backend in [SigningBackend::Software, SigningBackend::Accelerated] {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            if backend.sign(keypair, &message)? != expected {
+                return Err(format!("{:?} backend produced a different signature", backend));
+            }
+        }
+        timings.push((backend, start.elapsed() / iterations.max(1)));
+    }
+
+    for (backend, per_signature) in &timings {
+        info!("{:?} signing: {} us per signature", backend, per_signature.as_micros());
+    }
+    if let [(_, software), (_, accelerated)] = timings.as_slice() {
+        info!(
+            "Accelerated backend speedup: {:.2}x",
+            software.as_secs_f32() / accelerated.as_secs_f32().max(f32::EPSILON)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use std::num::NonZeroU32;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::hal::task::notification::Notification;
+use log::{info, warn};
+
+use crate::display;
+
+// Amount entry on a rotary encoder with a push switch, the EC11 modules sold for this, on
+// devices without a keypad. Turning moves the amount by the current step, a press switches to
+// the next step size and holding the switch confirms. The A and B interrupts wake a thread that
+// follows the quadrature sequence. All three inputs are active low against the pull-ups.
+
+const DEBOUNCE: Duration = Duration::from_millis(20);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+const HOLD: Duration = Duration::from_millis(800);
+const MAX_HOLD: Duration = Duration::from_secs(10);
+const LISTENER_STACK_SIZE: usize = 4 * 1024;
+// A and B both high, where EC11 encoders rest between detents
+const DETENT: u8 = 0b11;
+// Count change for each (previous << 2 | current) A/B state, 0 for no change or a skipped state
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialEvent {
+    // Detents, positive clockwise. Swap A and B if it counts the wrong way.
+    Turned(i32),
+    Pressed,
+    Held,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DialConfig {
+    // Of the unit dialed in, 9 for SOL or the token's
+    pub decimals: u8,
+    // In base units per detent, a press moves on to the next and after the last back to the first
+    pub steps: &'static [u64],
+    pub max: u64,
+    pub unit: &'static str,
+}
+
+static EVENTS: Mutex<Option<Receiver<DialEvent>>> = Mutex::new(None);
+
+pub fn listen(a: AnyIOPin, b: AnyIOPin, switch: AnyIOPin) -> Result<(), String> {
+    let mut pins = Vec::new();
+    for (pin, interrupt) in [(a, InterruptType::AnyEdge), (b, InterruptType::AnyEdge), (switch, InterruptType::NegEdge)] {
+        let mut driver = PinDriver::input(pin).map_err(|e| format!("Encoder pin init: {:?}", e))?;
+        driver
+            .set_pull(Pull::Up)
+            .map_err(|e| format!("Encoder pull-up: {:?}", e))?;
+        driver
+            .set_interrupt_type(interrupt)
+            .map_err(|e| format!("Encoder interrupt type: {:?}", e))?;
+        pins.push(driver);
+    }
+
+    let (events, received) = channel();
+    std::thread::Builder::new()
+        .name("encoder".to_string())
+        .stack_size(LISTENER_STACK_SIZE)
+        .spawn(move || {
+            // Wakes this thread, so it has to be created on it
+            let notification = Notification::new();
+            for pin in pins.iter_mut() {
+                let notifier = notification.notifier();
+                let subscribed = unsafe {
+                    pin.subscribe(move || {
+                        notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+                    })
+                };
+                if let Err(e) = subscribed {
+                    warn!("Encoder interrupt: {:?}", e);
+                    return;
+                }
+            }
+            let [a, b, switch] = &mut pins[..] else {
+                return;
+            };
+
+            let mut state = quadrature(a, b);
+            let mut count = 0;
+            loop {
+                // The driver disables each interrupt when it fires
+                for pin in [&mut *a, &mut *b, &mut *switch] {
+                    if let Err(e) = pin.enable_interrupt() {
+                        warn!("Encoder interrupt enable: {:?}", e);
+                        return;
+                    }
+                }
+                notification.wait(BLOCK);
+
+                let current = quadrature(a, b);
+                count += TRANSITIONS[(state << 2 | current) as usize];
+                state = current;
+                // A full cycle is four transitions, two are taken as one in case a state was missed
+                if state == DETENT {
+                    if count.abs() >= 2 {
+                        let _ = events.send(DialEvent::Turned(count.signum() as i32));
+                    }
+                    count = 0;
+                }
+
+                if switch.is_low() {
+                    if let Some(event) = classify(switch) {
+                        let _ = events.send(event);
+                    }
+                }
+            }
+        })
+        .map_err(|e| format!("Encoder listener: {:?}", e))?;
+
+    *EVENTS.lock().unwrap() = Some(received);
+    info!("Rotary encoder armed");
+    Ok(())
+}
+
+// The next event, None when there was none within the timeout
+pub fn next_event(timeout: Duration) -> Option<DialEvent> {
+    let events = EVENTS.lock().unwrap();
+    match events.as_ref()?.recv_timeout(timeout) {
+        Ok(event) => Some(event),
+        Err(RecvTimeoutError::Timeout) => None,
+        Err(RecvTimeoutError::Disconnected) => {
+            drop(events);
+            std::thread::sleep(timeout);
+            None
+        }
+    }
+}
+
+fn quadrature(a: &PinDriver<'static, AnyIOPin, Input>, b: &PinDriver<'static, AnyIOPin, Input>) -> u8 {
+    (a.is_high() as u8) << 1 | b.is_high() as u8
+}
+
+// A press or a hold, None for a bounce
+fn classify(switch: &PinDriver<'static, AnyIOPin, Input>) -> Option<DialEvent> {
+    wait_for_level(switch, true, Instant::now() + DEBOUNCE * 2)?;
+    let pressed_at = Instant::now();
+    if wait_for_level(switch, false, pressed_at + HOLD).is_some() {
+        return Some(DialEvent::Pressed);
+    }
+    let _ = wait_for_level(switch, false, pressed_at + MAX_HOLD);
+    Some(DialEvent::Held)
+}
+
+// Waits until the switch has been stable at the level for DEBOUNCE
+fn wait_for_level(switch: &PinDriver<'static, AnyIOPin, Input>, pressed: bool, deadline: Instant) -> Option<()> {
+    let mut stable_since: Option<Instant> = None;
+    while Instant::now() < deadline {
+        if switch.is_low() == pressed {
+            if stable_since.get_or_insert_with(Instant::now).elapsed() >= DEBOUNCE {
+                return Some(());
+            }
+        } else {
+            stable_since = None;
+        }
+        std::thread::sleep(SAMPLE_INTERVAL);
+    }
+    None
+}
+
+// The amount being dialed in, kept between entries so a repeated amount is only confirmed
+pub struct AmountDial {
+    config: DialConfig,
+    amount: u64,
+    step: usize,
+}
+
+impl AmountDial {
+    pub fn new(config: DialConfig) -> Self {
+        Self {
+            config,
+            amount: 0,
+            step: 0,
+        }
+    }
+
+    // Waits up to the timeout for input, Some with the amount in base units once one above
+    // zero is confirmed
+    pub fn poll(&mut self, timeout: Duration) -> Option<u64> {
+        let step = self.config.steps.get(self.step).copied().unwrap_or(1);
+        match next_event(timeout)? {
+            DialEvent::Turned(detents) if detents > 0 => {
+                self.amount = self.amount.saturating_add(step.saturating_mul(detents as u64)).min(self.config.max)
+            }
+            DialEvent::Turned(detents) => self.amount = self.amount.saturating_sub(step.saturating_mul(detents.unsigned_abs() as u64)),
+            DialEvent::Pressed => self.step = (self.step + 1) % self.config.steps.len().max(1),
+            DialEvent::Held if self.amount > 0 => {
+                info!("Amount confirmed: {} {}", self.decimal(self.amount), self.config.unit);
+                display::clear_overlay();
+                return Some(self.amount);
+            }
+            DialEvent::Held => {}
+        }
+        self.show();
+        None
+    }
+
+    pub fn show(&self) {
+        let amount = format!("{} {}", self.fixed(self.amount), self.config.unit);
+        let step = self.config.steps.get(self.step).copied().unwrap_or(1);
+        let step = format!("Step {}", self.decimal(step));
+        info!("Dialed {}, {}", amount, step.to_lowercase());
+        display::show_entry(&["Amount", "", &amount, "", &step, "", "", "Hold to confirm"]);
+    }
+
+    // The amount as a decimal without trailing zeros, as Solana Pay URLs write it
+    pub fn decimal(&self, units: u64) -> String {
+        let fixed = format_units(units, self.config.decimals, self.config.decimals);
+        match fixed.contains('.') {
+            true => fixed.trim_end_matches('0').trim_end_matches('.').to_string(),
+            false => fixed,
+        }
+    }
+
+    // Down to the smallest step, so the digits don't jump around while turning
+    fn fixed(&self, units: u64) -> String {
+        let smallest = self.config.steps.iter().copied().min().unwrap_or(1).max(1);
+        let mut shown = self.config.decimals;
+        let mut scale = smallest;
+        while shown > 0 && scale % 10 == 0 {
+            shown -= 1;
+            scale /= 10;
+        }
+        format_units(units, self.config.decimals, shown)
+    }
+}
+
+// Base units as a decimal with `shown` fraction digits, cut rather than rounded
+fn format_units(units: u64, decimals: u8, shown: u8) -> String {
+    let scale = 10u64.pow(decimals as u32);
+    let fraction = format!("{:0width$}", units % scale, width = decimals as usize);
+    match shown {
+        0 => format!("{}", units / scale),
+        _ => format!("{}.{}", units / scale, &fraction[..shown as usize]),
+    }
+}
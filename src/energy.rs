@@ -0,0 +1,442 @@
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::{TickType, BLOCK};
+use esp_idf_svc::hal::gpio::{AnyIOPin, Gpio0, Gpio1, IOPin, Input, InterruptType, Output, PinDriver, Pull};
+use esp_idf_svc::hal::task::notification::Notification;
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Transaction;
+
+use crate::signer::TxSigner;
+use crate::solrpc::{get_account_info, get_latest_blockhash, get_token_account_balance, send_transaction};
+use crate::token::{associated_token_address, create_associated_token_account_idempotent, transfer_checked};
+
+// Prepaid metered power: the payer approves the device key as delegate of their token account
+// for as much as they prepay, and the device transfers the price of what was consumed from it
+// as it goes. The supply relay stays on while the remaining allowance covers the unbilled
+// consumption and a reserve, and is cut off once it doesn't. Consumption comes from the S0
+// pulse output of a DIN-rail meter or from a PZEM-004T module over Modbus.
+
+const ENERGY_NAMESPACE: &str = "energy";
+// Meter reading in mWh up to which consumption has been charged
+const BILLED_KEY: &str = "billed";
+// Pulse meters only: the reading at the last settlement, the next boot counts pulses from it
+const READING_KEY: &str = "reading";
+const MWH_PER_KWH: u64 = 1_000_000;
+
+// S0 pulses last 30 ms or more, anything shorter is noise on the line
+const PULSE_MIN: Duration = Duration::from_millis(20);
+const PULSE_MAX: Duration = Duration::from_secs(1);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(5);
+
+const PZEM_BAUD_RATE: u32 = 9_600;
+const PZEM_READ_INPUT: u8 = 0x04;
+// Voltage, current, power, energy, frequency, power factor and alarm
+const PZEM_REGISTERS: u16 = 10;
+const PZEM_POLL: Duration = Duration::from_secs(2);
+const PZEM_TIMEOUT_MS: u64 = 500;
+
+const LISTENER_STACK_SIZE: usize = 4 * 1024;
+
+// SPL token account layout, the same for Token-2022 in its first 165 bytes
+const ACCOUNT_MINT: usize = 0;
+const ACCOUNT_AMOUNT: usize = 64;
+const ACCOUNT_DELEGATE: usize = 72;
+const ACCOUNT_STATE: usize = 108;
+const ACCOUNT_DELEGATED_AMOUNT: usize = 121;
+const ACCOUNT_LEN: usize = 165;
+const STATE_INITIALIZED: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum EnergySource {
+    // Open-collector S0 output between GPIO1 and GND, at the meter's impulses per kWh
+    Pulses { per_kwh: u32 },
+    // PZEM-004T v3, its TX on GPIO1 and RX on GPIO0. 0xF8 reaches any single module.
+    Pzem { address: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EnergyConfig {
+    pub source: EnergySource,
+    // Whose token account is charged, after approving the device key as its delegate. Metering
+    // doesn't start until it is set.
+    pub payer: Option<Pubkey>,
+    pub mint: Pubkey,
+    pub token_program: Pubkey,
+    // Paid to this wallet's associated token account, None for the device's own
+    pub recipient: Option<Pubkey>,
+    // In the token's base units, 250_000 is 0.25 of a 6-decimal token like USDC
+    pub price_per_kwh: u64,
+    // Consumption charged in one transfer, or after `settle_interval` whatever is owed
+    pub settle_every_wh: u64,
+    pub settle_interval: Duration,
+    // The supply is cut off once the allowance left would pay for fewer than this many Wh
+    pub reserve_wh: u64,
+    // How often the allowance is read and the relay set
+    pub check_interval: Duration,
+    // Relay modules differ, many of the opto-isolated ones switch on a low input
+    pub active_high: bool,
+}
+
+static PULSES: AtomicU64 = AtomicU64::new(0);
+// The PZEM's own energy counter in Wh, it keeps counting while the device is off
+static PZEM_WH: Mutex<Option<u32>> = Mutex::new(None);
+
+struct Meter {
+    config: EnergyConfig,
+    payer: Pubkey,
+    nvs: EspNvs<NvsDefault>,
+    relay: PinDriver<'static, AnyIOPin, Output>,
+    powered: bool,
+    billed_mwh: u64,
+    // Pulse meters only
+    reading_at_boot_mwh: u64,
+    decimals: Option<u8>,
+    checked: Option<Instant>,
+    settled: Instant,
+}
+
+static METER: Mutex<Option<Meter>> = Mutex::new(None);
+
+// Takes the supply relay on GPIO10 and cuts it off until an allowance has been seen, early at
+// boot like the pay-to-unlock relay. The meter is read on a thread of its own.
+pub fn start(uart: UART1, tx: Gpio0, rx: Gpio1, relay: AnyIOPin, config: EnergyConfig, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+    if config.price_per_kwh == 0 {
+        return Err("Price per kWh must not be zero".to_string());
+    }
+    let payer = config.payer.ok_or("No payer set, the customer's wallet goes in `payer`")?;
+    let relay = PinDriver::output(relay).map_err(|e| format!("Supply relay init: {:?}", e))?;
+    let nvs = EspNvs::new(nvs, ENERGY_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+    let billed_mwh = nvs
+        .get_u64(BILLED_KEY)
+        .map_err(|e| format!("Energy billed read: {:?}", e))?
+        .unwrap_or(0);
+    let reading_at_boot_mwh = nvs
+        .get_u64(READING_KEY)
+        .map_err(|e| format!("Energy reading read: {:?}", e))?
+        .unwrap_or(0);
+    let mut meter = Meter {
+        config,
+        payer,
+        nvs,
+        relay,
+        powered: true,
+        billed_mwh,
+        reading_at_boot_mwh,
+        decimals: None,
+        checked: None,
+        settled: Instant::now(),
+    };
+    meter.set_power(false)?;
+    *METER.lock().unwrap() = Some(meter);
+
+    match config.source {
+        EnergySource::Pulses { per_kwh } => {
+            if per_kwh == 0 {
+                return Err("Impulses per kWh must not be zero".to_string());
+            }
+            count_pulses(rx.downgrade())?;
+        }
+        EnergySource::Pzem { address } => poll_pzem(uart, tx, rx, address)?,
+    }
+    info!(
+        "Energy meter up, {} per kWh, {} Wh charged so far",
+        config.price_per_kwh,
+        billed_mwh / 1000
+    );
+    Ok(())
+}
+
+// Charges for the consumption and switches the supply, called from the main loop which holds
+// the signer
+pub fn settle_due(signer: &impl TxSigner) {
+    let mut meter = METER.lock().unwrap();
+    let Some(meter) = meter.as_mut() else {
+        return;
+    };
+    if meter.checked.is_some_and(|checked| checked.elapsed() < meter.config.check_interval) {
+        return;
+    }
+    meter.checked = Some(Instant::now());
+    if let Err(e) = meter.settle(signer) {
+        warn!("Energy settlement: {}", e);
+    }
+}
+
+impl Meter {
+    fn settle(&mut self, signer: &impl TxSigner) -> Result<(), String> {
+        let reading = self.reading().ok_or("No meter reading yet")?;
+        if reading < self.billed_mwh {
+            warn!("Meter reading went back to {} Wh, the meter was reset or replaced", reading / 1000);
+            self.billed_mwh = reading;
+            self.save(reading)?;
+        }
+
+        let device = signer.pubkey();
+        let mut allowance = self.allowance(&device)?;
+        let price = self.config.price_per_kwh;
+        let owed_mwh = reading - self.billed_mwh;
+        let due = owed_mwh >= self.config.settle_every_wh * 1000 || self.settled.elapsed() >= self.config.settle_interval;
+        let amount = cost(owed_mwh, price).min(allowance);
+        if due && amount > 0 {
+            let decimals = match self.decimals {
+                Some(decimals) => decimals,
+                None => self.payer_balance_decimals()?,
+            };
+            let signature = self.charge(signer, amount, decimals)?;
+            // Rounded down, the rest stays owed
+            let covered_mwh = ((amount as u128 * MWH_PER_KWH as u128) / price as u128) as u64;
+            self.billed_mwh += covered_mwh;
+            self.settled = Instant::now();
+            allowance -= amount;
+            // Counted as soon as it is sent, a transfer that doesn't land is the payer's gain
+            self.save(reading)?;
+            info!("Charged {} for {} Wh: {}", amount, covered_mwh / 1000, signature);
+        }
+
+        let unbilled = cost(reading - self.billed_mwh, price);
+        let reserve = cost(self.config.reserve_wh * 1000, price);
+        let supply = allowance > unbilled && allowance - unbilled >= reserve;
+        if supply != self.powered {
+            match supply {
+                true => info!("Supply on, {} of prepaid allowance left", allowance),
+                false => warn!("Prepaid allowance exhausted ({} left), supply cut off", allowance),
+            }
+        }
+        self.set_power(supply)
+    }
+
+    // The meter's total in mWh
+    fn reading(&self) -> Option<u64> {
+        match self.config.source {
+            EnergySource::Pulses { per_kwh } => {
+                Some(self.reading_at_boot_mwh + PULSES.load(Ordering::Relaxed) * MWH_PER_KWH / per_kwh as u64)
+            }
+            EnergySource::Pzem { .. } => PZEM_WH.lock().unwrap().map(|wh| wh as u64 * 1000),
+        }
+    }
+
+    // What the device may still take from the payer's account, the smaller of the approved
+    // amount and the balance
+    fn allowance(&self, device: &Pubkey) -> Result<u64, String> {
+        let account = associated_token_address(&self.payer, &self.config.mint, &self.config.token_program);
+        let Some(account) = get_account_info(&account).map_err(|e| e.to_string())? else {
+            return Ok(0);
+        };
+        let data = &account.data;
+        if account.owner != self.config.token_program || data.len() < ACCOUNT_LEN {
+            return Err("Payer's token account is not a token account".to_string());
+        }
+        if data[ACCOUNT_MINT..ACCOUNT_MINT + 32] != self.config.mint.to_bytes() {
+            return Err("Payer's token account holds another mint".to_string());
+        }
+        let delegated = u32::from_le_bytes(data[ACCOUNT_DELEGATE..ACCOUNT_DELEGATE + 4].try_into().unwrap()) == 1
+            && data[ACCOUNT_DELEGATE + 4..ACCOUNT_DELEGATE + 36] == device.to_bytes();
+        // Frozen accounts can't be charged
+        if !delegated || data[ACCOUNT_STATE] != STATE_INITIALIZED {
+            return Ok(0);
+        }
+        let amount = u64::from_le_bytes(data[ACCOUNT_AMOUNT..ACCOUNT_AMOUNT + 8].try_into().unwrap());
+        let approved = u64::from_le_bytes(
+            data[ACCOUNT_DELEGATED_AMOUNT..ACCOUNT_DELEGATED_AMOUNT + 8]
+                .try_into()
+                .unwrap(),
+        );
+        Ok(amount.min(approved))
+    }
+
+    fn payer_balance_decimals(&mut self) -> Result<u8, String> {
+        let account = associated_token_address(&self.payer, &self.config.mint, &self.config.token_program);
+        let (_, decimals) = get_token_account_balance(&account)
+            .map_err(|e| e.to_string())?
+            .ok_or("Payer's token account not found")?;
+        self.decimals = Some(decimals);
+        Ok(decimals)
+    }
+
+    fn charge(&self, signer: &impl TxSigner, amount: u64, decimals: u8) -> Result<String, String> {
+        let device = signer.pubkey();
+        let config = &self.config;
+        let recipient = config.recipient.unwrap_or(device);
+        let instructions = [
+            create_associated_token_account_idempotent(&device, &recipient, &config.mint, &config.token_program),
+            transfer_checked(
+                &config.token_program,
+                &associated_token_address(&self.payer, &config.mint, &config.token_program),
+                &config.mint,
+                &associated_token_address(&recipient, &config.mint, &config.token_program),
+                &device,
+                amount,
+                decimals,
+            ),
+        ];
+        let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+        let mut transaction = Transaction::new_with_payer(&instructions, Some(&device));
+        signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+        send_transaction(&transaction).map_err(|e| e.to_string())
+    }
+
+    fn save(&mut self, reading: u64) -> Result<(), String> {
+        self.nvs
+            .set_u64(BILLED_KEY, self.billed_mwh)
+            .map_err(|e| format!("Energy billed write: {:?}", e))?;
+        if let EnergySource::Pulses { .. } = self.config.source {
+            self.nvs
+                .set_u64(READING_KEY, reading)
+                .map_err(|e| format!("Energy reading write: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    fn set_power(&mut self, on: bool) -> Result<(), String> {
+        match on == self.config.active_high {
+            true => self.relay.set_high(),
+            false => self.relay.set_low(),
+        }
+        .map_err(|e| format!("Supply relay: {:?}", e))?;
+        self.powered = on;
+        Ok(())
+    }
+}
+
+// Token base units for the consumption, rounded down
+fn cost(mwh: u64, price_per_kwh: u64) -> u64 {
+    ((mwh as u128 * price_per_kwh as u128) / MWH_PER_KWH as u128) as u64
+}
+
+// Counts S0 pulses on a thread of its own, woken by the pin's interrupt
+fn count_pulses(pin: AnyIOPin) -> Result<(), String> {
+    let mut input = PinDriver::input(pin).map_err(|e| format!("Pulse input init: {:?}", e))?;
+    input
+        .set_pull(Pull::Up)
+        .map_err(|e| format!("Pulse input pull-up: {:?}", e))?;
+    input
+        .set_interrupt_type(InterruptType::NegEdge)
+        .map_err(|e| format!("Pulse input interrupt type: {:?}", e))?;
+
+    std::thread::Builder::new()
+        .name("energy-pulses".to_string())
+        .stack_size(LISTENER_STACK_SIZE)
+        .spawn(move || {
+            // Wakes this thread, so it has to be created on it
+            let notification = Notification::new();
+            let notifier = notification.notifier();
+            let subscribed = unsafe {
+                input.subscribe(move || {
+                    notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+                })
+            };
+            if let Err(e) = subscribed {
+                warn!("Pulse input interrupt: {:?}", e);
+                return;
+            }
+            loop {
+                // The driver disables the interrupt each time it fires
+                if let Err(e) = input.enable_interrupt() {
+                    warn!("Pulse input interrupt enable: {:?}", e);
+                    return;
+                }
+                notification.wait(BLOCK);
+                if is_pulse(&input) {
+                    PULSES.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })
+        .map_err(|e| format!("Pulse counter: {:?}", e))?;
+    Ok(())
+}
+
+// Low for at least PULSE_MIN, then waits for the end of the pulse
+fn is_pulse(input: &PinDriver<'static, AnyIOPin, Input>) -> bool {
+    let started = Instant::now();
+    while input.is_low() {
+        if started.elapsed() >= PULSE_MAX {
+            break;
+        }
+        std::thread::sleep(SAMPLE_INTERVAL);
+    }
+    started.elapsed() >= PULSE_MIN
+}
+
+fn poll_pzem(uart: UART1, tx: Gpio0, rx: Gpio1, address: u8) -> Result<(), String> {
+    let uart = UartDriver::new(
+        uart,
+        tx,
+        rx,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::default().baudrate(Hertz(PZEM_BAUD_RATE)),
+    )
+    .map_err(|e| format!("PZEM UART: {:?}", e))?;
+
+    std::thread::Builder::new()
+        .name("energy-pzem".to_string())
+        .stack_size(LISTENER_STACK_SIZE)
+        .spawn(move || {
+            let mut failing = false;
+            loop {
+                match read_pzem_wh(&uart, address) {
+                    Ok(wh) => {
+                        *PZEM_WH.lock().unwrap() = Some(wh);
+                        failing = false;
+                    }
+                    // Logged once per outage, the module polls every two seconds
+                    Err(e) if !failing => {
+                        warn!("PZEM read: {}", e);
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+                std::thread::sleep(PZEM_POLL);
+            }
+        })
+        .map_err(|e| format!("PZEM poller: {:?}", e))?;
+    Ok(())
+}
+
+// Modbus RTU "read input registers" from 0, the energy counter is registers 5 and 6, low word first
+fn read_pzem_wh(uart: &UartDriver<'static>, address: u8) -> Result<u32, String> {
+    let mut request = vec![address, PZEM_READ_INPUT, 0, 0];
+    request.extend_from_slice(&PZEM_REGISTERS.to_be_bytes());
+    let crc = modbus_crc(&request);
+    request.extend_from_slice(&crc.to_le_bytes());
+    let _ = uart.clear_rx();
+    uart.write(&request).map_err(|e| format!("PZEM write: {:?}", e))?;
+
+    let mut response = [0u8; 5 + 2 * PZEM_REGISTERS as usize];
+    let mut read = 0;
+    let deadline = Instant::now() + Duration::from_millis(PZEM_TIMEOUT_MS);
+    while read < response.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err("PZEM not answering".to_string());
+        }
+        read += uart
+            .read(&mut response[read..], TickType::new_millis(remaining.as_millis() as u64).into())
+            .map_err(|e| format!("PZEM read: {:?}", e))?;
+    }
+    let (frame, crc) = response.split_at(response.len() - 2);
+    if modbus_crc(frame) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err("PZEM reply checksum mismatch".to_string());
+    }
+    if frame[1] != PZEM_READ_INPUT || frame[2] as u16 != 2 * PZEM_REGISTERS {
+        return Err(format!("Unexpected PZEM reply (function {:#04x})", frame[1]));
+    }
+    let register = |index: usize| u16::from_be_bytes([frame[3 + 2 * index], frame[4 + 2 * index]]) as u32;
+    Ok(register(5) | register(6) << 16)
+}
+
+fn modbus_crc(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ u16::from(*byte), |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xA001,
+            _ => crc >> 1,
+        })
+    })
+}
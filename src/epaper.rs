@@ -0,0 +1,218 @@
+use std::ptr::{addr_of, addr_of_mut};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, Output, PinDriver};
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::hal::spi::config::{Config, DriverConfig};
+use esp_idf_svc::hal::spi::{Dma, SpiDeviceDriver, SpiDriver, SPI2};
+use log::info;
+
+use crate::display::{Backend, Frame, HEIGHT, WIDTH};
+
+// Waveshare 2.9" e-paper module (V2, SSD1680 controller, 128x296) over SPI, in landscape with
+// the status layout drawn at twice its size. An update that only changes part of the screen
+// uses the controller's partial refresh, the panel is put to deep sleep after every update and
+// keeps showing the image without power.
+
+const PANEL_WIDTH: usize = 128;
+const PANEL_HEIGHT: usize = 296;
+const LINE_BYTES: usize = PANEL_WIDTH / 8;
+const BUFFER_LEN: usize = LINE_BYTES * PANEL_HEIGHT;
+const SCALE: usize = 2;
+// The scaled layout centred on the long side
+const MARGIN: usize = (PANEL_HEIGHT - WIDTH * SCALE) / 2;
+const _: () = assert!(HEIGHT * SCALE == PANEL_WIDTH);
+
+const DRIVER_OUTPUT_CONTROL: u8 = 0x01;
+const DEEP_SLEEP: u8 = 0x10;
+const DATA_ENTRY_MODE: u8 = 0x11;
+const SOFT_RESET: u8 = 0x12;
+const MASTER_ACTIVATION: u8 = 0x20;
+const DISPLAY_UPDATE_CONTROL: u8 = 0x22;
+const WRITE_RAM_BW: u8 = 0x24;
+const WRITE_RAM_PREVIOUS: u8 = 0x26;
+const BORDER_WAVEFORM: u8 = 0x3C;
+const RAM_X_RANGE: u8 = 0x44;
+const RAM_Y_RANGE: u8 = 0x45;
+const RAM_X_COUNTER: u8 = 0x4E;
+const RAM_Y_COUNTER: u8 = 0x4F;
+// Update sequences with the waveforms in the controller's OTP: display mode 1 redraws the whole
+// screen through a few black and white flashes, mode 2 only drives the pixels that differ
+// between the two RAMs
+const FULL_UPDATE: u8 = 0xF7;
+const PARTIAL_UPDATE: u8 = 0xFF;
+
+// A full refresh takes about 2 s, the margin covers cold panels
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+const BUSY_POLL: Duration = Duration::from_millis(10);
+
+// What the panel shows and the partial refreshes since the last full one. In RTC memory, which
+// the chip's deep sleep keeps like the panel keeps its image, so the first update after a wake
+// is a partial one as well.
+#[link_section = ".rtc.data"]
+static mut SHOWN: Option<(Frame, u32)> = None;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EPaperConfig {
+    // Partial refreshes leave a faint ghost of earlier images, a full refresh after this many
+    // clears it
+    pub full_refresh_every: u32,
+    // Turned by 180 degrees, for modules mounted with the connector on the other side
+    pub flipped: bool,
+}
+
+pub struct EPaper {
+    spi: SpiDeviceDriver<'static, SpiDriver<'static>>,
+    dc: PinDriver<'static, AnyIOPin, Output>,
+    reset: PinDriver<'static, AnyIOPin, Output>,
+    busy: PinDriver<'static, AnyIOPin, Input>,
+    config: EPaperConfig,
+}
+
+impl EPaper {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        spi: SPI2,
+        sclk: AnyIOPin,
+        mosi: AnyIOPin,
+        cs: AnyIOPin,
+        dc: AnyIOPin,
+        reset: AnyIOPin,
+        busy: AnyIOPin,
+        config: EPaperConfig,
+    ) -> Result<Self, String> {
+        let driver = SpiDriver::new(spi, sclk, mosi, None::<AnyIOPin>, &DriverConfig::new().dma(Dma::Auto(BUFFER_LEN)))
+            .map_err(|e| format!("SPI init: {:?}", e))?;
+        let spi = SpiDeviceDriver::new(driver, Some(cs), &Config::new().baudrate(4.MHz().into()))
+            .map_err(|e| format!("SPI device: {:?}", e))?;
+        let epaper = Self {
+            spi,
+            dc: PinDriver::output(dc).map_err(|e| format!("E-paper DC pin: {:?}", e))?,
+            reset: PinDriver::output(reset).map_err(|e| format!("E-paper reset pin: {:?}", e))?,
+            busy: PinDriver::input(busy).map_err(|e| format!("E-paper busy pin: {:?}", e))?,
+            config,
+        };
+        info!("E-paper up, full refresh every {} updates", config.full_refresh_every);
+        Ok(epaper)
+    }
+
+    // Wakes the controller from deep sleep, which only a hardware reset does, and sets it up
+    // for a full screen write
+    fn wake(&mut self) -> Result<(), String> {
+        self.reset.set_low().map_err(|e| format!("E-paper reset: {:?}", e))?;
+        std::thread::sleep(Duration::from_millis(10));
+        self.reset.set_high().map_err(|e| format!("E-paper reset: {:?}", e))?;
+        std::thread::sleep(Duration::from_millis(10));
+        self.wait_idle()?;
+
+        self.command(SOFT_RESET, &[])?;
+        self.wait_idle()?;
+        // 296 gate lines
+        self.command(DRIVER_OUTPUT_CONTROL, &[((PANEL_HEIGHT - 1) & 0xFF) as u8, ((PANEL_HEIGHT - 1) >> 8) as u8, 0x00])?;
+        // X and Y counting up, X first
+        self.command(DATA_ENTRY_MODE, &[0x03])?;
+        self.command(RAM_X_RANGE, &[0x00, (LINE_BYTES - 1) as u8])?;
+        self.command(RAM_Y_RANGE, &[0x00, 0x00, ((PANEL_HEIGHT - 1) & 0xFF) as u8, ((PANEL_HEIGHT - 1) >> 8) as u8])?;
+        // White border
+        self.command(BORDER_WAVEFORM, &[0x05])?;
+        self.wait_idle()
+    }
+
+    fn write_ram(&mut self, ram: u8, buffer: &[u8]) -> Result<(), String> {
+        self.command(RAM_X_COUNTER, &[0x00])?;
+        self.command(RAM_Y_COUNTER, &[0x00, 0x00])?;
+        self.command(ram, buffer)
+    }
+
+    fn update(&mut self, sequence: u8) -> Result<(), String> {
+        self.command(DISPLAY_UPDATE_CONTROL, &[sequence])?;
+        self.command(MASTER_ACTIVATION, &[])?;
+        self.wait_idle()
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<(), String> {
+        self.dc.set_low().map_err(|e| format!("E-paper DC: {:?}", e))?;
+        self.spi.write(&[command]).map_err(|e| format!("E-paper write: {:?}", e))?;
+        if !data.is_empty() {
+            self.dc.set_high().map_err(|e| format!("E-paper DC: {:?}", e))?;
+            self.spi.write(data).map_err(|e| format!("E-paper write: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    // BUSY is high while the controller works
+    fn wait_idle(&mut self) -> Result<(), String> {
+        let deadline = Instant::now() + BUSY_TIMEOUT;
+        while self.busy.is_high() {
+            if Instant::now() >= deadline {
+                return Err("E-paper stuck busy".to_string());
+            }
+            std::thread::sleep(BUSY_POLL);
+        }
+        Ok(())
+    }
+
+    // The frame in the controller's RAM layout: a line of 128 pixels per byte row down the
+    // long side, MSB first, a set bit white
+    fn render(&self, frame: &Frame) -> Vec<u8> {
+        let mut buffer = vec![0xFF; BUFFER_LEN];
+        for line in 0..PANEL_HEIGHT {
+            for pixel in 0..PANEL_WIDTH {
+                let (x, y) = match self.config.flipped {
+                    false => (line, PANEL_WIDTH - 1 - pixel),
+                    true => (PANEL_HEIGHT - 1 - line, pixel),
+                };
+                let Some(x) = x.checked_sub(MARGIN).map(|x| x / SCALE).filter(|x| *x < WIDTH) else {
+                    continue;
+                };
+                if frame.pixel(x, y / SCALE) {
+                    buffer[line * LINE_BYTES + pixel / 8] &= !(0x80 >> (pixel % 8));
+                }
+            }
+        }
+        buffer
+    }
+}
+
+impl Backend for EPaper {
+    fn flush(&mut self, frame: &Frame) -> Result<(), String> {
+        // Every refresh costs power and flickers, the display thread redraws more often than
+        // the content changes
+        // Only the display thread holds the panel
+        let shown = unsafe { (*addr_of!(SHOWN)).clone() };
+        if matches!(&shown, Some((shown, _)) if shown == frame) {
+            return Ok(());
+        }
+        let buffer = self.render(frame);
+        // Until the update completes the panel's content is unknown, the next one is a full refresh
+        unsafe { *addr_of_mut!(SHOWN) = None };
+        self.wake()?;
+
+        let partials = match shown {
+            // Mode 2 drives the pixels that differ between the previous image and the new one.
+            // Both are written, the controller's RAM doesn't survive the reset that ends deep
+            // sleep.
+            Some((previous, partials)) if partials < self.config.full_refresh_every => {
+                let previous = self.render(&previous);
+                self.write_ram(WRITE_RAM_PREVIOUS, &previous)?;
+                self.write_ram(WRITE_RAM_BW, &buffer)?;
+                self.update(PARTIAL_UPDATE)?;
+                partials + 1
+            }
+            _ => {
+                self.write_ram(WRITE_RAM_PREVIOUS, &buffer)?;
+                self.write_ram(WRITE_RAM_BW, &buffer)?;
+                self.update(FULL_UPDATE)?;
+                0
+            }
+        };
+        // Deep sleep mode 1, a few µA until the next update
+        self.command(DEEP_SLEEP, &[0x01])?;
+        unsafe { *addr_of_mut!(SHOWN) = Some((frame.clone(), partials)) };
+        Ok(())
+    }
+
+    fn lit_is_dark(&self) -> bool {
+        true
+    }
+}
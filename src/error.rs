@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use solana_program::pubkey::Pubkey;
+
+use crate::client::CallError;
+#[cfg(not(feature = "watch-only"))]
+use crate::policy::PolicyDenied;
+
+// Errors of the uplink, the RPC client and the signers, the places callers most often need to
+// tell causes apart: wait when the link is down, show the node's reason for a refused
+// transaction, ask for the PIN. Callers that only pass the message on format it with
+// `to_string`.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetError {
+    // The uplink stayed down for the whole wait
+    LinkDown,
+    // Requests are answered by a network login page, see captive.rs
+    CaptivePortal,
+    // Looking the host up failed, over DoH or the system resolver
+    Dns(String),
+    // Connecting, the TLS handshake, or writing or reading the exchange failed
+    Connection(String),
+    // The connection closed before the declared length or the last chunk
+    Truncated,
+    // The uplink's driver, interface, timers or event subscriptions couldn't be set up
+    Driver(String),
+    // No stored network can be joined with, none is stored or the driver takes none of them
+    NotConfigured(String),
+    // Associating with the network failed, or it handed out no address in time
+    Join(String),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::LinkDown => write!(f, "Network link down"),
+            NetError::CaptivePortal => write!(f, "Captive portal in the way, log in to the network first"),
+            NetError::Dns(e) => write!(f, "{}", e),
+            NetError::Connection(e) => write!(f, "{}", e),
+            NetError::Truncated => write!(f, "Connection closed before the response was complete"),
+            NetError::Driver(e) => write!(f, "{}", e),
+            NetError::NotConfigured(e) => write!(f, "{}", e),
+            NetError::Join(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for NetError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    Net(NetError),
+    // The server answered with a status outside 2xx
+    Status(u16),
+    // The response outgrew the buffer it was read into, or the memory left, at this many bytes
+    TooLarge(usize),
+    // The node refused the call, a failed preflight or an expired blockhash for instance
+    Node { code: i64, message: String },
+    // The response isn't what the method returns
+    Parse(String),
+    // The transaction landed but failed, with the node's error for it
+    TransactionFailed(String),
+    // The signature didn't reach the commitment before the wait ran out
+    Unconfirmed { signature: String, waited: std::time::Duration },
+    // This device doesn't reach the network itself, relay and LoRa nodes go through a gateway
+    NoUplink(&'static str),
+    // The gateway of a relay or LoRa node couldn't make the call
+    Gateway(String),
+    // The client itself failed before anything was sent: the request didn't serialize, or a
+    // worker thread or timer of the async client couldn't start
+    Client(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Net(e) => write!(f, "{}", e),
+            RpcError::Status(status) => write!(f, "HTTP Error: Status code {}", status),
+            RpcError::TooLarge(limit) => write!(f, "Response over {} bytes", limit),
+            RpcError::Node { code, message } => write!(f, "RPC error {}: {}", code, message),
+            RpcError::Parse(e) => write!(f, "{}", e),
+            RpcError::TransactionFailed(e) => write!(f, "Transaction failed: {}", e),
+            RpcError::Unconfirmed { signature, waited } => {
+                write!(f, "Transaction {} not confirmed within {:?}", signature, waited)
+            }
+            RpcError::NoUplink(reason) => write!(f, "{}", reason),
+            RpcError::Gateway(e) => write!(f, "Gateway: {}", e),
+            RpcError::Client(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RpcError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RpcError::Net(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<NetError> for RpcError {
+    fn from(e: NetError) -> Self {
+        RpcError::Net(e)
+    }
+}
+
+impl From<CallError> for RpcError {
+    fn from(e: CallError) -> Self {
+        match e {
+            CallError::Node { code, message } => RpcError::Node { code, message },
+            CallError::Request(e) => RpcError::Client(e),
+            CallError::Parse(e) => RpcError::Parse(e),
+        }
+    }
+}
+
+impl RpcError {
+    // Worth trying again as it is: the network, not the request, was the problem
+    pub fn is_transient(&self) -> bool {
+        matches!(self, RpcError::Net(_) | RpcError::Status(429 | 500..=599))
+    }
+
+    // The node's answer for an account that doesn't exist where the method needs one, which
+    // getTokenAccountBalance gives as an invalid parameter
+    pub fn is_account_not_found(&self) -> bool {
+        matches!(self, RpcError::Node { code: -32602, message } if message.contains("could not find account"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    // Tamper detection locked the device down, the PIN can't lift it
+    LockedDown,
+    // The PIN has to be entered before anything is signed
+    PinRequired,
+    // The PIN entered doesn't match the one set
+    WrongPin,
+    // Too many wrong PINs, for this long or, with None, until NVS is erased
+    PinLockedOut(Option<Duration>),
+    // Session keys check their expiry against the clock, which hasn't synced yet
+    ClockNotSynced,
+    // The session key is past its lifetime
+    Expired(String),
+    // The key isn't a required signer of the message
+    NotASigner(Pubkey),
+    // The message header claims more signers than it has accounts
+    MalformedMessage,
+    // The spending policy refused, with the rule the message broke
+    #[cfg(not(feature = "watch-only"))]
+    PolicyDenied(PolicyDenied),
+    // A signing hook, a key's policy or the approval button refused
+    Refused(String),
+    // The other device in remote signing answered ERR, or nothing that makes sense
+    Remote(String),
+    // The signing backend or the link to the remote signer failed
+    Backend(String),
+    // Reading or writing keys, the PIN or their state in NVS failed
+    Storage(String),
+    // A keyfile, key name, PIN, RPC URL or session key request that doesn't meet the requirements
+    Invalid(String),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::LockedDown => write!(f, "Device locked down after tamper detection"),
+            WalletError::PinRequired => write!(f, "Signing locked, PIN required"),
+            WalletError::WrongPin => write!(f, "Wrong PIN"),
+            WalletError::PinLockedOut(Some(remaining)) => write!(f, "PIN locked out for {}s", remaining.as_secs()),
+            WalletError::PinLockedOut(None) => {
+                write!(f, "PIN gate permanently locked, erase NVS and restore the key from backup")
+            }
+            WalletError::ClockNotSynced => write!(f, "Session keys need the clock, which is not synced"),
+            WalletError::Expired(purpose) => write!(f, "Session key for '{}' expired", purpose),
+            WalletError::NotASigner(pubkey) => write!(f, "{} is not a required signer of this message", pubkey),
+            WalletError::MalformedMessage => write!(f, "Malformed message header"),
+            #[cfg(not(feature = "watch-only"))]
+            WalletError::PolicyDenied(denied) => write!(f, "Policy denied: {}", denied),
+            WalletError::Refused(reason) => write!(f, "{}", reason),
+            WalletError::Remote(reason) => write!(f, "Remote signer: {}", reason),
+            WalletError::Backend(e) => write!(f, "{}", e),
+            WalletError::Storage(e) => write!(f, "{}", e),
+            WalletError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for WalletError {}
@@ -0,0 +1,219 @@
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+#[cfg(feature = "ethernet-rmii")]
+use esp_idf_svc::eth::{RmiiClockConfig, RmiiEthChipset};
+#[cfg(feature = "ethernet-w5500")]
+use esp_idf_svc::eth::SpiEthChipset;
+use esp_idf_svc::eth::{BlockingEth, EspEth, EthDriver, EthEvent};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::handle::RawHandle;
+#[cfg(feature = "ethernet-rmii")]
+use esp_idf_svc::hal::gpio::{Gpio0, Gpio16, Gpio17, Gpio18, Gpio19, Gpio21, Gpio22, Gpio23, Gpio25, Gpio26, Gpio27};
+#[cfg(feature = "ethernet-w5500")]
+use esp_idf_svc::hal::gpio::{Gpio10, Gpio2, Gpio4, Gpio5, Gpio6, Gpio7};
+#[cfg(feature = "ethernet-rmii")]
+use esp_idf_svc::hal::mac::MAC;
+#[cfg(feature = "ethernet-w5500")]
+use esp_idf_svc::hal::prelude::*;
+#[cfg(feature = "ethernet-w5500")]
+use esp_idf_svc::hal::spi::{config::DriverConfig, Dma, SpiDriver, SPI2};
+use esp_idf_svc::netif::IpEvent;
+use esp_idf_svc::sys::{esp_eth_start, esp_eth_stop, esp_netif_dhcpc_start, esp_netif_dhcpc_stop};
+#[cfg(feature = "ethernet-w5500")]
+use esp_idf_svc::sys::{esp_mac_type_t_ESP_MAC_ETH, esp_read_mac, ESP_OK};
+use log::{info, warn};
+
+use crate::dualstack;
+use crate::net::{self, set_link, Recovery};
+
+// Boot carries on without a cable, RPC calls wait for the link like they do for WiFi
+const BOOT_LINK_WAIT: Duration = Duration::from_secs(10);
+const SUPERVISOR_STACK_SIZE: usize = 4 * 1024;
+// The W5500 is specified up to 80 MHz, breadboard wiring is happier well below that
+#[cfg(feature = "ethernet-w5500")]
+const W5500_CLOCK_MHZ: u32 = 20;
+// LAN8720 modules (WT32-ETH01 and most breakout boards) strap the PHY to address 1
+#[cfg(feature = "ethernet-rmii")]
+const RMII_PHY_ADDRESS: u32 = 1;
+
+// W5500 wiring on the ESP32-C3, away from the tamper (GPIO3) and BOOT (GPIO9) pins
+#[cfg(feature = "ethernet-w5500")]
+pub struct W5500Pins {
+    pub sclk: Gpio6,
+    pub mosi: Gpio7,
+    pub miso: Gpio2,
+    pub cs: Gpio10,
+    pub int: Gpio4,
+    pub rst: Gpio5,
+}
+
+// Fixed RMII data pins of the classic ESP32's EMAC, plus the WT32-ETH01's MDC/MDIO, the 50 MHz
+// clock coming in on GPIO0 and its oscillator enable on GPIO16, which doubles as PHY reset
+#[cfg(feature = "ethernet-rmii")]
+pub struct RmiiPins {
+    pub rxd0: Gpio25,
+    pub rxd1: Gpio26,
+    pub crs_dv: Gpio27,
+    pub mdc: Gpio23,
+    pub txd1: Gpio22,
+    pub tx_en: Gpio21,
+    pub txd0: Gpio19,
+    pub mdio: Gpio18,
+    pub clock: Gpio0,
+    pub reset: Gpio16,
+}
+
+// Brings up a W5500 over SPI, false when the chip doesn't answer so the caller can use WiFi
+#[cfg(feature = "ethernet-w5500")]
+pub fn connect_w5500(spi: SPI2, pins: W5500Pins, sys_loop: EspSystemEventLoop) -> bool {
+    let driver = SpiDriver::new(
+        spi,
+        pins.sclk,
+        pins.mosi,
+        Some(pins.miso),
+        &DriverConfig::new().dma(Dma::Auto(4096)),
+    )
+    .map_err(|e| format!("Ethernet SPI: {:?}", e))
+    .and_then(|spi| {
+        // The W5500 has no MAC address of its own, it gets the one eFuse reserves for Ethernet
+        let mac = eth_mac()?;
+        EthDriver::new_spi(
+            spi,
+            pins.int,
+            Some(pins.cs),
+            Some(pins.rst),
+            SpiEthChipset::W5500,
+            W5500_CLOCK_MHZ.MHz().into(),
+            Some(&mac),
+            None,
+            sys_loop.clone(),
+        )
+        .map_err(|e| format!("W5500: {:?}", e))
+    });
+
+    report(driver.and_then(|driver| start(driver, sys_loop)))
+}
+
+// Brings up a LAN87xx PHY on the ESP32's EMAC, false when it doesn't answer
+#[cfg(feature = "ethernet-rmii")]
+pub fn connect_rmii(mac: MAC, pins: RmiiPins, sys_loop: EspSystemEventLoop) -> bool {
+    let driver = EthDriver::new_rmii(
+        mac,
+        pins.rxd0,
+        pins.rxd1,
+        pins.crs_dv,
+        pins.mdc,
+        pins.txd1,
+        pins.tx_en,
+        pins.txd0,
+        pins.mdio,
+        RmiiClockConfig::<Gpio0, Gpio16, Gpio17>::Input(pins.clock),
+        Some(pins.reset),
+        RmiiEthChipset::LAN87XX,
+        Some(RMII_PHY_ADDRESS),
+        sys_loop.clone(),
+    )
+    .map_err(|e| format!("RMII PHY: {:?}", e));
+
+    report(driver.and_then(|driver| start(driver, sys_loop)))
+}
+
+fn report(result: Result<(), String>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Ethernet unavailable, falling back to WiFi: {}", e);
+            false
+        }
+    }
+}
+
+// The driver reconnects and renews DHCP by itself when the cable comes back, the thread only
+// keeps it and the subscriptions alive and logs link changes
+fn start<T: Send + 'static>(driver: EthDriver<'static, T>, sys_loop: EspSystemEventLoop) -> Result<(), String> {
+    let eth = EspEth::wrap(driver).map_err(|e| format!("Ethernet netif: {:?}", e))?;
+    let mut eth = BlockingEth::wrap(eth, sys_loop.clone()).map_err(|e| format!("Ethernet: {:?}", e))?;
+
+    // Raw handles aren't Send, the netif and driver live as long as the supervisor keeps `eth`
+    let netif = eth.eth().netif().handle() as usize;
+    let (changed, changes) = channel();
+    let eth_events = sys_loop
+        .subscribe::<EthEvent, _>(move |event| match event {
+            EthEvent::Connected(_) => {
+                dualstack::enable(netif as *mut _);
+                let _ = changed.send(true);
+            }
+            EthEvent::Disconnected(_) => {
+                set_link(false);
+                let _ = changed.send(false);
+            }
+            _ => {}
+        })
+        .map_err(|e| format!("Ethernet events: {:?}", e))?;
+    let ip_events = sys_loop
+        .subscribe::<IpEvent, _>(|event| match event {
+            IpEvent::DhcpIpAssigned(_) | IpEvent::DhcpIp6Assigned(_) => set_link(true),
+            IpEvent::DhcpIpDeassigned(_) => set_link(false),
+            _ => {}
+        })
+        .map_err(|e| format!("IP events: {:?}", e))?;
+
+    eth.start().map_err(|e| format!("Ethernet start: {:?}", e))?;
+
+    // There is no association to redo, a restarted driver renegotiates the link
+    let driver = eth.eth().handle() as usize;
+    net::set_recovery(move |step| {
+        match step {
+            Recovery::RestartDhcp => {
+                warn!("Restarting DHCP");
+                unsafe {
+                    esp_netif_dhcpc_stop(netif as *mut _);
+                    esp_netif_dhcpc_start(netif as *mut _);
+                }
+            }
+            Recovery::Reassociate => return false,
+            Recovery::PowerCycle => {
+                warn!("Restarting the Ethernet driver");
+                unsafe {
+                    esp_eth_stop(driver as *mut _);
+                    esp_eth_start(driver as *mut _);
+                }
+            }
+        }
+        true
+    });
+    match net::wait_for_link(BOOT_LINK_WAIT) {
+        true => match eth.eth().netif().get_ip_info() {
+            Ok(ip_info) => info!("Ethernet connected, address {}", ip_info.ip),
+            Err(_) => info!("Ethernet connected"),
+        },
+        false => warn!("No Ethernet link yet, RPC waits for the cable"),
+    }
+
+    std::thread::Builder::new()
+        .name("eth".to_string())
+        .stack_size(SUPERVISOR_STACK_SIZE)
+        .spawn(move || {
+            let _eth = eth;
+            let _subscriptions = (eth_events, ip_events);
+            // The sender lives in the Ethernet subscription this thread keeps, so this never ends
+            for connected in changes {
+                match connected {
+                    true => info!("Ethernet cable connected"),
+                    false => warn!("Ethernet link lost, pausing RPC until it is back"),
+                }
+            }
+        })
+        .map_err(|e| format!("Ethernet supervisor: {:?}", e))?;
+    Ok(())
+}
+
+#[cfg(feature = "ethernet-w5500")]
+fn eth_mac() -> Result<[u8; 6], String> {
+    let mut mac = [0u8; 6];
+    match unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_ETH) } {
+        ESP_OK => Ok(mac),
+        ret => Err(format!("Ethernet MAC read: {}", ret)),
+    }
+}
@@ -0,0 +1,256 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Gpio0, Gpio1};
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Message;
+
+use crate::approval::{describe, needs_approval};
+use crate::error::WalletError;
+use crate::pin::PinGate;
+use crate::signer::SigningHook;
+
+// R503 and AS608 optical/capacitive modules speak the same packet protocol over UART:
+//   EF 01 | address (4) | kind | length (2) | payload | checksum (2)
+// big-endian, the length counting payload and checksum, the checksum summing kind, length and
+// payload. Fingerprints are enrolled into and matched against the module's own template library.
+
+// Both modules ship at 57600 baud
+const BAUD_RATE: u32 = 57_600;
+const HEADER: [u8; 2] = [0xEF, 0x01];
+// Modules answer on any address until one is set
+const ADDRESS: [u8; 4] = [0xFF; 4];
+const KIND_COMMAND: u8 = 0x01;
+const KIND_ACK: u8 = 0x07;
+
+const GEN_IMAGE: u8 = 0x01;
+const IMAGE_TO_TEMPLATE: u8 = 0x02;
+const SEARCH: u8 = 0x04;
+const REGISTER_MODEL: u8 = 0x05;
+const STORE: u8 = 0x06;
+const READ_SYSTEM_PARAMETERS: u8 = 0x0F;
+const VERIFY_PASSWORD: u8 = 0x13;
+const TEMPLATE_COUNT: u8 = 0x1D;
+
+const CONFIRM_OK: u8 = 0x00;
+const CONFIRM_NO_FINGER: u8 = 0x02;
+const CONFIRM_NOT_FOUND: u8 = 0x09;
+
+// Taking an image takes the module up to half a second
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+const FINGER_POLL: Duration = Duration::from_millis(100);
+const ENROLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+pub struct FingerprintConfig {
+    // SOL transfers up to this amount are signed without a finger, token instructions never are
+    pub threshold_lamports: u64,
+    pub timeout: Duration,
+    // Match score a finger needs, the modules rate a good match well above 100
+    pub min_score: u16,
+    // Module password, 0 unless it was changed
+    pub password: u32,
+    // Fingers the `enroll` provisioning command fills the library up to
+    pub enroll_fingers: u16,
+}
+
+// Requires a matching finger on a UART fingerprint module before signing large transfers
+pub struct FingerprintApproval {
+    uart: UartDriver<'static>,
+    config: FingerprintConfig,
+    library_size: u16,
+}
+
+impl FingerprintApproval {
+    // Module RX on GPIO0 and TX on GPIO1, powered from 3.3V
+    pub fn new(uart: UART1, tx: Gpio0, rx: Gpio1, config: FingerprintConfig) -> Result<Self, String> {
+        let uart = UartDriver::new(
+            uart,
+            tx,
+            rx,
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &UartConfig::default().baudrate(Hertz(BAUD_RATE)),
+        )
+        .map_err(|e| format!("Fingerprint UART: {:?}", e))?;
+        let mut sensor = Self {
+            uart,
+            config,
+            library_size: 0,
+        };
+
+        let confirm = sensor.command(&[&[VERIFY_PASSWORD][..], &config.password.to_be_bytes()].concat())?.0;
+        if confirm != CONFIRM_OK {
+            return Err(format!("Fingerprint module refused the password ({:#04x})", confirm));
+        }
+        let parameters = sensor.expect(&[READ_SYSTEM_PARAMETERS], "Reading parameters")?;
+        sensor.library_size = parameters
+            .get(4..6)
+            .map(|size| u16::from_be_bytes([size[0], size[1]]))
+            .ok_or("Short parameter reply")?;
+
+        info!(
+            "Fingerprint module up, {} of {} templates enrolled",
+            sensor.template_count()?,
+            sensor.library_size
+        );
+        Ok(sensor)
+    }
+
+    // Enrolls fingers into the next free slots until `enroll_fingers` are stored. A new finger
+    // approves transfers, so it takes the signing PIN, which has to be set.
+    pub fn enroll_missing(&self, pin_gate: &mut PinGate, pin: &str) -> Result<u16, String> {
+        if !pin_gate.is_configured().map_err(|e| e.to_string())? {
+            return Err("Set a signing PIN before enrolling fingers".to_string());
+        }
+        pin_gate.verify(pin).map_err(|e| e.to_string())?;
+
+        let enrolled = self.template_count()?;
+        for slot in enrolled..self.config.enroll_fingers.min(self.library_size) {
+            info!("Enrolling finger {} of {}", slot + 1, self.config.enroll_fingers);
+            self.enroll(slot)?;
+        }
+        self.template_count()
+    }
+
+    fn template_count(&self) -> Result<u16, String> {
+        let count = self.expect(&[TEMPLATE_COUNT], "Counting templates")?;
+        count
+            .get(..2)
+            .map(|count| u16::from_be_bytes([count[0], count[1]]))
+            .ok_or_else(|| "Short template count reply".to_string())
+    }
+
+    // Two images of the same finger are merged into one template and stored in the slot
+    fn enroll(&self, slot: u16) -> Result<(), String> {
+        for buffer in [1u8, 2] {
+            info!("Place the finger on the sensor");
+            self.capture(buffer, Instant::now() + ENROLL_TIMEOUT)?;
+            info!("Lift the finger");
+            let deadline = Instant::now() + ENROLL_TIMEOUT;
+            while self.command(&[GEN_IMAGE])?.0 != CONFIRM_NO_FINGER {
+                if Instant::now() >= deadline {
+                    return Err("Finger not lifted".to_string());
+                }
+                std::thread::sleep(FINGER_POLL);
+            }
+        }
+        self.expect(&[REGISTER_MODEL], "Images don't match, enroll again")?;
+        self.expect(&[&[STORE, 1][..], &slot.to_be_bytes()].concat(), "Storing template")?;
+        info!("Finger enrolled in slot {}", slot);
+        Ok(())
+    }
+
+    // Waits for a finger and turns its image into a template in the character buffer
+    fn capture(&self, buffer: u8, deadline: Instant) -> Result<(), String> {
+        loop {
+            match self.command(&[GEN_IMAGE])?.0 {
+                CONFIRM_OK => break,
+                CONFIRM_NO_FINGER if Instant::now() < deadline => std::thread::sleep(FINGER_POLL),
+                CONFIRM_NO_FINGER => return Err("No finger placed".to_string()),
+                confirm => warn!("Fingerprint image failed ({:#04x}), try again", confirm),
+            }
+        }
+        self.expect(&[IMAGE_TO_TEMPLATE, buffer], "Fingerprint unclear, try again")
+            .map(|_| ())
+    }
+
+    // Whether the finger on the sensor matches an enrolled one well enough
+    fn matches(&self, deadline: Instant) -> Result<bool, String> {
+        self.capture(1, deadline)?;
+        let search = [&[SEARCH, 1, 0, 0][..], &self.library_size.to_be_bytes()].concat();
+        match self.command(&search)? {
+            (CONFIRM_OK, found) if found.len() >= 4 => {
+                let score = u16::from_be_bytes([found[2], found[3]]);
+                info!("Finger matches slot {} with score {}", u16::from_be_bytes([found[0], found[1]]), score);
+                Ok(score >= self.config.min_score)
+            }
+            (CONFIRM_NOT_FOUND, _) => Ok(false),
+            (confirm, _) => Err(format!("Fingerprint search failed ({:#04x})", confirm)),
+        }
+    }
+
+    fn expect(&self, payload: &[u8], context: &str) -> Result<Vec<u8>, String> {
+        match self.command(payload)? {
+            (CONFIRM_OK, data) => Ok(data),
+            (confirm, _) => Err(format!("{} ({:#04x})", context, confirm)),
+        }
+    }
+
+    // Sends one command packet and returns the confirmation code and data of its acknowledgment
+    fn command(&self, payload: &[u8]) -> Result<(u8, Vec<u8>), String> {
+        let length = (payload.len() as u16 + 2).to_be_bytes();
+        let mut packet = [&HEADER[..], &ADDRESS, &[KIND_COMMAND], &length, payload].concat();
+        let sum = checksum(&packet[6..]);
+        packet.extend_from_slice(&sum.to_be_bytes());
+
+        let _ = self.uart.clear_rx();
+        self.uart
+            .write(&packet)
+            .map_err(|e| format!("Fingerprint write: {:?}", e))?;
+
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        let head = self.read_exact(9, deadline)?;
+        if head[..2] != HEADER || head[6] != KIND_ACK {
+            return Err("Unexpected fingerprint reply".to_string());
+        }
+        let length = usize::from(u16::from_be_bytes([head[7], head[8]]));
+        if length < 3 {
+            return Err("Short fingerprint reply".to_string());
+        }
+        let body = self.read_exact(length, deadline)?;
+        let (data, sum) = body.split_at(length - 2);
+        if checksum(&[&head[6..], data].concat()) != u16::from_be_bytes([sum[0], sum[1]]) {
+            return Err("Fingerprint reply checksum mismatch".to_string());
+        }
+        Ok((data[0], data[1..].to_vec()))
+    }
+
+    fn read_exact(&self, len: usize, deadline: Instant) -> Result<Vec<u8>, String> {
+        let mut data = vec![0u8; len];
+        let mut read = 0;
+        while read < len {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err("Fingerprint module not answering".to_string());
+            }
+            read += self
+                .uart
+                .read(&mut data[read..], TickType::new_millis(remaining.as_millis() as u64).into())
+                .map_err(|e| format!("Fingerprint read: {:?}", e))?;
+        }
+        Ok(data)
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |sum, byte| sum.wrapping_add(u16::from(*byte)))
+}
+
+impl SigningHook for FingerprintApproval {
+    fn check(&self, message: &Message, signer: &Pubkey) -> Result<(), WalletError> {
+        if !needs_approval(message, signer, self.config.threshold_lamports) {
+            return Ok(());
+        }
+        // An empty library would otherwise leave no way to approve, and no one to approve it
+        if self.template_count().map_err(WalletError::Refused)? == 0 {
+            return Err(WalletError::Refused("No fingerprints enrolled".to_string()));
+        }
+
+        info!(
+            "Place an enrolled finger on the sensor within {}s to approve {}",
+            self.config.timeout.as_secs(),
+            describe(message, signer)
+        );
+        match self.matches(Instant::now() + self.config.timeout).map_err(WalletError::Refused)? {
+            true => {
+                info!("Transaction approved by fingerprint");
+                Ok(())
+            }
+            false => Err(WalletError::Refused("Fingerprint not recognized".to_string())),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, Once};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use solana_transaction::{Hash, Signature, Transaction};
+
+use crate::solrpc::{get_latest_blockhash, send_transaction};
+
+// The gateway side shared by the relays (relay.rs over ESP-NOW, lora.rs over LoRa): transactions
+// reassembled from their nodes are checked, repeats dropped, and the rest submitted in order
+// over this device's uplink by a thread of their own.
+
+pub const ACK_QUEUED: u8 = 0;
+pub const ACK_DUPLICATE: u8 = 1;
+pub const ACK_REJECTED: u8 = 2;
+pub const ACK_QUEUE_FULL: u8 = 3;
+
+// A blockhash stays usable for about a minute, nodes get one with most of that left
+#[cfg(feature = "espnow-relay")]
+const BLOCKHASH_MAX_AGE: Duration = Duration::from_secs(20);
+const MAX_QUEUED: usize = 16;
+const SUBMIT_ATTEMPTS: u32 = 3;
+// Signatures remembered for dropping retransmissions
+const SEEN_LIMIT: usize = 64;
+const SUBMIT_POLL: Duration = Duration::from_secs(1);
+const SUBMIT_STACK_SIZE: usize = 8 * 1024;
+
+struct Relayed {
+    transaction: Transaction,
+    attempts: u32,
+}
+
+static QUEUE: Mutex<VecDeque<Relayed>> = Mutex::new(VecDeque::new());
+static QUEUED: Condvar = Condvar::new();
+static SEEN: Mutex<VecDeque<Signature>> = Mutex::new(VecDeque::new());
+static LATEST_BLOCKHASH: Mutex<Option<(Hash, Instant)>> = Mutex::new(None);
+static BLOCKHASH_WANTED: AtomicBool = AtomicBool::new(false);
+static SUBMITTER: Once = Once::new();
+
+// Starts the submit thread, once however many relays serve nodes
+pub fn start() -> Result<(), String> {
+    let mut result = Ok(());
+    SUBMITTER.call_once(|| {
+        result = std::thread::Builder::new()
+            .name("relay-submit".to_string())
+            .stack_size(SUBMIT_STACK_SIZE)
+            .spawn(submit_queued)
+            .map(|_| ())
+            .map_err(|e| format!("Relay submit thread: {:?}", e));
+    });
+    result
+}
+
+// Checks a reassembled transaction and queues it, returns the ack status for the node
+pub fn accept(payload: &[u8]) -> Result<(u8, Signature), String> {
+    let transaction: Transaction =
+        bincode::deserialize(payload).map_err(|e| format!("Transaction decode: {:?}", e))?;
+    let signature = *transaction.signatures.first().ok_or("Transaction is not signed")?;
+
+    let mut seen = SEEN.lock().unwrap();
+    if seen.contains(&signature) {
+        return Ok((ACK_DUPLICATE, signature));
+    }
+    // The gateway pays for nothing, but a bad signature is not worth an RPC call
+    let message = transaction.message_data();
+    let signers = usize::from(transaction.message.header.num_required_signatures);
+    let valid = transaction.signatures.len() == signers
+        && transaction
+            .signatures
+            .iter()
+            .zip(&transaction.message.account_keys)
+            .all(|(signature, signer)| signature.verify(signer.as_ref(), &message));
+    if !valid {
+        warn!("Relayed transaction {} has invalid signatures", signature);
+        return Ok((ACK_REJECTED, signature));
+    }
+
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() >= MAX_QUEUED {
+        return Ok((ACK_QUEUE_FULL, signature));
+    }
+    queue.push_back(Relayed {
+        transaction,
+        attempts: 0,
+    });
+    QUEUED.notify_all();
+
+    seen.push_back(signature);
+    if seen.len() > SEEN_LIMIT {
+        seen.pop_front();
+    }
+    info!("Relayed transaction {} queued", signature);
+    Ok((ACK_QUEUED, signature))
+}
+
+// A blockhash with most of its life left for a relay node, None while a fresh one is fetched.
+// LoRa nodes sign with a durable nonce instead.
+#[cfg(feature = "espnow-relay")]
+pub fn blockhash() -> Option<Hash> {
+    let fresh = LATEST_BLOCKHASH
+        .lock()
+        .unwrap()
+        .filter(|(_, fetched)| fetched.elapsed() < BLOCKHASH_MAX_AGE)
+        .map(|(hash, _)| hash);
+    if fresh.is_none() {
+        BLOCKHASH_WANTED.store(true, Ordering::SeqCst);
+        QUEUED.notify_all();
+    }
+    fresh
+}
+
+// Fetches blockhashes nodes asked for and submits queued transactions in order
+fn submit_queued() {
+    loop {
+        if BLOCKHASH_WANTED.swap(false, Ordering::SeqCst) {
+            match get_latest_blockhash() {
+                Ok(hash) => *LATEST_BLOCKHASH.lock().unwrap() = Some((hash, Instant::now())),
+                Err(e) => warn!("Blockhash for relay nodes: {}", e),
+            }
+        }
+
+        let next = {
+            let queue = QUEUE.lock().unwrap();
+            let (mut queue, _) = QUEUED
+                .wait_timeout_while(queue, SUBMIT_POLL, |queue| {
+                    queue.is_empty() && !BLOCKHASH_WANTED.load(Ordering::SeqCst)
+                })
+                .unwrap();
+            queue.pop_front()
+        };
+        let Some(mut relayed) = next else {
+            continue;
+        };
+
+        match send_transaction(&relayed.transaction) {
+            Ok(signature) => info!("Relayed transaction sent: {}", signature),
+            Err(e) => {
+                relayed.attempts += 1;
+                let signature = relayed.transaction.signatures[0];
+                if relayed.attempts < SUBMIT_ATTEMPTS {
+                    warn!("Relayed transaction {} failed, retrying: {}", signature, e);
+                    QUEUE.lock().unwrap().push_back(relayed);
+                } else {
+                    warn!("Relayed transaction {} dropped after {} attempts: {}", signature, SUBMIT_ATTEMPTS, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Gpio1};
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartRxDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use log::{info, warn};
+
+// Position fixes from a UART GPS module (u-blox NEO-6M/M8N, ATGM336H and the like), read off the
+// NMEA sentences it sends once a second. RMC gives the position, speed and UTC time, GGA the
+// satellites and altitude, talker IDs (GP, GN, GL...) are all taken.
+
+// NMEA allows 82 characters, some modules send longer proprietary sentences
+const MAX_SENTENCE_LEN: usize = 120;
+// A fix not refreshed this long is lost, the module stopped answering or has no signal
+const FIX_STALE: Duration = Duration::from_secs(10);
+const READ_TIMEOUT_MS: u64 = 1000;
+const READER_STACK_SIZE: usize = 4 * 1024;
+const KNOTS_TO_MPS: f64 = 0.514_444;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    // Unix time of the fix, from the satellites
+    pub time: u64,
+    // Degrees, north and east positive
+    pub latitude: f64,
+    pub longitude: f64,
+    pub speed_mps: f64,
+    pub satellites: Option<u8>,
+    pub altitude_m: Option<f64>,
+}
+
+static LATEST: Mutex<Option<(Fix, Instant)>> = Mutex::new(None);
+
+// Reads the module on UART1, its TX on GPIO1. The device never writes to it.
+pub fn start(uart: UART1, rx: Gpio1, baud_rate: u32) -> Result<(), String> {
+    let uart = UartRxDriver::new(
+        uart,
+        rx,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::default().baudrate(Hertz(baud_rate)),
+    )
+    .map_err(|e| format!("GPS UART: {:?}", e))?;
+
+    std::thread::Builder::new()
+        .name("gps".to_string())
+        .stack_size(READER_STACK_SIZE)
+        .spawn(move || read(uart))
+        .map_err(|e| format!("GPS reader: {:?}", e))?;
+    info!("GPS listening at {} baud", baud_rate);
+    Ok(())
+}
+
+// The current fix, None while the module has none
+pub fn latest() -> Option<Fix> {
+    LATEST
+        .lock()
+        .unwrap()
+        .filter(|(_, at)| at.elapsed() < FIX_STALE)
+        .map(|(fix, _)| fix)
+}
+
+fn read(uart: UartRxDriver<'static>) {
+    let mut sentence: Vec<u8> = Vec::with_capacity(MAX_SENTENCE_LEN);
+    // GGA of the same second, RMC finishes the fix
+    let mut satellites: Option<(u8, Option<f64>)> = None;
+    let mut had_fix = false;
+    let mut buf = [0u8; 64];
+    loop {
+        let read = match uart.read(&mut buf, TickType::new_millis(READ_TIMEOUT_MS).into()) {
+            Ok(read) => read,
+            Err(e) => {
+                warn!("GPS read: {:?}", e);
+                std::thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        for &byte in &buf[..read] {
+            match byte {
+                b'$' => sentence = vec![byte],
+                b'\r' | b'\n' if !sentence.is_empty() => {
+                    if let Some(fields) = std::str::from_utf8(&sentence).ok().and_then(checked_fields) {
+                        handle(&fields, &mut satellites, &mut had_fix);
+                    }
+                    sentence.clear();
+                }
+                _ if !sentence.is_empty() && sentence.len() < MAX_SENTENCE_LEN => sentence.push(byte),
+                // Too long for a sentence, or noise between sentences
+                _ => sentence.clear(),
+            }
+        }
+    }
+}
+
+fn handle(fields: &[&str], satellites: &mut Option<(u8, Option<f64>)>, had_fix: &mut bool) {
+    match fields.first().map(|kind| kind.get(2..).unwrap_or_default()) {
+        Some("GGA") => *satellites = parse_gga(fields),
+        Some("RMC") => {
+            let fix = parse_rmc(fields);
+            if fix.is_some() != *had_fix {
+                *had_fix = fix.is_some();
+                info!("GPS fix {}", if *had_fix { "acquired" } else { "lost" });
+            }
+            if let Some(mut fix) = fix {
+                if let Some((count, altitude)) = satellites.take() {
+                    fix.satellites = Some(count);
+                    fix.altitude_m = altitude;
+                }
+                *LATEST.lock().unwrap() = Some((fix, Instant::now()));
+            }
+        }
+        _ => {}
+    }
+}
+
+// The comma separated fields after the '$', None unless the checksum after '*' matches
+fn checked_fields(sentence: &str) -> Option<Vec<&str>> {
+    let (body, checksum) = sentence.strip_prefix('$')?.split_once('*')?;
+    let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+    if body.bytes().fold(0, |sum, byte| sum ^ byte) != expected {
+        return None;
+    }
+    Some(body.split(',').collect())
+}
+
+// $GPRMC,hhmmss.ss,A,ddmm.mmmm,N,dddmm.mmmm,E,knots,course,ddmmyy,...
+fn parse_rmc(fields: &[&str]) -> Option<Fix> {
+    if *fields.get(2)? != "A" {
+        return None;
+    }
+    Some(Fix {
+        time: unix_time(fields.get(1)?, fields.get(9)?)?,
+        latitude: coordinate(fields.get(3)?, fields.get(4)?, 2)?,
+        longitude: coordinate(fields.get(5)?, fields.get(6)?, 3)?,
+        speed_mps: fields.get(7)?.parse::<f64>().unwrap_or(0.0) * KNOTS_TO_MPS,
+        satellites: None,
+        altitude_m: None,
+    })
+}
+
+// $GPGGA,hhmmss.ss,lat,N,lon,E,quality,satellites,hdop,altitude,M,...
+fn parse_gga(fields: &[&str]) -> Option<(u8, Option<f64>)> {
+    if fields.get(6)?.parse::<u8>().ok()? == 0 {
+        return None;
+    }
+    Some((fields.get(7)?.parse().ok()?, fields.get(9)?.parse().ok()))
+}
+
+// ddmm.mmmm or dddmm.mmmm with its hemisphere, to signed degrees
+fn coordinate(value: &str, hemisphere: &str, degree_digits: usize) -> Option<f64> {
+    let degrees: f64 = value.get(..degree_digits)?.parse().ok()?;
+    let minutes: f64 = value.get(degree_digits..)?.parse().ok()?;
+    let coordinate = degrees + minutes / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(coordinate),
+        "S" | "W" => Some(-coordinate),
+        _ => None,
+    }
+}
+
+// hhmmss.ss and ddmmyy, UTC, to unix time
+fn unix_time(time: &str, date: &str) -> Option<u64> {
+    let part = |text: &str, at: usize| text.get(at..at + 2)?.parse::<u64>().ok();
+    let (hours, minutes, seconds) = (part(time, 0)?, part(time, 2)?, part(time, 4)?);
+    let (day, month, year) = (part(date, 0)?, part(date, 2)?, 2000 + part(date, 4)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since 1970-01-01 of the civil date, March-based years put the leap day last
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some(days * 86_400 + hours * 3600 + minutes * 60 + seconds)
+}
@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::Ets;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, Output, PinDriver};
+use esp_idf_svc::hal::interrupt;
+
+// HX711 load cell amplifier, channel A at gain 128. It has no bus: the chip pulls DOUT low once
+// a conversion is ready, 10 a second with RATE tied low, and the 24 bits are clocked out on
+// PD_SCK. One more pulse selects the gain for the next conversion, holding PD_SCK high for over
+// 60 µs powers the chip down, so the bits go out with interrupts off.
+
+// 10 samples a second, a spare one to be safe
+const READY_TIMEOUT: Duration = Duration::from_millis(200);
+const READY_POLL: Duration = Duration::from_millis(5);
+const TARE_SAMPLES: usize = 16;
+
+pub struct Hx711 {
+    dout: PinDriver<'static, AnyIOPin, Input>,
+    sck: PinDriver<'static, AnyIOPin, Output>,
+    // Raw reading of the empty platform
+    offset: i32,
+    counts_per_gram: f32,
+}
+
+impl Hx711 {
+    // Determine `counts_per_gram` once per load cell: the raw reading with a known weight on,
+    // less the empty one, divided by the weight
+    pub fn new(dout: AnyIOPin, sck: AnyIOPin, counts_per_gram: f32) -> Result<Self, String> {
+        let dout = PinDriver::input(dout).map_err(|e| format!("HX711 DOUT: {:?}", e))?;
+        let mut sck = PinDriver::output(sck).map_err(|e| format!("HX711 PD_SCK: {:?}", e))?;
+        sck.set_low().map_err(|e| format!("HX711 PD_SCK: {:?}", e))?;
+        Ok(Self {
+            dout,
+            sck,
+            offset: 0,
+            counts_per_gram,
+        })
+    }
+
+    // Takes the current load as zero, the platform has to be empty
+    pub fn tare(&mut self) -> Result<(), String> {
+        self.offset = self.average(TARE_SAMPLES)?;
+        Ok(())
+    }
+
+    // The load on the platform, averaged over `samples` conversions
+    pub fn grams(&mut self, samples: usize) -> Result<f32, String> {
+        let raw = self.average(samples)?;
+        Ok((raw - self.offset) as f32 / self.counts_per_gram)
+    }
+
+    fn average(&mut self, samples: usize) -> Result<i32, String> {
+        let mut sum = 0i64;
+        for _ in 0..samples.max(1) {
+            sum += self.read()? as i64;
+        }
+        Ok((sum / samples.max(1) as i64) as i32)
+    }
+
+    fn read(&mut self) -> Result<i32, String> {
+        let deadline = Instant::now() + READY_TIMEOUT;
+        while self.dout.is_high() {
+            if Instant::now() >= deadline {
+                return Err("HX711 not ready, check the wiring".to_string());
+            }
+            std::thread::sleep(READY_POLL);
+        }
+
+        let (dout, sck) = (&self.dout, &mut self.sck);
+        let raw = interrupt::free(|| {
+            let mut raw = 0u32;
+            for bit in 0..25 {
+                let _ = sck.set_high();
+                Ets::delay_us(1);
+                // The 25th pulse only sets the gain
+                if bit < 24 {
+                    raw = (raw << 1) | dout.is_high() as u32;
+                }
+                let _ = sck.set_low();
+                Ets::delay_us(1);
+            }
+            raw
+        });
+        // Two's complement in 24 bits
+        Ok(((raw << 8) as i32) >> 8)
+    }
+}
@@ -0,0 +1,41 @@
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Message;
+
+use resp32sol_core::inspect::{self as core_inspect, CompiledInstruction, TokenTransfer};
+
+// The decoding lives in resp32sol-core, where it is tested on the host. It reads its own copy
+// of the message, since solana-transaction's Message needs std.
+
+pub fn view(message: &Message) -> core_inspect::Message {
+    core_inspect::Message {
+        account_keys: message.account_keys.clone(),
+        instructions: message
+            .instructions
+            .iter()
+            .map(|instruction| CompiledInstruction {
+                program_id_index: instruction.program_id_index,
+                accounts: instruction.accounts.clone(),
+                data: instruction.data.clone(),
+            })
+            .collect(),
+    }
+}
+
+// Programs invoked by the message's top level instructions
+pub fn invoked_programs(message: &Message) -> Vec<Pubkey> {
+    core_inspect::invoked_programs(&view(message))
+}
+
+// Total lamports the message moves out of `from` through system program instructions
+pub fn outgoing_lamports(message: &Message, from: &Pubkey) -> u64 {
+    core_inspect::outgoing_lamports(&view(message), from)
+}
+
+// Whether `signer` takes part in any token program instruction
+pub fn authorizes_tokens(message: &Message, signer: &Pubkey) -> bool {
+    core_inspect::authorizes_tokens(&view(message), signer)
+}
+
+pub fn token_transfers(message: &Message) -> Vec<TokenTransfer> {
+    core_inspect::token_transfers(&view(message))
+}
@@ -0,0 +1,248 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::rmt::config::ReceiveConfig;
+use esp_idf_svc::hal::rmt::{PinState, Pulse, Receive, RxRmtDriver};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+use solana_system_interface::instruction as system_instruction;
+use solana_transaction::Transaction;
+
+use crate::amount::Sol;
+#[cfg(feature = "oled-display")]
+use crate::display;
+use crate::signer::TxSigner;
+use crate::solrpc::{get_balance, get_latest_blockhash, send_transaction};
+
+// Buttons on an NEC infrared remote, received through a 38 kHz demodulating receiver (VS1838B,
+// TSOP38238 and the like) whose output idles high and pulls low for each burst. The RMT
+// peripheral times the bursts in microseconds. A frame is a 9 ms burst, a 4.5 ms gap, then 32
+// bits LSB first: address, inverted address, command, inverted command. Each bit is a 560 µs
+// burst followed by a 560 µs gap for 0 or a 1690 µs gap for 1. A held button sends short repeat
+// frames instead, which are ignored so holding a button never pays twice.
+
+// One microsecond per tick from the 80 MHz APB clock
+const CLOCK_DIVIDER: u8 = 80;
+// A frame ends once the line stays idle this long, the gap to a repeat frame is longer
+const IDLE_THRESHOLD_US: u16 = 12_000;
+// Items in the ring buffer, a frame takes 34
+const RING_BUFFER_ITEMS: usize = 256;
+// One frame at most, as much as an RMT memory block holds
+const FRAME_ITEMS: usize = 64;
+const LEADER_BURST_US: u32 = 9_000;
+const LEADER_GAP_US: u32 = 4_500;
+const BIT_BURST_US: u32 = 560;
+const ZERO_GAP_US: u32 = 560;
+const ONE_GAP_US: u32 = 1_690;
+// Receivers stretch and shorten bursts, remotes run on cheap resonators
+const TOLERANCE_PERCENT: u32 = 25;
+// Buttons pressed faster than the main loop handles them only get logged
+const MAX_QUEUED: usize = 4;
+const IR_STACK_SIZE: usize = 4 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum IrAction {
+    // A fixed payment from the device key, past the same approval and spending policy as any other
+    Pay { recipient: Pubkey, lamports: u64 },
+    // Logs the balance and brings the status screen back with it refreshed
+    ShowBalance,
+    // Blanks the status display or lights it again
+    ToggleDisplay,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IrButton {
+    // 8-bit addresses are sent with their inverse, as 0x00 for the common 21-key remotes
+    pub address: u16,
+    pub command: u8,
+    pub action: IrAction,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IrConfig {
+    // Codes without a button here are logged, which is how a remote's codes are found
+    pub buttons: &'static [IrButton],
+    // The same code again within this long is taken as the same press
+    pub repeat_guard: Duration,
+    // Payments from the remote are this far apart at least, whoever holds one of the same model
+    // can press its buttons
+    pub pay_interval: Duration,
+}
+
+struct Handler {
+    config: IrConfig,
+    actions: Receiver<IrAction>,
+    paid: Option<Instant>,
+}
+
+static HANDLER: Mutex<Option<Handler>> = Mutex::new(None);
+
+// The first RMT channel able to receive: the ESP32-C3 and ESP32-C6 receive on channels 2 and 3,
+// the ESP32-S3 on 4 to 7, the classic ESP32 on any
+#[cfg(esp32s3)]
+pub type RxChannel = esp_idf_svc::hal::rmt::CHANNEL4;
+#[cfg(not(esp32s3))]
+pub type RxChannel = esp_idf_svc::hal::rmt::CHANNEL2;
+
+// The receiver's output on `pin`, through the chip's RxChannel
+pub fn start(channel: RxChannel, pin: AnyIOPin, config: IrConfig) -> Result<(), String> {
+    let rmt_config = ReceiveConfig::new()
+        .clock_divider(CLOCK_DIVIDER)
+        .idle_threshold(IDLE_THRESHOLD_US);
+    let rx = RxRmtDriver::new(channel, pin, &rmt_config, RING_BUFFER_ITEMS).map_err(|e| format!("RMT init: {:?}", e))?;
+    rx.start().map_err(|e| format!("RMT start: {:?}", e))?;
+
+    let (actions, received) = sync_channel(MAX_QUEUED);
+    std::thread::Builder::new()
+        .name("ir".to_string())
+        .stack_size(IR_STACK_SIZE)
+        .spawn(move || run(rx, config, actions))
+        .map_err(|e| format!("IR thread: {:?}", e))?;
+
+    *HANDLER.lock().unwrap() = Some(Handler {
+        config,
+        actions: received,
+        paid: None,
+    });
+    info!("IR receiver up with {} buttons", config.buttons.len());
+    Ok(())
+}
+
+// Carries out the buttons pressed since the last call, called from the main loop which holds the
+// signer
+pub fn handle_due(signer: &impl TxSigner) {
+    let mut handler = HANDLER.lock().unwrap();
+    let Some(handler) = handler.as_mut() else {
+        return;
+    };
+    loop {
+        let action = match handler.actions.try_recv() {
+            Ok(action) => action,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {
+                warn!("IR receiver stopped");
+                return;
+            }
+        };
+        if let Err(e) = handler.act(action, signer) {
+            warn!("IR {:?} failed: {}", action, e);
+        }
+    }
+}
+
+impl Handler {
+    fn act(&mut self, action: IrAction, signer: &impl TxSigner) -> Result<(), String> {
+        match action {
+            IrAction::Pay { recipient, lamports } => {
+                if self.paid.is_some_and(|paid| paid.elapsed() < self.config.pay_interval) {
+                    return Err(format!("last payment less than {}s ago", self.config.pay_interval.as_secs()));
+                }
+                self.paid = Some(Instant::now());
+                let from_pubkey = signer.pubkey();
+                let instruction = system_instruction::transfer(&from_pubkey, &recipient, lamports);
+                info!("IR button: paying {} to {}", Sol(lamports), recipient);
+                let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+                let mut transaction = Transaction::new_with_payer(&[instruction], Some(&from_pubkey));
+                signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+                let signature = send_transaction(&transaction).map_err(|e| e.to_string())?;
+                info!("Payment sent: {}", signature);
+            }
+            IrAction::ShowBalance => {
+                let lamports = get_balance(&signer.pubkey()).map_err(|e| e.to_string())?;
+                info!("Balance of {}: {}", signer.pubkey(), Sol(lamports));
+                #[cfg(feature = "oled-display")]
+                display::show_balance();
+            }
+            IrAction::ToggleDisplay => {
+                #[cfg(feature = "oled-display")]
+                display::toggle_blank();
+                #[cfg(not(feature = "oled-display"))]
+                return Err("no status display".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn run(mut rx: RxRmtDriver<'static>, config: IrConfig, actions: SyncSender<IrAction>) {
+    let mut items = [(Pulse::zero(), Pulse::zero()); FRAME_ITEMS];
+    let mut last: Option<(u16, u8, Instant)> = None;
+    loop {
+        let len = match rx.receive(&mut items, BLOCK) {
+            Ok(Receive::Read(len)) => len,
+            Ok(Receive::Overflow(_)) | Ok(Receive::Timeout) => continue,
+            Err(e) => {
+                warn!("IR receive: {:?}", e);
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        // Repeat frames and noise, e.g. from lamps and sunlight
+        let Some((address, command)) = decode(&items[..len]) else {
+            continue;
+        };
+        if last.is_some_and(|(a, c, at)| (a, c) == (address, command) && at.elapsed() < config.repeat_guard) {
+            last = Some((address, command, Instant::now()));
+            continue;
+        }
+        last = Some((address, command, Instant::now()));
+
+        let Some(button) = config
+            .buttons
+            .iter()
+            .find(|button| button.address == address && button.command == command)
+        else {
+            info!("IR code address 0x{:04X} command 0x{:02X} has no action", address, command);
+            continue;
+        };
+        info!("IR button 0x{:02X}: {:?}", command, button.action);
+        match actions.try_send(button.action) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("IR buttons queued up, {:?} dropped", button.action),
+            Err(TrySendError::Disconnected(_)) => return,
+        }
+    }
+}
+
+// Address and command of a full NEC frame, 16-bit extended addresses included
+fn decode(items: &[(Pulse, Pulse)]) -> Option<(u16, u8)> {
+    let (leader, bits) = items.split_first()?;
+    if !is_burst(&leader.0, LEADER_BURST_US) || !is_gap(&leader.1, LEADER_GAP_US) || bits.len() < 32 {
+        return None;
+    }
+    let mut frame = 0u32;
+    for (index, (burst, gap)) in bits[..32].iter().enumerate() {
+        if !is_burst(burst, BIT_BURST_US) {
+            return None;
+        }
+        if is_gap(gap, ONE_GAP_US) {
+            frame |= 1 << index;
+        } else if !is_gap(gap, ZERO_GAP_US) {
+            return None;
+        }
+    }
+    let [address_low, address_high, command, inverted] = frame.to_le_bytes();
+    if command != !inverted {
+        return None;
+    }
+    let address = match address_high == !address_low {
+        true => address_low as u16,
+        false => u16::from_le_bytes([address_low, address_high]),
+    };
+    Some((address, command))
+}
+
+fn is_burst(pulse: &Pulse, us: u32) -> bool {
+    pulse.pin_state == PinState::Low && near(pulse.ticks.ticks() as u32, us)
+}
+
+fn is_gap(pulse: &Pulse, us: u32) -> bool {
+    pulse.pin_state == PinState::High && near(pulse.ticks.ticks() as u32, us)
+}
+
+fn near(ticks: u32, us: u32) -> bool {
+    ticks.abs_diff(us) <= us * TOLERANCE_PERCENT / 100
+}
@@ -0,0 +1,688 @@
+use esp_idf_svc::nvs::{
+    EspDefaultNvsPartition, EspEncryptedNvsPartition, EspNvs, NvsDefault, NvsEncrypted,
+};
+use log::{info, warn};
+use solana_keypair::{Keypair, Signer};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::{Hash, Message, Signature, Transaction};
+use zeroize::Zeroizing;
+
+use crate::error::WalletError;
+
+// Partition names must match partitions.csv
+const ENCRYPTED_PARTITION: &str = "nvs_enc";
+const KEYS_PARTITION: &str = "nvs_key";
+
+const KEYSTORE_NAMESPACE: &str = "keystore";
+const DEVICE_KEY: &str = "device_key";
+// Replacement device key persisted while a rotation is in flight
+const PENDING_ROTATION_KEY: &str = "rot_pending";
+
+// Named keys are stored as "k.<name>" (seed) and "p.<name>" (policy), NVS keys are limited to 15 chars
+const NAMED_SEED_PREFIX: &str = "k.";
+const NAMED_POLICY_PREFIX: &str = "p.";
+const NAMES_INDEX: &str = "names";
+const MAX_NAME_LEN: usize = 13;
+// The index is read back into a fixed buffer, including the NUL terminator
+const MAX_NAMES_INDEX_LEN: usize = 256;
+
+// Fleet provisioning state: the RPC endpoint (which usually embeds the provider's API key)
+// and the flag set by `seal`
+const RPC_URL_ENTRY: &str = "rpc_url";
+const MAX_RPC_URL_LEN: usize = 256;
+const SEALED_ENTRY: &str = "sealed";
+
+const SEED_LEN: usize = 32;
+const KEYPAIR_LEN: usize = 64;
+
+// Every copy of a seed outside the Keypair (which wipes itself on drop) lives in one of these
+type Seed = Zeroizing<[u8; SEED_LEN]>;
+
+extern "C" {
+    // Provided by the bootloader_support component, not part of the generated bindings
+    fn esp_flash_encryption_enabled() -> bool;
+}
+
+pub fn flash_encryption_enabled() -> bool {
+    unsafe { esp_flash_encryption_enabled() }
+}
+
+// The encrypted partition can only be taken once per boot, the keystore and the PIN gate
+// each open their own namespace in it
+pub fn take_encrypted_partition() -> Option<EspEncryptedNvsPartition> {
+    match EspEncryptedNvsPartition::take(ENCRYPTED_PARTITION, Some(KEYS_PARTITION)) {
+        Ok(partition) => Some(partition),
+        Err(e) => {
+            warn!("Encrypted NVS partition unavailable: {:?}", e);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SecurityState {
+    pub flash_encryption: bool,
+    pub nvs_encryption: bool,
+}
+
+impl SecurityState {
+    // NVS encryption keys live in the nvs_key partition, which is only protected
+    // when flash encryption is on, so both are needed for secrets to be safe at rest
+    pub fn is_secure(&self) -> bool {
+        self.flash_encryption && self.nvs_encryption
+    }
+}
+
+// What a named key may be used for, so e.g. a leaked telemetry key can't move funds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPolicy {
+    pub sign_transactions: bool,
+    pub sign_messages: bool,
+}
+
+impl KeyPolicy {
+    pub const PAYMENTS: Self = Self {
+        sign_transactions: true,
+        sign_messages: false,
+    };
+    pub const TELEMETRY: Self = Self {
+        sign_transactions: false,
+        sign_messages: true,
+    };
+    pub const ADMIN: Self = Self {
+        sign_transactions: true,
+        sign_messages: true,
+    };
+
+    fn to_bits(self) -> u8 {
+        (self.sign_transactions as u8) | ((self.sign_messages as u8) << 1)
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            sign_transactions: bits & 0b01 != 0,
+            sign_messages: bits & 0b10 != 0,
+        }
+    }
+}
+
+// A purpose key loaded from the keystore, signing is only possible within its policy
+pub struct NamedKey {
+    name: String,
+    policy: KeyPolicy,
+    keypair: Keypair,
+}
+
+impl NamedKey {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn policy(&self) -> KeyPolicy {
+        self.policy
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        self.keypair.pubkey()
+    }
+
+    pub fn sign_transaction(&self, transaction: &mut Transaction, blockhash: Hash) -> Result<(), WalletError> {
+        if !self.policy.sign_transactions {
+            return Err(WalletError::Refused(format!("Key '{}' is not allowed to sign transactions", self.name)));
+        }
+
+        transaction
+            .try_sign(&[&self.keypair], blockhash)
+            .map_err(|e| WalletError::Backend(format!("Sign: {:?}", e)))
+    }
+
+    pub fn sign_message(&self, message: &[u8]) -> Result<Signature, WalletError> {
+        if !self.policy.sign_messages {
+            return Err(WalletError::Refused(format!("Key '{}' is not allowed to sign messages", self.name)));
+        }
+
+        Ok(self.keypair.sign_message(message))
+    }
+}
+
+// The trait signs Solana messages, so it goes by the transaction policy. The inherent
+// sign_message above is the one for off-chain messages.
+impl crate::signer::TxSigner for NamedKey {
+    fn pubkey(&self) -> Pubkey {
+        NamedKey::pubkey(self)
+    }
+
+    fn sign_message(&self, message: &Message) -> Result<Signature, WalletError> {
+        if !self.policy.sign_transactions {
+            return Err(WalletError::Refused(format!("Key '{}' is not allowed to sign transactions", self.name)));
+        }
+
+        Ok(Signer::sign_message(&self.keypair, &message.serialize()))
+    }
+}
+
+enum Backend {
+    Encrypted(EspNvs<NvsEncrypted>),
+    Plaintext(EspNvs<NvsDefault>),
+}
+
+pub struct Keystore {
+    backend: Backend,
+    security: SecurityState,
+    allow_plaintext: bool,
+}
+
+impl Keystore {
+    // Opens the encrypted keystore when available, otherwise the plaintext default partition.
+    // Writing secrets to plaintext storage is refused unless `allow_plaintext` is set.
+    pub fn open(
+        nvs: EspDefaultNvsPartition,
+        encrypted: Option<EspEncryptedNvsPartition>,
+        allow_plaintext: bool,
+    ) -> Result<Self, WalletError> {
+        let flash_encryption = flash_encryption_enabled();
+
+        let encrypted = encrypted
+            .map(|partition| EspNvs::new(partition, KEYSTORE_NAMESPACE, true))
+            .transpose()
+            .map_err(|e| WalletError::Storage(format!("Encrypted NVS open: {:?}", e)))?;
+
+        let security = SecurityState {
+            flash_encryption,
+            nvs_encryption: encrypted.is_some(),
+        };
+        info!(
+            "Keystore security: flash encryption {}, NVS encryption {}",
+            security.flash_encryption, security.nvs_encryption
+        );
+
+        let mut plaintext = EspNvs::new(nvs, KEYSTORE_NAMESPACE, true)
+            .map_err(|e| WalletError::Storage(format!("NVS open: {:?}", e)))?;
+
+        let backend = match encrypted {
+            Some(mut encrypted) => {
+                migrate_plaintext(&mut plaintext, &mut encrypted)?;
+                Backend::Encrypted(encrypted)
+            }
+            None => Backend::Plaintext(plaintext),
+        };
+
+        if !security.is_secure() {
+            warn!("Keystore is not protected by flash encryption");
+        }
+
+        Ok(Self {
+            backend,
+            security,
+            allow_plaintext,
+        })
+    }
+
+    pub fn security(&self) -> SecurityState {
+        self.security
+    }
+
+    pub fn load(&self) -> Result<Option<Keypair>, WalletError> {
+        let mut seed = Seed::default();
+        let found = self.read_seed(DEVICE_KEY, &mut seed)?;
+
+        Ok(found.then(|| Keypair::new_from_array(*seed)))
+    }
+
+    pub fn store(&mut self, keypair: &Keypair) -> Result<(), WalletError> {
+        self.write_seed(DEVICE_KEY, keypair.secret_bytes())
+    }
+
+    pub fn load_named(&self, name: &str) -> Result<Option<NamedKey>, WalletError> {
+        validate_name(name)?;
+
+        let mut seed = Seed::default();
+        if !self.read_seed(&named_entry(NAMED_SEED_PREFIX, name), &mut seed)? {
+            return Ok(None);
+        }
+
+        let policy_entry = named_entry(NAMED_POLICY_PREFIX, name);
+        let bits = match &self.backend {
+            Backend::Encrypted(nvs) => nvs.get_u8(&policy_entry),
+            Backend::Plaintext(nvs) => nvs.get_u8(&policy_entry),
+        }
+        .map_err(|e| WalletError::Storage(format!("Policy read: {:?}", e)))?
+        .ok_or_else(|| WalletError::Storage(format!("Key '{}' has no usage policy", name)))?;
+
+        Ok(Some(NamedKey {
+            name: name.to_string(),
+            policy: KeyPolicy::from_bits(bits),
+            keypair: Keypair::new_from_array(*seed),
+        }))
+    }
+
+    pub fn store_named(&mut self, name: &str, keypair: &Keypair, policy: KeyPolicy) -> Result<(), WalletError> {
+        validate_name(name)?;
+
+        // Check the index has room before writing a seed it couldn't list
+        let mut names = self.names()?;
+        let indexed = names.iter().any(|n| n == name);
+        if !indexed {
+            names.push(name.to_string());
+            check_names_len(&names)?;
+        }
+
+        self.write_seed(&named_entry(NAMED_SEED_PREFIX, name), keypair.secret_bytes())?;
+
+        let policy_entry = named_entry(NAMED_POLICY_PREFIX, name);
+        match &self.backend {
+            Backend::Encrypted(nvs) => nvs.set_u8(&policy_entry, policy.to_bits()),
+            Backend::Plaintext(nvs) => nvs.set_u8(&policy_entry, policy.to_bits()),
+        }
+        .map_err(|e| WalletError::Storage(format!("Policy store: {:?}", e)))?;
+
+        if !indexed {
+            self.write_names(&names)?;
+        }
+
+        Ok(())
+    }
+
+    // Loads a named key, generating it with the given policy if it doesn't exist yet
+    pub fn load_or_generate_named(&mut self, name: &str, policy: KeyPolicy) -> Result<NamedKey, WalletError> {
+        if let Some(key) = self.load_named(name)? {
+            return Ok(key);
+        }
+
+        let keypair = Keypair::new();
+        self.store_named(name, &keypair, policy)?;
+        info!("Generated and stored new '{}' key", name);
+
+        Ok(NamedKey {
+            name: name.to_string(),
+            policy,
+            keypair,
+        })
+    }
+
+    // Installs a solana-keygen keyfile, as the device key when `name` is None.
+    // An installed key is only overwritten with `replace`, whatever it holds is stranded.
+    pub fn import_keyfile(&mut self, name: Option<&str>, json: &str, replace: bool) -> Result<Pubkey, WalletError> {
+        let keypair = parse_solana_keyfile(json)?;
+
+        let mut seed = Seed::default();
+        match name {
+            Some(name) => {
+                validate_name(name)?;
+                if !replace && self.read_seed(&named_entry(NAMED_SEED_PREFIX, name), &mut seed)? {
+                    return Err(WalletError::Refused(format!(
+                        "Key '{}' already installed, use `import --replace {} <keyfile json>`",
+                        name, name
+                    )));
+                }
+                self.store_named(name, &keypair, KeyPolicy::PAYMENTS)?
+            }
+            None => {
+                if !replace && self.read_seed(DEVICE_KEY, &mut seed)? {
+                    return Err(WalletError::Refused(
+                        "Device key already installed, use `import --replace <keyfile json>`".to_string(),
+                    ));
+                }
+                self.store(&keypair)?
+            }
+        }
+
+        info!("Imported key {} into keystore", keypair.pubkey());
+        Ok(keypair.pubkey())
+    }
+
+    pub fn remove_named(&mut self, name: &str) -> Result<(), WalletError> {
+        validate_name(name)?;
+
+        for entry in [named_entry(NAMED_SEED_PREFIX, name), named_entry(NAMED_POLICY_PREFIX, name)] {
+            match &mut self.backend {
+                Backend::Encrypted(nvs) => nvs.remove(&entry),
+                Backend::Plaintext(nvs) => nvs.remove(&entry),
+            }
+            .map_err(|e| WalletError::Storage(format!("Key remove: {:?}", e)))?;
+        }
+
+        let names: Vec<String> = self.names()?.into_iter().filter(|n| n != name).collect();
+        self.write_names(&names)
+    }
+
+    pub fn names(&self) -> Result<Vec<String>, WalletError> {
+        let mut buf = [0u8; MAX_NAMES_INDEX_LEN];
+        let index = match &self.backend {
+            Backend::Encrypted(nvs) => nvs.get_str(NAMES_INDEX, &mut buf),
+            Backend::Plaintext(nvs) => nvs.get_str(NAMES_INDEX, &mut buf),
+        }
+        .map_err(|e| WalletError::Storage(format!("Key index read: {:?}", e)))?;
+
+        Ok(index
+            .map(|names| {
+                names
+                    .split(',')
+                    .filter(|n| !n.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn write_names(&mut self, names: &[String]) -> Result<(), WalletError> {
+        check_names_len(names)?;
+        let index = names.join(",");
+        match &mut self.backend {
+            Backend::Encrypted(nvs) => nvs.set_str(NAMES_INDEX, &index),
+            Backend::Plaintext(nvs) => nvs.set_str(NAMES_INDEX, &index),
+        }
+        .map_err(|e| WalletError::Storage(format!("Key index write: {:?}", e)))
+    }
+
+    // RPC endpoint injected at provisioning time, stored with the same protection as keys
+    pub fn store_rpc_url(&mut self, url: &str) -> Result<(), WalletError> {
+        if !url.starts_with("https://") {
+            return Err(WalletError::Invalid("RPC URL must start with https://".to_string()));
+        }
+        if url.len() >= MAX_RPC_URL_LEN {
+            return Err(WalletError::Invalid(format!("RPC URL exceeds {} bytes", MAX_RPC_URL_LEN)));
+        }
+        if !self.security.is_secure() && !self.allow_plaintext {
+            return Err(WalletError::Refused("Refusing to store RPC credentials without flash encryption".to_string()));
+        }
+
+        match &mut self.backend {
+            Backend::Encrypted(nvs) => nvs.set_str(RPC_URL_ENTRY, url),
+            Backend::Plaintext(nvs) => nvs.set_str(RPC_URL_ENTRY, url),
+        }
+        .map_err(|e| WalletError::Storage(format!("RPC URL store: {:?}", e)))
+    }
+
+    pub fn load_rpc_url(&self) -> Result<Option<String>, WalletError> {
+        let mut buf = [0u8; MAX_RPC_URL_LEN];
+        let url = match &self.backend {
+            Backend::Encrypted(nvs) => nvs.get_str(RPC_URL_ENTRY, &mut buf),
+            Backend::Plaintext(nvs) => nvs.get_str(RPC_URL_ENTRY, &mut buf),
+        }
+        .map_err(|e| WalletError::Storage(format!("RPC URL read: {:?}", e)))?;
+
+        Ok(url.map(str::to_string))
+    }
+
+    // A sealed device no longer opens the provisioning window, only erasing NVS undoes it
+    pub fn is_sealed(&self) -> Result<bool, WalletError> {
+        match &self.backend {
+            Backend::Encrypted(nvs) => nvs.get_u8(SEALED_ENTRY),
+            Backend::Plaintext(nvs) => nvs.get_u8(SEALED_ENTRY),
+        }
+        .map(|sealed| sealed.is_some_and(|sealed| sealed != 0))
+        .map_err(|e| WalletError::Storage(format!("Seal read: {:?}", e)))
+    }
+
+    pub fn seal(&mut self) -> Result<(), WalletError> {
+        match &self.backend {
+            Backend::Encrypted(nvs) => nvs.set_u8(SEALED_ENTRY, 1),
+            Backend::Plaintext(nvs) => nvs.set_u8(SEALED_ENTRY, 1),
+        }
+        .map_err(|e| WalletError::Storage(format!("Seal store: {:?}", e)))
+    }
+
+    fn read_seed(&self, entry: &str, seed: &mut [u8; SEED_LEN]) -> Result<bool, WalletError> {
+        match &self.backend {
+            Backend::Encrypted(nvs) => read_seed(nvs, entry, seed),
+            Backend::Plaintext(nvs) => read_seed(nvs, entry, seed),
+        }
+    }
+
+    fn write_seed(&mut self, entry: &str, seed: &[u8; SEED_LEN]) -> Result<(), WalletError> {
+        if !self.security.is_secure() && !self.allow_plaintext {
+            return Err(WalletError::Refused("Refusing to store secret key without flash encryption".to_string()));
+        }
+
+        match &mut self.backend {
+            Backend::Encrypted(nvs) => nvs.set_blob(entry, seed),
+            Backend::Plaintext(nvs) => nvs.set_blob(entry, seed),
+        }
+        .map_err(|e| WalletError::Storage(format!("Key store: {:?}", e)))
+    }
+
+    pub fn load_pending_rotation(&self) -> Result<Option<Keypair>, WalletError> {
+        let mut seed = Seed::default();
+        let found = self.read_seed(PENDING_ROTATION_KEY, &mut seed)?;
+
+        Ok(found.then(|| Keypair::new_from_array(*seed)))
+    }
+
+    pub fn store_pending_rotation(&mut self, keypair: &Keypair) -> Result<(), WalletError> {
+        self.write_seed(PENDING_ROTATION_KEY, keypair.secret_bytes())
+    }
+
+    // Replaces the device key with the pending rotation key, overwriting the old secret
+    pub fn commit_rotation(&mut self) -> Result<Keypair, WalletError> {
+        let keypair = self
+            .load_pending_rotation()?
+            .ok_or_else(|| WalletError::Refused("No pending key rotation".to_string()))?;
+
+        self.store(&keypair)?;
+        match &mut self.backend {
+            Backend::Encrypted(nvs) => nvs.remove(PENDING_ROTATION_KEY),
+            Backend::Plaintext(nvs) => nvs.remove(PENDING_ROTATION_KEY),
+        }
+        .map_err(|e| WalletError::Storage(format!("Pending key erase: {:?}", e)))?;
+
+        Ok(keypair)
+    }
+
+    // Loads the stored key, or generates and persists a new one on first boot
+    pub fn load_or_generate(&mut self) -> Result<Keypair, WalletError> {
+        if let Some(keypair) = self.load()? {
+            return Ok(keypair);
+        }
+
+        let keypair = Keypair::new();
+        self.store(&keypair)?;
+        info!("Generated and stored new device key");
+
+        Ok(keypair)
+    }
+
+    pub fn wipe(&mut self) -> Result<(), WalletError> {
+        match &mut self.backend {
+            Backend::Encrypted(nvs) => nvs.remove(DEVICE_KEY),
+            Backend::Plaintext(nvs) => nvs.remove(DEVICE_KEY),
+        }
+        .map(|_| ())
+        .map_err(|e| WalletError::Storage(format!("Key wipe: {:?}", e)))
+    }
+
+    // Erases every secret in the keystore: device key, pending rotation key, all named keys and
+    // the RPC credentials.
+    // Keeps going past failed entries so one bad entry doesn't leave the rest behind.
+    // An unreadable index is reported as a failure, its named seeds can't be found to erase.
+    pub fn wipe_all(&mut self) -> Result<(), WalletError> {
+        let mut failed = Vec::new();
+        let mut entries = vec![DEVICE_KEY.to_string(), PENDING_ROTATION_KEY.to_string()];
+        match self.names() {
+            Ok(names) => {
+                for name in names {
+                    entries.push(named_entry(NAMED_SEED_PREFIX, &name));
+                    entries.push(named_entry(NAMED_POLICY_PREFIX, &name));
+                }
+                entries.push(NAMES_INDEX.to_string());
+            }
+            // Keep the index so a later wipe can still find the named seeds
+            Err(e) => failed.push(format!("{}: {}", NAMES_INDEX, e)),
+        }
+        entries.push(RPC_URL_ENTRY.to_string());
+
+        for entry in entries {
+            let removed = match &mut self.backend {
+                Backend::Encrypted(nvs) => nvs.remove(&entry),
+                Backend::Plaintext(nvs) => nvs.remove(&entry),
+            };
+            if let Err(e) = removed {
+                failed.push(format!("{}: {:?}", entry, e));
+            }
+        }
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(WalletError::Storage(format!("Key wipe failed for {}", failed.join(", ")))),
+        }
+    }
+}
+
+// Upgrade path for firmware that stored keys in the plaintext partition:
+// copy them into the encrypted partition, verify the copy, then erase the plaintext entries
+fn migrate_plaintext(
+    plaintext: &mut EspNvs<NvsDefault>,
+    encrypted: &mut EspNvs<NvsEncrypted>,
+) -> Result<(), WalletError> {
+    migrate_seed(plaintext, encrypted, DEVICE_KEY)?;
+
+    let mut buf = [0u8; MAX_NAMES_INDEX_LEN];
+    let names = match plaintext
+        .get_str(NAMES_INDEX, &mut buf)
+        .map_err(|e| WalletError::Storage(format!("Key index read: {:?}", e)))?
+    {
+        Some(names) => names.to_string(),
+        None => return Ok(()),
+    };
+
+    for name in names.split(',').filter(|n| !n.is_empty()) {
+        migrate_seed(plaintext, encrypted, &named_entry(NAMED_SEED_PREFIX, name))?;
+
+        let policy_entry = named_entry(NAMED_POLICY_PREFIX, name);
+        if let Some(bits) = plaintext
+            .get_u8(&policy_entry)
+            .map_err(|e| WalletError::Storage(format!("Policy read: {:?}", e)))?
+        {
+            encrypted
+                .set_u8(&policy_entry, bits)
+                .map_err(|e| WalletError::Storage(format!("Policy migrate: {:?}", e)))?;
+            plaintext
+                .remove(&policy_entry)
+                .map_err(|e| WalletError::Storage(format!("Plaintext policy erase: {:?}", e)))?;
+        }
+    }
+
+    // Keys stored encrypted before the migration stay in the index
+    let mut buf = [0u8; MAX_NAMES_INDEX_LEN];
+    let mut merged: Vec<&str> = encrypted
+        .get_str(NAMES_INDEX, &mut buf)
+        .map_err(|e| WalletError::Storage(format!("Key index read: {:?}", e)))?
+        .map(|index| index.split(',').filter(|n| !n.is_empty()).collect())
+        .unwrap_or_default();
+    for name in names.split(',').filter(|n| !n.is_empty()) {
+        if !merged.contains(&name) {
+            merged.push(name);
+        }
+    }
+    let merged = merged.join(",");
+    if merged.len() >= buf.len() {
+        return Err(WalletError::Storage("Key index too long to migrate".to_string()));
+    }
+
+    encrypted
+        .set_str(NAMES_INDEX, &merged)
+        .map_err(|e| WalletError::Storage(format!("Key index migrate: {:?}", e)))?;
+    plaintext
+        .remove(NAMES_INDEX)
+        .map_err(|e| WalletError::Storage(format!("Plaintext index erase: {:?}", e)))?;
+
+    Ok(())
+}
+
+fn migrate_seed(
+    plaintext: &mut EspNvs<NvsDefault>,
+    encrypted: &mut EspNvs<NvsEncrypted>,
+    entry: &str,
+) -> Result<(), WalletError> {
+    let mut seed = Seed::default();
+    if !read_seed(plaintext, entry, &mut seed)? {
+        return Ok(());
+    }
+
+    info!("Migrating plaintext key '{}' into encrypted NVS", entry);
+
+    let mut existing = Seed::default();
+    if read_seed(encrypted, entry, &mut existing)? {
+        if *existing != *seed {
+            return Err(WalletError::Storage(format!(
+                "Plaintext and encrypted '{}' keys differ, refusing to migrate",
+                entry
+            )));
+        }
+    } else {
+        encrypted
+            .set_blob(entry, seed.as_slice())
+            .map_err(|e| WalletError::Storage(format!("Key migrate: {:?}", e)))?;
+
+        if !read_seed(encrypted, entry, &mut existing)? || *existing != *seed {
+            return Err(WalletError::Storage("Encrypted key verification failed after migration".to_string()));
+        }
+    }
+
+    plaintext
+        .remove(entry)
+        .map_err(|e| WalletError::Storage(format!("Plaintext key erase: {:?}", e)))?;
+
+    info!("Key '{}' migrated, plaintext copy erased", entry);
+    Ok(())
+}
+
+// Parses the 64-byte JSON array written by `solana-keygen new`, checking that the
+// embedded pubkey half matches the one derived from the secret half
+pub fn parse_solana_keyfile(json: &str) -> Result<Keypair, WalletError> {
+    let bytes: Zeroizing<Vec<u8>> = Zeroizing::new(
+        serde_json::from_str(json.trim()).map_err(|e| WalletError::Invalid(format!("Keyfile parse: {:?}", e)))?,
+    );
+
+    if bytes.len() != KEYPAIR_LEN {
+        return Err(WalletError::Invalid(format!(
+            "Keyfile must contain {} bytes, got {}",
+            KEYPAIR_LEN,
+            bytes.len()
+        )));
+    }
+
+    Keypair::try_from(bytes.as_slice())
+        .map_err(|_| WalletError::Invalid("Keyfile pubkey does not match its secret key".to_string()))
+}
+
+fn validate_name(name: &str) -> Result<(), WalletError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(WalletError::Invalid(format!("Key name must be 1-{} characters", MAX_NAME_LEN)));
+    }
+    if !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return Err(WalletError::Invalid("Key name may only contain letters, digits and '_'".to_string()));
+    }
+    Ok(())
+}
+
+fn check_names_len(names: &[String]) -> Result<(), WalletError> {
+    let len = names.iter().map(|n| n.len() + 1).sum::<usize>().saturating_sub(1);
+    match len < MAX_NAMES_INDEX_LEN {
+        true => Ok(()),
+        false => Err(WalletError::Refused(format!(
+            "Key index full, at most {} bytes of names",
+            MAX_NAMES_INDEX_LEN - 1
+        ))),
+    }
+}
+
+fn named_entry(prefix: &str, name: &str) -> String {
+    format!("{}{}", prefix, name)
+}
+
+fn read_seed<T: esp_idf_svc::nvs::NvsPartitionId>(
+    nvs: &EspNvs<T>,
+    name: &str,
+    seed: &mut [u8; SEED_LEN],
+) -> Result<bool, WalletError> {
+    let mut buf = Seed::default();
+    match nvs.get_blob(name, buf.as_mut_slice()).map_err(|e| WalletError::Storage(format!("Key read: {:?}", e)))? {
+        Some(data) if data.len() == SEED_LEN => {
+            seed.copy_from_slice(data);
+            Ok(true)
+        }
+        Some(data) => Err(WalletError::Storage(format!("Stored key has invalid length {}", data.len()))),
+        None => Ok(false),
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, PinState};
+use esp_idf_svc::hal::rmt::config::TransmitConfig;
+use esp_idf_svc::hal::rmt::{FixedLengthSignal, Pulse, TxRmtDriver, CHANNEL0};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+
+use crate::error::RpcError;
+#[cfg(feature = "low-power")]
+use crate::lowpower;
+use crate::net::{self, NetEvent, NetSubscription};
+use crate::solrpc;
+
+// A single WS2812 ("NeoPixel") that shows what a headless device is doing, driven over the RMT
+// peripheral by a thread of its own. It blinks while connecting, sending or low on funds, and
+// the outcome of a transaction stays lit for a few seconds before it falls back to idle.
+
+// WS2812 bit timings, the RMT counts the 80 MHz APB clock undivided
+const T0H: Duration = Duration::from_nanos(350);
+const T0L: Duration = Duration::from_nanos(800);
+const T1H: Duration = Duration::from_nanos(700);
+const T1L: Duration = Duration::from_nanos(600);
+const SLOW_BLINK: Duration = Duration::from_millis(500);
+const FAST_BLINK: Duration = Duration::from_millis(100);
+const CONFIRMED_HOLD: Duration = Duration::from_secs(3);
+const ERROR_HOLD: Duration = Duration::from_secs(5);
+// Checked while idle, a low balance only needs noticing eventually
+const BALANCE_REFRESH: Duration = Duration::from_secs(5 * 60);
+// Balance lookups go over TLS from this thread
+const LED_STACK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedState {
+    Connecting,
+    Idle,
+    Sending,
+    Confirmed,
+    Error,
+    LowBalance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub u8, pub u8, pub u8);
+
+impl Color {
+    const OFF: Color = Color(0, 0, 0);
+}
+
+// Colors are red, green, blue at 0 to 255. The defaults are dim, at full brightness a WS2812
+// is hard to look at and draws 60 mA.
+#[derive(Debug, Clone, Copy)]
+pub struct LedConfig {
+    pub connecting: Color,
+    pub idle: Color,
+    pub sending: Color,
+    pub confirmed: Color,
+    pub error: Color,
+    pub low_balance: Color,
+    // Idle shows as low balance below this, 0 disables the check
+    pub low_balance_lamports: u64,
+}
+
+impl LedConfig {
+    pub const DEFAULT: LedConfig = LedConfig {
+        connecting: Color(0, 0, 32),
+        idle: Color(0, 6, 0),
+        sending: Color(32, 20, 0),
+        confirmed: Color(0, 48, 0),
+        error: Color(48, 0, 0),
+        low_balance: Color(32, 6, 0),
+        low_balance_lamports: 10_000_000,
+    };
+
+    fn color(&self, state: LedState) -> Color {
+        match state {
+            LedState::Connecting => self.connecting,
+            LedState::Idle => self.idle,
+            LedState::Sending => self.sending,
+            LedState::Confirmed => self.confirmed,
+            LedState::Error => self.error,
+            LedState::LowBalance => self.low_balance,
+        }
+    }
+}
+
+enum Update {
+    Net(NetEvent),
+    Address(Pubkey),
+    Show(LedState),
+}
+
+static UPDATES: Mutex<Option<Sender<Update>>> = Mutex::new(None);
+static SUBSCRIPTION: Mutex<Option<NetSubscription>> = Mutex::new(None);
+
+pub fn start(channel0: CHANNEL0, pin: AnyIOPin, config: LedConfig) -> Result<(), String> {
+    let rmt = TxRmtDriver::new(channel0, pin, &TransmitConfig::new().clock_divider(1))
+        .map_err(|e| format!("RMT init: {:?}", e))?;
+    let mut led = Ws2812::new(rmt)?;
+    led.set(config.connecting)?;
+
+    let (updates, received) = channel();
+    std::thread::Builder::new()
+        .name("status-led".to_string())
+        .stack_size(LED_STACK_SIZE)
+        .spawn(move || run(led, config, received))
+        .map_err(|e| format!("Status LED thread: {:?}", e))?;
+
+    *UPDATES.lock().unwrap() = Some(updates.clone());
+    if net::link_up() {
+        let _ = updates.send(Update::Net(NetEvent::GotIp));
+    }
+    *SUBSCRIPTION.lock().unwrap() = Some(net::subscribe(move |event| {
+        let _ = updates.send(Update::Net(event));
+    }));
+    info!("Status LED up");
+    Ok(())
+}
+
+// The wallet whose balance is watched for LowBalance
+pub fn set_address(address: Pubkey) {
+    send(Update::Address(address));
+}
+
+pub fn show(state: LedState) {
+    send(Update::Show(state));
+}
+
+// Called with the outcome of every sendTransaction
+pub fn transaction_sent(result: &Result<String, RpcError>) {
+    show(match result {
+        Ok(_) => LedState::Confirmed,
+        Err(_) => LedState::Error,
+    });
+}
+
+fn send(update: Update) {
+    if let Some(updates) = UPDATES.lock().unwrap().as_ref() {
+        let _ = updates.send(update);
+    }
+}
+
+fn run(mut led: Ws2812, config: LedConfig, updates: Receiver<Update>) {
+    let mut online = false;
+    let mut address: Option<Pubkey> = None;
+    let mut low_balance = false;
+    let mut balance_checked: Option<Instant> = None;
+    // What is shown, and until when for the transaction outcomes
+    let mut state = LedState::Connecting;
+    let mut state_until: Option<Instant> = None;
+    let mut lit = true;
+
+    loop {
+        let hold = state_until.map(|until| until.saturating_duration_since(Instant::now()));
+        let idle_wait = BALANCE_REFRESH;
+        // Waits for the next RPC window instead when the refresh is due before it opens
+        #[cfg(feature = "low-power")]
+        let idle_wait = idle_wait.max(lowpower::until_window());
+        let timeout = blink_interval(state).or(hold).unwrap_or(idle_wait);
+        match updates.recv_timeout(timeout) {
+            Ok(Update::Net(event)) => {
+                online = event == NetEvent::GotIp;
+                if online {
+                    balance_checked = None;
+                }
+            }
+            Ok(Update::Address(new_address)) => {
+                address = Some(new_address);
+                balance_checked = None;
+            }
+            Ok(Update::Show(shown)) => {
+                state = shown;
+                state_until = match shown {
+                    LedState::Confirmed => Some(Instant::now() + CONFIRMED_HOLD),
+                    LedState::Error => Some(Instant::now() + ERROR_HOLD),
+                    _ => None,
+                };
+                // A transfer out may be what brought the balance down
+                if shown == LedState::Confirmed {
+                    balance_checked = None;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let balance_due = balance_checked.is_none_or(|checked| checked.elapsed() >= BALANCE_REFRESH);
+        #[cfg(feature = "low-power")]
+        let balance_due = balance_due && lowpower::until_window().is_zero();
+        if let (true, true, Some(address)) = (balance_due, online, address) {
+            if config.low_balance_lamports > 0 {
+                match solrpc::get_balance(&address) {
+                    Ok(lamports) => low_balance = lamports < config.low_balance_lamports,
+                    Err(e) => warn!("Status LED balance check failed: {}", e),
+                }
+            }
+            balance_checked = Some(Instant::now());
+        }
+
+        // Sending lasts until the outcome arrives and the outcome until its hold ends, the
+        // other states follow the link and the balance
+        let transient = match state {
+            LedState::Sending => true,
+            LedState::Confirmed | LedState::Error => state_until.is_some_and(|until| Instant::now() < until),
+            _ => false,
+        };
+        if !transient {
+            state_until = None;
+            state = match (online, low_balance) {
+                (false, _) => LedState::Connecting,
+                (true, true) => LedState::LowBalance,
+                (true, false) => LedState::Idle,
+            };
+        }
+
+        lit = blink_interval(state).is_none() || !lit;
+        let color = match lit {
+            true => config.color(state),
+            false => Color::OFF,
+        };
+        if let Err(e) = led.set(color) {
+            warn!("Status LED update failed: {}", e);
+        }
+    }
+}
+
+fn blink_interval(state: LedState) -> Option<Duration> {
+    match state {
+        LedState::Connecting | LedState::LowBalance => Some(SLOW_BLINK),
+        LedState::Sending => Some(FAST_BLINK),
+        _ => None,
+    }
+}
+
+struct Ws2812 {
+    rmt: TxRmtDriver<'static>,
+    zero: (Pulse, Pulse),
+    one: (Pulse, Pulse),
+}
+
+impl Ws2812 {
+    fn new(rmt: TxRmtDriver<'static>) -> Result<Self, String> {
+        let ticks_hz = rmt.counter_clock().map_err(|e| format!("RMT clock: {:?}", e))?;
+        let pulse = |state, duration| {
+            Pulse::new_with_duration(ticks_hz, state, &duration).map_err(|e| format!("RMT pulse: {:?}", e))
+        };
+        Ok(Self {
+            zero: (pulse(PinState::High, T0H)?, pulse(PinState::Low, T0L)?),
+            one: (pulse(PinState::High, T1H)?, pulse(PinState::Low, T1L)?),
+            rmt,
+        })
+    }
+
+    fn set(&mut self, Color(red, green, blue): Color) -> Result<(), String> {
+        // The LED takes green first, most significant bit first
+        let bits = (green as u32) << 16 | (red as u32) << 8 | blue as u32;
+        let mut signal = FixedLengthSignal::<24>::new();
+        for i in 0..24 {
+            let bit = match bits & (1 << (23 - i)) {
+                0 => &self.zero,
+                _ => &self.one,
+            };
+            signal.set(i, bit).map_err(|e| format!("RMT signal: {:?}", e))?;
+        }
+        self.rmt
+            .start_blocking(&signal)
+            .map_err(|e| format!("RMT send: {:?}", e))
+    }
+}
@@ -0,0 +1,477 @@
+// Solana RPC, signing and device networking for ESP32 boards on ESP-IDF. The firmware in
+// main.rs and the examples build on these modules, other projects can depend on the crate the
+// same way and pick the modules they need.
+
+#[cfg(all(feature = "remote-signer", feature = "watch-only"))]
+compile_error!("`remote-signer` and `watch-only` are mutually exclusive");
+#[cfg(all(feature = "remote-signer", feature = "ble-provisioning"))]
+compile_error!("`ble-provisioning` needs WiFi, which `remote-signer` compiles out");
+#[cfg(all(feature = "ethernet-w5500", feature = "ethernet-rmii"))]
+compile_error!("`ethernet-w5500` and `ethernet-rmii` are mutually exclusive");
+#[cfg(all(feature = "ethernet-rmii", feature = "cellular"))]
+compile_error!("`cellular` uses GPIO0, the RMII clock input");
+#[cfg(all(feature = "espnow-relay", feature = "remote-signer"))]
+compile_error!("`espnow-relay` needs the WiFi radio, which `remote-signer` compiles out");
+#[cfg(all(feature = "pay-button", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`pay-button` sends payments, which needs signing and the network");
+#[cfg(all(feature = "pay-button", any(feature = "cellular", feature = "ethernet-rmii")))]
+compile_error!("`pay-button` uses GPIO0, which `cellular` and `ethernet-rmii` take");
+#[cfg(all(feature = "oled-display", feature = "remote-signer"))]
+compile_error!("`oled-display` shows network and balance state, which `remote-signer` compiles out");
+#[cfg(all(feature = "oled-display", any(feature = "ethernet-w5500", feature = "ethernet-rmii")))]
+compile_error!("`oled-display` uses GPIO5 and GPIO6, which `ethernet-w5500` takes and the classic ESP32 wires to flash");
+#[cfg(all(feature = "status-led", feature = "remote-signer"))]
+compile_error!("`status-led` follows the network and transactions, which `remote-signer` compiles out");
+#[cfg(all(feature = "sensor-log", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`sensor-log` publishes signed memos, which needs signing and the network");
+#[cfg(all(feature = "sensor-log", feature = "ethernet-w5500"))]
+compile_error!("`sensor-log` samples GPIO4, the W5500 interrupt line");
+#[cfg(all(feature = "nfc", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`nfc` pays and checks tapped addresses, which needs signing and the network");
+#[cfg(all(feature = "nfc", feature = "pay-button"))]
+compile_error!("`nfc` and `pay-button` each run the main loop, enable one of them");
+#[cfg(all(feature = "nfc", feature = "oled-display"))]
+compile_error!("`nfc` readers use GPIO5 and GPIO6, the display's I2C bus");
+#[cfg(all(feature = "nfc", any(feature = "ethernet-w5500", feature = "ethernet-rmii")))]
+compile_error!("`nfc` readers use GPIO5 to GPIO7, which `ethernet-w5500` takes and the classic ESP32 wires to flash");
+#[cfg(all(feature = "buzzer", feature = "remote-signer"))]
+compile_error!("`buzzer` sounds transaction and payment events, which `remote-signer` compiles out");
+#[cfg(all(feature = "buzzer", feature = "cellular"))]
+compile_error!("`buzzer` uses GPIO1, the cellular modem's RX line");
+#[cfg(all(feature = "rotary-encoder", feature = "watch-only"))]
+compile_error!("`rotary-encoder` enters amounts to send or ask for, which needs signing and the network");
+#[cfg(all(feature = "rotary-encoder", feature = "pay-button"))]
+compile_error!("`rotary-encoder` feeds the transfer demo or `receive-qr`, which `pay-button` replaces");
+#[cfg(all(feature = "receive-qr", feature = "watch-only"))]
+compile_error!("`receive-qr` asks for payments to the device's own address, which `watch-only` doesn't have");
+#[cfg(all(feature = "receive-qr", feature = "pay-button"))]
+compile_error!("`receive-qr` and `pay-button` each run the main loop, enable one of them");
+#[cfg(all(feature = "battery-monitor", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`battery-monitor` signs its telemetry and low-battery alerts, which needs signing and the network");
+#[cfg(all(feature = "battery-monitor", feature = "ethernet-w5500"))]
+compile_error!("`battery-monitor` measures on GPIO4, the W5500 interrupt line");
+#[cfg(all(feature = "epaper", any(feature = "sensor-log", feature = "battery-monitor")))]
+compile_error!("`epaper` reads the panel's BUSY line on GPIO4, which `sensor-log` and `battery-monitor` sample");
+#[cfg(all(feature = "epaper", feature = "rotary-encoder"))]
+compile_error!("`epaper` drives the panel on GPIO2, GPIO7 and GPIO10, the rotary encoder's pins");
+#[cfg(all(feature = "pay-to-unlock", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`pay-to-unlock` watches for payments to the device's own address, which needs the network and a key");
+#[cfg(all(feature = "pay-to-unlock", any(feature = "pay-button", feature = "nfc", feature = "receive-qr")))]
+compile_error!("`pay-to-unlock` runs the main loop, which `pay-button`, `nfc` and `receive-qr` each replace");
+#[cfg(all(feature = "pay-to-unlock", any(feature = "rotary-encoder", feature = "ethernet-w5500", feature = "epaper")))]
+compile_error!("`pay-to-unlock` drives the relay on GPIO10, which `rotary-encoder`, `ethernet-w5500` and `epaper` use");
+#[cfg(all(feature = "lora-bridge", feature = "remote-signer"))]
+compile_error!("`lora-bridge` relays transactions to and from the network, which `remote-signer` compiles out");
+#[cfg(all(
+    feature = "lora-bridge",
+    any(
+        feature = "oled-display",
+        feature = "nfc",
+        feature = "rotary-encoder",
+        feature = "pay-to-unlock",
+        feature = "ethernet-w5500"
+    )
+))]
+compile_error!("`lora-bridge` drives the radio on GPIO2, GPIO6, GPIO7 and GPIO10, which `oled-display`, `nfc`, `rotary-encoder`, `pay-to-unlock` and `ethernet-w5500` use");
+#[cfg(all(feature = "lora-bridge", any(feature = "ethernet-rmii", feature = "camera")))]
+compile_error!("`lora-bridge` uses GPIO6 and GPIO7, which the classic ESP32 wires to flash");
+#[cfg(all(feature = "can-log", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`can-log` anchors signed memos, which needs signing and the network");
+#[cfg(all(feature = "can-log", any(feature = "ethernet-rmii", feature = "camera")))]
+compile_error!("`can-log` uses GPIO20 and GPIO21, which the classic ESP32 boards behind `ethernet-rmii` and `camera` take or lack");
+#[cfg(all(
+    feature = "sd-log",
+    any(
+        feature = "lora-bridge",
+        feature = "oled-display",
+        feature = "nfc",
+        feature = "rotary-encoder",
+        feature = "pay-to-unlock",
+        feature = "ethernet-w5500"
+    )
+))]
+compile_error!("`sd-log` drives the card on SPI2 over GPIO2, GPIO6, GPIO7 and GPIO10, which `lora-bridge`, `oled-display`, `nfc`, `rotary-encoder`, `pay-to-unlock` and `ethernet-w5500` use");
+#[cfg(all(feature = "sd-log", any(feature = "ethernet-rmii", feature = "camera")))]
+compile_error!("`sd-log` uses GPIO6 and GPIO7, which the classic ESP32 wires to flash");
+#[cfg(all(feature = "cli-console", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`cli-console` sends and checks balances, which needs signing and the network");
+#[cfg(all(
+    feature = "cli-console",
+    any(
+        feature = "pay-button",
+        feature = "nfc",
+        feature = "receive-qr",
+        feature = "pay-to-unlock",
+        feature = "camera"
+    )
+))]
+compile_error!("`cli-console` drives the transfer demo's loop, which `pay-button`, `nfc`, `receive-qr`, `pay-to-unlock` and `camera` each replace");
+#[cfg(all(feature = "usb-wallet", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`usb-wallet` signs and sends for the host, which needs the device key and the network");
+#[cfg(all(feature = "fingerprint", feature = "watch-only"))]
+compile_error!("`fingerprint` approves signatures, which `watch-only` never makes");
+#[cfg(all(
+    feature = "fingerprint",
+    any(feature = "cellular", feature = "pay-button", feature = "buzzer", feature = "ethernet-rmii", feature = "camera")
+))]
+compile_error!("`fingerprint` talks to the module over GPIO0 and GPIO1, which `cellular`, `pay-button`, `buzzer` and the classic ESP32 boards behind `ethernet-rmii` and `camera` use");
+#[cfg(all(feature = "gps-beacon", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`gps-beacon` publishes signed positions, which needs signing and the network");
+#[cfg(all(
+    feature = "gps-beacon",
+    any(feature = "cellular", feature = "fingerprint", feature = "buzzer", feature = "ethernet-rmii", feature = "camera")
+))]
+compile_error!("`gps-beacon` reads the GPS on UART1 over GPIO1, which `cellular`, `fingerprint`, `buzzer` and the classic ESP32 boards behind `ethernet-rmii` and `camera` use");
+#[cfg(all(feature = "vending", feature = "rotary-encoder"))]
+compile_error!("`vending` prices what is put on the scale, the rotary encoder's amounts have no place in it");
+#[cfg(all(
+    feature = "vending",
+    any(
+        feature = "cellular",
+        feature = "buzzer",
+        feature = "fingerprint",
+        feature = "gps-beacon",
+        feature = "ethernet-rmii"
+    )
+))]
+compile_error!("`vending` reads the HX711 on GPIO0 and GPIO1, which `cellular`, `buzzer`, `fingerprint`, `gps-beacon` and the classic ESP32 boards behind `ethernet-rmii` use");
+#[cfg(all(feature = "vending", feature = "epaper"))]
+compile_error!("`vending` drives the dispense output on GPIO10, which `epaper` uses");
+#[cfg(all(feature = "energy-meter", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`energy-meter` charges the payer with transfers the device signs, which needs signing and the network");
+#[cfg(all(
+    feature = "energy-meter",
+    any(
+        feature = "cellular",
+        feature = "pay-button",
+        feature = "buzzer",
+        feature = "fingerprint",
+        feature = "gps-beacon",
+        feature = "vending",
+        feature = "ethernet-rmii",
+        feature = "camera"
+    )
+))]
+compile_error!("`energy-meter` reads the meter on GPIO0 and GPIO1, which `cellular`, `pay-button`, `buzzer`, `fingerprint`, `gps-beacon`, `vending` and the classic ESP32 boards behind `ethernet-rmii` and `camera` use");
+#[cfg(all(
+    feature = "energy-meter",
+    any(
+        feature = "pay-to-unlock",
+        feature = "rotary-encoder",
+        feature = "nfc",
+        feature = "ethernet-w5500",
+        feature = "epaper",
+        feature = "lora-bridge",
+        feature = "sd-log"
+    )
+))]
+compile_error!("`energy-meter` drives the supply relay on GPIO10, which `pay-to-unlock`, `rotary-encoder`, `nfc`, `ethernet-w5500`, `epaper`, `lora-bridge` and `sd-log` use");
+#[cfg(all(feature = "pay-actuator", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`pay-actuator` acts on payments to the device's own address, watched from the main loop `remote-signer` and `watch-only` replace");
+#[cfg(all(
+    feature = "pay-actuator",
+    any(
+        feature = "sensor-log",
+        feature = "battery-monitor",
+        feature = "epaper",
+        feature = "ethernet-w5500",
+        feature = "camera"
+    )
+))]
+compile_error!("`pay-actuator` drives its output on GPIO4, which `sensor-log`, `battery-monitor`, `epaper`, `ethernet-w5500` and the ESP32-CAM behind `camera` use");
+#[cfg(all(feature = "ir-remote", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`ir-remote` buttons act from the main loop, which `remote-signer` and `watch-only` replace");
+#[cfg(all(
+    feature = "ir-remote",
+    any(
+        feature = "sensor-log",
+        feature = "battery-monitor",
+        feature = "epaper",
+        feature = "ethernet-w5500",
+        feature = "pay-actuator",
+        feature = "camera"
+    )
+))]
+compile_error!("`ir-remote` reads the receiver on GPIO4, which `sensor-log`, `battery-monitor`, `epaper`, `ethernet-w5500`, `pay-actuator` and the ESP32-CAM behind `camera` use");
+#[cfg(all(feature = "receipt-printer", any(feature = "remote-signer", feature = "watch-only")))]
+compile_error!("`receipt-printer` prints the payments the device sends and receives, which `remote-signer` and `watch-only` never see");
+#[cfg(all(
+    feature = "receipt-printer",
+    any(
+        feature = "cellular",
+        feature = "pay-button",
+        feature = "fingerprint",
+        feature = "gps-beacon",
+        feature = "vending",
+        feature = "energy-meter",
+        feature = "ethernet-rmii",
+        feature = "camera"
+    )
+))]
+compile_error!("`receipt-printer` talks to the printer on UART1 over GPIO0, which `cellular`, `pay-button`, `fingerprint`, `gps-beacon`, `vending`, `energy-meter` and the classic ESP32 boards behind `ethernet-rmii` and `camera` use");
+#[cfg(all(feature = "camera", any(feature = "watch-only", all(feature = "remote-signer", not(feature = "air-gap")))))]
+compile_error!("`camera` scans codes to pay or, with `air-gap`, transactions to sign");
+#[cfg(all(
+    feature = "camera",
+    any(feature = "pay-button", feature = "nfc", feature = "receive-qr", feature = "pay-to-unlock")
+))]
+compile_error!("`camera` runs the main loop, which `pay-button`, `nfc`, `receive-qr` and `pay-to-unlock` each replace");
+#[cfg(all(
+    feature = "camera",
+    any(
+        feature = "oled-display",
+        feature = "status-led",
+        feature = "sensor-log",
+        feature = "battery-monitor",
+        feature = "buzzer",
+        feature = "cellular",
+        feature = "ethernet-w5500",
+        feature = "ethernet-rmii"
+    )
+))]
+compile_error!("`camera` leaves the ESP32-CAM no free pins for displays, LEDs, sensors, buzzers or other uplinks");
+#[cfg(all(feature = "touch-pad", any(feature = "watch-only", feature = "fingerprint")))]
+compile_error!("`touch-pad` approves signatures in place of the button, which `watch-only` never makes and `fingerprint` approves instead");
+#[cfg(all(feature = "bench", feature = "watch-only"))]
+compile_error!("`bench` times signing with the device key, which `watch-only` doesn't have");
+#[cfg(all(feature = "low-power", feature = "remote-signer"))]
+compile_error!("`low-power` schedules the radio and RPC calls, which `remote-signer` compiles out");
+
+// The chip feature matrix from the README. The pins above are the ESP32-C3's, which the ESP32-C6
+// and ESP32-S3 have free as well. The classic ESP32 wires GPIO6 to GPIO11 to flash and GPIO1
+// and GPIO3 to the console, and has no GPIO20.
+#[cfg(not(any(esp32, esp32s3, esp32c3, esp32c6)))]
+compile_error!("REsp32Sol runs on the ESP32, ESP32-S3, ESP32-C3 and ESP32-C6, set MCU and the matching target in .cargo/config.toml");
+#[cfg(all(feature = "ethernet-rmii", not(esp32)))]
+compile_error!("`ethernet-rmii` needs the classic ESP32's EMAC, the other chips have none");
+#[cfg(all(feature = "camera", not(esp32)))]
+compile_error!("`camera` is for the ESP32-CAM, a classic ESP32");
+#[cfg(all(feature = "touch-pad", not(esp32)))]
+compile_error!("`touch-pad` uses the touch pads of the classic ESP32, the ESP32-C3 and ESP32-C6 have none and the ESP32-S3's work differently");
+#[cfg(all(feature = "usb-wallet", esp32))]
+compile_error!("`usb-wallet` needs a USB Serial/JTAG port, which the classic ESP32 lacks");
+#[cfg(all(
+    esp32,
+    any(
+        feature = "oled-display",
+        feature = "ethernet-w5500",
+        feature = "lora-bridge",
+        feature = "sd-log",
+        feature = "nfc",
+        feature = "status-led",
+        feature = "rotary-encoder",
+        feature = "pay-to-unlock",
+        feature = "vending",
+        feature = "energy-meter"
+    )
+))]
+compile_error!("`oled-display`, `ethernet-w5500`, `lora-bridge`, `sd-log`, `nfc`, `status-led`, `rotary-encoder`, `pay-to-unlock`, `vending` and `energy-meter` use pins between GPIO6 and GPIO10, which the classic ESP32 wires to flash");
+#[cfg(all(esp32, any(feature = "buzzer", feature = "fingerprint", feature = "gps-beacon", feature = "cellular")))]
+compile_error!("`buzzer`, `fingerprint`, `gps-beacon` and `cellular` use GPIO1, the classic ESP32's console TX");
+#[cfg(all(esp32, any(feature = "battery-monitor", feature = "sensor-log")))]
+compile_error!("`battery-monitor` and `sensor-log` measure on GPIO4, an ADC2 pin on the classic ESP32 that WiFi blocks");
+#[cfg(all(esp32, feature = "can-log"))]
+compile_error!("`can-log` receives on GPIO20, which the classic ESP32 lacks");
+#[cfg(all(esp32s3, feature = "can-log", feature = "usb-wallet"))]
+compile_error!("`can-log` receives on GPIO20, the ESP32-S3's USB D+ line `usb-wallet` needs");
+
+// Signing is compiled out entirely in watch-only mode, the firmware never holds a private key
+#[cfg(feature = "pay-actuator")]
+pub mod actuator;
+#[cfg(feature = "air-gap")]
+pub mod airgap;
+pub mod app;
+#[cfg(not(feature = "watch-only"))]
+pub mod approval;
+#[cfg(not(feature = "remote-signer"))]
+pub mod asyncrpc;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+pub mod attestation;
+pub mod b58;
+#[cfg(feature = "battery-monitor")]
+pub mod battery;
+#[cfg(feature = "gps-beacon")]
+pub mod beacon;
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(feature = "ble-provisioning")]
+pub mod ble_prov;
+#[cfg(not(feature = "remote-signer"))]
+pub mod buffers;
+#[cfg(feature = "buzzer")]
+pub mod buzzer;
+#[cfg(feature = "camera")]
+pub mod camera;
+#[cfg(feature = "can-log")]
+pub mod canlog;
+pub mod cluster;
+#[cfg(not(feature = "remote-signer"))]
+pub mod captive;
+#[cfg(feature = "cli-console")]
+pub mod cli;
+#[cfg(all(feature = "cellular", not(feature = "remote-signer")))]
+pub mod cellular;
+pub mod chip;
+#[cfg(not(feature = "remote-signer"))]
+pub mod config;
+pub mod crashlog;
+#[cfg(not(feature = "remote-signer"))]
+pub mod discovery;
+#[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+pub mod display;
+#[cfg(not(feature = "remote-signer"))]
+mod doh;
+#[cfg(not(feature = "remote-signer"))]
+mod dualstack;
+#[cfg(not(feature = "remote-signer"))]
+mod eap;
+#[cfg(not(feature = "watch-only"))]
+pub mod ed25519;
+pub mod error;
+#[cfg(all(feature = "epaper", not(feature = "remote-signer")))]
+pub mod epaper;
+#[cfg(feature = "rotary-encoder")]
+pub mod encoder;
+#[cfg(feature = "energy-meter")]
+pub mod energy;
+#[cfg(all(any(feature = "ethernet-w5500", feature = "ethernet-rmii"), not(feature = "remote-signer")))]
+pub mod eth;
+#[cfg(feature = "fingerprint")]
+pub mod fingerprint;
+#[cfg(any(feature = "espnow-relay", feature = "lora-bridge"))]
+mod gateway;
+#[cfg(feature = "gps-beacon")]
+pub mod gps;
+#[cfg(feature = "vending")]
+mod hx711;
+#[cfg(not(feature = "watch-only"))]
+mod inspect;
+#[cfg(feature = "ir-remote")]
+pub mod ir;
+#[cfg(not(feature = "watch-only"))]
+pub mod keystore;
+#[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+pub mod led;
+#[cfg(not(feature = "remote-signer"))]
+pub mod lifecycle;
+pub mod loglevel;
+#[cfg(all(feature = "low-power", not(feature = "remote-signer")))]
+pub mod lowpower;
+#[cfg(feature = "lora-bridge")]
+pub mod lora;
+#[cfg(not(feature = "remote-signer"))]
+pub mod net;
+#[cfg(not(feature = "remote-signer"))]
+pub mod netwatch;
+#[cfg(feature = "nfc")]
+pub mod nfc;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+pub mod offline;
+#[cfg(all(feature = "oled-display", not(any(feature = "remote-signer", feature = "epaper"))))]
+pub mod oled;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+mod memo;
+#[cfg(feature = "pay-button")]
+pub mod paybutton;
+#[cfg(not(feature = "watch-only"))]
+pub mod pin;
+#[cfg(feature = "nfc")]
+pub mod pn532;
+#[cfg(not(feature = "watch-only"))]
+pub mod policy;
+#[cfg(not(feature = "remote-signer"))]
+mod portal;
+#[cfg(not(any(
+    feature = "remote-signer",
+    feature = "watch-only",
+    feature = "pay-button",
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    feature = "camera"
+)))]
+pub mod power;
+#[cfg(feature = "receipt-printer")]
+pub mod printer;
+#[cfg(not(feature = "watch-only"))]
+pub mod provisioning;
+pub mod psram;
+#[cfg(feature = "vending")]
+mod pyth;
+pub mod qr;
+#[cfg(not(feature = "remote-signer"))]
+mod quorum;
+#[cfg(feature = "nfc")]
+pub mod rc522;
+#[cfg(feature = "receive-qr")]
+pub mod receive;
+pub mod recovery;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+mod ota;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only", feature = "receive-qr", feature = "pay-to-unlock")))]
+pub mod outbox;
+#[cfg(feature = "remote-signer")]
+pub mod remote_signer;
+#[cfg(feature = "espnow-relay")]
+pub mod relay;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+pub mod rotation;
+#[cfg(feature = "sd-log")]
+pub mod sdlog;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+pub mod sender;
+#[cfg(feature = "sensor-log")]
+pub mod sensorlog;
+pub mod serial;
+#[cfg(not(any(feature = "remote-signer", feature = "watch-only")))]
+pub mod rollback;
+#[cfg(not(feature = "watch-only"))]
+pub mod session;
+#[cfg(not(feature = "watch-only"))]
+pub mod signer;
+#[cfg(any(
+    feature = "nfc",
+    feature = "receive-qr",
+    feature = "pay-to-unlock",
+    all(feature = "camera", not(feature = "air-gap")),
+    feature = "cli-console"
+))]
+pub mod solanapay;
+#[cfg(not(feature = "remote-signer"))]
+pub mod solrpc;
+#[cfg(not(feature = "watch-only"))]
+pub mod spend;
+#[cfg(feature = "lora-bridge")]
+mod sx127x;
+#[cfg(not(feature = "watch-only"))]
+pub mod tamper;
+#[cfg(not(feature = "remote-signer"))]
+pub mod taskwdt;
+pub mod tasks;
+#[cfg(not(feature = "watch-only"))]
+pub mod telemetry;
+#[cfg(not(feature = "remote-signer"))]
+pub mod timesync;
+#[cfg(not(feature = "remote-signer"))]
+pub mod tls_pin;
+#[cfg(not(feature = "watch-only"))]
+pub mod token;
+#[cfg(all(feature = "tpu-direct", not(feature = "remote-signer")))]
+mod tpu;
+#[cfg(feature = "touch-pad")]
+pub mod touch;
+#[cfg(not(feature = "remote-signer"))]
+pub mod transport;
+#[cfg(feature = "pay-to-unlock")]
+pub mod unlock;
+#[cfg(feature = "usb-wallet")]
+pub mod usbwallet;
+#[cfg(feature = "vending")]
+pub mod vending;
+#[cfg(not(feature = "watch-only"))]
+pub mod wallet;
+#[cfg(feature = "watch-only")]
+pub mod watch;
+
+// The parts that build without std live in the resp32sol-core crate under core/
+pub use resp32sol_core::{amount, client, form, frag, leanjson, static_ip, wire};
@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+#[cfg(feature = "sd-log")]
+use serde_json::json;
+
+use crate::net::{self, NetEvent, NetSubscription};
+#[cfg(feature = "sd-log")]
+use crate::sdlog;
+
+// Where the wallet is, from getting its credentials to the outcome of the last transaction.
+// The uplink, the RPC client and the sender report events, and `next` is the only place that
+// decides what an event means in a state. Events that mean nothing in the current state, an
+// RPC failure while the link is down for one, leave it alone. Anything can subscribe to the
+// transitions, the status display shows them and the SD card log records them.
+//
+//   Provisioning  no credentials yet, the device waits in BLE provisioning or the setup portal
+//   Connecting    joining the uplink, or rejoining after it was lost
+//   Syncing       online, a first blockhash hasn't come back yet
+//   Ready         the node answers, transfers can be signed
+//   Sending       a sendTransaction is in flight
+//   Confirming    the node accepted it, its confirmation is outstanding
+//   Error         a sync, send or confirmation failed
+//   Recovering    retrying after an error, back to Ready once a sync or send works again
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletState {
+    Provisioning,
+    Connecting,
+    Syncing,
+    Ready,
+    Sending,
+    Confirming,
+    Error,
+    Recovering,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletEvent {
+    // Stored credentials were found or provisioning stored new ones
+    Provisioned,
+    // The stored networks couldn't be joined and the setup portal starts
+    SetupNeeded,
+    LinkUp,
+    LinkLost,
+    // A blockhash is being fetched, and came back
+    SyncStarted,
+    Synced,
+    SendStarted,
+    // sendTransaction returned the signature
+    Sent,
+    Confirmed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub from: WalletState,
+    pub to: WalletState,
+    pub event: WalletEvent,
+}
+
+// The state `event` leads to from `state`, None when it stays
+pub fn next(state: WalletState, event: WalletEvent) -> Option<WalletState> {
+    use WalletEvent::*;
+    use WalletState::*;
+    let to = match (state, event) {
+        (_, SetupNeeded) => Provisioning,
+        (Provisioning, Provisioned) => Connecting,
+        // Wired and cellular uplinks have nothing to provision
+        (Provisioning | Connecting, LinkUp) => Syncing,
+        (Provisioning, _) => return None,
+        (_, LinkLost) => Connecting,
+        (Syncing | Recovering, Synced) => Ready,
+        (Error, SyncStarted | SendStarted) => Recovering,
+        (Ready | Confirming | Recovering, SendStarted) => Sending,
+        (Sending | Recovering, Sent) => Confirming,
+        (Confirming, Confirmed) => Ready,
+        (Syncing | Ready | Sending | Confirming | Recovering, Failed) => Error,
+        _ => return None,
+    };
+    (to != state).then_some(to)
+}
+
+type Listener = Arc<dyn Fn(Transition) + Send + Sync>;
+
+static STATE: Mutex<WalletState> = Mutex::new(WalletState::Provisioning);
+static LISTENERS: Mutex<Vec<(u32, Listener)>> = Mutex::new(Vec::new());
+static NEXT_LISTENER: AtomicU32 = AtomicU32::new(0);
+static NET_SUBSCRIPTION: Mutex<Option<NetSubscription>> = Mutex::new(None);
+
+// Follows the uplink from here on
+pub fn start() {
+    *NET_SUBSCRIPTION.lock().unwrap() = Some(net::subscribe(|event| match event {
+        NetEvent::GotIp => notify(WalletEvent::LinkUp),
+        NetEvent::LostIp | NetEvent::Reconnecting => notify(WalletEvent::LinkLost),
+        NetEvent::CaptivePortalSuspected => {}
+    }));
+    if net::link_up() {
+        notify(WalletEvent::LinkUp);
+    }
+}
+
+pub fn state() -> WalletState {
+    *STATE.lock().unwrap()
+}
+
+pub fn notify(event: WalletEvent) {
+    let transition = {
+        let mut state = STATE.lock().unwrap();
+        let Some(to) = next(*state, event) else {
+            return;
+        };
+        let transition = Transition {
+            from: *state,
+            to,
+            event,
+        };
+        *state = to;
+        transition
+    };
+    info!("Wallet {:?} -> {:?} on {:?}", transition.from, transition.to, transition.event);
+    #[cfg(feature = "sd-log")]
+    sdlog::record(
+        "state",
+        json!({
+            "from": format!("{:?}", transition.from),
+            "to": format!("{:?}", transition.to),
+            "event": format!("{:?}", transition.event),
+        }),
+    );
+    // Called outside the locks, so a callback may read the state or unsubscribe
+    let listeners: Vec<Listener> = LISTENERS.lock().unwrap().iter().map(|(_, listener)| listener.clone()).collect();
+    for listener in listeners {
+        listener(transition);
+    }
+}
+
+// Keeps a callback registered, dropping it unsubscribes
+pub struct LifecycleSubscription(u32);
+
+impl Drop for LifecycleSubscription {
+    fn drop(&mut self) {
+        LISTENERS.lock().unwrap().retain(|(id, _)| *id != self.0);
+    }
+}
+
+// Calls `callback` on every transition, on the thread that reported the event, so anything
+// slow belongs on a thread of its own
+pub fn subscribe(callback: impl Fn(Transition) + Send + Sync + 'static) -> LifecycleSubscription {
+    let id = NEXT_LISTENER.fetch_add(1, Ordering::Relaxed);
+    LISTENERS.lock().unwrap().push((id, Arc::new(callback)));
+    LifecycleSubscription(id)
+}
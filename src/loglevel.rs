@@ -0,0 +1,135 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::CONFIG_LOG_DEFAULT_LEVEL;
+use log::{info, warn, LevelFilter};
+
+// Log levels per target, changed at runtime and kept in NVS, so a device in the field can log
+// more of one part without reflashing: `resp32sol::solrpc` at debug, ESP-IDF's `wifi` at warn.
+// Targets are what log lines show before the message, the crate's module paths and ESP-IDF's
+// component tags, and `*` is the level of every target without one of its own. Nothing above
+// CONFIG_LOG_MAXIMUM_LEVEL in sdkconfig.defaults shows, and quiet-log compiles info and below
+// out of release builds.
+
+const LOG_NAMESPACE: &str = "log_levels";
+const LEVELS_KEY: &str = "levels";
+// "target=level" entries joined by commas
+const MAX_LEVELS_LEN: usize = 1024;
+const DEFAULT_TARGET: &str = "*";
+
+static LOGGER: EspLogger = EspLogger::new();
+// The levels set, `*` among them only when it was changed
+static LEVELS: Mutex<Vec<(String, LevelFilter)>> = Mutex::new(Vec::new());
+
+// Applies the levels stored in NVS, at boot once the logger is up
+pub fn restore(nvs: EspDefaultNvsPartition) -> Result<(), String> {
+    let nvs = EspNvs::new(nvs, LOG_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+    let mut buf = vec![0u8; MAX_LEVELS_LEN];
+    let stored = nvs
+        .get_str(LEVELS_KEY, &mut buf)
+        .map_err(|e| format!("Log levels read: {:?}", e))?
+        .unwrap_or_default();
+
+    let mut levels = Vec::new();
+    for entry in stored.split(',').filter(|entry| !entry.is_empty()) {
+        match entry.split_once('=').map(|(target, level)| (target, LevelFilter::from_str(level))) {
+            Some((target, Ok(level))) => levels.push((target.to_string(), level)),
+            _ => warn!("Stored log level '{}' ignored", entry),
+        }
+    }
+    apply(&levels)?;
+    if !levels.is_empty() {
+        info!("Log levels: {}", describe(&levels));
+    }
+    *LEVELS.lock().unwrap() = levels;
+    Ok(())
+}
+
+// Sets `target` to "off", "error", "warn", "info", "debug" or "trace" and stores it, "default"
+// makes it follow `*` again. For the wallet console, MQTT or BLE handlers alike.
+pub fn set_level(nvs: EspDefaultNvsPartition, target: &str, level: &str) -> Result<(), String> {
+    if target.is_empty() || target.contains([',', '=']) {
+        return Err(format!("Invalid log target '{}'", target));
+    }
+    let mut levels = LEVELS.lock().unwrap();
+    let mut updated: Vec<(String, LevelFilter)> =
+        levels.iter().filter(|(set, _)| set != target).cloned().collect();
+    if level != "default" {
+        let level = LevelFilter::from_str(level)
+            .map_err(|_| format!("Unknown level '{}', off, error, warn, info, debug or trace", level))?;
+        if level > LOGGER.get_max_level() {
+            return Err(format!(
+                "{} is above CONFIG_LOG_MAXIMUM_LEVEL, {} at most",
+                name(level),
+                name(LOGGER.get_max_level())
+            ));
+        }
+        updated.push((target.to_string(), level));
+    }
+    apply(&updated)?;
+
+    let stored: Vec<String> = updated.iter().map(|(target, level)| format!("{}={}", target, name(*level))).collect();
+    let stored = stored.join(",");
+    if stored.len() >= MAX_LEVELS_LEN {
+        return Err("Too many log levels set, set some back to default".to_string());
+    }
+    let mut nvs = EspNvs::new(nvs, LOG_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+    nvs.set_str(LEVELS_KEY, &stored).map_err(|e| format!("Log levels store: {:?}", e))?;
+    *levels = updated;
+    Ok(())
+}
+
+// "*=info resp32sol::solrpc=debug", the default level first
+pub fn levels() -> String {
+    let levels = LEVELS.lock().unwrap();
+    match levels.iter().any(|(target, _)| target == DEFAULT_TARGET) {
+        true => describe(&levels),
+        false => format!("{}={} {}", DEFAULT_TARGET, name(default_level()), describe(&levels)).trim_end().to_string(),
+    }
+}
+
+// ESP-IDF drops every target's level when `*` is set, so `*` goes first, and goes back to
+// CONFIG_LOG_DEFAULT_LEVEL for a target set back to default to lose its level
+fn apply(levels: &[(String, LevelFilter)]) -> Result<(), String> {
+    let default = levels
+        .iter()
+        .find(|(target, _)| target == DEFAULT_TARGET)
+        .map_or(default_level(), |(_, level)| *level);
+    set_target_level(DEFAULT_TARGET, default)?;
+    for (target, level) in levels.iter().filter(|(target, _)| target != DEFAULT_TARGET) {
+        set_target_level(target, *level)?;
+    }
+    Ok(())
+}
+
+fn set_target_level(target: &str, level: LevelFilter) -> Result<(), String> {
+    LOGGER
+        .set_target_level(target, level)
+        .map_err(|e| format!("Log level of {}: {:?}", target, e))
+}
+
+// "debug", as the console takes it
+fn name(level: LevelFilter) -> String {
+    level.as_str().to_lowercase()
+}
+
+fn describe(levels: &[(String, LevelFilter)]) -> String {
+    let mut levels = levels.to_vec();
+    levels.sort_by_key(|(target, _)| target != DEFAULT_TARGET);
+    let levels: Vec<String> = levels.iter().map(|(target, level)| format!("{}={}", target, name(*level))).collect();
+    levels.join(" ")
+}
+
+// CONFIG_LOG_DEFAULT_LEVEL, ESP_LOG_NONE to ESP_LOG_VERBOSE
+fn default_level() -> LevelFilter {
+    match CONFIG_LOG_DEFAULT_LEVEL {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::spi::SPI2;
+use esp_idf_svc::sys::{esp_efuse_mac_get_default, ESP_OK};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::{Hash, Transaction};
+
+use crate::frag::{fragment, payload_id, Reassembler, MAX_TRANSACTION_LEN};
+use crate::gateway::{self, ACK_DUPLICATE, ACK_QUEUED, ACK_REJECTED};
+use crate::solrpc::get_nonce;
+use crate::sx127x::{Packet, Sx127x, MAX_PACKET_LEN};
+
+// Gateway pattern over LoRa for nodes kilometers from any network: they sign transactions
+// against a durable nonce, which the gateway looks up for them, and push them to the gateway
+// in RSF frames (see frag.rs) that it checks, deduplicates and submits like ESP-NOW relays'
+// (see gateway.rs). Recent blockhashes expire before a transaction crawls over a slow link, a
+// durable nonce waits until it is spent however many retries that takes.
+// LoRa is a shared broadcast medium, every packet carries the node's MAC after a magic:
+//   RS N <node> <nonce account>         node -> gateway, current nonce request
+//   RS V <node> <nonce account> <hash>  gateway -> node, the nonce
+//   RS X <node> <nonce account>         gateway -> node, no nonce for that account
+//   RS T <node> <RSF frame>             node -> gateway, one frame of a serialized transaction
+//   RS A <node> <status> <sig>          gateway -> node, the transaction with that first signature arrived
+const MAGIC: &[u8] = b"RS";
+const NONCE_REQUEST: u8 = b'N';
+const NONCE: u8 = b'V';
+const NONCE_UNAVAILABLE: u8 = b'X';
+const TX_FRAME: u8 = b'T';
+const ACK: u8 = b'A';
+const HEADER_LEN: usize = MAGIC.len() + 1 + 6;
+
+// "node", "gateway" or empty (see build.rs)
+const ROLE: &str = env!("RESP32SOL_LORA");
+
+// An RSF frame of this many bytes is 241 characters, under the radio's 255-byte packets with
+// the header
+const CHUNK_LEN: usize = 168;
+const _: () = assert!(HEADER_LEN + 17 + CHUNK_LEN.div_ceil(3) * 4 <= MAX_PACKET_LEN);
+// Covers the gateway's duty cycle wait and, for nonces, its RPC call
+const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+const REQUEST_ATTEMPTS: u32 = 3;
+const SEND_ATTEMPTS: u32 = 3;
+const QUEUE_FULL_DELAY: Duration = Duration::from_secs(30);
+// Transactions half received, one more drops the one that went quiet longest
+const MAX_PARTIALS: usize = 16;
+// Frames of a transaction arrive a duty cycle wait apart and again after SEND_ATTEMPTS
+// timeouts, one with no frame for this long is dropped
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(120);
+const SERVE_POLL: Duration = Duration::from_secs(1);
+const GATEWAY_STACK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoraConfig {
+    // Has to be the same on nodes and gateway, and allowed where they are
+    pub frequency_hz: u32,
+    // 7 to 12, every step up doubles the time on air and adds about 2.5 dB of range
+    pub spreading_factor: u8,
+    pub tx_power_dbm: u8,
+    // Keeps other LoRa networks' packets out of the radio, 0x34 is LoRaWAN's
+    pub sync_word: u8,
+    // Share of time the radio may transmit, what the band allows (1 or 10 in most of the EU
+    // 868 MHz band), 100 for none
+    pub duty_cycle_percent: u8,
+}
+
+struct Radio {
+    sx127x: Sx127x,
+    duty_cycle_percent: u8,
+    // When the duty cycle allows transmitting again
+    clear_at: Instant,
+}
+
+impl Radio {
+    fn send(&mut self, tag: u8, node: &[u8; 6], body: &[u8]) -> Result<(), String> {
+        let wait = self.clear_at.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        let airtime = self.sx127x.transmit(&[MAGIC, &[tag], node, body].concat())?;
+        let percent = u32::from(self.duty_cycle_percent.clamp(1, 100));
+        self.clear_at = Instant::now() + airtime * (100 - percent) / percent;
+        Ok(())
+    }
+
+    // Next packet of ours within the timeout, as its tag, node and the packet with the body left
+    fn receive(&mut self, timeout: Duration) -> Result<Option<(u8, [u8; 6], Packet)>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(mut packet) = self.sx127x.receive(remaining)? else {
+                return Ok(None);
+            };
+            if packet.data.len() < HEADER_LEN || !packet.data.starts_with(MAGIC) {
+                continue;
+            }
+            let tag = packet.data[MAGIC.len()];
+            let node: [u8; 6] = packet.data[MAGIC.len() + 1..HEADER_LEN].try_into().unwrap();
+            packet.data.drain(..HEADER_LEN);
+            return Ok(Some((tag, node, packet)));
+        }
+    }
+}
+
+struct Node {
+    radio: Radio,
+    mac: [u8; 6],
+}
+
+static NODE: Mutex<Option<Node>> = Mutex::new(None);
+
+pub fn is_node() -> bool {
+    ROLE == "node"
+}
+
+pub fn is_gateway() -> bool {
+    ROLE == "gateway"
+}
+
+// Brings up the radio for the configured role: a node keeps it for its transactions, a
+// gateway serves nodes from a thread of its own
+pub fn start(
+    spi: SPI2,
+    sclk: AnyIOPin,
+    mosi: AnyIOPin,
+    miso: AnyIOPin,
+    cs: AnyIOPin,
+    config: &LoraConfig,
+) -> Result<(), String> {
+    let radio = Radio {
+        sx127x: Sx127x::new(spi, sclk, mosi, miso, cs, config)?,
+        duty_cycle_percent: config.duty_cycle_percent,
+        clear_at: Instant::now(),
+    };
+    if is_node() {
+        let mut mac = [0u8; 6];
+        match unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) } {
+            ESP_OK => {}
+            ret => return Err(format!("MAC read: {}", ret)),
+        }
+        *NODE.lock().unwrap() = Some(Node { radio, mac });
+        info!("LoRa node {}, transactions go through the gateway", format_mac(&mac));
+    } else if is_gateway() {
+        std::thread::Builder::new()
+            .name("lora".to_string())
+            .stack_size(GATEWAY_STACK_SIZE)
+            .spawn(move || serve(radio))
+            .map_err(|e| format!("LoRa thread: {:?}", e))?;
+        gateway::start()?;
+        info!("LoRa gateway listening");
+    }
+    Ok(())
+}
+
+// Current value of a durable nonce, looked up by the gateway
+pub fn nonce(nonce_account: &Pubkey) -> Result<Hash, String> {
+    let mut node = NODE.lock().unwrap();
+    let node = node.as_mut().ok_or("LoRa bridge not running")?;
+
+    for _ in 0..REQUEST_ATTEMPTS {
+        node.send(NONCE_REQUEST, nonce_account.as_ref())?;
+        let reply = node.wait_reply(|tag, body| {
+            (tag == NONCE || tag == NONCE_UNAVAILABLE) && body.get(..32) == Some(nonce_account.as_ref())
+        })?;
+        match reply {
+            Some((NONCE, body)) => {
+                let hash: [u8; 32] = body[32..].try_into().map_err(|_| "Malformed nonce from the gateway")?;
+                return Ok(Hash::new_from_array(hash));
+            }
+            Some(_) => return Err(format!("Gateway found no nonce in {}", nonce_account)),
+            None => warn!("LoRa gateway not answering"),
+        }
+    }
+    Err("No nonce from the LoRa gateway".to_string())
+}
+
+// Hands a signed transaction to the gateway, Ok once it has queued it
+pub fn submit(transaction: &Transaction) -> Result<String, String> {
+    // A blockhash would expire while frames and retries crawl over the air
+    #[cfg(not(feature = "watch-only"))]
+    if !crate::offline::uses_durable_nonce(transaction) {
+        return Err("LoRa nodes only send durable nonce transactions".to_string());
+    }
+    let signature = *transaction.signatures.first().ok_or("Transaction is not signed")?;
+    let bytes = bincode::serialize(transaction).map_err(|e| format!("Transaction serialization failed: {:?}", e))?;
+    let frames = fragment(&bytes, CHUNK_LEN);
+
+    let mut node = NODE.lock().unwrap();
+    let node = node.as_mut().ok_or("LoRa bridge not running")?;
+
+    for _ in 0..SEND_ATTEMPTS {
+        // The gateway keeps frames that arrived, a retry fills in the ones lost
+        for frame in &frames {
+            node.send(TX_FRAME, frame.as_bytes())?;
+        }
+
+        let ack = node.wait_reply(|tag, body| {
+            tag == ACK && body.get(1..).is_some_and(|acked| acked == signature.as_ref())
+        })?;
+        match ack.and_then(|(_, body)| body.first().copied()) {
+            Some(ACK_QUEUED) | Some(ACK_DUPLICATE) => return Ok(signature.to_string()),
+            Some(ACK_REJECTED) => return Err("LoRa gateway rejected the transaction".to_string()),
+            Some(_) => std::thread::sleep(QUEUE_FULL_DELAY),
+            None => warn!("No ack for {}, sending again", signature),
+        }
+    }
+    Err(format!("LoRa gateway did not take {}", signature))
+}
+
+impl Node {
+    fn send(&mut self, tag: u8, body: &[u8]) -> Result<(), String> {
+        let mac = self.mac;
+        self.radio.send(tag, &mac, body)
+    }
+
+    // Next reply to this node the filter accepts, everything else is dropped
+    fn wait_reply(&mut self, accept: impl Fn(u8, &[u8]) -> bool) -> Result<Option<(u8, Vec<u8>)>, String> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some((tag, to, packet)) = self.radio.receive(remaining)? else {
+                return Ok(None);
+            };
+            if to == self.mac && accept(tag, &packet.data) {
+                return Ok(Some((tag, packet.data)));
+            }
+        }
+    }
+}
+
+fn serve(mut radio: Radio) {
+    // Transactions being received by their frames' checksum, not by the node id a packet
+    // claims, which only says where the ack goes. A packet with another node's id can't
+    // replace that node's frames.
+    let mut reassemblers: HashMap<String, (Reassembler, Instant)> = HashMap::new();
+    loop {
+        let (tag, node, packet) = match radio.receive(SERVE_POLL) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("LoRa receive: {}", e);
+                std::thread::sleep(SERVE_POLL);
+                continue;
+            }
+        };
+        let (reply_tag, reply) = match tag {
+            NONCE_REQUEST => {
+                let Ok(account) = <[u8; 32]>::try_from(packet.data.as_slice()) else {
+                    continue;
+                };
+                match get_nonce(&Pubkey::new_from_array(account)) {
+                    Ok(hash) => (NONCE, [&account[..], hash.as_ref()].concat()),
+                    Err(e) => {
+                        warn!("Nonce for LoRa node {}: {}", format_mac(&node), e);
+                        (NONCE_UNAVAILABLE, account.to_vec())
+                    }
+                }
+            }
+            TX_FRAME => {
+                let frame = String::from_utf8_lossy(&packet.data);
+                let Some(id) = payload_id(&frame).map(str::to_string) else {
+                    warn!("LoRa frame from {}: not a payload frame", format_mac(&node));
+                    continue;
+                };
+                reassemblers.retain(|_, (_, last_frame)| last_frame.elapsed() < PARTIAL_TIMEOUT);
+                if !reassemblers.contains_key(&id) && reassemblers.len() >= MAX_PARTIALS {
+                    let stalest =
+                        reassemblers.iter().min_by_key(|(_, (_, last_frame))| *last_frame).map(|(id, _)| id.clone());
+                    if let Some(stalest) = stalest {
+                        reassemblers.remove(&stalest);
+                    }
+                }
+                let (reassembler, last_frame) = reassemblers
+                    .entry(id.clone())
+                    .or_insert_with(|| (Reassembler::new(MAX_TRANSACTION_LEN, CHUNK_LEN), Instant::now()));
+                *last_frame = Instant::now();
+                match reassembler.push(&frame) {
+                    Ok(Some(payload)) => {
+                        reassemblers.remove(&id);
+                        match gateway::accept(&payload) {
+                            Ok((status, signature)) => {
+                                info!(
+                                    "LoRa transaction from {} at {} dBm, SNR {} dB",
+                                    format_mac(&node),
+                                    packet.rssi,
+                                    packet.snr
+                                );
+                                (ACK, [&[status], signature.as_ref()].concat())
+                            }
+                            Err(e) => {
+                                warn!("LoRa transaction from {} dropped: {}", format_mac(&node), e);
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("LoRa frame from {}: {}", format_mac(&node), e);
+                        continue;
+                    }
+                }
+            }
+            // Other gateways' replies
+            _ => continue,
+        };
+        if let Err(e) = radio.send(reply_tag, &node, &reply) {
+            warn!("LoRa reply to {}: {}", format_mac(&node), e);
+        }
+    }
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":")
+}
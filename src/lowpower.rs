@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::{
+    esp_pm_config_t, esp_pm_configure, esp_wifi_set_ps, wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    wifi_ps_type_t_WIFI_PS_MIN_MODEM, ESP_ERR_NOT_SUPPORTED, ESP_OK,
+};
+use log::{info, warn};
+
+#[cfg(feature = "oled-display")]
+use crate::display;
+use crate::lifecycle::{self, LifecycleSubscription};
+
+// Battery profile for devices that stay up. The CPU clocks down and light sleeps whenever every
+// task is blocked, background RPC calls such as the balance refreshes wait for a short window
+// every few minutes, the radio sleeps through more beacons outside the windows, and the status
+// display dims while the wallet is idle. Transfers and their confirmations never wait for a
+// window. The average current is estimated from the time RPC calls keep the radio busy and the
+// currents measured on the bench, and checked against the budget.
+
+// How often the estimate is logged
+const REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Longest the power thread sleeps, for the dimming
+const MAX_TICK: Duration = Duration::from_secs(30);
+const POWER_STACK_SIZE: usize = 4 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PowerProfile {
+    // Frequency scaling range, the CPU runs at the minimum while idle
+    pub max_cpu_mhz: i32,
+    pub min_cpu_mhz: i32,
+    // Light sleep when every task is blocked, which drops the USB console on the C3
+    pub light_sleep: bool,
+    // WiFi modem sleep between windows wakes for every DTIM beacon with this off, and for only
+    // every few (the station's listen interval) with it on
+    pub max_modem_sleep: bool,
+    // A window of `rpc_window` opens every `rpc_period`, background RPC calls wait for it
+    pub rpc_period: Duration,
+    pub rpc_window: Duration,
+    // The status display dims after this long without a wallet state change, None keeps it bright
+    pub dim_after: Option<Duration>,
+    // Supply current measured with an RPC call in flight and while idle, and the average the
+    // device should stay below, in mA
+    pub active_ma: f32,
+    pub idle_ma: f32,
+    pub budget_ma: f32,
+}
+
+static PROFILE: Mutex<Option<(PowerProfile, Instant)>> = Mutex::new(None);
+// Time RPC calls took in total, in microseconds
+static RPC_BUSY_US: AtomicU64 = AtomicU64::new(0);
+static LAST_ACTIVITY: Mutex<Option<Instant>> = Mutex::new(None);
+static DIMMED: AtomicBool = AtomicBool::new(false);
+static LIFECYCLE: Mutex<Option<LifecycleSubscription>> = Mutex::new(None);
+
+pub fn start(profile: PowerProfile) -> Result<(), String> {
+    let config = esp_pm_config_t {
+        max_freq_mhz: profile.max_cpu_mhz,
+        min_freq_mhz: profile.min_cpu_mhz,
+        light_sleep_enable: profile.light_sleep,
+    };
+    match unsafe { esp_pm_configure(&config as *const esp_pm_config_t as *const _) } {
+        ESP_OK => info!(
+            "CPU scaling {}-{} MHz, light sleep {}",
+            profile.min_cpu_mhz,
+            profile.max_cpu_mhz,
+            if profile.light_sleep { "on" } else { "off" }
+        ),
+        ESP_ERR_NOT_SUPPORTED => warn!("CPU scaling needs CONFIG_PM_ENABLE in sdkconfig.defaults"),
+        e => warn!("CPU scaling config: {}", e),
+    }
+
+    let started = Instant::now();
+    *PROFILE.lock().unwrap() = Some((profile, started));
+    *LAST_ACTIVITY.lock().unwrap() = Some(Instant::now());
+    *LIFECYCLE.lock().unwrap() = Some(lifecycle::subscribe(|_| {
+        *LAST_ACTIVITY.lock().unwrap() = Some(Instant::now());
+        if DIMMED.swap(false, Ordering::SeqCst) {
+            #[cfg(feature = "oled-display")]
+            display::set_dimmed(false);
+        }
+    }));
+
+    std::thread::Builder::new()
+        .name("power".to_string())
+        .stack_size(POWER_STACK_SIZE)
+        .spawn(move || run(profile, started))
+        .map_err(|e| format!("Power thread: {:?}", e))?;
+    info!(
+        "Low-power profile up, RPC window {}s every {}s, budget {} mA",
+        profile.rpc_window.as_secs(),
+        profile.rpc_period.as_secs(),
+        profile.budget_ma
+    );
+    Ok(())
+}
+
+// How long background RPC calls still have to wait, zero while a window is open or without
+// the profile
+pub fn until_window() -> Duration {
+    match *PROFILE.lock().unwrap() {
+        Some((profile, started)) => match window_phase(&profile, started) {
+            (true, _) => Duration::ZERO,
+            (false, until_open) => until_open,
+        },
+        None => Duration::ZERO,
+    }
+}
+
+// Called with the time every RPC call took, urgent or not
+pub fn rpc_busy(took: Duration) {
+    RPC_BUSY_US.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+}
+
+// Whether a window is open, and how long until that changes
+fn window_phase(profile: &PowerProfile, started: Instant) -> (bool, Duration) {
+    let period = profile.rpc_period.as_millis().max(1);
+    let window = profile.rpc_window.as_millis().min(period);
+    let phase = started.elapsed().as_millis() % period;
+    match phase < window {
+        true => (true, Duration::from_millis((window - phase) as u64)),
+        false => (false, Duration::from_millis((period - phase) as u64)),
+    }
+}
+
+fn run(profile: PowerProfile, started: Instant) {
+    let mut reported = Instant::now();
+    let mut busy_at_report = RPC_BUSY_US.load(Ordering::Relaxed);
+    loop {
+        let (open, until_change) = window_phase(&profile, started);
+        // Applied on every pass, a driver restart after a recovery resets it. Fails on wired and
+        // cellular uplinks, which have no WiFi driver to set.
+        let mode = match open || !profile.max_modem_sleep {
+            true => wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+            false => wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+        };
+        unsafe { esp_wifi_set_ps(mode) };
+
+        let idle = LAST_ACTIVITY.lock().unwrap().map_or(Duration::ZERO, |at| at.elapsed());
+        if profile.dim_after.is_some_and(|dim_after| idle >= dim_after) && !DIMMED.swap(true, Ordering::SeqCst) {
+            #[cfg(feature = "oled-display")]
+            display::set_dimmed(true);
+        }
+
+        if reported.elapsed() >= REPORT_INTERVAL {
+            let busy_us = RPC_BUSY_US.load(Ordering::Relaxed);
+            let busy = Duration::from_micros(busy_us - busy_at_report).as_secs_f32() / reported.elapsed().as_secs_f32();
+            let average = profile.idle_ma + (profile.active_ma - profile.idle_ma) * busy.min(1.0);
+            match average <= profile.budget_ma {
+                true => info!(
+                    "Estimated average current {:.1} mA, RPC busy {:.1}% of the time, budget {} mA",
+                    average,
+                    busy * 100.0,
+                    profile.budget_ma
+                ),
+                false => warn!(
+                    "Estimated average current {:.1} mA is over the {} mA budget, RPC busy {:.1}% of the time",
+                    average,
+                    profile.budget_ma,
+                    busy * 100.0
+                ),
+            }
+            reported = Instant::now();
+            busy_at_report = busy_us;
+        }
+
+        std::thread::sleep(until_change.min(MAX_TICK));
+    }
+}
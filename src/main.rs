@@ -1,94 +1,464 @@
 // ESP-IDF specific imports
-use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::hal::peripherals::Peripherals;
-
-use esp_idf_svc::io::EspIOError;
+#[cfg(feature = "battery-monitor")]
+use esp_idf_svc::hal::adc::oneshot::config::Calibration;
+#[cfg(feature = "can-log")]
+use esp_idf_svc::hal::can::config::Timing;
 use esp_idf_svc::log::EspLogger;
-use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::sys::link_patches;
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
-
-// Solana related imports
-use solana_program::native_token::LAMPORTS_PER_SOL;
-use solana_program::pubkey::Pubkey;
-use solana_system_interface::instruction as system_instruction;
-use solana_transaction::Transaction;
-use solana_keypair::{Keypair, Signer};
 
-use log::info;
+// The board is brought up in the library, the firmware only settles what each feature does
+#[cfg(feature = "pay-actuator")]
+use resp32sol::actuator::{Actuation, ActuatorConfig};
+use resp32sol::app::{self, FirmwareConfig};
+#[cfg(not(feature = "watch-only"))]
+use resp32sol::app::AppConfig;
+#[cfg(feature = "battery-monitor")]
+use resp32sol::battery::BatteryConfig;
+#[cfg(feature = "gps-beacon")]
+use resp32sol::beacon::BeaconConfig;
+#[cfg(not(feature = "remote-signer"))]
+use resp32sol::buffers::BufferConfig;
+#[cfg(feature = "buzzer")]
+use resp32sol::buzzer::BuzzerConfig;
+#[cfg(feature = "camera")]
+use resp32sol::camera::{CameraConfig, CameraPins};
+#[cfg(feature = "can-log")]
+use resp32sol::canlog::{Anchor, CanLogConfig, CanSignal};
+#[cfg(all(feature = "oled-display", not(any(feature = "remote-signer", feature = "epaper"))))]
+use resp32sol::oled::Controller;
+#[cfg(not(feature = "watch-only"))]
+use resp32sol::ed25519::SigningBackend;
+#[cfg(all(feature = "epaper", not(feature = "remote-signer")))]
+use resp32sol::epaper::EPaperConfig;
+#[cfg(feature = "rotary-encoder")]
+use resp32sol::encoder::DialConfig;
+#[cfg(feature = "energy-meter")]
+use resp32sol::energy::{EnergyConfig, EnergySource};
+#[cfg(feature = "fingerprint")]
+use resp32sol::fingerprint::FingerprintConfig;
+#[cfg(feature = "ir-remote")]
+use resp32sol::ir::{IrAction, IrButton, IrConfig};
+#[cfg(feature = "lora-bridge")]
+use resp32sol::lora::LoraConfig;
+#[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+use resp32sol::led::LedConfig;
+#[cfg(feature = "low-power")]
+use resp32sol::lowpower::PowerProfile;
+#[cfg(feature = "nfc")]
+use resp32sol::nfc::{NfcConfig, NfcReader, TapAction};
+#[cfg(feature = "receipt-printer")]
+use resp32sol::printer::{PrinterConfig, QrStyle};
+#[cfg(feature = "receive-qr")]
+use resp32sol::receive::ReceiveConfig;
+#[cfg(feature = "sd-log")]
+use resp32sol::sdlog::SdLogConfig;
+use resp32sol::tasks::StackConfig;
+#[cfg(feature = "energy-meter")]
+use resp32sol::token;
+#[cfg(feature = "touch-pad")]
+use resp32sol::touch::TouchConfig;
+#[cfg(feature = "pay-to-unlock")]
+use resp32sol::unlock::UnlockConfig;
+#[cfg(feature = "vending")]
+use resp32sol::vending::{Price, Product, VendingConfig};
+#[cfg(not(feature = "watch-only"))]
+use resp32sol::wallet::WalletConfig;
 
-mod solrpc;
-use crate::solrpc::{get_latest_blockhash, send_transaction};
+use std::time::Duration;
 
+// Transfers above this many lamports need a press on the BOOT button, or the fingerprint or
+// touch pad that takes its place, within the timeout
+#[cfg(not(feature = "watch-only"))]
+const APPROVAL_THRESHOLD_LAMPORTS: u64 = 100_000_000;
+#[cfg(not(feature = "watch-only"))]
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(30);
 
+const FIRMWARE: FirmwareConfig = FirmwareConfig {
+    // Stack sizes of the network, rpc and ui tasks in bytes, and the free stack below which the
+    // supervisor warns about a task. Every task's high-water mark is logged each report interval,
+    // `None` leaves only the warnings.
+    task_stacks: StackConfig {
+        network: 8 * 1024,
+        rpc: 12 * 1024,
+        ui: 8 * 1024,
+        margin: 1024,
+        report_interval: Some(Duration::from_secs(300)),
+    },
+    // RAM for RPC traffic and the task queues, see `buffers`. Boards short of heap lower
+    // `max_response` and `json_scratch`, ones with PSRAM can raise `read_chunk` and
+    // `max_external_response`, e.g. `BufferConfig { read_chunk: 2048, ..BufferConfig::DEFAULT }`.
+    #[cfg(not(feature = "remote-signer"))]
+    buffers: BufferConfig::DEFAULT,
+    // RPC calls that go this long between steps reset the device, it has to outlast the HTTP
+    // timeout (30s) a slow TLS handshake or read may use up
+    #[cfg(not(feature = "remote-signer"))]
+    task_watchdog_timeout: Duration::from_secs(75),
+    // Battery profile for --features low-power. The currents are what a USB power meter shows on
+    // this board with an RPC call in flight and while idle between windows, the estimate in the log
+    // is only as good as they are.
+    #[cfg(feature = "low-power")]
+    low_power: PowerProfile {
+        max_cpu_mhz: 160,
+        min_cpu_mhz: 40,
+        light_sleep: false,
+        max_modem_sleep: true,
+        rpc_period: Duration::from_secs(300),
+        rpc_window: Duration::from_secs(30),
+        dim_after: Some(Duration::from_secs(60)),
+        active_ma: 85.0,
+        idle_ma: 18.0,
+        budget_ma: 25.0,
+    },
+    // events.log rotates at 1 MiB with 8 older files kept, about 9 MiB of the card at most
+    #[cfg(feature = "sd-log")]
+    sd_log: SdLogConfig {
+        max_file_bytes: 1024 * 1024,
+        rotated_files: 8,
+    },
+    // Controller of the OLED module, Sh1106 for most 1.3" ones
+    #[cfg(all(feature = "oled-display", not(any(feature = "remote-signer", feature = "epaper"))))]
+    oled_controller: Controller::Ssd1306,
+    // A full refresh every 20 updates clears what partial ones leave behind, `flipped` for panels
+    // mounted upside down
+    #[cfg(all(feature = "epaper", not(feature = "remote-signer")))]
+    epaper: EPaperConfig {
+        full_refresh_every: 20,
+        flipped: false,
+    },
+    // Status LED colors, e.g. `LedConfig { idle: Color(0, 0, 0), ..LedConfig::DEFAULT }` to stay
+    // dark while idle
+    #[cfg(all(feature = "status-led", not(feature = "remote-signer")))]
+    status_led: LedConfig::DEFAULT,
+    // `enabled: false` keeps the buzzer quiet without rebuilding the rest, volume is the duty cycle
+    // in percent up to 50
+    #[cfg(feature = "buzzer")]
+    buzzer: BuzzerConfig {
+        enabled: true,
+        volume: 50,
+    },
+    // The LoRa link, the same on nodes and their gateway: 869.525 MHz is the EU band's 10% duty
+    // cycle channel, 915 MHz regions use e.g. `frequency_hz: 915_000_000, duty_cycle_percent: 100`
+    #[cfg(feature = "lora-bridge")]
+    lora: LoraConfig {
+        frequency_hz: 869_525_000,
+        spreading_factor: 9,
+        tx_power_dbm: 14,
+        sync_word: 0x12,
+        duty_cycle_percent: 10,
+    },
+    // Prepaid power at 0.25 USDC per kWh from a 1000 imp/kWh meter, charged every 100 Wh or hour.
+    // `payer` is the customer's wallet, e.g. `Some(pubkey!("<payer>"))`, which approves the device
+    // key as delegate of its USDC account for what it prepays. Metering refuses to start without
+    // it. `EnergySource::Pzem { address: 0xF8 }` reads a PZEM-004T instead.
+    #[cfg(feature = "energy-meter")]
+    energy: EnergyConfig {
+        source: EnergySource::Pulses { per_kwh: 1000 },
+        payer: None,
+        mint: solana_program::pubkey!("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v"),
+        token_program: token::TOKEN_PROGRAM_ID,
+        recipient: None,
+        price_per_kwh: 250_000,
+        settle_every_wh: 100,
+        settle_interval: Duration::from_secs(60 * 60),
+        reserve_wh: 50,
+        check_interval: Duration::from_secs(60),
+        active_high: true,
+    },
+    // Battery behind a 1:1 divider on GPIO4. To calibrate, note the logged and the multimeter's
+    // voltage at two charge levels: gain is the ratio of their differences, offset_mv what is left.
+    #[cfg(feature = "battery-monitor")]
+    battery: BatteryConfig {
+        // Curve fitting on every chip the battery monitor runs on, the classic ESP32 only has line
+        // fitting but its GPIO4 is on ADC2
+        calibration: Calibration::Curve,
+        divider: 2.0,
+        gain: 1.0,
+        offset_mv: 0.0,
+        empty_mv: 3300,
+        full_mv: 4200,
+        interval: Duration::from_secs(60),
+        alert_below_mv: Some(3500),
+    },
+    // J1939 engine speed, coolant temperature and vehicle speed from any source address, as most
+    // trucks and machines broadcast them at 250 kbit/s, summed up every 15 minutes.
+    // `anchor: Anchor::Hash` keeps the values off-chain.
+    #[cfg(feature = "can-log")]
+    can_log: CanLogConfig {
+        timing: Timing::B250K,
+        signals: &[
+            CanSignal {
+                name: "rpm",
+                id: 0x0CF0_0400,
+                id_mask: 0x00FF_FF00,
+                extended: true,
+                start: 3,
+                len: 2,
+                big_endian: false,
+                signed: false,
+                scale: 0.125,
+                offset: 0.0,
+            },
+            CanSignal {
+                name: "coolant_c",
+                id: 0x18FE_EE00,
+                id_mask: 0x00FF_FF00,
+                extended: true,
+                start: 0,
+                len: 1,
+                big_endian: false,
+                signed: false,
+                scale: 1.0,
+                offset: -40.0,
+            },
+            CanSignal {
+                name: "speed_kmh",
+                id: 0x18FE_F100,
+                id_mask: 0x00FF_FF00,
+                extended: true,
+                start: 1,
+                len: 2,
+                big_endian: false,
+                signed: false,
+                scale: 1.0 / 256.0,
+                offset: 0.0,
+            },
+        ],
+        window: Duration::from_secs(15 * 60),
+        anchor: Anchor::Values,
+        max_fees_per_day: 1_000_000,
+    },
+    // A position every 2 minutes while moving and every 6 hours standing, `geohash_precision: Some(6)`
+    // publishes a cell of about a kilometer instead
+    #[cfg(feature = "gps-beacon")]
+    gps_beacon: BeaconConfig {
+        baud_rate: 9600,
+        moving_interval: Duration::from_secs(2 * 60),
+        still_interval: Duration::from_secs(6 * 60 * 60),
+        moving_speed_mps: 1.0,
+        moving_distance_m: 50.0,
+        geohash_precision: None,
+        tracking_program: None,
+        max_fees_per_day: 1_000_000,
+    },
+    // A standard servo swinging from 30 to 150 degrees for 1 to 5 seconds, harder and longer the
+    // closer a payment comes to 0.1 SOL. `Actuation::Pwm { frequency_hz: 5_000, min_duty_percent: 20,
+    // max_duty_percent: 100 }` drives a motor or light through a MOSFET instead.
+    #[cfg(feature = "pay-actuator")]
+    pay_actuator: ActuatorConfig {
+        actuation: Actuation::Servo {
+            min_pulse_us: 500,
+            max_pulse_us: 2_500,
+            rest_degrees: 0,
+            min_degrees: 30,
+            max_degrees: 150,
+        },
+        min_lamports: 1_000_000,
+        full_lamports: 100_000_000,
+        min_duration: Duration::from_secs(1),
+        max_duration: Duration::from_secs(5),
+        poll_interval: Duration::from_secs(5),
+    },
+    // Buttons of the common 21-key NEC remotes, whose address is 0: CH shows the balance, EQ blanks
+    // the display. Unknown codes are logged, add a payment with e.g. `IrButton { address: 0x00,
+    // command: 0x0C, action: IrAction::Pay { recipient: pubkey!("<recipient>"), lamports: 1_000_000 } }`.
+    #[cfg(feature = "ir-remote")]
+    ir_remote: IrConfig {
+        buttons: &[
+            IrButton {
+                address: 0x00,
+                command: 0x46,
+                action: IrAction::ShowBalance,
+            },
+            IrButton {
+                address: 0x00,
+                command: 0x09,
+                action: IrAction::ToggleDisplay,
+            },
+        ],
+        repeat_guard: Duration::from_millis(500),
+        pay_interval: Duration::from_secs(10),
+    },
+    // A 58 mm ESC/POS panel printer at 9600 baud, drawing the explorer QR code itself. Printers
+    // without the QR code command take `QrStyle::Raster { scale: 6 }`.
+    #[cfg(feature = "receipt-printer")]
+    receipt_printer: PrinterConfig {
+        baud_rate: 9_600,
+        header: "REsp32Sol",
+        qr: QrStyle::Native { module_size: 6 },
+        cut: false,
+        confirm_timeout: Duration::from_secs(60),
+    },
+    // With a fingerprint module, an enrolled finger approves large transfers instead of the button.
+    // `enroll <pin>` in the provisioning window fills the module up to two fingers.
+    #[cfg(feature = "fingerprint")]
+    fingerprint: FingerprintConfig {
+        threshold_lamports: APPROVAL_THRESHOLD_LAMPORTS,
+        timeout: APPROVAL_TIMEOUT,
+        min_score: 50,
+        password: 0,
+        enroll_fingers: 2,
+    },
+    #[cfg(not(feature = "watch-only"))]
+    wallet: WalletConfig {
+        // Persisting the device key without flash encryption must be opted into explicitly
+        allow_plaintext_keystore: false,
+        // How long to wait for the signing PIN on the console at boot
+        pin_entry_timeout: Duration::from_secs(60),
+        approval_threshold_lamports: APPROVAL_THRESHOLD_LAMPORTS,
+        approval_timeout: APPROVAL_TIMEOUT,
+        // Accelerated on the ESP32 and ESP32-S3, whose SHA peripheral does SHA-512, software on the
+        // ESP32-C3 and ESP32-C6, build with --features bench-signing to compare on your chip
+        signing_backend: SigningBackend::for_chip(),
+        // Case switch or light sensor on GPIO3 that wipes all keys when triggered, None disables it.
+        // Only enable it once the input is wired, a floating pin would wipe the keys.
+        tamper_switch: None,
+        // Account publishing the minimum firmware version, payments are refused below it and the
+        // update at `update_url` is flashed
+        #[cfg(not(feature = "remote-signer"))]
+        firmware_floor: None,
+        // Stop all activity below the floor instead of only refusing payments, until the device is
+        // reflashed
+        #[cfg(not(feature = "remote-signer"))]
+        halt_below_floor: false,
+        // With a touch pad, holding a finger on it approves large transfers instead of the button, a
+        // tap shows the address QR code. T6 is GPIO14, free on the WT32-ETH01 and the ESP32-CAM.
+        #[cfg(feature = "touch-pad")]
+        touch: TouchConfig {
+            pad: esp_idf_svc::sys::touch_pad_t_TOUCH_PAD_NUM6,
+            touched_percent: 20,
+            long_touch: Duration::from_secs(2),
+            threshold_lamports: APPROVAL_THRESHOLD_LAMPORTS,
+            timeout: APPROVAL_TIMEOUT,
+        },
+        // Repetitions of each local step and RPC round trips timed by --features bench
+        #[cfg(feature = "bench")]
+        bench_iterations: 50,
+        #[cfg(feature = "bench")]
+        bench_rpc_calls: 5,
+    },
+    #[cfg(not(feature = "watch-only"))]
+    app: AppConfig {
+        // The task supervisor restarts the device when one pass of the main loop takes longer, waits
+        // for a PIN, an approval or the link included
+        #[cfg(not(feature = "remote-signer"))]
+        deadline: Duration::from_secs(600),
+        // Set to hold outgoing transfers for this long before sending, during which they can be
+        // cancelled on the console, None sends right away
+        #[cfg(not(any(feature = "remote-signer", feature = "receive-qr", feature = "pay-to-unlock")))]
+        outbox_delay: None,
+        // Battery operation: one transfer per wake, then deep sleep for the interval, e.g.
+        // `Some(SleepConfig { interval: Duration::from_secs(3600), wake_pin: None, wake_high: false })`
+        #[cfg(not(any(
+            feature = "remote-signer",
+            feature = "pay-button",
+            feature = "nfc",
+            feature = "receive-qr",
+            feature = "pay-to-unlock",
+            feature = "camera"
+        )))]
+        deep_sleep: None,
+        // What a tap on the NFC reader does, `TapAction::CheckOwnership { mint: pubkey!("<mint>") }`
+        // checks for a token instead of paying
+        #[cfg(feature = "nfc")]
+        nfc: NfcConfig {
+            reader: NfcReader::Pn532,
+            action: TapAction::Pay {
+                lamports: 10_000_000,
+                max_lamports: 100_000_000,
+            },
+            cooldown: Duration::from_secs(10),
+            require_counter: false,
+            // UIDs of the tags that may pay, as logged when a tag is tapped, e.g. "04a1b2c3d4e5f6"
+            enrolled: &[],
+        },
+        // The payment the receive-qr screen asks for, `spl_token: Some(pubkey!("<mint>"))` with the
+        // amount in the token's units to be paid in a token
+        #[cfg(feature = "receive-qr")]
+        receive: ReceiveConfig {
+            amount: "0.01",
+            spl_token: None,
+            label: "REsp32Sol",
+            message: None,
+            expiry: Duration::from_secs(300),
+            poll_interval: Duration::from_secs(3),
+        },
+        // Containers told apart by their empty weight, each filled for `dispense_for` once paid.
+        // `Price::Usd` converts at the Pyth SOL/USD feed, the receive config's expiry and poll
+        // interval apply to every sale.
+        #[cfg(feature = "vending")]
+        vending: VendingConfig {
+            products: &[
+                Product {
+                    name: "Small",
+                    grams: 12.0,
+                    tolerance_grams: 3.0,
+                    price: Price::Fixed("0.005"),
+                    dispense_for: Duration::from_secs(4),
+                },
+                Product {
+                    name: "Large",
+                    grams: 22.0,
+                    tolerance_grams: 3.0,
+                    price: Price::Usd(1.5),
+                    dispense_for: Duration::from_secs(8),
+                },
+            ],
+            counts_per_gram: 420.0,
+            settle: Duration::from_secs(2),
+            active_high: true,
+            price_feed: solana_program::pubkey!("7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE"),
+            max_price_age: Duration::from_secs(60),
+        },
+        // Every payment of `price` carrying the machine's reference holds the relay on for
+        // `unlock_for`. `reference: Some(pubkey!("<reference>"))` for codes printed before this
+        // device was provisioned, None generates one and logs the code to print at boot.
+        #[cfg(feature = "pay-to-unlock")]
+        unlock: UnlockConfig {
+            price: "0.01",
+            spl_token: None,
+            reference: None,
+            label: "REsp32Sol",
+            message: None,
+            unlock_for: Duration::from_secs(5),
+            active_high: true,
+            poll_interval: Duration::from_secs(3),
+        },
+        // The ESP32-CAM's camera, and without air-gap what the codes it scans get paid
+        #[cfg(feature = "camera")]
+        camera: CameraConfig {
+            pins: CameraPins::AI_THINKER,
+            repeat_after: Duration::from_secs(10),
+            lamports: 10_000_000,
+            max_lamports: 100_000_000,
+        },
+        // Amounts dialed in on the rotary encoder, in steps of 0.001, 0.01 and 0.1 SOL. With
+        // `receive-qr` asking for a token, decimals and unit are the token's.
+        #[cfg(feature = "rotary-encoder")]
+        dial: DialConfig {
+            decimals: 9,
+            steps: &[1_000_000, 10_000_000, 100_000_000],
+            max: 10_000_000_000,
+            unit: "SOL",
+        },
+        // Durable nonce account a LoRa node signs the demo transfer with, created with the device's
+        // address as its authority, e.g. `Some(pubkey!("<nonce account>"))`
+        #[cfg(all(
+            feature = "lora-bridge",
+            not(any(
+                feature = "remote-signer",
+                feature = "pay-button",
+                feature = "nfc",
+                feature = "receive-qr",
+                feature = "pay-to-unlock",
+                feature = "camera"
+            ))
+        ))]
+        lora_nonce_account: None,
+    },
+};
 
-fn main() -> Result<(), EspIOError> {
+fn main() {
     link_patches();
     EspLogger::initialize_default();
-
-    // WiFi initialization
-    let peripherals = Peripherals::take().unwrap();
-    let sys_loop = EspSystemEventLoop::take().unwrap();
-    let nvs = EspDefaultNvsPartition::take().unwrap();
-
-    let mut esp_wifi = EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs)).unwrap();
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sys_loop.clone()).unwrap();
-
-    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
-        esp_idf_svc::wifi::ClientConfiguration {
-            ssid: "berg_iot".try_into().unwrap(), // WiFi SSID
-            password: "bergiotsupersecret123.".try_into().unwrap(), // WiFi password
-            auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Personal,
-            ..Default::default()
-        },
-    ))
-    .unwrap();
-
-    wifi.start().unwrap();
-    wifi.connect().unwrap();
-    wifi.wait_netif_up().unwrap();
-
-    let keypair = Keypair::new();
-    info!("Keyapir generated for demo: {}", keypair.pubkey());
-
-    loop {
-        unsafe {
-            // Sleep for 2 seconds with each iteration
-            esp_idf_svc::sys::sleep(2);
-        }
-
-        if let Ok(blockhash) = get_latest_blockhash() {
-            info!("Latest blockhash: {}", blockhash);
-
-            // Example: Build and sign a transaction
-            let to_pubkey = Pubkey::new_unique();
-            let from_pubkey = keypair.pubkey();
-            // Transfer 1 sol
-            let instruction = system_instruction::transfer(&from_pubkey, &to_pubkey, LAMPORTS_PER_SOL);
-
-            let transaction = Transaction::new_signed_with_payer(
-                &[instruction], 
-                Some(&from_pubkey),
-                &[&keypair],
-                blockhash
-            );
-            
-            info!("Signed transaction: {:?}", transaction);
-
-            // Send the transaction to the Solana network
-            match send_transaction(&transaction) {
-                Ok(signature) => {
-                    info!("Transaction sent successfully!");
-                    info!("Transaction signature: {}", signature);
-                }
-                Err(e) => {
-                    info!("Failed to send transaction: {}", e);
-                }
-            }
-        } else {
-            info!("Failed to get blockhash");
-        }
-
-    }
+    app::start(&FIRMWARE)
 }
-
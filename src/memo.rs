@@ -0,0 +1,17 @@
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::pubkey;
+use solana_program::pubkey::Pubkey;
+
+pub const MEMO_PROGRAM_ID: Pubkey = pubkey!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+
+// SPL Memo instruction, every signer listed is verified by the memo program
+pub fn memo(memo: &str, signers: &[&Pubkey]) -> Instruction {
+    Instruction {
+        program_id: MEMO_PROGRAM_ID,
+        accounts: signers
+            .iter()
+            .map(|signer| AccountMeta::new_readonly(**signer, true))
+            .collect(),
+        data: memo.as_bytes().to_vec(),
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// The WiFi uplink, the others live in eth.rs and cellular.rs
+pub mod wifi;
+
+// Reconnect delays double from the first to the last, so a long outage doesn't keep the
+// radio busy
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// WiFi RSSI bands in dBm. Below the weak one TLS handshakes often die halfway, so non-urgent
+// sends wait. Wired and cellular uplinks report no RSSI and count as fair.
+const WEAK_RSSI: i8 = -80;
+const STRONG_RSSI: i8 = -65;
+// On a strong link a request this slow is stuck, not slow, and is better failed and retried
+const STRONG_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkQuality {
+    Strong,
+    Fair,
+    Weak,
+}
+
+impl LinkQuality {
+    pub fn rpc_timeout(self, configured: Duration) -> Duration {
+        match self {
+            LinkQuality::Strong => configured.min(STRONG_RPC_TIMEOUT),
+            LinkQuality::Fair | LinkQuality::Weak => configured,
+        }
+    }
+}
+
+pub fn link_quality() -> LinkQuality {
+    match wifi::rssi() {
+        Some(rssi) if rssi < WEAK_RSSI => LinkQuality::Weak,
+        Some(rssi) if rssi >= STRONG_RSSI => LinkQuality::Strong,
+        _ => LinkQuality::Fair,
+    }
+}
+
+// Whether the active uplink (WiFi, Ethernet or cellular) has an IP address, updated from its events
+static LINK_UP: Mutex<bool> = Mutex::new(false);
+static LINK_CHANGED: Condvar = Condvar::new();
+
+pub fn link_up() -> bool {
+    *LINK_UP.lock().unwrap()
+}
+
+// Blocks until the link is up, false if it didn't come back within the timeout
+pub fn wait_for_link(timeout: Duration) -> bool {
+    let up = LINK_UP.lock().unwrap();
+    let (up, _) = LINK_CHANGED.wait_timeout_while(up, timeout, |up| !*up).unwrap();
+    *up
+}
+
+pub fn set_link(up: bool) {
+    let changed = std::mem::replace(&mut *LINK_UP.lock().unwrap(), up) != up;
+    LINK_CHANGED.notify_all();
+    if changed {
+        notify(match up {
+            true => NetEvent::GotIp,
+            false => NetEvent::LostIp,
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetEvent {
+    // The active uplink has an address, RPC calls go through
+    GotIp,
+    LostIp,
+    // WiFi is rejoining or the modem redialing after a loss, Ethernet waits for the cable
+    Reconnecting,
+    // HTTP requests are being answered by something other than the intended server, typically
+    // a hotel or guest network login page (see captive.rs)
+    CaptivePortalSuspected,
+}
+
+type Listener = Arc<dyn Fn(NetEvent) + Send + Sync>;
+
+static LISTENERS: Mutex<Vec<(u32, Listener)>> = Mutex::new(Vec::new());
+static NEXT_LISTENER: AtomicU32 = AtomicU32::new(0);
+
+// Keeps a callback registered, dropping it unsubscribes
+pub struct NetSubscription(u32);
+
+impl Drop for NetSubscription {
+    fn drop(&mut self) {
+        LISTENERS.lock().unwrap().retain(|(id, _)| *id != self.0);
+    }
+}
+
+// Calls `callback` on every connectivity change. It runs on whichever thread reports the
+// change, often the system event loop, so it should hand anything slow to its own thread.
+pub fn subscribe(callback: impl Fn(NetEvent) + Send + Sync + 'static) -> NetSubscription {
+    let id = NEXT_LISTENER.fetch_add(1, Ordering::Relaxed);
+    LISTENERS.lock().unwrap().push((id, Arc::new(callback)));
+    NetSubscription(id)
+}
+
+pub fn notify(event: NetEvent) {
+    // Called outside the lock, so a callback may subscribe or unsubscribe
+    let listeners: Vec<Listener> = LISTENERS.lock().unwrap().iter().map(|(_, listener)| listener.clone()).collect();
+    for listener in listeners {
+        listener(event);
+    }
+}
+
+// Steps the stall watchdog (see netwatch.rs) asks the active uplink to take, mildest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    RestartDhcp,
+    Reassociate,
+    // Stops and restarts the WiFi or Ethernet driver, resets the cellular modem
+    PowerCycle,
+}
+
+type RecoveryHandler = Box<dyn Fn(Recovery) -> bool + Send>;
+
+static RECOVERY: Mutex<Option<RecoveryHandler>> = Mutex::new(None);
+
+// Registered by the uplink that came up, returns false for steps that don't apply to it
+pub fn set_recovery(handler: impl Fn(Recovery) -> bool + Send + 'static) {
+    *RECOVERY.lock().unwrap() = Some(Box::new(handler));
+}
+
+// Whether the active uplink took the step
+pub fn recover(step: Recovery) -> bool {
+    RECOVERY.lock().unwrap().as_ref().is_some_and(|handler| handler(step))
+}
+
+pub struct Backoff(Duration);
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self(MIN_BACKOFF)
+    }
+
+    // Never runs out, so not an Iterator
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Duration {
+        let delay = self.0;
+        self.0 = (self.0 * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
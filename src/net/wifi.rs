@@ -0,0 +1,378 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::eventloop::{EspSystemEventLoop, EspSystemSubscription};
+use esp_idf_svc::hal::task::block_on;
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::ipv4;
+use esp_idf_svc::netif::{EspNetif, IpEvent, NetifConfiguration};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspEncryptedNvsPartition};
+use esp_idf_svc::sys::{esp_netif_dhcpc_start, esp_netif_dhcpc_stop, esp_wifi_sta_get_ap_info, wifi_ap_record_t, ESP_OK};
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::timer::{EspAsyncTimer, EspTaskTimerService};
+use esp_idf_svc::wifi::{AsyncWifi, ClientConfiguration, Configuration, EspWifi, WifiEvent};
+use log::{info, warn};
+
+#[cfg(feature = "ble-provisioning")]
+use crate::ble_prov;
+use crate::config::{stored_wifi_networks, StaticIp, WifiCerts, WifiCredentials, WifiNetworks, WifiSecurity};
+use crate::dualstack;
+use crate::eap;
+use crate::error::NetError;
+use crate::lifecycle::{self, WalletEvent};
+use crate::net::{self, set_link, Backoff, NetEvent, Recovery};
+use crate::portal;
+use crate::recovery::{self, BootStep};
+use crate::tasks::{self, NETWORK};
+
+// Passes over all known networks before the setup portal opens for a while, over and over
+// until one of them can be joined
+const CONNECT_ROUNDS: u32 = 3;
+// How long a joined network has to hand out an IPv4 or global IPv6 address
+const ADDRESS_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Static address the station interface was created with, None for the default DHCP interface
+static STATION_IP: Mutex<Option<StaticIp>> = Mutex::new(None);
+
+// Gets the device set up if it isn't, then joins one of the stored networks and keeps the link
+// up on a thread of its own. Returns before a network is joined, RPC calls wait for the link
+// meanwhile, so the rest of the device boots while the driver associates. Fails when the driver
+// or the manager can't start, or when `recover` lets a device without usable credentials run
+// offline rather than wait for provisioning.
+pub fn connect(
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    encrypted: Option<EspEncryptedNvsPartition>,
+    recover: recovery::Handler,
+) -> Result<(), NetError> {
+    // Enterprise networks' certificates and keys, in the encrypted partition when there is one
+    let mut certs = WifiCerts::open(nvs.clone(), encrypted).map_err(NetError::Driver)?;
+    let esp_wifi = EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone()))
+        .map_err(|e| NetError::Driver(format!("WiFi driver: {:?}", e)))?;
+    let timers = EspTaskTimerService::new().map_err(|e| NetError::Driver(format!("Timer service: {:?}", e)))?;
+    let timer = timers.timer_async().map_err(|e| NetError::Driver(format!("Timer: {:?}", e)))?;
+    let mut wifi = AsyncWifi::wrap(esp_wifi, sys_loop.clone(), timers)
+        .map_err(|e| NetError::Driver(format!("WiFi driver: {:?}", e)))?;
+
+    // Subscribed before anything is joined, so a failure leaves the driver unused rather than
+    // a link nobody supervises
+    let (wake, wakes) = channel();
+    let disconnected = wake.clone();
+    let wifi_events = sys_loop
+        .subscribe::<WifiEvent, _>(move |event| {
+            if let WifiEvent::StaDisconnected(_) = event {
+                set_link(false);
+                let _ = disconnected.send(Wake::Disconnected);
+            }
+        })
+        .map_err(|e| NetError::Driver(format!("WiFi events: {:?}", e)))?;
+    let ip_events = sys_loop
+        .subscribe::<IpEvent, _>(|event| match event {
+            IpEvent::DhcpIpAssigned(_) | IpEvent::DhcpIp6Assigned(_) => set_link(true),
+            IpEvent::DhcpIpDeassigned(_) => set_link(false),
+            _ => {}
+        })
+        .map_err(|e| NetError::Driver(format!("IP events: {:?}", e)))?;
+
+    // An unconfigured device gets its credentials from a phone over BLE, or in the setup portal.
+    // It has nothing to do without them, so this part blocks.
+    let networks = match recovery::attempt(BootStep::Credentials, recover, || usable_networks(nvs.clone())) {
+        Ok(networks) => networks,
+        Err(failure) if failure.recovery == recovery::Recovery::Provision => {
+            provision(&mut wifi, nvs.clone(), &mut certs)
+        }
+        Err(failure) => return Err(NetError::NotConfigured(failure.to_string())),
+    };
+    lifecycle::notify(WalletEvent::Provisioned);
+
+    // The manager owns the driver and the subscriptions for the rest of the device's life
+    let subscriptions = (wifi_events, ip_events);
+    tasks::spawn(&NETWORK, move || manage(wifi, timer, networks, certs, nvs, wake, wakes, subscriptions))
+        .map_err(NetError::Driver)
+}
+
+// The stored networks, as long as the driver can be configured with at least one of them.
+// join_any skips the others.
+fn usable_networks(nvs: EspDefaultNvsPartition) -> Result<WifiNetworks, NetError> {
+    let networks =
+        stored_wifi_networks(nvs).ok_or_else(|| NetError::NotConfigured("No WiFi network stored".to_string()))?;
+    match networks.networks().iter().any(|credentials| client_configuration(credentials).is_ok()) {
+        true => Ok(networks),
+        false => Err(NetError::NotConfigured("None of the stored WiFi networks is usable".to_string())),
+    }
+}
+
+// BLE provisioning where it is built in, the setup portal when that fails or isn't
+#[allow(unused_variables)]
+fn provision(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    nvs: EspDefaultNvsPartition,
+    certs: &mut WifiCerts,
+) -> WifiNetworks {
+    #[cfg(feature = "ble-provisioning")]
+    match ble_prov::provision(wifi, nvs.clone()).and_then(|_| usable_networks(nvs.clone()).map_err(|e| e.to_string())) {
+        Ok(networks) => return networks,
+        Err(e) => warn!("BLE provisioning failed: {}", e),
+    }
+    lifecycle::notify(WalletEvent::SetupNeeded);
+    portal::run(wifi, nvs, certs)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn manage(
+    mut wifi: AsyncWifi<EspWifi<'static>>,
+    mut timer: EspAsyncTimer,
+    mut networks: WifiNetworks,
+    mut certs: WifiCerts,
+    nvs: EspDefaultNvsPartition,
+    wake: Sender<Wake>,
+    wakes: Receiver<Wake>,
+    _subscriptions: (EspSystemSubscription<'static>, EspSystemSubscription<'static>),
+) -> ! {
+    loop {
+        let joined = block_on(async {
+            let mut backoff = Backoff::new();
+            for round in 1..=CONNECT_ROUNDS {
+                if join_any(&mut wifi, &mut networks, &mut certs).await {
+                    return true;
+                }
+                if round < CONNECT_ROUNDS {
+                    pause(&mut timer, backoff.next()).await;
+                }
+            }
+            false
+        });
+        if joined {
+            break;
+        }
+        // Wrong passwords or the networks are gone, or only down for now. The user can enter
+        // new details behind the portal's passphrase, the networks are tried again after.
+        warn!("Could not join any known WiFi network, starting the setup portal");
+        lifecycle::notify(WalletEvent::SetupNeeded);
+        portal::run_recovery(&mut wifi, nvs.clone(), &mut certs);
+        lifecycle::notify(WalletEvent::Provisioned);
+    }
+    // Failed attempts report disconnects of their own
+    while let Ok(Wake::Disconnected) = wakes.try_recv() {}
+    set_link(true);
+
+    // Recovery runs on the manager, which owns the driver
+    net::set_recovery(move |step| {
+        let wanted = match step {
+            // A static address has no lease to renew
+            Recovery::RestartDhcp if STATION_IP.lock().unwrap().is_some() => return false,
+            Recovery::RestartDhcp => Wake::RestartDhcp,
+            Recovery::Reassociate => Wake::Reassociate,
+            Recovery::PowerCycle => Wake::RestartDriver,
+        };
+        wake.send(wanted).is_ok()
+    });
+
+    supervise(wifi, timer, networks, certs, wakes)
+}
+
+// What the manager wakes up for
+enum Wake {
+    Disconnected,
+    RestartDhcp,
+    Reassociate,
+    RestartDriver,
+}
+
+// Signal of the joined access point in dBm, None while WiFi isn't connected (e.g. on Ethernet)
+pub fn rssi() -> Option<i8> {
+    let mut ap_info = wifi_ap_record_t::default();
+    match unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) } {
+        ESP_OK => Some(ap_info.rssi),
+        _ => None,
+    }
+}
+
+// Recreates the station interface when the network being joined is addressed differently
+// from the last one
+async fn apply_ip(wifi: &mut AsyncWifi<EspWifi<'static>>, ip: Option<&StaticIp>) -> Result<(), NetError> {
+    // Only the manager sets it, the lock isn't held over the driver calls
+    if STATION_IP.lock().unwrap().as_ref() == ip {
+        return Ok(());
+    }
+
+    let ip_configuration = match ip {
+        Some(ip) => ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+            ip: ip.address,
+            subnet: ipv4::Subnet {
+                gateway: ip.gateway,
+                mask: ipv4::Mask(ip.prefix_len),
+            },
+            dns: Some(ip.dns),
+            secondary_dns: ip.secondary_dns,
+        }),
+        None => ipv4::ClientConfiguration::default(),
+    };
+    let netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: Some(ipv4::Configuration::Client(ip_configuration)),
+        ..NetifConfiguration::wifi_default_client()
+    })
+    .map_err(|e| NetError::Driver(format!("Station interface: {:?}", e)))?;
+
+    // The interface is only swapped with the driver stopped
+    if wifi.is_started().map_err(|e| NetError::Driver(format!("WiFi state: {:?}", e)))? {
+        wifi.stop().await.map_err(|e| NetError::Driver(format!("WiFi stop: {:?}", e)))?;
+    }
+    wifi.wifi_mut()
+        .swap_netif_sta(netif)
+        .map_err(|e| NetError::Driver(format!("Station interface swap: {:?}", e)))?;
+
+    match ip {
+        Some(ip) => info!("Using static address {}/{}", ip.address, ip.prefix_len),
+        None => info!("Using DHCP"),
+    }
+    *STATION_IP.lock().unwrap() = ip.cloned();
+    Ok(())
+}
+
+async fn join(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    networks: &WifiNetworks,
+    certs: &mut WifiCerts,
+    index: usize,
+) -> Result<(), NetError> {
+    let credentials = &networks.networks()[index];
+    apply_ip(wifi, credentials.ip.as_ref()).await?;
+    let enterprise = match &credentials.security {
+        WifiSecurity::Enterprise(enterprise) => Some(enterprise),
+        _ => None,
+    };
+
+    wifi.set_configuration(&client_configuration(credentials)?)
+        .map_err(|e| NetError::Driver(format!("WiFi config: {:?}", e)))?;
+    eap::configure(enterprise, &credentials.password, certs).map_err(NetError::Driver)?;
+    if !wifi.is_started().map_err(|e| NetError::Driver(format!("WiFi state: {:?}", e)))? {
+        wifi.start().await.map_err(|e| NetError::Driver(format!("WiFi start: {:?}", e)))?;
+    }
+
+    let joined = match wifi.connect().await {
+        Ok(()) => wait_for_address(wifi).await,
+        Err(e) => Err(e),
+    };
+    if joined.is_err() {
+        let _ = wifi.disconnect().await;
+    }
+    joined.map_err(|e| NetError::Join(format!("{:?}", e)))
+}
+
+// The driver's station configuration, which fails for an SSID or password too long for it
+fn client_configuration(credentials: &WifiCredentials) -> Result<Configuration, NetError> {
+    let enterprise = matches!(credentials.security, WifiSecurity::Enterprise(_));
+    Ok(Configuration::Client(ClientConfiguration {
+        ssid: credentials
+            .ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| NetError::NotConfigured(format!("SSID '{}' too long", credentials.ssid)))?,
+        // Enterprise networks take the password through the supplicant instead
+        password: match enterprise {
+            true => Default::default(),
+            false => credentials
+                .password
+                .as_str()
+                .try_into()
+                .map_err(|_| NetError::NotConfigured("Password too long".to_string()))?,
+        },
+        auth_method: credentials.auth_method(),
+        ..Default::default()
+    }))
+}
+
+// IPv6-only networks never bring the interface up the IPv4 way, a global IPv6 address counts too
+async fn wait_for_address(wifi: &mut AsyncWifi<EspWifi<'static>>) -> Result<(), EspError> {
+    let netif = wifi.wifi().sta_netif().handle();
+    dualstack::enable(netif);
+    wifi.ip_wait_while(
+        |wifi| Ok(!wifi.is_up()? && !dualstack::has_global_address(netif)),
+        Some(ADDRESS_TIMEOUT),
+    )
+    .await
+}
+
+// One pass over the known networks, the last one that worked first
+async fn join_any(wifi: &mut AsyncWifi<EspWifi<'static>>, networks: &mut WifiNetworks, certs: &mut WifiCerts) -> bool {
+    for index in networks.order() {
+        let ssid = networks.networks()[index].ssid.clone();
+        match join(wifi, networks, certs, index).await {
+            Ok(()) => {
+                match rssi() {
+                    Some(rssi) => info!("WiFi connected to {} ({} dBm)", ssid, rssi),
+                    None => info!("WiFi connected to {}", ssid),
+                }
+                if let Err(e) = networks.remember(index) {
+                    warn!("WiFi network not remembered: {}", e);
+                }
+                return true;
+            }
+            Err(e) => warn!("Joining {} failed: {}", ssid, e),
+        }
+    }
+    false
+}
+
+fn supervise(
+    mut wifi: AsyncWifi<EspWifi<'static>>,
+    mut timer: EspAsyncTimer,
+    mut networks: WifiNetworks,
+    mut certs: WifiCerts,
+    wakes: Receiver<Wake>,
+) -> ! {
+    loop {
+        // The disconnect sender lives in the WiFi subscription this thread keeps, so this only
+        // returns on events
+        match wakes.recv() {
+            Ok(Wake::RestartDhcp) => {
+                warn!("Restarting DHCP");
+                let netif = wifi.wifi().sta_netif().handle();
+                unsafe {
+                    esp_netif_dhcpc_stop(netif);
+                    esp_netif_dhcpc_start(netif);
+                }
+                continue;
+            }
+            Ok(Wake::Reassociate) => {
+                warn!("Reassociating with the access point");
+                let _ = block_on(wifi.disconnect());
+            }
+            Ok(Wake::RestartDriver) => {
+                warn!("Restarting the WiFi driver");
+                // Joining starts it again
+                let _ = block_on(wifi.stop());
+            }
+            Ok(Wake::Disconnected) | Err(_) => warn!("WiFi link lost, pausing RPC until it is back"),
+        }
+        set_link(false);
+        net::notify(NetEvent::Reconnecting);
+
+        // The network that was just lost comes first, e.g. after a router reboot
+        block_on(async {
+            let mut backoff = Backoff::new();
+            while !join_any(&mut wifi, &mut networks, &mut certs).await {
+                let delay = backoff.next();
+                warn!("No known WiFi network reachable, retrying in {}s", delay.as_secs());
+                pause(&mut timer, delay).await;
+            }
+        });
+        // Failed attempts report disconnects of their own
+        while let Ok(Wake::Disconnected) = wakes.try_recv() {}
+
+        set_link(true);
+        info!("WiFi link restored");
+    }
+}
+
+// Waits on an ESP timer instead of sleeping the thread, a failing timer falls back to sleeping so
+// retries never spin
+async fn pause(timer: &mut EspAsyncTimer, delay: Duration) {
+    if timer.after(delay).await.is_err() {
+        std::thread::sleep(delay);
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::net::{self, Recovery};
+
+// Recovers devices whose uplink reports connected while nothing gets through, e.g. a wedged
+// access point, an expired DHCP lease that never renewed or a modem stuck in a data call.
+// When RPC calls have failed for STALL_TIME without one reaching a server, it escalates one
+// step at a time and gives each step STEP_GRACE to show an effect before the next.
+
+const STALL_TIME: Duration = Duration::from_secs(5 * 60);
+const STEP_GRACE: Duration = Duration::from_secs(2 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const ESCALATION: [Recovery; 3] = [Recovery::RestartDhcp, Recovery::Reassociate, Recovery::PowerCycle];
+const WATCHDOG_STACK_SIZE: usize = 4 * 1024;
+
+// First and latest failure since an RPC call last reached a server
+static FAILURES: Mutex<Option<(Instant, Instant)>> = Mutex::new(None);
+
+// Called by the RPC client for every request it made with the link up. Any HTTP status counts
+// as reached, errors from the server say nothing about the network.
+pub fn record(reached: bool) {
+    let mut failures = FAILURES.lock().unwrap();
+    *failures = match (reached, *failures) {
+        (true, _) => None,
+        (false, Some((first, _))) => Some((first, Instant::now())),
+        (false, None) => Some((Instant::now(), Instant::now())),
+    };
+}
+
+pub fn spawn() -> Result<(), String> {
+    std::thread::Builder::new()
+        .name("net-watchdog".to_string())
+        .stack_size(WATCHDOG_STACK_SIZE)
+        .spawn(watch)
+        .map(|_| ())
+        .map_err(|e| format!("Network watchdog: {:?}", e))
+}
+
+fn watch() {
+    // Index into ESCALATION of the next step, and when the last one was taken
+    let mut step = 0;
+    let mut acted_at: Option<Instant> = None;
+    loop {
+        std::thread::sleep(CHECK_INTERVAL);
+        let Some((first, latest)) = *FAILURES.lock().unwrap() else {
+            if step > 0 {
+                info!("RPC reachable again, network recovered");
+                step = 0;
+                acted_at = None;
+            }
+            continue;
+        };
+        // A link that is down is the uplink supervisor's to bring back
+        if !net::link_up() || first.elapsed() < STALL_TIME {
+            continue;
+        }
+        // Each step needs a failure after the last one, not just time passing without calls
+        if let Some(acted_at) = acted_at {
+            if acted_at.elapsed() < STEP_GRACE || latest < acted_at {
+                continue;
+            }
+        }
+
+        loop {
+            let Some(&recovery) = ESCALATION.get(step) else {
+                warn!("RPC unreachable for {}s through every recovery step, rebooting", first.elapsed().as_secs());
+                unsafe { esp_idf_svc::sys::esp_restart() }
+            };
+            step += 1;
+            if net::recover(recovery) {
+                warn!("RPC unreachable for {}s with the link up, trying {:?}", first.elapsed().as_secs(), recovery);
+                break;
+            }
+        }
+        acted_at = Some(Instant::now());
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+
+use crate::solanapay;
+use crate::solrpc::get_token_account_balance;
+use crate::token;
+
+// Tap-to-pay: an NTAG21x sticker or card holding a pubkey or a Solana Pay URL, as an NDEF URI
+// or text record, is read by a PN532 or MFRC522 on a thread of its own. Taps queue up for the
+// main loop, which pays the address or checks that it holds a token, e.g. an NFT membership pass.
+// A tag is only acted on once per cooldown, and tags with the NTAG NFC counter enabled must show
+// a higher count than on their last tap, which a copy replaying a recorded read can't. Only
+// tags enrolled in the config can pay.
+
+const TAGS_NAMESPACE: &str = "nfctags";
+// Counters of tags outside the enrolled list, kept in RAM only so a UID-cycling emulator can't
+// fill NVS. The stalest is forgotten when it is full.
+const MAX_UNENROLLED: usize = 32;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// A tag that misses this many polls in a row was taken away, the first miss can be a bad read
+const ABSENT_POLLS: u32 = 3;
+// User memory of an NTAG21x starts at page 4, the largest (NTAG216) has 888 bytes of it
+const FIRST_USER_PAGE: u8 = 4;
+const MAX_NDEF_LEN: usize = 888;
+const LISTENER_STACK_SIZE: usize = 6 * 1024;
+
+// What a reader has to offer: one ISO14443A tag in the field at a time, NTAG21x commands
+pub trait TagReader: Send {
+    // The UID of the tag in the field, selected for the reads that follow
+    fn select(&mut self) -> Result<Option<Vec<u8>>, String>;
+    // Four pages, 16 bytes, starting at `page`
+    fn read(&mut self, page: u8) -> Result<[u8; 16], String>;
+    // The NFC counter, None when the tag has it disabled. A refusal ends the session with the
+    // tag, so it is read last.
+    fn read_counter(&mut self) -> Result<Option<u32>, String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NfcReader {
+    // On I2C0, SDA GPIO5 and SCL GPIO6
+    Pn532,
+    // On SPI2, SCLK GPIO6, MOSI GPIO7, MISO GPIO2 and CS GPIO10
+    Rc522,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TapAction {
+    // Pays tags holding an address `lamports`, Solana Pay URLs the amount they ask for up to
+    // `max_lamports`. Token amounts are left to the spending policy.
+    Pay { lamports: u64, max_lamports: u64 },
+    // Checks the address on the tag holds a token of the mint
+    CheckOwnership { mint: Pubkey },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NfcConfig {
+    pub reader: NfcReader,
+    pub action: TapAction,
+    // Taps of the same tag within this long are ignored
+    pub cooldown: Duration,
+    // Refuses tags without the NFC counter, which can't be told from a copy
+    pub require_counter: bool,
+    // UIDs in hex, as logged on a tap, of the tags that may pay. Only their counters are kept
+    // in NVS.
+    pub enrolled: &'static [&'static str],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub uid: Vec<u8>,
+    // The URI or text of the first NDEF record that has one
+    pub content: Option<String>,
+    pub counter: Option<u32>,
+}
+
+impl Tag {
+    pub fn id(&self) -> String {
+        self.uid.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+static TAPS: Mutex<Option<Receiver<Tag>>> = Mutex::new(None);
+
+// Polls the reader on a thread of its own, each tag brought into the field queues one tap for
+// next_tap
+pub fn listen(mut reader: Box<dyn TagReader>) -> Result<(), String> {
+    let (taps, received) = channel();
+    std::thread::Builder::new()
+        .name("nfc".to_string())
+        .stack_size(LISTENER_STACK_SIZE)
+        .spawn(move || {
+            let mut present: Option<Vec<u8>> = None;
+            let mut misses = 0;
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+                let uid = match reader.select() {
+                    Ok(uid) => uid,
+                    Err(e) => {
+                        warn!("NFC poll failed: {}", e);
+                        None
+                    }
+                };
+                let Some(uid) = uid else {
+                    misses += 1;
+                    if misses >= ABSENT_POLLS {
+                        present = None;
+                    }
+                    continue;
+                };
+                misses = 0;
+                // Held on the reader, not tapped again
+                if present.as_ref() == Some(&uid) {
+                    continue;
+                }
+                match read_tag(reader.as_mut(), uid.clone()) {
+                    Ok(tag) => {
+                        present = Some(uid);
+                        let _ = taps.send(tag);
+                    }
+                    // Retried on the next poll, the tag was likely pulled away mid-read
+                    Err(e) => warn!("NFC tag read failed: {}", e),
+                }
+            }
+        })
+        .map_err(|e| format!("NFC listener: {:?}", e))?;
+
+    *TAPS.lock().unwrap() = Some(received);
+    info!("NFC reader listening");
+    Ok(())
+}
+
+// The next tap, None when there was none within the timeout
+pub fn next_tap(timeout: Duration) -> Option<Tag> {
+    let taps = TAPS.lock().unwrap();
+    match taps.as_ref()?.recv_timeout(timeout) {
+        Ok(tag) => Some(tag),
+        Err(RecvTimeoutError::Timeout) => None,
+        Err(RecvTimeoutError::Disconnected) => {
+            drop(taps);
+            std::thread::sleep(timeout);
+            None
+        }
+    }
+}
+
+fn read_tag(reader: &mut dyn TagReader, uid: Vec<u8>) -> Result<Tag, String> {
+    // Type 2 tag TLVs: NULL padding, lock and memory control, the NDEF message, terminator
+    let mut memory = Vec::new();
+    let mut page = FIRST_USER_PAGE;
+    let mut fill = |memory: &mut Vec<u8>, len: usize, reader: &mut dyn TagReader| -> Result<(), String> {
+        while memory.len() < len {
+            memory.extend_from_slice(&reader.read(page)?);
+            page = page.checked_add(4).ok_or("Tag memory ended")?;
+        }
+        Ok(())
+    };
+    let mut offset = 0;
+    let ndef = loop {
+        fill(&mut memory, offset + 1, reader)?;
+        match memory[offset] {
+            0x00 => offset += 1,
+            0xFE => break None,
+            tlv_type => {
+                fill(&mut memory, offset + 4, reader)?;
+                let (len, header) = match memory[offset + 1] {
+                    0xFF => (u16::from_be_bytes([memory[offset + 2], memory[offset + 3]]) as usize, 4),
+                    len => (len as usize, 2),
+                };
+                if len > MAX_NDEF_LEN {
+                    return Err(format!("Tag TLV of {} bytes", len));
+                }
+                let value = offset + header;
+                if tlv_type == 0x03 {
+                    fill(&mut memory, value + len, reader)?;
+                    break Some(memory[value..value + len].to_vec());
+                }
+                offset = value + len;
+            }
+        }
+        if offset >= MAX_NDEF_LEN {
+            break None;
+        }
+    };
+    let content = ndef.as_deref().map(ndef_content).transpose()?.flatten();
+    // Read after the NDEF message, the counter counts the first read of each tap
+    let counter = reader.read_counter()?;
+    Ok(Tag { uid, content, counter })
+}
+
+// The URI or text of the first well-known record carrying one
+fn ndef_content(message: &[u8]) -> Result<Option<String>, String> {
+    let mut offset = 0;
+    while offset < message.len() {
+        let header = message[offset];
+        let short = header & 0x10 != 0;
+        let has_id = header & 0x08 != 0;
+        let type_len = *message.get(offset + 1).ok_or("NDEF record truncated")? as usize;
+        let mut cursor = offset + 2;
+        let payload_len = if short {
+            cursor += 1;
+            *message.get(cursor - 1).ok_or("NDEF record truncated")? as usize
+        } else {
+            cursor += 4;
+            let len = message.get(cursor - 4..cursor).ok_or("NDEF record truncated")?;
+            u32::from_be_bytes(len.try_into().unwrap()) as usize
+        };
+        let id_len = if has_id {
+            cursor += 1;
+            *message.get(cursor - 1).ok_or("NDEF record truncated")? as usize
+        } else {
+            0
+        };
+        let record_type = message.get(cursor..cursor + type_len).ok_or("NDEF record truncated")?;
+        let payload_start = cursor + type_len + id_len;
+        let payload = message
+            .get(payload_start..payload_start + payload_len)
+            .ok_or("NDEF record truncated")?;
+
+        // Well-known type, NFC Forum RTD URI and Text
+        if header & 0x07 == 0x01 {
+            match record_type {
+                // Prefix code 0, no abbreviation, is the only one a solana: URL or address can use
+                b"U" if payload.first() == Some(&0x00) => {
+                    return String::from_utf8(payload[1..].to_vec())
+                        .map(Some)
+                        .map_err(|e| format!("NDEF URI: {:?}", e))
+                }
+                // Status byte: UTF-16 flag and the length of the language code that follows
+                b"T" if payload.first().is_some_and(|status| status & 0x80 == 0) => {
+                    let text = payload.get(1 + (payload[0] & 0x3F) as usize..).ok_or("NDEF text truncated")?;
+                    return String::from_utf8(text.to_vec())
+                        .map(Some)
+                        .map_err(|e| format!("NDEF text: {:?}", e));
+                }
+                _ => {}
+            }
+        }
+        // Message end
+        if header & 0x40 != 0 {
+            break;
+        }
+        offset = payload_start + payload_len;
+    }
+    Ok(None)
+}
+
+// Per-tag cooldowns, and the last NFC counter of each tag: in NVS across reboots for enrolled
+// tags, in RAM for the others. Only enrolled tags are admitted when taps pay.
+pub struct ReplayGuard {
+    nvs: EspNvs<NvsDefault>,
+    cooldown: Duration,
+    require_counter: bool,
+    enrolled: &'static [&'static str],
+    pays: bool,
+    last_taps: HashMap<Vec<u8>, Instant>,
+    unenrolled_counters: HashMap<Vec<u8>, (u32, Instant)>,
+}
+
+impl ReplayGuard {
+    pub fn open(nvs: EspDefaultNvsPartition, config: &NfcConfig) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, TAGS_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        Ok(Self {
+            nvs,
+            cooldown: config.cooldown,
+            require_counter: config.require_counter,
+            enrolled: config.enrolled,
+            pays: matches!(config.action, TapAction::Pay { .. }),
+            last_taps: HashMap::new(),
+            unenrolled_counters: HashMap::new(),
+        })
+    }
+
+    // Admits the tap or says why not. An admitted tap counts whatever happens to it after.
+    pub fn admit(&mut self, tag: &Tag) -> Result<(), String> {
+        let now = Instant::now();
+        self.last_taps.retain(|_, tapped| now.duration_since(*tapped) < self.cooldown);
+        if self.last_taps.contains_key(&tag.uid) {
+            return Err("tapped again within the cooldown".to_string());
+        }
+
+        let enrolled = self.enrolled.iter().any(|id| id.eq_ignore_ascii_case(&tag.id()));
+        if self.pays && !enrolled {
+            return Err("tag not enrolled for payments".to_string());
+        }
+
+        let key = counter_key(&tag.uid);
+        let last = match enrolled {
+            true => self.nvs.get_u32(&key).map_err(|e| format!("Tag counter read: {:?}", e))?,
+            false => self.unenrolled_counters.get(&tag.uid).map(|(counter, _)| *counter),
+        };
+        match (tag.counter, last) {
+            (Some(counter), Some(last)) if counter <= last => {
+                return Err(format!("counter {} not above {}, a replayed read", counter, last));
+            }
+            // A tag that had a counter doesn't lose it, a copy without one does
+            (None, Some(_)) => return Err("counter missing on a tag that had one".to_string()),
+            (None, None) if self.require_counter => return Err("tag without an NFC counter".to_string()),
+            _ => {}
+        }
+        match (tag.counter, enrolled) {
+            (Some(counter), true) => self
+                .nvs
+                .set_u32(&key, counter)
+                .map_err(|e| format!("Tag counter write: {:?}", e))?,
+            (Some(counter), false) => {
+                let full = self.unenrolled_counters.len() >= MAX_UNENROLLED;
+                if full && !self.unenrolled_counters.contains_key(&tag.uid) {
+                    let stalest = self
+                        .unenrolled_counters
+                        .iter()
+                        .min_by_key(|(_, (_, seen))| *seen)
+                        .map(|(uid, _)| uid.clone());
+                    if let Some(uid) = stalest {
+                        self.unenrolled_counters.remove(&uid);
+                    }
+                }
+                self.unenrolled_counters.insert(tag.uid.clone(), (counter, now));
+            }
+            (None, _) => {}
+        }
+        self.last_taps.insert(tag.uid.clone(), now);
+        Ok(())
+    }
+}
+
+// NVS keys are at most 15 characters, the first 7 bytes of a UID are its manufacturer and serial
+fn counter_key(uid: &[u8]) -> String {
+    let id: String = uid.iter().take(7).map(|byte| format!("{:02x}", byte)).collect();
+    format!("c{}", id)
+}
+
+// Whether the owner's token account for the mint holds any, only the associated account counts
+pub fn holds_token(owner: &Pubkey, mint: &Pubkey) -> Result<bool, String> {
+    let (token_program, _) = solanapay::mint_info(mint)?;
+    let account = token::associated_token_address(owner, mint, &token_program);
+    Ok(get_token_account_balance(&account).map_err(|e| e.to_string())?.is_some_and(|(amount, _)| amount > 0))
+}
@@ -0,0 +1,280 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use solana_program::instruction::Instruction;
+use solana_program::pubkey::Pubkey;
+use solana_system_interface::instruction::{self as system_instruction, SystemInstruction};
+use solana_transaction::{Signature, Transaction};
+
+use crate::error::RpcError;
+use crate::net;
+use crate::solrpc::send_transaction;
+use crate::timesync;
+
+const OFFLINE_NAMESPACE: &str = "offline";
+const HEAD_KEY: &str = "head";
+const TAIL_KEY: &str = "tail";
+const ITEM_PREFIX: &str = "tx";
+// Enough for a day of hourly payments, a full queue refuses new items instead of dropping old ones
+const CAPACITY: u32 = 32;
+// Item header: queued at and expires at in unix seconds, 0 when the clock wasn't trusted
+const HEADER_LEN: usize = 16;
+// A serialized transaction never exceeds the 1232-byte packet limit
+const MAX_ITEM_LEN: usize = HEADER_LEN + 1232;
+// Sends refused for good (e.g. an advanced nonce) before an item is given up on. Temporary
+// failures, a node timing out or answering 429 or 5xx, never count, those items wait for as long
+// as their TTL, retried with a delay doubling up to MAX_RETRY_DELAY.
+const FLUSH_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+const IDLE_POLL: Duration = Duration::from_secs(5);
+const FLUSHER_STACK_SIZE: usize = 8 * 1024;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+// A recent blockhash expires within two minutes, so only durable-nonce transactions survive the
+// wait. Builds one whose first instruction advances the nonce, sign it with the nonce as blockhash.
+pub fn nonce_transaction(
+    instructions: &[Instruction],
+    payer: &Pubkey,
+    nonce_account: &Pubkey,
+    nonce_authority: &Pubkey,
+) -> Transaction {
+    let mut all = vec![system_instruction::advance_nonce_account(nonce_account, nonce_authority)];
+    all.extend_from_slice(instructions);
+    Transaction::new_with_payer(&all, Some(payer))
+}
+
+pub fn uses_durable_nonce(transaction: &Transaction) -> bool {
+    let message = &transaction.message;
+    message.instructions.first().is_some_and(|instruction| {
+        message.account_keys.get(instruction.program_id_index as usize) == Some(&solana_system_interface::program::ID)
+            && matches!(
+                bincode::deserialize::<SystemInstruction>(&instruction.data),
+                Ok(SystemInstruction::AdvanceNonceAccount)
+            )
+    })
+}
+
+// What send_or_queue did with a transaction, the signature either way
+pub enum Delivery {
+    Sent(String),
+    Queued(Signature),
+}
+
+struct QueuedTransaction {
+    expires_at: u64,
+    transaction: Transaction,
+}
+
+struct QueueState {
+    nvs: EspNvs<NvsDefault>,
+    // Sequence numbers of the oldest item and of the next one, items live at tx<seq>
+    head: u32,
+    tail: u32,
+}
+
+// Signed transactions waiting in NVS for the network, sent oldest first once it is back. Clones
+// share the queue, so the flusher thread and whoever queues can each hold one.
+#[derive(Clone)]
+pub struct OfflineQueue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl OfflineQueue {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, OFFLINE_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        let head = nvs.get_u32(HEAD_KEY).map_err(|e| format!("Offline queue read: {:?}", e))?.unwrap_or(0);
+        let tail = nvs.get_u32(TAIL_KEY).map_err(|e| format!("Offline queue read: {:?}", e))?.unwrap_or(head);
+        if tail != head {
+            info!("{} offline transactions waiting to be sent", tail.wrapping_sub(head));
+        }
+        Ok(Self {
+            state: Arc::new(Mutex::new(QueueState { nvs, head, tail })),
+        })
+    }
+
+    pub fn len(&self) -> u32 {
+        let state = self.state.lock().unwrap();
+        state.tail.wrapping_sub(state.head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Stores a signed transaction to be sent once the network is back, dropped if still unsent
+    // after `ttl`. The expiry only applies once the clock is trusted, items queued before that
+    // never expire.
+    pub fn push(&self, transaction: &Transaction, ttl: Duration) -> Result<(), String> {
+        if !uses_durable_nonce(transaction) {
+            return Err("Only durable-nonce transactions can wait offline".to_string());
+        }
+        let bytes = bincode::serialize(transaction).map_err(|e| format!("Transaction serialization failed: {:?}", e))?;
+        if HEADER_LEN + bytes.len() > MAX_ITEM_LEN {
+            return Err("Transaction too large".to_string());
+        }
+
+        let queued_at = timesync::trusted_unix_time().unwrap_or(0);
+        let expires_at = match queued_at {
+            0 => 0,
+            now => now + ttl.as_secs(),
+        };
+        let mut item = Vec::with_capacity(HEADER_LEN + bytes.len());
+        item.extend_from_slice(&queued_at.to_le_bytes());
+        item.extend_from_slice(&expires_at.to_le_bytes());
+        item.extend_from_slice(&bytes);
+
+        let mut state = self.state.lock().unwrap();
+        if state.tail.wrapping_sub(state.head) >= CAPACITY {
+            return Err(format!("Offline queue full ({} transactions)", CAPACITY));
+        }
+        let tail = state.tail;
+        state
+            .nvs
+            .set_blob(&item_key(tail), &item)
+            .map_err(|e| format!("Offline queue write: {:?}", e))?;
+        state
+            .nvs
+            .set_u32(TAIL_KEY, tail.wrapping_add(1))
+            .map_err(|e| format!("Offline queue write: {:?}", e))?;
+        state.tail = tail.wrapping_add(1);
+
+        info!("Queued {} offline", transaction.signatures[0]);
+        Ok(())
+    }
+
+    // Sends with `send` unless older transactions wait, and queues when the send fails with the
+    // link down. Relay and LoRa nodes fail with a gateway error instead, their sends never wait.
+    pub fn send_or_queue(
+        &self,
+        transaction: &Transaction,
+        ttl: Duration,
+        send: impl FnOnce(&Transaction) -> Result<String, RpcError>,
+    ) -> Result<Delivery, RpcError> {
+        // Anything older waits in the queue and goes first
+        if self.is_empty() {
+            match send(transaction) {
+                Ok(signature) => return Ok(Delivery::Sent(signature)),
+                Err(RpcError::Net(e)) if !net::link_up() => warn!("Send failed with the link down, queueing: {}", e),
+                Err(e) => return Err(e),
+            }
+        }
+        self.push(transaction, ttl).map_err(RpcError::Client)?;
+        Ok(Delivery::Queued(transaction.signatures[0]))
+    }
+
+    // Flushes the queue in its own thread every time the link comes back
+    pub fn spawn_flusher(&self) -> Result<(), String> {
+        let queue = self.clone();
+        std::thread::Builder::new()
+            .name("offline-flush".to_string())
+            .stack_size(FLUSHER_STACK_SIZE)
+            .spawn(move || loop {
+                if queue.is_empty() || !net::wait_for_link(IDLE_POLL) {
+                    std::thread::sleep(IDLE_POLL);
+                    continue;
+                }
+                queue.flush();
+            })
+            .map(|_| ())
+            .map_err(|e| format!("Offline flusher: {:?}", e))
+    }
+
+    fn flush(&self) {
+        let mut attempts = 0;
+        let mut retry_delay = RETRY_DELAY;
+        loop {
+            let queued = match self.front() {
+                Ok(Some(queued)) => queued,
+                Ok(None) => return,
+                Err(e) => {
+                    // Unreadable items would block the queue for good
+                    warn!("Offline transaction dropped: {}", e);
+                    self.pop();
+                    continue;
+                }
+            };
+            let signature = queued.transaction.signatures[0];
+
+            let now = timesync::trusted_unix_time();
+            if queued.expires_at != 0 && now.is_some_and(|now| now >= queued.expires_at) {
+                warn!("Offline transaction {} expired unsent", signature);
+                self.pop();
+                continue;
+            }
+
+            match send_transaction(&queued.transaction) {
+                Ok(signature) => {
+                    info!("Offline transaction sent: {}", signature);
+                    self.pop();
+                    attempts = 0;
+                    retry_delay = RETRY_DELAY;
+                }
+                Err(e) if !net::link_up() => {
+                    warn!("Link lost while flushing, {} offline transactions wait: {}", self.len(), e);
+                    return;
+                }
+                Err(e) if e.is_transient() => {
+                    warn!("Offline transaction {} failed, retrying in {}s: {}", signature, retry_delay.as_secs(), e);
+                    std::thread::sleep(retry_delay);
+                    retry_delay = retry_delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+                }
+                Err(e) => {
+                    attempts += 1;
+                    if attempts < FLUSH_ATTEMPTS {
+                        warn!("Offline transaction {} failed, retrying: {}", signature, e);
+                        std::thread::sleep(RETRY_DELAY);
+                    } else {
+                        warn!("Offline transaction {} dropped after {} attempts: {}", signature, FLUSH_ATTEMPTS, e);
+                        self.pop();
+                        attempts = 0;
+                        retry_delay = RETRY_DELAY;
+                    }
+                }
+            }
+        }
+    }
+
+    fn front(&self) -> Result<Option<QueuedTransaction>, String> {
+        let state = self.state.lock().unwrap();
+        if state.head == state.tail {
+            return Ok(None);
+        }
+
+        let mut buf = [0u8; MAX_ITEM_LEN];
+        let item = state
+            .nvs
+            .get_blob(&item_key(state.head), &mut buf)
+            .map_err(|e| format!("Offline queue read: {:?}", e))?
+            .ok_or("Offline queue item missing")?;
+        if item.len() < HEADER_LEN {
+            return Err("Offline queue item truncated".to_string());
+        }
+        let expires_at = u64::from_le_bytes(item[8..16].try_into().unwrap());
+        let transaction = bincode::deserialize(&item[HEADER_LEN..]).map_err(|e| format!("Transaction decode: {:?}", e))?;
+        Ok(Some(QueuedTransaction {
+            expires_at,
+            transaction,
+        }))
+    }
+
+    fn pop(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.head == state.tail {
+            return;
+        }
+        let head = state.head;
+        let _ = state.nvs.remove(&item_key(head));
+        if let Err(e) = state.nvs.set_u32(HEAD_KEY, head.wrapping_add(1)) {
+            warn!("Offline queue write: {:?}", e);
+        }
+        state.head = head.wrapping_add(1);
+    }
+}
+
+fn item_key(seq: u32) -> String {
+    format!("{}{}", ITEM_PREFIX, seq)
+}
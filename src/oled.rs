@@ -0,0 +1,103 @@
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver, I2C0};
+use esp_idf_svc::hal::prelude::*;
+use log::info;
+
+use crate::display::{Backend, Frame, WIDTH};
+
+// SSD1306 and SH1106 128x64 OLED modules on I2C, the status display's default panel.
+
+// 0x3D on modules with the address jumper moved
+const I2C_ADDRESS: u8 = 0x3C;
+const I2C_TIMEOUT_MS: u64 = 100;
+// The contrast register sets the segment current, dimmed draws a fraction of the power
+const CONTRAST: u8 = 0xCF;
+const DIMMED_CONTRAST: u8 = 0x08;
+
+// The two controllers sold on these modules take the same commands, apart from the charge pump
+// and the SH1106's 132 column RAM with the panel in its middle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Ssd1306,
+    Sh1106,
+}
+
+impl Controller {
+    fn column_offset(self) -> u8 {
+        match self {
+            Controller::Ssd1306 => 0,
+            Controller::Sh1106 => 2,
+        }
+    }
+
+    fn charge_pump(self) -> &'static [u8] {
+        match self {
+            Controller::Ssd1306 => &[0x8D, 0x14],
+            Controller::Sh1106 => &[0xAD, 0x8B],
+        }
+    }
+}
+
+pub struct Oled {
+    i2c: I2cDriver<'static>,
+    controller: Controller,
+}
+
+impl Oled {
+    pub fn new(i2c: I2C0, sda: AnyIOPin, scl: AnyIOPin, controller: Controller) -> Result<Self, String> {
+        let config = I2cConfig::new().baudrate(400.kHz().into());
+        let i2c = I2cDriver::new(i2c, sda, scl, &config).map_err(|e| format!("I2C init: {:?}", e))?;
+        let mut oled = Self { i2c, controller };
+        oled.init()?;
+        info!("OLED up ({:?})", controller);
+        Ok(oled)
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        self.command(&[0xAE])?; // display off
+        self.command(&[0xD5, 0x80])?; // clock divider
+        self.command(&[0xA8, 0x3F])?; // 64 rows
+        self.command(&[0xD3, 0x00])?; // no vertical offset
+        self.command(&[0x40])?; // start line 0
+        self.command(self.controller.charge_pump())?;
+        self.command(&[0x20, 0x02])?; // page addressing, the only mode the SH1106 has
+        self.command(&[0xA1, 0xC8])?; // flipped so the pins are at the top
+        self.command(&[0xDA, 0x12])?; // alternative COM pin layout of the 64 row panels
+        self.command(&[0x81, CONTRAST])?;
+        self.command(&[0xD9, 0xF1])?; // precharge
+        self.command(&[0xDB, 0x40])?; // VCOMH level
+        self.command(&[0xA4, 0xA6])?; // show RAM, not inverted
+        self.command(&[0xAF]) // display on
+    }
+
+    fn command(&mut self, command: &[u8]) -> Result<(), String> {
+        let mut bytes = vec![0x00];
+        bytes.extend_from_slice(command);
+        self.write(&bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.i2c
+            .write(I2C_ADDRESS, bytes, TickType::new_millis(I2C_TIMEOUT_MS).into())
+            .map_err(|e| format!("I2C write: {:?}", e))
+    }
+}
+
+impl Backend for Oled {
+    fn flush(&mut self, frame: &Frame) -> Result<(), String> {
+        let offset = self.controller.column_offset();
+        for (page, columns) in frame.pages().iter().enumerate() {
+            self.command(&[0xB0 + page as u8, offset & 0x0F, 0x10 | (offset >> 4)])?;
+            let mut data = [0u8; WIDTH + 1];
+            data[0] = 0x40;
+            data[1..].copy_from_slice(columns);
+            self.write(&data)?;
+        }
+        Ok(())
+    }
+
+    fn set_dimmed(&mut self, dimmed: bool) -> Result<(), String> {
+        self.command(&[0x81, if dimmed { DIMMED_CONTRAST } else { CONTRAST }])
+    }
+}
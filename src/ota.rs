@@ -0,0 +1,61 @@
+use std::error::Error;
+use std::time::Duration;
+
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::{
+    client::{Configuration, EspHttpConnection},
+    Method,
+};
+use esp_idf_svc::ota::EspOta;
+use log::info;
+
+use crate::taskwdt;
+use crate::transport;
+
+// Over-the-air update from an HTTPS URL into the next OTA slot. Needs a partition table with
+// two OTA slots (partitions-ota.csv), with the factory-only partitions.csv it fails before
+// anything is written. The bootloader checks the image, and with secure boot its signature,
+// so a compromised server can't get an unsigned image booted.
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// For the whole image, a few MB over a slow link
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Downloads and flashes the image, which boots at the next restart. On failure the running slot
+// stays the boot slot.
+pub fn update(url: &str) -> Result<(), String> {
+    info!("Firmware update from {}", url);
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(CONNECT_TIMEOUT),
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })
+    .map_err(|e| format!("HTTP init: {:?}", e))?;
+    let mut ota = EspOta::new().map_err(|e| format!("OTA init: {:?}", e))?;
+
+    let _supervised = taskwdt::supervise();
+    let mut client = Client::wrap(connection);
+    let request = client
+        .request(Method::Get, url, &[])
+        .map_err(|e| format!("Request: {:?}", e))?;
+    let mut response = request.submit().map_err(|e| format!("Submit: {:?}", e))?;
+    let status = response.status();
+    if !(200..=299).contains(&status) {
+        return Err(format!("HTTP Error: Status code {}", status));
+    }
+
+    // Dropping an unfinished update aborts it
+    let mut update = ota.initiate_update().map_err(|e| format!("OTA begin: {:?}", e))?;
+    let mut written = 0usize;
+    transport::read_body::<Box<dyn Error>>(&mut response, DOWNLOAD_TIMEOUT, &mut |data| {
+        taskwdt::feed();
+        written += data.len();
+        update.write(data).map_err(|e| format!("OTA write: {:?}", e).into())
+    })
+    .map_err(|e| e.to_string())?;
+    update.complete().map_err(|e| format!("OTA image rejected: {:?}", e))?;
+
+    info!("Firmware update written ({} bytes)", written);
+    Ok(())
+}
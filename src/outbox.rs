@@ -0,0 +1,172 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use solana_program::instruction::Instruction;
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Transaction;
+
+use crate::net::{self, LinkQuality};
+use crate::signer::TxSigner;
+use crate::solrpc::{get_latest_blockhash, send_transaction};
+
+// Due transactions wait at most this long for the WiFi signal to recover before going out anyway
+const MAX_WEAK_SIGNAL_DEFERRAL: Duration = Duration::from_secs(300);
+
+// A transaction waiting out its cancel window. Only the instructions are kept, it is signed
+// with a fresh blockhash on release since a blockhash expires long before the window ends.
+#[derive(Debug, Clone)]
+pub struct PendingTransaction {
+    pub id: u32,
+    pub description: String,
+    pub instructions: Vec<Instruction>,
+    pub payer: Pubkey,
+    pub release_at: Instant,
+    // Held back past release_at by a weak signal
+    pub deferred: bool,
+}
+
+impl PendingTransaction {
+    pub fn remaining(&self) -> Duration {
+        self.release_at.saturating_duration_since(Instant::now())
+    }
+}
+
+#[derive(Default)]
+struct OutboxState {
+    next_id: u32,
+    pending: Vec<PendingTransaction>,
+}
+
+// Timelocked queue for non-urgent outgoing transactions. Clones share the queue, so any
+// input (console, button, MQTT or BLE handler) holding one can cancel during the window.
+#[derive(Clone)]
+pub struct Outbox {
+    state: Arc<Mutex<OutboxState>>,
+    delay: Duration,
+}
+
+impl Outbox {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(OutboxState::default())),
+            delay,
+        }
+    }
+
+    pub fn queue(&self, description: &str, instructions: Vec<Instruction>, payer: Pubkey) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        state.next_id = state.next_id.wrapping_add(1);
+        let id = state.next_id;
+
+        state.pending.push(PendingTransaction {
+            id,
+            description: description.to_string(),
+            instructions,
+            payer,
+            release_at: Instant::now() + self.delay,
+            deferred: false,
+        });
+
+        info!(
+            "Queued #{} ({}), sending in {}s unless cancelled with `cancel {}`",
+            id,
+            description,
+            self.delay.as_secs(),
+            id
+        );
+        id
+    }
+
+    pub fn cancel(&self, id: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.pending.len();
+        state.pending.retain(|pending| pending.id != id);
+
+        let cancelled = state.pending.len() != before;
+        if cancelled {
+            info!("Cancelled #{}", id);
+        }
+        cancelled
+    }
+
+    pub fn cancel_all(&self) -> usize {
+        let cancelled = std::mem::take(&mut self.state.lock().unwrap().pending).len();
+        if cancelled > 0 {
+            info!("Cancelled {} pending transactions", cancelled);
+        }
+        cancelled
+    }
+
+    pub fn pending(&self) -> Vec<PendingTransaction> {
+        self.state.lock().unwrap().pending.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().pending.is_empty()
+    }
+
+    // Removes and returns the transactions whose cancel window has passed
+    pub fn take_due(&self) -> Vec<PendingTransaction> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let (due, waiting) = std::mem::take(&mut state.pending)
+            .into_iter()
+            .partition(|pending| pending.release_at <= now);
+        state.pending = waiting;
+        due
+    }
+
+    // Console interface to the queue:
+    //   pending         lists the queued transactions
+    //   cancel <id>     cancels one transaction
+    //   cancel all      cancels everything queued
+    pub fn handle_command(&self, line: &str) -> Result<String, String> {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["pending"] => Ok(self
+                .pending()
+                .iter()
+                .map(|pending| format!("#{} {} ({}s)", pending.id, pending.description, pending.remaining().as_secs()))
+                .collect::<Vec<_>>()
+                .join(", ")),
+            ["cancel", "all"] => Ok(format!("{} cancelled", self.cancel_all())),
+            ["cancel", id] => {
+                let id = id.parse::<u32>().map_err(|e| format!("Invalid id: {:?}", e))?;
+                if self.cancel(id) {
+                    Ok(format!("#{} cancelled", id))
+                } else {
+                    Err(format!("No pending transaction #{}", id))
+                }
+            }
+            _ => Err(format!("Unknown command '{}'", line)),
+        }
+    }
+
+    // Signs and sends every transaction whose window has passed, unless the signal is too weak
+    // for the send to be likely to get through
+    pub fn release_due(&self, signer: &impl TxSigner) {
+        let weak = net::link_quality() == LinkQuality::Weak;
+        for mut pending in self.take_due() {
+            if weak && pending.release_at.elapsed() < MAX_WEAK_SIGNAL_DEFERRAL {
+                if !pending.deferred {
+                    info!("Holding #{} ({}) until the WiFi signal improves", pending.id, pending.description);
+                    pending.deferred = true;
+                }
+                self.state.lock().unwrap().pending.push(pending);
+                continue;
+            }
+            match sign_and_send(signer, &pending) {
+                Ok(signature) => info!("Released #{} ({}): {}", pending.id, pending.description, signature),
+                Err(e) => warn!("Failed to release #{} ({}): {}", pending.id, pending.description, e),
+            }
+        }
+    }
+}
+
+fn sign_and_send(signer: &impl TxSigner, pending: &PendingTransaction) -> Result<String, String> {
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    let mut transaction = Transaction::new_with_payer(&pending.instructions, Some(&pending.payer));
+    signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+
+    send_transaction(&transaction).map_err(|e| e.to_string())
+}
@@ -0,0 +1,199 @@
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::hal::task::notification::Notification;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+
+// The "dash button": an active-low button that sends a preset payment per gesture. Presets live
+// in NVS as "<recipient> <lamports>" strings under the gesture's key, e.g. written with
+// nvs_partition_gen.py during fleet provisioning.
+
+const BUTTON_NAMESPACE: &str = "paybutton";
+const DEBOUNCE: Duration = Duration::from_millis(30);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+// A second press within this long of the first release makes a double press
+const MULTI_PRESS_GAP: Duration = Duration::from_millis(400);
+const HOLD: Duration = Duration::from_millis(1500);
+// A stuck button isn't a gesture, it is only watched again once released
+const MAX_HOLD: Duration = Duration::from_secs(10);
+const LISTENER_STACK_SIZE: usize = 4 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Single,
+    Double,
+    Hold,
+}
+
+impl Gesture {
+    const ALL: [Gesture; 3] = [Gesture::Single, Gesture::Double, Gesture::Hold];
+
+    fn key(self) -> &'static str {
+        match self {
+            Gesture::Single => "single",
+            Gesture::Double => "double",
+            Gesture::Hold => "hold",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preset {
+    pub recipient: Pubkey,
+    pub lamports: u64,
+}
+
+impl Preset {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (recipient, lamports) = value.trim().split_once(' ').ok_or("Expected \"<recipient> <lamports>\"")?;
+        let recipient = Pubkey::from_str(recipient).map_err(|e| format!("Recipient parse: {:?}", e))?;
+        let lamports = lamports.trim().parse().map_err(|e| format!("Amount parse: {:?}", e))?;
+        if lamports == 0 {
+            return Err("Amount must not be zero".to_string());
+        }
+        Ok(Self { recipient, lamports })
+    }
+}
+
+#[derive(Default)]
+pub struct PaymentPresets(Vec<(Gesture, Preset)>);
+
+impl PaymentPresets {
+    pub fn load(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, BUTTON_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        let mut presets = Vec::new();
+        let mut buf = [0u8; 80];
+        for gesture in Gesture::ALL {
+            let value = nvs
+                .get_str(gesture.key(), &mut buf)
+                .map_err(|e| format!("Preset read: {:?}", e))?;
+            if let Some(value) = value {
+                // A broken preset only disables its own gesture
+                match Preset::parse(value) {
+                    Ok(preset) => presets.push((gesture, preset)),
+                    Err(e) => warn!("Ignoring the {} press preset: {}", gesture.key(), e),
+                }
+            }
+        }
+        Ok(Self(presets))
+    }
+
+    // Replaces or, with None, removes the preset of a gesture
+    pub fn store(nvs: EspDefaultNvsPartition, gesture: Gesture, preset: Option<&Preset>) -> Result<(), String> {
+        let mut nvs = EspNvs::new(nvs, BUTTON_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        match preset {
+            Some(preset) => nvs.set_str(gesture.key(), &format!("{} {}", preset.recipient, preset.lamports)),
+            None => nvs.remove(gesture.key()).map(|_| ()),
+        }
+        .map_err(|e| format!("Preset store: {:?}", e))
+    }
+
+    pub fn get(&self, gesture: Gesture) -> Option<&Preset> {
+        self.0.iter().find(|(known, _)| *known == gesture).map(|(_, preset)| preset)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+static GESTURES: Mutex<Option<Receiver<Gesture>>> = Mutex::new(None);
+
+// Watches the button on a thread of its own, woken by its interrupt. Gestures queue up for
+// next_gesture.
+pub fn listen(pin: AnyIOPin) -> Result<(), String> {
+    let mut button = PinDriver::input(pin).map_err(|e| format!("Pay button init: {:?}", e))?;
+    button
+        .set_pull(Pull::Up)
+        .map_err(|e| format!("Pay button pull-up: {:?}", e))?;
+    button
+        .set_interrupt_type(InterruptType::NegEdge)
+        .map_err(|e| format!("Pay button interrupt type: {:?}", e))?;
+
+    let (gestures, received) = channel();
+    std::thread::Builder::new()
+        .name("pay-button".to_string())
+        .stack_size(LISTENER_STACK_SIZE)
+        .spawn(move || {
+            // Wakes this thread, so it has to be created on it
+            let notification = Notification::new();
+            let notifier = notification.notifier();
+            let subscribed = unsafe {
+                button.subscribe(move || {
+                    notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+                })
+            };
+            if let Err(e) = subscribed {
+                warn!("Pay button interrupt: {:?}", e);
+                return;
+            }
+            loop {
+                // The driver disables the interrupt each time it fires
+                if let Err(e) = button.enable_interrupt() {
+                    warn!("Pay button interrupt enable: {:?}", e);
+                    return;
+                }
+                notification.wait(BLOCK);
+                if let Some(gesture) = classify(&button) {
+                    let _ = gestures.send(gesture);
+                }
+            }
+        })
+        .map_err(|e| format!("Pay button listener: {:?}", e))?;
+
+    *GESTURES.lock().unwrap() = Some(received);
+    info!("Pay button armed");
+    Ok(())
+}
+
+// The next gesture, None when there was none within the timeout
+pub fn next_gesture(timeout: Duration) -> Option<Gesture> {
+    let gestures = GESTURES.lock().unwrap();
+    match gestures.as_ref()?.recv_timeout(timeout) {
+        Ok(gesture) => Some(gesture),
+        Err(RecvTimeoutError::Timeout) => None,
+        Err(RecvTimeoutError::Disconnected) => {
+            drop(gestures);
+            std::thread::sleep(timeout);
+            None
+        }
+    }
+}
+
+// Follows a press from its first edge to the end of the gesture, None for a bounce
+fn classify(button: &PinDriver<'static, AnyIOPin, Input>) -> Option<Gesture> {
+    wait_for_level(button, true, Instant::now() + DEBOUNCE * 2)?;
+    let pressed_at = Instant::now();
+    if wait_for_level(button, false, pressed_at + HOLD).is_none() {
+        let _ = wait_for_level(button, false, pressed_at + MAX_HOLD);
+        return Some(Gesture::Hold);
+    }
+    if wait_for_level(button, true, Instant::now() + MULTI_PRESS_GAP).is_none() {
+        return Some(Gesture::Single);
+    }
+    let _ = wait_for_level(button, false, Instant::now() + MAX_HOLD);
+    Some(Gesture::Double)
+}
+
+// Waits until the button has been stable at the level for DEBOUNCE
+fn wait_for_level(button: &PinDriver<'static, AnyIOPin, Input>, pressed: bool, deadline: Instant) -> Option<()> {
+    let mut stable_since: Option<Instant> = None;
+    while Instant::now() < deadline {
+        if button.is_low() == pressed {
+            if stable_since.get_or_insert_with(Instant::now).elapsed() >= DEBOUNCE {
+                return Some(());
+            }
+        } else {
+            stable_since = None;
+        }
+        std::thread::sleep(SAMPLE_INTERVAL);
+    }
+    None
+}
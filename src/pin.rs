@@ -0,0 +1,275 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspEncryptedNvsPartition, EspNvs, NvsDefault, NvsEncrypted};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::error::WalletError;
+use crate::serial::LineReader;
+
+const PIN_NAMESPACE: &str = "pin_gate";
+const SALT_KEY: &str = "salt";
+const HASH_KEY: &str = "hash";
+const FAILURES_KEY: &str = "fails";
+
+const SALT_LEN: usize = 16;
+const HASH_ITERATIONS: u32 = 10_000;
+const MIN_PIN_LEN: usize = 4;
+
+// After FREE_ATTEMPTS failures every further failure doubles the lockout, after
+// HARD_LOCK_ATTEMPTS the gate stays locked until both NVS partitions are erased (see the
+// README's Signing PIN section). The failure count is persisted, so rebooting doesn't reset
+// the lockout.
+const FREE_ATTEMPTS: u8 = 5;
+const HARD_LOCK_ATTEMPTS: u8 = 15;
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(3600);
+
+// Signing relocks automatically after this long without an explicit lock()
+const UNLOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+extern "C" {
+    fn esp_fill_random(buf: *mut core::ffi::c_void, len: usize);
+}
+
+// Where the salt, hash and failure count live, the encrypted partition next to the keys
+// whenever it is available
+enum Backend {
+    Encrypted(EspNvs<NvsEncrypted>),
+    Plaintext(EspNvs<NvsDefault>),
+}
+
+pub struct PinGate {
+    backend: Backend,
+    failures: u8,
+    locked_out_until: Option<Instant>,
+    unlocked_at: Option<Instant>,
+}
+
+impl PinGate {
+    // Opens the PIN state in the encrypted partition, moving a PIN set by older firmware out
+    // of the plaintext one. Without NVS encryption it stays in the plaintext partition.
+    pub fn open(nvs: EspDefaultNvsPartition, encrypted: Option<EspEncryptedNvsPartition>) -> Result<Self, WalletError> {
+        let mut plaintext =
+            EspNvs::new(nvs, PIN_NAMESPACE, true).map_err(|e| WalletError::Storage(format!("NVS open: {:?}", e)))?;
+
+        let backend = match encrypted {
+            Some(partition) => {
+                let mut encrypted = EspNvs::new(partition, PIN_NAMESPACE, true)
+                    .map_err(|e| WalletError::Storage(format!("Encrypted NVS open: {:?}", e)))?;
+                migrate_plaintext(&mut plaintext, &mut encrypted)?;
+                Backend::Encrypted(encrypted)
+            }
+            None => {
+                warn!("PIN hash stored without NVS encryption");
+                Backend::Plaintext(plaintext)
+            }
+        };
+
+        let failures = match &backend {
+            Backend::Encrypted(nvs) => nvs.get_u8(FAILURES_KEY),
+            Backend::Plaintext(nvs) => nvs.get_u8(FAILURES_KEY),
+        }
+        .map_err(|e| WalletError::Storage(format!("PIN state read: {:?}", e)))?
+        .unwrap_or(0);
+
+        let mut gate = Self {
+            backend,
+            failures,
+            locked_out_until: None,
+            unlocked_at: None,
+        };
+        gate.apply_lockout();
+
+        Ok(gate)
+    }
+
+    // A read error is returned rather than taken as "no PIN", which would unlock signing
+    pub fn is_configured(&self) -> Result<bool, WalletError> {
+        match &self.backend {
+            Backend::Encrypted(nvs) => nvs.contains(HASH_KEY),
+            Backend::Plaintext(nvs) => nvs.contains(HASH_KEY),
+        }
+        .map_err(|e| WalletError::Storage(format!("PIN state read: {:?}", e)))
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked_at
+            .map(|at| at.elapsed() < UNLOCK_TIMEOUT)
+            .unwrap_or(false)
+    }
+
+    pub fn lock(&mut self) {
+        self.unlocked_at = None;
+    }
+
+    // Sets or changes the PIN, changing an existing PIN requires the current one
+    pub fn set_pin(&mut self, new_pin: &str, current_pin: Option<&str>) -> Result<(), WalletError> {
+        if self.is_configured()? {
+            self.verify(current_pin.ok_or_else(|| WalletError::Invalid("Current PIN required".to_string()))?)?;
+        }
+        if new_pin.len() < MIN_PIN_LEN {
+            return Err(WalletError::Invalid(format!("PIN must be at least {} characters", MIN_PIN_LEN)));
+        }
+
+        let mut salt = [0u8; SALT_LEN];
+        unsafe { esp_fill_random(salt.as_mut_ptr().cast(), SALT_LEN) };
+        let hash = hash_pin(&salt, new_pin);
+
+        match &mut self.backend {
+            Backend::Encrypted(nvs) => nvs
+                .set_blob(SALT_KEY, &salt)
+                .and_then(|_| nvs.set_blob(HASH_KEY, hash.as_slice())),
+            Backend::Plaintext(nvs) => nvs
+                .set_blob(SALT_KEY, &salt)
+                .and_then(|_| nvs.set_blob(HASH_KEY, hash.as_slice())),
+        }
+        .map_err(|e| WalletError::Storage(format!("PIN store: {:?}", e)))?;
+        self.record_failures(0)?;
+
+        info!("Signing PIN set");
+        Ok(())
+    }
+
+    // Verifies a PIN from any input source (serial, keypad callback, BLE write)
+    // and unlocks signing on success
+    pub fn verify(&mut self, pin: &str) -> Result<(), WalletError> {
+        if self.failures >= HARD_LOCK_ATTEMPTS {
+            return Err(WalletError::PinLockedOut(None));
+        }
+        if let Some(until) = self.locked_out_until {
+            if let Some(remaining) = until.checked_duration_since(Instant::now()) {
+                return Err(WalletError::PinLockedOut(Some(remaining)));
+            }
+        }
+
+        let mut salt = [0u8; SALT_LEN];
+        let mut stored = [0u8; 32];
+        let (salt, stored) = match &self.backend {
+            Backend::Encrypted(nvs) => read_pin(nvs, &mut salt, &mut stored)?,
+            Backend::Plaintext(nvs) => read_pin(nvs, &mut salt, &mut stored)?,
+        };
+
+        if constant_time_eq(hash_pin(salt, pin).as_slice(), stored) {
+            self.record_failures(0)?;
+            self.locked_out_until = None;
+            self.unlocked_at = Some(Instant::now());
+            info!("Signing unlocked");
+            return Ok(());
+        }
+
+        self.record_failures(self.failures.saturating_add(1))?;
+        self.apply_lockout();
+        warn!("Wrong PIN, {} failed attempts", self.failures);
+
+        Err(WalletError::WrongPin)
+    }
+
+    // Prompts for the PIN on the serial console until it is accepted or the timeout passes
+    pub fn unlock_from_serial(&mut self, timeout: Duration) -> Result<(), WalletError> {
+        let deadline = Instant::now() + timeout;
+        let mut reader = LineReader::new();
+
+        info!("Enter signing PIN on the console");
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let Some(pin) = reader.read_line(remaining) else {
+                break;
+            };
+            match self.verify(&pin) {
+                Ok(()) => return Ok(()),
+                Err(e) => println!("ERR {}", e),
+            }
+        }
+
+        Err(WalletError::PinRequired)
+    }
+
+    fn record_failures(&mut self, failures: u8) -> Result<(), WalletError> {
+        self.failures = failures;
+        match &mut self.backend {
+            Backend::Encrypted(nvs) => nvs.set_u8(FAILURES_KEY, failures),
+            Backend::Plaintext(nvs) => nvs.set_u8(FAILURES_KEY, failures),
+        }
+        .map_err(|e| WalletError::Storage(format!("PIN state write: {:?}", e)))
+    }
+
+    fn apply_lockout(&mut self) {
+        if self.failures < FREE_ATTEMPTS {
+            return;
+        }
+
+        let doublings = (self.failures - FREE_ATTEMPTS).min(16) as u32;
+        let lockout = BASE_LOCKOUT.saturating_mul(1 << doublings).min(MAX_LOCKOUT);
+        self.locked_out_until = Some(Instant::now() + lockout);
+    }
+}
+
+fn read_pin<'a, T: esp_idf_svc::nvs::NvsPartitionId>(
+    nvs: &EspNvs<T>,
+    salt: &'a mut [u8; SALT_LEN],
+    stored: &'a mut [u8; 32],
+) -> Result<(&'a [u8], &'a [u8]), WalletError> {
+    let salt = nvs
+        .get_blob(SALT_KEY, salt)
+        .map_err(|e| WalletError::Storage(format!("PIN read: {:?}", e)))?
+        .ok_or_else(|| WalletError::Refused("No PIN configured".to_string()))?;
+    let stored = nvs
+        .get_blob(HASH_KEY, stored)
+        .map_err(|e| WalletError::Storage(format!("PIN read: {:?}", e)))?
+        .ok_or_else(|| WalletError::Refused("No PIN configured".to_string()))?;
+    Ok((salt, stored))
+}
+
+// Upgrade path for firmware that kept the PIN in the plaintext partition: copy the salt, hash
+// and failure count, then erase the plaintext entries. A failed copy keeps the device from
+// signing instead of dropping the PIN.
+fn migrate_plaintext(
+    plaintext: &mut EspNvs<NvsDefault>,
+    encrypted: &mut EspNvs<NvsEncrypted>,
+) -> Result<(), WalletError> {
+    if !plaintext
+        .contains(HASH_KEY)
+        .map_err(|e| WalletError::Storage(format!("PIN state read: {:?}", e)))?
+    {
+        return Ok(());
+    }
+    let mut salt = [0u8; SALT_LEN];
+    let mut hash = [0u8; 32];
+    let (salt, hash) = read_pin(plaintext, &mut salt, &mut hash)?;
+    let failures = plaintext
+        .get_u8(FAILURES_KEY)
+        .map_err(|e| WalletError::Storage(format!("PIN state read: {:?}", e)))?
+        .unwrap_or(0);
+
+    encrypted
+        .set_blob(SALT_KEY, salt)
+        .and_then(|_| encrypted.set_blob(HASH_KEY, hash))
+        .and_then(|_| encrypted.set_u8(FAILURES_KEY, failures))
+        .map_err(|e| WalletError::Storage(format!("PIN migration: {:?}", e)))?;
+    for key in [SALT_KEY, HASH_KEY, FAILURES_KEY] {
+        plaintext
+            .remove(key)
+            .map_err(|e| WalletError::Storage(format!("Plaintext PIN erase: {:?}", e)))?;
+    }
+
+    info!("Signing PIN moved to encrypted NVS");
+    Ok(())
+}
+
+// Intermediate and final hashes are wiped on drop, the stored hash is the only long-lived copy
+fn hash_pin(salt: &[u8], pin: &str) -> Zeroizing<[u8; 32]> {
+    let mut hash = Zeroizing::new([0u8; 32]);
+    let mut hasher = Sha256::new().chain_update(salt).chain_update(pin);
+    for _ in 0..HASH_ITERATIONS {
+        hasher.finalize_into_reset((&mut *hash).into());
+        hasher.update(salt);
+        hasher.update(*hash);
+    }
+    hasher.finalize_into((&mut *hash).into());
+    hash
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver, I2C0};
+use esp_idf_svc::hal::prelude::*;
+use log::info;
+
+use crate::nfc::TagReader;
+
+// NXP PN532 over I2C, in the host controller frame format of its user manual (UM0701-02). Set
+// the module's mode switches to I2C.
+
+const ADDRESS: u8 = 0x24;
+const HOST_TO_PN532: u8 = 0xD4;
+const PN532_TO_HOST: u8 = 0xD5;
+const ACK: [u8; 6] = [0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+const GET_FIRMWARE_VERSION: u8 = 0x02;
+const SAM_CONFIGURATION: u8 = 0x14;
+const RF_CONFIGURATION: u8 = 0x32;
+const IN_DATA_EXCHANGE: u8 = 0x40;
+const IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+const IN_RELEASE: u8 = 0x52;
+const NTAG_READ: u8 = 0x30;
+const NTAG_READ_CNT: u8 = 0x39;
+const NTAG_NFC_COUNTER: u8 = 0x02;
+// The longest response read is 16 bytes of pages behind the frame header
+const MAX_FRAME_LEN: usize = 48;
+const I2C_TIMEOUT_MS: u64 = 50;
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+const READY_POLL: Duration = Duration::from_millis(5);
+
+pub struct Pn532 {
+    i2c: I2cDriver<'static>,
+    // A tag is selected and has to be released before looking for the next
+    selected: bool,
+}
+
+impl Pn532 {
+    pub fn new(i2c: I2C0, sda: AnyIOPin, scl: AnyIOPin) -> Result<Self, String> {
+        let config = I2cConfig::new().baudrate(100.kHz().into());
+        let i2c = I2cDriver::new(i2c, sda, scl, &config).map_err(|e| format!("I2C init: {:?}", e))?;
+        let mut pn532 = Self { i2c, selected: false };
+
+        // The first frame wakes it from power-down and may go unanswered
+        let _ = pn532.command(GET_FIRMWARE_VERSION, &[]);
+        let version = pn532.command(GET_FIRMWARE_VERSION, &[])?;
+        if version.first() != Some(&0x32) || version.len() < 3 {
+            return Err(format!("Not a PN532: {:02x?}", version));
+        }
+        info!("PN532 firmware {}.{}", version[1], version[2]);
+        // Normal mode, the IRQ line left alone
+        pn532.command(SAM_CONFIGURATION, &[0x01, 0x14, 0x01])?;
+        // Two activation attempts per poll, instead of waiting for a tag forever
+        pn532.command(RF_CONFIGURATION, &[0x05, 0xFF, 0x01, 0x02])?;
+        Ok(pn532)
+    }
+
+    // Sends a command and returns the data of its response
+    fn command(&mut self, code: u8, params: &[u8]) -> Result<Vec<u8>, String> {
+        let len = params.len() as u8 + 2;
+        let mut frame = vec![0x00, 0x00, 0xFF, len, len.wrapping_neg(), HOST_TO_PN532, code];
+        frame.extend_from_slice(params);
+        let sum = params.iter().fold(HOST_TO_PN532.wrapping_add(code), |sum, byte| sum.wrapping_add(*byte));
+        frame.extend_from_slice(&[sum.wrapping_neg(), 0x00]);
+        self.i2c
+            .write(ADDRESS, &frame, TickType::new_millis(I2C_TIMEOUT_MS).into())
+            .map_err(|e| format!("PN532 write: {:?}", e))?;
+
+        if self.read_ready(ACK.len())?[..] != ACK {
+            return Err("PN532 did not acknowledge".to_string());
+        }
+        let response = self.read_ready(MAX_FRAME_LEN)?;
+
+        // Preamble, start code, length and its checksum, then the data and its checksum
+        let start = response
+            .windows(2)
+            .position(|window| window == [0x00, 0xFF])
+            .ok_or("PN532 response without a start code")?
+            + 2;
+        let (len, len_checksum) = match response.get(start..start + 2) {
+            Some(&[len, len_checksum]) => (len as usize, len_checksum),
+            _ => return Err("PN532 response truncated".to_string()),
+        };
+        if (len as u8).wrapping_add(len_checksum) != 0 {
+            return Err("PN532 response length checksum mismatch".to_string());
+        }
+        let body = response
+            .get(start + 2..start + 3 + len)
+            .ok_or("PN532 response truncated")?;
+        if body.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err("PN532 response checksum mismatch".to_string());
+        }
+        match body {
+            [PN532_TO_HOST, answer, data @ .., _] if *answer == code + 1 => Ok(data.to_vec()),
+            _ => Err(format!("PN532 error frame: {:02x?}", body)),
+        }
+    }
+
+    // Waits for the ready bit of the status byte that leads every read
+    fn read_ready(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut buf = vec![0u8; len + 1];
+        loop {
+            self.i2c
+                .read(ADDRESS, &mut buf, TickType::new_millis(I2C_TIMEOUT_MS).into())
+                .map_err(|e| format!("PN532 read: {:?}", e))?;
+            if buf[0] & 0x01 == 0x01 {
+                return Ok(buf.split_off(1));
+            }
+            if Instant::now() >= deadline {
+                return Err("PN532 not ready".to_string());
+            }
+            std::thread::sleep(READY_POLL);
+        }
+    }
+}
+
+impl TagReader for Pn532 {
+    fn select(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.selected {
+            self.selected = false;
+            self.command(IN_RELEASE, &[0x00])?;
+        }
+        // One ISO14443A tag at 106 kbps
+        let response = self.command(IN_LIST_PASSIVE_TARGET, &[0x01, 0x00])?;
+        if response.first() != Some(&1) {
+            return Ok(None);
+        }
+        // Target number, SENS_RES (2), SEL_RES, UID length, UID
+        let len = *response.get(5).ok_or("PN532 target data truncated")? as usize;
+        let uid = response.get(6..6 + len).ok_or("PN532 target data truncated")?;
+        self.selected = true;
+        Ok(Some(uid.to_vec()))
+    }
+
+    fn read(&mut self, page: u8) -> Result<[u8; 16], String> {
+        let response = self.command(IN_DATA_EXCHANGE, &[0x01, NTAG_READ, page])?;
+        match response.split_first() {
+            Some((0, data)) => data.try_into().map_err(|_| "Short read from the tag".to_string()),
+            Some((status, _)) => Err(format!("Tag read failed: status {:#04x}", status)),
+            None => Err("Empty response to a tag read".to_string()),
+        }
+    }
+
+    fn read_counter(&mut self) -> Result<Option<u32>, String> {
+        let response = self.command(IN_DATA_EXCHANGE, &[0x01, NTAG_READ_CNT, NTAG_NFC_COUNTER])?;
+        // Tags without the counter enabled refuse the command
+        match response[..] {
+            [0, low, mid, high] => Ok(Some(u32::from_le_bytes([low, mid, high, 0]))),
+            _ => Ok(None),
+        }
+    }
+}
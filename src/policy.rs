@@ -0,0 +1,182 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use resp32sol_core::policy;
+pub use resp32sol_core::policy::{PolicyDenied, SpendingPolicy};
+#[cfg(feature = "sd-log")]
+use serde_json::json;
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Message;
+
+use crate::error::WalletError;
+#[cfg(feature = "sd-log")]
+use crate::inspect::outgoing_lamports;
+use crate::inspect::view;
+use crate::pin::PinGate;
+#[cfg(feature = "sd-log")]
+use crate::sdlog;
+use crate::signer::SigningHook;
+use crate::spend::{trusted_time, SpendLedger};
+use crate::token::associated_token_address;
+
+// The policy and its evaluation live in resp32sol-core, where they are tested on the host. This
+// stores the policy and enforces it with the persisted ledger and the device's clock.
+
+const POLICY_NAMESPACE: &str = "policy";
+const SPENDING_KEY: &str = "spending";
+const MAX_POLICY_LEN: usize = 2048;
+
+// Persists the spending policy, changes require the signing PIN
+pub struct PolicyStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl PolicyStore {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, POLICY_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        Ok(Self { nvs })
+    }
+
+    pub fn load(&self) -> Result<SpendingPolicy, String> {
+        let mut buf = vec![0u8; MAX_POLICY_LEN];
+        match self
+            .nvs
+            .get_str(SPENDING_KEY, &mut buf)
+            .map_err(|e| format!("Policy read: {:?}", e))?
+        {
+            Some(json) => SpendingPolicy::from_json(json),
+            None => Ok(SpendingPolicy::default()),
+        }
+    }
+
+    // The only way to change the policy: the PIN must be set and entered, so a compromised
+    // application task can't loosen its own limits
+    pub fn update(&mut self, policy: &SpendingPolicy, pin_gate: &mut PinGate, pin: &str) -> Result<(), String> {
+        if !pin_gate.is_configured().map_err(|e| e.to_string())? {
+            return Err("Set a signing PIN before changing the spending policy".to_string());
+        }
+        pin_gate.verify(pin).map_err(|e| e.to_string())?;
+
+        let json = policy.to_json();
+        if json.len() >= MAX_POLICY_LEN {
+            return Err(format!("Policy exceeds {} bytes", MAX_POLICY_LEN));
+        }
+        self.nvs
+            .set_str(SPENDING_KEY, &json)
+            .map_err(|e| format!("Policy store: {:?}", e))?;
+
+        info!("Spending policy updated: {}", json);
+        Ok(())
+    }
+}
+
+// Signing hook enforcing a SpendingPolicy. Spending in the hourly and daily windows is
+// kept in a persisted ledger, so rebooting doesn't reset the budget.
+pub struct PolicyEngine {
+    policy: SpendingPolicy,
+    spending: Mutex<Spending>,
+}
+
+// The ledger and the spends taken from it for messages not signed yet, under one lock so two
+// signatures can't both fit in what is left
+struct Spending {
+    ledger: SpendLedger,
+    reserved: Vec<Reservation>,
+}
+
+struct Reservation {
+    message: Message,
+    lamports: u64,
+    bucket: u64,
+}
+
+impl PolicyEngine {
+    pub fn new(policy: SpendingPolicy, ledger: SpendLedger) -> Self {
+        Self {
+            policy,
+            spending: Mutex::new(Spending {
+                ledger,
+                reserved: Vec::new(),
+            }),
+        }
+    }
+
+    // Checks the message against the policy. What it spends is reserved in the ledger in the
+    // same step, `signed` keeps the reservation and `aborted` gives it back.
+    pub fn evaluate(&self, message: &Message, signer: &Pubkey) -> Result<(), PolicyDenied> {
+        let now = trusted_time();
+        let mut spending = self.spending.lock().unwrap();
+        let lamports = policy::evaluate(
+            &self.policy,
+            &view(message),
+            signer,
+            spending.ledger.buckets(),
+            now,
+            associated_token_address,
+        )?;
+        // Without window limits the spend is still counted, once the clock allows it
+        let Some(now) = now.filter(|_| lamports > 0) else {
+            return Ok(());
+        };
+
+        match spending.ledger.record(lamports, now) {
+            Ok(bucket) => spending.reserved.push(Reservation {
+                message: message.clone(),
+                lamports,
+                bucket,
+            }),
+            Err(e) => warn!("Spending not persisted: {}", e),
+        }
+        Ok(())
+    }
+
+    fn take_reservation(spending: &mut Spending, message: &Message) -> Option<Reservation> {
+        let position = spending.reserved.iter().position(|reservation| reservation.message == *message)?;
+        Some(spending.reserved.remove(position))
+    }
+}
+
+impl SigningHook for PolicyEngine {
+    fn check(&self, message: &Message, signer: &Pubkey) -> Result<(), WalletError> {
+        let decision = self.evaluate(message, signer);
+        #[cfg(feature = "sd-log")]
+        sdlog::record(
+            "policy",
+            json!({
+                "allowed": decision.is_ok(),
+                "lamports": outgoing_lamports(message, signer),
+                "reason": decision.as_ref().err().map(|denied| denied.to_string()),
+            }),
+        );
+        decision.map_err(|denied| {
+            warn!("Policy denied signature: {}", denied);
+            WalletError::PolicyDenied(denied)
+        })
+    }
+
+    // The spend was recorded when it was reserved
+    fn signed(&self, message: &Message, _signer: &Pubkey) {
+        Self::take_reservation(&mut self.spending.lock().unwrap(), message);
+    }
+
+    fn aborted(&self, message: &Message, _signer: &Pubkey) {
+        let mut spending = self.spending.lock().unwrap();
+        if let Some(reservation) = Self::take_reservation(&mut spending, message) {
+            if let Err(e) = spending.ledger.release(reservation.lamports, reservation.bucket) {
+                warn!("Released spending not persisted: {}", e);
+            }
+        }
+    }
+}
+
+// Refuses every signature, installed when the policy can't be loaded so the device fails closed
+pub struct DenyAll(pub String);
+
+impl SigningHook for DenyAll {
+    fn check(&self, _message: &Message, _signer: &Pubkey) -> Result<(), WalletError> {
+        #[cfg(feature = "sd-log")]
+        sdlog::record("policy", json!({ "allowed": false, "reason": format!("Policy unavailable: {}", self.0) }));
+        Err(WalletError::Refused(format!("Policy unavailable: {}", self.0)))
+    }
+}
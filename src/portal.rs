@@ -0,0 +1,344 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::task::block_on;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{EspIOError, Read, Write};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{AccessPointConfiguration, AuthMethod, AsyncWifi, Configuration, EspWifi};
+#[cfg(debug_assertions)]
+use log::debug;
+use log::{error, info, warn};
+use solana_program::pubkey::Pubkey;
+use zeroize::Zeroizing;
+
+use crate::b58::Base58;
+use crate::cluster::Cluster;
+use crate::config::{device_name, handle_wifi_command, DeviceSettings, WifiCerts, WifiCredentials};
+#[cfg(feature = "oled-display")]
+use crate::display;
+use crate::form::form_value;
+#[cfg(feature = "oled-display")]
+use crate::qr::QrMatrix;
+use crate::serial::LineReader;
+
+// Larger submissions are refused rather than truncated
+const MAX_FORM_LEN: usize = 1024;
+// Lets the "saved" page reach the phone before the access point goes away
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+// After a failed start, e.g. the WiFi driver refusing the AP configuration
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+const DNS_STACK_SIZE: usize = 4 * 1024;
+const DNS_POLL: Duration = Duration::from_secs(1);
+// How long a provisioned device that can't join its networks keeps the portal open before it
+// tries them again
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(300);
+// Random bytes of the recovery portal's WPA2 passphrase, about 12 base58 characters
+const PASSPHRASE_BYTES: usize = 9;
+
+// Set by the form handler once everything is stored
+static SAVED: AtomicBool = AtomicBool::new(false);
+// Keeps the DNS thread answering, cleared when the recovery portal closes
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn esp_fill_random(buf: *mut core::ffi::c_void, len: usize);
+}
+
+// For a device that was never set up: opens an unencrypted "REsp32Sol-XXXX" access point with
+// a setup page that every DNS name resolves to, so phones show it as a captive portal. Reboots
+// once WiFi details have been saved, there or on the console.
+pub fn run(wifi: &mut AsyncWifi<EspWifi<'static>>, nvs: EspDefaultNvsPartition, certs: &mut WifiCerts) -> ! {
+    match start(wifi, nvs.clone(), None) {
+        Ok(_server) => wait_for_setup(nvs, certs, None),
+        Err(e) => error!("Setup portal failed: {}", e),
+    }
+    std::thread::sleep(RETRY_DELAY);
+    unsafe { esp_idf_svc::sys::esp_restart() }
+}
+
+// For a provisioned device that can't join any of its networks, where anyone nearby could
+// otherwise replace its recipient, networks and cluster: the same portal behind a WPA2
+// passphrase that is new every time and only shown on the display, closed again after
+// RECOVERY_TIMEOUT. Reboots once WiFi details have been saved, returns with the driver
+// stopped for the networks to be tried again otherwise.
+pub fn run_recovery(wifi: &mut AsyncWifi<EspWifi<'static>>, nvs: EspDefaultNvsPartition, certs: &mut WifiCerts) {
+    let mut random = Zeroizing::new([0u8; PASSPHRASE_BYTES]);
+    unsafe { esp_fill_random(random.as_mut_ptr().cast(), PASSPHRASE_BYTES) };
+    let passphrase = Zeroizing::new(Base58(random.as_slice()).to_string());
+
+    match start(wifi, nvs.clone(), Some(&passphrase)) {
+        Ok(server) => {
+            show_passphrase(&passphrase);
+            wait_for_setup(nvs, certs, Some(Instant::now() + RECOVERY_TIMEOUT));
+            drop(server);
+            #[cfg(feature = "oled-display")]
+            display::clear_overlay();
+            info!("Setup portal closed, trying the stored networks again");
+        }
+        Err(e) => error!("Setup portal failed: {}", e),
+    }
+    OPEN.store(false, Ordering::SeqCst);
+    let _ = block_on(wifi.stop());
+}
+
+// As a code phones join the access point with, so only someone in front of the device learns
+// it. Headless devices are set up over the console instead. Debug builds also log it at debug
+// level, release builds never do, log lines can end up on the SD card.
+#[allow(unused_variables)]
+fn show_passphrase(passphrase: &str) {
+    #[cfg(feature = "oled-display")]
+    match device_name()
+        .and_then(|name| QrMatrix::encode(&Zeroizing::new(format!("WIFI:T:WPA;S:{};P:{};;", name, passphrase))))
+        .and_then(|qr| display::show_request(&qr, &["Join", "setup"]))
+    {
+        Ok(()) => info!("Setup portal passphrase on the display"),
+        Err(e) => warn!("Setup portal passphrase not shown: {}", e),
+    }
+    #[cfg(debug_assertions)]
+    debug!("Setup portal passphrase: {}", passphrase);
+}
+
+fn start(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    nvs: EspDefaultNvsPartition,
+    passphrase: Option<&str>,
+) -> Result<EspHttpServer<'static>, String> {
+    let name = device_name()?;
+
+    // Stopping a driver that never started fails harmlessly
+    let _ = block_on(wifi.stop());
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: name.as_str().try_into().map_err(|_| "Access point name too long")?,
+        auth_method: match passphrase {
+            Some(_) => AuthMethod::WPA2Personal,
+            None => AuthMethod::None,
+        },
+        password: passphrase
+            .unwrap_or_default()
+            .try_into()
+            .map_err(|_| "Access point passphrase too long")?,
+        channel: 1,
+        max_connections: 4,
+        ..Default::default()
+    }))
+    .map_err(|e| format!("Access point config: {:?}", e))?;
+    block_on(wifi.start()).map_err(|e| format!("Access point start: {:?}", e))?;
+    block_on(wifi.wait_netif_up()).map_err(|e| format!("Access point netif: {:?}", e))?;
+
+    let ip = wifi
+        .wifi()
+        .ap_netif()
+        .get_ip_info()
+        .map_err(|e| format!("Access point address: {:?}", e))?
+        .ip;
+
+    OPEN.store(true, Ordering::SeqCst);
+    std::thread::Builder::new()
+        .name("portal-dns".to_string())
+        .stack_size(DNS_STACK_SIZE)
+        .spawn(move || run_dns(ip))
+        .map_err(|e| format!("Portal DNS: {:?}", e))?;
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })
+    .map_err(|e| format!("Portal HTTP server: {:?}", e))?;
+
+    server
+        .fn_handler("/", Method::Get, |req| -> Result<(), EspIOError> {
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
+                .write_all(setup_page().as_bytes())
+        })
+        .map_err(|e| format!("Portal page: {:?}", e))?;
+
+    server
+        .fn_handler("/", Method::Post, move |mut req| -> Result<(), EspIOError> {
+            let mut body = Zeroizing::new(vec![0u8; MAX_FORM_LEN]);
+            let mut len = 0;
+            while len < body.len() {
+                match req.read(&mut body[len..])? {
+                    0 => break,
+                    read => len += read,
+                }
+            }
+
+            let result = match len < body.len() {
+                true => save(&String::from_utf8_lossy(&body[..len]), nvs.clone()),
+                false => Err("Form too large".to_string()),
+            };
+            let (status, html) = match result {
+                Ok(ssid) => {
+                    info!("Setup saved for {}, restarting", ssid);
+                    SAVED.store(true, Ordering::SeqCst);
+                    (200, page(&format!("Saved. The device restarts and joins {}.", html_escape(&ssid))))
+                }
+                Err(e) => {
+                    warn!("Setup form rejected: {}", e);
+                    (400, page(&format!("{} <a href=\"/\">Back</a>", html_escape(&e))))
+                }
+            };
+            req.into_response(status, None, &[("Content-Type", "text/html")])?
+                .write_all(html.as_bytes())
+        })
+        .map_err(|e| format!("Portal form: {:?}", e))?;
+
+    // Connectivity checks (generate_204, hotspot-detect.html, ...) land here, the redirect
+    // makes the phone open the setup page
+    let location = format!("http://{}/", ip);
+    server
+        .fn_handler("/*", Method::Get, move |req| -> Result<(), EspIOError> {
+            req.into_response(302, Some("Found"), &[("Location", location.as_str())])?;
+            Ok(())
+        })
+        .map_err(|e| format!("Portal redirect: {:?}", e))?;
+
+    info!("Setup portal open, join the {} network and browse to {}", name, ip);
+    Ok(server)
+}
+
+// Returns when `until` passes without anything saved, reboots once something is
+fn wait_for_setup(nvs: EspDefaultNvsPartition, certs: &mut WifiCerts, until: Option<Instant>) {
+    info!("Or send `wifi {{\"ssid\":\"..\",\"password\":\"..\"}}` on the console");
+    let mut reader = LineReader::new();
+    loop {
+        if SAVED.load(Ordering::SeqCst) {
+            break;
+        }
+        if until.is_some_and(|until| Instant::now() >= until) {
+            return;
+        }
+        let Some(line) = reader.read_line(Duration::from_secs(1)) else {
+            continue;
+        };
+        match handle_wifi_command(&line, nvs.clone(), certs) {
+            Ok(stored) => {
+                println!("OK {}", stored);
+                break;
+            }
+            Err(e) => println!("ERR {}", e),
+        }
+    }
+
+    std::thread::sleep(RESTART_DELAY);
+    unsafe { esp_idf_svc::sys::esp_restart() }
+}
+
+// Validates the whole form before storing anything, returns the SSID to join
+fn save(form: &str, nvs: EspDefaultNvsPartition) -> Result<String, String> {
+    let ssid = form_value(form, "ssid").unwrap_or_default();
+    let password = Zeroizing::new(form_value(form, "password").unwrap_or_default());
+    let credentials = WifiCredentials::new(&ssid, &password)?;
+
+    let recipient = match form_value(form, "recipient").as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(recipient) => Some(Pubkey::from_str(recipient).map_err(|_| "Recipient is not a valid Solana address")?),
+    };
+    let cluster = match form_value(form, "cluster").as_deref() {
+        None | Some("") => None,
+        Some(cluster) => Some(
+            Cluster::PUBLIC
+                .into_iter()
+                .find(|public| public.name() == cluster)
+                .ok_or("Unknown cluster")?,
+        ),
+    };
+
+    // The settings the form doesn't have stay as they are
+    let settings = DeviceSettings::load(nvs.clone()).unwrap_or_default();
+    DeviceSettings { recipient, cluster, ..settings }.store(nvs.clone())?;
+    credentials.store(nvs)?;
+    Ok(credentials.ssid)
+}
+
+fn setup_page() -> String {
+    let clusters: String = Cluster::PUBLIC
+        .iter()
+        .map(|cluster| format!("<option>{}</option>", cluster.name()))
+        .collect();
+    page(&format!(
+        "<form method=\"post\">\
+         <label>WiFi network<input name=\"ssid\" maxlength=\"32\" required></label>\
+         <label>Password<input name=\"password\" type=\"password\" maxlength=\"64\"></label>\
+         <label>Recipient address<input name=\"recipient\" maxlength=\"44\"></label>\
+         <label>Cluster<select name=\"cluster\">{}</select></label>\
+         <button>Save and restart</button></form>",
+        clusters
+    ))
+}
+
+fn page(body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+         <title>REsp32Sol setup</title><style>body{{font-family:sans-serif;max-width:24em;margin:auto}}\
+         label,input,select,button{{display:block;width:100%;margin:.4em 0}}</style></head>\
+         <body><h1>REsp32Sol setup</h1>{}</body></html>",
+        body
+    )
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Answers every A query with the portal's own address while the portal is open
+fn run_dns(ip: Ipv4Addr) {
+    let socket = match UdpSocket::bind("0.0.0.0:53") {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Portal DNS unavailable, browse to {} manually: {:?}", ip, e);
+            return;
+        }
+    };
+    let _ = socket.set_read_timeout(Some(DNS_POLL));
+
+    let mut buf = [0u8; 512];
+    while OPEN.load(Ordering::SeqCst) {
+        let Ok((len, peer)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if let Some(reply) = dns_reply(&buf[..len], ip) {
+            let _ = socket.send_to(&reply, peer);
+        }
+    }
+}
+
+fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    // Standard query with exactly one question
+    if query.len() < 12 || query[2] & 0xf8 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+
+    let mut pos = 12;
+    loop {
+        let label = *query.get(pos)? as usize;
+        pos += 1;
+        match label {
+            0 => break,
+            // Compression pointers don't belong in a question
+            label if label & 0xc0 != 0 => return None,
+            label => pos += label,
+        }
+    }
+    let question_end = pos + 4;
+    let is_a = query.get(pos..question_end)? == [0, 1, 0, 1];
+
+    let mut reply = query[..question_end].to_vec();
+    // Response, recursion desired copied, recursion available, no error
+    reply[2] = 0x80 | (query[2] & 0x01);
+    reply[3] = 0x80;
+    reply[6..12].copy_from_slice(&[0, is_a as u8, 0, 0, 0, 0]);
+    if is_a {
+        // Name pointer to the question, type A, class IN, TTL 60 s, 4 bytes of address
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
@@ -0,0 +1,188 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{
+    esp_deep_sleep_start, esp_err_t, esp_sleep_enable_timer_wakeup, esp_sleep_get_wakeup_cause,
+    esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER, ESP_OK,
+};
+#[cfg(any(esp32, esp32s3))]
+use esp_idf_svc::sys::{esp_sleep_enable_ext0_wakeup, esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0};
+#[cfg(feature = "touch-pad")]
+use esp_idf_svc::sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TOUCHPAD;
+#[cfg(not(any(esp32, esp32s3)))]
+use esp_idf_svc::sys::{
+    esp_deep_sleep_enable_gpio_wakeup, esp_deepsleep_gpio_wake_up_mode_t_ESP_GPIO_WAKEUP_GPIO_HIGH,
+    esp_deepsleep_gpio_wake_up_mode_t_ESP_GPIO_WAKEUP_GPIO_LOW, esp_sleep_source_t_ESP_SLEEP_WAKEUP_GPIO,
+};
+use log::{info, warn};
+use solana_transaction::Signature;
+
+use crate::solrpc::{confirm_transaction, get_signature_status, ConfirmationStatus};
+use crate::spend::unix_time;
+#[cfg(feature = "touch-pad")]
+use crate::touch;
+
+// Battery devices do their work, then deep sleep until the next cycle. Deep sleep powers down
+// RAM, so a transaction is kept in NVS from the moment it is signed until it is confirmed, and
+// a wake finds out what became of it before signing another one.
+
+const POWER_NAMESPACE: &str = "power";
+const IN_FLIGHT_KEY: &str = "inflight";
+// How long a cycle waits for its transaction before leaving it for the next wake
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+// Unknown to the cluster by then, its blockhash expired (150 slots) and it never landed
+const IN_FLIGHT_EXPIRY: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy)]
+pub struct SleepConfig {
+    // Woken by the RTC timer after this long
+    pub interval: Duration,
+    // And by a button or sensor on this pin, EXT0 on the classic ESP32 and S3, one of the RTC
+    // GPIOs 0 to 5 on the ESP32-C3 or the LP GPIOs 0 to 7 on the ESP32-C6
+    pub wake_pin: Option<i32>,
+    pub wake_high: bool,
+}
+
+pub struct PowerManager {
+    nvs: EspNvs<NvsDefault>,
+    config: SleepConfig,
+}
+
+impl PowerManager {
+    pub fn open(nvs: EspDefaultNvsPartition, config: SleepConfig) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, POWER_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        info!("Woken by {}", wake_cause());
+        Ok(Self { nvs, config })
+    }
+
+    // Confirms the transaction left in flight before the last sleep, if any. While it may still
+    // land, sending another one could pay twice, so the device goes back to sleep instead.
+    pub fn resume(&mut self) {
+        let Some((signature, sent)) = self.in_flight() else {
+            return;
+        };
+        match confirm_transaction(&signature, ConfirmationStatus::Confirmed, CONFIRM_TIMEOUT) {
+            Ok(()) => {
+                info!("Transaction from before sleep confirmed: {}", signature);
+                self.clear();
+            }
+            Err(e) => {
+                let age = unix_time().zip(sent).map(|(now, sent)| now.saturating_sub(sent));
+                let unknown = matches!(get_signature_status(&signature), Ok(None));
+                // Without a clock its age is unknown, one wake is all it gets
+                if unknown && age.is_none_or(|age| age >= IN_FLIGHT_EXPIRY.as_secs()) {
+                    warn!("Transaction from before sleep never landed, dropped: {}", signature);
+                    self.clear();
+                } else {
+                    warn!("Transaction from before sleep still pending: {}", e);
+                    self.sleep();
+                }
+            }
+        }
+    }
+
+    // Ends the work cycle: waits for the transaction sent in it, leaving it in NVS should the
+    // wait run out, then sleeps until the next cycle
+    pub fn end_cycle(&mut self, sent: Option<&str>) -> ! {
+        let signature = sent.and_then(|sent| Signature::from_str(sent).ok());
+        if let Some(signature) = signature {
+            match confirm_transaction(&signature, ConfirmationStatus::Confirmed, CONFIRM_TIMEOUT) {
+                Ok(()) => {
+                    info!("Transaction confirmed: {}", signature);
+                    self.clear();
+                }
+                Err(e) => warn!("{}, confirming after the wake", e),
+            }
+        }
+        self.sleep()
+    }
+
+    pub fn sleep(&self) -> ! {
+        if let Err(e) = self.enable_wakeups() {
+            // Sleeping without a way to wake would brick the device until a power cycle
+            warn!("Wake-up sources unavailable ({}), restarting instead", e);
+            unsafe { esp_idf_svc::sys::esp_restart() }
+        }
+        info!("Deep sleep for {}s", self.config.interval.as_secs());
+        unsafe { esp_deep_sleep_start() }
+    }
+
+    fn enable_wakeups(&self) -> Result<(), String> {
+        check(
+            unsafe { esp_sleep_enable_timer_wakeup(self.config.interval.as_micros() as u64) },
+            "Timer wake-up",
+        )?;
+        // EXT0 refuses to wake alongside the touch pads
+        #[cfg(feature = "touch-pad")]
+        if touch::enable_wakeup()? {
+            if self.config.wake_pin.is_some() {
+                warn!("The touch pad wakes the device, the wake pin doesn't");
+            }
+            return Ok(());
+        }
+        let Some(pin) = self.config.wake_pin else {
+            return Ok(());
+        };
+        #[cfg(any(esp32, esp32s3))]
+        let ret = unsafe { esp_sleep_enable_ext0_wakeup(pin, self.config.wake_high as i32) };
+        #[cfg(not(any(esp32, esp32s3)))]
+        let ret = unsafe {
+            let mode = match self.config.wake_high {
+                true => esp_deepsleep_gpio_wake_up_mode_t_ESP_GPIO_WAKEUP_GPIO_HIGH,
+                false => esp_deepsleep_gpio_wake_up_mode_t_ESP_GPIO_WAKEUP_GPIO_LOW,
+            };
+            esp_deep_sleep_enable_gpio_wakeup(1 << pin, mode)
+        };
+        check(ret, "Pin wake-up")
+    }
+
+    // Keeps a signed transaction until it is confirmed, called before it is sent so a power loss
+    // or a send error after it landed can't make the next wake pay again
+    pub fn track(&mut self, signature: &Signature) -> Result<(), String> {
+        let sent = unix_time().map_or("-".to_string(), |now| now.to_string());
+        self.nvs
+            .set_str(IN_FLIGHT_KEY, &format!("{} {}", signature, sent))
+            .map_err(|e| format!("In-flight write: {:?}", e))
+    }
+
+    fn in_flight(&self) -> Option<(Signature, Option<u64>)> {
+        let mut buf = [0u8; 128];
+        let value = match self.nvs.get_str(IN_FLIGHT_KEY, &mut buf) {
+            Ok(value) => value?,
+            Err(e) => {
+                warn!("In-flight read: {:?}", e);
+                return None;
+            }
+        };
+        let (signature, sent) = value.split_once(' ')?;
+        Some((Signature::from_str(signature).ok()?, sent.parse().ok()))
+    }
+
+    fn clear(&mut self) {
+        if let Err(e) = self.nvs.remove(IN_FLIGHT_KEY) {
+            warn!("In-flight clear: {:?}", e);
+        }
+    }
+}
+
+fn wake_cause() -> &'static str {
+    #[cfg(any(esp32, esp32s3))]
+    let pin_wakeup = esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0;
+    #[cfg(not(any(esp32, esp32s3)))]
+    let pin_wakeup = esp_sleep_source_t_ESP_SLEEP_WAKEUP_GPIO;
+    match unsafe { esp_sleep_get_wakeup_cause() } {
+        cause if cause == esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => "the sleep timer",
+        cause if cause == pin_wakeup => "the wake pin",
+        #[cfg(feature = "touch-pad")]
+        cause if cause == esp_sleep_source_t_ESP_SLEEP_WAKEUP_TOUCHPAD => "a touch",
+        _ => "power-on or reset",
+    }
+}
+
+fn check(ret: esp_err_t, what: &str) -> Result<(), String> {
+    match ret {
+        ESP_OK => Ok(()),
+        ret => Err(format!("{}: {}", what, ret)),
+    }
+}
@@ -0,0 +1,274 @@
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Gpio0};
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartTxDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use log::{info, warn};
+use solana_transaction::{Signature, Transaction};
+
+use crate::amount::Sol;
+use crate::b58::Short;
+use crate::error::RpcError;
+use crate::inspect::outgoing_lamports;
+use crate::qr::QrMatrix;
+use crate::solrpc::{self, get_signature_status, ConfirmationStatus};
+use crate::spend::unix_time;
+
+// Receipts on an ESC/POS thermal printer (58 mm panel printers like the CSN-A2 or QR204, and most
+// receipt printers with a TTL serial input), one for every confirmed payment the device receives
+// through a payment request and every SOL transfer it sends. The printer only listens, nothing is
+// read back, so a printer out of paper loses its receipts.
+
+const ESC: u8 = 0x1B;
+const GS: u8 = 0x1D;
+// 32 characters of font A on 58 mm paper
+const LINE_WIDTH: usize = 32;
+// Characters kept from each end of a signature
+const SIGNATURE_ENDS: usize = 8;
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// Confirmation checks go over TLS from this thread
+const PRINTER_STACK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum QrStyle {
+    // GS ( k, printed by the printer's firmware from the URL, `module_size` dots per module
+    Native { module_size: u8 },
+    // A raster image for printers without a QR code command, `scale` dots per module
+    Raster { scale: usize },
+    Off,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PrinterConfig {
+    // Most panel printers ship at 9600 or 19200 baud, printing their setting on a self-test page
+    pub baud_rate: u32,
+    // First line of every receipt, e.g. the shop's name
+    pub header: &'static str,
+    pub qr: QrStyle,
+    // For printers with an auto-cutter
+    pub cut: bool,
+    // Sent transfers not confirmed by then get no receipt
+    pub confirm_timeout: Duration,
+}
+
+enum Receipt {
+    Received { amount: String, signature: String },
+    Sent { lamports: u64, signature: String },
+}
+
+static RECEIPTS: Mutex<Option<Sender<Receipt>>> = Mutex::new(None);
+
+// Printer RX on GPIO0, its TX is left unconnected
+pub fn start(uart: UART1, tx: Gpio0, config: PrinterConfig) -> Result<(), String> {
+    let uart = UartTxDriver::new(
+        uart,
+        tx,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::default().baudrate(Hertz(config.baud_rate)),
+    )
+    .map_err(|e| format!("Printer UART: {:?}", e))?;
+    let mut printer = Printer { uart, config };
+    printer.write(&[ESC, b'@'])?;
+
+    let (receipts, received) = channel();
+    std::thread::Builder::new()
+        .name("printer".to_string())
+        .stack_size(PRINTER_STACK_SIZE)
+        .spawn(move || run(printer, received))
+        .map_err(|e| format!("Printer thread: {:?}", e))?;
+    *RECEIPTS.lock().unwrap() = Some(receipts);
+    info!("Receipt printer up at {} baud", config.baud_rate);
+    Ok(())
+}
+
+// A payment request paid in full, `amount` with its unit, e.g. "0.5 SOL". Only receive-qr and
+// pay-to-unlock ask for payments.
+pub fn payment_received(amount: &str, signature: &str) {
+    send(Receipt::Received {
+        amount: amount.to_string(),
+        signature: signature.to_string(),
+    });
+}
+
+// Called for every transaction sent, those moving SOL out of the fee payer get a receipt once
+// confirmed. Token transfers carry no decimals in the message, so they get none.
+pub fn transaction_sent(transaction: &Transaction, signature: &str) {
+    let Some(payer) = transaction.message.account_keys.first() else {
+        return;
+    };
+    let lamports = outgoing_lamports(&transaction.message, payer);
+    if lamports > 0 {
+        send(Receipt::Sent {
+            lamports,
+            signature: signature.to_string(),
+        });
+    }
+}
+
+fn send(receipt: Receipt) {
+    if let Some(receipts) = RECEIPTS.lock().unwrap().as_ref() {
+        let _ = receipts.send(receipt);
+    }
+}
+
+fn run(mut printer: Printer, receipts: Receiver<Receipt>) {
+    for receipt in receipts {
+        let (title, amount, signature) = match receipt {
+            Receipt::Received { amount, signature } => ("RECEIVED", amount, signature),
+            Receipt::Sent { lamports, signature } => {
+                if let Err(e) = confirm(&signature, printer.config.confirm_timeout) {
+                    warn!("No receipt for {}: {}", signature, e);
+                    continue;
+                }
+                ("SENT", Sol(lamports).to_string(), signature)
+            }
+        };
+        match printer.print(title, &amount, &signature) {
+            Ok(()) => info!("Receipt printed for {}", signature),
+            Err(e) => warn!("Receipt for {} not printed: {}", signature, e),
+        }
+    }
+}
+
+// Waits for the cluster to confirm a sent transaction, without the side effects of
+// `solrpc::confirm_transaction` whose callers already get them
+fn confirm(signature: &str, timeout: Duration) -> Result<(), String> {
+    let signature = Signature::from_str(signature).map_err(|e| format!("Invalid signature: {:?}", e))?;
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        match get_signature_status(&signature) {
+            Ok(Some(status)) if status >= ConfirmationStatus::Confirmed => return Ok(()),
+            Ok(_) => {}
+            // Landed but failed, there was no payment
+            Err(e @ RpcError::TransactionFailed(_)) => return Err(e.to_string()),
+            Err(e) => warn!("Receipt confirmation check: {}", e),
+        }
+        std::thread::sleep(CONFIRM_POLL_INTERVAL);
+    }
+    Err("not confirmed in time".to_string())
+}
+
+struct Printer {
+    uart: UartTxDriver<'static>,
+    config: PrinterConfig,
+}
+
+impl Printer {
+    fn print(&mut self, title: &str, amount: &str, signature: &str) -> Result<(), String> {
+        let url = solrpc::cluster().explorer_tx_url(signature);
+        let time = unix_time().map_or("Time not synced".to_string(), utc_time);
+
+        // Centered, header and amount in double size
+        self.write(&[ESC, b'@', ESC, b'a', 1])?;
+        self.write(&[GS, b'!', 0x11])?;
+        self.line(self.config.header)?;
+        self.write(&[GS, b'!', 0x00])?;
+        self.line(&"-".repeat(LINE_WIDTH))?;
+        self.line(title)?;
+        self.write(&[ESC, b'E', 1, GS, b'!', 0x11])?;
+        self.line(amount)?;
+        self.write(&[ESC, b'E', 0, GS, b'!', 0x00])?;
+        self.line(&time)?;
+        self.line(&Short(signature, SIGNATURE_ENDS).to_string())?;
+        self.write(b"\n")?;
+        match self.config.qr {
+            QrStyle::Native { module_size } => self.native_qr(&url, module_size)?,
+            QrStyle::Raster { scale } => self.raster_qr(&url, scale)?,
+            QrStyle::Off => {}
+        }
+        if !matches!(self.config.qr, QrStyle::Off) {
+            self.line("Scan to view the transaction")?;
+        }
+        // Past the tear bar
+        self.write(&[ESC, b'd', 4])?;
+        if self.config.cut {
+            self.write(&[GS, b'V', 66, 0])?;
+        }
+        self.uart.wait_done(BLOCK).map_err(|e| format!("Printer UART flush: {:?}", e))
+    }
+
+    fn native_qr(&mut self, url: &str, module_size: u8) -> Result<(), String> {
+        // Model 2, the module size, error correction M, then the data and the print command
+        self.write(&[GS, b'(', b'k', 4, 0, 0x31, 0x41, 0x32, 0x00])?;
+        self.write(&[GS, b'(', b'k', 3, 0, 0x31, 0x43, module_size])?;
+        self.write(&[GS, b'(', b'k', 3, 0, 0x31, 0x45, 0x31])?;
+        let len = (url.len() + 3) as u16;
+        self.write(&[GS, b'(', b'k', len as u8, (len >> 8) as u8, 0x31, 0x50, 0x30])?;
+        self.write(url.as_bytes())?;
+        self.write(&[GS, b'(', b'k', 3, 0, 0x31, 0x51, 0x30])?;
+        self.write(b"\n")
+    }
+
+    // GS v 0, one bit per dot and rows padded to whole bytes
+    fn raster_qr(&mut self, url: &str, scale: usize) -> Result<(), String> {
+        let bitmap = QrMatrix::encode(url)?.to_bitmap(scale.max(1));
+        let height = bitmap.len();
+        let width_bytes = bitmap.first().map_or(0, |row| row.len().div_ceil(8));
+        self.write(&[
+            GS,
+            b'v',
+            b'0',
+            0,
+            width_bytes as u8,
+            (width_bytes >> 8) as u8,
+            height as u8,
+            (height >> 8) as u8,
+        ])?;
+        for row in &bitmap {
+            let mut bytes = vec![0u8; width_bytes];
+            for (x, dark) in row.iter().enumerate() {
+                if *dark {
+                    bytes[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            self.write(&bytes)?;
+        }
+        self.write(b"\n")
+    }
+
+    fn line(&mut self, text: &str) -> Result<(), String> {
+        // The printers' code pages disagree beyond ASCII
+        let text: String = text.chars().map(|c| if c.is_ascii() { c } else { '?' }).collect();
+        self.write(text.as_bytes())?;
+        self.write(b"\n")
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut written = 0;
+        while written < bytes.len() {
+            written += self
+                .uart
+                .write(&bytes[written..])
+                .map_err(|e| format!("Printer UART write: {:?}", e))?;
+        }
+        Ok(())
+    }
+}
+
+// "2025-06-01 14:05 UTC", the civil date from days since the epoch
+fn utc_time(unix: u64) -> String {
+    let days = (unix / 86_400) as i64;
+    let seconds = unix % 86_400;
+    let era_days = days + 719_468;
+    let era = era_days.div_euclid(146_097);
+    let day_of_era = era_days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds % 3_600 / 60
+    )
+}
@@ -0,0 +1,246 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, PinDriver, Pull};
+use log::{info, warn};
+use serde_json::json;
+use solana_keypair::Signer;
+
+#[cfg(feature = "fingerprint")]
+use crate::fingerprint::FingerprintApproval;
+use crate::keystore::Keystore;
+use crate::pin::PinGate;
+use crate::policy::{PolicyStore, SpendingPolicy};
+#[cfg(not(feature = "remote-signer"))]
+use crate::rotation::{rotate_key, RotationConfig};
+use crate::serial::LineReader;
+
+// How long the device listens for provisioning commands on the console after boot
+const PROVISIONING_WINDOW: Duration = Duration::from_secs(5);
+// How often the button is checked while waiting for a line, and how long a press has to last
+const BUTTON_POLL: Duration = Duration::from_millis(50);
+const DEBOUNCE: Duration = Duration::from_millis(30);
+
+// Listens on the console for key import and fleet provisioning commands, each accepted
+// command keeps the window open for another PROVISIONING_WINDOW. Apart from `info`, commands
+// are only taken from someone at the device: once a PIN is set, after `unlock <pin>`, before
+// that after a press on the approval button during the window.
+//   info                           prints the device pubkey, seal and keystore security state
+//   unlock <pin>                   opens the window for the other commands
+//   import <keyfile json>          installs the device key, unless the device has one
+//   import --replace <keyfile json>
+//                                  replaces the device key
+//   import <name> <keyfile json>   installs a named key, unless it exists
+//   import --replace <name> <keyfile json>
+//                                  replaces a named key
+//   generate                       generates the device key on the device, unless it has one
+//   pin <new> [current]            sets or changes the signing PIN
+//   policy                         prints the spending policy
+//   policy <pin> <policy json>     replaces the spending policy, requires the signing PIN
+//   enroll <pin>                   enrolls fingers on the fingerprint module, requires the signing PIN
+//   rpc <url>                      stores the RPC endpoint, including any API key, in the keystore
+//   rotate                         moves the device key's SOL to a fresh key and replaces it
+//                                  (rpc and rotate are not available in remote-signer mode,
+//                                  which never goes online)
+//   seal                           ends provisioning for good, the window no longer opens
+//   done                           ends the window early
+pub fn run_provisioning_window(
+    keystore: &mut Keystore,
+    mut pin_gate: Option<&mut PinGate>,
+    mut policy_store: Option<&mut PolicyStore>,
+    #[cfg(feature = "fingerprint")] fingerprint: Option<&FingerprintApproval>,
+    button: &mut AnyIOPin,
+) {
+    match keystore.is_sealed() {
+        Ok(false) => {}
+        Ok(true) => {
+            info!("Device is sealed, provisioning disabled");
+            return;
+        }
+        Err(e) => {
+            warn!("Seal state unknown ({}), provisioning disabled", e);
+            return;
+        }
+    }
+
+    // The PIN can't be checked without its state, and a button press would bypass it
+    let pin_set = match pin_gate.as_deref().map(PinGate::is_configured).transpose() {
+        Ok(pin_set) => pin_set.unwrap_or(false),
+        Err(e) => {
+            warn!("PIN state unknown ({}), provisioning disabled", e);
+            return;
+        }
+    };
+    let button = match pin_set {
+        true => None,
+        false => PinDriver::input(button)
+            .and_then(|mut button| button.set_pull(Pull::Up).map(|_| button))
+            .map_err(|e| warn!("Provisioning button unavailable: {:?}", e))
+            .ok(),
+    };
+
+    info!(
+        "Provisioning window open for {}s, {} first",
+        PROVISIONING_WINDOW.as_secs(),
+        if pin_set { "send `unlock <pin>`" } else { "press the button" }
+    );
+
+    let mut deadline = Instant::now() + PROVISIONING_WINDOW;
+    let mut reader = LineReader::new();
+    let mut present = false;
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if !present && button.as_ref().is_some_and(|button| pressed(button)) {
+            present = true;
+            deadline = Instant::now() + PROVISIONING_WINDOW;
+            println!("OK present");
+        }
+
+        let Some(line) = reader.read_line(remaining.min(BUTTON_POLL)) else {
+            continue;
+        };
+
+        if line.as_str() == "done" {
+            break;
+        }
+
+        let (command, args) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+        if command == "unlock" {
+            let unlocked = match pin_gate.as_deref_mut() {
+                Some(pin_gate) => pin_gate.verify(args.trim()).map_err(|e| e.to_string()),
+                None => Err("PIN gate unavailable".to_string()),
+            };
+            match unlocked {
+                Ok(()) => {
+                    present = true;
+                    deadline = Instant::now() + PROVISIONING_WINDOW;
+                    println!("OK unlocked");
+                }
+                Err(e) => println!("ERR {}", e),
+            }
+            continue;
+        }
+        if !present && command != "info" {
+            println!("ERR {}", if pin_set { "Send `unlock <pin>` first" } else { "Press the button first" });
+            continue;
+        }
+
+        match handle_command(
+            keystore,
+            pin_gate.as_deref_mut(),
+            policy_store.as_deref_mut(),
+            #[cfg(feature = "fingerprint")]
+            fingerprint,
+            &line,
+        ) {
+            Ok(response) => {
+                println!("OK {}", response);
+                if line.as_str() == "seal" {
+                    break;
+                }
+                deadline = Instant::now() + PROVISIONING_WINDOW;
+            }
+            Err(e) => println!("ERR {}", e),
+        }
+    }
+
+    info!("Provisioning window closed");
+}
+
+// Low on two samples DEBOUNCE apart, so a glitch doesn't count as someone at the device
+fn pressed(button: &PinDriver<'_, AnyIOPin, Input>) -> bool {
+    if !button.is_low() {
+        return false;
+    }
+    std::thread::sleep(DEBOUNCE);
+    button.is_low()
+}
+
+fn handle_command(
+    keystore: &mut Keystore,
+    pin_gate: Option<&mut PinGate>,
+    policy_store: Option<&mut PolicyStore>,
+    #[cfg(feature = "fingerprint")] fingerprint: Option<&FingerprintApproval>,
+    line: &str,
+) -> Result<String, String> {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+
+    match command {
+        "info" => {
+            let security = keystore.security();
+            Ok(json!({
+                "pubkey": keystore.load().map_err(|e| e.to_string())?.map(|keypair| keypair.pubkey().to_string()),
+                "sealed": keystore.is_sealed().map_err(|e| e.to_string())?,
+                "flash_encryption": security.flash_encryption,
+                "nvs_encryption": security.nvs_encryption,
+                "fw": env!("CARGO_PKG_VERSION"),
+            })
+            .to_string())
+        }
+        "import" => {
+            let args = args.trim();
+            // Overwriting a key strands whatever it holds, so it takes `--replace`
+            let (replace, args) = match args.strip_prefix("--replace ") {
+                Some(args) => (true, args.trim_start()),
+                None => (false, args),
+            };
+            let (name, json) = if args.starts_with('[') {
+                (None, args)
+            } else {
+                let (name, json) = args
+                    .split_once(' ')
+                    .ok_or("Usage: import [--replace] [name] <keyfile json>")?;
+                (Some(name), json)
+            };
+
+            keystore
+                .import_keyfile(name, json, replace)
+                .map(|pubkey| pubkey.to_string())
+                .map_err(|e| e.to_string())
+        }
+        "generate" => keystore
+            .load_or_generate()
+            .map(|keypair| keypair.pubkey().to_string())
+            .map_err(|e| e.to_string()),
+        "pin" => {
+            let pin_gate = pin_gate.ok_or("PIN gate unavailable")?;
+            let mut args = args.split_whitespace();
+            let new_pin = args.next().ok_or("Usage: pin <new> [current]")?;
+
+            pin_gate.set_pin(new_pin, args.next()).map_err(|e| e.to_string())?;
+            Ok("PIN set".to_string())
+        }
+        "policy" => {
+            let policy_store = policy_store.ok_or("Policy store unavailable")?;
+            let Some((pin, json)) = args.trim().split_once(' ') else {
+                return policy_store.load().map(|policy| policy.to_json());
+            };
+
+            let policy = SpendingPolicy::from_json(json)?;
+            policy_store.update(&policy, pin_gate.ok_or("PIN gate unavailable")?, pin)?;
+            Ok(policy.to_json())
+        }
+        #[cfg(feature = "fingerprint")]
+        "enroll" => {
+            let fingerprint = fingerprint.ok_or("Fingerprint module unavailable")?;
+            let enrolled = fingerprint.enroll_missing(pin_gate.ok_or("PIN gate unavailable")?, args.trim())?;
+            Ok(format!("{} fingers enrolled", enrolled))
+        }
+        #[cfg(not(feature = "remote-signer"))]
+        "rpc" => {
+            keystore.store_rpc_url(args.trim()).map_err(|e| e.to_string())?;
+            Ok("RPC endpoint stored".to_string())
+        }
+        #[cfg(not(feature = "remote-signer"))]
+        "rotate" => {
+            let old = keystore.load().map_err(|e| e.to_string())?.ok_or("No device key to rotate")?;
+            let (new, report) = rotate_key(keystore, &old, &RotationConfig::default())?;
+
+            Ok(format!("{} {}", new.pubkey(), report.signature))
+        }
+        "seal" => {
+            keystore.seal().map_err(|e| e.to_string())?;
+            Ok("sealed".to_string())
+        }
+        _ => Err(format!("Unknown command '{}'", command)),
+    }
+}
@@ -0,0 +1,103 @@
+use std::ptr::NonNull;
+
+use esp_idf_svc::sys::{
+    heap_caps_free, heap_caps_get_free_size, heap_caps_get_total_size, heap_caps_malloc, heap_caps_realloc,
+    MALLOC_CAP_8BIT, MALLOC_CAP_SPIRAM,
+};
+
+// External PSRAM on WROVER, ESP32-CAM and most S3 modules, detected at runtime so one build runs
+// with and without it. Large RPC responses are kept there instead of in the internal RAM WiFi
+// and TLS need. Boards without PSRAM, like the ESP32-C3, report none and everything stays
+// internal. Needs CONFIG_SPIRAM in sdkconfig.defaults.
+
+const CAPS: u32 = MALLOC_CAP_SPIRAM | MALLOC_CAP_8BIT;
+// First allocation of a buffer, it doubles from there
+const INITIAL_CAPACITY: usize = 16 * 1024;
+
+pub fn available() -> bool {
+    unsafe { heap_caps_get_total_size(MALLOC_CAP_SPIRAM) > 0 }
+}
+
+pub fn free() -> usize {
+    unsafe { heap_caps_get_free_size(MALLOC_CAP_SPIRAM) }
+}
+
+// Bytes in PSRAM, growing like a Vec
+pub struct PsramVec {
+    ptr: Option<NonNull<u8>>,
+    len: usize,
+    capacity: usize,
+}
+
+// The memory is owned like a Vec's, nothing else points into it
+unsafe impl Send for PsramVec {}
+
+impl PsramVec {
+    pub const fn new() -> Self {
+        Self {
+            ptr: None,
+            len: 0,
+            capacity: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self.ptr {
+            Some(ptr) => unsafe { std::slice::from_raw_parts(ptr.as_ptr(), self.len) },
+            None => &[],
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> Result<(), String> {
+        let needed = self.len + data.len();
+        if needed > self.capacity {
+            self.grow(needed)?;
+        }
+        if let Some(ptr) = self.ptr {
+            unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.as_ptr().add(self.len), data.len()) };
+        }
+        self.len = needed;
+        Ok(())
+    }
+
+    fn grow(&mut self, needed: usize) -> Result<(), String> {
+        let capacity = needed.max(self.capacity * 2).max(INITIAL_CAPACITY);
+        let grown = unsafe {
+            match self.ptr {
+                Some(ptr) => heap_caps_realloc(ptr.as_ptr().cast(), capacity, CAPS),
+                None => heap_caps_malloc(capacity, CAPS),
+            }
+        };
+        // A failed realloc leaves the old block as it was
+        let grown = NonNull::new(grown.cast()).ok_or_else(|| format!("PSRAM full, {} bytes wanted", capacity))?;
+        self.ptr = Some(grown);
+        self.capacity = capacity;
+        Ok(())
+    }
+}
+
+impl Default for PsramVec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PsramVec {
+    fn drop(&mut self) {
+        if let Some(ptr) = self.ptr {
+            unsafe { heap_caps_free(ptr.as_ptr().cast()) };
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use solana_program::pubkey;
+use solana_program::pubkey::Pubkey;
+
+use crate::solrpc::get_account_info;
+use crate::spend::unix_time;
+
+// Prices from Pyth price feed accounts, the PriceUpdateV2 accounts the Pyth receiver program
+// keeps updated on-chain. Read with `getAccountInfo` like any other account, no Pyth API needed.
+
+const RECEIVER_PROGRAM_ID: Pubkey = pubkey!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+// Anchor discriminator and write authority
+const VERIFICATION_LEVEL_OFFSET: usize = 8 + 32;
+const VERIFICATION_FULL: u8 = 1;
+// Feed id, price, confidence, exponent and publish time
+const MESSAGE_LEN: usize = 32 + 8 + 8 + 4 + 8;
+
+// The feed's price in its quote currency, e.g. USD for one SOL. Refused unless fully verified by
+// the Wormhole guardians and published within `max_age`.
+pub fn price(feed: &Pubkey, max_age: Duration) -> Result<f64, String> {
+    let account = get_account_info(feed).map_err(|e| e.to_string())?.ok_or("Price feed account not found")?;
+    if account.owner != RECEIVER_PROGRAM_ID {
+        return Err("Not a Pyth price feed account".to_string());
+    }
+    let data = &account.data;
+    // Partial verification carries the number of signatures, full verification nothing
+    if data.get(VERIFICATION_LEVEL_OFFSET) != Some(&VERIFICATION_FULL) {
+        return Err("Price update not fully verified".to_string());
+    }
+    let message = data
+        .get(VERIFICATION_LEVEL_OFFSET + 1..VERIFICATION_LEVEL_OFFSET + 1 + MESSAGE_LEN)
+        .ok_or("Price feed account too short")?;
+    let price = i64::from_le_bytes(message[32..40].try_into().unwrap());
+    let exponent = i32::from_le_bytes(message[48..52].try_into().unwrap());
+    let published = i64::from_le_bytes(message[52..60].try_into().unwrap());
+
+    let now = unix_time().ok_or("Price age needs the clock, which is not synced yet")?;
+    if published < 0 || now.saturating_sub(published as u64) > max_age.as_secs() {
+        return Err(format!("Price is stale, published at {}", published));
+    }
+    if price <= 0 {
+        return Err("Price feed holds no positive price".to_string());
+    }
+    Ok(price as f64 * 10f64.powi(exponent))
+}
@@ -0,0 +1,108 @@
+use qrcodegen::{QrCode, QrCodeEcc};
+use solana_program::pubkey::Pubkey;
+
+// SSD1306 128x64 framebuffer, GDDRAM layout: 8 pages of 128 columns, one byte per 8 vertical pixels
+pub const SSD1306_WIDTH: usize = 128;
+pub const SSD1306_HEIGHT: usize = 64;
+pub const SSD1306_BUFFER_LEN: usize = SSD1306_WIDTH * SSD1306_HEIGHT / 8;
+
+// The QR spec asks for 4 modules of quiet zone, small displays get by with fewer
+const QUIET_ZONE: usize = 2;
+
+// Display-agnostic QR code: a square matrix of dark (true) / light (false) modules
+pub struct QrMatrix {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrMatrix {
+    pub fn encode(text: &str) -> Result<Self, String> {
+        let qr = QrCode::encode_text(text, QrCodeEcc::Low).map_err(|e| format!("QR encode: {:?}", e))?;
+        let size = qr.size() as usize;
+
+        let mut modules = Vec::with_capacity(size * size);
+        for y in 0..size {
+            for x in 0..size {
+                modules.push(qr.get_module(x as i32, y as i32));
+            }
+        }
+
+        Ok(Self { size, modules })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    // Rows of dark/light pixels with a quiet zone, each module drawn as scale x scale pixels
+    pub fn to_bitmap(&self, scale: usize) -> Vec<Vec<bool>> {
+        let side = (self.size + 2 * QUIET_ZONE) * scale;
+        (0..side)
+            .map(|py| {
+                (0..side)
+                    .map(|px| {
+                        let x = (px / scale).wrapping_sub(QUIET_ZONE);
+                        let y = (py / scale).wrapping_sub(QUIET_ZONE);
+                        self.is_dark(x, y)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Two modules per character using half blocks, scannable straight off a serial terminal
+    pub fn to_terminal_string(&self) -> String {
+        let bitmap = self.to_bitmap(1);
+        let mut out = String::new();
+        for rows in bitmap.chunks(2) {
+            for x in 0..rows[0].len() {
+                let top = rows[0][x];
+                let bottom = rows.get(1).map(|row| row[x]).unwrap_or(false);
+                out.push(match (top, bottom) {
+                    (true, true) => ' ',
+                    (true, false) => '▄',
+                    (false, true) => '▀',
+                    (false, false) => '█',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    // Renders centered at the largest integer scale that fits, into an SSD1306 style buffer.
+    // Dark modules are drawn as unlit pixels on a lit background, as scanners expect.
+    pub fn render_ssd1306(&self, buffer: &mut [u8; SSD1306_BUFFER_LEN]) -> Result<(), String> {
+        let modules = self.size + 2 * QUIET_ZONE;
+        let scale = SSD1306_HEIGHT / modules;
+        if scale == 0 {
+            return Err(format!("QR code with {} modules doesn't fit the display", self.size));
+        }
+
+        let bitmap = self.to_bitmap(scale);
+        let side = bitmap.len();
+        let x0 = (SSD1306_WIDTH - side) / 2;
+        let y0 = (SSD1306_HEIGHT - side) / 2;
+
+        buffer.fill(0);
+        for (y, row) in bitmap.iter().enumerate() {
+            for (x, dark) in row.iter().enumerate() {
+                if !dark {
+                    let (px, py) = (x0 + x, y0 + y);
+                    buffer[(py / 8) * SSD1306_WIDTH + px] |= 1 << (py % 8);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Plain address URI understood by Solana wallets when scanned
+pub fn wallet_uri(pubkey: &Pubkey) -> String {
+    format!("solana:{}", pubkey)
+}
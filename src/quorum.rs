@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde_json::Value;
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Signature;
+
+use crate::solrpc::{
+    self, parse_account_info, parse_balance, parse_signature_status, rpc_call, AccountInfo, ConfirmationStatus,
+    RpcConfig, SolanaRpcMethod, CONFIRM_POLL_INTERVAL,
+};
+
+// Providers asked on top of the configured endpoint, comma separated, and how many answers
+// must agree (quorum_urls and quorum_threshold in cfg.toml, checked by build.rs)
+const QUORUM_URLS: &str = env!("RESP32SOL_QUORUM_URLS");
+const QUORUM_THRESHOLD: &str = env!("RESP32SOL_QUORUM_THRESHOLD");
+
+// Issues the same read to several independent providers and only returns a value once
+// `threshold` of them agree, so a single lying or compromised endpoint can't feed the
+// device a fake balance, account or confirmation. Any two answers that differ fail the
+// read outright, a disagreement is treated as an attack rather than outvoted.
+#[derive(Debug, Clone)]
+pub struct Quorum {
+    providers: Vec<RpcConfig>,
+    threshold: usize,
+}
+
+impl Quorum {
+    pub fn new(providers: Vec<RpcConfig>, threshold: usize) -> Result<Self, String> {
+        if threshold < 2 || threshold > providers.len() {
+            return Err(format!(
+                "Quorum threshold must be between 2 and the number of providers ({})",
+                providers.len()
+            ));
+        }
+        Ok(Self { providers, threshold })
+    }
+
+    // Balances and accounts are read at the default finalized commitment, so honest
+    // providers at slightly different slots still return the same value
+    pub fn get_balance(&self, pubkey: &Pubkey) -> Result<u64, String> {
+        self.agree(SolanaRpcMethod::GetBalance(pubkey.to_string()), parse_balance)
+    }
+
+    pub fn get_account_info(&self, pubkey: &Pubkey) -> Result<Option<AccountInfo>, String> {
+        self.agree(SolanaRpcMethod::GetAccountInfo(pubkey.to_string()), parse_account_info)
+    }
+
+    // A status still moving through the commitment levels can differ between providers
+    // for a moment, poll again until they agree
+    pub fn get_signature_status(&self, signature: &Signature) -> Result<Option<ConfirmationStatus>, String> {
+        self.agree(
+            SolanaRpcMethod::GetSignatureStatuses(vec![signature.to_string()]),
+            parse_signature_status,
+        )
+    }
+
+    fn agree<T: PartialEq + std::fmt::Debug>(
+        &self,
+        method: SolanaRpcMethod,
+        parse: fn(&Value) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let mut agreed: Option<(T, usize)> = None;
+        let mut errors = Vec::new();
+
+        for provider in &self.providers {
+            let answer = match rpc_call(provider, method.clone())
+                .map_err(|e| e.to_string())
+                .and_then(|result| parse(&result))
+            {
+                Ok(answer) => answer,
+                Err(e) => {
+                    warn!("Quorum provider {} failed: {}", provider.url, e);
+                    errors.push(format!("{}: {}", provider.url, e));
+                    continue;
+                }
+            };
+
+            match &mut agreed {
+                None => agreed = Some((answer, 1)),
+                Some((value, count)) if *value == answer => *count += 1,
+                Some((value, _)) => {
+                    warn!("Quorum provider {} answered {:?}, others {:?}", provider.url, answer, value);
+                    return Err(format!("RPC providers disagree on {}", method.method_name()));
+                }
+            }
+        }
+
+        match agreed {
+            Some((value, count)) if count >= self.threshold => Ok(value),
+            agreed => Err(format!(
+                "Only {} of {} required providers answered {}: {}",
+                agreed.map_or(0, |(_, count)| count),
+                self.threshold,
+                method.method_name(),
+                errors.join(", ")
+            )),
+        }
+    }
+}
+
+// The build's quorum over the configured endpoint and the quorum_urls providers, None when no
+// providers are listed
+pub fn configured() -> Option<Quorum> {
+    if QUORUM_URLS.is_empty() {
+        return None;
+    }
+    let mut providers = vec![solrpc::rpc_config()];
+    providers.extend(QUORUM_URLS.split(',').map(|url| RpcConfig {
+        url: url.trim().to_string(),
+        ..Default::default()
+    }));
+    match Quorum::new(providers, QUORUM_THRESHOLD.parse().unwrap_or(2)) {
+        Ok(quorum) => Some(quorum),
+        Err(e) => {
+            // build.rs checks the settings, only a threshold it let through can get here
+            warn!("Quorum not used: {}", e);
+            None
+        }
+    }
+}
+
+// The operations that move or destroy value read through these: the configured quorum's
+// answer when the build has one, the configured endpoint's otherwise
+
+pub fn get_balance(pubkey: &Pubkey) -> Result<u64, String> {
+    match configured() {
+        Some(quorum) => quorum.get_balance(pubkey),
+        None => solrpc::get_balance(pubkey).map_err(|e| e.to_string()),
+    }
+}
+
+pub fn get_account_info(pubkey: &Pubkey) -> Result<Option<AccountInfo>, String> {
+    match configured() {
+        Some(quorum) => quorum.get_account_info(pubkey),
+        None => solrpc::get_account_info(pubkey).map_err(|e| e.to_string()),
+    }
+}
+
+// Polls until the providers agree the transaction reached `target`. A disagreement or a
+// provider's error is polled through until the timeout.
+pub fn confirm_transaction(signature: &Signature, target: ConfirmationStatus, timeout: Duration) -> Result<(), String> {
+    let Some(quorum) = configured() else {
+        return solrpc::confirm_transaction(signature, target, timeout).map_err(|e| e.to_string());
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match quorum.get_signature_status(signature) {
+            Ok(Some(status)) if status >= target => return Ok(()),
+            _ if Instant::now() < deadline => std::thread::sleep(CONFIRM_POLL_INTERVAL),
+            Ok(status) => return Err(format!("{} still {:?} after {}s", signature, status, timeout.as_secs())),
+            Err(e) => return Err(e),
+        }
+    }
+}
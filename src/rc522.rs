@@ -0,0 +1,218 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::hal::spi::config::{Config, DriverConfig};
+use esp_idf_svc::hal::spi::{SpiDeviceDriver, SpiDriver, SPI2};
+use log::info;
+
+use crate::nfc::TagReader;
+
+// NXP MFRC522 over SPI. Unlike the PN532 it only moves frames, the ISO14443A activation
+// (REQA, anticollision, select) runs here.
+
+const COMMAND: u8 = 0x01;
+const COM_IRQ: u8 = 0x04;
+const DIV_IRQ: u8 = 0x05;
+const ERROR: u8 = 0x06;
+const FIFO_DATA: u8 = 0x09;
+const FIFO_LEVEL: u8 = 0x0A;
+const CONTROL: u8 = 0x0C;
+const BIT_FRAMING: u8 = 0x0D;
+const MODE: u8 = 0x11;
+const TX_CONTROL: u8 = 0x14;
+const TX_ASK: u8 = 0x15;
+const CRC_RESULT_HIGH: u8 = 0x21;
+const CRC_RESULT_LOW: u8 = 0x22;
+const T_MODE: u8 = 0x2A;
+const T_PRESCALER: u8 = 0x2B;
+const T_RELOAD_HIGH: u8 = 0x2C;
+const T_RELOAD_LOW: u8 = 0x2D;
+const VERSION: u8 = 0x37;
+
+const IDLE: u8 = 0x00;
+const CALC_CRC: u8 = 0x03;
+const TRANSCEIVE: u8 = 0x0C;
+const SOFT_RESET: u8 = 0x0F;
+
+// Tag commands: wake-up (reaches halted tags too), halt, the select cascade levels and NTAG21x
+const WUPA: u8 = 0x52;
+const HLTA: [u8; 2] = [0x50, 0x00];
+const SELECT_CASCADE: [u8; 3] = [0x93, 0x95, 0x97];
+const CASCADE_TAG: u8 = 0x88;
+const NTAG_READ: u8 = 0x30;
+const NTAG_READ_CNT: u8 = 0x39;
+const NTAG_NFC_COUNTER: u8 = 0x02;
+
+// The tag answers within its 25 ms receive timer, this only guards against a stuck chip
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(50);
+
+pub struct Rc522 {
+    spi: SpiDeviceDriver<'static, SpiDriver<'static>>,
+}
+
+impl Rc522 {
+    pub fn new(spi: SPI2, sclk: AnyIOPin, mosi: AnyIOPin, miso: AnyIOPin, cs: AnyIOPin) -> Result<Self, String> {
+        let driver = SpiDriver::new(spi, sclk, mosi, Some(miso), &DriverConfig::new())
+            .map_err(|e| format!("SPI init: {:?}", e))?;
+        let spi = SpiDeviceDriver::new(driver, Some(cs), &Config::new().baudrate(4.MHz().into()))
+            .map_err(|e| format!("SPI device: {:?}", e))?;
+        let mut rc522 = Self { spi };
+
+        rc522.write(COMMAND, SOFT_RESET)?;
+        std::thread::sleep(Duration::from_millis(50));
+        let version = rc522.read(VERSION)?;
+        if version == 0x00 || version == 0xFF {
+            return Err("No MFRC522 answering".to_string());
+        }
+        info!("MFRC522 version {:#04x}", version);
+
+        // Receive timeout of 25 ms: 40 kHz timer ticks, 1000 of them
+        rc522.write(T_MODE, 0x80)?;
+        rc522.write(T_PRESCALER, 0xA9)?;
+        rc522.write(T_RELOAD_HIGH, 0x03)?;
+        rc522.write(T_RELOAD_LOW, 0xE8)?;
+        // 100% ASK modulation, CRC preset 0x6363 as ISO14443A wants
+        rc522.write(TX_ASK, 0x40)?;
+        rc522.write(MODE, 0x3D)?;
+        let tx_control = rc522.read(TX_CONTROL)?;
+        rc522.write(TX_CONTROL, tx_control | 0x03)?;
+        Ok(rc522)
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, String> {
+        let mut buf = [0u8; 2];
+        self.spi
+            .transfer(&mut buf, &[0x80 | (register << 1), 0x00])
+            .map_err(|e| format!("MFRC522 read: {:?}", e))?;
+        Ok(buf[1])
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), String> {
+        self.spi
+            .write(&[register << 1, value])
+            .map_err(|e| format!("MFRC522 write: {:?}", e))
+    }
+
+    // Sends a frame, the last byte `bits` long (0 for all 8), and returns the answer with the
+    // valid bits of its last byte. None when no tag answered.
+    fn transceive(&mut self, data: &[u8], bits: u8) -> Result<Option<(Vec<u8>, u8)>, String> {
+        self.write(COMMAND, IDLE)?;
+        self.write(COM_IRQ, 0x7F)?;
+        self.write(FIFO_LEVEL, 0x80)?;
+        for byte in data {
+            self.write(FIFO_DATA, *byte)?;
+        }
+        self.write(BIT_FRAMING, bits)?;
+        self.write(COMMAND, TRANSCEIVE)?;
+        self.write(BIT_FRAMING, 0x80 | bits)?;
+
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        loop {
+            let irq = self.read(COM_IRQ)?;
+            // Received, or the receive timer ran out
+            if irq & 0x30 != 0 {
+                break;
+            }
+            if irq & 0x01 != 0 {
+                return Ok(None);
+            }
+            if Instant::now() >= deadline {
+                return Err("MFRC522 command timed out".to_string());
+            }
+        }
+        self.write(BIT_FRAMING, 0x00)?;
+
+        // Buffer overflow, collision, parity or protocol error
+        let error = self.read(ERROR)?;
+        if error & 0x1B != 0 {
+            return Err(format!("MFRC522 error {:#04x}", error));
+        }
+        let len = self.read(FIFO_LEVEL)?;
+        let bits = self.read(CONTROL)? & 0x07;
+        let answer = (0..len).map(|_| self.read(FIFO_DATA)).collect::<Result<Vec<u8>, String>>()?;
+        Ok(Some((answer, bits)))
+    }
+
+    // The frame followed by its CRC_A, computed by the chip
+    fn with_crc(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        self.write(COMMAND, IDLE)?;
+        self.write(DIV_IRQ, 0x04)?;
+        self.write(FIFO_LEVEL, 0x80)?;
+        for byte in data {
+            self.write(FIFO_DATA, *byte)?;
+        }
+        self.write(COMMAND, CALC_CRC)?;
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        while self.read(DIV_IRQ)? & 0x04 == 0 {
+            if Instant::now() >= deadline {
+                return Err("MFRC522 CRC timed out".to_string());
+            }
+        }
+        self.write(COMMAND, IDLE)?;
+        let mut frame = data.to_vec();
+        frame.push(self.read(CRC_RESULT_LOW)?);
+        frame.push(self.read(CRC_RESULT_HIGH)?);
+        Ok(frame)
+    }
+}
+
+impl TagReader for Rc522 {
+    fn select(&mut self) -> Result<Option<Vec<u8>>, String> {
+        // The tag selected last is halted first, a selected tag would ignore the wake-up and
+        // look gone
+        let halt = self.with_crc(&HLTA)?;
+        let _ = self.transceive(&halt, 0);
+        match self.transceive(&[WUPA], 7) {
+            Ok(Some((answer, _))) if answer.len() == 2 => {}
+            _ => return Ok(None),
+        }
+
+        let mut uid = Vec::new();
+        for cascade in SELECT_CASCADE {
+            let (answer, _) = self
+                .transceive(&[cascade, 0x20], 0)?
+                .ok_or("Tag left during anticollision")?;
+            if answer.len() != 5 || answer[..4].iter().fold(0, |check, byte| check ^ byte) != answer[4] {
+                return Err("Tag UID check byte mismatch".to_string());
+            }
+            let mut select = vec![cascade, 0x70];
+            select.extend_from_slice(&answer);
+            let select = self.with_crc(&select)?;
+            let (sak, _) = self.transceive(&select, 0)?.ok_or("Tag left during select")?;
+            if sak.len() != 3 {
+                return Err("Tag select answer truncated".to_string());
+            }
+            match answer[0] {
+                CASCADE_TAG => uid.extend_from_slice(&answer[1..4]),
+                _ => uid.extend_from_slice(&answer[..4]),
+            }
+            // The UID is complete once the select answer stops asking for the next level
+            if sak[0] & 0x04 == 0 {
+                return Ok(Some(uid));
+            }
+        }
+        Err("Tag UID longer than three cascade levels".to_string())
+    }
+
+    fn read(&mut self, page: u8) -> Result<[u8; 16], String> {
+        let request = self.with_crc(&[NTAG_READ, page])?;
+        let (answer, _) = self.transceive(&request, 0)?.ok_or("No answer to a tag read")?;
+        if answer.len() != 18 {
+            return Err(format!("Tag refused the read of page {}", page));
+        }
+        if self.with_crc(&answer[..16])?[16..] != answer[16..] {
+            return Err("Tag read CRC mismatch".to_string());
+        }
+        Ok(answer[..16].try_into().unwrap())
+    }
+
+    fn read_counter(&mut self) -> Result<Option<u32>, String> {
+        let request = self.with_crc(&[NTAG_READ_CNT, NTAG_NFC_COUNTER])?;
+        // Tags without the counter enabled answer with a 4 bit NAK
+        match self.transceive(&request, 0)? {
+            Some((answer, _)) if answer.len() == 5 => Ok(Some(u32::from_le_bytes([answer[0], answer[1], answer[2], 0]))),
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use solana_keypair::{Keypair, Signer};
+use solana_program::pubkey::Pubkey;
+
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, Sound};
+use crate::display;
+#[cfg(feature = "receipt-printer")]
+use crate::printer;
+use crate::qr::QrMatrix;
+use crate::solanapay::TransferRequest;
+use crate::solrpc::{get_signatures_for_address, get_transaction};
+
+// Point-of-sale receiving: every sale gets a Solana Pay transfer request with a reference key of
+// its own, shown as a QR code on the display. The payer's wallet adds the reference to its
+// transfer, so the payment turns up among the reference's transactions without going through
+// everything else the recipient receives.
+
+// More than one, anyone can send a transaction carrying the reference
+const SIGNATURES_PER_POLL: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReceiveConfig {
+    // Decimal in SOL or in the token's units, rotary-encoder builds dial it in instead
+    pub amount: &'static str,
+    pub spl_token: Option<Pubkey>,
+    // Shown by the wallet as who is asking
+    pub label: &'static str,
+    pub message: Option<&'static str>,
+    // An unpaid request is replaced by a new one after this
+    pub expiry: Duration,
+    pub poll_interval: Duration,
+}
+
+pub struct PaymentRequest {
+    request: TransferRequest,
+    reference: Pubkey,
+    created: Instant,
+    // Transactions carrying the reference that didn't pay it, not fetched again
+    rejected: Vec<String>,
+}
+
+impl PaymentRequest {
+    pub fn new(recipient: Pubkey, amount: &str, config: &ReceiveConfig) -> Result<Self, String> {
+        // Only its address is used, nothing ever signs with it
+        let reference = Keypair::new().pubkey();
+        let request = TransferRequest {
+            recipient,
+            amount: Some(amount.to_string()),
+            spl_token: config.spl_token,
+            references: vec![reference],
+            label: Some(config.label.to_string()),
+            message: config.message.map(|message| message.to_string()),
+            memo: None,
+        };
+        // Catches a malformed amount before a wallet refuses the code
+        TransferRequest::parse(&request.to_url())?;
+        Ok(Self {
+            request,
+            reference,
+            created: Instant::now(),
+            rejected: Vec::new(),
+        })
+    }
+
+    pub fn show(&self) -> Result<(), String> {
+        let url = self.request.to_url();
+        let qr = QrMatrix::encode(&url)?;
+        info!("Scan to pay {}:\n{}", url, qr.to_terminal_string());
+        display::show_request(&qr, &[self.amount(), self.unit()])
+    }
+
+    pub fn expired(&self, expiry: Duration) -> bool {
+        self.created.elapsed() >= expiry
+    }
+
+    // The signature of a transaction paying the request in full, once one has landed
+    pub fn poll(&mut self) -> Result<Option<String>, String> {
+        for signature in get_signatures_for_address(&self.reference, SIGNATURES_PER_POLL).map_err(|e| e.to_string())? {
+            if self.rejected.contains(&signature) {
+                continue;
+            }
+            // Listed before the node serving getTransaction has it, looked at again next poll
+            let Some(transaction) = get_transaction(&signature).map_err(|e| e.to_string())? else {
+                continue;
+            };
+            match self.request.validate(&transaction) {
+                Ok(()) => {
+                    info!("Payment of {} {} received: {}", self.amount(), self.unit(), signature);
+                    display::payment_received(&format!("Paid {} {}", self.amount(), self.unit()));
+                    #[cfg(feature = "buzzer")]
+                    buzzer::play(Sound::Incoming);
+                    #[cfg(feature = "receipt-printer")]
+                    printer::payment_received(&format!("{} {}", self.amount(), self.unit()), &signature);
+                    return Ok(Some(signature));
+                }
+                Err(e) => {
+                    warn!("Transaction {} carries the reference but doesn't pay: {}", signature, e);
+                    self.rejected.push(signature);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn amount(&self) -> &str {
+        self.request.amount.as_deref().unwrap_or_default()
+    }
+
+    fn unit(&self) -> &'static str {
+        match self.request.spl_token {
+            None => "SOL",
+            Some(_) => "token",
+        }
+    }
+}
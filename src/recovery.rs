@@ -0,0 +1,80 @@
+use std::fmt;
+use std::time::Duration;
+
+use log::{error, warn};
+
+// What boot does when one of its steps fails, instead of panicking on it. The step returns its
+// error here and the handler app::start passes in decides, by step and attempt: try again after a
+// pause, send the device to provisioning, go on without what the step brings up, or restart.
+// A panic during boot restarts the device over and over, a degraded one at least shows what is
+// wrong and keeps doing what it still can.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootStep {
+    // Taking the peripherals, which everything else is built from
+    Peripherals,
+    // The default NVS partition, holding the credentials, PIN, policy and logs
+    Storage,
+    // The system event loop the network drivers report on
+    EventLoop,
+    // The WiFi driver and the thread that keeps the link up
+    Uplink,
+    // Stored WiFi networks the driver can be configured with
+    Credentials,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    Retry(Duration),
+    // New credentials are needed, over BLE or in the setup portal
+    Provision,
+    // Go on without what the step brings up
+    Degrade,
+    Restart(Duration),
+}
+
+// Decides what to do about a failed step, `attempt` counts from 1
+pub type Handler = fn(BootStep, u32, &str) -> Recovery;
+
+#[derive(Debug, Clone)]
+pub struct BootFailure {
+    pub step: BootStep,
+    pub recovery: Recovery,
+    pub error: String,
+}
+
+impl fmt::Display for BootFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} failed: {}", self.step, self.error)
+    }
+}
+
+// Runs `step` until it works or the handler gives up on it with Provision or Degrade. Restart
+// doesn't return.
+pub fn attempt<T, E: fmt::Display>(
+    step: BootStep,
+    handler: Handler,
+    mut run: impl FnMut() -> Result<T, E>,
+) -> Result<T, BootFailure> {
+    let mut attempt = 1;
+    loop {
+        let error = match run() {
+            Ok(value) => return Ok(value),
+            Err(e) => e.to_string(),
+        };
+        let recovery = handler(step, attempt, &error);
+        warn!("{:?} failed on attempt {}: {}, {:?}", step, attempt, error, recovery);
+        match recovery {
+            Recovery::Retry(delay) => std::thread::sleep(delay),
+            Recovery::Restart(delay) => restart(delay),
+            Recovery::Provision | Recovery::Degrade => return Err(BootFailure { step, recovery, error }),
+        }
+        attempt += 1;
+    }
+}
+
+pub fn restart(delay: Duration) -> ! {
+    error!("Restarting in {}s", delay.as_secs());
+    std::thread::sleep(delay);
+    unsafe { esp_idf_svc::sys::esp_restart() }
+}
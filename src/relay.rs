@@ -0,0 +1,331 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::espnow::{EspNow, PeerInfo, BROADCAST};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{esp_wifi_set_channel, wifi_interface_t_WIFI_IF_STA, wifi_second_chan_t_WIFI_SECOND_CHAN_NONE};
+use esp_idf_svc::wifi::{ClientConfiguration, Configuration, EspWifi};
+use log::{error, info, warn};
+use solana_transaction::{Hash, Transaction};
+
+use crate::frag::{fragment, Reassembler, MAX_TRANSACTION_LEN};
+use crate::gateway::{self, ACK_DUPLICATE, ACK_QUEUED, ACK_REJECTED};
+
+// Gateway pattern over ESP-NOW: battery nodes never join WiFi, they ask one gateway for a
+// recent blockhash, sign locally and push the serialized transaction to it in RSF frames (see
+// frag.rs). The gateway checks the signatures, drops repeats and submits over its own uplink
+// (see gateway.rs).
+// Every message is one ESP-NOW packet starting with its tag:
+//   B                 node -> gateway, blockhash request
+//   H <32 bytes>      gateway -> node, recent blockhash
+//   P                 gateway -> node, blockhash being fetched, ask again shortly
+//   T <RSF frame>     node -> gateway, one frame of a serialized transaction
+//   A <status> <sig>  gateway -> node, the transaction with that first signature arrived
+const BLOCKHASH_REQUEST: u8 = b'B';
+const BLOCKHASH: u8 = b'H';
+const BLOCKHASH_PENDING: u8 = b'P';
+const TX_FRAME: u8 = b'T';
+const ACK: u8 = b'A';
+
+// "node", "gateway" or empty, and optionally the gateway MAC a node only trusts (see build.rs)
+const ROLE: &str = env!("RESP32SOL_RELAY");
+const PINNED_GATEWAY: &str = env!("RESP32SOL_RELAY_GATEWAY");
+
+// Keeps an RSF frame with its tag under the 250-byte ESP-NOW payload limit
+const CHUNK_LEN: usize = 168;
+// Lets the driver drain its queue between frames of one transaction
+const FRAME_GAP: Duration = Duration::from_millis(5);
+const WIFI_CHANNELS: u8 = 13;
+// Per channel while looking for the gateway, it answers within a few milliseconds
+const SCAN_WAIT: Duration = Duration::from_millis(300);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const REQUEST_ATTEMPTS: u32 = 5;
+const SEND_ATTEMPTS: u32 = 3;
+const QUEUE_FULL_DELAY: Duration = Duration::from_secs(5);
+
+// ESP-NOW allows 20 unencrypted peers, the oldest node is dropped beyond this
+const MAX_PEERS: usize = 16;
+// A node sends all frames of a transaction within milliseconds and again after REPLY_TIMEOUT,
+// frames of one that went quiet for this long are dropped
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(10);
+const RELAY_STACK_SIZE: usize = 8 * 1024;
+
+struct Node {
+    _wifi: EspWifi<'static>,
+    espnow: EspNow<'static>,
+    inbox: Receiver<([u8; 6], Vec<u8>)>,
+    gateway: Option<[u8; 6]>,
+}
+
+static NODE: Mutex<Option<Node>> = Mutex::new(None);
+
+pub fn is_node() -> bool {
+    ROLE == "node"
+}
+
+pub fn is_gateway() -> bool {
+    ROLE == "gateway"
+}
+
+// Starts the radio for ESP-NOW only, a node never joins an access point
+pub fn start_node(modem: Modem, sys_loop: EspSystemEventLoop, nvs: EspDefaultNvsPartition) {
+    match open_node(modem, sys_loop, nvs) {
+        Ok(node) => {
+            info!("ESP-NOW relay node, transactions go through the gateway");
+            *NODE.lock().unwrap() = Some(node);
+        }
+        Err(e) => error!("ESP-NOW relay unavailable: {}", e),
+    }
+}
+
+fn open_node(modem: Modem, sys_loop: EspSystemEventLoop, nvs: EspDefaultNvsPartition) -> Result<Node, String> {
+    let mut wifi = EspWifi::new(modem, sys_loop, Some(nvs)).map_err(|e| format!("WiFi driver: {:?}", e))?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))
+        .map_err(|e| format!("WiFi config: {:?}", e))?;
+    wifi.start().map_err(|e| format!("WiFi start: {:?}", e))?;
+
+    let espnow = EspNow::take().map_err(|e| format!("ESP-NOW: {:?}", e))?;
+    add_peer(&espnow, BROADCAST)?;
+
+    let pinned = match PINNED_GATEWAY {
+        "" => None,
+        mac => Some(parse_mac(mac).ok_or("relay_gateway is not a MAC address")?),
+    };
+    let (received, inbox) = channel();
+    espnow
+        .register_recv_cb(move |info, data| {
+            // A pinned gateway is the only peer a node listens to
+            if pinned.is_none_or(|gateway| gateway == *info.src_addr) {
+                let _ = received.send((*info.src_addr, data.to_vec()));
+            }
+        })
+        .map_err(|e| format!("ESP-NOW receive: {:?}", e))?;
+
+    Ok(Node {
+        _wifi: wifi,
+        espnow,
+        inbox,
+        gateway: None,
+    })
+}
+
+// Blockhash to sign with, from the gateway's RPC endpoint
+pub fn latest_blockhash() -> Result<Hash, String> {
+    let mut node = NODE.lock().unwrap();
+    let node = node.as_mut().ok_or("ESP-NOW relay not running")?;
+
+    for _ in 0..REQUEST_ATTEMPTS {
+        let reply = match node.gateway {
+            Some(gateway) => {
+                node.send(gateway, &[BLOCKHASH_REQUEST])?;
+                node.wait_reply(REPLY_TIMEOUT, |tag, _| tag == BLOCKHASH || tag == BLOCKHASH_PENDING)
+            }
+            None => node.find_gateway()?,
+        };
+        match reply {
+            Some((BLOCKHASH, body)) => {
+                let hash: [u8; 32] = body.try_into().map_err(|_| "Malformed blockhash from the gateway")?;
+                return Ok(Hash::new_from_array(hash));
+            }
+            Some(_) => std::thread::sleep(REPLY_TIMEOUT),
+            None => {
+                // Moved to another channel or gone, look for it again
+                warn!("Relay gateway not answering");
+                node.gateway = None;
+            }
+        }
+    }
+    Err("No blockhash from the relay gateway".to_string())
+}
+
+// Hands a signed transaction to the gateway, Ok once it has queued it
+pub fn submit(transaction: &Transaction) -> Result<String, String> {
+    let signature = *transaction.signatures.first().ok_or("Transaction is not signed")?;
+    let bytes = bincode::serialize(transaction).map_err(|e| format!("Transaction serialization failed: {:?}", e))?;
+    let frames = fragment(&bytes, CHUNK_LEN);
+
+    let mut node = NODE.lock().unwrap();
+    let node = node.as_mut().ok_or("ESP-NOW relay not running")?;
+    let gateway = node.gateway.ok_or("Relay gateway unknown, fetch a blockhash first")?;
+
+    for _ in 0..SEND_ATTEMPTS {
+        for frame in &frames {
+            node.send(gateway, &[&[TX_FRAME], frame.as_bytes()].concat())?;
+            std::thread::sleep(FRAME_GAP);
+        }
+
+        let ack = node.wait_reply(REPLY_TIMEOUT, |tag, body| {
+            tag == ACK && body.get(1..).is_some_and(|acked| acked == signature.as_ref())
+        });
+        match ack.and_then(|(_, body)| body.first().copied()) {
+            Some(ACK_QUEUED) | Some(ACK_DUPLICATE) => return Ok(signature.to_string()),
+            Some(ACK_REJECTED) => return Err("Relay gateway rejected the transaction".to_string()),
+            Some(_) => std::thread::sleep(QUEUE_FULL_DELAY),
+            None => warn!("No ack for {}, sending again", signature),
+        }
+    }
+    Err(format!("Relay gateway did not take {}", signature))
+}
+
+impl Node {
+    fn send(&self, peer: [u8; 6], data: &[u8]) -> Result<(), String> {
+        self.espnow
+            .send(peer, data)
+            .map_err(|e| format!("ESP-NOW send: {:?}", e))
+    }
+
+    // Next reply the filter accepts from the gateway, everything else is dropped
+    fn wait_reply(&mut self, timeout: Duration, accept: impl Fn(u8, &[u8]) -> bool) -> Option<(u8, Vec<u8>)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (from, data) = self.inbox.recv_timeout(remaining).ok()?;
+            let Some((&tag, body)) = data.split_first() else {
+                continue;
+            };
+            if self.gateway.is_none_or(|gateway| gateway == from) && accept(tag, body) {
+                self.gateway = Some(from);
+                return Some((tag, body.to_vec()));
+            }
+        }
+    }
+
+    // Broadcasts a blockhash request on every channel until a gateway answers, ESP-NOW only
+    // reaches it on the channel of the access point it is connected to
+    fn find_gateway(&mut self) -> Result<Option<(u8, Vec<u8>)>, String> {
+        for channel in 1..=WIFI_CHANNELS {
+            unsafe { esp_wifi_set_channel(channel, wifi_second_chan_t_WIFI_SECOND_CHAN_NONE) };
+            self.send(BROADCAST, &[BLOCKHASH_REQUEST])?;
+            if let Some(reply) = self.wait_reply(SCAN_WAIT, |tag, _| tag == BLOCKHASH || tag == BLOCKHASH_PENDING) {
+                let gateway = self.gateway.ok_or("Relay gateway unknown")?;
+                add_peer(&self.espnow, gateway)?;
+                info!("Relay gateway {} on channel {}", format_mac(&gateway), channel);
+                return Ok(Some(reply));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Listens for nodes next to the normal uplink, needs WiFi since ESP-NOW shares its radio
+pub fn start_gateway() -> Result<(), String> {
+    let espnow = EspNow::take().map_err(|e| format!("ESP-NOW: {:?}", e))?;
+    let (received, inbox) = channel();
+    espnow
+        .register_recv_cb(move |info, data| {
+            let _ = received.send((*info.src_addr, data.to_vec()));
+        })
+        .map_err(|e| format!("ESP-NOW receive: {:?}", e))?;
+
+    std::thread::Builder::new()
+        .name("relay".to_string())
+        .stack_size(RELAY_STACK_SIZE)
+        .spawn(move || serve(espnow, inbox))
+        .map_err(|e| format!("Relay thread: {:?}", e))?;
+    gateway::start()?;
+
+    info!("ESP-NOW relay gateway listening");
+    Ok(())
+}
+
+fn serve(espnow: EspNow<'static>, inbox: Receiver<([u8; 6], Vec<u8>)>) {
+    // Each node's transaction being received, and when its last frame came
+    let mut reassemblers: HashMap<[u8; 6], (Reassembler, Instant)> = HashMap::new();
+    let mut peers: VecDeque<[u8; 6]> = VecDeque::new();
+
+    // The sender lives in the receive callback, which stays registered, so this never ends
+    for (node, data) in inbox {
+        let Some((&tag, body)) = data.split_first() else {
+            continue;
+        };
+        let reply = match tag {
+            BLOCKHASH_REQUEST => match gateway::blockhash() {
+                Some(hash) => [&[BLOCKHASH], hash.as_ref()].concat(),
+                None => vec![BLOCKHASH_PENDING],
+            },
+            TX_FRAME => {
+                reassemblers.retain(|_, (_, last_frame)| last_frame.elapsed() < PARTIAL_TIMEOUT);
+                if !reassemblers.contains_key(&node) && reassemblers.len() >= MAX_PEERS {
+                    // Half-sent transactions are retransmitted in full anyway, the one that
+                    // went quiet longest makes room
+                    let stalest =
+                        reassemblers.iter().min_by_key(|(_, (_, last_frame))| *last_frame).map(|(node, _)| *node);
+                    if let Some(stalest) = stalest {
+                        reassemblers.remove(&stalest);
+                    }
+                }
+                let frame = String::from_utf8_lossy(body);
+                let (reassembler, last_frame) = reassemblers
+                    .entry(node)
+                    .or_insert_with(|| (Reassembler::new(MAX_TRANSACTION_LEN, CHUNK_LEN), Instant::now()));
+                *last_frame = Instant::now();
+                match reassembler.push(&frame) {
+                    Ok(Some(payload)) => {
+                        reassemblers.remove(&node);
+                        match gateway::accept(&payload) {
+                            Ok((status, signature)) => [&[ACK, status], signature.as_ref()].concat(),
+                            Err(e) => {
+                                warn!("Relayed transaction from {} dropped: {}", format_mac(&node), e);
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("Relay frame from {}: {}", format_mac(&node), e);
+                        continue;
+                    }
+                }
+            }
+            _ => continue,
+        };
+
+        if !peers.contains(&node) {
+            if peers.len() >= MAX_PEERS {
+                if let Some(oldest) = peers.pop_front() {
+                    let _ = espnow.del_peer(oldest);
+                }
+            }
+            if let Err(e) = add_peer(&espnow, node) {
+                warn!("Relay node {} not added: {}", format_mac(&node), e);
+                continue;
+            }
+            peers.push_back(node);
+        }
+        if let Err(e) = espnow.send(node, &reply) {
+            warn!("Relay reply to {}: {:?}", format_mac(&node), e);
+        }
+    }
+}
+
+fn add_peer(espnow: &EspNow<'static>, peer: [u8; 6]) -> Result<(), String> {
+    if espnow.peer_exists(peer).unwrap_or(false) {
+        return Ok(());
+    }
+    espnow
+        .add_peer(PeerInfo {
+            peer_addr: peer,
+            // The current channel
+            channel: 0,
+            ifidx: wifi_interface_t_WIFI_IF_STA,
+            encrypt: false,
+            ..Default::default()
+        })
+        .map_err(|e| format!("ESP-NOW peer: {:?}", e))
+}
+
+fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<u8> = text
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":")
+}
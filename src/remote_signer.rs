@@ -0,0 +1,94 @@
+use base64::{engine::general_purpose, Engine as _};
+use log::{info, warn};
+use solana_transaction::Message;
+use zeroize::Zeroizing;
+
+use crate::serial::LineReader;
+use crate::signer::DeviceSigner;
+
+use std::time::Duration;
+
+// Local transport between the host application and the signer, one request/response per line.
+// Requests can carry the PIN, so they are handed over in a buffer that is wiped on drop.
+pub trait SignerChannel {
+    fn recv(&mut self) -> Option<Zeroizing<String>>;
+    fn send(&mut self, line: &str);
+}
+
+pub struct SerialChannel {
+    reader: LineReader,
+}
+
+impl SerialChannel {
+    pub fn new() -> Self {
+        Self {
+            reader: LineReader::new(),
+        }
+    }
+}
+
+impl Default for SerialChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignerChannel for SerialChannel {
+    fn recv(&mut self) -> Option<Zeroizing<String>> {
+        self.reader.read_line(Duration::from_secs(3600))
+    }
+
+    fn send(&mut self, line: &str) {
+        println!("{}", line);
+    }
+}
+
+// Network-isolated signer loop, the device only answers requests from the channel:
+//   PUBKEY                  -> OK <pubkey>
+//   SIGN <base64 message>   -> OK <base58 signature>
+//   UNLOCK <pin>            -> OK unlocked
+// Errors are answered with ERR <reason>.
+pub fn run(signer: &mut DeviceSigner, channel: &mut impl SignerChannel) -> ! {
+    info!("Remote signer ready for {}", signer.pubkey());
+
+    loop {
+        let Some(line) = channel.recv() else {
+            continue;
+        };
+
+        let response = match handle_request(signer, &line) {
+            Ok(response) => format!("OK {}", response),
+            Err(e) => {
+                warn!("Remote sign request refused: {}", e);
+                format!("ERR {}", e)
+            }
+        };
+        channel.send(&response);
+    }
+}
+
+fn handle_request(signer: &mut DeviceSigner, line: &str) -> Result<String, String> {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+
+    match command {
+        "PUBKEY" => Ok(signer.pubkey().to_string()),
+        "UNLOCK" => {
+            signer
+                .pin_gate()
+                .ok_or("No PIN gate configured")?
+                .verify(args.trim())
+                .map_err(|e| e.to_string())?;
+            Ok("unlocked".to_string())
+        }
+        "SIGN" => {
+            let message_bytes = general_purpose::STANDARD
+                .decode(args.trim())
+                .map_err(|e| format!("Base64 decode: {:?}", e))?;
+            let message: Message = bincode::deserialize(&message_bytes)
+                .map_err(|e| format!("Message decode: {:?}", e))?;
+
+            Ok(signer.sign_message(&message).map_err(|e| e.to_string())?.to_string())
+        }
+        _ => Err(format!("Unknown request '{}'", command)),
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Message;
+
+use crate::error::WalletError;
+use crate::inspect::{outgoing_lamports, token_transfers};
+use crate::ota;
+use crate::quorum;
+use crate::signer::SigningHook;
+
+const FLOOR_NAMESPACE: &str = "fw_floor";
+const FLOOR_KEY: &str = "floor";
+// The floor an update was last tried for, so an image that is still too old isn't flashed again
+// at every boot
+const UPDATED_FOR_KEY: &str = "updated_for";
+const VERSION_LEN: usize = 6;
+// How often a running device checks the published floor again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+const REFRESH_STACK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl FirmwareVersion {
+    pub fn running() -> Result<Self, String> {
+        Self::parse(env!("CARGO_PKG_VERSION"))
+    }
+
+    pub fn parse(version: &str) -> Result<Self, String> {
+        let mut parts = version.split('-').next().unwrap_or(version).split('.');
+        let mut next = || {
+            parts
+                .next()
+                .ok_or_else(|| format!("Version '{}' must be major.minor.patch", version))?
+                .parse::<u16>()
+                .map_err(|e| format!("Version '{}': {:?}", version, e))
+        };
+
+        Ok(Self {
+            major: next()?,
+            minor: next()?,
+            patch: next()?,
+        })
+    }
+
+    // Encoded as three little-endian u16: major, minor, patch
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let word = |i: usize| bytes.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        Some(Self {
+            major: word(0)?,
+            minor: word(2)?,
+            patch: word(4)?,
+        })
+    }
+
+    fn to_bytes(self) -> [u8; VERSION_LEN] {
+        let mut bytes = [0u8; VERSION_LEN];
+        bytes[0..2].copy_from_slice(&self.major.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.minor.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.patch.to_le_bytes());
+        bytes
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+// Where the minimum firmware version is published: `offset` bytes into the data of
+// `account`, which must be owned by `owner` so only its authority can move the floor
+#[derive(Debug, Clone)]
+pub struct FloorConfig {
+    pub account: Pubkey,
+    pub owner: Pubkey,
+    pub offset: usize,
+    // Image flashed over the air when the running firmware is below the floor, None only
+    // refuses payments (or halts)
+    pub update_url: Option<&'static str>,
+}
+
+// Anti-rollback check: once a floor has been seen it is remembered in NVS and only ever
+// raised, so an endpoint hiding the account (or a rolled back account) can't lower it
+pub struct FirmwareFloor {
+    nvs: EspNvs<NvsDefault>,
+    running: FirmwareVersion,
+    // Shared with the hooks, so a floor raised while running applies to the next signature
+    floor: Arc<Mutex<Option<FirmwareVersion>>>,
+}
+
+impl FirmwareFloor {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, FLOOR_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+
+        let mut buf = [0u8; VERSION_LEN];
+        let floor = nvs
+            .get_blob(FLOOR_KEY, &mut buf)
+            .map_err(|e| format!("Firmware floor read: {:?}", e))?
+            .and_then(FirmwareVersion::from_bytes);
+
+        Ok(Self {
+            nvs,
+            running: FirmwareVersion::running()?,
+            floor: Arc::new(Mutex::new(floor)),
+        })
+    }
+
+    // Fetches the published floor and raises the remembered one if it is higher
+    pub fn refresh(&mut self, config: &FloorConfig) -> Result<(), String> {
+        // Through the quorum when the build has one, a lying endpoint could otherwise hold back a raised floor
+        let account = quorum::get_account_info(&config.account)?.ok_or("Firmware floor account not found")?;
+        if account.owner != config.owner {
+            return Err(format!("Firmware floor account is owned by {}, expected {}", account.owner, config.owner));
+        }
+        let published = account
+            .data
+            .get(config.offset..)
+            .and_then(FirmwareVersion::from_bytes)
+            .ok_or("Firmware floor account data too short")?;
+
+        let mut floor = self.floor.lock().unwrap();
+        if floor.is_none_or(|floor| published > floor) {
+            self.nvs
+                .set_blob(FLOOR_KEY, &published.to_bytes())
+                .map_err(|e| format!("Firmware floor write: {:?}", e))?;
+            *floor = Some(published);
+            info!("Firmware floor raised to {}", published);
+        }
+
+        Ok(())
+    }
+
+    // The floor while the running firmware is below it
+    pub fn below(&self) -> Option<FirmwareVersion> {
+        (*self.floor.lock().unwrap()).filter(|floor| self.running < *floor)
+    }
+
+    // Below the floor, flashes the published update until one has been flashed for this floor.
+    // Returns whether the firmware is below it, a successful update restarts instead.
+    pub fn enforce(&mut self, config: &FloorConfig) -> bool {
+        let Some(floor) = self.below() else {
+            return false;
+        };
+        let Some(url) = config.update_url else {
+            return true;
+        };
+        let mut buf = [0u8; VERSION_LEN];
+        let updated_for = match self.nvs.get_blob(UPDATED_FOR_KEY, &mut buf) {
+            Ok(updated_for) => updated_for.and_then(FirmwareVersion::from_bytes),
+            Err(e) => {
+                warn!("Firmware update state read: {:?}", e);
+                return true;
+            }
+        };
+        if updated_for.is_some_and(|updated_for| updated_for >= floor) {
+            warn!("Firmware update for {} already tried, {} is still running", floor, self.running);
+            return true;
+        }
+        warn!("Firmware {} is below the floor {}, updating", self.running, floor);
+        if let Err(e) = ota::update(url) {
+            warn!("Firmware update failed: {}", e);
+            return true;
+        }
+        // An image that boots but is still too old mustn't be flashed in a loop
+        if let Err(e) = self.nvs.set_blob(UPDATED_FOR_KEY, &floor.to_bytes()) {
+            warn!("Firmware update state write: {:?}", e);
+            return true;
+        }
+        info!("Restarting into the updated firmware");
+        unsafe { esp_idf_svc::sys::esp_restart() }
+    }
+
+    // Hook refusing payments while the running firmware is below the floor
+    pub fn hook(&self) -> BelowFloor {
+        BelowFloor {
+            running: self.running,
+            floor: self.floor.clone(),
+        }
+    }
+
+    // Checks the published floor every REFRESH_INTERVAL, updating when it rises above the
+    // running firmware. With `halt` the device restarts instead when it can't update, and the
+    // boot check halts it.
+    pub fn spawn_refresh(mut self, config: FloorConfig, halt: bool) -> Result<(), String> {
+        std::thread::Builder::new()
+            .name("fw_floor".to_string())
+            .stack_size(REFRESH_STACK_SIZE)
+            .spawn(move || loop {
+                std::thread::sleep(REFRESH_INTERVAL);
+                if let Err(e) = self.refresh(&config) {
+                    warn!("Firmware floor refresh failed: {}", e);
+                }
+                if self.enforce(&config) && halt {
+                    warn!("Firmware is below the published minimum version, restarting to halt");
+                    unsafe { esp_idf_svc::sys::esp_restart() }
+                }
+            })
+            .map_err(|e| format!("Firmware floor refresh: {:?}", e))?;
+        Ok(())
+    }
+}
+
+pub struct BelowFloor {
+    running: FirmwareVersion,
+    floor: Arc<Mutex<Option<FirmwareVersion>>>,
+}
+
+impl SigningHook for BelowFloor {
+    fn check(&self, message: &Message, signer: &Pubkey) -> Result<(), WalletError> {
+        let Some(floor) = (*self.floor.lock().unwrap()).filter(|floor| self.running < *floor) else {
+            return Ok(());
+        };
+        let is_payment = outgoing_lamports(message, signer) > 0
+            || token_transfers(message).iter().any(|transfer| transfer.authority == *signer);
+        if !is_payment {
+            return Ok(());
+        }
+
+        warn!("Payment refused, firmware {} is below the published floor {}", self.running, floor);
+        Err(WalletError::Refused(format!(
+            "Firmware {} is below the minimum version {}, update required",
+            self.running, floor
+        )))
+    }
+}
+
+// Stops everything but this loop until the device is reflashed
+pub fn halt_below_floor() -> ! {
+    loop {
+        warn!("Firmware is below the published minimum version, reflash the device");
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
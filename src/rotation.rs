@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use solana_keypair::{Keypair, Signer};
+#[cfg(feature = "spl")]
+use solana_program::instruction::Instruction;
+use solana_program::pubkey::Pubkey;
+use solana_system_interface::instruction as system_instruction;
+use solana_transaction::{Message, Signature, Transaction};
+
+use crate::keystore::Keystore;
+use crate::quorum;
+use crate::solrpc::{get_fee_for_message, get_latest_blockhash, send_transaction, ConfirmationStatus};
+#[cfg(feature = "spl")]
+use crate::solrpc::{get_minimum_balance_for_rent_exemption, get_token_account_balance};
+#[cfg(feature = "spl")]
+use crate::token::{self, AuthorityType};
+
+// Size of an SPL token account, used to reserve rent for the new associated token accounts
+#[cfg(feature = "spl")]
+const TOKEN_ACCOUNT_LEN: usize = 165;
+const MAX_TRANSACTION_SIZE: usize = 1232;
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[cfg(feature = "spl")]
+#[derive(Debug, Clone)]
+pub struct TokenHandover {
+    pub mint: Pubkey,
+    pub token_program: Pubkey,
+    // Close the old associated token account after emptying it, reclaiming its rent
+    pub close_old_account: bool,
+}
+
+#[cfg(feature = "spl")]
+#[derive(Debug, Clone)]
+pub struct AuthorityHandover {
+    pub account: Pubkey,
+    pub token_program: Pubkey,
+    pub authority_type: AuthorityType,
+}
+
+// Without `spl` only the SOL moves over
+#[derive(Debug, Clone, Default)]
+pub struct RotationConfig {
+    #[cfg(feature = "spl")]
+    pub tokens: Vec<TokenHandover>,
+    #[cfg(feature = "spl")]
+    pub authorities: Vec<AuthorityHandover>,
+}
+
+#[derive(Debug)]
+pub struct RotationReport {
+    pub old_pubkey: Pubkey,
+    pub new_pubkey: Pubkey,
+    pub signature: Signature,
+    pub lamports_moved: u64,
+    pub fee: u64,
+    #[cfg(feature = "spl")]
+    pub tokens_moved: Vec<(Pubkey, u64)>,
+    #[cfg(feature = "spl")]
+    pub authorities_moved: Vec<(Pubkey, AuthorityType)>,
+}
+
+// Rotates the device key: a replacement key is generated and persisted first, then one final
+// transaction signed by the old key moves tokens, authorities and the remaining SOL to it.
+// Only once that transaction is finalized does the replacement overwrite the old key.
+// If a previous rotation was interrupted, its pending key is reused so funds are never split.
+#[cfg_attr(not(feature = "spl"), allow(unused_variables))]
+pub fn rotate_key(
+    keystore: &mut Keystore,
+    old: &Keypair,
+    config: &RotationConfig,
+) -> Result<(Keypair, RotationReport), String> {
+    let new = match keystore.load_pending_rotation().map_err(|e| e.to_string())? {
+        Some(pending) => {
+            warn!("Resuming interrupted key rotation to {}", pending.pubkey());
+            pending
+        }
+        None => {
+            let new = Keypair::new();
+            keystore.store_pending_rotation(&new).map_err(|e| e.to_string())?;
+            new
+        }
+    };
+
+    let old_pubkey = old.pubkey();
+    let new_pubkey = new.pubkey();
+    info!("Rotating device key {} -> {}", old_pubkey, new_pubkey);
+
+    let mut instructions = Vec::new();
+    #[cfg(feature = "spl")]
+    let handover = hand_over_tokens(config, &old_pubkey, &new_pubkey, &mut instructions)?;
+    #[cfg(feature = "spl")]
+    let rent = handover.rent;
+    #[cfg(not(feature = "spl"))]
+    let rent = 0;
+
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    // What is swept and when the old key is overwritten decide over everything it holds, both
+    // reads go through the quorum when the build has one
+    let balance = quorum::get_balance(&old_pubkey)?;
+
+    // The fee doesn't depend on the transfer amount, price the message with a placeholder
+    let mut probe = instructions.clone();
+    probe.push(system_instruction::transfer(&old_pubkey, &new_pubkey, 0));
+    let fee = get_fee_for_message(&Message::new_with_blockhash(&probe, Some(&old_pubkey), &blockhash))
+        .map_err(|e| e.to_string())?;
+
+    let lamports_moved = balance
+        .checked_sub(fee + rent)
+        .ok_or_else(|| format!("Balance {} too low to cover fee {} and rent {}", balance, fee, rent))?;
+    if lamports_moved > 0 {
+        instructions.push(system_instruction::transfer(&old_pubkey, &new_pubkey, lamports_moved));
+    }
+
+    let transaction =
+        Transaction::new_signed_with_payer(&instructions, Some(&old_pubkey), &[old], blockhash);
+
+    let size = bincode::serialized_size(&transaction).map_err(|e| format!("Transaction size: {:?}", e))?;
+    if size as usize > MAX_TRANSACTION_SIZE {
+        return Err(format!("Handover transaction too large ({} bytes), reduce configured accounts", size));
+    }
+
+    let signature = send_transaction(&transaction).map_err(|e| e.to_string())?
+        .parse::<Signature>()
+        .map_err(|e| format!("Signature parse: {:?}", e))?;
+    info!("Handover transaction sent: {}", signature);
+
+    quorum::confirm_transaction(&signature, ConfirmationStatus::Finalized, CONFIRM_TIMEOUT)
+        .map_err(|e| format!("{}, pending key kept for retry", e))?;
+
+    let new = keystore.commit_rotation().map_err(|e| e.to_string())?;
+
+    let report = RotationReport {
+        old_pubkey,
+        new_pubkey,
+        signature,
+        lamports_moved,
+        fee,
+        #[cfg(feature = "spl")]
+        tokens_moved: handover.tokens_moved,
+        #[cfg(feature = "spl")]
+        authorities_moved: handover.authorities_moved,
+    };
+    info!("Key rotation complete: {:?}", report);
+
+    Ok((new, report))
+}
+
+#[cfg(feature = "spl")]
+struct TokenMoves {
+    tokens_moved: Vec<(Pubkey, u64)>,
+    authorities_moved: Vec<(Pubkey, AuthorityType)>,
+    // For the token accounts created on the way
+    rent: u64,
+}
+
+// Adds the instructions moving the configured tokens and authorities from the old key to the new
+// one
+#[cfg(feature = "spl")]
+fn hand_over_tokens(
+    config: &RotationConfig,
+    old_pubkey: &Pubkey,
+    new_pubkey: &Pubkey,
+    instructions: &mut Vec<Instruction>,
+) -> Result<TokenMoves, String> {
+    let mut tokens_moved = Vec::new();
+    let mut created_accounts = 0u64;
+
+    for handover in &config.tokens {
+        let old_ata =
+            token::associated_token_address(old_pubkey, &handover.mint, &handover.token_program);
+        let new_ata =
+            token::associated_token_address(new_pubkey, &handover.mint, &handover.token_program);
+
+        // Only a missing account is skipped, any other RPC error aborts before anything is signed
+        let Some((amount, decimals)) = get_token_account_balance(&old_ata).map_err(|e| e.to_string())? else {
+            info!("No token account for mint {}, skipping", handover.mint);
+            continue;
+        };
+
+        instructions.push(token::create_associated_token_account_idempotent(
+            old_pubkey,
+            new_pubkey,
+            &handover.mint,
+            &handover.token_program,
+        ));
+        created_accounts += 1;
+
+        if amount > 0 {
+            instructions.push(token::transfer_checked(
+                &handover.token_program,
+                &old_ata,
+                &handover.mint,
+                &new_ata,
+                old_pubkey,
+                amount,
+                decimals,
+            ));
+        }
+        tokens_moved.push((handover.mint, amount));
+
+        if handover.close_old_account {
+            instructions.push(token::close_account(
+                &handover.token_program,
+                &old_ata,
+                new_pubkey,
+                old_pubkey,
+            ));
+        }
+    }
+
+    let mut authorities_moved = Vec::new();
+    for handover in &config.authorities {
+        instructions.push(token::set_authority(
+            &handover.token_program,
+            &handover.account,
+            Some(new_pubkey),
+            handover.authority_type,
+            old_pubkey,
+        ));
+        authorities_moved.push((handover.account, handover.authority_type));
+    }
+
+    let rent = if created_accounts > 0 {
+        get_minimum_balance_for_rent_exemption(TOKEN_ACCOUNT_LEN).map_err(|e| e.to_string())? * created_accounts
+    } else {
+        0
+    };
+    Ok(TokenMoves {
+        tokens_moved,
+        authorities_moved,
+        rent,
+    })
+}
@@ -0,0 +1,219 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::fs::fatfs::Fatfs;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_svc::hal::sd::{spi::SdSpiHostDriver, SdCardConfiguration, SdCardDriver};
+use esp_idf_svc::hal::spi::{config::DriverConfig, Dma, SpiDriver, SPI2};
+use esp_idf_svc::io::vfs::MountedFatfs;
+use esp_idf_svc::sys::esp_timer_get_time;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+// Event log on an SD card for reconstructing field incidents: every transaction built, sent and
+// confirmed, every RPC error and every policy decision is appended as one line of JSON,
+//   {"seq":12,"time":1718000000,"uptime_ms":53211,"event":"sent","signature":"..."}
+// with `time` null until SNTP has synced. Lines go to a writer thread, nothing that records one
+// waits for the card, and the files rotate by size so the card never fills up.
+
+const MOUNT_POINT: &str = "/sdcard";
+// 8.3 names, FATFS without long file name support takes nothing longer
+const CURRENT: &str = "events.log";
+// Anything earlier means the clock was never set, as in spend.rs
+const MIN_VALID_UNIX_TIME: u64 = 1_704_067_200;
+// Lines recorded while the card is slow, more are dropped and counted rather than blocking
+const MAX_PENDING: usize = 32;
+const WRITER_STACK_SIZE: usize = 6 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SdLogConfig {
+    // events.log is renamed to events.1 once it grows past this, events.1 to events.2 and so on
+    pub max_file_bytes: u64,
+    // Rotated files kept next to events.log, the oldest is deleted
+    pub rotated_files: u32,
+}
+
+struct Line {
+    // Counts lines since boot, a gap in it is a line dropped
+    seq: u64,
+    event: &'static str,
+    fields: Value,
+    uptime_ms: i64,
+    time: Option<u64>,
+}
+
+static LINES: Mutex<Option<SyncSender<Line>>> = Mutex::new(None);
+static SEQ: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Mounts the card on SPI2 (SCLK GPIO6, MOSI GPIO7, MISO GPIO2, CS GPIO10) and starts the writer
+pub fn start(
+    spi: SPI2,
+    sclk: AnyIOPin,
+    mosi: AnyIOPin,
+    miso: AnyIOPin,
+    cs: AnyIOPin,
+    config: SdLogConfig,
+) -> Result<(), String> {
+    let driver = SpiDriver::new(spi, sclk, mosi, Some(miso), &DriverConfig::new().dma(Dma::Auto(4096)))
+        .map_err(|e| format!("SPI init: {:?}", e))?;
+    let card = SdCardDriver::new_spi(
+        SdSpiHostDriver::new(driver, Some(cs), AnyIOPin::none(), AnyIOPin::none(), AnyIOPin::none(), None)
+            .map_err(|e| format!("SD SPI host: {:?}", e))?,
+        &SdCardConfiguration::new(),
+    )
+    .map_err(|e| format!("No SD card: {:?}", e))?;
+    let fatfs = Fatfs::new_sdcard(0, card).map_err(|e| format!("FAT init: {:?}", e))?;
+    let mounted = MountedFatfs::mount(fatfs, MOUNT_POINT, 2).map_err(|e| format!("SD mount: {:?}", e))?;
+
+    let mut log = EventLog {
+        file: open_current()?,
+        written: fs::metadata(path(CURRENT)).map(|meta| meta.len()).unwrap_or(0),
+        config,
+    };
+    // Marks where each boot starts, and why the one before ended
+    log.append(&Line::new(
+        "boot",
+        json!({ "reset": format!("{:?}", ResetReason::get()), "version": env!("CARGO_PKG_VERSION") }),
+    ))?;
+    log.file.sync_data().map_err(|e| format!("SD sync: {:?}", e))?;
+
+    let (lines, received) = sync_channel(MAX_PENDING);
+    std::thread::Builder::new()
+        .name("sdlog".to_string())
+        .stack_size(WRITER_STACK_SIZE)
+        .spawn(move || {
+            // Unmounting with the thread would leave nothing to write to
+            let _mounted = mounted;
+            run(log, received)
+        })
+        .map_err(|e| format!("SD log thread: {:?}", e))?;
+
+    *LINES.lock().unwrap() = Some(lines);
+    info!("Event log at {}, rotated every {} KiB", path(CURRENT), config.max_file_bytes / 1024);
+    Ok(())
+}
+
+// Appends an event with its fields, an object, to the log. Does nothing without a card.
+pub fn record(event: &'static str, fields: Value) {
+    let Some(lines) = LINES.lock().unwrap().clone() else {
+        return;
+    };
+    if lines.try_send(Line::new(event, fields)).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Line {
+    fn new(event: &'static str, fields: Value) -> Self {
+        Self {
+            seq: SEQ.fetch_add(1, Ordering::Relaxed),
+            event,
+            fields,
+            uptime_ms: uptime_ms(),
+            time: unix_time(),
+        }
+    }
+}
+
+struct EventLog {
+    file: File,
+    written: u64,
+    config: SdLogConfig,
+}
+
+impl EventLog {
+    fn append(&mut self, line: &Line) -> Result<(), String> {
+        let mut entry = json!({
+            "seq": line.seq,
+            "time": line.time,
+            "uptime_ms": line.uptime_ms,
+            "event": line.event,
+        });
+        if let (Some(entry), Value::Object(fields)) = (entry.as_object_mut(), &line.fields) {
+            entry.extend(fields.clone());
+        }
+        let mut text = entry.to_string();
+        text.push('\n');
+
+        if self.written > 0 && self.written + text.len() as u64 > self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(text.as_bytes())
+            .map_err(|e| format!("SD write: {:?}", e))?;
+        self.written += text.len() as u64;
+        Ok(())
+    }
+
+    // Shifts events.log to events.1 and every older file one number up, dropping the last
+    fn rotate(&mut self) -> Result<(), String> {
+        self.file.sync_all().map_err(|e| format!("SD sync: {:?}", e))?;
+        let rotated = |number: u32| path(&format!("events.{}", number));
+        if self.config.rotated_files == 0 {
+            fs::remove_file(path(CURRENT)).map_err(|e| format!("SD remove: {:?}", e))?;
+        } else {
+            // FAT won't rename over an existing file
+            let _ = fs::remove_file(rotated(self.config.rotated_files));
+            for number in (1..self.config.rotated_files).rev() {
+                let _ = fs::rename(rotated(number), rotated(number + 1));
+            }
+            fs::rename(path(CURRENT), rotated(1)).map_err(|e| format!("SD rename: {:?}", e))?;
+        }
+        self.file = open_current()?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn run(mut log: EventLog, lines: Receiver<Line>) {
+    while let Ok(mut line) = lines.recv() {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("SD log too slow, {} events dropped", dropped);
+        }
+        loop {
+            if let Err(e) = log.append(&line) {
+                warn!("{}", e);
+            }
+            // Lines queued meanwhile go out before the sync, one per batch
+            match lines.try_recv() {
+                Ok(next) => line = next,
+                Err(_) => break,
+            }
+        }
+        // On the card before the power can go, not just in the FAT buffers
+        if let Err(e) = log.file.sync_data() {
+            warn!("SD sync: {:?}", e);
+        }
+    }
+}
+
+fn open_current() -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path(CURRENT))
+        .map_err(|e| format!("SD open {}: {:?}", CURRENT, e))
+}
+
+fn path(name: &str) -> String {
+    format!("{}/{}", MOUNT_POINT, name)
+}
+
+fn uptime_ms() -> i64 {
+    (unsafe { esp_timer_get_time() }) / 1000
+}
+
+fn unix_time() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|since_epoch| since_epoch.as_secs())
+        .filter(|&secs| secs >= MIN_VALID_UNIX_TIME)
+}
@@ -0,0 +1,115 @@
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{info, warn};
+use solana_transaction::Transaction;
+
+use crate::buffers;
+use crate::error::RpcError;
+use crate::offline::{self, Delivery, OfflineQueue};
+#[cfg(not(feature = "tpu-direct"))]
+use crate::solrpc;
+use crate::tasks::{self, RPC};
+#[cfg(feature = "tpu-direct")]
+use crate::tpu;
+
+// The rpc task: sends the transactions the application signed, one after the other. The
+// application queues a transaction and goes back to its inputs while the send waits for the
+// link and runs its TLS session here.
+
+// Longest a healthy send takes, waiting for the link included
+const SEND_DEADLINE: Duration = Duration::from_secs(180);
+// How often an idle task reports in to the supervisor
+const IDLE_BEAT: Duration = Duration::from_secs(30);
+
+struct Job {
+    transaction: Transaction,
+    description: String,
+    result: Sender<Result<String, RpcError>>,
+}
+
+static JOBS: Mutex<Option<SyncSender<Job>>> = Mutex::new(None);
+// Where durable-nonce transactions wait when the link is down, see queue_offline
+static OFFLINE: Mutex<Option<OfflineQueue>> = Mutex::new(None);
+
+pub fn start() -> Result<(), String> {
+    let (jobs, received) = sync_channel(buffers::config().send_queue);
+    tasks::spawn(&RPC, move || run(received))?;
+    *JOBS.lock().unwrap() = Some(jobs);
+    Ok(())
+}
+
+// Queues a signed transaction, `description` names it in the log. The signature or the error
+// arrives on the returned receiver, which callers that don't wait for it drop. A transaction
+// waiting in the offline queue counts as sent. Fails right away when the queue is full, and
+// sends on the calling thread when the rpc task isn't up.
+pub fn submit(transaction: Transaction, description: &str) -> Result<Receiver<Result<String, RpcError>>, String> {
+    let (result, outcome) = channel();
+    let job = Job {
+        transaction,
+        description: description.to_string(),
+        result,
+    };
+    let job = match JOBS.lock().unwrap().as_ref() {
+        Some(jobs) => match jobs.try_send(job) {
+            Ok(()) => return Ok(outcome),
+            Err(TrySendError::Full(_)) => return Err(format!("Send queue full, {} dropped", description)),
+            Err(TrySendError::Disconnected(job)) => job,
+        },
+        None => job,
+    };
+    send(job);
+    Ok(outcome)
+}
+
+// From now on a durable-nonce transaction sent with the link down waits in `queue` instead of
+// failing, and so does every one after it until the queue is flushed
+pub fn queue_offline(queue: OfflineQueue) {
+    *OFFLINE.lock().unwrap() = Some(queue);
+}
+
+fn run(jobs: Receiver<Job>) {
+    loop {
+        tasks::beat("rpc", SEND_DEADLINE);
+        match jobs.recv_timeout(IDLE_BEAT) {
+            Ok(job) => {
+                // The deadline counts from the start of the send
+                tasks::beat("rpc", SEND_DEADLINE);
+                send(job);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+fn send(job: Job) {
+    // Straight to the leaders with tpu-direct, through the RPC node otherwise
+    #[cfg(feature = "tpu-direct")]
+    let send_transaction = tpu::send_transaction;
+    #[cfg(not(feature = "tpu-direct"))]
+    let send_transaction = solrpc::send_transaction;
+
+    // A blockhash expires before the link is back, only a durable nonce outlasts the wait
+    let queue = OFFLINE.lock().unwrap().clone();
+    let delivery = match queue.filter(|_| offline::uses_durable_nonce(&job.transaction)) {
+        Some(queue) => queue.send_or_queue(&job.transaction, offline::DEFAULT_TTL, send_transaction),
+        None => send_transaction(&job.transaction).map(Delivery::Sent),
+    };
+    let result = match delivery {
+        Ok(Delivery::Sent(signature)) => {
+            info!("Sent {}: {}", job.description, signature);
+            Ok(signature)
+        }
+        Ok(Delivery::Queued(signature)) => {
+            info!("{} queued until the link is back: {}", job.description, signature);
+            Ok(signature.to_string())
+        }
+        Err(e) => {
+            warn!("{} not sent: {}", job.description, e);
+            Err(e)
+        }
+    };
+    let _ = job.result.send(result);
+}
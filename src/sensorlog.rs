@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::hal::adc::attenuation::DB_11;
+use esp_idf_svc::hal::adc::oneshot::config::AdcChannelConfig;
+use esp_idf_svc::hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
+use esp_idf_svc::hal::adc::ADCPin;
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::i2c::I2cDriver;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{info, warn};
+use serde_json::json;
+use solana_transaction::Transaction;
+
+use crate::memo;
+use crate::signer::TxSigner;
+use crate::solrpc::{get_fee_for_message, get_latest_blockhash, send_transaction};
+use crate::spend::{unix_time, SpendLedger};
+
+// Samples sensors on a schedule and publishes the readings as memos signed by the device key,
+// an append-only log anyone can read back from the chain with no backend in between. Readings
+// are batched into one memo to keep the fees down, and the fees paid are capped per day.
+
+const FEES_NAMESPACE: &str = "sensorfees";
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+// Leaves room in the 1232 byte transaction for the signature, accounts and blockhash
+const MAX_MEMO_LEN: usize = 700;
+// Readings held back by the budget or an outage, the oldest are dropped past this
+const MAX_ROUNDS: usize = 256;
+// After a failed or refused publish, the main loop would retry every few seconds otherwise
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const I2C_TIMEOUT_MS: u64 = 100;
+const SAMPLER_STACK_SIZE: usize = 4 * 1024;
+
+pub trait Sensor: Send {
+    fn name(&self) -> &str;
+    fn read(&mut self) -> Result<f64, String>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SensorLogConfig {
+    pub interval: Duration,
+    // Rounds of readings per memo
+    pub batch: usize,
+    // Publishes a partial batch once its oldest reading is this old
+    pub max_delay: Duration,
+    // Fees spent on memos within any 24 hours
+    pub max_fees_per_day: u64,
+}
+
+impl Default for SensorLogConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5 * 60),
+            batch: 12,
+            max_delay: Duration::from_secs(2 * 60 * 60),
+            max_fees_per_day: 1_000_000,
+        }
+    }
+}
+
+// Millivolts on an ADC pin times `scale`, e.g. 0.002 for volts behind a 1:1 divider
+pub struct AdcSensor<T: ADCPin + 'static> {
+    name: String,
+    channel: AdcChannelDriver<'static, T, Arc<AdcDriver<'static, T::Adc>>>,
+    scale: f64,
+}
+
+impl<T: ADCPin + 'static> AdcSensor<T> {
+    // Sensors on the same ADC unit share its driver
+    pub fn new(name: &str, adc: Arc<AdcDriver<'static, T::Adc>>, pin: T, scale: f64) -> Result<Self, String> {
+        let config = AdcChannelConfig {
+            attenuation: DB_11,
+            ..Default::default()
+        };
+        let channel = AdcChannelDriver::new(adc, pin, &config).map_err(|e| format!("ADC channel: {:?}", e))?;
+        Ok(Self {
+            name: name.to_string(),
+            channel,
+            scale,
+        })
+    }
+}
+
+impl<T: ADCPin + 'static> Sensor for AdcSensor<T> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, String> {
+        let millivolts = self.channel.read().map_err(|e| format!("ADC read: {:?}", e))?;
+        Ok(millivolts as f64 * self.scale)
+    }
+}
+
+// A signed big-endian 16 bit register times `scale`, e.g. 0.00390625 for the temperature of a
+// TMP102 or LM75 in °C
+pub struct I2cSensor {
+    name: String,
+    bus: Arc<Mutex<I2cDriver<'static>>>,
+    address: u8,
+    register: u8,
+    scale: f64,
+}
+
+impl I2cSensor {
+    pub fn new(name: &str, bus: Arc<Mutex<I2cDriver<'static>>>, address: u8, register: u8, scale: f64) -> Self {
+        Self {
+            name: name.to_string(),
+            bus,
+            address,
+            register,
+            scale,
+        }
+    }
+}
+
+impl Sensor for I2cSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, String> {
+        let mut value = [0u8; 2];
+        self.bus
+            .lock()
+            .unwrap()
+            .write_read(self.address, &[self.register], &mut value, TickType::new_millis(I2C_TIMEOUT_MS).into())
+            .map_err(|e| format!("I2C read: {:?}", e))?;
+        Ok(i16::from_be_bytes(value) as f64 * self.scale)
+    }
+}
+
+struct Log {
+    config: SensorLogConfig,
+    names: Vec<String>,
+    // Unix time of each round and its readings, None where a sensor failed
+    rounds: VecDeque<(u64, Vec<Option<f64>>)>,
+    fees: SpendLedger,
+    retry_at: u64,
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+pub fn start(mut sensors: Vec<Box<dyn Sensor>>, config: SensorLogConfig, nvs: EspDefaultNvsPartition) -> Result<(), String> {
+    if sensors.is_empty() {
+        return Err("No sensors configured".to_string());
+    }
+    let fees = SpendLedger::open_in(nvs, FEES_NAMESPACE)?;
+    let names = sensors.iter().map(|sensor| sensor.name().to_string()).collect();
+    *LOG.lock().unwrap() = Some(Log {
+        config,
+        names,
+        rounds: VecDeque::new(),
+        fees,
+        retry_at: 0,
+    });
+
+    std::thread::Builder::new()
+        .name("sensor-log".to_string())
+        .stack_size(SAMPLER_STACK_SIZE)
+        .spawn(move || loop {
+            sample(&mut sensors);
+            std::thread::sleep(config.interval);
+        })
+        .map_err(|e| format!("Sensor sampler: {:?}", e))?;
+    info!("Sensor log sampling every {}s", config.interval.as_secs());
+    Ok(())
+}
+
+fn sample(sensors: &mut [Box<dyn Sensor>]) {
+    // A log entry without a time says nothing, wait for the clock
+    let Some(now) = unix_time() else {
+        warn!("Sensor readings skipped, the clock is not set");
+        return;
+    };
+    let readings = sensors
+        .iter_mut()
+        .map(|sensor| match sensor.read() {
+            // Three decimals keep the memo short, more is noise for these sensors
+            Ok(value) => Some((value * 1000.0).round() / 1000.0),
+            Err(e) => {
+                warn!("Sensor {} failed: {}", sensor.name(), e);
+                None
+            }
+        })
+        .collect();
+
+    let mut log = LOG.lock().unwrap();
+    let Some(log) = log.as_mut() else {
+        return;
+    };
+    if log.rounds.len() == MAX_ROUNDS {
+        log.rounds.pop_front();
+        warn!("Sensor log full, oldest reading dropped");
+    }
+    log.rounds.push_back((now, readings));
+}
+
+// Publishes the next batch once it is complete or old enough, called from the main loop which
+// holds the signer
+pub fn publish_due(signer: &impl TxSigner) {
+    let mut log = LOG.lock().unwrap();
+    let Some(log) = log.as_mut() else {
+        return;
+    };
+    let (Some(now), Some(&(oldest, _))) = (unix_time(), log.rounds.front()) else {
+        return;
+    };
+    if now < log.retry_at || (log.rounds.len() < log.config.batch && now < oldest + log.config.max_delay.as_secs()) {
+        return;
+    }
+
+    // As many rounds as fit the memo, the rest go in the next one
+    let mut count = log.rounds.len().min(log.config.batch);
+    let memo = loop {
+        let entries: Vec<serde_json::Value> = log
+            .rounds
+            .iter()
+            .take(count)
+            .map(|(time, readings)| {
+                let mut entry = vec![json!(time)];
+                entry.extend(readings.iter().map(|reading| json!(reading)));
+                serde_json::Value::Array(entry)
+            })
+            .collect();
+        let memo = json!({ "sensors": log.names, "log": entries }).to_string();
+        if memo.len() <= MAX_MEMO_LEN || count == 1 {
+            break memo;
+        }
+        count -= 1;
+    };
+
+    match publish(signer, &memo, &mut log.fees, log.config.max_fees_per_day, now) {
+        Ok(signature) => {
+            info!("Published {} sensor readings: {}", count, signature);
+            log.rounds.drain(..count);
+        }
+        Err(e) => {
+            warn!("Sensor log not published, retrying in {}s: {}", RETRY_DELAY.as_secs(), e);
+            log.retry_at = now + RETRY_DELAY.as_secs();
+        }
+    }
+}
+
+fn publish(signer: &impl TxSigner, memo: &str, fees: &mut SpendLedger, budget: u64, now: u64) -> Result<String, String> {
+    let device = signer.pubkey();
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    let mut transaction = Transaction::new_with_payer(&[memo::memo(memo, &[&device])], Some(&device));
+    transaction.message.recent_blockhash = blockhash;
+
+    let fee = get_fee_for_message(&transaction.message).map_err(|e| e.to_string())?;
+    let spent = fees.spent_within(DAY, now);
+    if spent.saturating_add(fee) > budget {
+        return Err(format!("daily fee budget reached ({} of {} lamports)", spent, budget));
+    }
+
+    signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+    let signature = send_transaction(&transaction).map_err(|e| e.to_string())?;
+    // Counted as soon as it is sent, whether it lands or not
+    if let Err(e) = fees.record(fee, now) {
+        warn!("Sensor fee not recorded: {}", e);
+    }
+    Ok(signature)
+}
@@ -0,0 +1,67 @@
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use zeroize::{Zeroize, Zeroizing};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const MAX_LINE_LEN: usize = 1024;
+
+// Line reader over the console (stdin), the ESP-IDF VFS console is non-blocking
+// so reads are polled until a full line arrives or the deadline passes.
+// Lines can carry PINs and keyfiles, so they are wiped once the caller drops them.
+pub struct LineReader {
+    pending: Vec<u8>,
+}
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineReader {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::with_capacity(128),
+        }
+    }
+
+    pub fn read_line(&mut self, timeout: Duration) -> Option<Zeroizing<String>> {
+        let deadline = Instant::now() + timeout;
+        let mut stdin = std::io::stdin();
+        let mut byte = [0u8; 1];
+
+        while Instant::now() < deadline {
+            match stdin.read(&mut byte) {
+                Ok(1) => match byte[0] {
+                    b'\n' | b'\r' => {
+                        if self.pending.is_empty() {
+                            continue;
+                        }
+                        let line = Zeroizing::new(String::from_utf8_lossy(&self.pending).trim().to_string());
+                        self.pending.zeroize();
+                        return Some(line);
+                    }
+                    b => {
+                        if self.pending.len() < MAX_LINE_LEN {
+                            self.pending.push(b);
+                        }
+                    }
+                },
+                Ok(_) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                    std::thread::sleep(POLL_INTERVAL)
+                }
+                Err(_) => std::thread::sleep(POLL_INTERVAL),
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for LineReader {
+    fn drop(&mut self) {
+        self.pending.zeroize();
+    }
+}
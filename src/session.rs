@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use solana_keypair::{Keypair, Signer};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::{Hash, Message, Signature, Transaction};
+use zeroize::Zeroizing;
+
+use crate::error::WalletError;
+use crate::spend::trusted_time;
+
+// Domain separator, changing it changes every derived session key
+const DERIVATION_CONTEXT: &[u8] = b"REsp32Sol session key v1";
+const MAX_PURPOSE_LEN: usize = 32;
+// A key meant for one login or delegation shouldn't outlive a month
+const MAX_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// Short-lived key for one purpose (a SIWS login, a delegate authority, ...), so the long-term
+// device key only ever signs payments. The same purpose derives the same key until the
+// lifetime period rolls over, letting a backend register it once per period.
+pub struct SessionKey {
+    purpose: String,
+    expires_at: u64,
+    keypair: Keypair,
+}
+
+impl SessionKey {
+    // Derives the key as HMAC-SHA256(master seed, context | purpose | period index)
+    pub fn derive(master: &Keypair, purpose: &str, lifetime: Duration, now: u64) -> Result<Self, WalletError> {
+        if purpose.is_empty() || purpose.len() > MAX_PURPOSE_LEN {
+            return Err(WalletError::Invalid(format!("Session purpose must be 1-{} characters", MAX_PURPOSE_LEN)));
+        }
+        if lifetime > MAX_LIFETIME {
+            return Err(WalletError::Invalid(format!(
+                "Session lifetime must be at most {} days",
+                MAX_LIFETIME.as_secs() / (24 * 60 * 60)
+            )));
+        }
+        let lifetime = lifetime.as_secs();
+        if lifetime == 0 {
+            return Err(WalletError::Invalid("Session lifetime must be at least one second".to_string()));
+        }
+
+        let period = now / lifetime;
+        let expires_at = period
+            .checked_add(1)
+            .and_then(|next| next.checked_mul(lifetime))
+            .ok_or_else(|| WalletError::Invalid(format!("Session expiry past the clock's range at {}", now)))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(master.secret_bytes())
+            .map_err(|e| WalletError::Backend(format!("Session key derivation: {:?}", e)))?;
+        mac.update(DERIVATION_CONTEXT);
+        mac.update(&[purpose.len() as u8]);
+        mac.update(purpose.as_bytes());
+        mac.update(&period.to_le_bytes());
+        let seed: Zeroizing<[u8; 32]> = Zeroizing::new(mac.finalize().into_bytes().into());
+
+        Ok(Self {
+            purpose: purpose.to_string(),
+            expires_at,
+            keypair: Keypair::new_from_array(*seed),
+        })
+    }
+
+    pub fn purpose(&self) -> &str {
+        &self.purpose
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        self.keypair.pubkey()
+    }
+
+    // Unix time after which the key refuses to sign
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    pub fn sign_message(&self, message: &[u8], now: u64) -> Result<Signature, WalletError> {
+        self.check_expiry(now)?;
+        Ok(self.keypair.sign_message(message))
+    }
+
+    pub fn sign_transaction(
+        &self,
+        transaction: &mut Transaction,
+        blockhash: Hash,
+        now: u64,
+    ) -> Result<(), WalletError> {
+        self.check_expiry(now)?;
+        transaction
+            .try_sign(&[&self.keypair], blockhash)
+            .map_err(|e| WalletError::Backend(format!("Sign: {:?}", e)))
+    }
+
+    fn check_expiry(&self, now: u64) -> Result<(), WalletError> {
+        if self.is_expired(now) {
+            return Err(WalletError::Expired(self.purpose.clone()));
+        }
+        Ok(())
+    }
+}
+
+// Expiry is checked against the synced clock, an unsynced clock refuses to sign
+impl crate::signer::TxSigner for SessionKey {
+    fn pubkey(&self) -> Pubkey {
+        SessionKey::pubkey(self)
+    }
+
+    fn sign_message(&self, message: &Message) -> Result<Signature, WalletError> {
+        let now = trusted_time().ok_or(WalletError::ClockNotSynced)?;
+        self.check_expiry(now)?;
+        Ok(Signer::sign_message(&self.keypair, &message.serialize()))
+    }
+}
@@ -0,0 +1,286 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine as _};
+#[cfg(feature = "sd-log")]
+use serde_json::json;
+use solana_keypair::{Keypair, Signer};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::{Hash, Message, Signature, Transaction};
+
+use crate::ed25519::SigningBackend;
+use crate::error::WalletError;
+#[cfg(feature = "sd-log")]
+use crate::inspect::invoked_programs;
+use crate::pin::PinGate;
+#[cfg(feature = "sd-log")]
+use crate::sdlog;
+use crate::session::SessionKey;
+use crate::spend::trusted_time;
+use crate::tamper;
+
+// First byte of every off-chain payload the device key signs. No Solana message can start
+// with it (it would be an invalid versioned message), so such a signature never authorizes a transaction.
+pub const OFFCHAIN_PREFIX: u8 = 0xff;
+
+// Anything that can sign for one key: the device key behind its gates, a bare Keypair, a keystore
+// purpose key, a session key or a key held by another device. Transaction builders take
+// `&impl TxSigner`, so a secure element driver only has to implement these two to plug in.
+pub trait TxSigner {
+    fn pubkey(&self) -> Pubkey;
+
+    fn sign_message(&self, message: &Message) -> Result<Signature, WalletError>;
+
+    // Adds this key's signature to the transaction, signatures of other signers are kept
+    // unless the blockhash changes, which invalidates them
+    fn sign_transaction(&self, transaction: &mut Transaction, blockhash: Hash) -> Result<(), WalletError> {
+        if transaction.message.recent_blockhash != blockhash {
+            transaction.message.recent_blockhash = blockhash;
+            transaction.signatures.iter_mut().for_each(|signature| *signature = Signature::default());
+        }
+
+        let signature = self.sign_message(&transaction.message)?;
+
+        let pubkey = self.pubkey();
+        let num_signers = transaction.message.header.num_required_signatures as usize;
+        let position = transaction
+            .message
+            .account_keys
+            .get(..num_signers)
+            .ok_or(WalletError::MalformedMessage)?
+            .iter()
+            .position(|key| *key == pubkey)
+            .ok_or(WalletError::NotASigner(pubkey))?;
+        transaction.signatures.resize(num_signers, Signature::default());
+        transaction.signatures[position] = signature;
+
+        Ok(())
+    }
+}
+
+// A key in RAM with no gates, for test keys and the session and purpose keys built on it
+impl TxSigner for Keypair {
+    fn pubkey(&self) -> Pubkey {
+        Signer::pubkey(self)
+    }
+
+    fn sign_message(&self, message: &Message) -> Result<Signature, WalletError> {
+        Ok(Signer::sign_message(self, &message.serialize()))
+    }
+}
+
+// Consulted before every signature, returning an error refuses to sign
+pub trait SigningHook: Send {
+    fn check(&self, message: &Message, signer: &Pubkey) -> Result<(), WalletError>;
+
+    // Called once the message has been signed, e.g. to account for spending
+    fn signed(&self, _message: &Message, _signer: &Pubkey) {}
+
+    // Called instead of `signed` after this hook allowed the message but a later gate or the
+    // signing itself failed, e.g. to give back a reserved spend
+    fn aborted(&self, _message: &Message, _signer: &Pubkey) {}
+}
+
+// Owns the device key and refuses to sign unless every configured gate allows it
+pub struct DeviceSigner {
+    keypair: Keypair,
+    backend: SigningBackend,
+    // The error when the PIN state couldn't be read, which refuses every signature
+    pin: Result<PinGate, WalletError>,
+    hooks: Vec<Box<dyn SigningHook>>,
+}
+
+impl DeviceSigner {
+    pub fn new(keypair: Keypair, pin: Result<PinGate, WalletError>) -> Self {
+        Self {
+            keypair,
+            backend: SigningBackend::default(),
+            pin,
+            hooks: Vec::new(),
+        }
+    }
+
+    pub fn set_backend(&mut self, backend: SigningBackend) {
+        self.backend = backend;
+    }
+
+    pub fn add_hook(&mut self, hook: impl SigningHook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        Signer::pubkey(&self.keypair)
+    }
+
+    // Whether signing is refused until the PIN is entered, or for good after tamper detection
+    pub fn is_locked(&self) -> bool {
+        self.check_pin().is_err()
+    }
+
+    pub fn pin_gate(&mut self) -> Option<&mut PinGate> {
+        self.pin.as_mut().ok()
+    }
+
+    // Derives a session key for one purpose from the device key, needs the same PIN unlock as signing
+    pub fn session_key(&self, purpose: &str, lifetime: Duration) -> Result<SessionKey, WalletError> {
+        self.check_pin()?;
+        let now = trusted_time().ok_or(WalletError::ClockNotSynced)?;
+
+        SessionKey::derive(&self.keypair, purpose, lifetime, now)
+    }
+
+    // Adds the device signature to the transaction, signatures of other signers are kept
+    // unless the blockhash changes, which invalidates them
+    pub fn sign_transaction(&self, transaction: &mut Transaction, blockhash: Hash) -> Result<(), WalletError> {
+        TxSigner::sign_transaction(self, transaction, blockhash)
+    }
+
+    // Signs a message built elsewhere (e.g. by a host in remote-signer mode)
+    pub fn sign_message(&self, message: &Message) -> Result<Signature, WalletError> {
+        let pubkey = Signer::pubkey(&self.keypair);
+        let signers = message
+            .account_keys
+            .get(..message.header.num_required_signatures as usize)
+            .ok_or(WalletError::MalformedMessage)?;
+        if !signers.contains(&pubkey) {
+            return Err(WalletError::NotASigner(pubkey));
+        }
+
+        self.check_gates(message)?;
+
+        let signature = match self.backend.sign(&self.keypair, &message.serialize()) {
+            Ok(signature) => signature,
+            Err(e) => {
+                self.abort(message, self.hooks.len());
+                return Err(WalletError::Backend(e));
+            }
+        };
+
+        let signer = Signer::pubkey(&self.keypair);
+        for hook in &self.hooks {
+            hook.signed(message, &signer);
+        }
+        #[cfg(feature = "sd-log")]
+        sdlog::record(
+            "signed",
+            json!({
+                "signature": signature.to_string(),
+                "fee_payer": message.account_keys.first().map(|payer| payer.to_string()),
+                "blockhash": message.recent_blockhash.to_string(),
+                "programs": invoked_programs(message).iter().map(|program| program.to_string()).collect::<Vec<_>>(),
+            }),
+        );
+
+        Ok(signature)
+    }
+
+    // Signs framed off-chain data such as telemetry. It needs the same PIN unlock as
+    // transactions, the hooks don't apply as nothing is being paid.
+    pub fn sign_offchain(&self, data: &[u8]) -> Result<Signature, WalletError> {
+        if data.first() != Some(&OFFCHAIN_PREFIX) {
+            return Err(WalletError::Refused(format!("Off-chain data must start with {:#04x}", OFFCHAIN_PREFIX)));
+        }
+        self.check_pin()?;
+
+        self.backend.sign(&self.keypair, data).map_err(WalletError::Backend)
+    }
+
+    fn check_gates(&self, message: &Message) -> Result<(), WalletError> {
+        self.check_pin()?;
+
+        let signer = Signer::pubkey(&self.keypair);
+        for (checked, hook) in self.hooks.iter().enumerate() {
+            if let Err(e) = hook.check(message, &signer) {
+                self.abort(message, checked);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tells the first `checked` hooks, which allowed the message, that it won't be signed
+    fn abort(&self, message: &Message, checked: usize) {
+        let signer = Signer::pubkey(&self.keypair);
+        for hook in &self.hooks[..checked] {
+            hook.aborted(message, &signer);
+        }
+    }
+
+    fn check_pin(&self) -> Result<(), WalletError> {
+        // Tamper lockdown overrides everything, the PIN can't lift it
+        if tamper::locked_down() {
+            return Err(WalletError::LockedDown);
+        }
+        let pin = self
+            .pin
+            .as_ref()
+            .map_err(|e| WalletError::Refused(format!("PIN gate unavailable: {}", e)))?;
+        match pin.is_configured()? {
+            true if !pin.is_unlocked() => Err(WalletError::PinRequired),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TxSigner for DeviceSigner {
+    fn pubkey(&self) -> Pubkey {
+        DeviceSigner::pubkey(self)
+    }
+
+    fn sign_message(&self, message: &Message) -> Result<Signature, WalletError> {
+        DeviceSigner::sign_message(self, message)
+    }
+}
+
+// The key of another device built with `remote-signer`, reached over a serial link, BLE or
+// anything else that carries lines. `exchange` sends one request line and returns the answer.
+// The gates (PIN, policies, approval) are those of the device holding the key.
+pub struct RemoteSigner<F> {
+    pubkey: Pubkey,
+    exchange: Mutex<F>,
+}
+
+impl<F: FnMut(&str) -> Result<String, String>> RemoteSigner<F> {
+    // Asks the remote device for its key, which also checks that it answers
+    pub fn connect(mut exchange: F) -> Result<Self, WalletError> {
+        let pubkey = request(&mut exchange, "PUBKEY")?
+            .parse()
+            .map_err(|e| WalletError::Remote(format!("pubkey {:?}", e)))?;
+
+        Ok(Self {
+            pubkey,
+            exchange: Mutex::new(exchange),
+        })
+    }
+
+    pub fn unlock(&self, pin: &str) -> Result<(), WalletError> {
+        let mut exchange = self.exchange.lock().unwrap();
+        request(&mut *exchange, &format!("UNLOCK {}", pin)).map(|_| ())
+    }
+}
+
+impl<F: FnMut(&str) -> Result<String, String>> TxSigner for RemoteSigner<F> {
+    fn pubkey(&self) -> Pubkey {
+        self.pubkey
+    }
+
+    fn sign_message(&self, message: &Message) -> Result<Signature, WalletError> {
+        let line = format!("SIGN {}", general_purpose::STANDARD.encode(message.serialize()));
+        let mut exchange = self.exchange.lock().unwrap();
+        request(&mut *exchange, &line)?
+            .parse()
+            .map_err(|e| WalletError::Remote(format!("signature {:?}", e)))
+    }
+}
+
+// One request of the remote-signer protocol, answered with OK <result> or ERR <reason>
+fn request(exchange: &mut impl FnMut(&str) -> Result<String, String>, line: &str) -> Result<String, WalletError> {
+    let response = exchange(line).map_err(WalletError::Backend)?;
+    let response = response.trim();
+    match response.split_once(' ').unwrap_or((response, "")) {
+        ("OK", result) => Ok(result.to_string()),
+        ("ERR", reason) => Err(WalletError::Remote(reason.to_string())),
+        _ => Err(WalletError::Remote(format!("unexpected answer '{}'", response))),
+    }
+}
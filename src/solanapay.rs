@@ -0,0 +1,323 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::pubkey::Pubkey;
+use solana_system_interface::instruction as system_instruction;
+
+use crate::amount::{parse_amount, split_amount, Sol, SOL_DECIMALS};
+use crate::memo;
+use crate::solrpc::{get_account_info, get_signatures_for_address};
+#[cfg(feature = "spl")]
+use crate::solrpc::get_token_account_balance;
+#[cfg(feature = "spl")]
+use crate::token;
+use crate::token::{TOKEN_2022_PROGRAM_ID, TOKEN_PROGRAM_ID};
+
+// Solana Pay transfer requests, `solana:<recipient>?amount=<decimal>&spl-token=<mint>&reference=
+// <pubkey>&label=..&message=..&memo=..` as specified at https://docs.solanapay.com/spec.
+// Transaction requests (`solana:https://..`) need an HTTPS round trip to the merchant and are
+// not supported.
+
+const SCHEME: &str = "solana:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferRequest {
+    pub recipient: Pubkey,
+    // Decimal in SOL or in the token's units, as written in the URL
+    pub amount: Option<String>,
+    pub spl_token: Option<Pubkey>,
+    // Added to the transfer as read-only accounts, so the payment can be found by them
+    pub references: Vec<Pubkey>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    pub memo: Option<String>,
+}
+
+impl TransferRequest {
+    pub fn parse(uri: &str) -> Result<Self, String> {
+        let rest = uri
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| format!("Not a Solana Pay URL: {}", uri))?;
+        if rest.starts_with("https:") || rest.starts_with("https%3A") {
+            return Err("Solana Pay transaction requests are not supported".to_string());
+        }
+        let (recipient, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let recipient = Pubkey::from_str(recipient).map_err(|e| format!("Recipient parse: {:?}", e))?;
+
+        let mut request = Self {
+            recipient,
+            amount: None,
+            spl_token: None,
+            references: Vec::new(),
+            label: None,
+            message: None,
+            memo: None,
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "amount" => {
+                    // Checked here so a malformed amount fails before anything is looked up
+                    split_amount(&value)?;
+                    request.amount = Some(value);
+                }
+                "spl-token" => {
+                    request.spl_token = Some(Pubkey::from_str(&value).map_err(|e| format!("Token mint parse: {:?}", e))?)
+                }
+                "reference" => request
+                    .references
+                    .push(Pubkey::from_str(&value).map_err(|e| format!("Reference parse: {:?}", e))?),
+                "label" => request.label = Some(value),
+                "message" => request.message = Some(value),
+                "memo" => request.memo = Some(value),
+                // Unknown parameters are for other wallets to understand
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+
+    // The amount in lamports for SOL requests, None when the payer chooses it
+    pub fn lamports(&self) -> Result<Option<u64>, String> {
+        if self.spl_token.is_some() {
+            return Err("Not a SOL transfer".to_string());
+        }
+        self.amount.as_deref().map(|amount| parse_amount(amount, SOL_DECIMALS)).transpose()
+    }
+
+    // The instructions paying the request from `payer`. SOL is paid `lamports`, which the caller
+    // settles from lamports() and its limits, tokens the amount in the URL. Token payments look
+    // the mint up and need the recipient's token account to exist, and `spl`.
+    pub fn instructions(&self, payer: &Pubkey, lamports: u64) -> Result<Vec<Instruction>, String> {
+        let mut instructions = Vec::new();
+        // The memo goes right before the transfer, as the spec asks
+        if let Some(text) = &self.memo {
+            instructions.push(memo::memo(text, &[payer]));
+        }
+        let mut transfer = match self.spl_token {
+            None => system_instruction::transfer(payer, &self.recipient, lamports),
+            #[cfg(not(feature = "spl"))]
+            Some(_) => return Err("Token payments need the `spl` feature".to_string()),
+            #[cfg(feature = "spl")]
+            Some(mint) => {
+                let amount = self.amount.as_deref().ok_or("Token request without an amount")?;
+                let (token_program, decimals) = mint_info(&mint)?;
+                let amount = parse_amount(amount, decimals)?;
+                let source = token::associated_token_address(payer, &mint, &token_program);
+                let destination = token::associated_token_address(&self.recipient, &mint, &token_program);
+                if get_token_account_balance(&destination).map_err(|e| e.to_string())?.is_none() {
+                    return Err(format!("{} has no account for the token {}", self.recipient, mint));
+                }
+                token::transfer_checked(&token_program, &source, &mint, &destination, payer, amount, decimals)
+            }
+        };
+        transfer
+            .accounts
+            .extend(self.references.iter().map(|reference| AccountMeta::new_readonly(*reference, false)));
+        instructions.push(transfer);
+        Ok(instructions)
+    }
+
+    // The URL for a wallet to scan, the reverse of parse()
+    pub fn to_url(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(amount) = &self.amount {
+            params.push(format!("amount={}", amount));
+        }
+        if let Some(mint) = &self.spl_token {
+            params.push(format!("spl-token={}", mint));
+        }
+        params.extend(self.references.iter().map(|reference| format!("reference={}", reference)));
+        for (key, value) in [("label", &self.label), ("message", &self.message), ("memo", &self.memo)] {
+            if let Some(value) = value {
+                params.push(format!("{}={}", key, percent_encode(value)));
+            }
+        }
+        match params.is_empty() {
+            true => format!("{}{}", SCHEME, self.recipient),
+            false => format!("{}{}?{}", SCHEME, self.recipient, params.join("&")),
+        }
+    }
+
+    // Checks a transaction from getTransaction pays the request in full. Anyone can put the
+    // reference into a transaction, so finding one by it proves nothing on its own.
+    pub fn validate(&self, transaction: &Value) -> Result<(), String> {
+        let meta = &transaction["meta"];
+        if !meta["err"].is_null() {
+            return Err(format!("Transaction failed: {}", meta["err"]));
+        }
+        let recipient = self.recipient.to_string();
+        let (received, decimals) = match self.spl_token {
+            None => {
+                let index = transaction["transaction"]["message"]["accountKeys"]
+                    .as_array()
+                    .ok_or("No account keys in transaction")?
+                    .iter()
+                    .position(|key| key["pubkey"].as_str() == Some(&recipient))
+                    .ok_or("Transaction doesn't touch the recipient")?;
+                let balance = |balances: &Value| balances[index].as_u64().ok_or("Balance missing in transaction");
+                let received = balance(&meta["postBalances"])?.saturating_sub(balance(&meta["preBalances"])?);
+                (received, SOL_DECIMALS)
+            }
+            Some(mint) => {
+                let mint = mint.to_string();
+                let (pre, _) = token_balance(&meta["preTokenBalances"], &recipient, &mint)?;
+                let (post, decimals) = token_balance(&meta["postTokenBalances"], &recipient, &mint)?;
+                (post.saturating_sub(pre), decimals.ok_or("Transaction pays no tokens of the mint")?)
+            }
+        };
+        // Without an amount the payer chose one, anything counts
+        let expected = match self.amount.as_deref() {
+            Some(amount) => parse_amount(amount, decimals)?,
+            None => 1,
+        };
+        match received >= expected {
+            true => Ok(()),
+            false => Err(format!("Recipient received {} of {} base units", received, expected)),
+        }
+    }
+}
+
+// What an NFC tag or a scanned QR code holds: an address, or a request to pay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentTarget {
+    Address(Pubkey),
+    Request(TransferRequest),
+}
+
+impl PaymentTarget {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let content = content.trim();
+        if content.starts_with("solana:") {
+            return TransferRequest::parse(content).map(PaymentTarget::Request);
+        }
+        Pubkey::from_str(content)
+            .map(PaymentTarget::Address)
+            .map_err(|_| format!("Neither an address nor a Solana Pay URL: {}", content))
+    }
+
+    pub fn address(&self) -> &Pubkey {
+        match self {
+            PaymentTarget::Address(address) => address,
+            PaymentTarget::Request(request) => &request.recipient,
+        }
+    }
+
+    // The instructions paying the target from `payer` and a description of the payment. An
+    // address is paid `lamports`, a request what it asks for up to `max_lamports`.
+    pub fn payment(&self, payer: &Pubkey, lamports: u64, max_lamports: u64) -> Result<(String, Vec<Instruction>), String> {
+        let request = match self {
+            PaymentTarget::Address(recipient) => TransferRequest {
+                recipient: *recipient,
+                amount: None,
+                spl_token: None,
+                references: Vec::new(),
+                label: None,
+                message: None,
+                memo: None,
+            },
+            PaymentTarget::Request(request) => request.clone(),
+        };
+        // A request with a reference is paid once, however often its code is read
+        for reference in &request.references {
+            if is_paid(reference)? {
+                return Err(format!("the request with reference {} is paid already", reference));
+            }
+        }
+
+        match request.spl_token {
+            Some(mint) => {
+                let amount = request.amount.as_deref().ok_or("token request without an amount")?;
+                let description = format!("{} of {} to {}", amount, mint, request.recipient);
+                Ok((description, request.instructions(payer, 0)?))
+            }
+            None => {
+                let amount = request.lamports()?.unwrap_or(lamports);
+                if amount == 0 {
+                    return Err("no amount given".to_string());
+                }
+                if amount > max_lamports {
+                    return Err(format!("{} asked, over the {} allowed per payment", Sol(amount), Sol(max_lamports)));
+                }
+                let description = format!("{} to {}", Sol(amount), request.recipient);
+                Ok((description, request.instructions(payer, amount)?))
+            }
+        }
+    }
+}
+
+// The recipient's balance of a mint across its token accounts, and the mint's decimals if one
+// of them is listed
+fn token_balance(balances: &Value, owner: &str, mint: &str) -> Result<(u64, Option<u8>), String> {
+    let mut total = 0u64;
+    let mut decimals = None;
+    let balances = balances.as_array().ok_or("No token balances in transaction")?;
+    for balance in balances.iter().filter(|balance| balance["owner"] == owner && balance["mint"] == mint) {
+        let amount = &balance["uiTokenAmount"];
+        total += amount["amount"]
+            .as_str()
+            .and_then(|amount| amount.parse::<u64>().ok())
+            .ok_or("Token balance missing in transaction")?;
+        decimals = amount["decimals"].as_u64().map(|decimals| decimals as u8);
+    }
+    Ok((total, decimals))
+}
+
+// Whether a payment carrying the reference has landed, so a request is only paid once
+pub fn is_paid(reference: &Pubkey) -> Result<bool, String> {
+    Ok(!get_signatures_for_address(reference, 1).map_err(|e| e.to_string())?.is_empty())
+}
+
+// The token program owning the mint and the mint's decimals
+pub fn mint_info(mint: &Pubkey) -> Result<(Pubkey, u8), String> {
+    let account = get_account_info(mint)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Token mint {} not found", mint))?;
+    if account.owner != TOKEN_PROGRAM_ID && account.owner != TOKEN_2022_PROGRAM_ID {
+        return Err(format!("{} is not a token mint", mint));
+    }
+    // Mint layout: authority option (36), supply (8), decimals
+    let decimals = *account.data.get(44).ok_or("Token mint account too short")?;
+    Ok((account.owner, decimals))
+}
+
+// Everything but the unreserved characters of RFC 3986 is escaped
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("Invalid percent-encoding in {}", value))?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|e| format!("UTF-8: {:?}", e))
+}
@@ -1,173 +1,766 @@
-use std::str::FromStr;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use base64::{Engine as _, engine::general_purpose};
 
+#[cfg(feature = "sd-log")]
 use serde_json::json;
 
-use embedded_svc::http::client::Client;
-use embedded_svc::http::Headers;
-use esp_idf_svc::http::{
-    client::{Configuration, EspHttpConnection},
-    Method,
-};
-use solana_transaction::{Hash, Transaction};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::{Hash, Message, Signature, Transaction};
 
-const RPC_URL: &str = "https://api.devnet.solana.com";
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, Sound};
+#[cfg(feature = "oled-display")]
+use crate::display;
+use crate::buffers::{self, BufferConfig};
+use crate::client;
+use crate::cluster::Cluster;
+use crate::config;
+use crate::error::RpcError;
+#[cfg(feature = "status-led")]
+use crate::led::{self, LedState};
+#[cfg(feature = "receipt-printer")]
+use crate::printer;
+#[cfg(feature = "sd-log")]
+use crate::leanjson;
+use crate::lifecycle::{self, WalletEvent};
+#[cfg(feature = "low-power")]
+use crate::lowpower;
+use crate::psram::{self, PsramVec};
+#[cfg(feature = "sd-log")]
+use crate::sdlog;
+#[cfg(feature = "lora-bridge")]
+use crate::lora;
+#[cfg(feature = "espnow-relay")]
+use crate::relay;
+use crate::tls_pin::CertPin;
+use crate::transport::{EspTransport, RpcTransport};
+// The methods, payloads and response parsing, shared with targets without std
+pub use resp32sol_core::rpc::*;
+
+// Chosen at build time by `cluster` or, in debug builds, `rpc_url` in cfg.toml, devnet by default
+const RPC_URL: &str = env!("RESP32SOL_RPC_URL");
+
+// Built with RpcClient::builder(), or as a struct with `..Default::default()` for the rest
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    pub url: String,
+    pub timeout: Duration,
+    // When non-empty the server chain must match one of these pins on top of the CA bundle
+    pub pins: Vec<CertPin>,
+    // Replaces the commitment of the methods that send one, each method's own otherwise
+    pub commitment: Option<ConfirmationStatus>,
+    // Further attempts after a network error, a 429 or a 5xx. Streamed calls aren't repeated,
+    // their caller has seen part of the response already.
+    pub retries: u32,
+    // Calls per second at most, over every call with a limit set. Public endpoints answer
+    // bursts with 429s.
+    pub rate_limit: Option<u32>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            url: RPC_URL.to_string(),
+            timeout: Duration::from_secs(30),
+            pins: Vec::new(),
+            commitment: None,
+            retries: 0,
+            rate_limit: None,
+        }
+    }
+}
+
+// A client for one endpoint, assembled knob by knob. Knobs left out keep RpcConfig's defaults,
+// so adding one never breaks a caller:
+// `RpcClient::builder().url(url).commitment(ConfirmationStatus::Confirmed).retries(2).build()`
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    config: RpcConfig,
+}
+
+impl RpcClient {
+    pub fn builder() -> RpcClientBuilder {
+        RpcClientBuilder {
+            config: RpcConfig::default(),
+        }
+    }
+
+    // Starts from the endpoint in use, to change some of its knobs and keep the rest
+    pub fn reconfigure() -> RpcClientBuilder {
+        RpcClientBuilder { config: rpc_config() }
+    }
+
+    pub fn config(&self) -> &RpcConfig {
+        &self.config
+    }
+
+    // Makes this the endpoint sol_rpc_call and the get_*/send_* functions use
+    pub fn install(self) {
+        set_rpc_config(self.config);
+    }
+}
 
-#[allow(unused)]
 #[derive(Debug, Clone)]
-pub enum SolanaRpcMethod {
-    GetLatestBlockhash,
-    GetBalance(String),
-    GetTransaction(String),
-    GetAccountInfo(String),
-    GetProgramAccounts(String),
-    GetRecentBlockhash,
-    GetSlot,
-    GetVersion,
-    SendTransaction(String),
-}
-
-pub fn get_latest_blockhash() -> Result<Hash, String> {
-    let result = sol_rpc_call(SolanaRpcMethod::GetLatestBlockhash)?;
-
-    let blockhash_str = result["value"]["blockhash"]
+pub struct RpcClientBuilder {
+    config: RpcConfig,
+}
+
+impl RpcClientBuilder {
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.config.url = url.into();
+        self
+    }
+
+    pub fn cluster(self, cluster: &Cluster) -> Self {
+        self.url(cluster.rpc_url())
+    }
+
+    pub fn commitment(mut self, commitment: ConfirmationStatus) -> Self {
+        self.config.commitment = Some(commitment);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.config.retries = retries;
+        self
+    }
+
+    pub fn rate_limit(mut self, calls_per_second: u32) -> Self {
+        self.config.rate_limit = Some(calls_per_second).filter(|calls| *calls > 0);
+        self
+    }
+
+    pub fn pin(mut self, pin: CertPin) -> Self {
+        self.config.pins.push(pin);
+        self
+    }
+
+    pub fn build(self) -> RpcClient {
+        RpcClient { config: self.config }
+    }
+}
+
+static RPC_CONFIG: Mutex<Option<RpcConfig>> = Mutex::new(None);
+// Set by set_transport, ESP-IDF's HTTP client otherwise
+static TRANSPORT: Mutex<Option<Arc<dyn RpcTransport<Error = RpcError> + Send + Sync>>> = Mutex::new(None);
+
+pub const CONFIRM_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Between attempts of a call with retries, doubled for each one
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// When the next rate limited call may go out
+static NEXT_CALL: Mutex<Option<Instant>> = Mutex::new(None);
+
+// Request and response bodies of RPC calls, kept from one call to the next so a long-running
+// device doesn't allocate and free them for every call and fragment the heap doing so
+#[derive(Default)]
+pub struct RpcBuffers {
+    request: String,
+    response: Vec<u8>,
+    // Takes over from `response` once a body outgrows the JSON scratch size, when there is PSRAM
+    external: PsramVec,
+}
+
+impl RpcBuffers {
+    pub const fn new() -> Self {
+        Self {
+            request: String::new(),
+            response: Vec::new(),
+            external: PsramVec::new(),
+        }
+    }
+
+    // Reserved up front, e.g. at boot while the heap is still in one piece
+    pub fn with_capacity(request: usize, response: usize) -> Self {
+        Self {
+            request: String::with_capacity(request),
+            response: Vec::with_capacity(response),
+            external: PsramVec::new(),
+        }
+    }
+
+    // PSRAM is plentiful and nothing else competes for it, the external buffer keeps its size
+    fn trim(&mut self, json_scratch: usize) {
+        self.request.shrink_to(json_scratch);
+        self.response.shrink_to(json_scratch);
+    }
+}
+
+// A response body on its way in, moved to PSRAM once it grows large
+struct Body<'a> {
+    internal: &'a mut Vec<u8>,
+    external: &'a mut PsramVec,
+    spilled: bool,
+    sizes: BufferConfig,
+}
+
+impl Body<'_> {
+    fn push(&mut self, data: &[u8]) -> Result<(), RpcError> {
+        if !self.spilled {
+            let len = self.internal.len() + data.len();
+            if len <= self.sizes.json_scratch || !psram::available() {
+                if len > self.sizes.max_response {
+                    return Err(RpcError::TooLarge(self.sizes.max_response));
+                }
+                self.internal.extend_from_slice(data);
+                return Ok(());
+            }
+            self.external.clear();
+            self.external
+                .extend_from_slice(self.internal)
+                .map_err(|_| RpcError::TooLarge(len))?;
+            self.internal.clear();
+            self.spilled = true;
+        }
+        let len = self.external.len() + data.len();
+        if len > self.sizes.max_external_response {
+            return Err(RpcError::TooLarge(self.sizes.max_external_response));
+        }
+        self.external.extend_from_slice(data).map_err(|_| RpcError::TooLarge(len))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        match self.spilled {
+            true => self.external.as_slice(),
+            false => self.internal,
+        }
+    }
+}
+
+thread_local! {
+    // rpc_call's, one set for every thread making calls so none is locked over a call
+    static BUFFERS: RefCell<RpcBuffers> = const { RefCell::new(RpcBuffers::new()) };
+}
+
+pub fn set_rpc_config(config: RpcConfig) {
+    *RPC_CONFIG.lock().unwrap() = Some(config);
+}
+
+pub fn rpc_config() -> RpcConfig {
+    RPC_CONFIG.lock().unwrap().clone().unwrap_or_default()
+}
+
+// The cluster the configured endpoint belongs to, see cluster.rs
+pub fn cluster() -> Cluster {
+    Cluster::from_url(&rpc_config().url)
+}
+
+// Sends every call through `transport` from now on instead of ESP-IDF's HTTP client. The pins in
+// RpcConfig only apply to the ESP-IDF one, another transport secures its link its own way.
+pub fn set_transport(transport: impl RpcTransport<Error = RpcError> + Send + Sync + 'static) {
+    *TRANSPORT.lock().unwrap() = Some(Arc::new(transport));
+}
+
+// The sync step of the wallet's lifecycle, every transfer starts with one
+pub fn get_latest_blockhash() -> Result<Hash, RpcError> {
+    lifecycle::notify(WalletEvent::SyncStarted);
+    let result = fetch_latest_blockhash();
+    lifecycle::notify(match result {
+        Ok(_) => WalletEvent::Synced,
+        Err(_) => WalletEvent::Failed,
+    });
+    result
+}
+
+fn fetch_latest_blockhash() -> Result<Hash, RpcError> {
+    // A relay node has no uplink, the gateway fetches the blockhash for it
+    #[cfg(feature = "espnow-relay")]
+    if relay::is_node() {
+        return relay::latest_blockhash().map_err(RpcError::Gateway);
+    }
+    #[cfg(feature = "lora-bridge")]
+    if lora::is_node() {
+        return Err(RpcError::NoUplink(
+            "LoRa nodes sign with a durable nonce, a blockhash would expire on the way",
+        ));
+    }
+
+    #[cfg(feature = "lean-json")]
+    {
+        rpc_call_raw(&rpc_config(), SolanaRpcMethod::GetLatestBlockhash, read_blockhash)
+    }
+    #[cfg(not(feature = "lean-json"))]
+    {
+        parse_blockhash(&sol_rpc_call(SolanaRpcMethod::GetLatestBlockhash)?).map_err(RpcError::Parse)
+    }
+}
+
+pub fn get_balance(pubkey: &Pubkey) -> Result<u64, RpcError> {
+    #[cfg(feature = "lean-json")]
+    {
+        rpc_call_raw(&rpc_config(), SolanaRpcMethod::GetBalance(pubkey.to_string()), read_balance)
+    }
+    #[cfg(not(feature = "lean-json"))]
+    {
+        let result = sol_rpc_call(SolanaRpcMethod::GetBalance(pubkey.to_string()))?;
+        parse_balance(&result).map_err(RpcError::Parse)
+    }
+}
+
+// None if the account doesn't exist
+pub fn get_account_info(pubkey: &Pubkey) -> Result<Option<AccountInfo>, RpcError> {
+    #[cfg(feature = "lean-json")]
+    {
+        rpc_call_raw(&rpc_config(), SolanaRpcMethod::GetAccountInfo(pubkey.to_string()), read_account_info)
+    }
+    #[cfg(not(feature = "lean-json"))]
+    {
+        let result = sol_rpc_call(SolanaRpcMethod::GetAccountInfo(pubkey.to_string()))?;
+        parse_account_info(&result).map_err(RpcError::Parse)
+    }
+}
+
+// Current value of a durable nonce, the "blockhash" transactions using it are signed with
+pub fn get_nonce(nonce_account: &Pubkey) -> Result<Hash, RpcError> {
+    #[cfg(feature = "lora-bridge")]
+    if lora::is_node() {
+        return lora::nonce(nonce_account).map_err(RpcError::Gateway);
+    }
+    let account = get_account_info(nonce_account)?
+        .ok_or_else(|| RpcError::Parse("Nonce account not found".to_string()))?;
+    parse_nonce(&account).map_err(RpcError::Parse)
+}
+
+// Returns the raw token amount and mint decimals, None if the token account doesn't exist
+#[cfg(feature = "rpc-token")]
+pub fn get_token_account_balance(token_account: &Pubkey) -> Result<Option<(u64, u8)>, RpcError> {
+    match sol_rpc_call(SolanaRpcMethod::GetTokenAccountBalance(token_account.to_string())) {
+        Err(e) if e.is_account_not_found() => Ok(None),
+        result => parse_token_account_balance(&result?).map_err(RpcError::Parse),
+    }
+}
+
+pub fn get_fee_for_message(message: &Message) -> Result<u64, RpcError> {
+    let message_bytes = bincode::serialize(message)
+        .map_err(|e| RpcError::Client(format!("Message serialization failed: {:?}", e)))?;
+    let base64_message = general_purpose::STANDARD.encode(&message_bytes);
+
+    let result = sol_rpc_call(SolanaRpcMethod::GetFeeForMessage(base64_message))?;
+
+    result["value"]
+        .as_u64()
+        .ok_or_else(|| RpcError::Parse("No fee in response (blockhash expired?)".to_string()))
+}
+
+pub fn get_minimum_balance_for_rent_exemption(data_len: usize) -> Result<u64, RpcError> {
+    let result = sol_rpc_call(SolanaRpcMethod::GetMinimumBalanceForRentExemption(data_len))?;
+
+    result
+        .as_u64()
+        .ok_or_else(|| RpcError::Parse("No rent exemption minimum in response".to_string()))
+}
+
+// None while the cluster hasn't seen the transaction yet, Err if it landed but failed
+pub fn get_signature_status(signature: &Signature) -> Result<Option<ConfirmationStatus>, RpcError> {
+    #[cfg(feature = "lean-json")]
+    {
+        let method = SolanaRpcMethod::GetSignatureStatuses(vec![signature.to_string()]);
+        rpc_call_raw(&rpc_config(), method, read_signature_status).map_err(landed_failed)
+    }
+    #[cfg(not(feature = "lean-json"))]
+    {
+        let result = sol_rpc_call(SolanaRpcMethod::GetSignatureStatuses(vec![signature.to_string()]))?;
+        parse_signature_status(&result).map_err(|e| landed_failed(RpcError::Parse(e)))
+    }
+}
+
+// The status parsers report a transaction that landed but failed as an error, it's no parse error
+fn landed_failed(e: RpcError) -> RpcError {
+    match e {
+        RpcError::Parse(e) if e.starts_with("Transaction failed: ") => {
+            RpcError::TransactionFailed(e["Transaction failed: ".len()..].to_string())
+        }
+        e => e,
+    }
+}
+
+// Newest first, transactions that failed included, up to `limit` of them
+#[cfg(feature = "rpc-history")]
+pub fn get_signatures_for_address(address: &Pubkey, limit: usize) -> Result<Vec<String>, RpcError> {
+    parse_signatures(&sol_rpc_call(SolanaRpcMethod::GetSignaturesForAddress(address.to_string(), limit))?)
+        .map_err(RpcError::Parse)
+}
+
+// Devnet and testnet faucet, returns the airdrop's signature. Refused without a call on mainnet,
+// where there is no faucet.
+#[cfg(feature = "rpc-airdrop")]
+pub fn request_airdrop(address: &Pubkey, lamports: u64) -> Result<String, RpcError> {
+    let cluster = cluster();
+    if !cluster.has_faucet() {
+        return Err(RpcError::Client(format!("No airdrops on {}", cluster)));
+    }
+    let result = sol_rpc_call(SolanaRpcMethod::RequestAirdrop(address.to_string(), lamports))?;
+    result
         .as_str()
-        .ok_or("No blockhash in response")?;
+        .map(|signature| signature.to_string())
+        .ok_or_else(|| RpcError::Parse("Airdrop refused, the faucet's rate limit perhaps".to_string()))
+}
+
+// The transaction in jsonParsed form, None until the node has it at confirmed commitment
+#[cfg(feature = "rpc-history")]
+pub fn get_transaction(signature: &str) -> Result<Option<serde_json::Value>, RpcError> {
+    let result = sol_rpc_call(SolanaRpcMethod::GetTransaction(signature.to_string()))?;
+    Ok((!result.is_null()).then_some(result))
+}
+
+// Polls the signature status until it reaches `target` or the timeout expires
+pub fn confirm_transaction(
+    signature: &Signature,
+    target: ConfirmationStatus,
+    timeout: Duration,
+) -> Result<(), RpcError> {
+    let deadline = Instant::now() + timeout;
 
-    Hash::from_str(blockhash_str).map_err(|e| format!("Hash parse: {:?}", e))
+    let result = loop {
+        match get_signature_status(signature) {
+            Ok(Some(status)) if status >= target => break Ok(status),
+            Ok(_) if Instant::now() < deadline => std::thread::sleep(CONFIRM_POLL_INTERVAL),
+            Ok(_) => {
+                break Err(RpcError::Unconfirmed {
+                    signature: signature.to_string(),
+                    waited: timeout,
+                })
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    confirmation_done(signature, &result);
+    result.map(|_| ())
 }
 
-pub fn send_transaction(transaction: &Transaction) -> Result<String, String> {
+// Reports, sounds and logs how waiting for a confirmation ended, for the blocking and the
+// async wait alike. Builds without the buzzer and SD card log do neither.
+#[allow(unused_variables)]
+pub fn confirmation_done(signature: &Signature, result: &Result<ConfirmationStatus, RpcError>) {
+    lifecycle::notify(match result {
+        Ok(_) => WalletEvent::Confirmed,
+        Err(_) => WalletEvent::Failed,
+    });
+    match result {
+        Ok(status) => {
+            #[cfg(feature = "buzzer")]
+            buzzer::play(Sound::Confirmed);
+            #[cfg(feature = "sd-log")]
+            sdlog::record("confirmed", json!({ "signature": signature.to_string(), "status": format!("{:?}", status) }));
+        }
+        Err(e) => {
+            #[cfg(feature = "sd-log")]
+            sdlog::record("unconfirmed", json!({ "signature": signature.to_string(), "error": e.to_string() }));
+        }
+    }
+}
+
+pub fn send_transaction(transaction: &Transaction) -> Result<String, RpcError> {
+    #[cfg(feature = "status-led")]
+    led::show(LedState::Sending);
+    lifecycle::notify(WalletEvent::SendStarted);
+    let result = submit_transaction(transaction);
+    lifecycle::notify(match result {
+        Ok(_) => WalletEvent::Sent,
+        Err(_) => WalletEvent::Failed,
+    });
+    #[cfg(feature = "oled-display")]
+    display::transaction_sent(&result);
+    #[cfg(feature = "status-led")]
+    led::transaction_sent(&result);
+    #[cfg(feature = "buzzer")]
+    buzzer::transaction_sent(&result);
+    #[cfg(feature = "receipt-printer")]
+    if let Ok(signature) = &result {
+        printer::transaction_sent(transaction, signature);
+    }
+    #[cfg(feature = "sd-log")]
+    sdlog::record(
+        "sent",
+        match &result {
+            Ok(signature) => json!({ "signature": signature }),
+            Err(e) => json!({ "signature": transaction.signatures.first().map(|s| s.to_string()), "error": e.to_string() }),
+        },
+    );
+    result
+}
+
+fn submit_transaction(transaction: &Transaction) -> Result<String, RpcError> {
+    #[cfg(feature = "espnow-relay")]
+    if relay::is_node() {
+        return relay::submit(transaction).map_err(RpcError::Gateway);
+    }
+    #[cfg(feature = "lora-bridge")]
+    if lora::is_node() {
+        return lora::submit(transaction).map_err(RpcError::Gateway);
+    }
+
+    check_fee(transaction)?;
     let transaction_bytes = bincode::serialize(transaction)
-        .map_err(|e| format!("Transaction serialization failed: {:?}", e))?;
+        .map_err(|e| RpcError::Client(format!("Transaction serialization failed: {:?}", e)))?;
 
     let base64_transaction = general_purpose::STANDARD.encode(&transaction_bytes);
 
     send_transaction_base64(base64_transaction)
 }
 
-pub fn send_transaction_base64(base64_transaction: String) -> Result<String, String> {
-    let result = sol_rpc_call(SolanaRpcMethod::SendTransaction(base64_transaction))?;
+// Refuses a transaction whose fee is over the max_fee setting, asked of the node only when set.
+// Every send from this device passes here, the rpc task's, the console's and the USB wallet's.
+pub fn check_fee(transaction: &Transaction) -> Result<(), RpcError> {
+    let Some(max_fee) = config::settings().max_fee else {
+        return Ok(());
+    };
+    let fee = get_fee_for_message(&transaction.message)?;
+    match fee <= max_fee {
+        true => Ok(()),
+        false => Err(RpcError::Client(format!("Fee of {} lamports over the {} lamport cap", fee, max_fee))),
+    }
+}
 
-    let signature = result.as_str()
-        .ok_or("Invalid response format: expected transaction signature")?
-        .to_string();
+pub fn send_transaction_base64(base64_transaction: String) -> Result<String, RpcError> {
+    #[cfg(feature = "lean-json")]
+    {
+        rpc_call_raw(&rpc_config(), SolanaRpcMethod::SendTransaction(base64_transaction), read_sent_signature)
+    }
+    #[cfg(not(feature = "lean-json"))]
+    {
+        parse_sent_signature(&sol_rpc_call(SolanaRpcMethod::SendTransaction(base64_transaction))?).map_err(RpcError::Parse)
+    }
+}
+pub fn get_slot() -> Result<u64, RpcError> {
+    let result = sol_rpc_call(SolanaRpcMethod::GetSlot)?;
+    result
+        .as_u64()
+        .ok_or_else(|| RpcError::Parse("Invalid response format: expected slot".to_string()))
+}
 
-    Ok(signature)
+// Leaders of `limit` slots from `start_slot` on, one entry per slot
+#[cfg(feature = "rpc-cluster")]
+pub fn get_slot_leaders(start_slot: u64, limit: u64) -> Result<Vec<Pubkey>, RpcError> {
+    parse_slot_leaders(&sol_rpc_call(SolanaRpcMethod::GetSlotLeaders(start_slot, limit))?).map_err(RpcError::Parse)
 }
 
-pub fn create_solana_payload(method: SolanaRpcMethod) -> serde_json::Value {
-    json!({
-        "jsonrpc": "2.0",
-        "id": 1,
-        "method": method.method_name(),
-        "params": method.params()
+pub fn sol_rpc_call(method: SolanaRpcMethod) -> Result<serde_json::Value, RpcError> {
+    rpc_call(&rpc_config(), method)
+}
+
+// Same as sol_rpc_call against an explicit endpoint instead of the configured one
+pub fn rpc_call(config: &RpcConfig, method: SolanaRpcMethod) -> Result<serde_json::Value, RpcError> {
+    BUFFERS.with(|buffers| match buffers.try_borrow_mut() {
+        Ok(mut buffers) => rpc_call_with_buffers(config, method, &mut buffers),
+        // A call from inside another one on this thread, which holds the buffers
+        Err(_) => rpc_call_with_buffers(config, method, &mut RpcBuffers::new()),
     })
 }
 
-pub fn sol_rpc_call(method: SolanaRpcMethod) -> Result<serde_json::Value, String> {
-    let connection = EspHttpConnection::new(&Configuration {
-        timeout: Some(std::time::Duration::from_secs(30)),
-        use_global_ca_store: true,
-        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
-        ..Default::default()
+// rpc_call with buffers the caller owns, e.g. reserved by a task at boot
+pub fn rpc_call_with_buffers(
+    config: &RpcConfig,
+    method: SolanaRpcMethod,
+    buffers: &mut RpcBuffers,
+) -> Result<serde_json::Value, RpcError> {
+    #[cfg(feature = "sd-log")]
+    let method_name = method.method_name();
+    let parsed = read_response(config, method, buffers, |body| {
+        serde_json::from_slice(body).map_err(|e| RpcError::Parse(format!("JSON parse: {:?}", e)))
+    });
+    #[cfg(feature = "sd-log")]
+    if let Ok(json_response) = &parsed {
+        log_rpc_error(method_name, json_response);
+    }
+    Ok(client::take_result(parsed?)?)
+}
+
+// Hands `parse` the whole raw response, the envelope included, instead of parsing it into a
+// Value. For reading a few fields with leanjson, e.g.
+// `|body| leanjson::u64_at(body, &["result", "value"])`.
+pub fn rpc_call_raw<T>(
+    config: &RpcConfig,
+    method: SolanaRpcMethod,
+    parse: impl FnOnce(&[u8]) -> Result<T, String>,
+) -> Result<T, RpcError> {
+    #[cfg(feature = "sd-log")]
+    let method_name = method.method_name();
+    let parse = |body: &[u8]| {
+        #[cfg(feature = "sd-log")]
+        if let Ok(Some(error)) = leanjson::find(body, &["error"]) {
+            sdlog::record("rpc_error", json!({ "method": method_name, "error": String::from_utf8_lossy(error) }));
+        }
+        if let Some(error) = client::read_node_error(body) {
+            return Err(error.into());
+        }
+        parse(body).map_err(RpcError::Parse)
+    };
+    BUFFERS.with(|buffers| match buffers.try_borrow_mut() {
+        Ok(mut buffers) => read_response(config, method, &mut buffers, parse),
+        Err(_) => read_response(config, method, &mut RpcBuffers::new(), parse),
     })
-    .map_err(|e| format!("HTTP init: {:?}", e))?;
+}
 
-    let mut client = Client::wrap(connection);
-    let payload = create_solana_payload(method);
+// Streams the response body into the buffers and parses it from there
+fn read_response<T>(
+    config: &RpcConfig,
+    method: SolanaRpcMethod,
+    buffers: &mut RpcBuffers,
+    parse: impl FnOnce(&[u8]) -> Result<T, RpcError>,
+) -> Result<T, RpcError> {
+    let RpcBuffers {
+        request,
+        response,
+        external,
+    } = &mut *buffers;
+    // Grows with what arrives instead of trusting Content-Length, which may be absent or wrong
+    response.clear();
+    let sizes = buffers::config();
+    let mut body = Body {
+        internal: response,
+        external,
+        spilled: false,
+        sizes,
+    };
+    let parsed = with_retries(config, || {
+        body.internal.clear();
+        body.spilled = false;
+        stream_with_request(config, method.clone(), request, &mut |data| body.push(data))
+    })
+    .and_then(|_| parse(body.as_slice()));
+    buffers.trim(sizes.json_scratch);
+    parsed
+}
 
-    let payload_str =
-        serde_json::to_string(&payload).map_err(|e| format!("JSON serialize: {:?}", e))?;
+// Same as rpc_call with the response in a fixed buffer, a static one for instance, so the body
+// never touches the heap. Responses over N bytes fail.
+pub fn rpc_call_fixed<const N: usize>(
+    config: &RpcConfig,
+    method: SolanaRpcMethod,
+    response: &mut heapless::Vec<u8, N>,
+) -> Result<serde_json::Value, RpcError> {
+    #[cfg(feature = "sd-log")]
+    let method_name = method.method_name();
+    response.clear();
+    let streamed = BUFFERS.with(|buffers| {
+        let mut own = RpcBuffers::new();
+        let mut borrowed = buffers.try_borrow_mut();
+        let request = match borrowed.as_mut() {
+            Ok(buffers) => &mut buffers.request,
+            Err(_) => &mut own.request,
+        };
+        let streamed = with_retries(config, || {
+            response.clear();
+            stream_with_request(config, method.clone(), request, &mut |data| {
+                response.extend_from_slice(data).map_err(|_| RpcError::TooLarge(N))
+            })
+        });
+        request.shrink_to(buffers::config().json_scratch);
+        streamed
+    });
+    let json_response: serde_json::Value = streamed.and_then(|_| {
+        serde_json::from_slice(response).map_err(|e| RpcError::Parse(format!("JSON parse: {:?}", e)))
+    })?;
+    #[cfg(feature = "sd-log")]
+    log_rpc_error(method_name, &json_response);
+    Ok(client::take_result(json_response)?)
+}
 
-    let headers = [
-        ("Content-Type", "application/json"),
-        ("Content-Length", &payload_str.len().to_string()),
-    ];
+// Repeats `call` after transient errors, up to config.retries more times
+fn with_retries(config: &RpcConfig, mut call: impl FnMut() -> Result<(), RpcError>) -> Result<(), RpcError> {
+    let mut delay = RETRY_DELAY;
+    for _ in 0..config.retries {
+        match call() {
+            Err(e) if e.is_transient() => {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    call()
+}
 
-    let mut request = client
-        .request(Method::Post, RPC_URL, &headers)
-        .map_err(|e| format!("Request: {:?}", e))?;
+// Holds the call back until the rate limit allows it, each call takes the next free slot
+fn pace(config: &RpcConfig) {
+    let Some(calls_per_second) = config.rate_limit else {
+        return;
+    };
+    let wait = {
+        let mut next_call = NEXT_CALL.lock().unwrap();
+        let now = Instant::now();
+        let slot = next_call.map_or(now, |next_call| next_call.max(now));
+        *next_call = Some(slot + Duration::from_secs(1) / calls_per_second);
+        slot - now
+    };
+    if !wait.is_zero() {
+        std::thread::sleep(wait);
+    }
+}
 
-    request
-        .write(payload_str.as_bytes())
-        .map_err(|e| format!("Write: {:?}", e))?;
+// The node answered but refused the call, e.g. a preflight failure
+#[cfg(feature = "sd-log")]
+fn log_rpc_error(method_name: &str, json_response: &serde_json::Value) {
+    if !json_response["error"].is_null() {
+        sdlog::record("rpc_error", json!({ "method": method_name, "error": json_response["error"] }));
+    }
+}
 
-    let response = request
-        .submit()
-        .map_err(|e| format!("Submit: {:?}", e))?;
+// Hands the raw response body to `on_data` as it arrives, for responses too large to hold in RAM
+pub fn rpc_call_streaming(
+    config: &RpcConfig,
+    method: SolanaRpcMethod,
+    on_data: &mut dyn FnMut(&[u8]) -> Result<(), String>,
+) -> Result<(), RpcError> {
+    stream_with_request(config, method, &mut String::new(), &mut |data| {
+        on_data(data).map_err(RpcError::Parse)
+    })
+}
 
-    let status = response.status();
-    if !(200..=299).contains(&status) {
-        return Err(format!("HTTP Error: Status code {}", status));
+fn stream_with_request(
+    config: &RpcConfig,
+    method: SolanaRpcMethod,
+    request: &mut String,
+    on_data: &mut dyn FnMut(&[u8]) -> Result<(), RpcError>,
+) -> Result<(), RpcError> {
+    #[cfg(feature = "sd-log")]
+    let method_name = method.method_name();
+    #[cfg(feature = "low-power")]
+    let started = Instant::now();
+    let result = stream_rpc_call(config, method, request, on_data);
+    #[cfg(feature = "low-power")]
+    lowpower::rpc_busy(started.elapsed());
+    // The endpoint stays out of the log, its URL may carry an API key
+    #[cfg(feature = "sd-log")]
+    if let Err(e) = &result {
+        sdlog::record("rpc_error", json!({ "method": method_name, "error": e.to_string() }));
     }
+    result
+}
 
-    let mut response_body = Vec::with_capacity(response.content_len().unwrap_or(0) as usize);
-    let mut reader = response;
-    let mut buf = [0u8; 256];
-    loop {
-        let size = reader.read(&mut buf).map_err(|e| format!("Read: {:?}", e))?;
-        if size == 0 {
-            break;
-        }
-        response_body.extend_from_slice(&buf[..size]);
-    }
-    let response_str = str::from_utf8(&response_body).map_err(|e| format!("UTF-8: {:?}", e))?;
-    let json_response: serde_json::Value = serde_json::from_str(response_str).map_err(|e| format!("JSON parse: {:?}", e))?;
-
-    Ok(json_response["result"].clone())
-}
-
-impl SolanaRpcMethod {
-    pub fn method_name(&self) -> &'static str {
-        match self {
-            SolanaRpcMethod::GetLatestBlockhash => "getLatestBlockhash",
-            SolanaRpcMethod::GetBalance(_) => "getBalance",
-            SolanaRpcMethod::GetTransaction(_) => "getTransaction",
-            SolanaRpcMethod::GetAccountInfo(_) => "getAccountInfo",
-            SolanaRpcMethod::GetProgramAccounts(_) => "getProgramAccounts",
-            SolanaRpcMethod::GetRecentBlockhash => "getRecentBlockhash",
-            SolanaRpcMethod::GetSlot => "getSlot",
-            SolanaRpcMethod::GetVersion => "getVersion",
-            SolanaRpcMethod::SendTransaction(_) => "sendTransaction",
-        }
+fn stream_rpc_call(
+    config: &RpcConfig,
+    method: SolanaRpcMethod,
+    request_body: &mut String,
+    on_data: &mut dyn FnMut(&[u8]) -> Result<(), RpcError>,
+) -> Result<(), RpcError> {
+    #[cfg(feature = "espnow-relay")]
+    if relay::is_node() {
+        return Err(RpcError::NoUplink("Relay nodes only reach the network through the gateway"));
+    }
+    #[cfg(feature = "lora-bridge")]
+    if lora::is_node() {
+        return Err(RpcError::NoUplink("LoRa nodes only reach the network through the gateway"));
     }
 
-    pub fn params(&self) -> serde_json::Value {
-        match self {
-            SolanaRpcMethod::GetLatestBlockhash => {
-                json!([{"commitment": "confirmed"}])
-            }
-            SolanaRpcMethod::GetBalance(wallet) => {
-                json!([wallet])
-            }
-            SolanaRpcMethod::GetTransaction(signature) => {
-                json!([signature, {"encoding": "jsonParsed"}])
-            }
-            SolanaRpcMethod::GetAccountInfo(account) => {
-                json!([account, {"encoding": "base64"}])
-            }
-            SolanaRpcMethod::GetProgramAccounts(program) => {
-                json!([program, {"encoding": "base64"}])
-            }
-            SolanaRpcMethod::GetRecentBlockhash => {
-                json!([])
-            }
-            SolanaRpcMethod::GetSlot => {
-                json!([])
-            }
-            SolanaRpcMethod::GetVersion => {
-                json!([])
-            }
-            SolanaRpcMethod::SendTransaction(transaction) => {
-                json!([transaction, {
-                    "encoding": "base64",
-                    "skipPreflight": false,
-                    "preflightCommitment": "confirmed",
-                    "maxRetries": 3
-                }])
-            }
+    client::write_request(request_body, method, config.commitment)?;
+    pace(config);
+    let transport = TRANSPORT.lock().unwrap().clone();
+    match transport {
+        Some(transport) => client::post(&*transport, &config.url, request_body, config.timeout, on_data),
+        None => {
+            let transport = EspTransport { pins: &config.pins };
+            client::post(&transport, &config.url, request_body, config.timeout, on_data)
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use resp32sol_core::spend::{SpendBuckets, LEDGER_LEN};
+
+const SPEND_NAMESPACE: &str = "spend";
+const LEDGER_KEY: &str = "ledger";
+
+// Anything earlier means the clock was never set (no SNTP sync since power loss)
+const MIN_VALID_UNIX_TIME: u64 = 1_704_067_200; // 2024-01-01
+
+// Wall clock time, kept by SNTP while online and by the RTC across deep sleep
+pub fn unix_time() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|since_epoch| since_epoch.as_secs())
+        .filter(|&secs| secs >= MIN_VALID_UNIX_TIME)
+}
+
+// Wall clock time for the spend windows and session expiry, only while SNTP keeps it trusted
+// (see timesync). The remote signer has no uplink to sync it, so it never has one.
+pub fn trusted_time() -> Option<u64> {
+    #[cfg(not(feature = "remote-signer"))]
+    return crate::timesync::trusted_unix_time();
+    #[cfg(feature = "remote-signer")]
+    None
+}
+
+// Rolling spend counters persisted in NVS, survive reboots and deep sleep. The buckets live in
+// resp32sol-core, where they are tested on the host.
+pub struct SpendLedger {
+    nvs: EspNvs<NvsDefault>,
+    buckets: SpendBuckets,
+}
+
+impl SpendLedger {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        Self::open_in(nvs, SPEND_NAMESPACE)
+    }
+
+    // A ledger of its own, for budgets kept apart from the spending policy's
+    pub fn open_in(nvs: EspDefaultNvsPartition, namespace: &str) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, namespace, true).map_err(|e| format!("NVS open: {:?}", e))?;
+
+        let mut buf = vec![0u8; LEDGER_LEN];
+        let buckets = match nvs
+            .get_blob(LEDGER_KEY, &mut buf)
+            .map_err(|e| format!("Spend ledger read: {:?}", e))?
+        {
+            Some(data) => SpendBuckets::from_bytes(data)?,
+            None => SpendBuckets::default(),
+        };
+
+        Ok(Self { nvs, buckets })
+    }
+
+    pub fn buckets(&self) -> &SpendBuckets {
+        &self.buckets
+    }
+
+    // Lamports spent in the window ending at `now`
+    pub fn spent_within(&self, window: Duration, now: u64) -> u64 {
+        self.buckets.spent_within(window, now)
+    }
+
+    // Returns the bucket the lamports went into, for release
+    pub fn record(&mut self, lamports: u64, now: u64) -> Result<u64, String> {
+        let bucket = self.buckets.record(lamports, now);
+        self.persist().map(|_| bucket)
+    }
+
+    // Takes back lamports recorded into `bucket` for a spend that didn't happen after all
+    pub fn release(&mut self, lamports: u64, bucket: u64) -> Result<(), String> {
+        match self.buckets.release(lamports, bucket) {
+            true => self.persist(),
+            false => Ok(()),
+        }
+    }
+
+    fn persist(&mut self) -> Result<(), String> {
+        self.nvs
+            .set_blob(LEDGER_KEY, &self.buckets.to_bytes())
+            .map_err(|e| format!("Spend ledger write: {:?}", e))
+    }
+}
@@ -0,0 +1,234 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::hal::spi::config::{Config, DriverConfig};
+use esp_idf_svc::hal::spi::{SpiDeviceDriver, SpiDriver, SPI2};
+use log::info;
+
+use crate::lora::LoraConfig;
+
+// Semtech SX1276/77/78 (RFM95W, Ra-02 and most 868/915 MHz LoRa modules) in LoRa mode over
+// SPI. DIO0 and RESET stay unconnected, the IRQ flags are polled and the chip is set up from
+// whatever state it was left in. 125 kHz bandwidth, coding rate 4/5, explicit header and CRC.
+
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_LNA: u8 = 0x0C;
+const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+const REG_FIFO_TX_BASE: u8 = 0x0E;
+const REG_FIFO_RX_BASE: u8 = 0x0F;
+const REG_FIFO_RX_CURRENT: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_BYTES: u8 = 0x13;
+const REG_PACKET_SNR: u8 = 0x19;
+const REG_PACKET_RSSI: u8 = 0x1A;
+const REG_HOP_CHANNEL: u8 = 0x1C;
+const REG_MODEM_CONFIG_1: u8 = 0x1D;
+const REG_MODEM_CONFIG_2: u8 = 0x1E;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PREAMBLE_LSB: u8 = 0x21;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_VERSION: u8 = 0x42;
+
+// Long range mode bit set in every mode
+const MODE_SLEEP: u8 = 0x80;
+const MODE_STANDBY: u8 = 0x81;
+const MODE_TX: u8 = 0x83;
+const MODE_RX_CONTINUOUS: u8 = 0x85;
+
+const IRQ_RX_DONE: u8 = 0x40;
+const IRQ_CRC_ERROR: u8 = 0x20;
+const IRQ_TX_DONE: u8 = 0x08;
+// Set in the hop channel register when the received header announced a payload CRC
+const CRC_ON_PAYLOAD: u8 = 0x40;
+
+const SX1276_VERSION: u8 = 0x12;
+const CRYSTAL_HZ: u64 = 32_000_000;
+const BANDWIDTH_HZ: u64 = 125_000;
+const PREAMBLE_SYMBOLS: u16 = 8;
+// One byte of the FIFO is the length register's limit
+pub const MAX_PACKET_LEN: usize = 255;
+const IRQ_POLL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    pub rssi: i16,
+    // In dB, negative below the noise floor
+    pub snr: f32,
+}
+
+pub struct Sx127x {
+    spi: SpiDeviceDriver<'static, SpiDriver<'static>>,
+    spreading_factor: u8,
+    receiving: bool,
+}
+
+impl Sx127x {
+    pub fn new(
+        spi: SPI2,
+        sclk: AnyIOPin,
+        mosi: AnyIOPin,
+        miso: AnyIOPin,
+        cs: AnyIOPin,
+        config: &LoraConfig,
+    ) -> Result<Self, String> {
+        if !(7..=12).contains(&config.spreading_factor) {
+            return Err(format!("Spreading factor {} out of range, 7 to 12", config.spreading_factor));
+        }
+        let driver = SpiDriver::new(spi, sclk, mosi, Some(miso), &DriverConfig::new())
+            .map_err(|e| format!("SPI init: {:?}", e))?;
+        let spi = SpiDeviceDriver::new(driver, Some(cs), &Config::new().baudrate(8.MHz().into()))
+            .map_err(|e| format!("SPI device: {:?}", e))?;
+        let mut radio = Self {
+            spi,
+            spreading_factor: config.spreading_factor,
+            receiving: false,
+        };
+
+        let version = radio.read(REG_VERSION)?;
+        if version != SX1276_VERSION {
+            return Err(format!("No SX1276 on the bus, version register reads {:#04x}", version));
+        }
+        // LoRa mode can only be switched to from sleep
+        radio.write(REG_OP_MODE, MODE_SLEEP)?;
+        let frf = ((config.frequency_hz as u64) << 19) / CRYSTAL_HZ;
+        radio.write_burst(REG_FRF_MSB, &[(frf >> 16) as u8, (frf >> 8) as u8, frf as u8])?;
+        // PA_BOOST, which the common modules wire to the antenna, from 2 to 17 dBm
+        radio.write(REG_PA_CONFIG, 0x80 | (config.tx_power_dbm.clamp(2, 17) - 2))?;
+        // Maximum gain, boosted LNA current
+        radio.write(REG_LNA, 0x23)?;
+        // 125 kHz, 4/5, explicit header
+        radio.write(REG_MODEM_CONFIG_1, 0x72)?;
+        radio.write(REG_MODEM_CONFIG_2, (config.spreading_factor << 4) | 0x04)?;
+        // Automatic gain control, and the low data rate optimization symbols over 16 ms need
+        radio.write(REG_MODEM_CONFIG_3, if radio.low_data_rate() { 0x0C } else { 0x04 })?;
+        radio.write(REG_PREAMBLE_MSB, (PREAMBLE_SYMBOLS >> 8) as u8)?;
+        radio.write(REG_PREAMBLE_LSB, PREAMBLE_SYMBOLS as u8)?;
+        radio.write(REG_SYNC_WORD, config.sync_word)?;
+        // Half duplex, transmit and receive share the whole FIFO
+        radio.write(REG_FIFO_TX_BASE, 0)?;
+        radio.write(REG_FIFO_RX_BASE, 0)?;
+        radio.write(REG_OP_MODE, MODE_STANDBY)?;
+
+        info!(
+            "SX1276 up at {} Hz, SF{}, {} dBm",
+            config.frequency_hz, config.spreading_factor, config.tx_power_dbm
+        );
+        Ok(radio)
+    }
+
+    // Sends one packet and waits for it to go out, returns its time on air
+    pub fn transmit(&mut self, data: &[u8]) -> Result<Duration, String> {
+        if data.is_empty() || data.len() > MAX_PACKET_LEN {
+            return Err(format!("LoRa packet of {} bytes", data.len()));
+        }
+        self.write(REG_OP_MODE, MODE_STANDBY)?;
+        self.receiving = false;
+        self.write(REG_FIFO_ADDR_PTR, 0)?;
+        self.write_burst(REG_FIFO, data)?;
+        self.write(REG_PAYLOAD_LENGTH, data.len() as u8)?;
+        self.write(REG_IRQ_FLAGS, 0xFF)?;
+        self.write(REG_OP_MODE, MODE_TX)?;
+
+        let airtime = self.airtime(data.len());
+        let deadline = Instant::now() + airtime * 2 + Duration::from_millis(100);
+        while self.read(REG_IRQ_FLAGS)? & IRQ_TX_DONE == 0 {
+            if Instant::now() >= deadline {
+                self.write(REG_OP_MODE, MODE_STANDBY)?;
+                return Err("LoRa transmit timed out".to_string());
+            }
+            std::thread::sleep(IRQ_POLL);
+        }
+        // The chip is back in standby once the packet is out
+        self.write(REG_IRQ_FLAGS, 0xFF)?;
+        Ok(airtime)
+    }
+
+    // The next packet received within the timeout with a valid CRC, the receiver stays on
+    // between calls so nothing arriving in between is lost
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Packet>, String> {
+        if !self.receiving {
+            self.write(REG_IRQ_FLAGS, 0xFF)?;
+            self.write(REG_OP_MODE, MODE_RX_CONTINUOUS)?;
+            self.receiving = true;
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let flags = self.read(REG_IRQ_FLAGS)?;
+            if flags & IRQ_RX_DONE != 0 {
+                self.write(REG_IRQ_FLAGS, 0xFF)?;
+                // Packets sent without a CRC can't be told from noise
+                if flags & IRQ_CRC_ERROR == 0 && self.read(REG_HOP_CHANNEL)? & CRC_ON_PAYLOAD != 0 {
+                    return self.read_packet().map(Some);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            std::thread::sleep(IRQ_POLL);
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Packet, String> {
+        let len = self.read(REG_RX_BYTES)? as usize;
+        let start = self.read(REG_FIFO_RX_CURRENT)?;
+        self.write(REG_FIFO_ADDR_PTR, start)?;
+        let mut data = vec![0u8; len];
+        self.read_burst(REG_FIFO, &mut data)?;
+        // The HF port's offset, modules for 433 MHz read 7 dB higher
+        let rssi = self.read(REG_PACKET_RSSI)? as i16 - 157;
+        let snr = self.read(REG_PACKET_SNR)? as i8 as f32 / 4.0;
+        Ok(Packet { data, rssi, snr })
+    }
+
+    // Time on air of a packet, from the formula in the datasheet
+    pub fn airtime(&self, len: usize) -> Duration {
+        let sf = self.spreading_factor as i64;
+        let symbol_us = (1u64 << sf) * 1_000_000 / BANDWIDTH_HZ;
+        let de = self.low_data_rate() as i64;
+        // Explicit header, CRC on, coding rate 4/5
+        let bits = 8 * len as i64 - 4 * sf + 28 + 16;
+        let per_block = 4 * (sf - 2 * de);
+        let payload_symbols = 8 + ((bits + per_block - 1) / per_block).max(0) * 5;
+        let preamble_us = (PREAMBLE_SYMBOLS as u64 * 4 + 17) * symbol_us / 4;
+        Duration::from_micros(preamble_us + payload_symbols as u64 * symbol_us)
+    }
+
+    fn low_data_rate(&self) -> bool {
+        (1u64 << self.spreading_factor) * 1_000_000 / BANDWIDTH_HZ > 16_000
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, String> {
+        let mut value = [0u8; 1];
+        self.read_burst(register, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_burst(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), String> {
+        let mut write = vec![0u8; buffer.len() + 1];
+        write[0] = register & 0x7F;
+        let mut read = vec![0u8; buffer.len() + 1];
+        self.spi
+            .transfer(&mut read, &write)
+            .map_err(|e| format!("SX1276 read: {:?}", e))?;
+        buffer.copy_from_slice(&read[1..]);
+        Ok(())
+    }
+
+    fn write(&mut self, register: u8, value: u8) -> Result<(), String> {
+        self.write_burst(register, &[value])
+    }
+
+    // A set top bit of the address selects a write
+    fn write_burst(&mut self, register: u8, data: &[u8]) -> Result<(), String> {
+        self.spi
+            .write(&[&[register | 0x80], data].concat())
+            .map_err(|e| format!("SX1276 write: {:?}", e))
+    }
+}
@@ -0,0 +1,221 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{error, info, warn};
+#[cfg(not(feature = "remote-signer"))]
+use serde_json::json;
+use solana_keypair::Keypair;
+#[cfg(not(feature = "remote-signer"))]
+use solana_keypair::Signer;
+#[cfg(not(feature = "remote-signer"))]
+use solana_transaction::Transaction;
+
+use crate::error::WalletError;
+use crate::keystore::Keystore;
+#[cfg(not(feature = "remote-signer"))]
+use crate::memo;
+#[cfg(not(feature = "remote-signer"))]
+use crate::solrpc::{get_latest_blockhash, send_transaction};
+use crate::spend::unix_time;
+
+const TAMPER_NAMESPACE: &str = "tamper";
+const EVENTS_KEY: &str = "events";
+const LAST_EVENT_KEY: &str = "last";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// Wiping NVS and sending the alert over TLS needs more than the default pthread stack
+const WATCHER_STACK_SIZE: usize = 16 * 1024;
+
+// Set once tamper has been detected, the signer refuses everything from then on
+static LOCKED_DOWN: AtomicBool = AtomicBool::new(false);
+// Set from the GPIO interrupt, so a pulse shorter than the poll interval isn't missed
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+
+pub fn locked_down() -> bool {
+    LOCKED_DOWN.load(Ordering::SeqCst)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TamperConfig {
+    // Level the input reads when tampered, e.g. high once a normally closed case switch to
+    // ground opens. The input is pulled towards it, so a cut wire counts as tamper too.
+    pub tripped_high: bool,
+    // Send a last memo signed by the device key before locking down, online builds only
+    pub alert: bool,
+}
+
+// Tamper events survive the wipe, a device that has been tampered with stays locked
+// down until its NVS partition is erased
+pub struct TamperLog {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl TamperLog {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, TAMPER_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        Ok(Self { nvs })
+    }
+
+    // A read error is returned rather than taken as no events, which would lift the lockdown
+    pub fn events(&self) -> Result<u8, String> {
+        self.nvs
+            .get_u8(EVENTS_KEY)
+            .map(|events| events.unwrap_or(0))
+            .map_err(|e| format!("Tamper log read: {:?}", e))
+    }
+
+    // Unix time of the last event, when the clock was synced at the time
+    pub fn last_event(&self) -> Option<u64> {
+        self.nvs.get_u64(LAST_EVENT_KEY).ok().flatten()
+    }
+
+    fn record(&self, now: Option<u64>) -> Result<(), String> {
+        // An unreadable count still records an event, lockdown only needs a nonzero one
+        let events = self.events().unwrap_or(0);
+        self.nvs
+            .set_u8(EVENTS_KEY, events.saturating_add(1))
+            .map_err(|e| format!("Tamper event store: {:?}", e))?;
+        if let Some(now) = now {
+            self.nvs
+                .set_u64(LAST_EVENT_KEY, now)
+                .map_err(|e| format!("Tamper time store: {:?}", e))?;
+        }
+        Ok(())
+    }
+}
+
+struct TamperResponse {
+    keystore: Option<Keystore>,
+    log: TamperLog,
+    alert_key: Option<Keypair>,
+}
+
+impl TamperResponse {
+    // Lock, record, wipe, alert and restart, in that order so a power cut midway (the obvious
+    // reaction of an attacker) still leaves the device locked down, and the wipe is run again
+    // at the next boot
+    fn run(mut self) -> ! {
+        LOCKED_DOWN.store(true, Ordering::SeqCst);
+        error!("Tamper detected, wiping key material");
+
+        if let Err(e) = self.log.record(unix_time()) {
+            error!("Tamper event not recorded: {}", e);
+        }
+
+        match &mut self.keystore {
+            Some(keystore) => match keystore.wipe_all() {
+                Ok(()) => info!("Keystore wiped"),
+                Err(e) => error!("Keystore wipe incomplete: {}", e),
+            },
+            None => warn!("No keystore to wipe"),
+        }
+
+        #[cfg(not(feature = "remote-signer"))]
+        if let Some(alert_key) = &self.alert_key {
+            match send_alert(alert_key, self.log.events().unwrap_or(1)) {
+                Ok(signature) => info!("Tamper alert sent: {}", signature),
+                Err(e) => warn!("Tamper alert failed: {}", e),
+            }
+        }
+
+        // The restart clears the device key held in RAM, the boot check keeps it locked
+        unsafe { esp_idf_svc::sys::esp_restart() }
+    }
+}
+
+// Watches the tamper input on a thread of its own, so it keeps working while the main task
+// waits for a PIN, a button press or the network
+pub fn spawn_watcher(
+    pin: AnyIOPin,
+    config: TamperConfig,
+    keystore: Option<Keystore>,
+    log: TamperLog,
+    alert_key: Option<Keypair>,
+) -> Result<(), String> {
+    let mut input = PinDriver::input(pin).map_err(|e| format!("Tamper pin: {:?}", e))?;
+    let (pull, edge) = match config.tripped_high {
+        true => (Pull::Up, InterruptType::PosEdge),
+        false => (Pull::Down, InterruptType::NegEdge),
+    };
+    input.set_pull(pull).map_err(|e| format!("Tamper pin pull: {:?}", e))?;
+    input
+        .set_interrupt_type(edge)
+        .map_err(|e| format!("Tamper interrupt type: {:?}", e))?;
+    // Only touches an atomic, which is fine from ISR context
+    unsafe { input.subscribe(|| TRIPPED.store(true, Ordering::SeqCst)) }
+        .map_err(|e| format!("Tamper interrupt: {:?}", e))?;
+    input
+        .enable_interrupt()
+        .map_err(|e| format!("Tamper interrupt enable: {:?}", e))?;
+
+    let response = TamperResponse {
+        keystore,
+        log,
+        alert_key,
+    };
+
+    std::thread::Builder::new()
+        .name("tamper".to_string())
+        .stack_size(WATCHER_STACK_SIZE)
+        .spawn(move || watch(input, config, response))
+        .map_err(|e| format!("Tamper watcher: {:?}", e))?;
+
+    info!("Tamper input armed");
+    Ok(())
+}
+
+fn watch(input: PinDriver<'static, AnyIOPin, Input>, config: TamperConfig, response: TamperResponse) -> ! {
+    loop {
+        // Polling as well catches an input that was already tripped when it was armed
+        if TRIPPED.load(Ordering::SeqCst) || input.is_high() == config.tripped_high {
+            response.run();
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(not(feature = "remote-signer"))]
+fn send_alert(keypair: &Keypair, events: u8) -> Result<String, String> {
+    let device = keypair.pubkey();
+    let alert = json!({ "t": "tamper", "n": events }).to_string();
+    let instruction = memo::memo(&alert, &[&device]);
+
+    let blockhash = get_latest_blockhash().map_err(|e| e.to_string())?;
+    let transaction = Transaction::new_signed_with_payer(&[instruction], Some(&device), &[keypair], blockhash);
+    send_transaction(&transaction).map_err(|e| e.to_string())
+}
+
+// Refuses every signature from now on, for a device whose tamper input couldn't be armed
+pub fn lock(reason: &str) {
+    LOCKED_DOWN.store(true, Ordering::SeqCst);
+    error!("Tamper detection unavailable ({}), signing locked", reason);
+}
+
+// Boot state of a device whose tamper log can't be read: it may have seen an event, so no key
+// is loaded and nothing is signed. Nothing is wiped either, a later boot may read the log.
+pub fn lockdown_unknown(reason: &str) -> ! {
+    LOCKED_DOWN.store(true, Ordering::SeqCst);
+    loop {
+        warn!("Device locked down, tamper log unreadable: {}", reason);
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
+// Boot state of a device that has seen a tamper event: the keystore is wiped again, in case
+// power was cut during the first wipe, no key is loaded and nothing is signed
+pub fn lockdown(log: &TamperLog, keystore: Result<Keystore, WalletError>) -> ! {
+    LOCKED_DOWN.store(true, Ordering::SeqCst);
+    match keystore.and_then(|mut keystore| keystore.wipe_all()) {
+        Ok(()) => info!("Keystore wiped again"),
+        Err(e) => error!("Keystore wipe at boot failed: {}", e),
+    }
+    loop {
+        match log.last_event() {
+            Some(at) => warn!("Device locked down after tamper detection at {}, erase NVS to recover", at),
+            None => warn!("Device locked down after tamper detection, erase NVS to recover"),
+        }
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
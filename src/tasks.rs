@@ -0,0 +1,213 @@
+use std::ffi::CStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+use esp_idf_svc::sys::{esp_restart, uxTaskGetNumberOfTasks, uxTaskGetSystemState, vTaskPrioritySet, TaskStatus_t};
+use log::{error, info, warn};
+
+// The firmware's task layout. Each part runs on a FreeRTOS task of its own at a fixed priority
+// and hands work to the others through bounded queues only, so a slow panel or a burst of
+// button presses can't hold up a TLS session or grow the heap:
+//   network      the WiFi manager, joins and recovers the uplink
+//   rpc          sends the transactions the application signed (see `sender`)
+//   application  the main task running the mode's loop, which owns the signer
+//   ui           the status display
+// Peripheral threads spawned with std::thread get ESP-IDF's default priority of 5, the same as
+// the application and below network and rpc, so a new peripheral can't starve the TLS stack.
+// The supervisor restarts the device once a watched task stops reporting in, and watches the
+// stack high-water marks of every task, ESP-IDF's own included.
+
+pub struct TaskSpec {
+    // NUL-terminated, FreeRTOS copies it into the task
+    pub name: &'static [u8],
+    // In bytes, the default for NETWORK, RPC and UI, which `StackConfig` can change
+    pub stack_size: usize,
+    // 1 to 24, lwIP runs at 18 and the WiFi driver at 23
+    pub priority: u8,
+}
+
+impl TaskSpec {
+    fn label(&self) -> &'static str {
+        std::str::from_utf8(&self.name[..self.name.len() - 1]).unwrap_or("task")
+    }
+}
+
+// Joining runs the driver calls, the supplicant setup and logging
+pub const NETWORK: TaskSpec = TaskSpec {
+    name: b"network\0",
+    stack_size: 8 * 1024,
+    priority: 7,
+};
+// TLS handshakes run on it
+pub const RPC: TaskSpec = TaskSpec {
+    name: b"rpc\0",
+    stack_size: 12 * 1024,
+    priority: 6,
+};
+// The main task's stack is CONFIG_ESP_MAIN_TASK_STACK_SIZE, only its priority is set here
+pub const APPLICATION_PRIORITY: u8 = 5;
+// Rendering and the balance lookup over TLS, below everything that moves money
+pub const UI: TaskSpec = TaskSpec {
+    name: b"ui\0",
+    stack_size: 8 * 1024,
+    priority: 3,
+};
+const SUPERVISOR: TaskSpec = TaskSpec {
+    name: b"supervisor\0",
+    stack_size: 4 * 1024,
+    priority: 2,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const STACK_MARGIN: u32 = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackConfig {
+    // Stack sizes in bytes of the firmware's own tasks. The main task's is
+    // CONFIG_ESP_MAIN_TASK_STACK_SIZE, peripheral threads have theirs in their module.
+    pub network: usize,
+    pub rpc: usize,
+    pub ui: usize,
+    // A task with fewer bytes than this left is warned about, once. An overflow corrupts the
+    // heap instead of panicking.
+    pub margin: u32,
+    // How often the remaining stack of every task is logged, None only warns
+    pub report_interval: Option<Duration>,
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        Self {
+            network: NETWORK.stack_size,
+            rpc: RPC.stack_size,
+            ui: UI.stack_size,
+            margin: STACK_MARGIN,
+            report_interval: None,
+        }
+    }
+}
+
+struct Watched {
+    name: &'static str,
+    deadline: Duration,
+    beat: Instant,
+}
+
+static WATCHED: Mutex<Vec<Watched>> = Mutex::new(Vec::new());
+// Set by `start`, the specs' own sizes apply until then
+static STACKS: Mutex<Option<StackConfig>> = Mutex::new(None);
+
+fn stack_size(spec: &TaskSpec) -> usize {
+    let Some(config) = *STACKS.lock().unwrap() else {
+        return spec.stack_size;
+    };
+    match spec.name {
+        name if name == NETWORK.name => config.network,
+        name if name == RPC.name => config.rpc,
+        name if name == UI.name => config.ui,
+        _ => spec.stack_size,
+    }
+}
+
+// Starts `f` as the task `spec` describes
+pub fn spawn<F, T>(spec: &TaskSpec, f: F) -> Result<(), String>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let stack_size = stack_size(spec);
+    ThreadSpawnConfiguration {
+        name: Some(spec.name),
+        stack_size,
+        priority: spec.priority,
+        ..Default::default()
+    }
+    .set()
+    .map_err(|e| format!("{} task config: {:?}", spec.label(), e))?;
+    let spawned = std::thread::Builder::new()
+        .name(spec.label().to_string())
+        .stack_size(stack_size)
+        .spawn(f);
+    // Back to the defaults for whatever this thread spawns next
+    ThreadSpawnConfiguration::default()
+        .set()
+        .map_err(|e| format!("Task config reset: {:?}", e))?;
+    spawned
+        .map(|_| ())
+        .map_err(|e| format!("{} task: {:?}", spec.label(), e))
+}
+
+// Raises the calling main task to the application priority and starts the supervisor. Called
+// before the tasks `stacks` sizes are spawned, they keep the default size otherwise.
+pub fn start(stacks: StackConfig) -> Result<(), String> {
+    unsafe { vTaskPrioritySet(core::ptr::null_mut(), APPLICATION_PRIORITY as u32) };
+    *STACKS.lock().unwrap() = Some(stacks);
+    spawn(&SUPERVISOR, move || supervise(stacks))?;
+    info!("Task supervisor up");
+    Ok(())
+}
+
+// Reports the calling task alive. It is restarted along with the device if it doesn't report
+// again within `deadline`, so tasks waiting on a queue wake up with a timeout to report.
+pub fn beat(name: &'static str, deadline: Duration) {
+    let mut watched = WATCHED.lock().unwrap();
+    match watched.iter_mut().find(|task| task.name == name) {
+        Some(task) => {
+            task.deadline = deadline;
+            task.beat = Instant::now();
+        }
+        None => watched.push(Watched {
+            name,
+            deadline,
+            beat: Instant::now(),
+        }),
+    }
+}
+
+// Name and the fewest bytes of stack it has had left, for every task. Needs
+// CONFIG_FREERTOS_USE_TRACE_FACILITY, which sdkconfig.defaults sets.
+pub fn stack_watermarks() -> Vec<(String, u32)> {
+    // Room for tasks created between the count and the snapshot
+    let capacity = unsafe { uxTaskGetNumberOfTasks() } as usize + 4;
+    let mut statuses = Vec::<TaskStatus_t>::with_capacity(capacity);
+    let count = unsafe { uxTaskGetSystemState(statuses.as_mut_ptr(), capacity as _, core::ptr::null_mut()) };
+    unsafe { statuses.set_len(count as usize) };
+    statuses
+        .iter()
+        .map(|status| {
+            let name = unsafe { CStr::from_ptr(status.pcTaskName) };
+            (name.to_string_lossy().into_owned(), status.usStackHighWaterMark)
+        })
+        .collect()
+}
+
+fn supervise(stacks: StackConfig) {
+    let mut warned = Vec::<String>::new();
+    let mut reported = Instant::now();
+    loop {
+        std::thread::sleep(CHECK_INTERVAL);
+        let watermarks = stack_watermarks();
+        for (name, free) in &watermarks {
+            if *free < stacks.margin && !warned.contains(name) {
+                warn!("Task {} has {} bytes of stack left", name, free);
+                warned.push(name.clone());
+            }
+        }
+        if stacks.report_interval.is_some_and(|interval| reported.elapsed() >= interval) {
+            let report: Vec<String> = watermarks.iter().map(|(name, free)| format!("{} {}", name, free)).collect();
+            info!("Stack left in bytes: {}", report.join(", "));
+            reported = Instant::now();
+        }
+        for task in WATCHED.lock().unwrap().iter() {
+            if task.beat.elapsed() > task.deadline {
+                error!(
+                    "Task {} stalled for {}s, restarting",
+                    task.name,
+                    task.beat.elapsed().as_secs()
+                );
+                unsafe { esp_restart() };
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_svc::hal::task::watchdog::{TWDTConfig, TWDTDriver, TWDT};
+use esp_idf_svc::sys::{esp_task_wdt_add, esp_task_wdt_delete, esp_task_wdt_reset, ESP_OK};
+use log::{info, warn};
+
+// Network calls run under the ESP-IDF task watchdog, whichever thread makes them. The HTTP
+// client's timeout only bounds each socket operation, a TLS handshake or read that wedges inside
+// mbedTLS or lwIP blocks forever while the device looks alive. A supervised thread that goes
+// longer than the timeout between feeds panics instead, and the panic reboots the device.
+
+static DRIVER: Mutex<Option<TWDTDriver<'static>>> = Mutex::new(None);
+
+thread_local! {
+    // Supervised sections the current thread is in, it is subscribed while there is one
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+// The timeout has to outlast the longest blocking step of a healthy call
+pub fn start(twdt: TWDT, timeout: Duration) -> Result<(), String> {
+    if ResetReason::get() == ResetReason::TaskWatchdog {
+        warn!("Restarted by the task watchdog after a network call hung");
+    }
+    let config = TWDTConfig {
+        duration: timeout,
+        panic_on_trigger: true,
+        ..Default::default()
+    };
+    let driver = TWDTDriver::new(twdt, &config).map_err(|e| format!("Task watchdog init: {:?}", e))?;
+    *DRIVER.lock().unwrap() = Some(driver);
+    info!("Task watchdog armed, {}s", timeout.as_secs());
+    Ok(())
+}
+
+// Subscribes the current thread to the watchdog until dropped. Nested sections share the
+// outermost subscription, without a started watchdog this does nothing.
+pub fn supervise() -> Supervised {
+    if DRIVER.lock().unwrap().is_none() {
+        return Supervised { active: false };
+    }
+    if DEPTH.get() == 0 {
+        let ret = unsafe { esp_task_wdt_add(core::ptr::null_mut()) };
+        if ret != ESP_OK {
+            warn!("Task watchdog subscribe: {}", ret);
+            return Supervised { active: false };
+        }
+    }
+    DEPTH.set(DEPTH.get() + 1);
+    Supervised { active: true }
+}
+
+// Called between the blocking steps of a supervised section
+pub fn feed() {
+    if DEPTH.get() > 0 {
+        unsafe { esp_task_wdt_reset() };
+    }
+}
+
+pub struct Supervised {
+    active: bool,
+}
+
+impl Drop for Supervised {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        DEPTH.set(DEPTH.get() - 1);
+        if DEPTH.get() == 0 {
+            unsafe { esp_task_wdt_delete(core::ptr::null_mut()) };
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use base64::{engine::general_purpose, Engine as _};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde_json::json;
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Signature;
+
+use crate::signer::{DeviceSigner, OFFCHAIN_PREFIX};
+use crate::spend::unix_time;
+
+const TELEMETRY_NAMESPACE: &str = "telemetry";
+const SEQUENCE_KEY: &str = "seq_reserved";
+// Sequence numbers are reserved in blocks so every reading doesn't cost a flash write,
+// a reboot skips the rest of the block but never reuses a number
+const SEQUENCE_BLOCK: u64 = 64;
+
+// Frame layout, all integers little-endian:
+//   magic (16) | version (1) | device pubkey (32) | sequence (8) | unix time, 0 if unknown (8)
+//   | payload length (4) | payload
+// The magic starts with the off-chain prefix, so a telemetry signature is never valid for a transaction.
+const MAGIC: &[u8; 16] = b"\xffREsp32Sol-telem";
+const _: () = assert!(MAGIC[0] == OFFCHAIN_PREFIX);
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 16 + 1 + 32 + 8 + 8 + 4;
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryFrame {
+    pub device: Pubkey,
+    pub sequence: u64,
+    pub timestamp: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+impl TelemetryFrame {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        frame.extend_from_slice(MAGIC);
+        frame.push(VERSION);
+        frame.extend_from_slice(self.device.as_ref());
+        frame.extend_from_slice(&self.sequence.to_le_bytes());
+        frame.extend_from_slice(&self.timestamp.unwrap_or(0).to_le_bytes());
+        frame.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&self.payload);
+        frame
+    }
+
+    pub fn from_bytes(frame: &[u8]) -> Result<Self, String> {
+        if frame.len() < HEADER_LEN || !frame.starts_with(MAGIC) {
+            return Err("Not a telemetry frame".to_string());
+        }
+        if frame[16] != VERSION {
+            return Err(format!("Unsupported telemetry frame version {}", frame[16]));
+        }
+
+        let u64_at = |i: usize| u64::from_le_bytes(frame[i..i + 8].try_into().unwrap());
+        let payload_len = u32::from_le_bytes(frame[65..69].try_into().unwrap()) as usize;
+        if frame.len() != HEADER_LEN + payload_len {
+            return Err("Telemetry payload length mismatch".to_string());
+        }
+
+        Ok(Self {
+            device: Pubkey::try_from(&frame[17..49]).map_err(|e| format!("Device pubkey: {:?}", e))?,
+            sequence: u64_at(49),
+            timestamp: Some(u64_at(57)).filter(|timestamp| *timestamp != 0),
+            payload: frame[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+// A frame with its detached ed25519 signature by the device key
+#[derive(Debug, Clone)]
+pub struct SignedTelemetry {
+    pub frame: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl SignedTelemetry {
+    // Binary envelope for MQTT: frame followed by the 64-byte signature
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut envelope = self.frame.clone();
+        envelope.extend_from_slice(self.signature.as_ref());
+        envelope
+    }
+
+    // JSON envelope for HTTP: {"frame": <base64>, "sig": <base58>}
+    pub fn to_json(&self) -> String {
+        json!({
+            "frame": general_purpose::STANDARD.encode(&self.frame),
+            "sig": self.signature.to_string(),
+        })
+        .to_string()
+    }
+
+    // What a backend does with a binary envelope: check the signature against the device
+    // key named in the frame, the caller then decides whether it trusts that device
+    pub fn verify(envelope: &[u8]) -> Result<TelemetryFrame, String> {
+        let split = envelope
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .ok_or("Telemetry envelope too short")?;
+        let (frame, signature) = envelope.split_at(split);
+
+        let parsed = TelemetryFrame::from_bytes(frame)?;
+        let signature = Signature::try_from(signature).map_err(|e| format!("Signature: {:?}", e))?;
+        if !signature.verify(parsed.device.as_ref(), frame) {
+            return Err("Telemetry signature does not match the device key".to_string());
+        }
+
+        Ok(parsed)
+    }
+}
+
+// Signs telemetry readings with a sequence number that keeps increasing across reboots,
+// so backends can drop replayed or reordered readings
+pub struct TelemetrySigner {
+    nvs: EspNvs<NvsDefault>,
+    next: u64,
+    reserved: u64,
+}
+
+impl TelemetrySigner {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, TELEMETRY_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        let reserved = nvs
+            .get_u64(SEQUENCE_KEY)
+            .map_err(|e| format!("Telemetry sequence read: {:?}", e))?
+            .unwrap_or(0);
+
+        Ok(Self {
+            nvs,
+            next: reserved,
+            reserved,
+        })
+    }
+
+    pub fn sign(&mut self, signer: &DeviceSigner, payload: &[u8]) -> Result<SignedTelemetry, String> {
+        if self.next >= self.reserved {
+            let reserved = self.next + SEQUENCE_BLOCK;
+            self.nvs
+                .set_u64(SEQUENCE_KEY, reserved)
+                .map_err(|e| format!("Telemetry sequence store: {:?}", e))?;
+            self.reserved = reserved;
+        }
+
+        let frame = TelemetryFrame {
+            device: signer.pubkey(),
+            sequence: self.next,
+            timestamp: unix_time(),
+            payload: payload.to_vec(),
+        }
+        .to_bytes();
+        let signature = signer.sign_offchain(&frame).map_err(|e| e.to_string())?;
+        self.next += 1;
+
+        Ok(SignedTelemetry { frame, signature })
+    }
+}
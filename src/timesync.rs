@@ -0,0 +1,69 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncMode};
+use esp_idf_svc::sys::sntp_set_sync_interval;
+use log::{info, warn};
+
+// Only as many are used as CONFIG_LWIP_SNTP_MAX_SERVERS allows, 1 by default
+const SERVERS: [&str; 3] = ["pool.ntp.org", "time.google.com", "time.cloudflare.com"];
+// Refresh while running, so RTC drift doesn't creep into the spend windows
+const SYNC_INTERVAL: Duration = Duration::from_secs(3600);
+// How long the clock counts as trusted after the last sync, the RTC drifts a few seconds a day
+const TRUST_WINDOW: Duration = Duration::from_secs(48 * 3600);
+// Boot waits this long for the first sync before carrying on without it
+const BOOT_SYNC_TIMEOUT: Duration = Duration::from_secs(15);
+
+static LAST_SYNC: Mutex<Option<Instant>> = Mutex::new(None);
+static SYNCED: Condvar = Condvar::new();
+
+// Starts SNTP and waits briefly for the first sync. The returned handle keeps it running.
+pub fn start() -> Result<EspSntp<'static>, String> {
+    let mut conf = SntpConf {
+        // Jump to the right time on the first sync instead of slewing towards it for hours
+        sync_mode: SyncMode::Immediate,
+        ..Default::default()
+    };
+    for (slot, server) in conf.servers.iter_mut().zip(SERVERS) {
+        *slot = server;
+    }
+
+    unsafe { sntp_set_sync_interval(SYNC_INTERVAL.as_millis() as u32) };
+    let sntp = EspSntp::new_with_callback(&conf, |_| {
+        *LAST_SYNC.lock().unwrap() = Some(Instant::now());
+        SYNCED.notify_all();
+    })
+    .map_err(|e| format!("SNTP start: {:?}", e))?;
+
+    match wait_for_sync(BOOT_SYNC_TIMEOUT) {
+        true => info!("Clock synced: {}", trusted_unix_time().unwrap_or(0)),
+        false => warn!("Clock not synced yet, time-dependent checks refuse until it is"),
+    }
+    Ok(sntp)
+}
+
+// Blocks until the clock has been synced at least once, false on timeout
+pub fn wait_for_sync(timeout: Duration) -> bool {
+    let last_sync = LAST_SYNC.lock().unwrap();
+    let (last_sync, _) = SYNCED
+        .wait_timeout_while(last_sync, timeout, |last_sync| last_sync.is_none())
+        .unwrap();
+    last_sync.is_some()
+}
+
+// Synced since boot and recently enough for the RTC not to have drifted far
+pub fn time_trusted() -> bool {
+    last_sync_age().is_some_and(|age| age < TRUST_WINDOW)
+}
+
+pub fn last_sync_age() -> Option<Duration> {
+    LAST_SYNC.lock().unwrap().map(|at| at.elapsed())
+}
+
+// Wall clock time, only when it is trusted
+pub fn trusted_unix_time() -> Option<u64> {
+    match time_trusted() {
+        true => SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|now| now.as_secs()),
+        false => None,
+    }
+}
@@ -0,0 +1,142 @@
+use std::cell::Cell;
+use std::ffi::{c_int, c_void};
+
+use base64::{engine::general_purpose, Engine as _};
+use esp_idf_svc::sys::{
+    esp_crt_bundle_attach, esp_err_t, mbedtls_ssl_conf_verify, mbedtls_ssl_config, mbedtls_x509_crt,
+    ESP_ERR_INVALID_STATE, ESP_OK, MBEDTLS_X509_BADCERT_NOT_TRUSTED,
+};
+use log::warn;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertPin {
+    // SHA-256 of the DER encoded certificate
+    Certificate([u8; 32]),
+    // SHA-256 of the DER encoded SubjectPublicKeyInfo, survives certificate renewals with the same key
+    PublicKey([u8; 32]),
+}
+
+impl CertPin {
+    // Parses the base64 "pin-sha256" format, as printed by:
+    // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    pub fn public_key_base64(pin: &str) -> Result<Self, String> {
+        let bytes = general_purpose::STANDARD
+            .decode(pin.trim())
+            .map_err(|e| format!("Pin decode: {:?}", e))?;
+        let hash: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| "Pin must be a SHA-256 hash".to_string())?;
+
+        Ok(CertPin::PublicKey(hash))
+    }
+
+    fn matches(&self, der: &[u8], spki: &[u8]) -> bool {
+        match self {
+            CertPin::Certificate(hash) => Sha256::digest(der).as_slice() == hash,
+            CertPin::PublicKey(hash) => Sha256::digest(spki).as_slice() == hash,
+        }
+    }
+}
+
+// Signature of the crt_bundle_attach hook in the HTTP client configuration
+pub type CrtBundleAttach = unsafe extern "C" fn(*mut c_void) -> esp_err_t;
+
+type VerifyFn = unsafe extern "C" fn(*mut c_void, *mut mbedtls_x509_crt, c_int, *mut u32) -> c_int;
+
+// The pins of one connection and the verify state of its handshake, handed to the verify
+// callback as its user data. The caller keeps it alive for as long as the connection.
+pub struct PinnedVerify<'a> {
+    pins: &'a [CertPin],
+    bundle_callback: Cell<Option<VerifyFn>>,
+    bundle_context: Cell<*mut c_void>,
+    // mbedtls verifies the chain top-down, ending at depth 0 (the leaf)
+    chain_matched: Cell<bool>,
+}
+
+thread_local! {
+    // The context for connections set up on this task, the attach hook gets no user data of
+    // its own. The handshake runs on the task that makes the request.
+    static ARMED: Cell<*const c_void> = const { Cell::new(core::ptr::null()) };
+}
+
+impl<'a> PinnedVerify<'a> {
+    pub fn new(pins: &'a [CertPin]) -> Self {
+        Self {
+            pins,
+            bundle_callback: Cell::new(None),
+            bundle_context: Cell::new(core::ptr::null_mut()),
+            chain_matched: Cell::new(false),
+        }
+    }
+
+    // Makes this the context of the connections this task opens until it is dropped. It must
+    // not move while armed.
+    pub fn arm(&self) {
+        ARMED.with(|armed| armed.set(self as *const Self as *const c_void));
+    }
+}
+
+impl Drop for PinnedVerify<'_> {
+    fn drop(&mut self) {
+        ARMED.with(|armed| {
+            if armed.get() == self as *const Self as *const c_void {
+                armed.set(core::ptr::null());
+            }
+        });
+    }
+}
+
+// Drop-in replacement for esp_crt_bundle_attach: the chain must still validate against the
+// CA bundle, and additionally some certificate in it has to match one of the armed context's
+// pins. Without an armed context the connection is refused.
+pub unsafe extern "C" fn pinned_crt_bundle_attach(conf: *mut c_void) -> esp_err_t {
+    let verify = ARMED.with(Cell::get) as *const PinnedVerify;
+    if verify.is_null() {
+        warn!("TLS pinning requested without pins, connection refused");
+        return ESP_ERR_INVALID_STATE;
+    }
+
+    let ret = esp_crt_bundle_attach(conf);
+    if ret != ESP_OK {
+        return ret;
+    }
+
+    // Called once per handshake, so nothing matched in an earlier one carries over
+    let ssl_conf = conf as *mut mbedtls_ssl_config;
+    (*verify).bundle_callback.set((*ssl_conf).private_f_vrfy);
+    (*verify).bundle_context.set((*ssl_conf).private_p_vrfy);
+    (*verify).chain_matched.set(false);
+    mbedtls_ssl_conf_verify(ssl_conf, Some(verify_pinned), verify as *mut c_void);
+
+    ESP_OK
+}
+
+unsafe extern "C" fn verify_pinned(
+    context: *mut c_void,
+    crt: *mut mbedtls_x509_crt,
+    depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    let verify = &*(context as *const PinnedVerify);
+    if let Some(callback) = verify.bundle_callback.get() {
+        let ret = callback(verify.bundle_context.get(), crt, depth, flags);
+        if ret != 0 {
+            return ret;
+        }
+    }
+
+    let cert = &*crt;
+    let der = core::slice::from_raw_parts(cert.raw.p, cert.raw.len);
+    let spki = core::slice::from_raw_parts(cert.pk_raw.p, cert.pk_raw.len);
+
+    let matched = verify.pins.iter().any(|pin| pin.matches(der, spki));
+    verify.chain_matched.set(verify.chain_matched.get() || matched);
+
+    if depth == 0 && !verify.chain_matched.replace(false) {
+        warn!("TLS certificate chain doesn't match any configured pin");
+        *flags |= MBEDTLS_X509_BADCERT_NOT_TRUSTED;
+    }
+
+    0
+}
@@ -0,0 +1,133 @@
+#[cfg(feature = "spl")]
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::pubkey;
+use solana_program::pubkey::Pubkey;
+
+pub use resp32sol_core::inspect::{TOKEN_2022_PROGRAM_ID, TOKEN_PROGRAM_ID};
+
+// SPL Token instructions are encoded by hand to avoid pulling in spl-token and its dependency tree.
+// The instructions are behind `spl`, the program IDs and account derivation stay for the policy
+// checks on transactions the device is asked to sign.
+
+pub const ASSOCIATED_TOKEN_PROGRAM_ID: Pubkey = pubkey!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+
+#[cfg(feature = "spl")]
+const TRANSFER_CHECKED: u8 = 12;
+#[cfg(feature = "spl")]
+const SET_AUTHORITY: u8 = 6;
+#[cfg(feature = "spl")]
+const CLOSE_ACCOUNT: u8 = 9;
+#[cfg(feature = "spl")]
+const ATA_CREATE_IDEMPOTENT: u8 = 1;
+
+#[cfg(feature = "spl")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorityType {
+    MintTokens = 0,
+    FreezeAccount = 1,
+    AccountOwner = 2,
+    CloseAccount = 3,
+}
+
+pub fn associated_token_address(owner: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[owner.as_ref(), token_program.as_ref(), mint.as_ref()],
+        &ASSOCIATED_TOKEN_PROGRAM_ID,
+    )
+    .0
+}
+
+#[cfg(feature = "spl")]
+pub fn create_associated_token_account_idempotent(
+    payer: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: ASSOCIATED_TOKEN_PROGRAM_ID,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(associated_token_address(owner, mint, token_program), false),
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(solana_system_interface::program::ID, false),
+            AccountMeta::new_readonly(*token_program, false),
+        ],
+        data: vec![ATA_CREATE_IDEMPOTENT],
+    }
+}
+
+#[cfg(feature = "spl")]
+pub fn transfer_checked(
+    token_program: &Pubkey,
+    source: &Pubkey,
+    mint: &Pubkey,
+    destination: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+    decimals: u8,
+) -> Instruction {
+    let mut data = Vec::with_capacity(10);
+    data.push(TRANSFER_CHECKED);
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.push(decimals);
+
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*source, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new_readonly(*owner, true),
+        ],
+        data,
+    }
+}
+
+#[cfg(feature = "spl")]
+pub fn set_authority(
+    token_program: &Pubkey,
+    account: &Pubkey,
+    new_authority: Option<&Pubkey>,
+    authority_type: AuthorityType,
+    current_authority: &Pubkey,
+) -> Instruction {
+    let mut data = Vec::with_capacity(35);
+    data.push(SET_AUTHORITY);
+    data.push(authority_type as u8);
+    match new_authority {
+        Some(authority) => {
+            data.push(1);
+            data.extend_from_slice(authority.as_ref());
+        }
+        None => data.push(0),
+    }
+
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*account, false),
+            AccountMeta::new_readonly(*current_authority, true),
+        ],
+        data,
+    }
+}
+
+#[cfg(feature = "spl")]
+pub fn close_account(
+    token_program: &Pubkey,
+    account: &Pubkey,
+    destination: &Pubkey,
+    owner: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*account, false),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new_readonly(*owner, true),
+        ],
+        data: vec![CLOSE_ACCOUNT],
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::{
+    esp_err_t, esp_sleep_enable_touchpad_wakeup, touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER, touch_pad_config,
+    touch_pad_filter_start, touch_pad_init, touch_pad_read_filtered, touch_pad_set_fsm_mode, touch_pad_set_thresh,
+    touch_pad_t, ESP_OK,
+};
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::Message;
+
+use crate::approval::{describe, needs_approval};
+#[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+use crate::display;
+use crate::error::WalletError;
+use crate::qr::{wallet_uri, QrMatrix};
+use crate::signer::SigningHook;
+
+// Capacitive touch on one of the classic ESP32's touch pads, a bare wire or a copper pad under
+// the case. A finger adds capacitance, which makes the pad's count drop below the untouched
+// baseline. A tap reveals the address QR code, holding the finger on approves a pending large
+// transfer, and the pad wakes the chip from deep sleep.
+
+// The hardware filter's IIR period
+const FILTER_PERIOD_MS: u32 = 10;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(20);
+const DEBOUNCE: Duration = Duration::from_millis(60);
+// Averaged at start with nothing on the pad
+const CALIBRATION_TIME: Duration = Duration::from_secs(1);
+// Untouched readings pull the baseline along by this share, humidity and temperature drift it
+const DRIFT_SHARE: u32 = 64;
+// A touch ends once the count is back above halfway between threshold and baseline
+const RELEASE_SHARE: u32 = 2;
+// The QR code replaces the status screen this long after a tap
+#[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+const REVEAL_FOR: Duration = Duration::from_secs(30);
+const TOUCH_STACK_SIZE: usize = 6 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TouchConfig {
+    // T0 to T9, e.g. touch_pad_t_TOUCH_PAD_NUM6 for GPIO14
+    pub pad: touch_pad_t,
+    // A touch drops the count by at least this share of the baseline
+    pub touched_percent: u32,
+    // Held at least this long, a touch approves instead of revealing the address
+    pub long_touch: Duration,
+    // Transfers up to this amount are signed without a touch
+    pub threshold_lamports: u64,
+    pub timeout: Duration,
+}
+
+struct Pad {
+    pad: touch_pad_t,
+    touched_percent: u32,
+    baseline: u32,
+}
+
+impl Pad {
+    fn threshold(&self) -> u16 {
+        (self.baseline * (100 - self.touched_percent.min(99)) / 100) as u16
+    }
+
+    fn release(&self) -> u16 {
+        let threshold = self.threshold() as u32;
+        (threshold + (self.baseline - threshold) / RELEASE_SHARE) as u16
+    }
+}
+
+// Set by the touch thread, read when going to sleep
+static PAD: Mutex<Option<Pad>> = Mutex::new(None);
+
+// Requires a long touch on the pad before signing large transfers
+pub struct TouchApproval {
+    config: TouchConfig,
+    long_touches: Receiver<()>,
+}
+
+// Calibrates the pad, which has to be untouched meanwhile, then follows it on a thread of its own
+pub fn start(device: Pubkey, config: TouchConfig) -> Result<TouchApproval, String> {
+    check(unsafe { touch_pad_init() }, "Touch init")?;
+    check(
+        unsafe { touch_pad_set_fsm_mode(touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER) },
+        "Touch FSM mode",
+    )?;
+    check(unsafe { touch_pad_config(config.pad, 0) }, "Touch pad config")?;
+    check(unsafe { touch_pad_filter_start(FILTER_PERIOD_MS) }, "Touch filter")?;
+    // The filter needs a few periods before its first value
+    std::thread::sleep(Duration::from_millis(FILTER_PERIOD_MS as u64 * 10));
+
+    let started = Instant::now();
+    let (mut sum, mut samples) = (0u32, 0u32);
+    while started.elapsed() < CALIBRATION_TIME {
+        sum += read(config.pad)? as u32;
+        samples += 1;
+        std::thread::sleep(SAMPLE_INTERVAL);
+    }
+    let baseline = sum / samples.max(1);
+    if baseline == 0 {
+        return Err("Touch pad reads 0, check the wiring".to_string());
+    }
+    let pad = Pad {
+        pad: config.pad,
+        touched_percent: config.touched_percent,
+        baseline,
+    };
+    info!("Touch pad calibrated, baseline {} and threshold {}", baseline, pad.threshold());
+    *PAD.lock().unwrap() = Some(pad);
+
+    let (approvals, long_touches) = sync_channel(1);
+    std::thread::Builder::new()
+        .name("touch".to_string())
+        .stack_size(TOUCH_STACK_SIZE)
+        .spawn(move || run(device, config, approvals))
+        .map_err(|e| format!("Touch thread: {:?}", e))?;
+    Ok(TouchApproval { config, long_touches })
+}
+
+// Arms the pad as a deep sleep wake-up source at its current threshold, false when the pad
+// isn't up. Only the transfer demo's loop sleeps.
+pub fn enable_wakeup() -> Result<bool, String> {
+    let pad = PAD.lock().unwrap();
+    let Some(pad) = pad.as_ref() else {
+        return Ok(false);
+    };
+    check(unsafe { touch_pad_set_thresh(pad.pad, pad.threshold()) }, "Touch threshold")?;
+    check(unsafe { esp_sleep_enable_touchpad_wakeup() }, "Touch wake-up")?;
+    Ok(true)
+}
+
+fn run(device: Pubkey, config: TouchConfig, approvals: SyncSender<()>) {
+    // Since when the pad is down, and whether that touch already approved
+    let mut touched: Option<(Instant, bool)> = None;
+    let mut below_since: Option<Instant> = None;
+    #[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+    let mut revealed: Option<Instant> = None;
+
+    loop {
+        std::thread::sleep(SAMPLE_INTERVAL);
+        let value = match read(config.pad) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        let (threshold, release) = {
+            let mut pad = PAD.lock().unwrap();
+            let Some(pad) = pad.as_mut() else {
+                return;
+            };
+            if touched.is_none() && value >= pad.release() {
+                pad.baseline = (pad.baseline * (DRIFT_SHARE - 1) + value as u32) / DRIFT_SHARE;
+            }
+            (pad.threshold(), pad.release())
+        };
+
+        match touched {
+            None if value < threshold => {
+                let since = *below_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= DEBOUNCE {
+                    touched = Some((since, false));
+                }
+            }
+            None => below_since = None,
+            Some((since, false)) if value < release && since.elapsed() >= config.long_touch => {
+                info!("Long touch");
+                // Only a check waiting for one takes it, the channel holds no more than one
+                let _ = approvals.try_send(());
+                touched = Some((since, true));
+            }
+            Some(_) if value < release => {}
+            Some((_, approved)) => {
+                touched = None;
+                below_since = None;
+                if !approved {
+                    reveal(&device);
+                    #[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+                    {
+                        revealed = Some(Instant::now());
+                    }
+                }
+            }
+        }
+
+        #[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+        if revealed.is_some_and(|revealed| revealed.elapsed() >= REVEAL_FOR) {
+            display::clear_overlay();
+            revealed = None;
+        }
+    }
+}
+
+fn reveal(device: &Pubkey) {
+    let qr = match QrMatrix::encode(&wallet_uri(device)) {
+        Ok(qr) => qr,
+        Err(e) => {
+            warn!("Address QR code: {}", e);
+            return;
+        }
+    };
+    info!("Scan to fund {}:\n{}", device, qr.to_terminal_string());
+    #[cfg(all(feature = "oled-display", not(feature = "remote-signer")))]
+    if let Err(e) = display::show_request(&qr, &["Fund", "this", "wallet"]) {
+        warn!("Address QR code on display: {}", e);
+    }
+}
+
+fn read(pad: touch_pad_t) -> Result<u16, String> {
+    let mut value = 0u16;
+    check(unsafe { touch_pad_read_filtered(pad, &mut value) }, "Touch read")?;
+    Ok(value)
+}
+
+fn check(ret: esp_err_t, what: &str) -> Result<(), String> {
+    match ret {
+        ESP_OK => Ok(()),
+        ret => Err(format!("{}: {}", what, ret)),
+    }
+}
+
+impl SigningHook for TouchApproval {
+    fn check(&self, message: &Message, signer: &Pubkey) -> Result<(), WalletError> {
+        if !needs_approval(message, signer, self.config.threshold_lamports) {
+            return Ok(());
+        }
+
+        // A long touch from before the request doesn't count
+        while self.long_touches.try_recv().is_ok() {}
+        info!(
+            "Hold the touch pad for {}s within {}s to approve {}",
+            self.config.long_touch.as_secs_f32(),
+            self.config.timeout.as_secs(),
+            describe(message, signer)
+        );
+        match self.long_touches.recv_timeout(self.config.timeout) {
+            Ok(()) => {
+                info!("Transaction approved on device");
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) => Err(WalletError::Refused("Approval timed out".to_string())),
+            Err(RecvTimeoutError::Disconnected) => Err(WalletError::Refused("Touch pad stopped".to_string())),
+        }
+    }
+}
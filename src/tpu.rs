@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use solana_program::pubkey::Pubkey;
+use solana_transaction::{Signature, Transaction};
+
+use crate::error::RpcError;
+use crate::solrpc::{self, rpc_config, SolanaRpcMethod};
+
+// Experimental: hands transactions straight to the upcoming leaders' TPU (transaction processing
+// unit) instead of going through an RPC node's sendTransaction queue, which saves the RPC node's
+// forwarding hop for time-critical actions.
+//
+// Validators take transactions over QUIC on their `tpuQuic` port. ESP-IDF has no QUIC stack, so
+// this sends single datagrams to the UDP `tpu` port that validators still advertise, and only
+// reaches leaders that have it enabled. Leaders advertising QUIC only are skipped. Nothing
+// acknowledges a datagram, so send_transaction falls back to RPC unless the cluster has seen
+// the transaction shortly after.
+
+// The next leaders get a copy each, the current one may be about to hand over (4 slots per leader)
+const LEADER_SLOTS: u64 = 12;
+// Contact info for the leaders seen recently, gossip addresses rarely change
+const CONTACT_TTL: Duration = Duration::from_secs(600);
+const MAX_CONTACTS: usize = 64;
+// getClusterNodes lists every validator, far more than fits in RAM on mainnet, so its entries
+// are parsed one at a time. An entry is a flat object of a dozen fields.
+const MAX_ENTRY_LEN: usize = 1024;
+// The UDP TPU takes a transaction as a single packet
+const PACKET_DATA_SIZE: usize = 1232;
+// How long a directly sent transaction has to show up before it goes over RPC as well, about
+// the 12 leader slots it was sent for
+const LANDING_WAIT: Duration = Duration::from_secs(5);
+const LANDING_POLL: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+struct Contact {
+    leader: Pubkey,
+    tpu: Option<SocketAddr>,
+    tpu_quic: Option<SocketAddr>,
+    seen: Instant,
+}
+
+static CONTACTS: Mutex<Vec<Contact>> = Mutex::new(Vec::new());
+
+// Sends directly to the leaders, and over RPC when none of them takes UDP or the cluster hasn't
+// seen the transaction within LANDING_WAIT. Sending it again is harmless, a signature is only
+// processed once.
+pub fn send_transaction(transaction: &Transaction) -> Result<String, RpcError> {
+    // The leaders would take it at any fee
+    solrpc::check_fee(transaction)?;
+    match submit(transaction) {
+        Ok(signature) if landed(&transaction.signatures[0])? => return Ok(signature),
+        Ok(signature) => warn!("{} not seen after direct TPU submission, sending over RPC", signature),
+        Err(e) => warn!("Direct TPU submission failed, sending over RPC: {}", e),
+    }
+    solrpc::send_transaction(transaction)
+}
+
+// Whether the cluster has the transaction, a failed one is returned as the error it is
+fn landed(signature: &Signature) -> Result<bool, RpcError> {
+    let deadline = Instant::now() + LANDING_WAIT;
+    while Instant::now() < deadline {
+        std::thread::sleep(LANDING_POLL);
+        match solrpc::get_signature_status(signature) {
+            Ok(Some(_)) => return Ok(true),
+            Err(e @ RpcError::TransactionFailed(_)) => return Err(e),
+            // Not seen yet, or the node didn't answer, which the RPC send settles
+            Ok(None) | Err(_) => {}
+        }
+    }
+    Ok(false)
+}
+
+// Returns the signature once at least one leader was sent the transaction. There is no
+// preflight and no acknowledgement, confirm it with solrpc::confirm_transaction.
+pub fn submit(transaction: &Transaction) -> Result<String, String> {
+    let bytes =
+        bincode::serialize(transaction).map_err(|e| format!("Transaction serialization failed: {:?}", e))?;
+    if bytes.len() > PACKET_DATA_SIZE {
+        return Err(format!("Transaction is {} bytes, over the {} byte packet limit", bytes.len(), PACKET_DATA_SIZE));
+    }
+
+    let slot = solrpc::get_slot().map_err(|e| e.to_string())?;
+    let mut leaders = Vec::new();
+    for leader in solrpc::get_slot_leaders(slot, LEADER_SLOTS).map_err(|e| e.to_string())? {
+        if !leaders.contains(&leader) {
+            leaders.push(leader);
+        }
+    }
+    let contacts = contacts(&leaders)?;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("UDP socket: {:?}", e))?;
+    let mut sent = 0;
+    for contact in &contacts {
+        let Some(tpu) = contact.tpu else {
+            if contact.tpu_quic.is_some() {
+                info!("Leader {} only takes QUIC, skipped", contact.leader);
+            }
+            continue;
+        };
+        match socket.send_to(&bytes, tpu) {
+            Ok(_) => sent += 1,
+            Err(e) => warn!("TPU send to {} ({}): {:?}", contact.leader, tpu, e),
+        }
+    }
+    if sent == 0 {
+        return Err("None of the upcoming leaders takes UDP, QUIC is not supported".to_string());
+    }
+
+    let signature = transaction.signatures[0].to_string();
+    info!("Sent {} to {} of {} upcoming leaders", signature, sent, leaders.len());
+    Ok(signature)
+}
+
+// Contact info for each leader, from the cache or a getClusterNodes scan for the missing ones
+fn contacts(leaders: &[Pubkey]) -> Result<Vec<Contact>, String> {
+    let fresh = |contact: &Contact| leaders.contains(&contact.leader) && contact.seen.elapsed() < CONTACT_TTL;
+    let known: Vec<Contact> = CONTACTS.lock().unwrap().iter().filter(|contact| fresh(contact)).cloned().collect();
+    if known.len() == leaders.len() {
+        return Ok(known);
+    }
+
+    let wanted: HashSet<Pubkey> = leaders.iter().copied().collect();
+    let mut scanner = EntryScanner::new();
+    let mut found = Vec::new();
+    solrpc::rpc_call_streaming(&rpc_config(), SolanaRpcMethod::GetClusterNodes, &mut |data| {
+        scanner.feed(data, &mut |entry| {
+            if let Some(contact) = parse_contact(entry, &wanted) {
+                found.push(contact);
+            }
+        });
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+    if found.is_empty() {
+        return Err("No contact info for the upcoming leaders".to_string());
+    }
+
+    let mut cache = CONTACTS.lock().unwrap();
+    cache.retain(|contact| contact.seen.elapsed() < CONTACT_TTL && !found.iter().any(|new| new.leader == contact.leader));
+    cache.extend(found.iter().cloned());
+    let excess = cache.len().saturating_sub(MAX_CONTACTS);
+    cache.drain(..excess);
+    Ok(found)
+}
+
+fn parse_contact(entry: &[u8], wanted: &HashSet<Pubkey>) -> Option<Contact> {
+    let entry: serde_json::Value = serde_json::from_slice(entry).ok()?;
+    let leader: Pubkey = entry["pubkey"].as_str()?.parse().ok()?;
+    if !wanted.contains(&leader) {
+        return None;
+    }
+    let address = |field: &str| entry[field].as_str().and_then(|address| address.parse().ok());
+    Some(Contact {
+        leader,
+        tpu: address("tpu"),
+        tpu_quic: address("tpuQuic"),
+        seen: Instant::now(),
+    })
+}
+
+// Cuts the objects of the response's `result` array out of the byte stream, so only one entry is
+// held at a time. Tracks string and escape state, braces inside strings don't count.
+struct EntryScanner {
+    depth: u32,
+    in_string: bool,
+    escaped: bool,
+    entry: Vec<u8>,
+    oversized: bool,
+}
+
+impl EntryScanner {
+    fn new() -> Self {
+        Self {
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            entry: Vec::new(),
+            oversized: false,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8], on_entry: &mut dyn FnMut(&[u8])) {
+        for &byte in data {
+            // Depth 1 is the response object, entries are the objects at depth 2
+            let in_entry = self.depth >= 2;
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                }
+            } else {
+                match byte {
+                    b'"' => self.in_string = true,
+                    b'{' => self.depth += 1,
+                    b'}' => self.depth = self.depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+
+            if self.depth >= 2 || in_entry {
+                if self.entry.len() < MAX_ENTRY_LEN {
+                    self.entry.push(byte);
+                } else {
+                    self.oversized = true;
+                }
+            }
+            if in_entry && self.depth == 1 {
+                if !self.oversized {
+                    on_entry(&self.entry);
+                }
+                self.entry.clear();
+                self.oversized = false;
+            }
+        }
+    }
+}
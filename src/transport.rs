@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use embedded_svc::http::client::{Client, Response};
+use embedded_svc::http::Headers;
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::http::{
+    client::{Configuration, EspHttpConnection},
+    Method,
+};
+use esp_idf_svc::sys::{esp_http_client_is_complete_data_received, ESP_ERR_HTTP_EAGAIN};
+
+use crate::buffers;
+use crate::captive;
+use crate::doh;
+use crate::dualstack;
+use crate::error::{NetError, RpcError};
+use crate::net;
+use crate::netwatch;
+use crate::taskwdt;
+use crate::tls_pin::{self, CertPin, CrtBundleAttach, PinnedVerify};
+
+// How RPC requests reach a node. resp32sol-core's client builds the JSON-RPC request and reads
+// the answer, a transport only carries the bytes: ESP-IDF's HTTP client by default, or whatever
+// was passed to solrpc::set_transport, e.g. a modem's own HTTP stack or a host on the other end
+// of the serial port. Core's tests run the client against recorded responses the same way.
+pub use crate::client::{OnData, RpcTransport};
+
+// How long a call waits for the network to come back before failing
+const LINK_WAIT: Duration = Duration::from_secs(60);
+
+// ESP-IDF's HTTP client over the device's uplink, with what every call needs around it: waiting
+// out reconnects, the captive portal check, DoH and dual-stack resolution, the certificate pins
+// and the task watchdog
+pub struct EspTransport<'a> {
+    // When non-empty the server chain must match one of these pins on top of the CA bundle
+    pub pins: &'a [CertPin],
+}
+
+impl RpcTransport for EspTransport<'_> {
+    type Error = RpcError;
+
+    fn post(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        timeout: Duration,
+        on_data: &mut OnData<'_, RpcError>,
+    ) -> Result<(), RpcError> {
+        // Calls pause while the uplink reconnects instead of failing one after another
+        if !net::wait_for_link(LINK_WAIT) {
+            return Err(NetError::LinkDown.into());
+        }
+        if captive::suspected() {
+            return Err(NetError::CaptivePortal.into());
+        }
+        // From here on every step can block in the network stack, a hung one resets the device
+        let _supervised = taskwdt::supervise();
+        // Before anything looks the host up, so the family race connects to the DoH answer too
+        doh::prepare(url)?;
+        taskwdt::feed();
+        dualstack::prepare(url);
+        taskwdt::feed();
+
+        // Outlives the connection below, whose handshakes check against it
+        let pinned = PinnedVerify::new(self.pins);
+        let crt_bundle_attach: CrtBundleAttach = if self.pins.is_empty() {
+            esp_idf_svc::sys::esp_crt_bundle_attach
+        } else {
+            pinned.arm();
+            tls_pin::pinned_crt_bundle_attach
+        };
+
+        let timeout = net::link_quality().rpc_timeout(timeout);
+        let connection = EspHttpConnection::new(&Configuration {
+            timeout: Some(timeout),
+            buffer_size: Some(buffers::config().http_buffer),
+            use_global_ca_store: true,
+            crt_bundle_attach: Some(crt_bundle_attach),
+            ..Default::default()
+        })
+        .map_err(|e| NetError::Connection(format!("HTTP init: {:?}", e)))?;
+        let mut client = Client::wrap(connection);
+
+        // The TLS handshake happens in here
+        let submitted = client
+            .request(Method::Post, url, headers)
+            .map_err(|e| NetError::Connection(format!("Request: {:?}", e)))
+            .and_then(|mut request| {
+                request
+                    .write(body)
+                    .map_err(|e| NetError::Connection(format!("Write: {:?}", e)))?;
+                request
+                    .submit()
+                    .map_err(|e| NetError::Connection(format!("Submit: {:?}", e)))
+            });
+        // Getting any status back means the network works, for the stall watchdog
+        netwatch::record(submitted.is_ok());
+        taskwdt::feed();
+        let mut response = submitted?;
+
+        let status = response.status();
+        if !(200..=299).contains(&status) {
+            return Err(RpcError::Status(status));
+        }
+
+        read_body(&mut response, timeout, on_data)
+    }
+}
+
+// Reads a response body to its end whatever its framing: Content-Length, chunked (decoded by
+// esp_http_client) or neither, in which case the server closing the connection ends it. A
+// connection that closes before a declared length or the last chunk is an error, not a short body.
+// Read errors come back as the error `on_data` returns.
+pub fn read_body<E: From<NetError>>(
+    response: &mut Response<&mut EspHttpConnection>,
+    timeout: Duration,
+    on_data: &mut dyn FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    let framed = response.content_len().is_some()
+        || response
+            .header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+
+    // The socket timeout applies per read, a slow server can stall a read without being gone
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; buffers::config().read_chunk];
+    loop {
+        taskwdt::feed();
+        match response.read(&mut buf) {
+            Ok(0) => break,
+            Ok(size) => on_data(&buf[..size])?,
+            Err(e) if e.0.code() == ESP_ERR_HTTP_EAGAIN as i32 && Instant::now() < deadline => {}
+            Err(e) => return Err(NetError::Connection(format!("Read: {:?}", e)).into()),
+        }
+    }
+
+    if framed && !unsafe { esp_http_client_is_complete_data_received(response.connection().handle()) } {
+        return Err(NetError::Truncated.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, Output, PinDriver};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use serde_json::json;
+use solana_keypair::{Keypair, Signer};
+use solana_program::pubkey::Pubkey;
+
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, Sound};
+#[cfg(feature = "oled-display")]
+use crate::display;
+#[cfg(feature = "receipt-printer")]
+use crate::printer;
+use crate::qr::QrMatrix;
+use crate::solanapay::TransferRequest;
+use crate::solrpc::{get_signatures_for_address, get_transaction};
+use crate::spend::unix_time;
+
+// Coin-operated machines: a fixed Solana Pay request with a reference of the machine's own, on a
+// sticker or the display, and a relay or solenoid driven for a while by every payment of at
+// least the price that carries it. The newest transaction dealt with is kept in NVS, so a reboot
+// neither unlocks again for old payments nor misses those that landed while the device was off.
+
+const UNLOCK_NAMESPACE: &str = "unlock";
+const REFERENCE_KEY: &str = "reference";
+const CURSOR_KEY: &str = "cursor";
+const COUNT_KEY: &str = "count";
+const LAST_KEY: &str = "last";
+// Payments landing faster than this between polls are only partly seen
+const SIGNATURES_PER_POLL: usize = 10;
+// A signature is 88 base58 characters at most
+const SIGNATURE_MAX_LEN: usize = 88;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UnlockConfig {
+    // Decimal in SOL or in the token's units, paying more also unlocks
+    pub price: &'static str,
+    pub spl_token: Option<Pubkey>,
+    // The printed code's reference, None uses one generated on first boot and kept in NVS
+    pub reference: Option<Pubkey>,
+    // Shown by the wallet as who is asking
+    pub label: &'static str,
+    pub message: Option<&'static str>,
+    // How long the relay stays energized per payment
+    pub unlock_for: Duration,
+    // Relay modules differ, many of the opto-isolated ones switch on a low input
+    pub active_high: bool,
+    pub poll_interval: Duration,
+}
+
+struct Relay {
+    pin: PinDriver<'static, AnyIOPin, Output>,
+    active_high: bool,
+}
+
+impl Relay {
+    fn set(&mut self, active: bool) -> Result<(), String> {
+        match active == self.active_high {
+            true => self.pin.set_high(),
+            false => self.pin.set_low(),
+        }
+        .map_err(|e| format!("Relay pin: {:?}", e))
+    }
+}
+
+static RELAY: Mutex<Option<Relay>> = Mutex::new(None);
+
+// Takes the relay pin and drives it off, early at boot so the machine doesn't unlock while the
+// rest comes up
+pub fn arm(pin: AnyIOPin, config: &UnlockConfig) -> Result<(), String> {
+    let pin = PinDriver::output(pin).map_err(|e| format!("Relay pin init: {:?}", e))?;
+    let mut relay = Relay {
+        pin,
+        active_high: config.active_high,
+    };
+    relay.set(false)?;
+    *RELAY.lock().unwrap() = Some(relay);
+    info!("Relay armed, {}s per payment", config.unlock_for.as_secs());
+    Ok(())
+}
+
+pub struct Unlocker {
+    config: UnlockConfig,
+    request: TransferRequest,
+    reference: Pubkey,
+    nvs: EspNvs<NvsDefault>,
+    // The newest transaction carrying the reference that was dealt with, paid or not. Empty
+    // once the reference was looked at and had none, None before that.
+    cursor: Option<String>,
+    fulfilled: u32,
+}
+
+impl Unlocker {
+    pub fn open(recipient: Pubkey, config: &UnlockConfig, nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let mut nvs = EspNvs::new(nvs, UNLOCK_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+        let reference = match config.reference {
+            Some(reference) => reference,
+            None => load_or_generate_reference(&mut nvs)?,
+        };
+        let mut buffer = [0u8; SIGNATURE_MAX_LEN + 1];
+        let cursor = nvs
+            .get_str(CURSOR_KEY, &mut buffer)
+            .map_err(|e| format!("Unlock cursor read: {:?}", e))?
+            .map(|cursor| cursor.to_string());
+        let fulfilled = nvs
+            .get_u32(COUNT_KEY)
+            .map_err(|e| format!("Unlock count read: {:?}", e))?
+            .unwrap_or(0);
+
+        let request = TransferRequest {
+            recipient,
+            amount: Some(config.price.to_string()),
+            spl_token: config.spl_token,
+            references: vec![reference],
+            label: Some(config.label.to_string()),
+            message: config.message.map(|message| message.to_string()),
+            memo: None,
+        };
+        // Catches a malformed price before a wallet refuses the code
+        TransferRequest::parse(&request.to_url())?;
+        info!("Pay-to-unlock with reference {}, {} unlocks so far", reference, fulfilled);
+        Ok(Self {
+            config: *config,
+            request,
+            reference,
+            nvs,
+            cursor,
+            fulfilled,
+        })
+    }
+
+    // The code to print for the machine, shown on the display too when there is one
+    pub fn show(&self) -> Result<(), String> {
+        let url = self.request.to_url();
+        let qr = QrMatrix::encode(&url)?;
+        info!("Scan to pay {}:\n{}", url, qr.to_terminal_string());
+        #[cfg(feature = "oled-display")]
+        display::show_request(&qr, &[self.config.price, self.unit()])?;
+        Ok(())
+    }
+
+    #[cfg(feature = "oled-display")]
+    fn show_again(&self) -> Result<(), String> {
+        let qr = QrMatrix::encode(&self.request.to_url())?;
+        display::show_request(&qr, &[self.config.price, self.unit()])
+    }
+
+    // Unlocks once for every payment that landed since the last poll, oldest first
+    pub fn poll(&mut self) -> Result<(), String> {
+        let signatures = get_signatures_for_address(&self.reference, SIGNATURES_PER_POLL).map_err(|e| e.to_string())?;
+        let Some(cursor) = self.cursor.as_deref() else {
+            // First look at a printed reference, whatever paid it before was handled elsewhere
+            let newest = signatures.first().cloned().unwrap_or_default();
+            if !newest.is_empty() {
+                info!("Skipping the earlier transactions carrying the reference, up to {}", newest);
+            }
+            return self.advance(&newest);
+        };
+
+        let new: Vec<String> = signatures.iter().take_while(|signature| *signature != cursor).cloned().collect();
+        if !cursor.is_empty() && new.len() == SIGNATURES_PER_POLL {
+            warn!("More than {} transactions since the last poll, older ones are not unlocked for", SIGNATURES_PER_POLL);
+        }
+        for signature in new.iter().rev() {
+            // Listed before the node serving getTransaction has it, looked at again next poll
+            let Some(transaction) = get_transaction(signature).map_err(|e| e.to_string())? else {
+                return Ok(());
+            };
+            match self.request.validate(&transaction) {
+                Ok(()) => self.fulfil(signature)?,
+                Err(e) => {
+                    warn!("Transaction {} carries the reference but doesn't pay: {}", signature, e);
+                    self.advance(signature)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn fulfil(&mut self, signature: &str) -> Result<(), String> {
+        // Recorded before the relay moves, so a reset halfway through doesn't hand out another
+        self.fulfilled += 1;
+        let last = json!({ "signature": signature, "count": self.fulfilled, "time": unix_time() }).to_string();
+        self.nvs
+            .set_u32(COUNT_KEY, self.fulfilled)
+            .map_err(|e| format!("Unlock count write: {:?}", e))?;
+        self.nvs
+            .set_str(LAST_KEY, &last)
+            .map_err(|e| format!("Unlock record write: {:?}", e))?;
+        self.advance(signature)?;
+
+        info!("Payment of {} {} received, unlocking: {}", self.config.price, self.unit(), signature);
+        #[cfg(feature = "oled-display")]
+        display::payment_received(&format!("Paid {} {}", self.config.price, self.unit()));
+        #[cfg(feature = "buzzer")]
+        buzzer::play(Sound::Incoming);
+        #[cfg(feature = "receipt-printer")]
+        printer::payment_received(&format!("{} {}", self.config.price, self.unit()), signature);
+        pulse(self.config.unlock_for)?;
+        // The code again for the next customer
+        #[cfg(feature = "oled-display")]
+        self.show_again()?;
+        Ok(())
+    }
+
+    fn advance(&mut self, signature: &str) -> Result<(), String> {
+        self.nvs
+            .set_str(CURSOR_KEY, signature)
+            .map_err(|e| format!("Unlock cursor write: {:?}", e))?;
+        self.cursor = Some(signature.to_string());
+        Ok(())
+    }
+
+    fn unit(&self) -> &'static str {
+        match self.config.spl_token {
+            None => "SOL",
+            Some(_) => "token",
+        }
+    }
+}
+
+// Only its address is used, nothing ever signs with it
+fn load_or_generate_reference(nvs: &mut EspNvs<NvsDefault>) -> Result<Pubkey, String> {
+    let mut buffer = [0u8; 45];
+    if let Some(stored) = nvs
+        .get_str(REFERENCE_KEY, &mut buffer)
+        .map_err(|e| format!("Unlock reference read: {:?}", e))?
+    {
+        return Pubkey::from_str(stored).map_err(|e| format!("Unlock reference in NVS: {:?}", e));
+    }
+    let reference = Keypair::new().pubkey();
+    nvs.set_str(REFERENCE_KEY, &reference.to_string())
+        .map_err(|e| format!("Unlock reference write: {:?}", e))?;
+    Ok(reference)
+}
+
+fn pulse(duration: Duration) -> Result<(), String> {
+    let mut relay = RELAY.lock().unwrap();
+    let relay = relay.as_mut().ok_or("Relay not armed")?;
+    relay.set(true)?;
+    std::thread::sleep(duration);
+    relay.set(false)
+}
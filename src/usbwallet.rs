@@ -0,0 +1,205 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::usb_serial::{UsbDMinGpio, UsbDPlusGpio, UsbSerialConfig, UsbSerialDriver, USB_SERIAL};
+use esp_idf_svc::sys::esp_timer_get_time;
+use log::{info, warn};
+use serde_json::json;
+use solana_transaction::{Hash, Message, Transaction};
+
+use crate::net;
+use crate::signer::DeviceSigner;
+use crate::solrpc::{get_latest_blockhash, rpc_config, send_transaction};
+
+// Wallet protocol for host software on the USB Serial/JTAG port, which shows up as a CDC-ACM
+// serial device. Binary frames, lengths little-endian:
+//   request   A5 5A | length (2) | op | body             length counts op and body
+//   response  A5 5A | length (2) | op | status | body    status 0 ok, 1 error with a UTF-8 reason
+// Ops and their bodies:
+//   01 get-pubkey   -                        -> the device's 32-byte public key
+//   02 get-status   -                        -> JSON: pubkey, version, uptime, link, locked
+//   03 sign         bincode Message          -> 64-byte signature
+//   04 send         bincode Transaction      -> 64-byte signature of the submitted transaction
+// `send` adds the device's signature when its key is a required signer, a transaction with an
+// all-zero blockhash gets a fresh one first. Requests are answered in order, one at a time.
+// Bytes are skipped up to the next magic, a frame that stalls halfway is dropped.
+
+const MAGIC: [u8; 2] = [0xA5, 0x5A];
+const OP_GET_PUBKEY: u8 = 0x01;
+const OP_GET_STATUS: u8 = 0x02;
+const OP_SIGN: u8 = 0x03;
+const OP_SEND: u8 = 0x04;
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+// Room for the largest transaction, 1232 bytes, with headroom
+const MAX_FRAME_LEN: usize = 2048;
+const FRAME_TIMEOUT: Duration = Duration::from_millis(500);
+const READ_POLL_MS: u64 = 50;
+const WRITE_TIMEOUT_MS: u64 = 1000;
+const USB_STACK_SIZE: usize = 6 * 1024;
+
+struct Request {
+    op: u8,
+    body: Vec<u8>,
+    answer: Sender<Result<Vec<u8>, String>>,
+}
+
+static REQUESTS: Mutex<Vec<Request>> = Mutex::new(Vec::new());
+
+// Installs the USB Serial/JTAG driver and starts reading frames. The console must stay on the
+// UART, the driver takes the port over from it otherwise.
+pub fn start(usb_serial: USB_SERIAL, d_minus: UsbDMinGpio, d_plus: UsbDPlusGpio) -> Result<(), String> {
+    let config = UsbSerialConfig::new()
+        .rx_buffer_size(MAX_FRAME_LEN)
+        .tx_buffer_size(MAX_FRAME_LEN);
+    let usb = UsbSerialDriver::new(usb_serial, d_minus, d_plus, &config)
+        .map_err(|e| format!("USB Serial/JTAG init: {:?}", e))?;
+    std::thread::Builder::new()
+        .name("usbwallet".to_string())
+        .stack_size(USB_STACK_SIZE)
+        .spawn(move || serve(usb))
+        .map_err(|e| format!("USB wallet thread: {:?}", e))?;
+    info!("USB wallet protocol listening");
+    Ok(())
+}
+
+// Answers the requests read since the last call, from the main loop that holds the key
+pub fn serve_due(signer: &DeviceSigner) {
+    let requests = std::mem::take(&mut *REQUESTS.lock().unwrap());
+    for request in requests {
+        let result = handle(signer, request.op, &request.body);
+        if let Err(e) = &result {
+            warn!("USB wallet request {:#04x} refused: {}", request.op, e);
+        }
+        let _ = request.answer.send(result);
+    }
+}
+
+fn handle(signer: &DeviceSigner, op: u8, body: &[u8]) -> Result<Vec<u8>, String> {
+    match op {
+        OP_GET_PUBKEY => Ok(signer.pubkey().to_bytes().to_vec()),
+        OP_GET_STATUS => {
+            let url = rpc_config().url;
+            let status = json!({
+                "pubkey": signer.pubkey().to_string(),
+                "version": env!("CARGO_PKG_VERSION"),
+                "uptime_s": unsafe { esp_timer_get_time() } / 1_000_000,
+                "link": net::link_up().then(|| format!("{:?}", net::link_quality())),
+                // The host, a full endpoint URL may carry an API key
+                "rpc": url.split('/').nth(2).unwrap_or(&url),
+                "locked": signer.is_locked(),
+            });
+            Ok(status.to_string().into_bytes())
+        }
+        OP_SIGN => {
+            let message: Message = bincode::deserialize(body).map_err(|e| format!("Message decode: {:?}", e))?;
+            Ok(signer.sign_message(&message).map_err(|e| e.to_string())?.as_ref().to_vec())
+        }
+        OP_SEND => {
+            let mut transaction: Transaction =
+                bincode::deserialize(body).map_err(|e| format!("Transaction decode: {:?}", e))?;
+            let required = usize::from(transaction.message.header.num_required_signatures);
+            // Anything else is sent as it came, signed by the host
+            if transaction.message.account_keys.iter().take(required).any(|key| *key == signer.pubkey()) {
+                let blockhash = match transaction.message.recent_blockhash {
+                    blockhash if blockhash == Hash::default() => get_latest_blockhash().map_err(|e| e.to_string())?,
+                    blockhash => blockhash,
+                };
+                signer.sign_transaction(&mut transaction, blockhash).map_err(|e| e.to_string())?;
+            }
+            send_transaction(&transaction).map_err(|e| e.to_string())?;
+            let signature = transaction.signatures.first().ok_or("Transaction is not signed")?;
+            Ok(signature.as_ref().to_vec())
+        }
+        _ => Err(format!("Unknown op {:#04x}", op)),
+    }
+}
+
+fn serve(mut usb: UsbSerialDriver<'static>) {
+    let mut pending: Vec<u8> = Vec::with_capacity(MAX_FRAME_LEN);
+    let mut started = Instant::now();
+    let mut buf = [0u8; 64];
+    loop {
+        let read = match usb.read(&mut buf, TickType::new_millis(READ_POLL_MS).into()) {
+            Ok(read) => read,
+            Err(e) => {
+                warn!("USB read: {:?}", e);
+                0
+            }
+        };
+        if read == 0 {
+            if !pending.is_empty() && started.elapsed() > FRAME_TIMEOUT {
+                pending.clear();
+            }
+            continue;
+        }
+        if pending.is_empty() {
+            started = Instant::now();
+        }
+        pending.extend_from_slice(&buf[..read]);
+
+        while let Some((op, body)) = next_frame(&mut pending) {
+            let (answer, result) = channel();
+            REQUESTS.lock().unwrap().push(Request { op, body, answer });
+            let response = match result.recv() {
+                Ok(Ok(body)) => [&[op, STATUS_OK], body.as_slice()].concat(),
+                Ok(Err(e)) => [&[op, STATUS_ERROR], e.as_bytes()].concat(),
+                Err(_) => [&[op, STATUS_ERROR][..], b"Request dropped"].concat(),
+            };
+            if let Err(e) = write_frame(&mut usb, &response) {
+                warn!("USB write: {}", e);
+            }
+            started = Instant::now();
+        }
+    }
+}
+
+// Takes the first complete frame off the buffer, dropping whatever precedes a magic
+fn next_frame(pending: &mut Vec<u8>) -> Option<(u8, Vec<u8>)> {
+    loop {
+        match pending.windows(2).position(|window| window == MAGIC) {
+            Some(start) => {
+                pending.drain(..start);
+            }
+            None => {
+                // A trailing first half of the magic may still be completed
+                let keep = usize::from(pending.last() == Some(&MAGIC[0]));
+                pending.drain(..pending.len() - keep);
+                return None;
+            }
+        }
+        if pending.len() < 4 {
+            return None;
+        }
+        let len = usize::from(u16::from_le_bytes([pending[2], pending[3]]));
+        if len == 0 || len > MAX_FRAME_LEN {
+            // Not a frame, look for the next magic
+            pending.drain(..2);
+            continue;
+        }
+        if pending.len() < 4 + len {
+            return None;
+        }
+        let frame: Vec<u8> = pending.drain(..4 + len).skip(4).collect();
+        return Some((frame[0], frame[1..].to_vec()));
+    }
+}
+
+fn write_frame(usb: &mut UsbSerialDriver<'static>, payload: &[u8]) -> Result<(), String> {
+    let frame = [&MAGIC[..], &(payload.len() as u16).to_le_bytes(), payload].concat();
+    let mut written = 0;
+    while written < frame.len() {
+        let count = usb
+            .write(&frame[written..], TickType::new_millis(WRITE_TIMEOUT_MS).into())
+            .map_err(|e| format!("{:?}", e))?;
+        // Nothing goes out while no host has the port open
+        if count == 0 {
+            return Err("Host not reading".to_string());
+        }
+        written += count;
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, Output, PinDriver};
+use log::{info, warn};
+use solana_program::native_token::LAMPORTS_PER_SOL;
+use solana_program::pubkey::Pubkey;
+
+use crate::amount::Amount;
+use crate::display;
+use crate::hx711::Hx711;
+use crate::pyth;
+use crate::receive::{PaymentRequest, ReceiveConfig};
+
+// Vending by weight: the customer puts a cup or container on the load cell, the product its
+// weight matches is priced and asked for with a Solana Pay code, and once the payment lands the
+// dispense output runs for as long as that product takes to fill. Taking the container off before
+// paying cancels the sale, the next one starts once the platform is empty again.
+
+// Conversions averaged per reading, the HX711 makes 10 a second
+const SAMPLES: usize = 3;
+// The platform counts as empty below this share of the lightest product
+const EMPTY_SHARE: f32 = 0.5;
+// Prices in SOL are rounded up to this many lamports, wallets show the rest as noise
+const LAMPORT_STEP: u64 = 1_000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Price {
+    // Decimal in SOL or in the token's units
+    Fixed(&'static str),
+    // US dollars, paid in SOL at the Pyth price
+    Usd(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Product {
+    pub name: &'static str,
+    // Weight of the empty container on the platform
+    pub grams: f32,
+    pub tolerance_grams: f32,
+    pub price: Price,
+    pub dispense_for: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VendingConfig {
+    pub products: &'static [Product],
+    // Raw HX711 counts per gram of this load cell, see `Hx711::new`
+    pub counts_per_gram: f32,
+    // A container has to rest this long before it is priced, placing it shakes the reading
+    pub settle: Duration,
+    // Pumps and valves switch through relay modules, many of which switch on a low input
+    pub active_high: bool,
+    // Pyth SOL/USD price feed account, for `Price::Usd`
+    pub price_feed: Pubkey,
+    pub max_price_age: Duration,
+}
+
+struct Hardware {
+    scale: Hx711,
+    dispense: PinDriver<'static, AnyIOPin, Output>,
+    active_high: bool,
+}
+
+impl Hardware {
+    fn set_dispense(&mut self, active: bool) -> Result<(), String> {
+        match active == self.active_high {
+            true => self.dispense.set_high(),
+            false => self.dispense.set_low(),
+        }
+        .map_err(|e| format!("Dispense pin: {:?}", e))
+    }
+}
+
+static HARDWARE: Mutex<Option<Hardware>> = Mutex::new(None);
+
+// Drives the dispense output off and zeroes the scale, early at boot so nothing dispenses while
+// the rest comes up. The platform has to be empty at power-on.
+pub fn arm(dout: AnyIOPin, sck: AnyIOPin, dispense: AnyIOPin, config: &VendingConfig) -> Result<(), String> {
+    let dispense = PinDriver::output(dispense).map_err(|e| format!("Dispense pin init: {:?}", e))?;
+    let mut hardware = Hardware {
+        scale: Hx711::new(dout, sck, config.counts_per_gram)?,
+        dispense,
+        active_high: config.active_high,
+    };
+    hardware.set_dispense(false)?;
+    hardware.scale.tare()?;
+    *HARDWARE.lock().unwrap() = Some(hardware);
+    info!("Vending armed with {} products", config.products.len());
+    Ok(())
+}
+
+enum State {
+    Idle,
+    Settling { product: &'static Product, since: Instant },
+    AwaitingPayment { product: &'static Product, request: Box<PaymentRequest>, polled: Instant },
+    // Dispensed, waiting for the container to be taken off
+    Clearing,
+}
+
+pub struct VendingMachine {
+    recipient: Pubkey,
+    config: &'static VendingConfig,
+    receive: &'static ReceiveConfig,
+    state: State,
+}
+
+impl VendingMachine {
+    pub fn new(recipient: Pubkey, config: &'static VendingConfig, receive: &'static ReceiveConfig) -> Result<Self, String> {
+        if HARDWARE.lock().unwrap().is_none() {
+            return Err("Scale and dispense output not armed".to_string());
+        }
+        let usd = config.products.iter().any(|product| matches!(product.price, Price::Usd(_)));
+        if usd && receive.spl_token.is_some() {
+            return Err("Dollar prices are paid in SOL, the receive config asks for a token".to_string());
+        }
+        let machine = Self {
+            recipient,
+            config,
+            receive,
+            state: State::Idle,
+        };
+        machine.show_products();
+        Ok(machine)
+    }
+
+    // Reads the scale once and moves the sale along, called every few hundred milliseconds
+    pub fn step(&mut self) -> Result<(), String> {
+        let grams = {
+            let mut hardware = HARDWARE.lock().unwrap();
+            let hardware = hardware.as_mut().ok_or("Scale not armed")?;
+            hardware.scale.grams(SAMPLES)?
+        };
+        let placed = self.matching(grams);
+
+        let state = std::mem::replace(&mut self.state, State::Idle);
+        self.state = match state {
+            State::Idle => match placed {
+                Some(product) => State::Settling { product, since: Instant::now() },
+                None => State::Idle,
+            },
+            State::Settling { product, since } => match placed {
+                Some(placed) if std::ptr::eq(placed, product) && since.elapsed() >= self.config.settle => {
+                    match self.request(product) {
+                        Ok(request) => State::AwaitingPayment {
+                            product,
+                            request: Box::new(request),
+                            polled: Instant::now(),
+                        },
+                        Err(e) => {
+                            warn!("{} not offered: {}", product.name, e);
+                            display::show_entry(&[product.name, "Unavailable", "Try again"]);
+                            State::Clearing
+                        }
+                    }
+                }
+                Some(placed) if std::ptr::eq(placed, product) => State::Settling { product, since },
+                Some(placed) => State::Settling { product: placed, since: Instant::now() },
+                None => State::Idle,
+            },
+            State::AwaitingPayment { product, mut request, polled } => {
+                if placed.is_none_or(|placed| !std::ptr::eq(placed, product)) {
+                    // A payment already under way would be lost, one last look
+                    if let Ok(Some(signature)) = request.poll() {
+                        warn!("{} paid as it was taken off, refund {}", product.name, signature);
+                    } else {
+                        info!("{} taken off, sale cancelled", product.name);
+                        self.show_products();
+                    }
+                    State::Clearing
+                } else if request.expired(self.receive.expiry) {
+                    info!("{} not paid in time", product.name);
+                    display::show_entry(&[product.name, "Not paid", "Take it off"]);
+                    State::Clearing
+                } else if polled.elapsed() < self.receive.poll_interval {
+                    State::AwaitingPayment { product, request, polled }
+                } else {
+                    match request.poll() {
+                        Ok(Some(signature)) => {
+                            if let Err(e) = self.dispense(product) {
+                                warn!("{} not dispensed, refund {}: {}", product.name, signature, e);
+                            }
+                            State::Clearing
+                        }
+                        Ok(None) => State::AwaitingPayment {
+                            product,
+                            request,
+                            polled: Instant::now(),
+                        },
+                        Err(e) => {
+                            warn!("Payment check failed: {}", e);
+                            State::AwaitingPayment {
+                                product,
+                                request,
+                                polled: Instant::now(),
+                            }
+                        }
+                    }
+                }
+            }
+            State::Clearing if grams < self.empty_grams() => {
+                self.show_products();
+                State::Idle
+            }
+            State::Clearing => State::Clearing,
+        };
+        Ok(())
+    }
+
+    fn matching(&self, grams: f32) -> Option<&'static Product> {
+        self.config
+            .products
+            .iter()
+            .find(|product| (grams - product.grams).abs() <= product.tolerance_grams)
+    }
+
+    fn empty_grams(&self) -> f32 {
+        let lightest = self.config.products.iter().map(|product| product.grams).fold(f32::MAX, f32::min);
+        lightest * EMPTY_SHARE
+    }
+
+    fn request(&self, product: &Product) -> Result<PaymentRequest, String> {
+        let amount = match product.price {
+            Price::Fixed(amount) => amount.to_string(),
+            Price::Usd(usd) => {
+                let sol_usd = pyth::price(&self.config.price_feed, self.config.max_price_age)?;
+                let lamports = (usd / sol_usd * LAMPORTS_PER_SOL as f64).ceil() as u64;
+                let lamports = lamports.div_ceil(LAMPORT_STEP) * LAMPORT_STEP;
+                info!("{} at ${} is {} lamports, SOL at ${:.2}", product.name, usd, lamports, sol_usd);
+                Amount::sol(lamports).to_string()
+            }
+        };
+        let request = PaymentRequest::new(self.recipient, &amount, self.receive)?;
+        request.show()?;
+        info!("{} placed, asking for {}", product.name, amount);
+        Ok(request)
+    }
+
+    fn dispense(&self, product: &Product) -> Result<(), String> {
+        let mut hardware = HARDWARE.lock().unwrap();
+        let hardware = hardware.as_mut().ok_or("Dispense output not armed")?;
+        info!("Dispensing {} for {}ms", product.name, product.dispense_for.as_millis());
+        hardware.set_dispense(true)?;
+        std::thread::sleep(product.dispense_for);
+        // Off again whatever happened, a stuck pump spills
+        let result = hardware.set_dispense(false);
+        display::show_entry(&[product.name, "Ready", "Take it off"]);
+        result
+    }
+
+    fn show_products(&self) {
+        let mut lines = vec!["Place a cup"];
+        lines.extend(self.config.products.iter().map(|product| product.name));
+        display::show_entry(&lines);
+    }
+}
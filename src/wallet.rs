@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspEncryptedNvsPartition};
+use log::{info, warn};
+use solana_keypair::{Keypair, Signer};
+
+use crate::approval::{ApprovalConfig, ButtonApproval, NoApproval};
+#[cfg(feature = "bench")]
+use crate::bench;
+#[cfg(feature = "ble-provisioning")]
+use crate::ble_prov;
+#[cfg(all(feature = "bench-signing", not(feature = "bench")))]
+use crate::ed25519;
+use crate::ed25519::SigningBackend;
+#[cfg(feature = "fingerprint")]
+use crate::fingerprint::FingerprintApproval;
+use crate::keystore::Keystore;
+use crate::pin::PinGate;
+use crate::policy::{DenyAll, PolicyEngine, PolicyStore};
+use crate::provisioning::run_provisioning_window;
+#[cfg(not(feature = "remote-signer"))]
+use crate::rollback::{self, FirmwareFloor, FloorConfig};
+use crate::signer::DeviceSigner;
+#[cfg(not(feature = "remote-signer"))]
+use crate::solrpc::{self, RpcClient};
+use crate::spend::SpendLedger;
+use crate::tamper::{self, TamperConfig, TamperLog};
+#[cfg(feature = "touch-pad")]
+use crate::touch::{self, TouchConfig};
+
+// The device key and everything that guards it: the tamper log, the PIN, the spending policy,
+// the firmware floor and the approval input. `open` assembles them into the DeviceSigner every
+// application mode signs with, so the modes never see the keystore itself.
+
+pub struct WalletConfig {
+    // Persisting the key without flash encryption has to be opted into
+    pub allow_plaintext_keystore: bool,
+    // How long boot waits for the signing PIN on the console
+    pub pin_entry_timeout: Duration,
+    // Transfers above the threshold wait for the approval input, and are refused after the timeout
+    pub approval_threshold_lamports: u64,
+    pub approval_timeout: Duration,
+    pub signing_backend: SigningBackend,
+    // None disables tamper detection
+    pub tamper_switch: Option<TamperConfig>,
+    // Account publishing the minimum firmware version, and whether the device halts below it
+    // instead of only refusing payments
+    #[cfg(not(feature = "remote-signer"))]
+    pub firmware_floor: Option<FloorConfig>,
+    #[cfg(not(feature = "remote-signer"))]
+    pub halt_below_floor: bool,
+    #[cfg(feature = "touch-pad")]
+    pub touch: TouchConfig,
+    // Repetitions of each local step and RPC round trips timed at boot
+    #[cfg(feature = "bench")]
+    pub bench_iterations: u32,
+    #[cfg(feature = "bench")]
+    pub bench_rpc_calls: u32,
+}
+
+// Loads or generates the device key, falling back to an ephemeral one without the keystore,
+// and hooks the policy and approvals in. The tamper switch is on `tamper_pin`, the approval
+// button on `button_pin` unless a fingerprint module or touch pad takes over.
+pub fn open(
+    nvs: EspDefaultNvsPartition,
+    encrypted: Option<EspEncryptedNvsPartition>,
+    tamper_pin: AnyIOPin,
+    mut button_pin: AnyIOPin,
+    config: &WalletConfig,
+    #[cfg(feature = "fingerprint")] fingerprint: Option<FingerprintApproval>,
+) -> DeviceSigner {
+    // A log that can't be read may hide an event, so it locks the device down as well
+    let tamper_log = match TamperLog::open(nvs.clone()).and_then(|log| log.events().map(|events| (log, events))) {
+        Ok((log, 0)) => log,
+        Ok((log, _)) => tamper::lockdown(
+            &log,
+            Keystore::open(nvs, encrypted, config.allow_plaintext_keystore),
+        ),
+        Err(e) => tamper::lockdown_unknown(&e),
+    };
+
+    // Without its PIN state the signer refuses to sign, it can't tell whether a PIN is set
+    let mut pin_gate = PinGate::open(nvs.clone(), encrypted.clone());
+    if let Err(e) = &pin_gate {
+        warn!("PIN gate unavailable ({}), all signatures will be refused", e);
+    }
+
+    let mut policy_store = match PolicyStore::open(nvs.clone()) {
+        Ok(policy_store) => Some(policy_store),
+        Err(e) => {
+            warn!("Policy store unavailable: {}", e);
+            None
+        }
+    };
+
+    let ledger = SpendLedger::open(nvs.clone());
+
+    #[cfg(not(feature = "remote-signer"))]
+    let floor = config.firmware_floor.as_ref().and_then(|floor| check_firmware_floor(nvs.clone(), floor));
+
+    let mut keystore = Keystore::open(nvs, encrypted, config.allow_plaintext_keystore);
+    let keypair = match keystore.as_mut().map_err(|e| e.clone()).and_then(|keystore| {
+        run_provisioning_window(
+            keystore,
+            pin_gate.as_mut().ok(),
+            policy_store.as_mut(),
+            #[cfg(feature = "fingerprint")]
+            fingerprint.as_ref(),
+            &mut button_pin,
+        );
+        keystore.load_or_generate()
+    }) {
+        Ok(keypair) => {
+            info!("Device key loaded from keystore: {}", keypair.pubkey());
+            keypair
+        }
+        Err(e) => {
+            warn!("Keystore unavailable ({}), using ephemeral key", e);
+            let keypair = Keypair::new();
+            info!("Keyapir generated for demo: {}", keypair.pubkey());
+            keypair
+        }
+    };
+
+    // Endpoint pushed over BLE during WiFi setup, kept with the other provisioned credentials.
+    // Like the provisioning window's `rpc`, it can't change a sealed device's endpoint.
+    #[cfg(feature = "ble-provisioning")]
+    if let (Some(url), Ok(keystore)) = (ble_prov::take_rpc_url(), keystore.as_mut()) {
+        match keystore.is_sealed() {
+            Ok(false) => {
+                if let Err(e) = keystore.store_rpc_url(&url) {
+                    warn!("RPC endpoint from BLE not stored: {}", e);
+                }
+            }
+            Ok(true) => warn!("RPC endpoint from BLE ignored, the device is sealed"),
+            Err(e) => warn!("RPC endpoint from BLE ignored, seal state unknown ({})", e),
+        }
+    }
+
+    // RPC endpoint injected by fleet provisioning, replaces the compiled-in default
+    #[cfg(not(feature = "remote-signer"))]
+    match keystore.as_ref().map(|keystore| keystore.load_rpc_url()) {
+        Ok(Ok(Some(url))) => RpcClient::reconfigure().url(url).build().install(),
+        Ok(Err(e)) => warn!("Provisioned RPC endpoint unavailable: {}", e),
+        _ => {}
+    }
+    #[cfg(not(feature = "remote-signer"))]
+    info!("Solana cluster: {}", solrpc::cluster());
+
+    // The watcher gets the keystore so it can wipe it, and a copy of the key for the alert
+    if let Some(tamper_config) = config.tamper_switch {
+        let alert_key = tamper_config.alert.then(|| keypair.insecure_clone());
+        // Signing with the input unwatched would leave the keys unprotected
+        if let Err(e) = tamper::spawn_watcher(tamper_pin, tamper_config, keystore.ok(), tamper_log, alert_key) {
+            tamper::lock(&e);
+        }
+    }
+
+    // bench includes the signing benchmark
+    #[cfg(all(feature = "bench-signing", not(feature = "bench")))]
+    if let Err(e) = ed25519::benchmark(&keypair, 20) {
+        warn!("Signing benchmark failed: {}", e);
+    }
+    #[cfg(feature = "bench")]
+    bench::run(&keypair, config.bench_iterations, config.bench_rpc_calls);
+
+    let mut signer = DeviceSigner::new(keypair, pin_gate);
+    signer.set_backend(config.signing_backend);
+    if let Some(pin_gate) = signer.pin_gate() {
+        match pin_gate.is_configured() {
+            Ok(true) => {
+                if let Err(e) = pin_gate.unlock_from_serial(config.pin_entry_timeout) {
+                    warn!("Signing stays locked: {}", e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Signing stays locked: {}", e),
+        }
+    }
+
+    let policy = policy_store
+        .as_ref()
+        .ok_or_else(|| "Policy store unavailable".to_string())
+        .and_then(|policy_store| policy_store.load())
+        .and_then(|policy| Ok((policy, ledger?)));
+    match policy {
+        Ok((policy, ledger)) => {
+            info!("Spending policy: {}", policy.to_json());
+            signer.add_hook(PolicyEngine::new(policy, ledger));
+        }
+        Err(e) => {
+            // Without its policy the device refuses to sign anything rather than signing unrestricted
+            warn!("Spending policy unavailable ({}), all signatures will be refused", e);
+            signer.add_hook(DenyAll(e));
+        }
+    }
+
+    #[cfg(not(feature = "remote-signer"))]
+    if let (Some(mut floor), Some(floor_config)) = (floor, config.firmware_floor.clone()) {
+        if floor.enforce(&floor_config) && config.halt_below_floor {
+            rollback::halt_below_floor();
+        }
+        signer.add_hook(floor.hook());
+        if let Err(e) = floor.spawn_refresh(floor_config, config.halt_below_floor) {
+            warn!("Firmware floor won't be refreshed until reboot: {}", e);
+        }
+    }
+
+    let approval_config = ApprovalConfig {
+        threshold_lamports: config.approval_threshold_lamports,
+        timeout: config.approval_timeout,
+        ..Default::default()
+    };
+    #[cfg(feature = "fingerprint")]
+    let button_pin = match fingerprint {
+        Some(approval) => {
+            signer.add_hook(approval);
+            None
+        }
+        None => Some(button_pin),
+    };
+    // Calibrated here, so the pad has to be left alone while the device boots
+    #[cfg(feature = "touch-pad")]
+    let button_pin = match touch::start(signer.pubkey(), config.touch) {
+        Ok(approval) => {
+            signer.add_hook(approval);
+            None
+        }
+        Err(e) => {
+            warn!("Touch pad unavailable, the button approves instead: {}", e);
+            Some(button_pin)
+        }
+    };
+    #[cfg(not(any(feature = "fingerprint", feature = "touch-pad")))]
+    let button_pin = Some(button_pin);
+    if let Some(button_pin) = button_pin {
+        match ButtonApproval::new(button_pin, approval_config) {
+            Ok(approval) => signer.add_hook(approval),
+            Err(e) => {
+                warn!("Button approval unavailable ({}), transfers that need it will be refused", e);
+                signer.add_hook(NoApproval {
+                    threshold_lamports: approval_config.threshold_lamports,
+                    reason: e,
+                });
+            }
+        }
+    }
+
+    signer
+}
+
+#[cfg(not(feature = "remote-signer"))]
+fn check_firmware_floor(nvs: EspDefaultNvsPartition, config: &FloorConfig) -> Option<FirmwareFloor> {
+    let mut floor = match FirmwareFloor::open(nvs) {
+        Ok(floor) => floor,
+        Err(e) => {
+            warn!("Firmware floor unavailable: {}", e);
+            return None;
+        }
+    };
+    // A failed refresh still enforces the last floor remembered in NVS
+    if let Err(e) = floor.refresh(config) {
+        warn!("Firmware floor refresh failed: {}", e);
+    }
+
+    Some(floor)
+}
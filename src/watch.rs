@@ -0,0 +1,218 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use solana_program::pubkey::Pubkey;
+
+use crate::amount::Sol;
+#[cfg(feature = "buzzer")]
+use crate::buzzer::{self, Sound};
+use crate::discovery;
+use crate::qr::{wallet_uri, QrMatrix};
+use crate::serial::LineReader;
+use crate::solrpc::{get_account_info, AccountInfo};
+
+const WATCH_NAMESPACE: &str = "watch";
+const ACCOUNTS_KEY: &str = "accounts";
+const MAX_ACCOUNTS: usize = 16;
+
+const PROVISIONING_WINDOW: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Public keys the device monitors, stored as a comma separated list
+pub struct WatchList {
+    nvs: EspNvs<NvsDefault>,
+    accounts: Vec<Pubkey>,
+}
+
+impl WatchList {
+    pub fn open(nvs: EspDefaultNvsPartition) -> Result<Self, String> {
+        let nvs = EspNvs::new(nvs, WATCH_NAMESPACE, true).map_err(|e| format!("NVS open: {:?}", e))?;
+
+        // Up to 44 base58 chars per pubkey plus the separator
+        let mut buf = vec![0u8; MAX_ACCOUNTS * 45 + 1];
+        let accounts = match nvs
+            .get_str(ACCOUNTS_KEY, &mut buf)
+            .map_err(|e| format!("Watch list read: {:?}", e))?
+        {
+            Some(list) => list
+                .split(',')
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| Pubkey::from_str(entry).map_err(|e| format!("Watch list entry: {:?}", e)))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+
+        Ok(Self { nvs, accounts })
+    }
+
+    pub fn accounts(&self) -> &[Pubkey] {
+        &self.accounts
+    }
+
+    pub fn add(&mut self, account: Pubkey) -> Result<(), String> {
+        if self.accounts.contains(&account) {
+            return Ok(());
+        }
+        if self.accounts.len() >= MAX_ACCOUNTS {
+            return Err(format!("Watch list is limited to {} accounts", MAX_ACCOUNTS));
+        }
+
+        self.accounts.push(account);
+        self.persist()
+    }
+
+    pub fn remove(&mut self, account: &Pubkey) -> Result<(), String> {
+        self.accounts.retain(|a| a != account);
+        self.persist()
+    }
+
+    fn persist(&mut self) -> Result<(), String> {
+        let list = self
+            .accounts
+            .iter()
+            .map(|account| account.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        self.nvs
+            .set_str(ACCOUNTS_KEY, &list)
+            .map_err(|e| format!("Watch list write: {:?}", e))
+    }
+}
+
+// Last observed state of a watched account, data is kept as a hash to save RAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Snapshot {
+    lamports: u64,
+    owner: Pubkey,
+    data_hash: [u8; 32],
+}
+
+impl From<&AccountInfo> for Snapshot {
+    fn from(account: &AccountInfo) -> Self {
+        Self {
+            lamports: account.lamports,
+            owner: account.owner,
+            data_hash: Sha256::digest(&account.data).into(),
+        }
+    }
+}
+
+// Watch-only firmware: holds no private key, only reports on the watched accounts
+pub fn run(nvs: EspDefaultNvsPartition) -> ! {
+    let mut watch_list = match WatchList::open(nvs) {
+        Ok(watch_list) => Some(watch_list),
+        Err(e) => {
+            warn!("Watch list unavailable: {}", e);
+            None
+        }
+    };
+
+    if let Some(watch_list) = watch_list.as_mut() {
+        run_provisioning_window(watch_list);
+    }
+    let accounts = watch_list.map(|w| w.accounts().to_vec()).unwrap_or_default();
+
+    if let Some(first) = accounts.first() {
+        match QrMatrix::encode(&wallet_uri(first)) {
+            Ok(qr) => info!("Scan to fund {}:\n{}", first, qr.to_terminal_string()),
+            Err(e) => warn!("Address QR code: {}", e),
+        }
+    }
+    info!("Watching {} accounts", accounts.len());
+    discovery::advertise(None);
+
+    let mut snapshots: Vec<Option<Snapshot>> = vec![None; accounts.len()];
+    loop {
+        for (account, last) in accounts.iter().zip(snapshots.iter_mut()) {
+            let current = match get_account_info(account) {
+                Ok(info) => info.as_ref().map(Snapshot::from),
+                Err(e) => {
+                    warn!("Failed to fetch {}: {}", account, e);
+                    continue;
+                }
+            };
+
+            report_changes(account, last.as_ref(), current.as_ref());
+            *last = current;
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn report_changes(account: &Pubkey, last: Option<&Snapshot>, current: Option<&Snapshot>) {
+    match (last, current) {
+        (None, Some(current)) => info!("{}: {}", account, Sol(current.lamports)),
+        (Some(_), None) => info!("{}: account closed", account),
+        (Some(last), Some(current)) => {
+            if current.lamports > last.lamports {
+                info!("{}: incoming payment of {}", account, Sol(current.lamports - last.lamports));
+                #[cfg(feature = "buzzer")]
+                buzzer::play(Sound::Incoming);
+            } else if current.lamports < last.lamports {
+                info!("{}: balance decreased by {}", account, Sol(last.lamports - current.lamports));
+            }
+            if current.owner != last.owner {
+                info!("{}: owner changed to {}", account, current.owner);
+            }
+            if current.data_hash != last.data_hash {
+                info!("{}: account data changed", account);
+            }
+        }
+        (None, None) => {}
+    }
+}
+
+// Console commands for managing the watch list:
+//   watch <pubkey>     adds an account
+//   unwatch <pubkey>   removes an account
+//   list               prints the watched accounts
+//   done               ends the window early
+fn run_provisioning_window(watch_list: &mut WatchList) {
+    info!(
+        "Provisioning window open for {}s, send `watch <pubkey>`",
+        PROVISIONING_WINDOW.as_secs()
+    );
+
+    let deadline = Instant::now() + PROVISIONING_WINDOW;
+    let mut reader = LineReader::new();
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let Some(line) = reader.read_line(remaining) else {
+            break;
+        };
+        let (command, args) = line.split_once(' ').unwrap_or((&line, ""));
+
+        let result = match command {
+            "done" => break,
+            "list" => Ok(watch_list
+                .accounts()
+                .iter()
+                .map(|account| account.to_string())
+                .collect::<Vec<_>>()
+                .join(",")),
+            "watch" | "unwatch" => Pubkey::from_str(args.trim())
+                .map_err(|e| format!("Invalid pubkey: {:?}", e))
+                .and_then(|account| {
+                    if command == "watch" {
+                        watch_list.add(account)
+                    } else {
+                        watch_list.remove(&account)
+                    }
+                    .map(|_| account.to_string())
+                }),
+            _ => Err(format!("Unknown command '{}'", command)),
+        };
+
+        match result {
+            Ok(response) => println!("OK {}", response),
+            Err(e) => println!("ERR {}", e),
+        }
+    }
+
+    info!("Provisioning window closed");
+}
@@ -0,0 +1,151 @@
+#!/usr/bin/env python3
+"""What takes the flash in a firmware built with --features size-report.
+
+    python tools/size_report.py [MAP] [--top N]
+
+MAP is the linker map the build writes next to the firmware, the newest REsp32Sol.map under
+target/ by default. Prints the size of each output section, then the crates and C libraries
+and the symbols with the most code and read-only data in the image.
+"""
+
+import argparse
+import collections
+import glob
+import os
+import re
+import sys
+
+OUTPUT_SECTION = re.compile(r"^(\.\S+)(?:\s+0x[0-9a-fA-F]+\s+0x([0-9a-fA-F]+).*)?$")
+INPUT_SECTION = re.compile(r"^ (\S+)\s+0x[0-9a-fA-F]+\s+0x([0-9a-fA-F]+)\s+(\S.*)$")
+INPUT_NAME = re.compile(r"^ (\.\S+)$")
+INPUT_REST = re.compile(r"^\s+0x[0-9a-fA-F]+\s+0x([0-9a-fA-F]+)\s+(\S.*)$")
+
+RUST_LIBRARY = re.compile(r"lib([^/\\]+?)-[0-9a-f]+\.rlib\(")
+C_LIBRARY = re.compile(r"([^/\\]+)\.a\(")
+RUST_OBJECT = re.compile(r"([^/\\.]+?)-[0-9a-f]{16}\.")
+
+# Legacy Rust mangling escapes, in the order they have to be undone
+ESCAPES = [
+    ("$LT$", "<"), ("$GT$", ">"), ("$RF$", "&"), ("$BP$", "*"), ("$LP$", "("), ("$RP$", ")"),
+    ("$C$", ","), ("$SP$", "@"), ("$u20$", " "), ("$u22$", '"'), ("$u27$", "'"), ("$u2b$", "+"),
+    ("$u3b$", ";"), ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"), ("$u7d$", "}"),
+    ("$u7e$", "~"), ("..", "::"),
+]
+
+
+def in_image(section):
+    # The sections the app image carries: code and data in flash, and what is copied from flash
+    # into IRAM, DRAM and RTC memory at boot. Zeroed and uninitialized memory takes no flash.
+    if not section.startswith((".flash.", ".iram0.", ".dram0.", ".rtc.")):
+        return False
+    return not any(word in section for word in ("bss", "noinit", "noload", "dummy"))
+
+
+def owner(path):
+    for pattern, label in ((RUST_LIBRARY, "{}"), (C_LIBRARY, "{}.a"), (RUST_OBJECT, "{}")):
+        match = pattern.search(path)
+        if match:
+            return label.format(match.group(1))
+    return os.path.basename(path)
+
+
+def demangle(symbol):
+    match = re.fullmatch(r"_ZN(.+)E", symbol)
+    if not match:
+        return symbol
+    rest, parts = match.group(1), []
+    while rest:
+        length = re.match(r"\d+", rest)
+        if not length:
+            return symbol
+        start = length.end()
+        parts.append(rest[start:start + int(length.group())])
+        rest = rest[start + int(length.group()):]
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    path = "::".join(part[1:] if part.startswith("_$") else part for part in parts)
+    for escape, char in ESCAPES:
+        path = path.replace(escape, char)
+    return path
+
+
+def symbol(section):
+    # .text.<symbol>, .rodata.<symbol> and so on with -ffunction-sections, which both rustc and
+    # ESP-IDF build with. A section without one is named after its object file.
+    parts = section.split(".", 2)
+    return demangle(parts[2]) if len(parts) == 3 and parts[2] else None
+
+
+def parse(lines):
+    sections = collections.Counter()
+    owners = collections.Counter()
+    symbols = collections.Counter()
+    output, pending = None, None
+    lines = iter(lines)
+    for line in lines:
+        if line.startswith("Linker script and memory map"):
+            break
+    for line in lines:
+        line = line.rstrip("\n")
+        match = OUTPUT_SECTION.match(line)
+        if match:
+            output, pending = match.group(1), None
+            if match.group(2):
+                sections[output] += int(match.group(2), 16)
+            continue
+        if pending:
+            match = INPUT_REST.match(line)
+            name, pending = pending, None
+            if match:
+                size, path = int(match.group(1), 16), match.group(2)
+            else:
+                continue
+        else:
+            match = INPUT_NAME.match(line)
+            if match:
+                pending = match.group(1)
+                continue
+            match = INPUT_SECTION.match(line)
+            if not match or match.group(1).startswith("*"):
+                continue
+            name, size, path = match.group(1), int(match.group(2), 16), match.group(3)
+        if size == 0 or output is None or not in_image(output):
+            continue
+        owners[owner(path)] += size
+        symbols[symbol(name) or "{} ({})".format(name, owner(path))] += size
+    return sections, owners, symbols
+
+
+def newest_map():
+    maps = glob.glob(os.path.join("target", "**", "REsp32Sol.map"), recursive=True)
+    if not maps:
+        sys.exit("No REsp32Sol.map under target/, build with --features size-report first")
+    return max(maps, key=os.path.getmtime)
+
+
+def table(title, counter, top):
+    print("\n{}".format(title))
+    for name, size in counter.most_common(top):
+        print("{:>10}  {}".format(size, name))
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("map", nargs="?", help="the linker map, the newest under target/ by default")
+    parser.add_argument("--top", type=int, default=25, help="crates and symbols listed, 25 by default")
+    args = parser.parse_args()
+
+    path = args.map or newest_map()
+    with open(path, encoding="utf-8", errors="replace") as file:
+        sections, owners, symbols = parse(file)
+
+    image = sum(size for section, size in sections.items() if in_image(section))
+    print("{}: {} bytes in the image".format(path, image))
+    table("Sections", collections.Counter(
+        {section: size for section, size in sections.items() if in_image(section)}), len(sections))
+    table("Crates and libraries", owners, args.top)
+    table("Symbols", symbols, args.top)
+
+
+if __name__ == "__main__":
+    main()